@@ -106,6 +106,10 @@ pub struct IgvmParamBlock {
     /// context is present.
     pub guest_context_offset: u32,
 
+    /// The offset, in bytes, of the page holding the ACPI SRAT supplied by
+    /// the loader, or zero if no SRAT page is present.
+    pub srat_offset: u32,
+
     /// The guest physical address of the CPUID page.
     pub cpuid_page: u32,
 
@@ -118,8 +122,37 @@ pub struct IgvmParamBlock {
     /// Indicates whether the guest can support alternate injection.
     pub use_alternate_injection: u8,
 
-    #[doc(hidden)]
-    pub _reserved: [u8; 5],
+    /// The I/O port used as doorbell for the host event channel, or zero if
+    /// no event channel is provided by the host.
+    pub event_channel_port: u16,
+
+    /// The interrupt vector the host uses to signal the event channel.
+    pub event_channel_vector: u8,
+
+    /// The number of 4K pages the SVSM allocates for the event channel
+    /// rings, rounded up to a power of two.
+    pub event_channel_pages: u8,
+
+    /// The number of 4K pages the SVSM converts to shared at boot for host
+    /// communication buffers, rounded up to a power of two, or zero to use
+    /// the default size.
+    pub shared_pool_pages: u8,
+
+    /// Indicates that the SVSM must place its heap and stacks at fixed
+    /// addresses instead of randomizing them, e.g. for debugging.
+    pub disable_layout_randomization: u8,
+
+    /// The number of consecutive times real-time tasks may be scheduled
+    /// while normal tasks are waiting to run, or zero to use the default.
+    pub rt_task_budget: u8,
+
+    /// Indicates that lower VMPLs may query the state of their memory as
+    /// seen by the SVSM.
+    pub memory_state_queries: u8,
+
+    /// The number of SVSM tasks validating guest memory in the background,
+    /// or zero if the SVSM does not validate guest memory for the guest.
+    pub prevalidation_workers: u8,
 
     /// Metadata containing information about the firmware image embedded in the
     /// IGVM file.
@@ -128,8 +161,30 @@ pub struct IgvmParamBlock {
     /// The number of bytes for the stage1 bootloader
     pub stage1_size: u32,
 
-    #[doc(hidden)]
-    pub _reserved2: u32,
+    /// The minimum interval between updates of the SVSM heartbeat page in
+    /// units of 2^20 TSC cycles, or zero if no heartbeat is reported.
+    /// Requires the host event channel.
+    pub heartbeat_interval: u32,
+
+    /// The maximum number of VMSAs the guest may create through the SVSM
+    /// core protocol, or zero for no limit.
+    pub guest_vmsa_limit: u32,
+
+    /// The maximum number of SVSM protocol calls the guest may issue per
+    /// 2^20 TSC cycles, or zero for no limit.
+    pub guest_call_rate: u32,
+
+    /// The maximum number of pages the SVSM may allocate on behalf of the
+    /// guest, or zero for no limit.
+    pub guest_memory_limit: u32,
+
+    /// The maximum number of pages the SVSM may share with the host on
+    /// behalf of the guest, or zero for no limit.
+    pub guest_shared_limit: u32,
+
+    /// The maximum number of pages the SVSM may use for its own page
+    /// tables, or zero for no limit.
+    pub page_table_pool_limit: u32,
 
     /// The guest physical address of the base of the stage1 bootloader
     pub stage1_base: u64,
@@ -146,6 +201,16 @@ pub struct IgvmParamBlock {
 
     /// The value of vTOM used by the guest, or zero if not used.
     pub vtom: u64,
+
+    /// The guest physical address at which deferred guest memory starts,
+    /// or zero if no memory is deferred. Deferred memory is reported in the
+    /// guest memory map, but the SVSM does not prevalidate it and only
+    /// brings it online when the guest first uses it.
+    pub deferred_memory_base: u64,
+
+    /// The number of bytes of guest memory the SVSM validates before it
+    /// launches the guest when prevalidating guest memory.
+    pub prevalidation_baseline: u64,
 }
 
 /// The IGVM context page is a measured page that is used to specify the start
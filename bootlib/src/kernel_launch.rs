@@ -19,6 +19,12 @@ pub struct KernelLaunchInfo {
     pub heap_area_size: u64,
     pub kernel_region_virt_start: u64,
     pub heap_area_virt_start: u64, // Start of virtual heap area mapping.
+    /// Random offset of the per-CPU area from its base address.
+    pub percpu_base_slide: u64,
+    /// Random offset of the per-CPU stacks from their base address.
+    pub percpu_stacks_slide: u64,
+    /// Random offset of the task stacks from their base address.
+    pub pertask_stack_slide: u64,
     pub kernel_elf_stage2_virt_start: u64, // Virtual address of kernel ELF in Stage2 mapping.
     pub kernel_elf_stage2_virt_end: u64,
     pub kernel_fs_start: u64,
@@ -30,6 +36,8 @@ pub struct KernelLaunchInfo {
     pub igvm_params_phys_addr: u64,
     pub igvm_params_virt_addr: u64,
     pub vtom: u64,
+    /// TSC value at stage2 entry.
+    pub stage2_entry_tsc: u64,
     pub debug_serial_port: u16,
     pub use_alternate_injection: bool,
     pub platform_type: SvsmPlatformType,
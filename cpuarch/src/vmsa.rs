@@ -20,6 +20,7 @@ pub enum GuestVMExit {
     VINTR = 0x64,
     PAUSE = 0x77,
     HLT = 0x78,
+    MSR = 0x7C,
     SHUTDOWN = 0x7F,
     EFER_WRITE_TRAP = 0x8F,
     CR0_WRITE_TRAP = 0x90,
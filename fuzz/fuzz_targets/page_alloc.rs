@@ -15,6 +15,7 @@ use svsm::mm::alloc::{
     allocate_zeroed_page, free_page, get_order, TestRootMem,
 };
 use svsm::types::PAGE_SIZE;
+use svsm::utils::ByteSize;
 
 const WRITE_BYTE: u8 = 0x66;
 const POISON_BYTE: u8 = 0xfa;
@@ -98,7 +99,10 @@ fuzz_target!(|inp: FuzzInput| {
                 }
             }
             Action::AllocatePages(size) => {
-                if let Ok(page) = allocate_pages(get_order(size)) {
+                let Some(order) = get_order(ByteSize::new(size)) else {
+                    continue;
+                };
+                if let Ok(page) = allocate_pages(order) {
                     pages.push(page);
                 }
             }
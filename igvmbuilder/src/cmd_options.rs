@@ -28,6 +28,11 @@ pub struct CmdOptions {
     #[arg(short, long)]
     pub firmware: Option<String>,
 
+    /// Additional firmware blob to load at a fixed guest physical address,
+    /// given as FILE@GPA, e.g. vtpm.bin@0xffc00000. Can be repeated.
+    #[arg(long, value_parser = parse_extra_firmware)]
+    pub extra_firmware: Vec<ExtraFirmware>,
+
     /// Output filename for the generated IGVM file
     #[arg(short, long)]
     pub output: String,
@@ -75,6 +80,120 @@ pub struct CmdOptions {
     /// Use Alternate Injection if available
     #[arg(long, default_value_t = false)]
     pub alt_injection: bool,
+
+    /// Allow the guest to query the state of its memory as seen by the SVSM
+    #[arg(long, default_value_t = false)]
+    pub memory_state_queries: bool,
+
+    /// Place the SVSM heap and stacks at fixed addresses instead of
+    /// randomizing them at boot. Useful for debugging.
+    #[arg(long, default_value_t = false)]
+    pub no_layout_randomization: bool,
+
+    /// Doorbell I/O port of the host event channel. No event channel is
+    /// used if not specified.
+    #[arg(long)]
+    pub event_channel_port: Option<u16>,
+
+    /// Interrupt vector used by the host to signal the event channel
+    #[arg(long, default_value_t = 0xec)]
+    pub event_channel_vector: u8,
+
+    /// Size of the event channel area in pages (rounded up to a power of two)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=128))]
+    pub event_channel_pages: u8,
+
+    /// Number of pages shared with the host for communication buffers
+    /// (rounded up to a power of two). The SVSM picks a default size if not
+    /// specified.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=128))]
+    pub shared_pool_pages: Option<u8>,
+
+    /// Minimum interval between updates of the SVSM heartbeat page in units
+    /// of 2^20 TSC cycles. No heartbeat is reported if not specified.
+    /// Requires --event-channel-port.
+    #[arg(long, requires = "event_channel_port", value_parser = clap::value_parser!(u32).range(1..))]
+    pub heartbeat_interval: Option<u32>,
+
+    /// Maximum number of VMSAs the guest may create through the SVSM. Not
+    /// limited if not specified.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub guest_vmsa_limit: Option<u32>,
+
+    /// Maximum number of SVSM protocol calls the guest may issue per 2^20
+    /// TSC cycles. Not limited if not specified.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub guest_call_rate: Option<u32>,
+
+    /// Maximum number of pages the SVSM may allocate on behalf of the
+    /// guest. Not limited if not specified.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub guest_memory_limit: Option<u32>,
+
+    /// Maximum number of pages the SVSM may share with the host on behalf
+    /// of the guest. Not limited if not specified.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub guest_shared_limit: Option<u32>,
+
+    /// Maximum number of pages the SVSM may use for its own page tables.
+    /// Not limited if not specified.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub page_table_pool_limit: Option<u32>,
+
+    /// Number of consecutive times the SVSM schedules real-time tasks while
+    /// normal tasks are waiting to run. The SVSM picks a default if not
+    /// specified.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    pub rt_task_budget: Option<u8>,
+
+    /// Guest physical address from which guest memory is not brought
+    /// online at boot, but only when the guest first uses it. Such memory is
+    /// not prevalidated, which speeds up booting guests with large amounts
+    /// of memory.
+    #[arg(long, value_parser = parse_gpa)]
+    pub deferred_memory_base: Option<u64>,
+
+    /// Number of SVSM tasks validating guest memory in the background, so
+    /// that the guest does not have to. The guest must tolerate finding
+    /// its memory validated already.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=8))]
+    pub prevalidation_workers: Option<u8>,
+
+    /// Amount of guest memory in MiB the SVSM validates before it launches
+    /// the guest. Requires --prevalidation-workers.
+    #[arg(long, default_value_t = 0, requires = "prevalidation_workers")]
+    pub prevalidation_baseline_mb: u64,
+}
+
+/// A firmware blob loaded at a fixed guest physical address
+#[derive(Clone, Debug)]
+pub struct ExtraFirmware {
+    pub path: String,
+    pub gpa: u64,
+}
+
+fn parse_extra_firmware(arg: &str) -> Result<ExtraFirmware, String> {
+    let (path, gpa) = arg
+        .rsplit_once('@')
+        .ok_or_else(|| format!("expected FILE@GPA, got '{arg}'"))?;
+    let gpa = parse_gpa(gpa)?;
+    if path.is_empty() {
+        return Err("missing firmware file name".into());
+    }
+    Ok(ExtraFirmware {
+        path: path.to_string(),
+        gpa,
+    })
+}
+
+/// Parses a guest physical address in decimal, like the other numeric
+/// options, or in hex with a 0x prefix.
+fn parse_gpa(gpa: &str) -> Result<u64, String> {
+    match gpa.strip_prefix("0x").or_else(|| gpa.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => gpa.parse(),
+    }
+    .map_err(|e| format!("invalid GPA '{gpa}': {e}"))
 }
 
 impl CmdOptions {
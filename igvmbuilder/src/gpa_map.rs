@@ -47,6 +47,10 @@ impl GpaRange {
     pub fn get_size(&self) -> u64 {
         self.size
     }
+
+    fn overlaps(&self, other: &GpaRange) -> bool {
+        self.start < other.end && other.start < self.end
+    }
 }
 
 #[derive(Debug)]
@@ -64,8 +68,10 @@ pub struct GpaMap {
     pub general_params: GpaRange,
     pub memory_map: GpaRange,
     pub guest_context: GpaRange,
+    pub srat: GpaRange,
     pub kernel: GpaRange,
     pub vmsa: GpaRange,
+    pub extra_firmware: Vec<GpaRange>,
 }
 
 impl GpaMap {
@@ -83,7 +89,9 @@ impl GpaMap {
         //   0x1nnnnn-0x1nnnnn: filesystem
         //   0x1nnnnn-0x1nnnnn: IGVM parameter block
         //   0x1nnnnn-0x1nnnnn: general and memory map parameter pages
+        //   0x1nnnnn-0x1nnnnn: [guest context page +] SRAT parameter page
         //   0xFFnn0000-0xFFFFFFFF: [TDX stage 1 +] OVMF firmware (QEMU only, if specified)
+        //   Additional firmware blobs at the addresses given on the command line
 
         let stage1_image = if let Some(stage1) = &options.tdx_stage1 {
             if COMPATIBILITY_MASK.contains(TDP_COMPATIBILITY_MASK) {
@@ -164,6 +172,12 @@ impl GpaMap {
         } else {
             GpaRange::new(0, 0)?
         };
+        // The SRAT page ends the parameter area
+        let srat = if guest_context.get_size() != 0 {
+            GpaRange::new_page(guest_context.get_end())?
+        } else {
+            GpaRange::new_page(memory_map.get_end())?
+        };
 
         let vmsa = match options.hypervisor {
             Hypervisor::Qemu => {
@@ -173,7 +187,7 @@ impl GpaMap {
             Hypervisor::HyperV => GpaRange::new_page(kernel.end - PAGE_SIZE_4K)?,
         };
 
-        let gpa_map = Self {
+        let mut gpa_map = Self {
             stage1_image,
             low_memory: GpaRange::new(0, 0xf000)?,
             stage2_stack: GpaRange::new_page(0xf000)?,
@@ -187,15 +201,61 @@ impl GpaMap {
             general_params,
             memory_map,
             guest_context,
+            srat,
             kernel,
             vmsa,
+            extra_firmware: Vec::new(),
         };
+        gpa_map.add_extra_firmware(options, &firmware_range)?;
         if options.verbose {
             println!("GPA Map: {gpa_map:#X?}");
         }
         Ok(gpa_map)
     }
 
+    fn add_extra_firmware(
+        &mut self,
+        options: &CmdOptions,
+        firmware_range: &GpaRange,
+    ) -> Result<(), Box<dyn Error>> {
+        // Everything below 640K is used by stage 2 and its pages.
+        let used = [
+            GpaRange::new(0, 0xa0000)?,
+            *firmware_range,
+            self.stage1_image,
+            self.kernel_elf,
+            self.kernel_fs,
+            self.igvm_param_block,
+            self.general_params,
+            self.memory_map,
+            self.guest_context,
+            self.srat,
+            self.kernel,
+            self.vmsa,
+        ];
+
+        for fw in &options.extra_firmware {
+            let len = Self::get_metadata(&fw.path)?.len();
+            let range = GpaRange::new(fw.gpa, len).map_err(|e| {
+                eprintln!("Firmware {} is not loaded at a page-aligned GPA", fw.path);
+                e
+            })?;
+            if used
+                .iter()
+                .chain(self.extra_firmware.iter())
+                .any(|r| r.get_size() != 0 && r.overlaps(&range))
+            {
+                return Err(format!(
+                    "Firmware {} at {:#x} overlaps with another region",
+                    fw.path, fw.gpa
+                )
+                .into());
+            }
+            self.extra_firmware.push(range);
+        }
+        Ok(())
+    }
+
     pub fn get_metadata(path: &String) -> Result<std::fs::Metadata, Box<dyn Error>> {
         let meta = metadata(path).map_err(|e| {
             eprintln!("Failed to access {}", path);
@@ -38,7 +38,8 @@ pub static COMPATIBILITY_MASK: PlatformMask = PlatformMask::new();
 // Parameter area indices
 const IGVM_GENERAL_PARAMS_PA: u32 = 0;
 const IGVM_MEMORY_MAP_PA: u32 = 1;
-const IGVM_PARAMETER_COUNT: u32 = 2;
+const IGVM_SRAT_PA: u32 = 2;
+const IGVM_PARAMETER_COUNT: u32 = 3;
 
 const _: () = assert!(size_of::<IgvmParamBlock>() as u64 <= PAGE_SIZE_4K);
 const _: () = assert!(size_of::<IgvmGuestContext>() as u64 <= PAGE_SIZE_4K);
@@ -188,6 +189,10 @@ impl IgvmBuilder {
             )
         };
 
+        // The SRAT page follows all other parameter pages.
+        let srat_offset = param_area_size;
+        let param_area_size = srat_offset + PAGE_SIZE_4K as u32;
+
         // Populate the firmware metadata.
         let (fw_info, vtom) = if let Some(firmware) = &self.firmware {
             (firmware.get_fw_info(), firmware.get_vtom())
@@ -210,6 +215,7 @@ impl IgvmBuilder {
             param_page_offset,
             memory_map_offset,
             guest_context_offset,
+            srat_offset,
             cpuid_page: self.gpa_map.cpuid_page.get_start() as u32,
             secrets_page: self.gpa_map.secrets_page.get_start() as u32,
             debug_serial_port: self.options.get_port_address(),
@@ -224,6 +230,25 @@ impl IgvmBuilder {
                 true => 1,
                 false => 0,
             },
+            event_channel_port: self.options.event_channel_port.unwrap_or(0),
+            event_channel_vector: self.options.event_channel_vector,
+            event_channel_pages: self.options.event_channel_pages.next_power_of_two(),
+            heartbeat_interval: self.options.heartbeat_interval.unwrap_or(0),
+            guest_vmsa_limit: self.options.guest_vmsa_limit.unwrap_or(0),
+            guest_call_rate: self.options.guest_call_rate.unwrap_or(0),
+            guest_memory_limit: self.options.guest_memory_limit.unwrap_or(0),
+            guest_shared_limit: self.options.guest_shared_limit.unwrap_or(0),
+            page_table_pool_limit: self.options.page_table_pool_limit.unwrap_or(0),
+            shared_pool_pages: self
+                .options
+                .shared_pool_pages
+                .map_or(0, u8::next_power_of_two),
+            disable_layout_randomization: u8::from(self.options.no_layout_randomization),
+            rt_task_budget: self.options.rt_task_budget.unwrap_or(0),
+            memory_state_queries: u8::from(self.options.memory_state_queries),
+            deferred_memory_base: self.options.deferred_memory_base.unwrap_or(0),
+            prevalidation_workers: self.options.prevalidation_workers.unwrap_or(0),
+            prevalidation_baseline: self.options.prevalidation_baseline_mb << 20,
             ..Default::default()
         })
     }
@@ -324,6 +349,23 @@ impl IgvmBuilder {
                 parameter_area_index: IGVM_MEMORY_MAP_PA,
                 byte_offset: 0,
             }));
+        self.directives.push(IgvmDirectiveHeader::ParameterArea {
+            number_of_bytes: PAGE_SIZE_4K,
+            parameter_area_index: IGVM_SRAT_PA,
+            initial_data: vec![],
+        });
+        self.directives
+            .push(IgvmDirectiveHeader::Srat(IGVM_VHS_PARAMETER {
+                parameter_area_index: IGVM_SRAT_PA,
+                byte_offset: 0,
+            }));
+        self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+            IGVM_VHS_PARAMETER_INSERT {
+                gpa: self.gpa_map.srat.get_start(),
+                compatibility_mask: COMPATIBILITY_MASK.get(),
+                parameter_area_index: IGVM_SRAT_PA,
+            },
+        ));
         self.directives.push(IgvmDirectiveHeader::ParameterInsert(
             IGVM_VHS_PARAMETER_INSERT {
                 gpa: self.gpa_map.memory_map.get_start(),
@@ -375,6 +417,17 @@ impl IgvmBuilder {
             )?;
         }
 
+        // Add additional firmware blobs
+        for (fw, range) in self
+            .options
+            .extra_firmware
+            .clone()
+            .iter()
+            .zip(self.gpa_map.extra_firmware.clone())
+        {
+            self.add_data_pages_from_file(&fw.path, range.get_start(), COMPATIBILITY_MASK.get())?;
+        }
+
         // Add the kernel elf binary
         self.add_data_pages_from_file(
             &self.options.kernel.clone(),
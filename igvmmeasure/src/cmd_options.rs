@@ -50,9 +50,15 @@ pub enum Commands {
         #[arg(short, long)]
         ignore_idblock: bool,
 
-        /// Bare output only, consisting of just the digest as a hex string
+        /// Bare output only, consisting of just the digest in the selected
+        /// format
         #[arg(short, long)]
         bare: bool,
+
+        /// Format of the printed digest. The JSON format is always printed
+        /// without the surrounding banner.
+        #[arg(short, long, value_enum, default_value_t = DigestFormat::Hex)]
+        format: DigestFormat,
     },
     /// Measure the input file and generate a new output file containing a
     /// signature suitable for the target platform. For SEV-SNP this generates
@@ -81,6 +87,14 @@ pub enum Commands {
         #[arg(long)]
         author_key: Option<String>,
     },
+    /// Measure the input file and a second IGVM file and report the pages
+    /// that cause their launch measurements to differ. Exits with an error
+    /// if the measurements are not identical.
+    Compare {
+        /// The filename of the IGVM file to compare the input file with.
+        #[arg()]
+        other: String,
+    },
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -88,3 +102,13 @@ pub enum Platform {
     /// Calculate the launch measurement for SEV-SNP
     SevSnp,
 }
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum DigestFormat {
+    /// Upper-case hex string
+    Hex,
+    /// Standard base64 encoding with padding
+    Base64,
+    /// JSON object containing the platform and the hex digest
+    Json,
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+use igvm_defs::PAGE_SIZE_4K;
+
+use crate::igvm_measure::{IgvmMeasure, MeasuredPage, SnpPageType};
+use crate::utils::to_hex;
+
+/// How a page contributes differently to the two measurements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PageDiff {
+    /// Only measured in the first file
+    Removed(SnpPageType),
+    /// Only measured in the second file
+    Added(SnpPageType),
+    /// Measured as a different page type
+    TypeChanged(SnpPageType, SnpPageType),
+    /// Measured with different contents
+    ContentsChanged(SnpPageType),
+}
+
+impl fmt::Display for PageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageDiff::Removed(t) => write!(f, "only in first file ({} page)", t),
+            PageDiff::Added(t) => write!(f, "only in second file ({} page)", t),
+            PageDiff::TypeChanged(a, b) => write!(f, "page type changed from {} to {}", a, b),
+            PageDiff::ContentsChanged(t) => write!(f, "contents changed ({} page)", t),
+        }
+    }
+}
+
+/// A run of contiguous pages with the same difference
+#[derive(Debug)]
+struct DiffRange {
+    gpa: u64,
+    len: u64,
+    diff: PageDiff,
+}
+
+fn diff_pages(a: &[MeasuredPage], b: &[MeasuredPage]) -> Vec<DiffRange> {
+    let map_a: BTreeMap<u64, &MeasuredPage> = a.iter().map(|p| (p.gpa, p)).collect();
+    let map_b: BTreeMap<u64, &MeasuredPage> = b.iter().map(|p| (p.gpa, p)).collect();
+    let gpas: BTreeSet<u64> = map_a.keys().chain(map_b.keys()).copied().collect();
+
+    let mut ranges: Vec<DiffRange> = Vec::new();
+    for gpa in gpas {
+        let diff = match (map_a.get(&gpa), map_b.get(&gpa)) {
+            (Some(pa), None) => PageDiff::Removed(pa.page_type),
+            (None, Some(pb)) => PageDiff::Added(pb.page_type),
+            (Some(pa), Some(pb)) if pa.page_type != pb.page_type => {
+                PageDiff::TypeChanged(pa.page_type, pb.page_type)
+            }
+            (Some(pa), Some(pb)) if pa.contents != pb.contents => {
+                PageDiff::ContentsChanged(pa.page_type)
+            }
+            _ => continue,
+        };
+        match ranges.last_mut() {
+            Some(r) if r.diff == diff && r.gpa + r.len == gpa => r.len += PAGE_SIZE_4K,
+            _ => ranges.push(DiffRange {
+                gpa,
+                len: PAGE_SIZE_4K,
+                diff,
+            }),
+        }
+    }
+    ranges
+}
+
+/// Prints the launch digests of two measured files and the pages in which
+/// their measurements differ. Returns an error if the digests differ.
+pub fn compare_measurements(
+    name_a: &str,
+    a: &IgvmMeasure,
+    name_b: &str,
+    b: &IgvmMeasure,
+) -> Result<(), Box<dyn Error>> {
+    println!("{}: {}", name_a, to_hex(&a.digest()));
+    println!("{}: {}", name_b, to_hex(&b.digest()));
+    if a.digest() == b.digest() {
+        println!("Launch measurements are identical");
+        return Ok(());
+    }
+
+    let ranges = diff_pages(a.pages(), b.pages());
+    for r in ranges.iter() {
+        println!("gpa {:#x} len {:#x}: {}", r.gpa, r.len, r.diff);
+    }
+
+    // Identical pages still produce a different digest if they are
+    // measured in a different order.
+    if ranges.is_empty() {
+        match a
+            .pages()
+            .iter()
+            .zip(b.pages())
+            .position(|(pa, pb)| pa.gpa != pb.gpa)
+        {
+            Some(index) => println!(
+                "Pages are measured in a different order, starting at page {} (gpa {:#x} vs {:#x})",
+                index,
+                a.pages()[index].gpa,
+                b.pages()[index].gpa
+            ),
+            None => println!(
+                "Pages are measured a different number of times ({} vs {} pages)",
+                a.pages().len(),
+                b.pages().len()
+            ),
+        }
+    }
+
+    Err("Launch measurements differ".into())
+}
@@ -94,8 +94,8 @@ impl std::fmt::Debug for IgvmMeasureError {
 }
 impl Error for IgvmMeasureError {}
 
-#[derive(PartialEq, Debug)]
-enum SnpPageType {
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SnpPageType {
     None,
     Normal,
     Unmeasured,
@@ -123,6 +123,15 @@ impl std::fmt::Display for SnpPageType {
     }
 }
 
+/// A page that contributed to the launch digest, in measurement order
+#[derive(Clone, Debug)]
+pub struct MeasuredPage {
+    pub gpa: u64,
+    pub page_type: SnpPageType,
+    /// Hash of the page contents, zero for pages measured without contents
+    pub contents: [u8; 48],
+}
+
 #[derive(Debug)]
 pub struct IgvmMeasure {
     show_progress: bool,
@@ -136,6 +145,7 @@ pub struct IgvmMeasure {
     compatibility_mask: u32,
     vmsa_count: u32,
     id_block_ld: Option<[u8; 48]>,
+    pages: Vec<MeasuredPage>,
 }
 
 const PAGE_SIZE_2M: u64 = 2 * 1024 * 1024;
@@ -160,6 +170,7 @@ impl IgvmMeasure {
             compatibility_mask,
             vmsa_count: 0,
             id_block_ld: None,
+            pages: Vec::new(),
         };
         result.do_measure(igvm)?;
         Ok(result)
@@ -178,6 +189,20 @@ impl IgvmMeasure {
         self.digest
     }
 
+    /// All pages that contributed to the digest, in measurement order
+    pub fn pages(&self) -> &[MeasuredPage] {
+        &self.pages
+    }
+
+    fn extend_digest(&mut self, page_type: SnpPageType, page_info: PageInfo) {
+        self.digest = page_info.update_hash();
+        self.pages.push(MeasuredPage {
+            gpa: page_info.gpa(),
+            page_type,
+            contents: page_info.contents(),
+        });
+    }
+
     fn do_measure(&mut self, igvm: &IgvmFile) -> Result<(), Box<dyn Error>> {
         for directive in igvm.directives() {
             match directive {
@@ -290,41 +315,43 @@ impl IgvmMeasure {
         let page_info = match data_type {
             IgvmPageDataType::NORMAL => {
                 if flags.unmeasured() {
-                    self.log_page(SnpPageType::Unmeasured, gpa, PAGE_SIZE_4K);
-                    Some(PageInfo::new_unmeasured_page(self.digest, gpa))
+                    Some((
+                        SnpPageType::Unmeasured,
+                        PageInfo::new_unmeasured_page(self.digest, gpa),
+                    ))
                 } else if data.is_empty() {
                     if self.native_zero {
-                        self.log_page(SnpPageType::Zero, gpa, PAGE_SIZE_4K);
-                        Some(PageInfo::new_zero_page(self.digest, gpa))
+                        Some((SnpPageType::Zero, PageInfo::new_zero_page(self.digest, gpa)))
                     } else {
-                        self.log_page(SnpPageType::Normal, gpa, PAGE_SIZE_4K);
-                        Some(PageInfo::new_normal_page(
-                            self.digest,
-                            gpa,
-                            &vec![0u8; PAGE_SIZE_4K as usize],
+                        Some((
+                            SnpPageType::Normal,
+                            PageInfo::new_normal_page(
+                                self.digest,
+                                gpa,
+                                &vec![0u8; PAGE_SIZE_4K as usize],
+                            ),
                         ))
                     }
                 } else {
-                    self.log_page(SnpPageType::Normal, gpa, data.len() as u64);
-                    Some(PageInfo::new_normal_page(self.digest, gpa, data))
+                    Some((
+                        SnpPageType::Normal,
+                        PageInfo::new_normal_page(self.digest, gpa, data),
+                    ))
                 }
             }
-            IgvmPageDataType::SECRETS => {
-                self.log_page(SnpPageType::Secrets, gpa, PAGE_SIZE_4K);
-                Some(PageInfo::new_secrets_page(self.digest, gpa))
-            }
-            IgvmPageDataType::CPUID_DATA => {
-                self.log_page(SnpPageType::CpuId, gpa, PAGE_SIZE_4K);
-                Some(PageInfo::new_cpuid_page(self.digest, gpa))
-            }
-            IgvmPageDataType::CPUID_XF => {
-                self.log_page(SnpPageType::CpuId, gpa, PAGE_SIZE_4K);
-                Some(PageInfo::new_cpuid_page(self.digest, gpa))
-            }
+            IgvmPageDataType::SECRETS => Some((
+                SnpPageType::Secrets,
+                PageInfo::new_secrets_page(self.digest, gpa),
+            )),
+            IgvmPageDataType::CPUID_DATA | IgvmPageDataType::CPUID_XF => Some((
+                SnpPageType::CpuId,
+                PageInfo::new_cpuid_page(self.digest, gpa),
+            )),
             _ => None,
         };
-        if let Some(page_info) = page_info {
-            self.digest = page_info.update_hash();
+        if let Some((page_type, page_info)) = page_info {
+            self.log_page(page_type, gpa, PAGE_SIZE_4K);
+            self.extend_digest(page_type, page_info);
         }
     }
 
@@ -358,7 +385,7 @@ impl IgvmMeasure {
         vmsa_page.resize(PAGE_SIZE_4K as usize, 0);
         self.log_page(SnpPageType::Vmsa, gpa, PAGE_SIZE_4K);
         let page_info = PageInfo::new_vmsa_page(self.digest, gpa, &vmsa_page);
-        self.digest = page_info.update_hash();
+        self.extend_digest(SnpPageType::Vmsa, page_info);
         self.vmsa_count += 1;
 
         Ok(())
@@ -9,16 +9,18 @@ use std::fs::{self, File};
 use std::io::Write;
 
 use clap::Parser;
-use cmd_options::{CmdOptions, Commands};
+use cmd_options::{CmdOptions, Commands, DigestFormat};
 use igvm::IgvmFile;
 use igvm_defs::IgvmPlatformType;
 use igvm_measure::IgvmMeasure;
-use utils::get_compatibility_mask;
+use utils::{get_compatibility_mask, to_base64, to_hex};
 use zerocopy::AsBytes;
 
+use crate::compare::compare_measurements;
 use crate::id_block::SevIdBlockBuilder;
 
 mod cmd_options;
+mod compare;
 mod id_block;
 mod igvm_measure;
 mod page_info;
@@ -27,8 +29,34 @@ mod utils;
 fn main() -> Result<(), Box<dyn Error>> {
     let options = CmdOptions::parse();
 
-    let igvm_buffer = fs::read(&options.input).map_err(|e| {
-        eprintln!("Failed to open firmware file {}", options.input);
+    let (igvm, measure) = load_and_measure(&options, &options.input)?;
+
+    match options.command {
+        Commands::Measure {
+            ignore_idblock,
+            bare,
+            format,
+        } => measure_command(&options, ignore_idblock, bare, format, &measure)?,
+        Commands::Sign {
+            output,
+            id_key,
+            author_key,
+        } => sign_command(&output, &id_key, &author_key, &igvm, &measure)?,
+        Commands::Compare { ref other } => {
+            let (_, other_measure) = load_and_measure(&options, other)?;
+            compare_measurements(&options.input, &measure, other, &other_measure)?
+        }
+    }
+
+    Ok(())
+}
+
+fn load_and_measure(
+    options: &CmdOptions,
+    filename: &str,
+) -> Result<(IgvmFile, IgvmMeasure), Box<dyn Error>> {
+    let igvm_buffer = fs::read(filename).map_err(|e| {
+        eprintln!("Failed to open firmware file {}", filename);
         e
     })?;
     let igvm = IgvmFile::new_from_binary(igvm_buffer.as_bytes(), None)?;
@@ -43,28 +71,19 @@ fn main() -> Result<(), Box<dyn Error>> {
         compatibility_mask,
         &igvm,
     )?;
-
-    match options.command {
-        Commands::Measure {
-            ignore_idblock,
-            bare,
-        } => measure_command(&options, ignore_idblock, bare, &measure)?,
-        Commands::Sign {
-            output,
-            id_key,
-            author_key,
-        } => sign_command(&output, &id_key, &author_key, &igvm, &measure)?,
-    }
-
-    Ok(())
+    Ok((igvm, measure))
 }
 
 fn measure_command(
     options: &CmdOptions,
     ignore_idblock: bool,
     bare: bool,
+    format: DigestFormat,
     measure: &IgvmMeasure,
 ) -> Result<(), Box<dyn Error>> {
+    let digest = measure.digest();
+    let bare = bare || format == DigestFormat::Json;
+
     if !bare {
         println!(
             "\n==============================================================================================================="
@@ -72,11 +91,14 @@ fn measure_command(
         print!("igvmmeasure '{}'\nLaunch Digest: ", options.input);
     }
 
-    measure
-        .digest()
-        .iter()
-        .for_each(|val| print!("{:02X}", val));
-    println!();
+    match format {
+        DigestFormat::Hex => println!("{}", to_hex(&digest)),
+        DigestFormat::Base64 => println!("{}", to_base64(&digest)),
+        DigestFormat::Json => println!(
+            "{{\"platform\": \"sev-snp\", \"launch_digest\": \"{}\"}}",
+            to_hex(&digest)
+        ),
+    }
 
     if !bare {
         println!(
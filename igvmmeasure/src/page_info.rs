@@ -95,6 +95,14 @@ impl PageInfo {
         }
     }
 
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    pub fn contents(&self) -> [u8; 48] {
+        self.contents
+    }
+
     pub fn update_hash(&self) -> [u8; 48] {
         Hash::hash(self.as_bytes())
     }
@@ -6,9 +6,11 @@
 
 extern crate alloc;
 
+use crate::address::PhysAddr;
 use crate::error::SvsmError;
 use crate::fw_cfg::FwCfg;
 use crate::string::FixedString;
+use crate::utils::MemoryRegion;
 use alloc::vec::Vec;
 use core::mem;
 
@@ -533,3 +535,178 @@ pub fn load_acpi_cpu_info(fw_cfg: &FwCfg<'_>) -> Result<Vec<ACPICPUInfo>, SvsmEr
 
     Ok(cpus)
 }
+
+/// Offset of the first entry in the SRAT, after the ACPI table header and
+/// 12 reserved bytes
+const SRAT_ENTRIES_OFFSET: usize = 48;
+
+/// Memory range belonging to a NUMA node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ACPIMemoryAffinity {
+    /// ACPI proximity domain of the memory
+    pub node: u32,
+    pub region: MemoryRegion<PhysAddr>,
+}
+
+/// NUMA node of a CPU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ACPICPUAffinity {
+    pub apic_id: u32,
+    /// ACPI proximity domain of the CPU
+    pub node: u32,
+}
+
+/// NUMA topology described by the System Resource Affinity Table (SRAT).
+/// Entries which are not enabled are left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ACPINumaInfo {
+    pub memory: Vec<ACPIMemoryAffinity>,
+    pub cpus: Vec<ACPICPUAffinity>,
+}
+
+impl ACPINumaInfo {
+    pub const fn new() -> Self {
+        Self {
+            memory: Vec::new(),
+            cpus: Vec::new(),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Parses an SRAT from `data`, which must start with the ACPI table header
+/// and may extend beyond the end of the table.
+///
+/// # Errors
+///
+/// Returns [`SvsmError::Acpi`] if `data` does not hold a valid SRAT.
+pub fn parse_srat(data: &[u8]) -> Result<ACPINumaInfo, SvsmError> {
+    if data.len() < SRAT_ENTRIES_OFFSET || &data[..4] != b"SRAT" {
+        return Err(SvsmError::Acpi);
+    }
+    let len = usize::try_from(read_u32(data, 4)).map_err(|_| SvsmError::Acpi)?;
+    let table = data.get(..len).ok_or(SvsmError::Acpi)?;
+    if len < SRAT_ENTRIES_OFFSET || table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+        return Err(SvsmError::Acpi);
+    }
+
+    let mut info = ACPINumaInfo::new();
+    let mut offset = SRAT_ENTRIES_OFFSET;
+    while offset + 2 <= len {
+        let (entry_type, entry_len) = (table[offset], usize::from(table[offset + 1]));
+        let entry = table
+            .get(offset..offset + entry_len)
+            .ok_or(SvsmError::Acpi)?;
+        match entry_type {
+            // Processor Local APIC/SAPIC Affinity
+            0 if entry_len == 16 => {
+                let node = u32::from(entry[2]) | (read_u32(entry, 8) & !0xff);
+                if read_u32(entry, 4) & 1 != 0 {
+                    info.cpus.push(ACPICPUAffinity {
+                        apic_id: u32::from(entry[3]),
+                        node,
+                    });
+                }
+            }
+            // Memory Affinity
+            1 if entry_len == 40 => {
+                let base = usize::try_from(read_u64(entry, 8)).map_err(|_| SvsmError::Acpi)?;
+                let size = usize::try_from(read_u64(entry, 16)).map_err(|_| SvsmError::Acpi)?;
+                if read_u32(entry, 28) & 1 != 0 && size != 0 {
+                    info.memory.push(ACPIMemoryAffinity {
+                        node: read_u32(entry, 2),
+                        region: MemoryRegion::checked_new(PhysAddr::from(base), size)
+                            .ok_or(SvsmError::Acpi)?,
+                    });
+                }
+            }
+            // Processor Local x2APIC Affinity
+            2 if entry_len == 24 => {
+                if read_u32(entry, 12) & 1 != 0 {
+                    info.cpus.push(ACPICPUAffinity {
+                        apic_id: read_u32(entry, 8),
+                        node: read_u32(entry, 4),
+                    });
+                }
+            }
+            _ if entry_len == 0 => {
+                log::warn!(
+                    "Found zero-length SRAT entry with type {}, stopping",
+                    entry_type
+                );
+                break;
+            }
+            _ => {
+                log::info!("Ignoring SRAT entry with type {}", entry_type);
+            }
+        }
+        offset += entry_len;
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_srat() -> Vec<u8> {
+        let mut srat = Vec::new();
+        srat.extend_from_slice(b"SRAT");
+        srat.extend_from_slice(&[0; SRAT_ENTRIES_OFFSET - 4]);
+        // x2APIC 3 on node 1
+        let mut cpu = [0u8; 24];
+        cpu[0] = 2;
+        cpu[1] = 24;
+        cpu[4..8].copy_from_slice(&1u32.to_le_bytes());
+        cpu[8..12].copy_from_slice(&3u32.to_le_bytes());
+        cpu[12..16].copy_from_slice(&1u32.to_le_bytes());
+        srat.extend_from_slice(&cpu);
+        // 1 GiB at 4 GiB on node 1, and a disabled entry
+        for flags in [1u32, 0] {
+            let mut mem = [0u8; 40];
+            mem[0] = 1;
+            mem[1] = 40;
+            mem[2..6].copy_from_slice(&1u32.to_le_bytes());
+            mem[8..16].copy_from_slice(&(4u64 << 30).to_le_bytes());
+            mem[16..24].copy_from_slice(&(1u64 << 30).to_le_bytes());
+            mem[28..32].copy_from_slice(&flags.to_le_bytes());
+            srat.extend_from_slice(&mem);
+        }
+        let len = srat.len() as u32;
+        srat[4..8].copy_from_slice(&len.to_le_bytes());
+        let sum = srat.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        srat[9] = sum.wrapping_neg();
+        srat
+    }
+
+    #[test]
+    fn srat() {
+        let mut data = test_srat();
+        // Trailing bytes after the table are ignored
+        data.extend_from_slice(&[0xff; 16]);
+        let info = parse_srat(&data).unwrap();
+        assert_eq!(
+            info.cpus,
+            [ACPICPUAffinity {
+                apic_id: 3,
+                node: 1
+            }]
+        );
+        assert_eq!(info.memory.len(), 1);
+        assert_eq!(info.memory[0].node, 1);
+        assert_eq!(info.memory[0].region.start(), PhysAddr::from(4u64 << 30));
+        assert_eq!(info.memory[0].region.len(), 1 << 30);
+
+        data[20] ^= 1;
+        assert!(parse_srat(&data).is_err());
+        assert!(parse_srat(&[0u8; 64]).is_err());
+    }
+}
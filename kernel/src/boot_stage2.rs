@@ -247,6 +247,7 @@ global_asm!(
         .quad 0x00cf92000000ffff /* 64 bit data segment */
     gdt64_end:
 
+        .globl gdt64_desc
     gdt64_desc:
         .word gdt64_end - gdt64 - 1
         .quad gdt64
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Timestamps of the boot phases of the SVSM.
+//!
+//! Each [`BootPhase`] is recorded with [`boot_phase_reached()`] the first
+//! time it is passed. Stage2 records its entry itself and hands the
+//! timestamp over in the launch info. Once the request loop starts, the
+//! timestamps are logged with [`log_boot_phases()`], and the heartbeat page
+//! reports them to the host, so that boot-time regressions can be measured
+//! on every deployment.
+
+use crate::time::now;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Boot phases in the order they are reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum BootPhase {
+    /// Stage2 started executing
+    Stage2Entry = 0,
+    /// The kernel started executing
+    KernelEntry,
+    /// The kernel page table is set up
+    PagingUp,
+    /// All secondary CPUs are running
+    SmpDone,
+    /// Protocols and host services are set up
+    ServicesUp,
+    /// The guest firmware was launched
+    GuestLaunched,
+}
+
+/// Number of [`BootPhase`]s
+pub const BOOT_PHASES: usize = 6;
+
+impl BootPhase {
+    pub const ALL: [BootPhase; BOOT_PHASES] = [
+        BootPhase::Stage2Entry,
+        BootPhase::KernelEntry,
+        BootPhase::PagingUp,
+        BootPhase::SmpDone,
+        BootPhase::ServicesUp,
+        BootPhase::GuestLaunched,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            BootPhase::Stage2Entry => "stage2 entry",
+            BootPhase::KernelEntry => "kernel entry",
+            BootPhase::PagingUp => "paging up",
+            BootPhase::SmpDone => "SMP done",
+            BootPhase::ServicesUp => "services up",
+            BootPhase::GuestLaunched => "guest launched",
+        }
+    }
+}
+
+/// TSC values at which each phase was reached, zero if it was not
+#[derive(Debug)]
+struct BootTimes {
+    timestamps: [AtomicU64; BOOT_PHASES],
+}
+
+impl BootTimes {
+    const fn new() -> Self {
+        Self {
+            timestamps: [const { AtomicU64::new(0) }; BOOT_PHASES],
+        }
+    }
+
+    /// Records `timestamp` for `phase` unless one was recorded already
+    fn record(&self, phase: BootPhase, timestamp: u64) {
+        let _ = self.timestamps[phase as usize].compare_exchange(
+            0,
+            timestamp,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn get(&self, phase: BootPhase) -> Option<u64> {
+        let timestamp = self.timestamps[phase as usize].load(Ordering::Relaxed);
+        (timestamp != 0).then_some(timestamp)
+    }
+
+    fn snapshot(&self) -> [u64; BOOT_PHASES] {
+        BootPhase::ALL.map(|phase| self.get(phase).unwrap_or(0))
+    }
+
+    /// Cycles between `phase` and the last phase reached before it, if
+    /// both were recorded
+    fn delta(&self, phase: BootPhase) -> Option<u64> {
+        let timestamp = self.get(phase)?;
+        let prev = BootPhase::ALL[..phase as usize]
+            .iter()
+            .rev()
+            .find_map(|prev| self.get(*prev))?;
+        Some(timestamp.saturating_sub(prev))
+    }
+}
+
+static BOOT_TIMES: BootTimes = BootTimes::new();
+
+/// Records the current time for `phase`, unless it was recorded before
+pub fn boot_phase_reached(phase: BootPhase) {
+    BOOT_TIMES.record(phase, now());
+}
+
+/// Records `timestamp` for `phase`, e.g. one taken by stage2
+pub fn boot_phase_reached_at(phase: BootPhase, timestamp: u64) {
+    BOOT_TIMES.record(phase, timestamp);
+}
+
+/// TSC value at which `phase` was reached, if it was
+pub fn boot_phase_timestamp(phase: BootPhase) -> Option<u64> {
+    BOOT_TIMES.get(phase)
+}
+
+/// TSC values of all phases, in the order of [`BootPhase::ALL`], with zero
+/// for phases which were not reached
+pub fn boot_phase_timestamps() -> [u64; BOOT_PHASES] {
+    BOOT_TIMES.snapshot()
+}
+
+pub fn log_boot_phases() {
+    log::info!("Boot phases (TSC cycles):");
+    for phase in BootPhase::ALL {
+        match (BOOT_TIMES.get(phase), BOOT_TIMES.delta(phase)) {
+            (Some(timestamp), Some(delta)) => {
+                log::info!("  {:<16} {:>20} (+{})", phase.name(), timestamp, delta)
+            }
+            (Some(timestamp), None) => log::info!("  {:<16} {:>20}", phase.name(), timestamp),
+            (None, _) => log::info!("  {:<16} {:>20}", phase.name(), "-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        for (i, phase) in BootPhase::ALL.iter().enumerate() {
+            assert_eq!(*phase as usize, i);
+        }
+    }
+
+    #[test]
+    fn record_and_delta() {
+        let times = BootTimes::new();
+        times.record(BootPhase::Stage2Entry, 100);
+        times.record(BootPhase::KernelEntry, 250);
+        // Only the first time a phase is reached counts
+        times.record(BootPhase::KernelEntry, 300);
+        times.record(BootPhase::SmpDone, 1000);
+
+        assert_eq!(times.get(BootPhase::KernelEntry), Some(250));
+        assert_eq!(times.get(BootPhase::PagingUp), None);
+        assert_eq!(times.delta(BootPhase::Stage2Entry), None);
+        assert_eq!(times.delta(BootPhase::KernelEntry), Some(150));
+        // Phases which were not reached are skipped
+        assert_eq!(times.delta(BootPhase::SmpDone), Some(750));
+        assert_eq!(times.delta(BootPhase::GuestLaunched), None);
+        assert_eq!(times.snapshot(), [100, 250, 0, 1000, 0, 0]);
+    }
+}
@@ -8,14 +8,19 @@ extern crate alloc;
 
 use core::slice;
 
-use crate::acpi::tables::{load_acpi_cpu_info, ACPICPUInfo};
+use crate::acpi::tables::{load_acpi_cpu_info, ACPICPUInfo, ACPINumaInfo};
 use crate::address::PhysAddr;
 use crate::error::SvsmError;
+use crate::event_channel::EventChannelParams;
 use crate::fw_cfg::FwCfg;
 use crate::fw_meta::{parse_fw_meta_data, SevFWMetaData};
 use crate::igvm_params::IgvmParams;
+use crate::mm::shared_pool::DEFAULT_SHARED_POOL_PAGES;
 use crate::mm::{PerCPUPageMappingGuard, PAGE_SIZE, SIZE_1G};
+use crate::prevalidate::PrevalidationParams;
+use crate::protocols::accounting::VmplLimits;
 use crate::serial::SERIAL_PORT;
+use crate::task::DEFAULT_RT_BUDGET;
 use crate::utils::MemoryRegion;
 use alloc::vec::Vec;
 use cpuarch::vmsa::VMSA;
@@ -95,6 +100,14 @@ impl SvsmConfig<'_> {
             SvsmConfig::IgvmConfig(igvm_params) => igvm_params.get_memory_regions(),
         }
     }
+    /// Returns the memory ranges assigned to the SVSM in addition to the
+    /// kernel region. IGVM launches always provide a single range.
+    pub fn get_extra_svsm_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+        match self {
+            SvsmConfig::FirmwareConfig(fw_cfg) => fw_cfg.get_extra_svsm_regions(),
+            SvsmConfig::IgvmConfig(_) => Ok(Vec::new()),
+        }
+    }
     pub fn write_guest_memory_map(&self, map: &[MemoryRegion<PhysAddr>]) -> Result<(), SvsmError> {
         match self {
             SvsmConfig::FirmwareConfig(_) => Ok(()),
@@ -113,6 +126,13 @@ impl SvsmConfig<'_> {
             SvsmConfig::IgvmConfig(igvm_params) => igvm_params.load_cpu_info(),
         }
     }
+    /// Returns the NUMA topology of the guest, if it is known.
+    pub fn load_numa_info(&self) -> Option<ACPINumaInfo> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.load_numa_info(),
+        }
+    }
     pub fn should_launch_fw(&self) -> bool {
         match self {
             SvsmConfig::FirmwareConfig(_) => true,
@@ -191,4 +211,85 @@ impl SvsmConfig<'_> {
             SvsmConfig::IgvmConfig(igvm_params) => igvm_params.use_alternate_injection(),
         }
     }
+
+    /// Whether the heap and stack base addresses are randomized at boot
+    pub fn layout_randomization(&self) -> bool {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => true,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.layout_randomization(),
+        }
+    }
+
+    pub fn event_channel(&self) -> Option<EventChannelParams> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.event_channel(),
+        }
+    }
+
+    /// Background prevalidation of guest memory, if configured
+    pub fn prevalidation(&self) -> Option<PrevalidationParams> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.prevalidation(),
+        }
+    }
+
+    /// Number of pages in the shared memory pool, or the default size
+    pub fn shared_pool_pages(&self) -> usize {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.shared_pool_pages(),
+        }
+        .unwrap_or(DEFAULT_SHARED_POOL_PAGES)
+    }
+
+    /// Start of guest memory which is brought online on first use instead
+    /// of at boot, if any
+    pub fn deferred_memory_base(&self) -> Option<PhysAddr> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.deferred_memory_base(),
+        }
+    }
+
+    /// Whether lower VMPLs may use the memory state protocol
+    pub fn memory_state_queries(&self) -> bool {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => false,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.memory_state_queries(),
+        }
+    }
+
+    /// Number of consecutive real-time task switches while normal tasks are
+    /// waiting, or the default budget
+    pub fn rt_task_budget(&self) -> u32 {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.rt_task_budget(),
+        }
+        .unwrap_or(DEFAULT_RT_BUDGET)
+    }
+
+    pub fn heartbeat_interval(&self) -> Option<u64> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.heartbeat_interval(),
+        }
+    }
+
+    pub fn page_table_pool_limit(&self) -> Option<usize> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.page_table_pool_limit(),
+        }
+    }
+
+    /// Caps on the resources the guest VMPL may use through SVSM protocols
+    pub fn guest_vmpl_limits(&self) -> VmplLimits {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => VmplLimits::unlimited(),
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.guest_vmpl_limits(),
+        }
+    }
 }
@@ -4,10 +4,17 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::locking::SpinLock;
+extern crate alloc;
+
+use crate::cpu::percpu::in_nmi;
+use crate::locking::{RWLock, SpinLock};
 use crate::serial::{Terminal, DEFAULT_SERIAL_PORT};
 use crate::utils::immut_after_init::{ImmutAfterInitCell, ImmutAfterInitResult};
+use alloc::string::String;
 use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::LevelFilter;
 
 #[derive(Clone, Copy)]
 struct Console {
@@ -47,6 +54,14 @@ pub fn _print(args: fmt::Arguments<'_>) {
     if !*CONSOLE_INITIALIZED {
         return;
     }
+    // The interrupted code may be in the middle of printing. Dropping the
+    // output is better than deadlocking.
+    if in_nmi() {
+        if let Some(mut writer) = WRITER.try_lock() {
+            writer.write_fmt(args).unwrap();
+        }
+        return;
+    }
     WRITER.lock().write_fmt(args).unwrap();
 }
 
@@ -68,9 +83,116 @@ impl ConsoleLogger {
     }
 }
 
+/// Maximum number of per-module log level overrides
+const MAX_MODULE_FILTERS: usize = 16;
+
+#[derive(Debug)]
+struct LogFilters {
+    /// Level for modules without an override
+    global: LevelFilter,
+    /// Per-module overrides, keyed by module path. A fixed array, so that
+    /// changing the overrides does not allocate under the lock.
+    modules: [Option<(String, LevelFilter)>; MAX_MODULE_FILTERS],
+}
+
+impl LogFilters {
+    /// Returns the level for `target`, using the override of the most
+    /// specific module path containing it, if any.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.global, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .map(|(_, level)| *level)
+            .fold(self.global, LevelFilter::max)
+    }
+}
+
+/// Readers never wait for this lock, see [`ConsoleLogger::enabled()`].
+static LOG_FILTERS: RWLock<LogFilters> = RWLock::new(LogFilters {
+    global: LevelFilter::Trace,
+    modules: [const { None }; MAX_MODULE_FILTERS],
+});
+/// Set while any per-module override is installed, so that the common case
+/// does not need to take the filter lock for every record.
+static HAS_MODULE_FILTERS: AtomicBool = AtomicBool::new(false);
+
+/// Set the log level for all modules without a per-module override. Levels
+/// above the one selected through the `log` crate features at build time
+/// have no effect.
+pub fn set_log_level(level: LevelFilter) {
+    let mut filters = LOG_FILTERS.lock_write();
+    filters.global = level;
+    log::set_max_level(filters.max_level());
+}
+
+/// Set the log level for `module` and all its submodules, or remove the
+/// override for `module` if `level` is `None`. Returns `false` if the
+/// maximum number of overrides is already installed or memory runs out.
+pub fn set_module_log_level(module: &str, level: Option<LevelFilter>) -> bool {
+    // Allocate the new override before and free the old one after taking
+    // the lock. Declared before the guard, so it is dropped after it.
+    let new = match level {
+        Some(level) => {
+            let mut name = String::new();
+            if name.try_reserve_exact(module.len()).is_err() {
+                return false;
+            }
+            name.push_str(module);
+            Some((name, level))
+        }
+        None => None,
+    };
+    let mut filters = LOG_FILTERS.lock_write();
+    let slot = filters
+        .modules
+        .iter()
+        .position(|filter| filter.as_ref().is_some_and(|(m, _)| m == module))
+        .or_else(|| {
+            new.as_ref()
+                .and(filters.modules.iter().position(Option::is_none))
+        });
+    let Some(slot) = slot else {
+        // Either there is nothing to remove or all slots are taken
+        return new.is_none();
+    };
+
+    let old = mem::replace(&mut filters.modules[slot], new);
+    HAS_MODULE_FILTERS.store(
+        filters.modules.iter().any(Option::is_some),
+        Ordering::Relaxed,
+    );
+    log::set_max_level(filters.max_level());
+    drop(filters);
+    drop(old);
+    true
+}
+
 impl log::Log for ConsoleLogger {
-    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        // Without overrides the global level is enforced by the log crate
+        // through log::max_level().
+        if !HAS_MODULE_FILTERS.load(Ordering::Relaxed) {
+            return true;
+        }
+        // Records can come from interrupt handlers which interrupted a
+        // writer on the same CPU, so never wait for the lock. Records are
+        // not filtered by module while the filters are being changed.
+        LOG_FILTERS
+            .try_lock_read()
+            .is_none_or(|filters| metadata.level() <= filters.level_for(metadata.target()))
     }
 
     fn log(&self, record: &log::Record<'_>) {
@@ -136,3 +258,27 @@ macro_rules! println {
     () => (log::info!(""));
     ($($arg:tt)*) => (log::info!($($arg)*));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_filter_lookup() {
+        let mut filters = LogFilters {
+            global: LevelFilter::Info,
+            modules: [const { None }; MAX_MODULE_FILTERS],
+        };
+        filters.modules[0] = Some((String::from("svsm::mm"), LevelFilter::Warn));
+        filters.modules[2] = Some((String::from("svsm::mm::alloc"), LevelFilter::Trace));
+        filters.modules[3] = Some((String::from("svsm::sev"), LevelFilter::Off));
+        assert_eq!(filters.level_for("svsm::mm"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("svsm::mm::vm"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("svsm::mm::alloc"), LevelFilter::Trace);
+        assert_eq!(filters.level_for("svsm::sev::ghcb"), LevelFilter::Off);
+        // Only whole path components match
+        assert_eq!(filters.level_for("svsm::mmio"), LevelFilter::Info);
+        assert_eq!(filters.level_for("svsm::cpu"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+    }
+}
@@ -68,6 +68,11 @@ pub fn dump_exception_table() {
     }
 }
 
+/// Returns whether faults at `rip` are covered by the exception table
+pub fn in_exception_table(rip: VirtAddr) -> bool {
+    check_exception_table(rip) != rip
+}
+
 pub fn handle_exception_table(ctx: &mut X86ExceptionContext) -> bool {
     let ex_rip = VirtAddr::from(ctx.frame.rip);
     let new_rip = check_exception_table(ex_rip);
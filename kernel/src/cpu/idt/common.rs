@@ -41,6 +41,7 @@ pub const VC_VECTOR: usize = 29;
 pub const SX_VECTOR: usize = 30;
 
 pub const PF_ERROR_WRITE: usize = 2;
+pub const PF_ERROR_RMP: usize = 1 << 31;
 
 pub const INT_INJ_VECTOR: usize = 0x50;
 
@@ -4,14 +4,28 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::common::{idt_mut, DF_VECTOR, HV_VECTOR, VC_VECTOR};
+use super::common::{idt_mut, DF_VECTOR, HV_VECTOR, PF_VECTOR, VC_VECTOR};
 use crate::cpu::control_regs::read_cr2;
 use crate::cpu::vc::{stage2_handle_vc_exception, stage2_handle_vc_exception_no_ghcb};
 use crate::cpu::X86ExceptionContext;
+use crate::sev::msr_protocol::{
+    request_termination_msr_reason, TERM_REASON_SET_SVSM, TERM_REASON_SVSM_STAGE2_EXCEPTION,
+};
+use crate::utils::halt;
+use bootlib::platform::SvsmPlatformType;
 use core::arch::global_asm;
 use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub fn early_idt_init_no_ghcb() {
+/// Whether fatal exceptions are reported to the hypervisor through the GHCB
+/// MSR protocol
+static MSR_TERMINATION: AtomicBool = AtomicBool::new(false);
+
+pub fn early_idt_init_no_ghcb(platform_type: SvsmPlatformType) {
+    MSR_TERMINATION.store(
+        matches!(platform_type, SvsmPlatformType::Snp),
+        Ordering::Relaxed,
+    );
     unsafe {
         let mut idt = idt_mut();
         idt.init(addr_of!(stage2_idt_handler_array_no_ghcb), 32);
@@ -27,57 +41,84 @@ pub fn early_idt_init() {
     }
 }
 
+/// Reports an exception stage 2 cannot handle and terminates. The dump only
+/// reaches the serial port once the console is set up, so on SEV-SNP the
+/// vector is also encoded in the termination reason code, which makes early
+/// failures distinguishable from resets on the host side.
+fn stage2_fatal_exception(ctx: &X86ExceptionContext, vector: usize) -> ! {
+    let regs = ctx.regs;
+    let frame = ctx.frame;
+    let err = ctx.error_code;
+
+    log::error!(
+        "Unhandled exception {} in stage 2 at RIP {:#018x} error code: {:#018x}",
+        vector,
+        { frame.rip },
+        err
+    );
+    if vector == PF_VECTOR || vector == DF_VECTOR {
+        log::error!("CR2: {:#018x}", read_cr2());
+    }
+    log::error!(
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}",
+        { regs.rax },
+        { regs.rbx },
+        { regs.rcx },
+        { regs.rdx }
+    );
+    log::error!(
+        "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x} RSP: {:#018x}",
+        { regs.rsi },
+        { regs.rdi },
+        { regs.rbp },
+        { frame.rsp }
+    );
+    log::error!(
+        "CS: {:#06x} SS: {:#06x} RFLAGS: {:#018x}",
+        { frame.cs },
+        { frame.ss },
+        { frame.flags }
+    );
+
+    if MSR_TERMINATION.load(Ordering::Relaxed) {
+        // Vectors are below 32, so the code stays within the SVSM range.
+        request_termination_msr_reason(
+            TERM_REASON_SET_SVSM,
+            TERM_REASON_SVSM_STAGE2_EXCEPTION + vector as u8,
+        );
+    }
+    loop {
+        halt();
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn stage2_generic_idt_handler(ctx: &mut X86ExceptionContext, vector: usize) {
     match vector {
-        DF_VECTOR => {
-            let cr2 = read_cr2();
-            let rip = ctx.frame.rip;
-            let rsp = ctx.frame.rsp;
-            panic!(
-                "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
-                rip, rsp, cr2
-            );
+        VC_VECTOR => {
+            if let Err(e) = stage2_handle_vc_exception(ctx) {
+                log::error!("Failed to handle #VC: {:?}", e);
+                stage2_fatal_exception(ctx, vector);
+            }
         }
-        VC_VECTOR => stage2_handle_vc_exception(ctx).expect("Failed to handle #VC"),
         HV_VECTOR =>
             // #HV does not require processing during stage 2 and can be
         // completely ignored.
             {}
-        _ => {
-            let err = ctx.error_code;
-            let rip = ctx.frame.rip;
-
-            panic!(
-                "Unhandled exception {} RIP {:#018x} error code: {:#018x}",
-                vector, rip, err
-            );
-        }
+        _ => stage2_fatal_exception(ctx, vector),
     }
 }
 
 #[no_mangle]
 pub extern "C" fn stage2_generic_idt_handler_no_ghcb(ctx: &mut X86ExceptionContext, vector: usize) {
     match vector {
-        DF_VECTOR => {
-            let cr2 = read_cr2();
-            let rip = ctx.frame.rip;
-            let rsp = ctx.frame.rsp;
-            panic!(
-                "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
-                rip, rsp, cr2
-            );
-        }
-        VC_VECTOR => stage2_handle_vc_exception_no_ghcb(ctx).expect("Failed to handle #VC"),
-        _ => {
-            let err = ctx.error_code;
-            let rip = ctx.frame.rip;
-
-            panic!(
-                "Unhandled exception {} RIP {:#018x} error code: {:#018x}",
-                vector, rip, err
-            );
+        VC_VECTOR => {
+            if let Err(e) = stage2_handle_vc_exception_no_ghcb(ctx) {
+                log::error!("Failed to handle #VC: {:?}", e);
+                stage2_fatal_exception(ctx, vector);
+            }
         }
+        _ => stage2_fatal_exception(ctx, vector),
     }
 }
 
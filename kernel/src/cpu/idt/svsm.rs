@@ -6,20 +6,27 @@
 
 use super::super::control_regs::read_cr2;
 use super::super::extable::handle_exception_table;
-use super::super::percpu::{current_task, this_cpu};
+use super::super::panic::park_if_panicking_at;
+use super::super::percpu::{current_task, this_cpu, NmiGuard};
 use super::super::tss::IST_DF;
 use super::super::vc::handle_vc_exception;
 use super::common::{
     idt_mut, user_mode, IdtEntry, AC_VECTOR, BP_VECTOR, BR_VECTOR, CP_VECTOR, DB_VECTOR, DE_VECTOR,
     DF_VECTOR, GP_VECTOR, HV_VECTOR, INT_INJ_VECTOR, MCE_VECTOR, MF_VECTOR, NMI_VECTOR, NM_VECTOR,
-    NP_VECTOR, OF_VECTOR, PF_ERROR_WRITE, PF_VECTOR, SS_VECTOR, SX_VECTOR, TS_VECTOR, UD_VECTOR,
-    VC_VECTOR, XF_VECTOR,
+    NP_VECTOR, OF_VECTOR, PF_ERROR_RMP, PF_ERROR_WRITE, PF_VECTOR, SS_VECTOR, SX_VECTOR, TS_VECTOR,
+    UD_VECTOR, VC_VECTOR, XF_VECTOR,
 };
 use crate::address::VirtAddr;
 use crate::cpu::X86ExceptionContext;
 use crate::debug::gdbstub::svsm_gdbstub::handle_debug_exception;
+use crate::event_channel::event_channel_interrupt;
+use crate::mm::vm::in_stack_guard;
+use crate::mm::{USER_MEM_END, USER_MEM_START};
 use crate::platform::SVSM_PLATFORM;
-use crate::task::{is_task_fault, terminate};
+use crate::sev::rmp_fault::handle_rmp_fault;
+use crate::task::{
+    handle_user_exception, is_task_fault, is_user_exception, terminate, try_current_task,
+};
 
 use core::arch::global_asm;
 
@@ -123,6 +130,37 @@ extern "C" fn ex_handler_breakpoint(ctx: &mut X86ExceptionContext) {
     handle_debug_exception(ctx, BP_VECTOR);
 }
 
+/// Panics with a stack overflow report if `vaddr` lies in the guard pages
+/// below the stack of the current task or a per-CPU stack. The panic
+/// handler prints the backtrace.
+fn check_stack_overflow(ctxt: &X86ExceptionContext, vaddr: VirtAddr) {
+    let rip = ctxt.frame.rip;
+    let rsp = ctxt.frame.rsp;
+
+    if let Some(task) = try_current_task() {
+        if in_stack_guard(task.stack_bounds(), vaddr) {
+            panic!(
+                "Stack overflow in task {} at RIP {:#018x} RSP {:#018x} (guard page access at {:#018x})",
+                task.get_task_id(),
+                rip,
+                rsp,
+                vaddr
+            );
+        }
+    }
+
+    if let Some(stack) = this_cpu().stack_guard_hit(vaddr) {
+        panic!(
+            "Stack overflow on {} stack of CPU {} at RIP {:#018x} RSP {:#018x} (guard page access at {:#018x})",
+            stack,
+            this_cpu().get_apic_id(),
+            rip,
+            rsp,
+            vaddr
+        );
+    }
+}
+
 // Doube-Fault handler
 #[no_mangle]
 extern "C" fn ex_handler_double_fault(ctxt: &mut X86ExceptionContext) {
@@ -139,6 +177,10 @@ extern "C" fn ex_handler_double_fault(ctxt: &mut X86ExceptionContext) {
         );
         terminate();
     } else {
+        // A #PF on a stack guard page escalates to a #DF, as the CPU fails
+        // to push the exception frame for the #PF onto the same stack.
+        check_stack_overflow(ctxt, VirtAddr::from(cr2));
+        check_stack_overflow(ctxt, VirtAddr::from(rsp));
         panic!(
             "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
             rip, rsp, cr2
@@ -154,10 +196,7 @@ extern "C" fn ex_handler_general_protection(ctxt: &mut X86ExceptionContext) {
     let rsp = ctxt.frame.rsp;
 
     if user_mode(ctxt) {
-        log::error!(
-            "Unhandled General-Protection-Fault at RIP {:#018x} error code: {:#018x} rsp: {:#018x} - Terminating task",
-            rip, err, rsp);
-        terminate();
+        handle_user_exception(ctxt, GP_VECTOR, None);
     } else if !handle_exception_table(ctxt) {
         panic!(
             "Unhandled General-Protection-Fault at RIP {:#018x} error code: {:#018x} rsp: {:#018x}",
@@ -166,6 +205,26 @@ extern "C" fn ex_handler_general_protection(ctxt: &mut X86ExceptionContext) {
     }
 }
 
+// NMI handler
+#[no_mangle]
+extern "C" fn ex_handler_nmi(ctxt: &mut X86ExceptionContext, vector: usize) {
+    // NMIs are not used by the SVSM. Report them in NMI context, so that
+    // the panic does not wait for locks held by the interrupted code.
+    let _guard = NmiGuard::enter();
+    ex_handler_panic(ctxt, vector);
+}
+
+/// Resolves a page fault from kernel mode. Faults on user addresses come from
+/// accesses to task memory through [`crate::mm::UserPtr`] and are resolved in
+/// the address space of the current task, like faults from the task itself.
+fn handle_kernel_pf(vaddr: VirtAddr, write: bool) -> bool {
+    if vaddr >= USER_MEM_START && vaddr < USER_MEM_END {
+        try_current_task().is_some_and(|task| task.fault(vaddr, write).is_ok())
+    } else {
+        this_cpu().handle_pf(vaddr, write).is_ok()
+    }
+}
+
 // Page-Fault handler
 #[no_mangle]
 extern "C" fn ex_handler_page_fault(ctxt: &mut X86ExceptionContext, vector: usize) {
@@ -184,15 +243,19 @@ extern "C" fn ex_handler_page_fault(ctxt: &mut X86ExceptionContext, vector: usiz
         };
 
         if kill_task {
-            log::error!("Unexpected user-mode page-fault at RIP {:#018x} CR2: {:#018x} error code: {:#018x} - Terminating task",
-                    rip, cr2, err);
-            terminate();
+            handle_user_exception(ctxt, vector, Some(vaddr));
+        }
+    } else if (err & PF_ERROR_RMP) != 0 {
+        if !handle_rmp_fault(ctxt, vaddr) {
+            handle_debug_exception(ctxt, vector);
+            panic!(
+                "Unhandled RMP violation at RIP {:#018x} CR2: {:#018x} error code: {:#018x}",
+                rip, cr2, err
+            );
         }
-    } else if this_cpu()
-        .handle_pf(VirtAddr::from(cr2), (err & PF_ERROR_WRITE) != 0)
-        .is_err()
-        && !handle_exception_table(ctxt)
+    } else if !handle_kernel_pf(vaddr, (err & PF_ERROR_WRITE) != 0) && !handle_exception_table(ctxt)
     {
+        check_stack_overflow(ctxt, vaddr);
         handle_debug_exception(ctxt, vector);
         panic!(
             "Unhandled Page-Fault at RIP {:#018x} CR2: {:#018x} error code: {:#018x}",
@@ -236,12 +299,21 @@ extern "C" fn ex_handler_system_call(ctxt: &mut X86ExceptionContext) {
     ctxt.regs.rax = match input {
         SYS_HELLO => sys_hello(),
         SYS_EXIT => sys_exit(),
+        SYS_MEMINFO => sys_meminfo(),
+        SYS_EXC_HANDLER => sys_exc_handler(ctxt.regs.rdi),
+        // Restores all registers from the exception frame
+        SYS_EXC_RETURN => return sys_exc_return(ctxt),
         _ => !0,
     };
 }
 
 #[no_mangle]
 pub extern "C" fn ex_handler_panic(ctx: &mut X86ExceptionContext, vector: usize) {
+    if user_mode(ctx) && is_user_exception(vector) {
+        handle_user_exception(ctx, vector, None);
+        return;
+    }
+
     let rip = ctx.frame.rip;
     let err = ctx.error_code;
     let rsp = ctx.frame.rsp;
@@ -252,10 +324,18 @@ pub extern "C" fn ex_handler_panic(ctx: &mut X86ExceptionContext, vector: usize)
     );
 }
 
+/// Handles the interrupt `vector`. `ctx` is the interrupted context, or
+/// `None` if the interrupt is processed at a point where there is none.
 #[no_mangle]
-pub extern "C" fn common_isr_handler(_vector: usize) {
+pub extern "C" fn common_isr_handler(vector: usize, ctx: Option<&X86ExceptionContext>) {
     // Interrupt injection requests currently require no processing; they occur
-    // simply to ensure an exit from the guest.
+    // simply to ensure an exit from the guest. They are also used to stop
+    // this CPU if another one has panicked.
+    park_if_panicking_at(ctx);
+
+    // Host event channel interrupts only flag the events as pending; they
+    // are processed from the request loop.
+    event_channel_interrupt(vector);
 
     // Treat any unhandled interrupt as a spurious interrupt.
     SVSM_PLATFORM.as_dyn_ref().eoi();
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Validation of the initial CPU state.
+//!
+//! The register state stage 2 starts with is supplied by the host. With
+//! SEV-SNP the launch VMSA is part of the launch measurement, but on other
+//! platforms it is not, and even a measured state may contain bits the boot
+//! code never sets explicitly and silently relies on. The boot code only
+//! sets the bits it needs in CR0, CR4 and EFER and keeps all others, so
+//! [`InitialCpuState`] captures these registers when stage 2 enters Rust
+//! code and checks that none of the bits the SVSM cannot cope with came
+//! from the launch context.
+//!
+//! The descriptor tables are captured as well. Where the launch state is
+//! known, as with the SEV-SNP launch VMSA, they are checked against it.
+//! Other launch paths, like the TDX stage 1, start with the architectural
+//! reset state or whatever their own boot code loaded, so the check is
+//! skipped there.
+
+use super::control_regs::{read_cr0, read_cr4, CR0Flags, CR4Flags};
+use super::efer::{read_efer, EFERFlags};
+use core::arch::asm;
+use core::fmt;
+use core::ptr::addr_of_mut;
+
+/// Contents of a GDTR or IDTR register
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct DescriptorTableReg {
+    pub limit: u16,
+    pub base: u64,
+}
+
+impl DescriptorTableReg {
+    pub fn read_gdtr() -> Self {
+        let mut gdtr = Self::default();
+        // SAFETY: SGDT only stores the GDTR into the provided memory.
+        unsafe {
+            asm!("sgdt ({0})", in(reg) addr_of_mut!(gdtr), options(att_syntax, nostack));
+        }
+        gdtr
+    }
+
+    pub fn read_idtr() -> Self {
+        let mut idtr = Self::default();
+        // SAFETY: SIDT only stores the IDTR into the provided memory.
+        unsafe {
+            asm!("sidt ({0})", in(reg) addr_of_mut!(idtr), options(att_syntax, nostack));
+        }
+        idtr
+    }
+}
+
+impl fmt::Display for DescriptorTableReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (base, limit) = (self.base, self.limit);
+        write!(f, "base={:#x} limit={:#x}", base, limit)
+    }
+}
+
+/// The GDTR and IDTR of a CPU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DescriptorTables {
+    pub gdtr: DescriptorTableReg,
+    pub idtr: DescriptorTableReg,
+}
+
+/// A mismatch between the initial CPU state and the expected one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitialStateError {
+    /// CR0 has forbidden bits set
+    Cr0(CR0Flags),
+    /// CR4 has forbidden bits set
+    Cr4(CR4Flags),
+    /// EFER has forbidden bits set
+    Efer(EFERFlags),
+    /// The GDT is not the expected one
+    Gdt(DescriptorTableReg),
+    /// The IDT is not the expected one
+    Idt(DescriptorTableReg),
+}
+
+impl fmt::Display for InitialStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cr0(cr0) => write!(f, "unexpected CR0 {:#x}", cr0.bits()),
+            Self::Cr4(cr4) => write!(f, "unexpected CR4 {:#x}", cr4.bits()),
+            Self::Efer(efer) => write!(f, "unexpected EFER {:#x}", efer.bits()),
+            Self::Gdt(gdtr) => write!(f, "unexpected GDT ({})", gdtr),
+            Self::Idt(idtr) => write!(f, "unexpected IDT ({})", idtr),
+        }
+    }
+}
+
+/// Bits which break assumptions of the SVSM if the host sets them.
+const CR0_FORBIDDEN: CR0Flags = CR0Flags::EM.union(CR0Flags::TS);
+
+const CR4_FORBIDDEN: CR4Flags = CR4Flags::VME
+    .union(CR4Flags::PVI)
+    .union(CR4Flags::LA57)
+    .union(CR4Flags::PCIDE)
+    .union(CR4Flags::PKE)
+    .union(CR4Flags::CET);
+
+const EFER_FORBIDDEN: EFERFlags = EFERFlags::LMSLE.union(EFERFlags::UAIE);
+
+/// Snapshot of the CPU state relevant for validation
+#[derive(Clone, Copy, Debug)]
+pub struct InitialCpuState {
+    pub cr0: CR0Flags,
+    pub cr4: CR4Flags,
+    pub efer: EFERFlags,
+    pub tables: DescriptorTables,
+}
+
+impl InitialCpuState {
+    /// Captures the state of the current CPU. Must be called before stage 2
+    /// changes any of the registers itself.
+    pub fn capture() -> Self {
+        Self {
+            cr0: read_cr0(),
+            cr4: read_cr4(),
+            efer: read_efer(),
+            tables: DescriptorTables {
+                gdtr: DescriptorTableReg::read_gdtr(),
+                idtr: DescriptorTableReg::read_idtr(),
+            },
+        }
+    }
+
+    /// Checks that the captured state has none of the forbidden bits set,
+    /// and that the descriptor tables match `tables` unless it is `None`.
+    pub fn verify(&self, tables: Option<DescriptorTables>) -> Result<(), InitialStateError> {
+        if self.cr0.intersects(CR0_FORBIDDEN) {
+            return Err(InitialStateError::Cr0(self.cr0));
+        }
+        if self.cr4.intersects(CR4_FORBIDDEN) {
+            return Err(InitialStateError::Cr4(self.cr4));
+        }
+        if self.efer.intersects(EFER_FORBIDDEN) {
+            return Err(InitialStateError::Efer(self.efer));
+        }
+        let Some(tables) = tables else {
+            return Ok(());
+        };
+        if self.tables.gdtr != tables.gdtr {
+            return Err(InitialStateError::Gdt(self.tables.gdtr));
+        }
+        if self.tables.idtr != tables.idtr {
+            return Err(InitialStateError::Idt(self.tables.idtr));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn launch_tables() -> DescriptorTables {
+        DescriptorTables {
+            gdtr: DescriptorTableReg {
+                limit: 0x27,
+                base: 0x10f00,
+            },
+            idtr: DescriptorTableReg::default(),
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let state = InitialCpuState {
+            cr0: CR0Flags::PE | CR0Flags::ET | CR0Flags::NE | CR0Flags::PG,
+            cr4: CR4Flags::MCE | CR4Flags::PAE,
+            efer: EFERFlags::LME | EFERFlags::LMA | EFERFlags::SVME,
+            tables: launch_tables(),
+        };
+        assert_eq!(state.verify(Some(launch_tables())), Ok(()));
+        assert_eq!(state.verify(None), Ok(()));
+
+        let bad = InitialCpuState {
+            cr0: state.cr0 | CR0Flags::TS,
+            ..state
+        };
+        assert!(matches!(bad.verify(None), Err(InitialStateError::Cr0(_))));
+
+        let bad = InitialCpuState {
+            cr4: state.cr4 | CR4Flags::LA57,
+            ..state
+        };
+        assert!(matches!(bad.verify(None), Err(InitialStateError::Cr4(_))));
+
+        let bad = InitialCpuState {
+            efer: state.efer | EFERFlags::LMSLE,
+            ..state
+        };
+        assert!(matches!(bad.verify(None), Err(InitialStateError::Efer(_))));
+    }
+
+    #[test]
+    fn verify_tables() {
+        let mut state = InitialCpuState {
+            cr0: CR0Flags::PE | CR0Flags::PG,
+            cr4: CR4Flags::PAE,
+            efer: EFERFlags::LME | EFERFlags::LMA,
+            tables: launch_tables(),
+        };
+        state.tables.gdtr.base = 0;
+        assert!(matches!(
+            state.verify(Some(launch_tables())),
+            Err(InitialStateError::Gdt(_))
+        ));
+
+        // The architectural reset IDTR, as seen on the TDX stage 1 path, is
+        // only accepted if the descriptor tables are not checked.
+        state.tables = DescriptorTables {
+            idtr: DescriptorTableReg {
+                limit: 0xffff,
+                base: 0,
+            },
+            ..launch_tables()
+        };
+        assert!(matches!(
+            state.verify(Some(launch_tables())),
+            Err(InitialStateError::Idt(_))
+        ));
+        assert_eq!(state.verify(None), Ok(()));
+    }
+}
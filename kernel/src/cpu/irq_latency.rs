@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Interrupt delivery latency tracking.
+//!
+//! Measures the time from the SVSM picking up an interrupt signalled through
+//! the #HV doorbell page until [`common_isr_handler()`] has completed for it.
+//! The hypervisor does not timestamp the doorbell write, so the measurement
+//! starts when the doorbell is processed. Time spent handling interrupts
+//! which arrive while the doorbell is processed is not counted towards the
+//! latency of the interrupted pass. Latencies are recorded in TSC
+//! cycles into a trace buffer of the most recent samples and into a
+//! histogram with power-of-two buckets, from which approximate percentiles
+//! are computed. [`log_irq_latency()`] writes them to the log.
+//!
+//! [`common_isr_handler()`]: crate::cpu::idt::svsm::common_isr_handler
+
+use crate::cpu::msr::rdtsc;
+use crate::cpu::percpu::this_cpu;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Number of samples kept in the trace buffer
+pub const IRQ_TRACE_ENTRIES: usize = 64;
+
+const HISTOGRAM_BUCKETS: usize = u64::BITS as usize;
+
+/// A single latency sample in the trace buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrqLatencySample {
+    pub vector: u8,
+    pub cycles: u64,
+}
+
+impl IrqLatencySample {
+    fn pack(self) -> u64 {
+        // Latencies beyond 2^56 cycles are not meaningful anyway
+        (self.cycles.min((1 << 56) - 1) << 8) | u64::from(self.vector)
+    }
+
+    fn unpack(raw: u64) -> Self {
+        Self {
+            vector: raw as u8,
+            cycles: raw >> 8,
+        }
+    }
+}
+
+/// Summary of the recorded latencies, in TSC cycles. Percentiles are upper
+/// bounds, rounded up to the next power of two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrqLatencySummary {
+    pub count: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+#[derive(Debug)]
+pub struct IrqLatencyStats {
+    histogram: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    max: AtomicU64,
+    trace: [AtomicU64; IRQ_TRACE_ENTRIES],
+    trace_next: AtomicUsize,
+}
+
+impl IrqLatencyStats {
+    pub const fn new() -> Self {
+        Self {
+            histogram: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS],
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            trace: [const { AtomicU64::new(0) }; IRQ_TRACE_ENTRIES],
+            trace_next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bucket `i` holds latencies in `[2^(i-1), 2^i)`, bucket 0 holds zero.
+    fn bucket(cycles: u64) -> usize {
+        (u64::BITS - cycles.leading_zeros()) as usize
+    }
+
+    fn bucket_limit(bucket: usize) -> u64 {
+        1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX)
+    }
+
+    pub fn record(&self, vector: u8, cycles: u64) {
+        let bucket = Self::bucket(cycles).min(HISTOGRAM_BUCKETS - 1);
+        self.histogram[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+
+        let slot = self.trace_next.fetch_add(1, Ordering::Relaxed) % IRQ_TRACE_ENTRIES;
+        self.trace[slot].store(
+            IrqLatencySample { vector, cycles }.pack(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Upper bound of the latency below which `percent` percent of the
+    /// samples fall
+    pub fn percentile(&self, percent: u64) -> u64 {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return 0;
+        }
+        let target = (count * percent.min(100)).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, bucket) in self.histogram.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return Self::bucket_limit(i);
+            }
+        }
+        self.max.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> IrqLatencySummary {
+        IrqLatencySummary {
+            count: self.count.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            p50: self.percentile(50),
+            p90: self.percentile(90),
+            p99: self.percentile(99),
+        }
+    }
+
+    /// Call `f` for each sample in the trace buffer, oldest first
+    pub fn for_each_trace<F: FnMut(IrqLatencySample)>(&self, mut f: F) {
+        let next = self.trace_next.load(Ordering::Relaxed);
+        let start = next.saturating_sub(IRQ_TRACE_ENTRIES);
+        for i in start..next {
+            f(IrqLatencySample::unpack(
+                self.trace[i % IRQ_TRACE_ENTRIES].load(Ordering::Relaxed),
+            ));
+        }
+    }
+
+    pub fn reset(&self) {
+        for bucket in self.histogram.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        self.trace_next.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for IrqLatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static IRQ_LATENCY: IrqLatencyStats = IrqLatencyStats::new();
+
+pub fn irq_latency_reset() {
+    IRQ_LATENCY.reset();
+}
+
+/// Log a summary of the interrupt delivery latencies, and the most recent
+/// samples at debug level
+pub fn log_irq_latency() {
+    let s = IRQ_LATENCY.summary();
+    log::info!(
+        "#HV interrupt latency (cycles): count={} p50<={} p90<={} p99<={} max={}",
+        s.count,
+        s.p50,
+        s.p90,
+        s.p99,
+        s.max
+    );
+    IRQ_LATENCY.for_each_trace(|sample| {
+        log::debug!("  vector {:#04x}: {} cycles", sample.vector, sample.cycles);
+    });
+}
+
+/// Measures the latency of the interrupts handled in one pass over the #HV
+/// doorbell page. Passes nested within this one, which handle interrupts
+/// that arrived while this pass was running, are not counted.
+#[derive(Debug)]
+pub struct IrqLatencyTimer {
+    start: u64,
+    nested_start: u64,
+}
+
+impl IrqLatencyTimer {
+    pub fn start() -> Self {
+        Self {
+            start: rdtsc(),
+            nested_start: this_cpu().irq_cycles(),
+        }
+    }
+
+    /// Cycles since the start of the pass, minus those spent in nested
+    /// passes which have completed
+    fn elapsed(&self) -> u64 {
+        let total = rdtsc().wrapping_sub(self.start);
+        let nested = this_cpu().irq_cycles().wrapping_sub(self.nested_start);
+        total.saturating_sub(nested)
+    }
+
+    /// Record the latency of `vector`, which was picked up by this pass and
+    /// has just been handled
+    pub fn record(&self, vector: u8) {
+        IRQ_LATENCY.record(vector, self.elapsed());
+    }
+}
+
+impl Drop for IrqLatencyTimer {
+    fn drop(&mut self) {
+        // Exclude this pass from the latencies of the pass it interrupted
+        this_cpu().add_irq_cycles(self.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let stats = IrqLatencyStats::new();
+        assert_eq!(stats.summary(), IrqLatencySummary::default());
+
+        for _ in 0..90 {
+            stats.record(0x50, 100);
+        }
+        for _ in 0..9 {
+            stats.record(0x50, 1000);
+        }
+        stats.record(0x50, 100_000);
+
+        let s = stats.summary();
+        assert_eq!(s.count, 100);
+        assert_eq!(s.max, 100_000);
+        assert_eq!(s.p50, 128);
+        assert_eq!(s.p90, 128);
+        assert_eq!(s.p99, 1024);
+        assert_eq!(stats.percentile(100), 131_072);
+
+        stats.reset();
+        assert_eq!(stats.summary(), IrqLatencySummary::default());
+    }
+
+    #[test]
+    fn trace_buffer_wraps() {
+        let stats = IrqLatencyStats::new();
+        for i in 0..(IRQ_TRACE_ENTRIES as u64 + 10) {
+            stats.record(i as u8, i);
+        }
+
+        let mut samples = 0;
+        let mut first = None;
+        stats.for_each_trace(|sample| {
+            first.get_or_insert(sample);
+            samples += 1;
+        });
+        assert_eq!(samples, IRQ_TRACE_ENTRIES);
+        assert_eq!(
+            first,
+            Some(IrqLatencySample {
+                vector: 10,
+                cycles: 10
+            })
+        );
+    }
+}
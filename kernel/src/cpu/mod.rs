@@ -12,13 +12,19 @@ pub mod extable;
 pub mod features;
 pub mod gdt;
 pub mod idt;
+pub mod initial_state;
+pub mod irq_latency;
 pub mod msr;
+pub mod panic;
 pub mod percpu;
 pub mod registers;
+#[cfg(test)]
+mod selftest;
 pub mod smp;
 pub mod tlb;
 pub mod tss;
 pub mod vc;
+pub mod vcpu_state;
 pub mod vmsa;
 
 pub use apic::LocalApic;
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Cross-CPU panic propagation.
+//!
+//! The first CPU to panic becomes the panic owner and asks all other CPUs to
+//! stop by sending them an IPI. The other CPUs park themselves at the next
+//! safe point, i.e. when processing interrupts or before entering the guest
+//! again. A parked CPU records the registers of the context it interrupted,
+//! if it was parked from an interrupt handler, marks itself as parked and
+//! halts forever, so that it no longer modifies shared state or writes to
+//! the console while the owner reports the panic.
+
+use crate::cpu::idt::common::INT_INJ_VECTOR;
+use crate::cpu::percpu::{this_cpu, PerCpuInfo, PERCPU_AREAS};
+use crate::cpu::X86ExceptionContext;
+use crate::platform::SVSM_PLATFORM;
+use crate::sev::ghcb::GhcbState;
+use crate::utils::halt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+const NO_OWNER: u32 = u32::MAX;
+
+/// Number of iterations the panic owner waits for other CPUs to park
+const PARK_TIMEOUT_SPINS: usize = 100_000_000;
+
+/// Fixed interrupt on the interrupt injection vector, sent to all CPUs
+/// except the sender. Any interrupt is enough to get a CPU out of the guest
+/// and into the SVSM, where it will notice the panic.
+const PANIC_IPI_ICR: u64 = INT_INJ_VECTOR as u64 | (3 << 18);
+
+static PANIC_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+static PARKED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Register snapshot of the context a CPU was running when it was parked
+/// from an interrupt handler because of a panic on another CPU
+#[derive(Debug)]
+pub struct ParkedRegs {
+    valid: AtomicBool,
+    rip: AtomicU64,
+    rsp: AtomicU64,
+    rbp: AtomicU64,
+    rflags: AtomicU64,
+}
+
+impl ParkedRegs {
+    pub const fn new() -> Self {
+        Self {
+            valid: AtomicBool::new(false),
+            rip: AtomicU64::new(0),
+            rsp: AtomicU64::new(0),
+            rbp: AtomicU64::new(0),
+            rflags: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, ctx: &X86ExceptionContext) {
+        self.rip.store(ctx.frame.rip as u64, Ordering::Relaxed);
+        self.rsp.store(ctx.frame.rsp as u64, Ordering::Relaxed);
+        self.rbp.store(ctx.regs.rbp as u64, Ordering::Relaxed);
+        self.rflags.store(ctx.frame.flags as u64, Ordering::Relaxed);
+        self.valid.store(true, Ordering::Release);
+    }
+
+    /// Returns the recorded `(rip, rsp, rbp, rflags)`, if any
+    pub fn get(&self) -> Option<(u64, u64, u64, u64)> {
+        self.valid.load(Ordering::Acquire).then(|| {
+            (
+                self.rip.load(Ordering::Relaxed),
+                self.rsp.load(Ordering::Relaxed),
+                self.rbp.load(Ordering::Relaxed),
+                self.rflags.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
+
+impl Default for ParkedRegs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether any CPU has started handling a panic
+pub fn panic_in_progress() -> bool {
+    PANIC_OWNER.load(Ordering::Acquire) != NO_OWNER
+}
+
+/// Claim the panic for the current CPU and stop all other CPUs. Returns
+/// `false` if another CPU is already handling a panic, in which case the
+/// caller should park itself with [`park_this_cpu()`] instead of reporting.
+/// A nested panic on the owner returns `true` without stopping the other
+/// CPUs again.
+pub fn panic_begin() -> bool {
+    let apic_id = this_cpu().get_apic_id();
+    match PANIC_OWNER.compare_exchange(NO_OWNER, apic_id, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {}
+        Err(owner) => return owner == apic_id,
+    }
+
+    let others = PERCPU_AREAS
+        .iter()
+        .map(PerCpuInfo::unwrap)
+        .filter(|cpu| cpu.apic_id() != apic_id && cpu.is_online())
+        .count();
+    if others == 0 {
+        return true;
+    }
+
+    // Errors cannot be handled here. CPUs that miss the IPI will still park
+    // before they enter the guest again.
+    let _ = SVSM_PLATFORM.as_dyn_ref().post_irq(PANIC_IPI_ICR);
+
+    for _ in 0..PARK_TIMEOUT_SPINS {
+        if PARKED_CPUS.load(Ordering::Acquire) >= others {
+            break;
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// Mark the current CPU as parked and halt it forever.
+pub fn park_this_cpu() -> ! {
+    this_cpu().shared().set_parked();
+    PARKED_CPUS.fetch_add(1, Ordering::Release);
+
+    loop {
+        halt();
+    }
+}
+
+/// Park the current CPU if another CPU is handling a panic
+pub fn park_if_panicking() {
+    park_if_panicking_at(None);
+}
+
+/// Like [`park_if_panicking()`], for interrupt handlers. The registers of
+/// `ctx`, the interrupted context, are kept for the panic report.
+pub fn park_if_panicking_at(ctx: Option<&X86ExceptionContext>) {
+    let owner = PANIC_OWNER.load(Ordering::Acquire);
+    if owner != NO_OWNER && owner != this_cpu().get_apic_id() {
+        if let Some(ctx) = ctx {
+            this_cpu().shared().parked_regs().record(ctx);
+        }
+        park_this_cpu();
+    }
+}
+
+/// Log the register snapshots of the parked CPUs and which CPUs did not
+/// park
+pub fn dump_parked_cpus() {
+    for cpu in PERCPU_AREAS.iter().map(PerCpuInfo::unwrap) {
+        if cpu.is_parked() {
+            match cpu.parked_regs().get() {
+                Some((rip, rsp, rbp, rflags)) => log::error!(
+                    "CPU[{}] parked: RIP={:#018x} RSP={:#018x} RBP={:#018x} RFLAGS={:#x}",
+                    cpu.apic_id(),
+                    rip,
+                    rsp,
+                    rbp,
+                    rflags
+                ),
+                None => log::error!("CPU[{}] parked", cpu.apic_id()),
+            }
+        } else if cpu.is_online() && cpu.apic_id() != this_cpu().get_apic_id() {
+            log::error!("CPU[{}] did not stop", cpu.apic_id());
+        }
+        // A CPU stuck in a VMGEXIT points at the hypervisor.
+        if cpu.ghcb_state() == GhcbState::InUse {
+            log::error!("CPU[{}] GHCB {}", cpu.apic_id(), cpu.ghcb_state());
+        }
+    }
+}
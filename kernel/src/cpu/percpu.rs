@@ -11,24 +11,33 @@ use super::tss::{X86Tss, IST_DF};
 use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::cpu::apic::ApicError;
 use crate::cpu::idt::common::INT_INJ_VECTOR;
+use crate::cpu::panic::ParkedRegs;
 use crate::cpu::tss::TSS_LIMIT;
+use crate::cpu::vcpu_state::{
+    notify_vcpu_state_change, VcpuState, VcpuStateCell, VcpuStateChange, VcpuStateError,
+};
 use crate::cpu::vmsa::{init_guest_vmsa, init_svsm_vmsa, vmsa_mut_ref_from_vaddr};
 use crate::cpu::LocalApic;
 use crate::error::SvsmError;
+use crate::event_channel::{register_event_handler, EventKind};
 use crate::locking::{LockGuard, RWLock, SpinLock};
-use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::mm::alloc::{allocate_zeroed_page_on_node, drain_page_cache, free_page, PageCache};
+use crate::mm::guest_ref::GuestPageRef;
+use crate::mm::memory::cpu_numa_node;
 use crate::mm::pagetable::{get_init_pgtable_locked, PTEntryFlags, PageTableRef};
 use crate::mm::virtualrange::VirtualRange;
-use crate::mm::vm::{Mapping, VMKernelStack, VMPhysMem, VMRMapping, VMReserved, VMR};
+use crate::mm::vm::{
+    in_stack_guard, Mapping, VMKernelStack, VMPhysMem, VMRMapping, VMReserved, VMR,
+};
 use crate::mm::{
-    virt_to_phys, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_END,
-    SVSM_PERCPU_TEMP_BASE_2M, SVSM_PERCPU_TEMP_BASE_4K, SVSM_PERCPU_TEMP_END_2M,
-    SVSM_PERCPU_TEMP_END_4K, SVSM_PERCPU_VMSA_BASE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE,
+    percpu_base, percpu_caa_base, percpu_init_stack_base, percpu_ist_df_stack_base,
+    percpu_temp_region_2m, percpu_temp_region_4k, percpu_vmsa_base, virt_to_phys, STACK_SIZE,
+    SVSM_PERCPU_BASE, SVSM_PERCPU_END,
 };
 use crate::platform::{SvsmPlatform, SVSM_PLATFORM};
-use crate::sev::ghcb::GHCB;
-use crate::sev::hv_doorbell::HVDoorbell;
-use crate::sev::msr_protocol::{hypervisor_ghcb_features, GHCBHvFeatures};
+use crate::sev::ghcb::{GhcbError, GhcbScratch, GhcbState, GHCB};
+use crate::sev::hv_doorbell::{register_hv_doorbell_teardown, HVDoorbell};
+use crate::sev::msr_protocol::{register_ghcb_gpa_msr, sev_caps, GHCBHvFeatures};
 use crate::sev::utils::RMPFlags;
 use crate::sev::vmsa::{allocate_new_vmsa, VMSAControl};
 use crate::task::{schedule, schedule_task, RunQueue, Task, TaskPointer, WaitQueue};
@@ -40,7 +49,7 @@ use core::cell::{Cell, OnceCell, RefCell, RefMut, UnsafeCell};
 use core::mem::size_of;
 use core::ptr;
 use core::slice::Iter;
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use cpuarch::vmsa::{VMSASegment, VMSA};
 
 #[derive(Copy, Clone, Debug)]
@@ -175,14 +184,14 @@ impl GuestVmsaRef {
         assert!(self.vmsa.is_some());
         // SAFETY: this function takes &mut self, so only one mutable
         // reference to the underlying VMSA can exist.
-        unsafe { SVSM_PERCPU_VMSA_BASE.as_mut_ptr::<VMSA>().as_mut().unwrap() }
+        unsafe { percpu_vmsa_base().as_mut_ptr::<VMSA>().as_mut().unwrap() }
     }
 
     pub fn caa_addr(&self) -> Option<VirtAddr> {
         let caa_phys = self.caa_phys()?;
         let offset = caa_phys.page_offset();
 
-        Some(SVSM_PERCPU_CAA_BASE + offset)
+        Some(percpu_caa_base() + offset)
     }
 }
 
@@ -194,6 +203,12 @@ pub struct PerCpuShared {
     ipi_irr: [AtomicU32; 8],
     ipi_pending: AtomicBool,
     nmi_pending: AtomicBool,
+    parked: AtomicBool,
+    parked_regs: ParkedRegs,
+    ghcb_state: AtomicU8,
+    hv_doorbell_check: AtomicBool,
+    /// Address of the #HV doorbell page of this CPU, or zero
+    hv_doorbell_page: AtomicU64,
 }
 
 impl PerCpuShared {
@@ -214,6 +229,11 @@ impl PerCpuShared {
             ],
             ipi_pending: AtomicBool::new(false),
             nmi_pending: AtomicBool::new(false),
+            parked: AtomicBool::new(false),
+            parked_regs: ParkedRegs::new(),
+            ghcb_state: AtomicU8::new(GhcbState::Unregistered as u8),
+            hv_doorbell_check: AtomicBool::new(false),
+            hv_doorbell_page: AtomicU64::new(0),
         }
     }
 
@@ -221,6 +241,58 @@ impl PerCpuShared {
         self.apic_id
     }
 
+    /// Whether this CPU was parked by a panic on another CPU
+    pub fn is_parked(&self) -> bool {
+        self.parked.load(Ordering::Acquire)
+    }
+
+    /// Marks this CPU as parked by a panic on another CPU
+    pub fn set_parked(&self) {
+        self.parked.store(true, Ordering::Release);
+    }
+
+    /// Registers of the context this CPU was running when it was parked
+    /// from an interrupt handler, for the panic report
+    pub fn parked_regs(&self) -> &ParkedRegs {
+        &self.parked_regs
+    }
+
+    /// Asks this CPU to check the registration of its #HV doorbell page the
+    /// next time it goes through the request loop, see
+    /// [`PerCpu::revalidate_hv_doorbell()`].
+    pub fn request_hv_doorbell_check(&self) {
+        self.hv_doorbell_check.store(true, Ordering::Release);
+    }
+
+    /// Returns whether a doorbell check was requested and clears the request
+    pub fn take_hv_doorbell_check(&self) -> bool {
+        self.hv_doorbell_check.swap(false, Ordering::Acquire)
+    }
+
+    fn set_hv_doorbell_page(&self, vaddr: VirtAddr) {
+        self.hv_doorbell_page
+            .store(u64::from(vaddr), Ordering::Release);
+    }
+
+    /// Returns the #HV doorbell page of this CPU, if any, so that it can be
+    /// torn down. Later calls return `None`.
+    pub fn take_hv_doorbell_page(&self) -> Option<VirtAddr> {
+        match self.hv_doorbell_page.swap(0, Ordering::AcqRel) {
+            0 => None,
+            vaddr => Some(VirtAddr::from(vaddr)),
+        }
+    }
+
+    /// Lifecycle state of the GHCB of this CPU. Other CPUs read it to
+    /// report which CPU was in a VMGEXIT when the SVSM hung.
+    pub fn ghcb_state(&self) -> GhcbState {
+        GhcbState::from_u8(self.ghcb_state.load(Ordering::Relaxed))
+    }
+
+    pub fn set_ghcb_state(&self, state: GhcbState) {
+        self.ghcb_state.store(state as u8, Ordering::Relaxed);
+    }
+
     pub fn update_guest_vmsa_caa(&self, vmsa: PhysAddr, caa: PhysAddr) {
         let mut locked = self.guest_vmsa.lock();
         locked.update_vmsa_caa(Some(vmsa), Some(caa));
@@ -291,6 +363,12 @@ const _: () = assert!(size_of::<PerCpu>() <= PAGE_SIZE);
 /// local CPU, much like thread-local data in an std environment. The only
 /// part of the struct that may be accessed from a different CPU is the
 /// `shared` field, a reference to which will be stored in [`PERCPU_AREAS`].
+///
+/// In debug builds, the accessors check that they are called on the CPU
+/// owning the structure once that CPU has claimed it, see
+/// [`PerCpu::claim()`]. Before that, the CPU bringing it up may set it up.
+/// Intentional accesses from other CPUs have to go through
+/// [`PerCpu::remote()`].
 #[derive(Debug)]
 pub struct PerCpu {
     /// Per-CPU storage that might be accessed from other CPUs.
@@ -313,18 +391,49 @@ pub struct PerCpu {
     request_waitqueue: RefCell<WaitQueue>,
     /// Local APIC state for APIC emulation
     apic: RefCell<LocalApic>,
+    /// Reference to the guest page of the mapped calling area
+    caa_ref: RefCell<Option<GuestPageRef>>,
+    /// Registry entry of the guest VMSA mapped on this CPU, see
+    /// [`PerCpu::set_guest_vcpu_state()`]
+    guest_vcpu: RefCell<Option<Arc<VmsaRegistryEntry>>>,
 
     /// GHCB page for this CPU.
     ghcb: Cell<Option<&'static GHCB>>,
+    /// Reservations of the shared buffer of the GHCB
+    ghcb_scratch: GhcbScratch,
 
-    /// `#HV` doorbell page for this CPU.
-    hv_doorbell: OnceCell<&'static HVDoorbell>,
+    /// `#HV` doorbell page for this CPU. The page may be replaced if the
+    /// hypervisor revokes the registration, see
+    /// [`PerCpu::revalidate_hv_doorbell()`].
+    hv_doorbell: Cell<Option<&'static HVDoorbell>>,
 
     init_stack: Cell<Option<VirtAddr>>,
     ist: IstStacks,
 
     /// Stack boundaries of the currently running task.
     current_stack: Cell<MemoryRegion<VirtAddr>>,
+
+    /// Nesting depth of NMI-like contexts, see [`in_nmi()`]. A `Cell` is
+    /// sufficient even though handlers can interrupt each other, as every
+    /// nested handler restores the value before returning.
+    nmi_depth: Cell<u32>,
+
+    /// TSC cycles spent in interrupt processing on this CPU, see
+    /// [`IrqLatencyTimer`](crate::cpu::irq_latency::IrqLatencyTimer)
+    irq_cycles: Cell<u64>,
+
+    /// Cache of free pages for lock-free page allocations, see
+    /// [`PageCache`]
+    page_cache: RefCell<PageCache>,
+
+    /// Whether the owning CPU has claimed this structure
+    #[cfg(debug_assertions)]
+    claimed: Cell<bool>,
+    /// Address of the per-CPU data of another CPU which this CPU accesses
+    /// through [`PerCpu::remote()`], or zero. Only used by the owning CPU,
+    /// so remote accesses never write to the structure they access.
+    #[cfg(debug_assertions)]
+    remote_target: Cell<usize>,
 }
 
 impl PerCpu {
@@ -342,20 +451,75 @@ impl PerCpu {
             request_waitqueue: RefCell::new(WaitQueue::new()),
             apic_emulation: Cell::new(false),
             apic: RefCell::new(LocalApic::new()),
+            caa_ref: RefCell::new(None),
+            guest_vcpu: RefCell::new(None),
 
             shared: PerCpuShared::new(apic_id),
             ghcb: Cell::new(None),
-            hv_doorbell: OnceCell::new(),
+            ghcb_scratch: GhcbScratch::new(),
+            hv_doorbell: Cell::new(None),
             init_stack: Cell::new(None),
             ist: IstStacks::new(),
             current_stack: Cell::new(MemoryRegion::new(VirtAddr::null(), 0)),
+            nmi_depth: Cell::new(0),
+            irq_cycles: Cell::new(0),
+            page_cache: RefCell::new(PageCache::new()),
+            #[cfg(debug_assertions)]
+            claimed: Cell::new(false),
+            #[cfg(debug_assertions)]
+            remote_target: Cell::new(0),
+        }
+    }
+
+    /// Marks this structure as owned by the executing CPU. Must be called on
+    /// the owning CPU once it runs on its own per-CPU page table. From then
+    /// on, debug builds check that all accesses come from that CPU.
+    pub fn claim(&self) {
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(self.get_apic_id(), this_cpu().get_apic_id());
+            self.claimed.set(true);
+        }
+    }
+
+    /// Checks that the calling CPU owns this structure, unless it has not
+    /// been claimed yet or is accessed through [`PerCpu::remote()`].
+    #[inline]
+    fn check_local(&self) {
+        #[cfg(debug_assertions)]
+        if self.claimed.get() && this_cpu().remote_target.get() != ptr::from_ref(self) as usize {
+            let current = this_cpu().get_apic_id();
+            assert_eq!(
+                self.get_apic_id(),
+                current,
+                "CPU {} accessed the per-CPU data of CPU {}",
+                current,
+                self.get_apic_id()
+            );
         }
     }
 
+    /// Runs `f` on the per-CPU data of a possibly different CPU without the
+    /// ownership checks of debug builds. The caller is responsible for
+    /// making sure that the owning CPU does not use the accessed state
+    /// concurrently, e.g. because it is parked or not yet running. The
+    /// exemption is recorded in the per-CPU data of the calling CPU, which
+    /// must be set up already.
+    pub fn remote<R>(&self, f: impl FnOnce(&Self) -> R) -> R {
+        #[cfg(debug_assertions)]
+        let prev = this_cpu()
+            .remote_target
+            .replace(ptr::from_ref(self) as usize);
+        let ret = f(self);
+        #[cfg(debug_assertions)]
+        this_cpu().remote_target.set(prev);
+        ret
+    }
+
     /// Creates a new default [`PerCpu`] struct, allocates it via the page
     /// allocator and adds it to the global per-cpu area list.
     pub fn alloc(apic_id: u32) -> Result<&'static Self, SvsmError> {
-        let vaddr = allocate_zeroed_page()?;
+        let vaddr = allocate_zeroed_page_on_node(cpu_numa_node(apic_id))?;
         let percpu_ptr = vaddr.as_mut_ptr::<Self>();
         unsafe {
             (*percpu_ptr) = Self::new(apic_id);
@@ -369,9 +533,15 @@ impl PerCpu {
         &self.shared
     }
 
+    /// NUMA node of this CPU, if known. Per-CPU structures are allocated
+    /// from memory local to this node where possible.
+    pub fn numa_node(&self) -> Option<u32> {
+        cpu_numa_node(self.shared.apic_id())
+    }
+
     /// Sets up the CPU-local GHCB page.
     pub fn setup_ghcb(&self) -> Result<(), SvsmError> {
-        let ghcb_page = allocate_zeroed_page()?;
+        let ghcb_page = allocate_zeroed_page_on_node(self.numa_node())?;
         if let Err(e) = GHCB::init(ghcb_page) {
             free_page(ghcb_page);
             return Err(e);
@@ -381,22 +551,28 @@ impl PerCpu {
         Ok(())
     }
 
+    pub fn ghcb_scratch(&self) -> &GhcbScratch {
+        self.check_local();
+        &self.ghcb_scratch
+    }
+
     fn ghcb(&self) -> Option<&'static GHCB> {
+        self.check_local();
         self.ghcb.get()
     }
 
     pub fn hv_doorbell(&self) -> Option<&'static HVDoorbell> {
-        self.hv_doorbell.get().copied()
+        self.check_local();
+        self.hv_doorbell.get()
     }
 
     /// Gets a pointer to the location of the HV doorbell pointer in the
-    /// PerCpu structure. Pointers and references have the same layout, so
-    /// the return type is equivalent to `*const *const HVDoorbell`.
-    pub fn hv_doorbell_addr(&self) -> *const &'static HVDoorbell {
-        self.hv_doorbell
-            .get()
-            .map(ptr::from_ref)
-            .unwrap_or(ptr::null())
+    /// PerCpu structure. `Option<&T>` has the same layout as a nullable
+    /// pointer, so the return type is equivalent to
+    /// `*const *const HVDoorbell`. The location stays the same when the
+    /// doorbell page is replaced.
+    pub fn hv_doorbell_addr(&self) -> *const Option<&'static HVDoorbell> {
+        self.hv_doorbell.as_ptr()
     }
 
     pub fn get_top_of_stack(&self) -> VirtAddr {
@@ -407,6 +583,20 @@ impl PerCpu {
         self.ist.double_fault_stack.get().unwrap()
     }
 
+    /// Returns the name of the per-CPU stack whose guard pages contain
+    /// `vaddr`, if any.
+    pub fn stack_guard_hit(&self, vaddr: VirtAddr) -> Option<&'static str> {
+        [
+            ("init", self.init_stack.get()),
+            ("double-fault", self.ist.double_fault_stack.get()),
+        ]
+        .into_iter()
+        .find_map(|(name, top)| {
+            let bounds = MemoryRegion::from_addresses(top? - STACK_SIZE, top?);
+            in_stack_guard(bounds, vaddr).then_some(name)
+        })
+    }
+
     pub fn get_current_stack(&self) -> MemoryRegion<VirtAddr> {
         self.current_stack.get()
     }
@@ -415,6 +605,32 @@ impl PerCpu {
         self.shared().apic_id()
     }
 
+    /// Enters an NMI-like context, which must be left again with
+    /// [`PerCpu::nmi_exit()`]. Contexts may nest.
+    pub fn nmi_enter(&self) {
+        self.nmi_depth.set(self.nmi_depth.get() + 1);
+    }
+
+    pub fn nmi_exit(&self) {
+        let depth = self.nmi_depth.get();
+        debug_assert!(depth > 0, "unbalanced NMI context exit");
+        self.nmi_depth.set(depth.saturating_sub(1));
+    }
+
+    pub fn in_nmi(&self) -> bool {
+        self.nmi_depth.get() > 0
+    }
+
+    /// Total TSC cycles spent in completed interrupt processing on this CPU
+    pub fn irq_cycles(&self) -> u64 {
+        self.irq_cycles.get()
+    }
+
+    pub fn add_irq_cycles(&self, cycles: u64) {
+        self.irq_cycles
+            .set(self.irq_cycles.get().wrapping_add(cycles));
+    }
+
     fn allocate_page_table(&self) -> Result<(), SvsmError> {
         self.vm_range.initialize()?;
         let pgtable_ref = get_init_pgtable_locked().clone_shared()?;
@@ -438,18 +654,19 @@ impl PerCpu {
     }
 
     fn allocate_init_stack(&self) -> Result<(), SvsmError> {
-        let init_stack = Some(self.allocate_stack(SVSM_STACKS_INIT_TASK)?);
+        let init_stack = Some(self.allocate_stack(percpu_init_stack_base())?);
         self.init_stack.set(init_stack);
         Ok(())
     }
 
     fn allocate_ist_stacks(&self) -> Result<(), SvsmError> {
-        let double_fault_stack = self.allocate_stack(SVSM_STACK_IST_DF_BASE)?;
+        let double_fault_stack = self.allocate_stack(percpu_ist_df_stack_base())?;
         self.ist.double_fault_stack.set(Some(double_fault_stack));
         Ok(())
     }
 
     pub fn get_pgtable(&self) -> RefMut<'_, PageTableRef> {
+        self.check_local();
         self.pgtbl.borrow_mut()
     }
 
@@ -460,11 +677,36 @@ impl PerCpu {
     /// Panics if the GHCB for this CPU has not been set up via
     /// [`PerCpu::setup_ghcb()`].
     pub fn register_ghcb(&self) -> Result<(), SvsmError> {
-        self.ghcb().unwrap().register()
+        self.ghcb().unwrap().register()?;
+        self.shared.set_ghcb_state(GhcbState::Registered);
+        Ok(())
     }
 
-    fn setup_hv_doorbell(&self) -> Result<(), SvsmError> {
-        let vaddr = allocate_zeroed_page()?;
+    /// Unregisters the GHCB of this CPU from the hypervisor without
+    /// releasing the page, for instance before the CPU enters a deep idle
+    /// state or goes offline. The GHCB must not be used until
+    /// [`PerCpu::unpark_ghcb()`] is called.
+    pub fn park_ghcb(&self) -> Result<(), SvsmError> {
+        let state = self.shared.ghcb_state();
+        if state != GhcbState::Registered {
+            return Err(GhcbError::InvalidState(state).into());
+        }
+        register_ghcb_gpa_msr(PhysAddr::null())?;
+        self.shared.set_ghcb_state(GhcbState::Parked);
+        Ok(())
+    }
+
+    /// Registers a GHCB parked with [`PerCpu::park_ghcb()`] again.
+    pub fn unpark_ghcb(&self) -> Result<(), SvsmError> {
+        let state = self.shared.ghcb_state();
+        if state != GhcbState::Parked {
+            return Err(GhcbError::InvalidState(state).into());
+        }
+        self.register_ghcb()
+    }
+
+    fn alloc_hv_doorbell(&self) -> Result<&'static HVDoorbell, SvsmError> {
+        let vaddr = allocate_zeroed_page_on_node(self.numa_node())?;
         let ghcb = current_ghcb();
         if let Err(e) = HVDoorbell::init(vaddr, ghcb) {
             free_page(vaddr);
@@ -474,13 +716,74 @@ impl PerCpu {
         // initialized. The HVDoorbell type's alignment requirements are met
         // by the fact that we allocated a whole page. Mutable references to
         // the page are never created, so this cannot be mutably aliased.
-        let doorbell = unsafe { &*vaddr.as_mut_ptr::<HVDoorbell>() };
-        self.hv_doorbell
-            .set(doorbell)
-            .expect("Attempted to reinitialize the HV doorbell page");
+        Ok(unsafe { &*vaddr.as_mut_ptr::<HVDoorbell>() })
+    }
+
+    fn setup_hv_doorbell(&self) -> Result<(), SvsmError> {
+        assert!(
+            self.hv_doorbell.get().is_none(),
+            "Attempted to reinitialize the HV doorbell page"
+        );
+        let doorbell = self.alloc_hv_doorbell()?;
+        self.hv_doorbell.set(Some(doorbell));
+        self.shared
+            .set_hv_doorbell_page(VirtAddr::from(ptr::from_ref(doorbell)));
+        register_hv_doorbell_teardown();
         Ok(())
     }
 
+    /// Checks whether the hypervisor still has the #HV doorbell page of this
+    /// CPU registered, and registers a fresh page if it has dropped or
+    /// reassigned it, e.g. across a host-side save/restore. Pending events
+    /// left on the old page are carried over to the new one. Returns `true`
+    /// if the doorbell page was replaced.
+    ///
+    /// This is a no-op on CPUs that do not use an #HV doorbell page.
+    pub fn revalidate_hv_doorbell(&self) -> Result<bool, SvsmError> {
+        let Some(old) = self.hv_doorbell.get() else {
+            return Ok(false);
+        };
+
+        let ghcb = current_ghcb();
+        let features = ghcb.hypervisor_features()?;
+        if !features.contains(GHCBHvFeatures::SEV_SNP_RESTR_INJ) {
+            // Without restricted injection the SVSM cannot receive any
+            // interrupts, so there is nothing a new registration would fix.
+            log::error!(
+                "Hypervisor no longer supports restricted injection: features={}",
+                features
+            );
+            return Err(SvsmError::NotSupported);
+        }
+
+        let old_vaddr = VirtAddr::from(ptr::from_ref(old));
+        if ghcb.query_hv_doorbell()? == Some(virt_to_phys(old_vaddr)) {
+            return Ok(false);
+        }
+
+        log::warn!(
+            "#HV doorbell page of CPU {} was revoked by the hypervisor, re-registering",
+            self.get_apic_id()
+        );
+        let new = self.alloc_hv_doorbell()?;
+        // Switch to the new page before draining the old one, so that
+        // events arriving from now on are picked up from the new page by
+        // the #HV handler.
+        self.hv_doorbell.set(Some(new));
+        self.shared
+            .set_hv_doorbell_page(VirtAddr::from(ptr::from_ref(new)));
+        old.process_pending_events(None);
+        new.take_pending_from(old);
+
+        // The old page is only released once the hypervisor no longer
+        // references it; if it cannot be made private again, leak it.
+        match HVDoorbell::fini(old_vaddr) {
+            Ok(()) => free_page(old_vaddr),
+            Err(e) => log::error!("Failed to reclaim old #HV doorbell page: {:?}", e),
+        }
+        Ok(true)
+    }
+
     /// Configures the HV doorbell page if restricted injection is enabled.
     ///
     /// # Panics
@@ -490,7 +793,7 @@ impl PerCpu {
     pub fn configure_hv_doorbell(&self) -> Result<(), SvsmError> {
         // #HV doorbell configuration is only required if this system will make
         // use of restricted injection.
-        if hypervisor_ghcb_features().contains(GHCBHvFeatures::SEV_SNP_RESTR_INJ) {
+        if sev_caps().hv_doorbell() {
             self.setup_hv_doorbell()?;
         }
         Ok(())
@@ -507,27 +810,27 @@ impl PerCpu {
         let vaddr = VirtAddr::from(ptr::from_ref(self));
         let paddr = virt_to_phys(vaddr);
         let flags = PTEntryFlags::data();
-        self.get_pgtable().map_4k(SVSM_PERCPU_BASE, paddr, flags)
+        self.get_pgtable().map_4k(percpu_base(), paddr, flags)
     }
 
     pub fn map_self(&self) -> Result<(), SvsmError> {
         let vaddr = VirtAddr::from(ptr::from_ref(self));
         let paddr = virt_to_phys(vaddr);
         let self_mapping = Arc::new(VMPhysMem::new_mapping(paddr, PAGE_SIZE, true));
-        self.vm_range.insert_at(SVSM_PERCPU_BASE, self_mapping)?;
+        self.vm_range.insert_at(percpu_base(), self_mapping)?;
         Ok(())
     }
 
     fn initialize_vm_ranges(&self) -> Result<(), SvsmError> {
-        let size_4k = SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K;
-        let temp_mapping_4k = Arc::new(VMReserved::new_mapping(size_4k));
+        let region_4k = percpu_temp_region_4k();
+        let temp_mapping_4k = Arc::new(VMReserved::new_mapping(region_4k.len()));
         self.vm_range
-            .insert_at(SVSM_PERCPU_TEMP_BASE_4K, temp_mapping_4k)?;
+            .insert_at(region_4k.start(), temp_mapping_4k)?;
 
-        let size_2m = SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M;
-        let temp_mapping_2m = Arc::new(VMReserved::new_mapping(size_2m));
+        let region_2m = percpu_temp_region_2m();
+        let temp_mapping_2m = Arc::new(VMReserved::new_mapping(region_2m.len()));
         self.vm_range
-            .insert_at(SVSM_PERCPU_TEMP_BASE_2M, temp_mapping_2m)?;
+            .insert_at(region_2m.start(), temp_mapping_2m)?;
 
         Ok(())
     }
@@ -600,11 +903,15 @@ impl PerCpu {
     pub fn load(&self) {
         self.load_pgtable();
         self.load_tss();
+        self.claim();
+        PERCPU_LOADED.store(true, Ordering::Relaxed);
     }
 
     pub fn shutdown(&self) -> Result<(), SvsmError> {
+        drain_page_cache();
         if let Some(ghcb) = self.ghcb.get() {
             ghcb.shutdown()?;
+            self.shared.set_ghcb_state(GhcbState::Unregistered);
         }
         Ok(())
     }
@@ -622,7 +929,7 @@ impl PerCpu {
             return Err(SvsmError::Mem);
         }
 
-        let vaddr = allocate_new_vmsa(RMPFlags::GUEST_VMPL)?;
+        let vaddr = allocate_new_vmsa(RMPFlags::GUEST_VMPL, self.numa_node())?;
         let paddr = virt_to_phys(vaddr);
 
         // SAFETY: we have exclusive access to this memory, as we just
@@ -649,19 +956,35 @@ impl PerCpu {
     pub fn unmap_guest_vmsa(&self) {
         assert!(self.shared().apic_id == this_cpu().get_apic_id());
         // Ignore errors - the mapping might or might not be there
-        let _ = self.vm_range.remove(SVSM_PERCPU_VMSA_BASE);
+        let _ = self.vm_range.remove(percpu_vmsa_base());
     }
 
     pub fn map_guest_vmsa(&self, paddr: PhysAddr) -> Result<(), SvsmError> {
         assert!(self.shared().apic_id == this_cpu().get_apic_id());
         let vmsa_mapping = Arc::new(VMPhysMem::new_mapping(paddr, PAGE_SIZE, true));
-        self.vm_range
-            .insert_at(SVSM_PERCPU_VMSA_BASE, vmsa_mapping)?;
+        self.vm_range.insert_at(percpu_vmsa_base(), vmsa_mapping)?;
 
         Ok(())
     }
 
+    /// Looks up the registry entry of the guest VMSA at `paddr`, which is
+    /// about to be mapped on this CPU.
+    pub fn set_guest_vcpu(&self, paddr: Option<PhysAddr>) {
+        *self.guest_vcpu.borrow_mut() = paddr.and_then(|paddr| PERCPU_VMSAS.entry(paddr));
+    }
+
+    /// Changes the run state of the vCPU whose VMSA is mapped on this CPU
+    /// and returns the previous state. Unlike [`PerCpuVmsas::set_state()`],
+    /// this neither takes the registry lock nor calls the state hooks, so
+    /// it can be used on every entry to and exit from the guest.
+    pub fn set_guest_vcpu_state(&self, next: VcpuState) -> Result<VcpuState, SvsmError> {
+        let vcpu = self.guest_vcpu.borrow();
+        let entry = vcpu.as_ref().ok_or(VcpuStateError::NotRegistered)?;
+        Ok(entry.state.transition(next)?)
+    }
+
     pub fn guest_vmsa_ref(&self) -> LockGuard<'_, GuestVmsaRef> {
+        self.check_local();
         self.shared().guest_vmsa.lock()
     }
 
@@ -675,12 +998,14 @@ impl PerCpu {
             ghcb.configure_interrupt_injection(INT_INJ_VECTOR)?;
         }
 
-        let vaddr = allocate_new_vmsa(RMPFlags::GUEST_VMPL)?;
+        let vaddr = allocate_new_vmsa(RMPFlags::GUEST_VMPL, self.numa_node())?;
         let paddr = virt_to_phys(vaddr);
 
         let vmsa = vmsa_mut_ref_from_vaddr(vaddr);
         init_guest_vmsa(vmsa, self.reset_ip.get(), self.apic_emulation.get());
 
+        PERCPU_VMSAS.register(paddr, self.get_apic_id(), false)?;
+        PERCPU_VMSAS.set_used(paddr);
         self.shared().update_guest_vmsa(paddr);
 
         Ok(())
@@ -688,14 +1013,17 @@ impl PerCpu {
 
     pub fn unmap_caa(&self) {
         // Ignore errors - the mapping might or might not be there
-        let _ = self.vm_range.remove(SVSM_PERCPU_CAA_BASE);
+        let _ = self.vm_range.remove(percpu_caa_base());
+        self.caa_ref.replace(None);
     }
 
     pub fn map_guest_caa(&self, paddr: PhysAddr) -> Result<(), SvsmError> {
         self.unmap_caa();
 
+        let caa_ref = GuestPageRef::new(paddr)?;
         let caa_mapping = Arc::new(VMPhysMem::new_mapping(paddr, PAGE_SIZE, true));
-        self.vm_range.insert_at(SVSM_PERCPU_CAA_BASE, caa_mapping)?;
+        self.vm_range.insert_at(percpu_caa_base(), caa_mapping)?;
+        self.caa_ref.replace(Some(caa_ref));
 
         Ok(())
     }
@@ -742,6 +1070,7 @@ impl PerCpu {
     }
 
     pub fn read_apic_register(&self, register: u64) -> Result<u64, ApicError> {
+        self.check_local();
         let mut vmsa_ref = self.guest_vmsa_ref();
         let caa_addr = vmsa_ref.caa_addr();
         let vmsa = vmsa_ref.vmsa();
@@ -751,6 +1080,7 @@ impl PerCpu {
     }
 
     pub fn write_apic_register(&self, register: u64, value: u64) -> Result<(), ApicError> {
+        self.check_local();
         let mut vmsa_ref = self.guest_vmsa_ref();
         let caa_addr = vmsa_ref.caa_addr();
         let vmsa = vmsa_ref.vmsa();
@@ -760,6 +1090,7 @@ impl PerCpu {
     }
 
     pub fn configure_apic_vector(&self, vector: u8, allowed: bool) {
+        self.check_local();
         self.apic.borrow_mut().configure_vector(vector, allowed)
     }
 
@@ -774,18 +1105,18 @@ impl PerCpu {
 
     fn virt_range_init(&self) {
         // Initialize 4k range
-        let page_count = (SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE;
-        assert!(page_count <= VirtualRange::CAPACITY);
+        let region = percpu_temp_region_4k();
         self.vrange_4k
             .borrow_mut()
-            .init(SVSM_PERCPU_TEMP_BASE_4K, page_count, PAGE_SHIFT);
+            .init(region.start(), region.len() / PAGE_SIZE, PAGE_SHIFT);
 
         // Initialize 2M range
-        let page_count = (SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M) / PAGE_SIZE_2M;
-        assert!(page_count <= VirtualRange::CAPACITY);
-        self.vrange_2m
-            .borrow_mut()
-            .init(SVSM_PERCPU_TEMP_BASE_2M, page_count, PAGE_SHIFT_2M);
+        let region = percpu_temp_region_2m();
+        self.vrange_2m.borrow_mut().init(
+            region.start(),
+            region.len() / PAGE_SIZE_2M,
+            PAGE_SHIFT_2M,
+        );
     }
 
     /// Create a new virtual memory mapping in the PerCpu VMR
@@ -801,6 +1132,7 @@ impl PerCpu {
     ///
     /// On error, an ['SvsmError'].
     pub fn new_mapping(&self, mapping: Arc<Mapping>) -> Result<VMRMapping<'_>, SvsmError> {
+        self.check_local();
         VMRMapping::new(&self.vm_range, mapping)
     }
 
@@ -814,6 +1146,7 @@ impl PerCpu {
     }
 
     pub fn handle_pf(&self, vaddr: VirtAddr, write: bool) -> Result<(), SvsmError> {
+        self.check_local();
         self.vm_range.handle_page_fault(vaddr, write)
     }
 
@@ -824,6 +1157,7 @@ impl PerCpu {
     }
 
     pub fn schedule_prepare(&self) -> Option<(TaskPointer, TaskPointer)> {
+        self.check_local();
         let ret = self.runqueue.borrow_mut().schedule_prepare();
         if let Some((_, ref next)) = ret {
             self.current_stack.set(next.stack_bounds());
@@ -832,10 +1166,12 @@ impl PerCpu {
     }
 
     pub fn runqueue(&self) -> &RefCell<RunQueue> {
+        self.check_local();
         &self.runqueue
     }
 
     pub fn current_task(&self) -> TaskPointer {
+        self.check_local();
         self.runqueue.borrow().current_task()
     }
 
@@ -847,13 +1183,68 @@ impl PerCpu {
 }
 
 pub fn this_cpu() -> &'static PerCpu {
-    unsafe { &*SVSM_PERCPU_BASE.as_mut_ptr::<PerCpu>() }
+    unsafe { &*percpu_base().as_mut_ptr::<PerCpu>() }
+}
+
+/// Set once the BSP runs on its per-CPU page table. Secondary CPUs always
+/// start with their per-CPU data mapped.
+static PERCPU_LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the current CPU executes an NMI or `#HV` handler. The
+/// code interrupted by these handlers may hold any lock, so code reachable
+/// from them must neither wait for locks nor allocate memory while this
+/// returns `true`.
+pub fn in_nmi() -> bool {
+    PERCPU_LOADED.load(Ordering::Relaxed) && this_cpu().in_nmi()
+}
+
+/// Marks the current CPU as executing in an NMI-like context for as long
+/// as the guard is alive.
+#[derive(Debug)]
+pub struct NmiGuard {
+    _private: (),
+}
+
+impl NmiGuard {
+    pub fn enter() -> Self {
+        this_cpu().nmi_enter();
+        Self { _private: () }
+    }
+}
+
+impl Drop for NmiGuard {
+    fn drop(&mut self) {
+        this_cpu().nmi_exit();
+    }
+}
+
+/// Returns the page allocator cache of the current CPU, or `None` while
+/// the per-CPU data is not set up yet.
+pub fn this_cpu_page_cache() -> Option<&'static RefCell<PageCache>> {
+    PERCPU_LOADED
+        .load(Ordering::Relaxed)
+        .then(|| &this_cpu().page_cache)
 }
 
 pub fn this_cpu_shared() -> &'static PerCpuShared {
     this_cpu().shared()
 }
 
+/// Asks all CPUs to check the registration of their #HV doorbell pages, see
+/// [`PerCpuShared::request_hv_doorbell_check()`].
+pub fn request_hv_doorbell_checks() {
+    for cpu in PERCPU_AREAS.iter().map(PerCpuInfo::unwrap) {
+        cpu.request_hv_doorbell_check();
+    }
+}
+
+/// Register the handler for migration events from the host event channel.
+/// The host sends one after it restored or migrated the guest, which can
+/// drop the #HV doorbell registrations of all CPUs.
+pub fn hv_doorbell_events_init() -> Result<(), SvsmError> {
+    register_event_handler(EventKind::Migration, |_| request_hv_doorbell_checks())
+}
+
 /// Gets the GHCB for this CPU.
 ///
 /// # Panics
@@ -864,12 +1255,12 @@ pub fn current_ghcb() -> &'static GHCB {
     this_cpu().ghcb().unwrap()
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 pub struct VmsaRegistryEntry {
     pub paddr: PhysAddr,
     pub apic_id: u32,
     pub guest_owned: bool,
-    pub in_use: bool,
+    pub state: VcpuStateCell,
 }
 
 impl VmsaRegistryEntry {
@@ -878,9 +1269,24 @@ impl VmsaRegistryEntry {
             paddr,
             apic_id,
             guest_owned,
-            in_use: false,
+            state: VcpuStateCell::new(VcpuState::Created),
         }
     }
+
+    /// Whether the VMSA has been set up to run
+    pub fn in_use(&self) -> bool {
+        self.state.get() != VcpuState::Created
+    }
+
+    fn transition(&self, next: VcpuState) -> Result<VcpuStateChange, VcpuStateError> {
+        let from = self.state.transition(next)?;
+        Ok(VcpuStateChange {
+            apic_id: self.apic_id,
+            vmsa: self.paddr,
+            from,
+            to: next,
+        })
+    }
 }
 
 // PERCPU VMSAs to apic_id map
@@ -888,7 +1294,7 @@ pub static PERCPU_VMSAS: PerCpuVmsas = PerCpuVmsas::new();
 
 #[derive(Debug)]
 pub struct PerCpuVmsas {
-    vmsas: RWLock<Vec<VmsaRegistryEntry>>,
+    vmsas: RWLock<Vec<Arc<VmsaRegistryEntry>>>,
 }
 
 impl PerCpuVmsas {
@@ -916,32 +1322,69 @@ impl PerCpuVmsas {
             return Err(SvsmError::InvalidAddress);
         }
 
-        guard.push(VmsaRegistryEntry::new(paddr, apic_id, guest_owned));
+        guard.push(Arc::new(VmsaRegistryEntry::new(
+            paddr,
+            apic_id,
+            guest_owned,
+        )));
         Ok(())
     }
 
+    /// Returns the registry entry of the VMSA at `paddr`
+    pub fn entry(&self, paddr: PhysAddr) -> Option<Arc<VmsaRegistryEntry>> {
+        self.vmsas
+            .lock_read()
+            .iter()
+            .find(|vmsa| vmsa.paddr == paddr)
+            .cloned()
+    }
+
     pub fn set_used(&self, paddr: PhysAddr) -> Option<u32> {
+        let change = self
+            .vmsas
+            .lock_read()
+            .iter()
+            .find(|vmsa| vmsa.paddr == paddr && !vmsa.in_use())
+            .and_then(|vmsa| vmsa.transition(VcpuState::Runnable).ok())?;
+        notify_vcpu_state_change(&change);
+        Some(change.apic_id)
+    }
+
+    /// Returns the run state of the vCPU using the VMSA at `paddr`
+    pub fn state(&self, paddr: PhysAddr) -> Option<VcpuState> {
         self.vmsas
-            .lock_write()
-            .iter_mut()
-            .find(|vmsa| vmsa.paddr == paddr && !vmsa.in_use)
-            .map(|vmsa| {
-                vmsa.in_use = true;
-                vmsa.apic_id
-            })
+            .lock_read()
+            .iter()
+            .find(|vmsa| vmsa.paddr == paddr)
+            .map(|vmsa| vmsa.state.get())
+    }
+
+    /// Changes the run state of the vCPU using the VMSA at `paddr` and
+    /// returns the previous state.
+    pub fn set_state(&self, paddr: PhysAddr, next: VcpuState) -> Result<VcpuState, SvsmError> {
+        let change = self
+            .vmsas
+            .lock_read()
+            .iter()
+            .find(|vmsa| vmsa.paddr == paddr)
+            .ok_or(VcpuStateError::NotRegistered)?
+            .transition(next)?;
+        notify_vcpu_state_change(&change);
+        Ok(change.from)
     }
 
-    pub fn unregister(&self, paddr: PhysAddr, in_use: bool) -> Result<VmsaRegistryEntry, u64> {
+    pub fn unregister(&self, paddr: PhysAddr, in_use: bool) -> Result<Arc<VmsaRegistryEntry>, u64> {
         let mut guard = self.vmsas.lock_write();
         let index = guard
             .iter()
-            .position(|vmsa| vmsa.paddr == paddr && vmsa.in_use == in_use)
+            .position(|vmsa| vmsa.paddr == paddr && vmsa.in_use() == in_use)
             .ok_or(0u64)?;
 
+        let mut change = None;
         if in_use {
             let vmsa = &guard[index];
 
-            if vmsa.apic_id == 0 {
+            if vmsa.apic_id == 0 || !vmsa.guest_owned {
                 return Err(0);
             }
 
@@ -949,9 +1392,17 @@ impl PerCpuVmsas {
                 .get(vmsa.apic_id)
                 .expect("Invalid APIC-ID in VMSA registry");
             target_cpu.clear_guest_vmsa_if_match(paddr);
+
+            // The vCPU may already be halted
+            change = vmsa.transition(VcpuState::Halted).ok();
         }
 
-        Ok(guard.swap_remove(index))
+        let entry = guard.swap_remove(index);
+        drop(guard);
+        if let Some(change) = change {
+            notify_vcpu_state_change(&change);
+        }
+        Ok(entry)
     }
 }
 
@@ -974,3 +1425,26 @@ pub fn process_requests() {
 pub fn current_task() -> TaskPointer {
     this_cpu().runqueue.borrow().current_task()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn remote_access() {
+        // Per-CPU data claimed by a CPU which does not exist
+        let cpu = PerCpu::new(this_cpu().get_apic_id() + 1);
+        #[cfg(debug_assertions)]
+        cpu.claimed.set(true);
+
+        assert!(cpu.remote(|cpu| cpu.hv_doorbell()).is_none());
+
+        // A nested remote access restores the exemption of the outer one
+        let ghcb = cpu.remote(|cpu| {
+            this_cpu().remote(|local| local.ghcb());
+            cpu.ghcb()
+        });
+        assert!(ghcb.is_none());
+    }
+}
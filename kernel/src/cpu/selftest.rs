@@ -0,0 +1,367 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Guest-facing self test.
+//!
+//! Boots a tiny real-mode payload at the guest VMPL on the current CPU and
+//! uses it to issue SVSM protocol calls, so that the path from the guest
+//! VMGEXIT through the request loop and back is exercised without a full
+//! guest image.
+//!
+//! The payload runs in real mode and cannot load 64-bit register values, so
+//! it only raises the VMGEXIT. The request registers are placed in the VMSA
+//! afterwards, as if the guest had loaded them before the call.
+
+use crate::address::{PhysAddr, VirtAddr};
+use crate::cpu::flush_tlb_global_sync;
+use crate::cpu::percpu::{current_ghcb, process_requests, this_cpu, this_cpu_shared};
+use crate::cpu::vmsa::{init_guest_vmsa, vmsa_mut_ref_from_vaddr};
+use crate::error::SvsmError;
+use crate::mm::access::TypedMapping;
+use crate::mm::alloc::allocate_zeroed_page;
+use crate::mm::memory::guest_memory_regions;
+use crate::mm::{virt_to_phys, PerCPUPageMappingGuard};
+use crate::protocols::core::prevalidate_guest_page;
+use crate::protocols::errors::SvsmResultCode;
+use crate::protocols::{SVSM_APIC_PROTOCOL, SVSM_CORE_PROTOCOL};
+use crate::requests::{check_requests, update_mappings};
+use crate::sev::ghcb::switch_to_vmpl;
+use crate::sev::utils::{rmp_grant_guest_access, RMPFlags};
+use crate::sev::vmsa::{allocate_new_vmsa, VMSAControl};
+use crate::types::{PageSize, GUEST_VMPL, PAGE_SIZE};
+use core::arch::global_asm;
+use core::ptr;
+use cpuarch::vmsa::GuestVMExit;
+
+extern "C" {
+    static guest_selftest_payload_start: u8;
+    static guest_selftest_payload_end: u8;
+}
+
+// The payload flags a call as pending in the CAA (addressed through DS, whose
+// base is set to the CAA page) and writes an SNP Run VMPL request for VMPL0
+// to the GHCB MSR. Every VMGEXIT returns to the SVSM, which places the
+// request in the VMSA before processing it.
+global_asm!(
+    r#"
+        .pushsection .rodata.guest_selftest, "a"
+        .code16
+        .globl guest_selftest_payload_start
+    guest_selftest_payload_start:
+    1:
+        movb $1, %ds:0
+        movl $0xc0010130, %ecx
+        movl $0x16, %eax
+        xorl %edx, %edx
+        wrmsr
+        rep; vmmcall
+        jmp 1b
+        .globl guest_selftest_payload_end
+    guest_selftest_payload_end:
+        .code64
+        .popsection
+        "#,
+    options(att_syntax)
+);
+
+fn payload() -> &'static [u8] {
+    // SAFETY: both symbols are defined in the same section by the assembly
+    // block above, with the start symbol preceding the end symbol.
+    unsafe {
+        let start = ptr::addr_of!(guest_selftest_payload_start);
+        let end = ptr::addr_of!(guest_selftest_payload_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Register state visible to the payload for a single protocol call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct GuestCall {
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+}
+
+#[derive(Debug)]
+struct GuestSelftest {
+    vmsa_pa: PhysAddr,
+}
+
+impl GuestSelftest {
+    fn grant_page() -> Result<VirtAddr, SvsmError> {
+        let vaddr = allocate_zeroed_page()?;
+        rmp_grant_guest_access(vaddr, PageSize::Regular)?;
+        Ok(vaddr)
+    }
+
+    /// Load the payload and install its VMSA as the guest VMSA of the
+    /// current CPU. The pages are intentionally never freed, as the VMSA
+    /// stays registered with the hypervisor.
+    fn launch() -> Result<Self, SvsmError> {
+        let code = payload();
+        assert!(code.len() <= PAGE_SIZE);
+
+        let code_page = Self::grant_page()?;
+        // SAFETY: `code_page` is a freshly allocated page and the payload
+        // fits in it.
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), code_page.as_mut_ptr::<u8>(), code.len());
+        }
+        let caa_page = Self::grant_page()?;
+        flush_tlb_global_sync();
+
+        let caa_pa = virt_to_phys(caa_page);
+        let vmsa_va = allocate_new_vmsa(RMPFlags::GUEST_VMPL, None)?;
+        let vmsa_pa = virt_to_phys(vmsa_va);
+        let vmsa = vmsa_mut_ref_from_vaddr(vmsa_va);
+        init_guest_vmsa(vmsa, u64::from(virt_to_phys(code_page)), false);
+        vmsa.ds.base = u64::from(caa_pa);
+
+        let cpu = this_cpu();
+        this_cpu_shared().update_guest_vmsa_caa(vmsa_pa, caa_pa);
+        current_ghcb().register_guest_vmsa(
+            vmsa_pa,
+            u64::from(cpu.get_apic_id()),
+            GUEST_VMPL as u64,
+            vmsa.sev_features,
+        )?;
+
+        Ok(Self { vmsa_pa })
+    }
+
+    /// Run the payload until its next VMGEXIT, turn it into the protocol
+    /// call described by `call`, let the request-processing task handle it
+    /// and return the register state the guest observes afterwards.
+    fn call(&self, call: GuestCall) -> GuestCall {
+        update_mappings().expect("Self-test VMSA not mapped");
+
+        {
+            let cpu = this_cpu();
+            let mut vmsa_ref = cpu.guest_vmsa_ref();
+            vmsa_ref.vmsa().enable();
+        }
+
+        flush_tlb_global_sync();
+        switch_to_vmpl(GUEST_VMPL as u32);
+
+        {
+            let cpu = this_cpu();
+            let mut vmsa_ref = cpu.guest_vmsa_ref();
+            let vmsa = vmsa_ref.vmsa();
+            vmsa.disable();
+            assert!(matches!(vmsa.guest_exit_code, GuestVMExit::VMGEXIT));
+            vmsa.rax = call.rax;
+            vmsa.rcx = call.rcx;
+            vmsa.rdx = call.rdx;
+        }
+
+        assert!(check_requests().expect("Failed to read self-test CAA"));
+        process_requests();
+
+        let cpu = this_cpu();
+        let mut vmsa_ref = cpu.guest_vmsa_ref();
+        let vmsa = vmsa_ref.vmsa();
+        GuestCall {
+            rax: vmsa.rax,
+            rcx: vmsa.rcx,
+            rdx: vmsa.rdx,
+        }
+    }
+}
+
+impl Drop for GuestSelftest {
+    fn drop(&mut self) {
+        this_cpu_shared().clear_guest_vmsa_if_match(self.vmsa_pa);
+    }
+}
+
+const SVSM_REQ_CORE_PVALIDATE: u64 = 1;
+const SVSM_REQ_CORE_QUERY_PROTOCOL: u64 = 6;
+
+const SVSM_REQ_APIC_QUERY_FEATURES: u64 = 0;
+const SVSM_REQ_APIC_READ_REGISTER: u64 = 2;
+const SVSM_REQ_APIC_WRITE_REGISTER: u64 = 3;
+const SVSM_REQ_APIC_CONFIGURE_VECTOR: u64 = 4;
+
+const APIC_REGISTER_ISR_0: u64 = 0x810;
+const APIC_REGISTER_IRR_0: u64 = 0x820;
+const APIC_REGISTER_SELF_IPI: u64 = 0x83F;
+
+fn protocol_call(protocol: u32, request: u64) -> u64 {
+    (u64::from(protocol) << 32) | request
+}
+
+fn core_call(request: u64) -> u64 {
+    protocol_call(SVSM_CORE_PROTOCOL, request)
+}
+
+fn apic_call(request: u64) -> u64 {
+    protocol_call(SVSM_APIC_PROTOCOL, request)
+}
+
+/// Returns the addresses of the last `N` pages of guest memory, which are
+/// not used by anything else while the tests run.
+fn guest_pages<const N: usize>() -> [PhysAddr; N] {
+    let regions = guest_memory_regions().expect("Failed to read guest memory map");
+    let region = regions
+        .iter()
+        .rev()
+        .find(|r| r.len() >= N * PAGE_SIZE)
+        .expect("No guest memory for self-test");
+    core::array::from_fn(|i| region.end() - (N - i) * PAGE_SIZE)
+}
+
+#[test]
+#[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+fn guest_protocol_calls() {
+    let selftest = GuestSelftest::launch().expect("Failed to launch self-test payload");
+
+    // Unknown core call
+    let ret = selftest.call(GuestCall {
+        rax: core_call(0xff),
+        ..Default::default()
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::UNSUPPORTED_CALL));
+
+    // Querying protocol 0 version 0 succeeds and reports it as unsupported
+    let ret = selftest.call(GuestCall {
+        rax: core_call(SVSM_REQ_CORE_QUERY_PROTOCOL),
+        rcx: 0,
+        rdx: 0,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(ret.rcx, 0);
+
+    // The core protocol reports its version range, which needs the upper
+    // half of RCX
+    let ret = selftest.call(GuestCall {
+        rax: core_call(SVSM_REQ_CORE_QUERY_PROTOCOL),
+        rcx: (u64::from(SVSM_CORE_PROTOCOL) << 32) | 1,
+        rdx: 0,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(ret.rcx, (1 << 32) | 1);
+
+    // Unknown protocol
+    let ret = selftest.call(GuestCall {
+        rax: protocol_call(0x7fff_ffff, 0),
+        ..Default::default()
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::UNSUPPORTED_PROTOCOL));
+
+    // PVALIDATE requests outside of guest memory, like in the VMSA of the
+    // payload, are rejected
+    let ret = selftest.call(GuestCall {
+        rax: core_call(SVSM_REQ_CORE_PVALIDATE),
+        rcx: u64::from(selftest.vmsa_pa),
+        rdx: 0,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::INVALID_PARAMETER));
+}
+
+/// Writes a PVALIDATE request for `entry` to the guest page at `request`,
+/// issues it and returns the result code together with the index of the
+/// next entry to process as written back by the SVSM.
+fn pvalidate_call(selftest: &GuestSelftest, request: PhysAddr, entry: u64) -> (u64, u64) {
+    // Header with one entry and `next` set to zero, followed by the entry
+    let mut guard = PerCPUPageMappingGuard::create_4k(request).unwrap();
+    let mut page = TypedMapping::<u64>::new(&mut guard, 0).unwrap();
+    page.write_slice(&[1, entry]).unwrap();
+
+    let ret = selftest.call(GuestCall {
+        rax: core_call(SVSM_REQ_CORE_PVALIDATE),
+        rcx: u64::from(request),
+        rdx: 0,
+    });
+
+    let header = page.read().unwrap();
+    (ret.rax, (header >> 16) & 0xffff)
+}
+
+#[test]
+#[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+fn guest_page_state_changes() {
+    let selftest = GuestSelftest::launch().expect("Failed to launch self-test payload");
+    let [request, target] = guest_pages::<2>();
+    assert!(prevalidate_guest_page(request, PageSize::Regular).unwrap());
+
+    // Each request has a single 4K entry, bit 2 requests validation
+    let (rax, next) = pvalidate_call(&selftest, request, u64::from(target) | 4);
+    assert_eq!(rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(next, 1);
+
+    // Validating it twice fails, unless bit 3 asks to ignore unchanged
+    // pages
+    let (rax, next) = pvalidate_call(&selftest, request, u64::from(target) | 4);
+    assert_ne!(rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(next, 0);
+
+    let (rax, next) = pvalidate_call(&selftest, request, u64::from(target) | 8 | 4);
+    assert_eq!(rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(next, 1);
+
+    // Rescind the validation again
+    let (rax, next) = pvalidate_call(&selftest, request, u64::from(target));
+    assert_eq!(rax, u64::from(SvsmResultCode::SUCCESS));
+    assert_eq!(next, 1);
+
+    // Pages must be aligned to their size
+    let (rax, _) = pvalidate_call(&selftest, request, u64::from(target) | 1 | 4);
+    assert_eq!(rax, u64::from(SvsmResultCode::INVALID_PARAMETER));
+}
+
+#[test]
+#[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+fn guest_interrupt_injection() {
+    let selftest = GuestSelftest::launch().expect("Failed to launch self-test payload");
+
+    let ret = selftest.call(GuestCall {
+        rax: apic_call(SVSM_REQ_APIC_QUERY_FEATURES),
+        ..Default::default()
+    });
+    if !this_cpu().use_apic_emulation() {
+        // Without alternate injection, the protocol is not offered
+        assert_eq!(ret.rax, u64::from(SvsmResultCode::UNSUPPORTED_PROTOCOL));
+        return;
+    }
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+
+    // Allow the vector to be raised and send it to the calling vCPU
+    const VECTOR: u64 = 0x41;
+    let ret = selftest.call(GuestCall {
+        rax: apic_call(SVSM_REQ_APIC_CONFIGURE_VECTOR),
+        rcx: 0x100 | VECTOR,
+        rdx: 0,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+    let ret = selftest.call(GuestCall {
+        rax: apic_call(SVSM_REQ_APIC_WRITE_REGISTER),
+        rcx: APIC_REGISTER_SELF_IPI,
+        rdx: VECTOR,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+
+    // The payload runs with interrupts disabled, so the interrupt is
+    // presented but stays pending. Depending on whether it was injected
+    // already, it shows up in the IRR or the ISR.
+    let mut pending = 0;
+    for base in [APIC_REGISTER_IRR_0, APIC_REGISTER_ISR_0] {
+        let ret = selftest.call(GuestCall {
+            rax: apic_call(SVSM_REQ_APIC_READ_REGISTER),
+            rcx: base + (VECTOR >> 5),
+            rdx: 0,
+        });
+        assert_eq!(ret.rax, u64::from(SvsmResultCode::SUCCESS));
+        pending |= ret.rdx;
+    }
+    assert_ne!(pending & (1 << (VECTOR & 31)), 0);
+
+    // Invalid vectors are rejected
+    let ret = selftest.call(GuestCall {
+        rax: apic_call(SVSM_REQ_APIC_WRITE_REGISTER),
+        rcx: APIC_REGISTER_SELF_IPI,
+        rdx: 0x100,
+    });
+    assert_eq!(ret.rax, u64::from(SvsmResultCode::INVALID_PARAMETER));
+}
@@ -39,6 +39,7 @@ pub fn start_secondary_cpus(platform: &dyn SvsmPlatform, cpus: &[ACPICPUInfo], v
 
 #[no_mangle]
 fn start_ap() {
+    this_cpu().claim();
     this_cpu()
         .setup_on_cpu(SVSM_PLATFORM.as_dyn_ref())
         .expect("setup_on_cpu() failed");
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Run state of guest vCPUs.
+//!
+//! Every VMSA in the registry ([`PERCPU_VMSAS`](super::percpu::PERCPU_VMSAS))
+//! carries a [`VcpuState`]. Changes go through [`VcpuStateCell::transition()`],
+//! which rejects transitions the state machine below does not allow, and
+//! the registry reports every change to the hooks registered with
+//! [`register_vcpu_state_hook()`]. The exception are the changes between
+//! `Runnable` and `Running` on every entry to and exit from the guest, which
+//! each CPU makes on its own VMSA without taking the registry lock and
+//! without calling the hooks. Migration, CPU offline and reset flows
+//! query and change the state through the registry instead of inferring it
+//! from other per-CPU data.
+//!
+//! ```text
+//! Created ---> Runnable <---> Running
+//!    |          |    ^           |
+//!    |          v    |           |
+//!    |         Parked            |
+//!    |            |              |
+//!    +---------> Halted <--------+
+//! ```
+//!
+//! A halted vCPU becomes runnable again when it is reset.
+
+use crate::address::PhysAddr;
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VcpuState {
+    /// The VMSA is registered but not yet set up to run
+    Created = 0,
+    /// The vCPU can enter the guest VMPL
+    Runnable = 1,
+    /// The vCPU is executing in the guest VMPL
+    Running = 2,
+    /// The vCPU has been stopped and runs again only after a reset
+    Halted = 3,
+    /// The vCPU must not enter the guest while its state is migrated
+    Parked = 4,
+}
+
+impl VcpuState {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            0 => Self::Created,
+            1 => Self::Runnable,
+            2 => Self::Running,
+            3 => Self::Halted,
+            4 => Self::Parked,
+            _ => unreachable!("invalid vCPU state {}", raw),
+        }
+    }
+
+    /// Returns whether a vCPU in this state may change to `next`
+    pub fn can_transition_to(self, next: Self) -> bool {
+        use VcpuState::*;
+        matches!(
+            (self, next),
+            (Created, Runnable)
+                | (Created, Halted)
+                | (Runnable, Running)
+                | (Runnable, Parked)
+                | (Runnable, Halted)
+                | (Running, Runnable)
+                | (Running, Halted)
+                | (Parked, Runnable)
+                | (Parked, Halted)
+                | (Halted, Runnable)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuStateError {
+    /// No VMSA is registered at the given address
+    NotRegistered,
+    /// The state machine does not allow the transition
+    IllegalTransition(VcpuState, VcpuState),
+}
+
+impl From<VcpuStateError> for SvsmError {
+    fn from(err: VcpuStateError) -> Self {
+        Self::VcpuState(err)
+    }
+}
+
+/// A [`VcpuState`] which can be changed concurrently
+#[derive(Debug)]
+pub struct VcpuStateCell {
+    state: AtomicU8,
+}
+
+impl VcpuStateCell {
+    pub const fn new(state: VcpuState) -> Self {
+        Self {
+            state: AtomicU8::new(state as u8),
+        }
+    }
+
+    pub fn get(&self) -> VcpuState {
+        VcpuState::from_raw(self.state.load(Ordering::Acquire))
+    }
+
+    /// Changes the state to `next` if the current state allows it, and
+    /// returns the previous state.
+    pub fn transition(&self, next: VcpuState) -> Result<VcpuState, VcpuStateError> {
+        let mut current = self.get();
+        loop {
+            if !current.can_transition_to(next) {
+                return Err(VcpuStateError::IllegalTransition(current, next));
+            }
+            match self.state.compare_exchange_weak(
+                current as u8,
+                next as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(current),
+                Err(raw) => current = VcpuState::from_raw(raw),
+            }
+        }
+    }
+}
+
+/// A change of the state of a vCPU, as reported to hooks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VcpuStateChange {
+    pub apic_id: u32,
+    /// Physical address of the VMSA of the vCPU
+    pub vmsa: PhysAddr,
+    pub from: VcpuState,
+    pub to: VcpuState,
+}
+
+/// Function called after every vCPU state change. Hooks run on the CPU
+/// which made the change and must not change vCPU states themselves.
+pub type VcpuStateHook = fn(&VcpuStateChange);
+
+const MAX_HOOKS: usize = 4;
+
+static HOOKS: RWLock<[Option<VcpuStateHook>; MAX_HOOKS]> = RWLock::new([None; MAX_HOOKS]);
+
+/// Register a hook for vCPU state changes. Fails with
+/// [`SvsmError::NotSupported`] if all hook slots are used.
+pub fn register_vcpu_state_hook(hook: VcpuStateHook) -> Result<(), SvsmError> {
+    let mut hooks = HOOKS.lock_write();
+    let slot = hooks
+        .iter_mut()
+        .find(|h| h.is_none())
+        .ok_or(SvsmError::NotSupported)?;
+    *slot = Some(hook);
+    Ok(())
+}
+
+/// Report a state change to all hooks
+pub fn notify_vcpu_state_change(change: &VcpuStateChange) {
+    let hooks = *HOOKS.lock_read();
+    for hook in hooks.iter().flatten() {
+        hook(change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU32;
+
+    #[test]
+    fn transitions() {
+        let cell = VcpuStateCell::new(VcpuState::Created);
+        assert_eq!(
+            cell.transition(VcpuState::Running),
+            Err(VcpuStateError::IllegalTransition(
+                VcpuState::Created,
+                VcpuState::Running
+            ))
+        );
+        assert_eq!(cell.transition(VcpuState::Runnable), Ok(VcpuState::Created));
+        assert_eq!(cell.transition(VcpuState::Parked), Ok(VcpuState::Runnable));
+        // A parked vCPU must not run
+        assert!(cell.transition(VcpuState::Running).is_err());
+        assert_eq!(cell.get(), VcpuState::Parked);
+        assert_eq!(cell.transition(VcpuState::Halted), Ok(VcpuState::Parked));
+        assert_eq!(cell.transition(VcpuState::Runnable), Ok(VcpuState::Halted));
+        assert_eq!(cell.transition(VcpuState::Running), Ok(VcpuState::Runnable));
+        assert!(cell.transition(VcpuState::Parked).is_err());
+        assert!(!VcpuState::Halted.can_transition_to(VcpuState::Created));
+    }
+
+    static HOOK_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    fn count_hook(change: &VcpuStateChange) {
+        // Only count changes from this test, as hooks are global
+        if change.apic_id == 0xffff_fff0 {
+            HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        register_vcpu_state_hook(count_hook).unwrap();
+        let change = VcpuStateChange {
+            apic_id: 0xffff_fff0,
+            vmsa: PhysAddr::from(0x1000u64),
+            from: VcpuState::Runnable,
+            to: VcpuState::Running,
+        };
+        notify_vcpu_state_change(&change);
+        notify_vcpu_state_change(&change);
+        assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 2);
+    }
+}
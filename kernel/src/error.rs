@@ -18,11 +18,14 @@
 //! a way to convert a leaf error into a SvsmError via the [`From`] trait.
 
 use crate::cpu::vc::VcError;
+use crate::cpu::vcpu_state::VcpuStateError;
+use crate::event_channel::EventChannelError;
 use crate::fs::FsError;
 use crate::fw_cfg::FwCfgError;
 use crate::insn_decode::InsnError;
 use crate::mm::alloc::AllocError;
 use crate::sev::ghcb::GhcbError;
+use crate::sev::migration::MigrationError;
 use crate::sev::msr_protocol::GhcbMsrError;
 use crate::sev::SevSnpError;
 use crate::task::TaskError;
@@ -61,6 +64,8 @@ pub enum SvsmError {
     Firmware,
     /// Errors related to firmware configuration contents
     FwCfg(FwCfgError),
+    /// Errors related to the host event channel
+    EventChannel(EventChannelError),
     /// Errors related to ACPI parsing.
     Acpi,
     /// Errors from the filesystem.
@@ -73,6 +78,10 @@ pub enum SvsmError {
     NotSupported,
     /// Generic errors related to APIC emulation.
     Apic,
+    /// Errors of the migration transport
+    Migration(MigrationError),
+    /// Errors related to guest vCPU run states
+    VcpuState(VcpuStateError),
 }
 
 impl From<ElfError> for SvsmError {
@@ -0,0 +1,496 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Host event channel.
+//!
+//! A generic transport for events between the host and the SVSM, shared by
+//! all features that need to talk to the host (log export, migration
+//! control, management commands) instead of each inventing its own.
+//!
+//! The host advertises the channel through the IGVM parameters with a
+//! doorbell I/O port, an interrupt vector and a size in pages. The SVSM
+//! allocates a shared memory area of that size and announces it by writing
+//! its page frame number to the doorbell port. The first half of the area
+//! holds the ring for events from the host to the SVSM, the second half the
+//! ring for events from the SVSM to the host. After writing to its ring, the
+//! SVSM writes [`EVENT_CHANNEL_NOTIFY`] to the doorbell port, while the host
+//! signals new events by injecting the interrupt vector.
+//!
+//! Each ring starts with a [`RingHeader`] of [`RING_HEADER_SIZE`] bytes,
+//! followed by the ring data. Events consist of a little-endian `u16` event
+//! kind and `u16` payload length, followed by the payload, and may wrap
+//! around the end of the ring data. Since the ring memory is shared with the
+//! host, the SVSM keeps a private copy of its own ring index and validates
+//! the index written by the host on every access.
+
+extern crate alloc;
+
+use crate::address::VirtAddr;
+use crate::error::SvsmError;
+use crate::locking::{RWLock, SpinLock};
+use crate::mm::alloc::{allocate_pages, free_page, get_order};
+use crate::mm::page_visibility::{make_region_private, make_region_shared};
+use crate::mm::{virt_to_phys, PAGE_SIZE};
+use crate::platform::SVSM_PLATFORM;
+use crate::teardown::{register_teardown_hook, TeardownCtx, TeardownReason};
+use crate::utils::{zero_mem_region, ByteSize, MemoryRegion, TryVec};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU8, Ordering};
+
+/// Size of the header at the start of each ring
+pub const RING_HEADER_SIZE: usize = 64;
+const EVENT_HEADER_SIZE: usize = 4;
+
+/// Value written to the doorbell port to signal new events to the host
+pub const EVENT_CHANNEL_NOTIFY: u32 = 0;
+
+/// Event channel configuration provided by the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventChannelParams {
+    /// Doorbell I/O port
+    pub port: u16,
+    /// Interrupt vector used by the host to signal new events
+    pub vector: u8,
+    /// Size of the shared area in 4K pages, a power of two
+    pub pages: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventChannelError {
+    /// The host did not provide an event channel
+    NotConfigured,
+    /// Not enough free space in the ring
+    RingFull,
+    /// The event does not fit into the ring
+    TooLarge,
+    /// The host wrote inconsistent ring state
+    Corrupted,
+    /// Not enough memory to receive an event. The event stays in the ring.
+    OutOfMemory,
+}
+
+impl From<EventChannelError> for SvsmError {
+    fn from(err: EventChannelError) -> Self {
+        Self::EventChannel(err)
+    }
+}
+
+/// Kinds of events carried by the channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum EventKind {
+    Log = 1,
+    Migration = 2,
+    Management = 3,
+    Heartbeat = 4,
+    AttestationUpdate = 5,
+}
+
+const EVENT_KINDS: usize = 6;
+
+impl TryFrom<u16> for EventKind {
+    type Error = ();
+
+    fn try_from(kind: u16) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(Self::Log),
+            2 => Ok(Self::Migration),
+            3 => Ok(Self::Management),
+            4 => Ok(Self::Heartbeat),
+            5 => Ok(Self::AttestationUpdate),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Handler for events from the host. Called in task context from the
+/// request loop with the payload of the event.
+pub type EventHandler = fn(&[u8]);
+
+/// Header at the start of each ring. `head` is written by the producer and
+/// `tail` by the consumer; both are byte offsets into the ring data.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RingHeader {
+    head: AtomicU32,
+    tail: AtomicU32,
+}
+
+/// One direction of the event channel
+#[derive(Debug)]
+struct Ring {
+    base: VirtAddr,
+    size: usize,
+    /// Private copy of the index owned by this side: the producer index for
+    /// outgoing rings and the consumer index for incoming ones.
+    local: usize,
+}
+
+impl Ring {
+    /// Sets up a ring in the `len` bytes at `base` and resets its indices.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be 8-byte aligned and point to `len` bytes of memory
+    /// which remain valid and are not otherwise accessed by the SVSM for the
+    /// lifetime of the ring.
+    unsafe fn new(base: VirtAddr, len: usize) -> Self {
+        debug_assert!(len > RING_HEADER_SIZE + EVENT_HEADER_SIZE);
+        let ring = Self {
+            base,
+            size: len - RING_HEADER_SIZE,
+            local: 0,
+        };
+        ring.header().head.store(0, Ordering::Relaxed);
+        ring.header().tail.store(0, Ordering::Release);
+        ring
+    }
+
+    fn header(&self) -> &RingHeader {
+        // SAFETY: the header is at the aligned start of the ring memory,
+        // which is valid for the lifetime of the ring per Ring::new().
+        unsafe { &*self.base.as_ptr::<RingHeader>() }
+    }
+
+    fn data(&self) -> *mut u8 {
+        (self.base + RING_HEADER_SIZE).as_mut_ptr::<u8>()
+    }
+
+    fn used(&self, head: usize, tail: usize) -> usize {
+        (head + self.size - tail) % self.size
+    }
+
+    fn copy_in(&self, mut pos: usize, bytes: &[u8]) -> usize {
+        for b in bytes {
+            // SAFETY: `pos` is always below `self.size`, so the write is
+            // within the ring data.
+            unsafe { ptr::write_volatile(self.data().add(pos), *b) };
+            pos = (pos + 1) % self.size;
+        }
+        pos
+    }
+
+    fn copy_out(&self, mut pos: usize, buf: &mut [u8]) -> usize {
+        for b in buf.iter_mut() {
+            // SAFETY: `pos` is always below `self.size`, so the read is
+            // within the ring data.
+            *b = unsafe { ptr::read_volatile(self.data().add(pos)) };
+            pos = (pos + 1) % self.size;
+        }
+        pos
+    }
+
+    /// Append an event to an outgoing ring
+    fn push(&mut self, kind: u16, payload: &[u8]) -> Result<(), EventChannelError> {
+        let len = u16::try_from(payload.len()).map_err(|_| EventChannelError::TooLarge)?;
+        let total = EVENT_HEADER_SIZE + payload.len();
+        if total >= self.size {
+            return Err(EventChannelError::TooLarge);
+        }
+
+        let tail = self.header().tail.load(Ordering::Acquire) as usize;
+        if tail >= self.size {
+            return Err(EventChannelError::Corrupted);
+        }
+        // One byte is kept free to tell a full ring from an empty one.
+        let free = self.size - 1 - self.used(self.local, tail);
+        if total > free {
+            return Err(EventChannelError::RingFull);
+        }
+
+        let mut header = [0u8; EVENT_HEADER_SIZE];
+        header[..2].copy_from_slice(&kind.to_le_bytes());
+        header[2..].copy_from_slice(&len.to_le_bytes());
+        let pos = self.copy_in(self.local, &header);
+        self.local = self.copy_in(pos, payload);
+        self.header()
+            .head
+            .store(self.local as u32, Ordering::Release);
+        Ok(())
+    }
+
+    /// Remove the next event from an incoming ring. Returns `None` if the
+    /// ring is empty.
+    fn pop(&mut self) -> Result<Option<(u16, Vec<u8>)>, EventChannelError> {
+        let head = self.header().head.load(Ordering::Acquire) as usize;
+        if head >= self.size {
+            return Err(EventChannelError::Corrupted);
+        }
+        let used = self.used(head, self.local);
+        if used == 0 {
+            return Ok(None);
+        }
+        if used < EVENT_HEADER_SIZE {
+            return Err(EventChannelError::Corrupted);
+        }
+
+        let mut header = [0u8; EVENT_HEADER_SIZE];
+        let pos = self.copy_out(self.local, &mut header);
+        let kind = u16::from_le_bytes([header[0], header[1]]);
+        let len = usize::from(u16::from_le_bytes([header[2], header[3]]));
+        if len > used - EVENT_HEADER_SIZE {
+            return Err(EventChannelError::Corrupted);
+        }
+
+        let mut payload =
+            TryVec::from_elem(0u8, len).map_err(|_| EventChannelError::OutOfMemory)?;
+        self.local = self.copy_out(pos, &mut payload);
+        self.header()
+            .tail
+            .store(self.local as u32, Ordering::Release);
+        Ok(Some((kind, payload.into_inner())))
+    }
+}
+
+#[derive(Debug)]
+struct EventChannel {
+    rx: SpinLock<Ring>,
+    tx: SpinLock<Ring>,
+    port: u16,
+    /// Shared area holding both rings
+    area: MemoryRegion<VirtAddr>,
+    /// Set on teardown, after which the rings are no longer accessed
+    closed: AtomicBool,
+}
+
+/// The channel, leaked on initialization and never changed afterwards, or
+/// null. An atomic pointer rather than a lock, so that the teardown on
+/// panic can reach the channel without taking a lock.
+static EVENT_CHANNEL: AtomicPtr<EventChannel> = AtomicPtr::new(ptr::null_mut());
+static EVENT_HANDLERS: RWLock<[Option<EventHandler>; EVENT_KINDS]> =
+    RWLock::new([None; EVENT_KINDS]);
+static EVENTS_PENDING: AtomicBool = AtomicBool::new(false);
+/// Interrupt vector of the channel, or zero if it is not configured. Kept
+/// outside of [`EVENT_CHANNEL`], so that teardown can stop the interrupt
+/// handler from touching the rings before it closes them.
+static EVENT_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+fn event_channel() -> Option<&'static EventChannel> {
+    // SAFETY: the pointer is either null or points to the leaked channel,
+    // which is never freed.
+    unsafe { EVENT_CHANNEL.load(Ordering::Acquire).as_ref() }
+}
+
+/// Set up the event channel described by `params` and announce it to the
+/// host.
+pub fn event_channel_init(params: EventChannelParams) -> Result<(), SvsmError> {
+    if event_channel().is_some() {
+        return Err(SvsmError::NotSupported);
+    }
+
+    let size = params.pages * PAGE_SIZE;
+    let order = get_order(ByteSize::new(size)).ok_or(SvsmError::Mem)?;
+    let vaddr = allocate_pages(order)?;
+    // The host is told the PFN through a 32-bit port write.
+    let Ok(pfn) = u32::try_from(u64::from(virt_to_phys(vaddr)) / PAGE_SIZE as u64) else {
+        free_page(vaddr);
+        return Err(SvsmError::Mem);
+    };
+    if let Err(e) = make_region_shared(MemoryRegion::new(vaddr, size)) {
+        free_page(vaddr);
+        return Err(e);
+    }
+    // Shared pages have undefined contents after the page state change.
+    zero_mem_region(vaddr, vaddr + size);
+
+    let half = size / 2;
+    // SAFETY: both halves of the freshly allocated area are page aligned
+    // and the area is never freed or otherwise used by the SVSM.
+    let (rx, tx) = unsafe { (Ring::new(vaddr, half), Ring::new(vaddr + half, half)) };
+    let channel = Box::leak(Box::new(EventChannel {
+        rx: SpinLock::new(rx),
+        tx: SpinLock::new(tx),
+        port: params.port,
+        area: MemoryRegion::new(vaddr, size),
+        closed: AtomicBool::new(false),
+    }));
+
+    EVENT_CHANNEL.store(channel, Ordering::Release);
+    EVENT_VECTOR.store(params.vector, Ordering::Release);
+    SVSM_PLATFORM
+        .as_dyn_ref()
+        .get_console_io_port()
+        .outl(params.port, pfn);
+
+    log::info!(
+        "Host event channel: {} pages at PFN {:#x}, doorbell port {:#x}, vector {:#x}",
+        params.pages,
+        pfn,
+        params.port,
+        params.vector
+    );
+
+    if let Err(e) = register_teardown_hook("event channel", event_channel_teardown) {
+        log::warn!("Failed to register event channel teardown: {:?}", e);
+    }
+    Ok(())
+}
+
+/// Stops taking the event interrupt and makes the rings private again once
+/// no CPU is using them. On panic, the rings are only closed: changing the
+/// page state takes page table and GHCB locks, which a parked CPU may hold.
+fn event_channel_teardown(ctx: &TeardownCtx<'_>) -> Result<(), SvsmError> {
+    EVENT_VECTOR.store(0, Ordering::Release);
+    let Some(channel) = event_channel() else {
+        return Ok(());
+    };
+    channel.closed.store(true, Ordering::Release);
+    if ctx.reason() == TeardownReason::Panic {
+        return Ok(());
+    }
+
+    // Wait for CPUs which still access the rings
+    let _guards = loop {
+        if let (Some(rx), Some(tx)) = (channel.rx.try_lock(), channel.tx.try_lock()) {
+            break (rx, tx);
+        }
+        if ctx.expired() {
+            return Err(SvsmError::NotSupported);
+        }
+        spin_loop();
+    };
+    make_region_private(channel.area)
+}
+
+/// Register the handler for events of `kind` from the host. Fails with
+/// [`SvsmError::NotSupported`] if a handler is already registered.
+pub fn register_event_handler(kind: EventKind, handler: EventHandler) -> Result<(), SvsmError> {
+    let mut handlers = EVENT_HANDLERS.lock_write();
+    let slot = &mut handlers[kind as usize];
+    if slot.is_some() {
+        return Err(SvsmError::NotSupported);
+    }
+    *slot = Some(handler);
+    Ok(())
+}
+
+/// Send an event to the host and ring the doorbell
+pub fn event_channel_send(kind: EventKind, payload: &[u8]) -> Result<(), SvsmError> {
+    let channel = event_channel()
+        .filter(|channel| !channel.closed.load(Ordering::Acquire))
+        .ok_or(EventChannelError::NotConfigured)?;
+    let mut tx = channel.tx.lock();
+    // Teardown may have happened while waiting for the lock
+    if channel.closed.load(Ordering::Acquire) {
+        return Err(EventChannelError::NotConfigured.into());
+    }
+    tx.push(kind as u16, payload)?;
+    drop(tx);
+    SVSM_PLATFORM
+        .as_dyn_ref()
+        .get_console_io_port()
+        .outl(channel.port, EVENT_CHANNEL_NOTIFY);
+    Ok(())
+}
+
+/// Called from the interrupt handler. Returns `true` if `vector` belongs to
+/// the event channel, in which case the events are processed by the next
+/// call to [`event_channel_poll()`].
+pub fn event_channel_interrupt(vector: usize) -> bool {
+    let channel_vector = EVENT_VECTOR.load(Ordering::Acquire);
+    if channel_vector == 0 || usize::from(channel_vector) != vector {
+        return false;
+    }
+    EVENTS_PENDING.store(true, Ordering::Release);
+    true
+}
+
+/// Dispatch the events received from the host since the last call to their
+/// handlers. Events of unknown kinds or without a handler are dropped.
+pub fn event_channel_poll() {
+    if !EVENTS_PENDING.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let Some(channel) = event_channel() else {
+        return;
+    };
+
+    loop {
+        // Do not hold the ring lock while running the handler, which may
+        // send events itself.
+        let mut rx = channel.rx.lock();
+        if channel.closed.load(Ordering::Acquire) {
+            break;
+        }
+        let event = rx.pop();
+        drop(rx);
+        let (kind, payload) = match event {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("Host event channel: {:?}", e);
+                break;
+            }
+        };
+
+        let handler = EventKind::try_from(kind)
+            .ok()
+            .and_then(|kind| EVENT_HANDLERS.lock_read()[kind as usize]);
+        match handler {
+            Some(handler) => handler(&payload),
+            None => log::debug!("Dropping host event of kind {}", kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RING_LEN: usize = RING_HEADER_SIZE + 32;
+
+    fn ring(buf: &mut [u64]) -> Ring {
+        assert!(buf.len() * 8 >= RING_LEN);
+        // SAFETY: the buffer is 8-byte aligned, large enough and outlives
+        // the ring in each test.
+        unsafe { Ring::new(VirtAddr::from(buf.as_mut_ptr()), RING_LEN) }
+    }
+
+    #[test]
+    fn push_pop_wraps() {
+        let mut buf = [0u64; RING_LEN / 8];
+        let mut producer = ring(&mut buf);
+        let mut consumer = Ring {
+            local: 0,
+            ..producer
+        };
+
+        for i in 0..10u8 {
+            let payload = [i; 7];
+            producer.push(EventKind::Log as u16, &payload).unwrap();
+            let (kind, data) = consumer.pop().unwrap().unwrap();
+            assert_eq!(kind, EventKind::Log as u16);
+            assert_eq!(data, payload);
+        }
+        assert!(consumer.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn full_ring() {
+        let mut buf = [0u64; RING_LEN / 8];
+        let mut producer = ring(&mut buf);
+        assert_eq!(producer.push(1, &[0; 32]), Err(EventChannelError::TooLarge));
+        producer.push(1, &[0; 20]).unwrap();
+        assert_eq!(producer.push(1, &[0; 4]), Err(EventChannelError::RingFull));
+    }
+
+    #[test]
+    fn corrupted_indices() {
+        let mut buf = [0u64; RING_LEN / 8];
+        let mut consumer = ring(&mut buf);
+
+        consumer.header().head.store(1000, Ordering::Relaxed);
+        assert_eq!(consumer.pop(), Err(EventChannelError::Corrupted));
+
+        // An event header claiming more data than available
+        let len = 30u16.to_le_bytes();
+        consumer.copy_in(0, &[1, 0, len[0], len[1]]);
+        consumer.header().head.store(8, Ordering::Relaxed);
+        assert_eq!(consumer.pop(), Err(EventChannelError::Corrupted));
+    }
+}
@@ -134,15 +134,34 @@ impl<'a> FwCfg<'a> {
         Err(SvsmError::FwCfg(FwCfgError::FileNotFound))
     }
 
-    fn find_svsm_region(&self) -> Result<MemoryRegion<PhysAddr>, SvsmError> {
+    /// Reads the memory ranges assigned to the SVSM. The first range holds
+    /// the kernel, any further ones are additional SVSM memory.
+    fn get_svsm_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
         let file = self.file_selector("etc/sev/svsm")?;
 
-        if file.size != 16 {
+        if file.size == 0 || file.size % 16 != 0 {
             return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
         }
 
         self.select(file.selector);
-        Ok(self.read_memory_region())
+        Ok((0..file.size / 16)
+            .map(|_| self.read_memory_region())
+            .collect())
+    }
+
+    fn find_svsm_region(&self) -> Result<MemoryRegion<PhysAddr>, SvsmError> {
+        Ok(self.get_svsm_regions()?[0])
+    }
+
+    /// Returns the memory ranges assigned to the SVSM in addition to the
+    /// kernel region, which some hosts provide when they cannot assign one
+    /// contiguous range.
+    pub fn get_extra_svsm_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+        match self.get_svsm_regions() {
+            Ok(regions) => Ok(regions[1..].to_vec()),
+            Err(SvsmError::FwCfg(FwCfgError::FileNotFound)) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
     }
 
     fn read_memory_region(&self) -> MemoryRegion<PhysAddr> {
@@ -11,6 +11,7 @@ use crate::config::SvsmConfig;
 use crate::cpu::percpu::current_ghcb;
 use crate::error::SvsmError;
 use crate::kernel_region::new_kernel_region;
+use crate::mm::memory::record_guest_validation;
 use crate::mm::PerCPUPageMappingGuard;
 use crate::platform::PageStateChangeOp;
 use crate::sev::{pvalidate, rmp_adjust, PvalidateOp, RMPFlags};
@@ -390,7 +391,7 @@ fn validate_fw_mem_region(
         zero_mem_region(vaddr, vaddr + PAGE_SIZE);
     }
 
-    Ok(())
+    record_guest_validation(region, true)
 }
 
 fn validate_fw_memory_vec(
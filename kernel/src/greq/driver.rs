@@ -11,7 +11,9 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
+use core::hint::spin_loop;
 use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::{cell::OnceCell, mem::size_of};
 
 use crate::{
@@ -19,16 +21,38 @@ use crate::{
     cpu::percpu::current_ghcb,
     error::SvsmError,
     greq::msg::{SnpGuestRequestExtData, SnpGuestRequestMsg, SnpGuestRequestMsgType},
-    locking::SpinLock,
+    locking::{LockGuard, SpinLock},
     protocols::errors::{SvsmReqError, SvsmResultCode},
     sev::{ghcb::GhcbError, secrets_page, secrets_page_mut, VMPCK_SIZE},
     types::PAGE_SHIFT,
     BIT,
 };
 
-/// Global `SNP_GUEST_REQUEST` driver instance
+/// VMPCK used by the SVSM for its own `SNP_GUEST_REQUEST` commands.
+///
+/// This is the only key the SVSM may use. VMPCK1-3 belong to the software
+/// running at the lower VMPLs (e.g. OVMF and the guest kernel), which talk to
+/// the PSP directly and keep their own sequence numbers. If the SVSM sent a
+/// message with one of those keys, the sequence number tracked by the owner
+/// would fall out of sync with the PSP and its next request would fail.
+const SVSM_VMPCK: usize = 0;
+
+/// Global `SNP_GUEST_REQUEST` driver instance. Concurrent requests are
+/// serialized by the (ticket-based, hence FIFO) spinlock, see
+/// [`lock_driver()`] for how they are ordered.
 static GREQ_DRIVER: SpinLock<OnceCell<SnpGuestRequestDriver>> = SpinLock::new(OnceCell::new());
 
+/// Queue of [`GuestRequestPriority::Guest`] commands. Only the command at
+/// its head competes for [`GREQ_DRIVER`].
+static GREQ_GUEST_QUEUE: SpinLock<()> = SpinLock::new(());
+
+/// Number of [`GuestRequestPriority::Svsm`] commands waiting for
+/// [`GREQ_DRIVER`]
+static GREQ_SVSM_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Contention and error counters for [`GREQ_DRIVER`]
+static GREQ_COUNTERS: GuestRequestCounters = GuestRequestCounters::new();
+
 // Hypervisor error codes
 
 /// Buffer provided is too small
@@ -44,6 +68,71 @@ enum SnpGuestRequestClass {
     Extended = 1,
 }
 
+/// Who a `SNP_GUEST_REQUEST` command is sent for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestRequestPriority {
+    /// The SVSM itself, e.g. to handle a host event. Goes ahead of all
+    /// waiting [`Guest`](Self::Guest) commands.
+    Svsm,
+    /// A protocol call of the guest. Served in order of arrival, after all
+    /// waiting [`Svsm`](Self::Svsm) commands.
+    Guest,
+}
+
+#[derive(Debug)]
+struct GuestRequestCounters {
+    requests: AtomicU64,
+    contended: AtomicU64,
+    deferred: AtomicU64,
+    busy_retries: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl GuestRequestCounters {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            deferred: AtomicU64::new(0),
+            busy_retries: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> GuestRequestStats {
+        GuestRequestStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            deferred: self.deferred.load(Ordering::Relaxed),
+            busy_retries: self.busy_retries.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.requests.store(0, Ordering::Relaxed);
+        self.contended.store(0, Ordering::Relaxed);
+        self.deferred.store(0, Ordering::Relaxed);
+        self.busy_retries.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the `SNP_GUEST_REQUEST` statistics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestRequestStats {
+    /// Number of commands submitted
+    pub requests: u64,
+    /// Number of commands that had to wait for another command to complete
+    pub contended: u64,
+    /// Number of guest commands that let waiting SVSM commands go first
+    pub deferred: u64,
+    /// Number of times the hypervisor reported busy and the command was resent
+    pub busy_retries: u64,
+    /// Number of commands that completed with an error
+    pub failures: u64,
+}
+
 /// `SNP_GUEST_REQUEST` driver
 #[derive(Debug)]
 struct SnpGuestRequestDriver {
@@ -127,12 +216,12 @@ impl SnpGuestRequestDriver {
         Ok(driver)
     }
 
-    /// Get the last VMPCK0 sequence number accounted
+    /// Get the last VMPCK sequence number accounted
     fn seqno_last_used(&self) -> u64 {
         self.vmpck0_seqno
     }
 
-    /// Increase the VMPCK0 sequence number by two. In order to keep the
+    /// Increase the VMPCK sequence number by two. In order to keep the
     /// sequence number in-sync with the PSP, this is called only when the
     /// `SNP_GUEST_REQUEST` response is received.
     fn seqno_add_two(&mut self) {
@@ -181,8 +270,7 @@ impl SnpGuestRequestDriver {
         buffer: &[u8],
         command_len: usize,
     ) -> Result<(), SvsmReqError> {
-        // VMPL0 `SNP_GUEST_REQUEST` commands are encrypted with the VMPCK0 key
-        let vmpck0: [u8; VMPCK_SIZE] = secrets_page().get_vmpck(0);
+        let vmpck: [u8; VMPCK_SIZE] = secrets_page().get_vmpck(SVSM_VMPCK);
 
         let inbuf = buffer
             .get(..command_len)
@@ -191,7 +279,7 @@ impl SnpGuestRequestDriver {
         // For security reasons, encrypt the message in protected memory (staging)
         // and then copy the result to shared memory (request)
         self.staging
-            .encrypt_set(msg_type, msg_seqno, &vmpck0, inbuf)?;
+            .encrypt_set(msg_type, msg_seqno, SVSM_VMPCK as u8, &vmpck, inbuf)?;
         *self.request = *self.staging;
         Ok(())
     }
@@ -203,27 +291,28 @@ impl SnpGuestRequestDriver {
         msg_type: SnpGuestRequestMsgType,
         buffer: &mut [u8],
     ) -> Result<usize, SvsmReqError> {
-        let vmpck0: [u8; VMPCK_SIZE] = secrets_page().get_vmpck(0);
+        let vmpck: [u8; VMPCK_SIZE] = secrets_page().get_vmpck(SVSM_VMPCK);
 
         // For security reasons, decrypt the message in protected memory (staging)
         *self.staging = *self.response;
-        let result = self
-            .staging
-            .decrypt_get(msg_type, msg_seqno, &vmpck0, buffer);
+        let result =
+            self.staging
+                .decrypt_get(msg_type, msg_seqno, SVSM_VMPCK as u8, &vmpck, buffer);
 
         if let Err(e) = result {
             match e {
                 // The buffer provided is too small to store the unwrapped response.
-                // There is no need to clear the VMPCK0, just report it as invalid parameter.
+                // There is no need to clear the VMPCK, just report it as invalid parameter.
                 SvsmReqError::RequestError(SvsmResultCode::INVALID_PARAMETER) => (),
-                _ => secrets_page_mut().clear_vmpck(0),
+                _ => secrets_page_mut().clear_vmpck(SVSM_VMPCK),
             }
         }
 
         result
     }
 
-    /// Send the provided VMPL0 `SNP_GUEST_REQUEST` command to the PSP.
+    /// Send the provided `SNP_GUEST_REQUEST` command to the PSP, protected
+    /// with the VMPCK0 key.
     ///
     /// The command will be encrypted using AES-256 GCM.
     ///
@@ -248,7 +337,7 @@ impl SnpGuestRequestDriver {
         buffer: &mut [u8],
         command_len: usize,
     ) -> Result<usize, SvsmReqError> {
-        if secrets_page().is_vmpck_clear(0) {
+        if secrets_page().is_vmpck_clear(SVSM_VMPCK) {
             return Err(SvsmReqError::invalid_request());
         }
 
@@ -257,7 +346,7 @@ impl SnpGuestRequestDriver {
         // The sequence number is restored only when the guest is rebooted.
         let Some(msg_seqno) = self.seqno_last_used().checked_add(1) else {
             log::error!("SNP_GUEST_REQUEST: sequence number overflow");
-            secrets_page_mut().clear_vmpck(0);
+            secrets_page_mut().clear_vmpck(SVSM_VMPCK);
             return Err(SvsmReqError::invalid_request());
         };
 
@@ -280,22 +369,23 @@ impl SnpGuestRequestDriver {
                                 log::error!(
                                     "SNP_GUEST_REQ_INVALID_LEN. Aborting, request resend failed"
                                 );
-                                secrets_page_mut().clear_vmpck(0);
+                                secrets_page_mut().clear_vmpck(SVSM_VMPCK);
                                 return Err(e1);
                             }
                             return Err(e);
                         } else {
                             // We sent a regular SNP_GUEST_REQUEST, but the hypervisor returned
                             // an error code that is exclusive for extended SNP_GUEST_REQUEST
-                            secrets_page_mut().clear_vmpck(0);
+                            secrets_page_mut().clear_vmpck(SVSM_VMPCK);
                             return Err(SvsmReqError::invalid_request());
                         }
                     }
                     // The hypervisor is busy.
                     SNP_GUEST_REQ_ERR_BUSY => {
+                        GREQ_COUNTERS.busy_retries.fetch_add(1, Ordering::Relaxed);
                         if let Err(e2) = self.send(req_class) {
                             log::error!("SNP_GUEST_REQ_ERR_BUSY. Aborting, request resend failed");
-                            secrets_page_mut().clear_vmpck(0);
+                            secrets_page_mut().clear_vmpck(SVSM_VMPCK);
                             return Err(e2);
                         }
                         // ... request resend worked, continue normally.
@@ -304,7 +394,7 @@ impl SnpGuestRequestDriver {
                     // the AMD SEV-SNP spec or in the linux kernel include/uapi/linux/psp-sev.h
                     _ => {
                         log::error!("SNP_GUEST_REQUEST failed, unknown error code={}\n", info2);
-                        secrets_page_mut().clear_vmpck(0);
+                        secrets_page_mut().clear_vmpck(SVSM_VMPCK);
                         return Err(e);
                     }
                 }
@@ -369,29 +459,102 @@ pub fn guest_request_driver_init() {
     });
 }
 
+/// Returns whether the global `SnpGuestRequestDriver` has been initialized
+pub fn guest_request_driver_ready() -> bool {
+    GREQ_DRIVER.lock().get().is_some()
+}
+
+/// Return the `SNP_GUEST_REQUEST` statistics collected so far
+pub fn guest_request_stats() -> GuestRequestStats {
+    GREQ_COUNTERS.snapshot()
+}
+
+/// Reset the `SNP_GUEST_REQUEST` statistics
+pub fn guest_request_stats_reset() {
+    GREQ_COUNTERS.reset();
+}
+
+/// Lock the driver, accounting whether we had to wait for it.
+///
+/// A command in progress cannot be interrupted, but SVSM commands never
+/// queue up behind guest commands: guest commands wait in their own queue,
+/// and the one at its head only takes the driver while no SVSM command is
+/// waiting for it.
+fn lock_driver(
+    priority: GuestRequestPriority,
+) -> LockGuard<'static, OnceCell<SnpGuestRequestDriver>> {
+    GREQ_COUNTERS.requests.fetch_add(1, Ordering::Relaxed);
+    match priority {
+        GuestRequestPriority::Svsm => {
+            if let Some(guard) = GREQ_DRIVER.try_lock() {
+                return guard;
+            }
+            GREQ_COUNTERS.contended.fetch_add(1, Ordering::Relaxed);
+            GREQ_SVSM_WAITING.fetch_add(1, Ordering::AcqRel);
+            let guard = GREQ_DRIVER.lock();
+            GREQ_SVSM_WAITING.fetch_sub(1, Ordering::AcqRel);
+            guard
+        }
+        GuestRequestPriority::Guest => {
+            let mut contended = false;
+            let mut deferred = false;
+            let _turn = GREQ_GUEST_QUEUE.try_lock().unwrap_or_else(|| {
+                contended = true;
+                GREQ_GUEST_QUEUE.lock()
+            });
+            let guard = loop {
+                if GREQ_SVSM_WAITING.load(Ordering::Acquire) != 0 {
+                    deferred = true;
+                } else if let Some(guard) = GREQ_DRIVER.try_lock() {
+                    break guard;
+                }
+                contended = true;
+                spin_loop();
+            };
+            if contended {
+                GREQ_COUNTERS.contended.fetch_add(1, Ordering::Relaxed);
+            }
+            if deferred {
+                GREQ_COUNTERS.deferred.fetch_add(1, Ordering::Relaxed);
+            }
+            guard
+        }
+    }
+}
+
+/// Account a failed command in the statistics
+fn account_result<T>(result: Result<T, SvsmReqError>) -> Result<T, SvsmReqError> {
+    if result.is_err() {
+        GREQ_COUNTERS.failures.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
 /// Send the provided regular `SNP_GUEST_REQUEST` command to the PSP.
 /// Further details can be found in the `SnpGuestRequestDriver.send_request()` documentation.
 pub fn send_regular_guest_request(
+    priority: GuestRequestPriority,
     msg_type: SnpGuestRequestMsgType,
     buffer: &mut [u8],
     request_len: usize,
 ) -> Result<usize, SvsmReqError> {
-    let mut cell = GREQ_DRIVER.lock();
+    let mut cell = lock_driver(priority);
     let driver: &mut SnpGuestRequestDriver =
         cell.get_mut().ok_or_else(SvsmReqError::invalid_request)?;
-    driver.send_regular_guest_request(msg_type, buffer, request_len)
+    account_result(driver.send_regular_guest_request(msg_type, buffer, request_len))
 }
 
-/// Send the provided extended `SNP_GUEST_REQUEST` command to the PSP
+/// Send the provided extended `SNP_GUEST_REQUEST` command to the PSP.
 /// Further details can be found in the `SnpGuestRequestDriver.send_request()` documentation.
 pub fn send_extended_guest_request(
+    priority: GuestRequestPriority,
     msg_type: SnpGuestRequestMsgType,
     buffer: &mut [u8],
     request_len: usize,
     certs: &mut [u8],
 ) -> Result<usize, SvsmReqError> {
-    let mut cell = GREQ_DRIVER.lock();
+    let mut cell = lock_driver(priority);
     let driver: &mut SnpGuestRequestDriver =
         cell.get_mut().ok_or_else(SvsmReqError::invalid_request)?;
-    driver.send_extended_guest_request(msg_type, buffer, request_len, certs)
+    account_result(driver.send_extended_guest_request(msg_type, buffer, request_len, certs))
 }
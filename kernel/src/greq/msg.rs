@@ -106,7 +106,12 @@ const _: () = assert!(size_of::<SnpGuestRequestMsgHdr>() <= u16::MAX as usize);
 
 impl SnpGuestRequestMsgHdr {
     /// Allocate a new [`SnpGuestRequestMsgHdr`] and initialize it
-    pub fn new(msg_sz: u16, msg_type: SnpGuestRequestMsgType, msg_seqno: u64) -> Self {
+    pub fn new(
+        msg_sz: u16,
+        msg_type: SnpGuestRequestMsgType,
+        msg_seqno: u64,
+        msg_vmpck: u8,
+    ) -> Self {
         Self {
             msg_seqno,
             algo: SnpGuestRequestAead::Aes256Gcm as u8,
@@ -115,7 +120,7 @@ impl SnpGuestRequestMsgHdr {
             msg_type: msg_type as u8,
             msg_version: MSG_VERSION,
             msg_sz,
-            msg_vmpck: 0,
+            msg_vmpck,
             ..Default::default()
         }
     }
@@ -134,12 +139,13 @@ impl SnpGuestRequestMsgHdr {
         &self,
         msg_type: SnpGuestRequestMsgType,
         msg_seqno: u64,
+        msg_vmpck: u8,
     ) -> Result<(), SvsmReqError> {
         if self.hdr_version != HDR_VERSION
             || self.hdr_sz != MSG_HDR_SIZE as u16
             || self.algo != SnpGuestRequestAead::Aes256Gcm as u8
             || self.msg_type != msg_type as u8
-            || self.msg_vmpck != 0
+            || self.msg_vmpck != msg_vmpck
             || self.msg_seqno != msg_seqno
         {
             return Err(SvsmReqError::invalid_format());
@@ -261,11 +267,12 @@ impl SnpGuestRequestMsg {
     /// # Arguments
     ///
     /// * `msg_type`: Type of the command stored in the `command` buffer.
-    /// * `msg_seqno`: VMPL0 sequence number to be used in the message. The PSP will reject
+    /// * `msg_seqno`: VMPCK sequence number to be used in the message. The PSP will reject
     ///                subsequent messages when it detects that the sequence numbers are
     ///                out of sync. The sequence number is also used as initialization
     ///                vector (IV) in encryption.
-    /// * `vmpck0`: VMPCK0 key that will be used to encrypt the command.
+    /// * `vmpck_id`: ID of the VMPCK that protects the message.
+    /// * `vmpck`: VMPCK key that will be used to encrypt the command.
     /// * `command`: command slice to be encrypted.
     ///
     /// # Returns
@@ -280,20 +287,22 @@ impl SnpGuestRequestMsg {
         &mut self,
         msg_type: SnpGuestRequestMsgType,
         msg_seqno: u64,
-        vmpck0: &[u8; VMPCK_SIZE],
+        vmpck_id: u8,
+        vmpck: &[u8; VMPCK_SIZE],
         command: &[u8],
     ) -> Result<(), SvsmReqError> {
         let payload_size_u16 =
             u16::try_from(command.len()).map_err(|_| SvsmReqError::invalid_parameter())?;
 
-        let mut msg_hdr = SnpGuestRequestMsgHdr::new(payload_size_u16, msg_type, msg_seqno);
+        let mut msg_hdr =
+            SnpGuestRequestMsgHdr::new(payload_size_u16, msg_type, msg_seqno, vmpck_id);
         let aad: &[u8] = msg_hdr.get_aad_slice();
         let iv: [u8; IV_SIZE] = build_iv(msg_seqno);
 
         self.pld.fill(0);
 
         // Encrypt the provided command and store the result in the message payload
-        let authtag_end: usize = Aes256Gcm::encrypt(&iv, vmpck0, aad, command, &mut self.pld)?;
+        let authtag_end: usize = Aes256Gcm::encrypt(&iv, vmpck, aad, command, &mut self.pld)?;
 
         // In the Aes256Gcm encrypt API, the authtag is postfixed (comes after the encrypted payload)
         let ciphertext_end: usize = authtag_end - AUTHTAG_SIZE;
@@ -324,8 +333,9 @@ impl SnpGuestRequestMsg {
     /// # Arguments
     ///
     /// * `msg_type`: Type of the command stored in the message payload
-    /// * `msg_seqno`: VMPL0 sequence number that was used in the message.
-    /// * `vmpck0`: VMPCK0 key, it will be used to decrypt the message
+    /// * `msg_seqno`: VMPCK sequence number that was used in the message.
+    /// * `vmpck_id`: ID of the VMPCK that is expected to protect the message.
+    /// * `vmpck`: VMPCK key, it will be used to decrypt the message
     /// * `outbuf`: buffer that will be used to store the decrypted message payload
     ///
     /// # Returns
@@ -338,10 +348,11 @@ impl SnpGuestRequestMsg {
         &mut self,
         msg_type: SnpGuestRequestMsgType,
         msg_seqno: u64,
-        vmpck0: &[u8; VMPCK_SIZE],
+        vmpck_id: u8,
+        vmpck: &[u8; VMPCK_SIZE],
         outbuf: &mut [u8],
     ) -> Result<usize, SvsmReqError> {
-        self.hdr.validate(msg_type, msg_seqno)?;
+        self.hdr.validate(msg_type, msg_seqno, vmpck_id)?;
 
         let iv: [u8; IV_SIZE] = build_iv(msg_seqno);
         let aad: &[u8] = self.hdr.get_aad_slice();
@@ -369,7 +380,7 @@ impl SnpGuestRequestMsg {
             .get(..tag_end)
             .ok_or_else(SvsmReqError::invalid_request)?;
 
-        let outbuf_len: usize = Aes256Gcm::decrypt(&iv, vmpck0, aad, inbuf, outbuf)?;
+        let outbuf_len: usize = Aes256Gcm::decrypt(&iv, vmpck, aad, inbuf, outbuf)?;
 
         Ok(outbuf_len)
     }
@@ -481,6 +492,7 @@ impl SnpGuestRequestExtData {
 mod tests {
     use super::*;
     use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+    use crate::protocols::errors::SvsmResultCode;
     use core::mem::offset_of;
 
     #[test]
@@ -544,6 +556,7 @@ mod tests {
         msg.encrypt_set(
             SnpGuestRequestMsgType::ReportRequest,
             vmpck0_seqno,
+            0,
             &vmpck0,
             PLAINTEXT,
         )
@@ -555,6 +568,7 @@ mod tests {
             .decrypt_get(
                 SnpGuestRequestMsgType::ReportRequest,
                 vmpck0_seqno,
+                0,
                 &vmpck0,
                 &mut outbuf,
             )
@@ -564,4 +578,40 @@ mod tests {
 
         assert_eq!(outbuf, PLAINTEXT);
     }
+
+    #[test]
+    fn decrypt_rejects_vmpck_mismatch() {
+        let mut msg = SnpGuestRequestMsg {
+            hdr: SnpGuestRequestMsgHdr::default(),
+            pld: [0; MSG_PAYLOAD_SIZE],
+        };
+
+        const PLAINTEXT: &[u8] = b"request-to-be-encrypted";
+        let vmpck1 = [7u8; VMPCK_SIZE];
+        let vmpck1_seqno: u64 = 1;
+
+        msg.encrypt_set(
+            SnpGuestRequestMsgType::ReportRequest,
+            vmpck1_seqno,
+            1,
+            &vmpck1,
+            PLAINTEXT,
+        )
+        .unwrap();
+
+        let mut outbuf = [0u8; PLAINTEXT.len()];
+
+        let result = msg.decrypt_get(
+            SnpGuestRequestMsgType::ReportRequest,
+            vmpck1_seqno,
+            0,
+            &vmpck1,
+            &mut outbuf,
+        );
+
+        assert!(matches!(
+            result,
+            Err(SvsmReqError::RequestError(SvsmResultCode::INVALID_FORMAT))
+        ));
+    }
 }
@@ -111,6 +111,11 @@ impl SnpReportResponse {
 
         Ok(())
     }
+
+    /// The attestation report generated by firmware
+    pub fn report(&self) -> &AttestationReport {
+        &self.report
+    }
 }
 
 /// The `TCB_VERSION` contains the security version numbers of each
@@ -135,6 +140,12 @@ struct Signature {
     reserved: [u8; 368],
 }
 
+/// Number of bytes at the start of a report covered by its signature
+pub const REPORT_SIGNED_SIZE: usize = 0x2a0;
+
+/// `SIGNATURE_ALGO` of reports signed with ECDSA P-384 with SHA-384
+pub const SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+
 /// ATTESTATION_REPORT format (AMD SEV-SNP spec. table 21)
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
@@ -190,6 +201,60 @@ pub struct AttestationReport {
     signature: Signature,
 }
 
+impl AttestationReport {
+    /// Guest policy bit allowing the guest to be debugged by the hypervisor
+    pub const POLICY_DEBUG: u64 = 1 << 19;
+
+    /// Take a slice and return a reference for Self
+    pub fn try_from_as_ref(buffer: &[u8]) -> Result<&Self, SvsmReqError> {
+        let buffer = buffer
+            .get(..size_of::<Self>())
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+
+        // SAFETY: AttestationReport has no invalid representations, as it is
+        // comprised entirely of integer types. It is repr(packed), so its
+        // required alignment is simply 1. We have checked the size, so this
+        // is entirely safe.
+        let report = unsafe { &*buffer.as_ptr().cast::<Self>() };
+        Ok(report)
+    }
+
+    /// The raw bytes of the report, as signed by the firmware
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the report is comprised entirely of integer types without
+        // padding, so all of its bytes are initialized.
+        unsafe {
+            core::slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>())
+        }
+    }
+
+    /// The guest policy the guest was launched with
+    pub fn policy(&self) -> u64 {
+        self.policy
+    }
+
+    /// The guest-provided data the report was requested with
+    pub fn report_data(&self) -> [u8; USER_DATA_SIZE] {
+        self.report_data
+    }
+
+    /// The launch measurement of the guest
+    pub fn measurement(&self) -> [u8; 48] {
+        self.measurement
+    }
+
+    /// The algorithm the report is signed with
+    pub fn signature_algo(&self) -> u32 {
+        self.signature_algo
+    }
+
+    /// The R and S components of the signature, little-endian and
+    /// zero-extended to 72 bytes
+    pub fn signature(&self) -> ([u8; 72], [u8; 72]) {
+        (self.signature.r, self.signature.s)
+    }
+}
+
 const _: () = assert!(size_of::<AttestationReport>() <= u32::MAX as usize);
 
 #[cfg(test)]
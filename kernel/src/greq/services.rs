@@ -7,11 +7,13 @@
 //! API to send `SNP_GUEST_REQUEST` commands to the PSP
 
 use crate::{
+    error::SvsmError,
     greq::{
-        driver::{send_extended_guest_request, send_regular_guest_request},
+        driver::{send_extended_guest_request, send_regular_guest_request, GuestRequestPriority},
         msg::SnpGuestRequestMsgType,
         pld_report::{SnpReportRequest, SnpReportResponse},
     },
+    mm::object_cache::{CachedObject, ObjectCache},
     protocols::errors::SvsmReqError,
 };
 use core::mem::size_of;
@@ -19,7 +21,24 @@ use core::mem::size_of;
 const REPORT_REQUEST_SIZE: usize = size_of::<SnpReportRequest>();
 const REPORT_RESPONSE_SIZE: usize = size_of::<SnpReportResponse>();
 
-fn get_report(buffer: &mut [u8], certs: Option<&mut [u8]>) -> Result<usize, SvsmReqError> {
+/// Buffer for a `MSG_REPORT_REQ` command and its `MSG_REPORT_RESP`
+pub type ReportBuffer = [u8; REPORT_RESPONSE_SIZE];
+
+static REPORT_BUFFERS: ObjectCache<ReportBuffer> =
+    ObjectCache::new("report", || [0; REPORT_RESPONSE_SIZE]);
+
+/// Allocates a zeroed buffer for [`get_regular_report()`] or
+/// [`get_extended_report()`]. As is, it holds a `MSG_REPORT_REQ` for a VMPL0
+/// report without user data.
+pub fn alloc_report_buffer() -> Result<CachedObject<'static, ReportBuffer>, SvsmError> {
+    REPORT_BUFFERS.alloc_with([0; REPORT_RESPONSE_SIZE])
+}
+
+fn get_report(
+    priority: GuestRequestPriority,
+    buffer: &mut [u8],
+    certs: Option<&mut [u8]>,
+) -> Result<usize, SvsmReqError> {
     let request: &SnpReportRequest = SnpReportRequest::try_from_as_ref(buffer)?;
     // Non-VMPL0 attestation reports can be requested by the guest kernel
     // directly to the PSP.
@@ -28,12 +47,14 @@ fn get_report(buffer: &mut [u8], certs: Option<&mut [u8]>) -> Result<usize, Svsm
     }
     let response_len = if certs.is_none() {
         send_regular_guest_request(
+            priority,
             SnpGuestRequestMsgType::ReportRequest,
             buffer,
             REPORT_REQUEST_SIZE,
         )?
     } else {
         send_extended_guest_request(
+            priority,
             SnpGuestRequestMsgType::ReportRequest,
             buffer,
             REPORT_REQUEST_SIZE,
@@ -59,6 +80,7 @@ fn get_report(buffer: &mut [u8], certs: Option<&mut [u8]>) -> Result<usize, Svsm
 ///
 /// # Arguments
 ///
+/// * `priority`: Who the report is requested for
 /// * `buffer`: Buffer with the [`MSG_REPORT_REQ`](SnpReportRequest) command that will be
 ///             sent to the PSP. It must be large enough to hold the
 ///             [`MSG_REPORT_RESP`](SnpReportResponse) received from the PSP.
@@ -70,8 +92,11 @@ fn get_report(buffer: &mut [u8], certs: Option<&mut [u8]>) -> Result<usize, Svsm
 ///        [`MSG_REPORT_RESP`](SnpReportResponse) size.
 /// * Error
 ///     * [`SvsmReqError`]
-pub fn get_regular_report(buffer: &mut [u8]) -> Result<usize, SvsmReqError> {
-    get_report(buffer, None)
+pub fn get_regular_report(
+    priority: GuestRequestPriority,
+    buffer: &mut [u8],
+) -> Result<usize, SvsmReqError> {
+    get_report(priority, buffer, None)
 }
 
 /// Request an extended VMPL0 attestation report to the PSP.
@@ -85,6 +110,7 @@ pub fn get_regular_report(buffer: &mut [u8]) -> Result<usize, SvsmReqError> {
 ///
 /// # Arguments
 ///
+/// * `priority`: Who the report is requested for
 /// * `buffer`: Buffer with the [`MSG_REPORT_REQ`](SnpReportRequest) command that will be
 ///             sent to the PSP. It must be large enough to hold the
 ///             [`MSG_REPORT_RESP`](SnpReportResponse) received from the PSP.
@@ -101,6 +127,10 @@ pub fn get_regular_report(buffer: &mut [u8]) -> Result<usize, SvsmReqError> {
 ///         * `certs` is not large enough to hold the certificates.
 ///             * `certs_buffer_size`: number of bytes required.
 ///             * `psp_rc`: PSP return code
-pub fn get_extended_report(buffer: &mut [u8], certs: &mut [u8]) -> Result<usize, SvsmReqError> {
-    get_report(buffer, Some(certs))
+pub fn get_extended_report(
+    priority: GuestRequestPriority,
+    buffer: &mut [u8],
+    certs: &mut [u8],
+) -> Result<usize, SvsmReqError> {
+    get_report(priority, buffer, Some(certs))
 }
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Handling of TCB and certificate updates signalled by the host.
+//!
+//! When the host updates the platform TCB or the VCEK certificate chain, data
+//! derived from earlier attestation reports and the cached certificates
+//! become stale. The host signals such an update by sending an
+//! [`EventKind::AttestationUpdate`] event on the host event channel. Since
+//! the signal may arrive in interrupt context, it only marks the update as
+//! pending, and the request loop processes it with
+//! [`attestation_update_poll()`]. Processing invalidates the cached
+//! attestation data, refetches the certificate chain and raises the
+//! subscribed interrupt vector on every guest vCPU that asked to be
+//! notified.
+
+extern crate alloc;
+
+use crate::cpu::percpu::PERCPU_AREAS;
+use crate::error::SvsmError;
+use crate::event_channel::{register_event_handler, EventKind};
+use crate::greq::driver::GuestRequestPriority;
+use crate::greq::msg::SNP_GUEST_REQ_MAX_DATA_SIZE;
+use crate::greq::services::{alloc_report_buffer, get_extended_report};
+use crate::locking::{RWLock, SpinLock};
+use crate::mm::alloc::AllocError;
+use crate::protocols::debug::invalidate_debug_policy;
+use crate::protocols::errors::SvsmReqError;
+use crate::utils::TryVec;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+static UPDATE_PENDING: AtomicBool = AtomicBool::new(false);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static CERTIFICATES: SpinLock<Option<TryVec<u8>>> = SpinLock::new(None);
+/// APIC IDs of the guest vCPUs to notify and the vectors to raise
+static SUBSCRIBERS: RWLock<Vec<(u32, u8)>> = RWLock::new(Vec::new());
+
+/// Register the handler for update events from the host event channel
+pub fn attestation_update_init() -> Result<(), SvsmError> {
+    register_event_handler(EventKind::AttestationUpdate, |_| {
+        signal_attestation_update()
+    })
+}
+
+/// Mark a TCB or certificate update as pending. Safe to call from interrupt
+/// context.
+pub fn signal_attestation_update() {
+    UPDATE_PENDING.store(true, Ordering::Release);
+}
+
+/// Number of updates processed so far. Data derived from attestation
+/// reports is stale if the generation changed since it was obtained.
+pub fn attestation_generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Process a pending update. Called from the request loop.
+pub fn attestation_update_poll() {
+    if !UPDATE_PENDING.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    let generation = GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    log::info!("Processing TCB/certificate update {}", generation);
+
+    invalidate_debug_policy();
+    *CERTIFICATES.lock() = None;
+    if let Err(e) = refresh_certificates() {
+        // The chain is fetched again on the next request for it
+        log::warn!("Failed to refresh SEV-SNP certificates: {:?}", e);
+    }
+
+    notify_subscribers();
+}
+
+fn fetch_certificates() -> Result<TryVec<u8>, SvsmReqError> {
+    // An all-zero MSG_REPORT_REQ asks for a VMPL0 report with no user data
+    let mut report = alloc_report_buffer()?;
+    let mut certs = TryVec::from_elem(0u8, SNP_GUEST_REQ_MAX_DATA_SIZE)?;
+    get_extended_report(GuestRequestPriority::Svsm, &mut report[..], &mut certs)?;
+    Ok(certs)
+}
+
+fn refresh_certificates() -> Result<(), SvsmReqError> {
+    let certs = fetch_certificates()?;
+    *CERTIFICATES.lock() = Some(certs);
+    Ok(())
+}
+
+/// Copy the cached certificate chain into `buf`, fetching it from the host
+/// first if it is not cached.
+///
+/// # Returns
+///
+/// The size of the certificate table on success. If `buf` is too small, it
+/// is left untouched and the call fails with an invalid parameter error.
+pub fn copy_certificates(buf: &mut [u8]) -> Result<usize, SvsmReqError> {
+    if CERTIFICATES.lock().is_none() {
+        refresh_certificates()?;
+    }
+
+    let certs = CERTIFICATES.lock();
+    let certs = certs.as_ref().ok_or_else(SvsmReqError::invalid_request)?;
+    let dst = buf
+        .get_mut(..certs.len())
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+    dst.copy_from_slice(certs);
+    Ok(certs.len())
+}
+
+/// Raise `vector` on the guest vCPU with `apic_id` after every update,
+/// replacing an earlier subscription of that vCPU. A `vector` of zero
+/// removes the subscription.
+pub fn subscribe_attestation_updates(apic_id: u32, vector: u8) -> Result<(), SvsmError> {
+    let mut subscribers = SUBSCRIBERS.lock_write();
+    subscribers.retain(|(id, _)| *id != apic_id);
+    if vector != 0 {
+        subscribers
+            .try_reserve(1)
+            .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+        subscribers.push((apic_id, vector));
+    }
+    Ok(())
+}
+
+fn notify_subscribers() {
+    for &(apic_id, vector) in SUBSCRIBERS.lock_read().iter() {
+        match PERCPU_AREAS.get(apic_id) {
+            Some(cpu) => cpu.request_ipi(vector),
+            None => log::warn!("Update subscriber with unknown APIC ID {}", apic_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscriptions() {
+        // APIC IDs no test CPU uses, as the subscriber list is global
+        subscribe_attestation_updates(0xfff0, 0x40).unwrap();
+        subscribe_attestation_updates(0xfff1, 0x41).unwrap();
+        subscribe_attestation_updates(0xfff0, 0x42).unwrap();
+        subscribe_attestation_updates(0xfff1, 0).unwrap();
+
+        let subscribers = SUBSCRIBERS.lock_read();
+        let ours: Vec<_> = subscribers.iter().filter(|(id, _)| *id >= 0xfff0).collect();
+        assert_eq!(ours, [&(0xfff0, 0x42)]);
+    }
+}
@@ -0,0 +1,496 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Verification of attestation reports.
+//!
+//! An attestation report is signed by the VCEK of the chip which produced
+//! it, with ECDSA P-384. The VCEK certificate is signed by the AMD SEV key
+//! (ASK), whose certificate is signed by the AMD root key (ARK), both with
+//! RSASSA-PSS. The host provides the certificates along with extended
+//! reports, in a table of GUID-tagged entries. A [`VcekVerifier`] checks the
+//! chain against a trusted ARK and then the report signature.
+//!
+//! Certificates are only parsed as far as needed to find the signed part,
+//! the signature and the public key. The validity period and extensions are
+//! not checked, as the SVSM has no trusted time source and the chain is
+//! anchored in a single trusted root.
+
+use super::pld_report::{AttestationReport, REPORT_SIGNED_SIZE, SIG_ALGO_ECDSA_P384_SHA384};
+use p384::ecdsa::signature::Verifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use p384::elliptic_curve::bigint::modular::runtime_mod::{DynResidue, DynResidueParams};
+use p384::elliptic_curve::bigint::{Encoding, U4096};
+use p384::FieldBytes;
+use sha2::{Digest, Sha384};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A certificate is missing, malformed or has an unsupported key
+    Certificate,
+    /// The root certificate does not carry the trusted ARK
+    UntrustedRoot,
+    /// A certificate or the report has an invalid signature
+    Signature,
+}
+
+/// GUIDs of the entries of the certificate table, in the byte order of the
+/// table (GHCB spec, section 4.1.8.1)
+const VCEK_GUID: [u8; 16] = guid(
+    0x63da758d,
+    0xe664,
+    0x4564,
+    [0xad, 0xc5, 0xf4, 0xb9, 0x3b, 0xe8, 0xac, 0xcd],
+);
+const ASK_GUID: [u8; 16] = guid(
+    0x4ab7b379,
+    0xbbac,
+    0x4fe4,
+    [0xa0, 0x2f, 0x05, 0xae, 0xf3, 0x27, 0xc7, 0x82],
+);
+const ARK_GUID: [u8; 16] = guid(
+    0xc0b406a4,
+    0xa803,
+    0x4952,
+    [0x97, 0x43, 0x3f, 0xb6, 0x01, 0x4c, 0xd0, 0xae],
+);
+
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6],
+        d[7],
+    ]
+}
+
+/// Size of an entry of the certificate table
+const CERT_ENTRY_SIZE: usize = 24;
+
+/// Returns the certificate with `guid` from the certificate table `table`.
+fn find_certificate<'a>(table: &'a [u8], guid: &[u8; 16]) -> Option<&'a [u8]> {
+    for entry in table.chunks_exact(CERT_ENTRY_SIZE) {
+        let offset = u32::from_le_bytes(entry[16..20].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as usize;
+        // The table ends with an all-zero entry
+        if entry.iter().all(|b| *b == 0) {
+            break;
+        }
+        if entry[..16] == guid[..] {
+            return table.get(offset..offset.checked_add(len)?);
+        }
+    }
+    None
+}
+
+/// DER-encoded certificates leading from the VCEK to the ARK
+#[derive(Clone, Copy, Debug)]
+pub struct CertChain<'a> {
+    pub vcek: &'a [u8],
+    pub ask: &'a [u8],
+    pub ark: &'a [u8],
+}
+
+impl<'a> CertChain<'a> {
+    /// Finds the chain in a certificate table returned with an extended
+    /// report.
+    pub fn from_table(table: &'a [u8]) -> Result<Self, VerifyError> {
+        let cert = |guid| find_certificate(table, guid).ok_or(VerifyError::Certificate);
+        Ok(Self {
+            vcek: cert(&VCEK_GUID)?,
+            ask: cert(&ASK_GUID)?,
+            ark: cert(&ARK_GUID)?,
+        })
+    }
+}
+
+/// Checks that an attestation report was produced by a genuine AMD
+/// processor
+pub trait ReportVerifier {
+    /// `certs` is the certificate table of the platform which produced
+    /// `report`, as returned with an extended report.
+    fn verify(&self, report: &AttestationReport, certs: &[u8]) -> Result<(), VerifyError>;
+}
+
+/// Verifies reports against the chain of certificates of the processor,
+/// anchored in the ARK of a processor family
+#[derive(Clone, Copy, Debug)]
+pub struct VcekVerifier {
+    ark_digest: [u8; 48],
+}
+
+impl VcekVerifier {
+    /// `ark_digest` is the SHA-384 digest of the DER-encoded
+    /// `SubjectPublicKeyInfo` of the trusted ARK.
+    pub fn new(ark_digest: [u8; 48]) -> Self {
+        Self { ark_digest }
+    }
+}
+
+impl ReportVerifier for VcekVerifier {
+    fn verify(&self, report: &AttestationReport, certs: &[u8]) -> Result<(), VerifyError> {
+        let certs = CertChain::from_table(certs)?;
+        let ark = Certificate::parse(certs.ark)?;
+        let ask = Certificate::parse(certs.ask)?;
+        let vcek = Certificate::parse(certs.vcek)?;
+        if Sha384::digest(ark.spki).as_slice() != self.ark_digest {
+            return Err(VerifyError::UntrustedRoot);
+        }
+        ark.verify_issued(&ask)?;
+        ask.verify_issued(&vcek)?;
+        let key = VerifyingKey::from_sec1_bytes(vcek.public_key()?)
+            .map_err(|_| VerifyError::Certificate)?;
+        verify_report_signature(report, &key)
+    }
+}
+
+/// Checks the signature of `report` with the VCEK `key`.
+pub fn verify_report_signature(
+    report: &AttestationReport,
+    key: &VerifyingKey,
+) -> Result<(), VerifyError> {
+    if report.signature_algo() != SIG_ALGO_ECDSA_P384_SHA384 {
+        return Err(VerifyError::Signature);
+    }
+    let (r, s) = report.signature();
+    let signature = Signature::from_scalars(scalar_bytes(&r)?, scalar_bytes(&s)?)
+        .map_err(|_| VerifyError::Signature)?;
+    key.verify(&report.as_bytes()[..REPORT_SIGNED_SIZE], &signature)
+        .map_err(|_| VerifyError::Signature)
+}
+
+/// Converts a little-endian, zero-extended signature component to the
+/// big-endian representation of a P-384 scalar.
+fn scalar_bytes(component: &[u8; 72]) -> Result<FieldBytes, VerifyError> {
+    let (value, padding) = component.split_at(48);
+    if padding.iter().any(|b| *b != 0) {
+        return Err(VerifyError::Signature);
+    }
+    let mut bytes = FieldBytes::default();
+    for (dst, src) in bytes.iter_mut().zip(value.iter().rev()) {
+        *dst = *src;
+    }
+    Ok(bytes)
+}
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXPLICIT_0: u8 = 0xa0;
+
+/// Splits the DER element at the start of `der` into its tag, its contents
+/// and the bytes following it.
+fn der_next(der: &[u8]) -> Result<(u8, &[u8], &[u8]), VerifyError> {
+    let [tag, first, rest @ ..] = der else {
+        return Err(VerifyError::Certificate);
+    };
+    let (len, rest) = if *first < 0x80 {
+        (usize::from(*first), rest)
+    } else {
+        // Long form, up to four length bytes
+        let count = usize::from(*first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err(VerifyError::Certificate);
+        }
+        let (len, rest) = rest.split_at(count);
+        let len = len.iter().fold(0, |len, b| (len << 8) | usize::from(*b));
+        (len, rest)
+    };
+    if rest.len() < len {
+        return Err(VerifyError::Certificate);
+    }
+    let (contents, rest) = rest.split_at(len);
+    Ok((*tag, contents, rest))
+}
+
+/// Like [`der_next()`], failing if the element does not have `tag`.
+fn der_expect(der: &[u8], tag: u8) -> Result<(&[u8], &[u8]), VerifyError> {
+    match der_next(der)? {
+        (t, contents, rest) if t == tag => Ok((contents, rest)),
+        _ => Err(VerifyError::Certificate),
+    }
+}
+
+/// Contents of a BIT STRING without unused bits
+fn bit_string(contents: &[u8]) -> Result<&[u8], VerifyError> {
+    match contents {
+        [0, bits @ ..] => Ok(bits),
+        _ => Err(VerifyError::Certificate),
+    }
+}
+
+/// Contents of an unsigned INTEGER without leading zeroes
+fn unsigned_integer(contents: &[u8]) -> &[u8] {
+    let zeroes = contents.iter().take_while(|b| **b == 0).count();
+    &contents[zeroes..]
+}
+
+/// The parts of an X.509 certificate needed to verify a chain
+#[derive(Clone, Copy, Debug)]
+struct Certificate<'a> {
+    /// The DER-encoded `TBSCertificate`, which the signature covers
+    tbs: &'a [u8],
+    /// The DER-encoded `SubjectPublicKeyInfo`
+    spki: &'a [u8],
+    signature: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    fn parse(der: &'a [u8]) -> Result<Self, VerifyError> {
+        let (cert, _) = der_expect(der, TAG_SEQUENCE)?;
+        let (tbs_contents, after_tbs) = der_expect(cert, TAG_SEQUENCE)?;
+        let tbs = &cert[..cert.len() - after_tbs.len()];
+        let (_, rest) = der_expect(after_tbs, TAG_SEQUENCE)?;
+        let (signature, _) = der_expect(rest, TAG_BIT_STRING)?;
+
+        // Skip the version, serial number, signature algorithm, issuer,
+        // validity and subject to get to the subject public key.
+        let mut rest = tbs_contents;
+        if rest.first() == Some(&TAG_EXPLICIT_0) {
+            rest = der_next(rest)?.2;
+        }
+        for _ in 0..5 {
+            rest = der_next(rest)?.2;
+        }
+        let (_, after_spki) = der_expect(rest, TAG_SEQUENCE)?;
+        let spki = &rest[..rest.len() - after_spki.len()];
+
+        Ok(Self {
+            tbs,
+            spki,
+            signature: bit_string(signature)?,
+        })
+    }
+
+    /// The subject public key, without the algorithm identifier
+    fn public_key(&self) -> Result<&'a [u8], VerifyError> {
+        let (spki, _) = der_expect(self.spki, TAG_SEQUENCE)?;
+        let (_, rest) = der_expect(spki, TAG_SEQUENCE)?;
+        let (key, _) = der_expect(rest, TAG_BIT_STRING)?;
+        bit_string(key)
+    }
+
+    /// Checks that `cert` is signed by the RSA key of this certificate.
+    fn verify_issued(&self, cert: &Certificate<'_>) -> Result<(), VerifyError> {
+        let (key, _) = der_expect(self.public_key()?, TAG_SEQUENCE)?;
+        let (modulus, rest) = der_expect(key, TAG_INTEGER)?;
+        let (exponent, _) = der_expect(rest, TAG_INTEGER)?;
+        rsa_pss_verify(
+            unsigned_integer(modulus),
+            unsigned_integer(exponent),
+            cert.tbs,
+            cert.signature,
+        )
+    }
+}
+
+/// Size of the RSA keys of the ARK and the ASK
+const RSA_SIZE: usize = 512;
+/// Size of a SHA-384 digest, which is also the salt size
+const HASH_SIZE: usize = 48;
+
+/// Checks the RSASSA-PSS signature of `msg` with SHA-384, MGF1 with
+/// SHA-384 and a 48 byte salt, as used by the ARK and the ASK.
+fn rsa_pss_verify(
+    modulus: &[u8],
+    exponent: &[u8],
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<(), VerifyError> {
+    if modulus.len() != RSA_SIZE
+        || modulus[RSA_SIZE - 1] & 1 == 0
+        || exponent.is_empty()
+        || exponent.len() > RSA_SIZE
+    {
+        return Err(VerifyError::Certificate);
+    }
+    if signature.len() != RSA_SIZE {
+        return Err(VerifyError::Signature);
+    }
+
+    let n = U4096::from_be_slice(modulus);
+    let s = U4096::from_be_slice(signature);
+    if s >= n {
+        return Err(VerifyError::Signature);
+    }
+    let mut e = [0u8; RSA_SIZE];
+    e[RSA_SIZE - exponent.len()..].copy_from_slice(exponent);
+    let e = U4096::from_be_slice(&e);
+
+    let params = DynResidueParams::new(&n);
+    let em = DynResidue::new(&s, params).pow(&e).retrieve().to_be_bytes();
+    emsa_pss_verify(&Sha384::digest(msg), &em)
+}
+
+/// EMSA-PSS-VERIFY from RFC 8017 for a 4096 bit modulus
+fn emsa_pss_verify(hash: &[u8], em: &[u8; RSA_SIZE]) -> Result<(), VerifyError> {
+    let db_len = RSA_SIZE - HASH_SIZE - 1;
+    let (masked_db, rest) = em.split_at(db_len);
+    let (h, trailer) = rest.split_at(HASH_SIZE);
+    // The top bit is outside of the 4095 bit encoded message
+    if trailer != [0xbc] || masked_db[0] & 0x80 != 0 {
+        return Err(VerifyError::Signature);
+    }
+
+    let mut db = [0u8; RSA_SIZE - HASH_SIZE - 1];
+    mgf1_sha384(h, &mut db);
+    for (d, m) in db.iter_mut().zip(masked_db) {
+        *d ^= *m;
+    }
+    db[0] &= 0x7f;
+
+    let (padding, salt) = db.split_at(db_len - HASH_SIZE);
+    let (one, zeroes) = padding.split_last().unwrap();
+    if *one != 1 || zeroes.iter().any(|b| *b != 0) {
+        return Err(VerifyError::Signature);
+    }
+
+    let expected = Sha384::new()
+        .chain_update([0u8; 8])
+        .chain_update(hash)
+        .chain_update(salt)
+        .finalize();
+    if expected.as_slice() != h {
+        return Err(VerifyError::Signature);
+    }
+    Ok(())
+}
+
+/// Fills `mask` with MGF1 output for `seed`, with SHA-384
+fn mgf1_sha384(seed: &[u8], mask: &mut [u8]) {
+    for (counter, chunk) in mask.chunks_mut(HASH_SIZE).enumerate() {
+        let digest = Sha384::new()
+            .chain_update(seed)
+            .chain_update((counter as u32).to_be_bytes())
+            .finalize();
+        chunk.copy_from_slice(&digest[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::ecdsa::signature::Signer;
+    use p384::ecdsa::SigningKey;
+
+    const REPORT_SIZE: usize = core::mem::size_of::<AttestationReport>();
+    const SIGNATURE_ALGO_OFFSET: usize = 0x34;
+
+    fn signed_report(key: &SigningKey) -> [u8; REPORT_SIZE] {
+        let mut bytes = [0u8; REPORT_SIZE];
+        bytes[SIGNATURE_ALGO_OFFSET..SIGNATURE_ALGO_OFFSET + 4]
+            .copy_from_slice(&SIG_ALGO_ECDSA_P384_SHA384.to_le_bytes());
+        bytes[0x90..0xc0].fill(0xaa);
+        let signature: Signature = key.sign(&bytes[..REPORT_SIGNED_SIZE]);
+        let (r, s) = signature.split_bytes();
+        for (i, b) in r.iter().rev().enumerate() {
+            bytes[REPORT_SIGNED_SIZE + i] = *b;
+        }
+        for (i, b) in s.iter().rev().enumerate() {
+            bytes[REPORT_SIGNED_SIZE + 72 + i] = *b;
+        }
+        bytes
+    }
+
+    #[test]
+    fn report_signature() {
+        let key = SigningKey::from_slice(&[0x11; 48]).unwrap();
+        let mut bytes = signed_report(&key);
+        let report = AttestationReport::try_from_as_ref(&bytes).unwrap();
+        assert_eq!(verify_report_signature(report, key.verifying_key()), Ok(()));
+
+        let other = SigningKey::from_slice(&[0x22; 48]).unwrap();
+        assert_eq!(
+            verify_report_signature(report, other.verifying_key()),
+            Err(VerifyError::Signature)
+        );
+
+        // Any change to the signed part invalidates the signature
+        bytes[0x90] ^= 1;
+        let report = AttestationReport::try_from_as_ref(&bytes).unwrap();
+        assert_eq!(
+            verify_report_signature(report, key.verifying_key()),
+            Err(VerifyError::Signature)
+        );
+    }
+
+    #[test]
+    fn cert_table() {
+        let mut table = [0u8; 4 * CERT_ENTRY_SIZE + 6];
+        let entries = [(&ARK_GUID, 0u8), (&ASK_GUID, 2), (&VCEK_GUID, 4)];
+        for (i, (guid, offset)) in entries.iter().enumerate() {
+            let entry = &mut table[i * CERT_ENTRY_SIZE..(i + 1) * CERT_ENTRY_SIZE];
+            entry[..16].copy_from_slice(*guid);
+            let offset = 4 * CERT_ENTRY_SIZE as u32 + u32::from(*offset);
+            entry[16..20].copy_from_slice(&offset.to_le_bytes());
+            entry[20..24].copy_from_slice(&2u32.to_le_bytes());
+        }
+        table[4 * CERT_ENTRY_SIZE..].copy_from_slice(b"ARASVC");
+
+        let chain = CertChain::from_table(&table).unwrap();
+        assert_eq!(chain.ark, b"AR");
+        assert_eq!(chain.ask, b"AS");
+        assert_eq!(chain.vcek, b"VC");
+
+        // Entries after the terminating entry are ignored
+        table.copy_within(0..CERT_ENTRY_SIZE, 3 * CERT_ENTRY_SIZE);
+        table[..CERT_ENTRY_SIZE].fill(0);
+        assert!(CertChain::from_table(&table).is_err());
+    }
+
+    #[test]
+    fn certificate_parsing() {
+        // SEQUENCE { SEQUENCE { [0] { INTEGER 2 }, INTEGER 1, SEQUENCE {},
+        // SEQUENCE {}, SEQUENCE {}, SEQUENCE {}, SEQUENCE { SEQUENCE {},
+        // BIT STRING 0xab } }, SEQUENCE {}, BIT STRING 0xcd }
+        let der = [
+            0x30, 0x20, 0x30, 0x18, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01, 0x30, 0x00,
+            0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x06, 0x30, 0x00, 0x03, 0x02, 0x00, 0xab,
+            0x30, 0x00, 0x03, 0x02, 0x00, 0xcd,
+        ];
+        let cert = Certificate::parse(&der).unwrap();
+        assert_eq!(cert.tbs, &der[2..28]);
+        assert_eq!(cert.spki, &der[20..28]);
+        assert_eq!(cert.public_key(), Ok(&[0xab][..]));
+        assert_eq!(cert.signature, &[0xcd]);
+
+        assert!(Certificate::parse(&der[..der.len() - 1]).is_err());
+        assert_eq!(
+            der_next(&[0x04, 0x81, 0x01, 0xff]),
+            Ok((0x04, &[0xff][..], &[][..]))
+        );
+    }
+
+    #[test]
+    fn pss_encoding() {
+        // Build a valid encoding of a message hash with a fixed salt
+        let hash = Sha384::digest(b"message");
+        let salt = [0x5a; HASH_SIZE];
+        let h = Sha384::new()
+            .chain_update([0u8; 8])
+            .chain_update(hash)
+            .chain_update(salt)
+            .finalize();
+        let db_len = RSA_SIZE - HASH_SIZE - 1;
+        let mut em = [0u8; RSA_SIZE];
+        em[db_len - HASH_SIZE - 1] = 1;
+        em[db_len - HASH_SIZE..db_len].copy_from_slice(&salt);
+        let mut mask = [0u8; RSA_SIZE - HASH_SIZE - 1];
+        mgf1_sha384(&h, &mut mask);
+        for (e, m) in em.iter_mut().zip(mask) {
+            *e ^= m;
+        }
+        em[0] &= 0x7f;
+        em[db_len..RSA_SIZE - 1].copy_from_slice(&h);
+        em[RSA_SIZE - 1] = 0xbc;
+
+        assert_eq!(emsa_pss_verify(&hash, &em), Ok(()));
+        assert_eq!(
+            emsa_pss_verify(&Sha384::digest(b"other"), &em),
+            Err(VerifyError::Signature)
+        );
+        em[RSA_SIZE - 1] = 0xbd;
+        assert_eq!(emsa_pss_verify(&hash, &em), Err(VerifyError::Signature));
+    }
+}
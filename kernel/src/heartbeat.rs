@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Liveness reporting to the host.
+//!
+//! If the host configures a heartbeat interval in the IGVM parameters, the
+//! SVSM allocates a page shared with the host and announces its guest
+//! physical address with an [`EventKind::Heartbeat`] event on the host event
+//! channel, carrying the address as a little-endian `u64`. The page holds a
+//! [`HeartbeatPage`], which the request loop updates at most once per
+//! interval. An orchestration layer can detect a wedged SVSM by watching the
+//! sequence counter without running anything in the guest.
+//!
+//! Updates happen in the request loop, after guest exits and whenever an
+//! idle CPU is woken up, e.g. by an event from the host. The SVSM has no
+//! timer of its own, so the host should only expect progress while the
+//! guest is executing or while it sends events.
+//!
+//! The page also carries the TSC values at which the SVSM reached each
+//! [`BootPhase`](crate::boot_time::BootPhase), so that the host can measure boot times.
+//!
+//! Missed deadlines of the guest [watchdog](crate::protocols::watchdog) are
+//! reported through [`HealthFlags::WATCHDOG_EXPIRED`] and counted in
+//! [`HeartbeatPage::watchdog_expirations`].
+
+extern crate alloc;
+
+use crate::boot_time::{boot_phase_timestamps, BOOT_PHASES};
+use crate::error::SvsmError;
+use crate::event_channel::{event_channel_send, EventKind};
+use crate::locking::SpinLock;
+use crate::mm::shared_pool::{HostShared, SharedBox};
+use crate::teardown::{register_teardown_hook, TeardownCtx, TeardownReason};
+use crate::time::now;
+use crate::utils::try_box;
+
+use alloc::boxed::Box;
+use bitflags::bitflags;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicPtr, AtomicU64, Ordering};
+
+bitflags! {
+    /// Health state reported in the heartbeat page
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct HealthFlags: u64 {
+        /// Initialization is complete and guest requests are processed
+        const RUNNING = 1 << 0;
+        /// An error was reported, see [`HeartbeatPage::last_error`]
+        const ERROR   = 1 << 1;
+        /// The guest missed the deadline of its watchdog and did not pet
+        /// it since
+        const WATCHDOG_EXPIRED = 1 << 2;
+    }
+}
+
+/// Error codes reported in [`HeartbeatPage::last_error`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum HeartbeatError {
+    /// A request loop stopped because of a fatal error
+    FatalRequest = 1,
+    /// The SVSM panicked
+    Panic = 2,
+}
+
+/// Layout of the page shared with the host. The host must read the fields
+/// between two reads of `sequence` and retry if it was odd or changed.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct HeartbeatPage {
+    /// Incremented before and after every update, odd while an update is
+    /// in progress
+    pub sequence: AtomicU64,
+    /// TSC value at the time of the last update
+    pub timestamp: AtomicU64,
+    /// Current [`HealthFlags`]
+    pub flags: AtomicU64,
+    /// Last [`HeartbeatError`] reported, or zero
+    pub last_error: AtomicU64,
+    /// TSC values at which the boot phases were reached, in the order of
+    /// [`BootPhase::ALL`](crate::boot_time::BootPhase::ALL), or zero for phases not reached (yet)
+    pub boot_phases: [AtomicU64; BOOT_PHASES],
+    /// Number of times the guest watchdog expired
+    pub watchdog_expirations: AtomicU64,
+}
+
+// SAFETY: the page only consists of atomic integers.
+unsafe impl HostShared for HeartbeatPage {}
+
+impl HeartbeatPage {
+    /// Publishes new contents. Must not be called concurrently.
+    fn update(
+        &self,
+        timestamp: u64,
+        flags: HealthFlags,
+        last_error: u64,
+        boot_phases: &[u64; BOOT_PHASES],
+        watchdog_expirations: u64,
+    ) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.timestamp.store(timestamp, Ordering::Relaxed);
+        self.flags.store(flags.bits(), Ordering::Relaxed);
+        self.last_error.store(last_error, Ordering::Relaxed);
+        for (dst, src) in self.boot_phases.iter().zip(boot_phases) {
+            dst.store(*src, Ordering::Relaxed);
+        }
+        self.watchdog_expirations
+            .store(watchdog_expirations, Ordering::Relaxed);
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+static HEALTH_FLAGS: AtomicU64 = AtomicU64::new(0);
+static LAST_ERROR: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG_EXPIRATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct Heartbeat {
+    page: &'static HeartbeatPage,
+    /// Minimum time between updates, in TSC cycles
+    interval: u64,
+    /// Timestamp at which the next update is due
+    next: AtomicU64,
+    writer: SpinLock<()>,
+}
+
+impl Heartbeat {
+    fn new(page: &'static HeartbeatPage, interval: u64) -> Self {
+        Self {
+            page,
+            interval,
+            next: AtomicU64::new(0),
+            writer: SpinLock::new(()),
+        }
+    }
+
+    fn tick(&self, now: u64) {
+        if now < self.next.load(Ordering::Relaxed) {
+            return;
+        }
+        // Another CPU is already updating the page.
+        let Some(_guard) = self.writer.try_lock() else {
+            return;
+        };
+        if now < self.next.load(Ordering::Relaxed) {
+            return;
+        }
+        self.publish(now);
+    }
+
+    /// Updates the page regardless of the interval. Fails if another CPU
+    /// holds the page until `expired` returns `true`.
+    fn flush(&self, now: u64, expired: impl Fn() -> bool) -> Result<(), SvsmError> {
+        let _guard = loop {
+            if let Some(guard) = self.writer.try_lock() {
+                break guard;
+            }
+            if expired() {
+                return Err(SvsmError::NotSupported);
+            }
+            spin_loop();
+        };
+        self.publish(now);
+        Ok(())
+    }
+
+    /// Must be called with `writer` held.
+    fn publish(&self, now: u64) {
+        self.next
+            .store(now.saturating_add(self.interval), Ordering::Relaxed);
+        self.page.update(
+            now,
+            HealthFlags::from_bits_retain(HEALTH_FLAGS.load(Ordering::Relaxed)),
+            LAST_ERROR.load(Ordering::Relaxed),
+            &boot_phase_timestamps(),
+            WATCHDOG_EXPIRATIONS.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// The heartbeat, leaked on initialization and never changed afterwards, or
+/// null. An atomic pointer rather than a lock, so that the teardown on
+/// panic can reach it without taking a lock.
+static HEARTBEAT: AtomicPtr<Heartbeat> = AtomicPtr::new(ptr::null_mut());
+
+fn heartbeat() -> Option<&'static Heartbeat> {
+    // SAFETY: the pointer is either null or points to the leaked heartbeat,
+    // which is never freed.
+    unsafe { HEARTBEAT.load(Ordering::Acquire).as_ref() }
+}
+
+/// Set up the heartbeat page and announce it to the host through the event
+/// channel, which must already be initialized. `interval` is the minimum
+/// time between updates in TSC cycles.
+pub fn heartbeat_init(interval: u64) -> Result<(), SvsmError> {
+    if heartbeat().is_some() {
+        return Err(SvsmError::NotSupported);
+    }
+
+    let page = SharedBox::try_new(HeartbeatPage::default())?;
+    let gpa = u64::from(page.paddr());
+    event_channel_send(EventKind::Heartbeat, &gpa.to_le_bytes())?;
+
+    let page = SharedBox::leak(page);
+    let heartbeat = Box::leak(try_box(Heartbeat::new(page, interval))?);
+    heartbeat.tick(now());
+    HEARTBEAT.store(heartbeat, Ordering::Release);
+
+    if let Err(e) = register_teardown_hook("heartbeat", heartbeat_teardown) {
+        log::warn!("Failed to register heartbeat teardown: {:?}", e);
+    }
+
+    log::info!(
+        "Heartbeat page at GPA {:#x}, interval {} cycles",
+        gpa,
+        interval
+    );
+    Ok(())
+}
+
+/// Update the heartbeat page if the interval has passed since the last
+/// update. Called from the request loop after every guest exit and every
+/// time a CPU without a runnable vCPU wakes up.
+pub fn heartbeat_tick() {
+    if let Some(heartbeat) = heartbeat() {
+        heartbeat.tick(now());
+    }
+}
+
+/// Set or clear health flags. The change becomes visible to the host with
+/// the next update.
+pub fn heartbeat_set_flags(flags: HealthFlags, set: bool) {
+    if set {
+        HEALTH_FLAGS.fetch_or(flags.bits(), Ordering::Relaxed);
+    } else {
+        HEALTH_FLAGS.fetch_and(!flags.bits(), Ordering::Relaxed);
+    }
+}
+
+/// Report an error to the host. This sets [`HealthFlags::ERROR`], which
+/// remains set.
+pub fn heartbeat_report_error(error: HeartbeatError) {
+    LAST_ERROR.store(error as u64, Ordering::Relaxed);
+    heartbeat_set_flags(HealthFlags::ERROR, true);
+}
+
+/// Report a missed deadline of the guest watchdog to the host. This sets
+/// [`HealthFlags::WATCHDOG_EXPIRED`] until the watchdog recovers.
+pub fn heartbeat_report_watchdog() {
+    WATCHDOG_EXPIRATIONS.fetch_add(1, Ordering::Relaxed);
+    heartbeat_set_flags(HealthFlags::WATCHDOG_EXPIRED, true);
+}
+
+/// Publishes the final state: the SVSM no longer runs, and if it panicked,
+/// reports the panic as an error. On panic, the page is only updated if no
+/// other CPU is updating it, as that CPU may be parked.
+fn heartbeat_teardown(ctx: &TeardownCtx<'_>) -> Result<(), SvsmError> {
+    let Some(heartbeat) = heartbeat() else {
+        return Ok(());
+    };
+    heartbeat_set_flags(HealthFlags::RUNNING, false);
+    let panic = ctx.reason() == TeardownReason::Panic;
+    if panic {
+        heartbeat_report_error(HeartbeatError::Panic);
+    }
+    heartbeat.flush(now(), || panic || ctx.expired())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+    #[test]
+    fn page_update() {
+        let page = HeartbeatPage::default();
+        let mut boot_phases = [0; BOOT_PHASES];
+        page.update(1234, HealthFlags::RUNNING, 0, &boot_phases, 0);
+        boot_phases[1] = 1000;
+        page.update(
+            5678,
+            HealthFlags::RUNNING | HealthFlags::ERROR,
+            1,
+            &boot_phases,
+            2,
+        );
+        assert_eq!(page.sequence.load(Ordering::Relaxed), 4);
+        assert_eq!(page.timestamp.load(Ordering::Relaxed), 5678);
+        assert_eq!(page.flags.load(Ordering::Relaxed), 3);
+        assert_eq!(page.last_error.load(Ordering::Relaxed), 1);
+        assert_eq!(page.boot_phases[1].load(Ordering::Relaxed), 1000);
+        assert_eq!(page.watchdog_expirations.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn tick_interval() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let page: &HeartbeatPage = Box::leak(Box::new(HeartbeatPage::default()));
+        let heartbeat = Heartbeat::new(page, 100);
+        heartbeat.tick(1000);
+        assert_eq!(page.sequence.load(Ordering::Relaxed), 2);
+        heartbeat.tick(1099);
+        assert_eq!(page.sequence.load(Ordering::Relaxed), 2);
+        heartbeat.tick(1100);
+        assert_eq!(page.sequence.load(Ordering::Relaxed), 4);
+        assert_eq!(page.timestamp.load(Ordering::Relaxed), 1100);
+    }
+}
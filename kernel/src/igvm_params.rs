@@ -6,13 +6,16 @@
 
 extern crate alloc;
 
-use crate::acpi::tables::ACPICPUInfo;
+use crate::acpi::tables::{parse_srat, ACPICPUInfo, ACPINumaInfo};
 use crate::address::{PhysAddr, VirtAddr};
 use crate::cpu::efer::EFERFlags;
 use crate::error::SvsmError;
+use crate::event_channel::EventChannelParams;
 use crate::fw_meta::SevFWMetaData;
 use crate::mm::{GuestPtr, PerCPUPageMappingGuard, PAGE_SIZE};
 use crate::platform::{PageStateChangeOp, SVSM_PLATFORM};
+use crate::prevalidate::PrevalidationParams;
+use crate::protocols::accounting::VmplLimits;
 use crate::types::PageSize;
 use crate::utils::MemoryRegion;
 use alloc::vec::Vec;
@@ -236,6 +239,28 @@ impl IgvmParams<'_> {
         Ok(cpus)
     }
 
+    /// Returns the NUMA topology from the SRAT supplied by the loader, or
+    /// `None` if the loader did not supply a valid SRAT.
+    pub fn load_numa_info(&self) -> Option<ACPINumaInfo> {
+        let offset = usize::try_from(self.igvm_param_block.srat_offset).ok()?;
+        if offset == 0 {
+            return None;
+        }
+        let srat_address = VirtAddr::from(self.igvm_param_block as *const IgvmParamBlock) + offset;
+        // SAFETY: the parameter area, which includes the SRAT page, is
+        // mapped for as long as the parameters are in use.
+        let srat = unsafe { core::slice::from_raw_parts(srat_address.as_ptr::<u8>(), PAGE_SIZE) };
+        match parse_srat(srat) {
+            Ok(info) => Some(info),
+            // An all-zero page means the loader provided no SRAT.
+            Err(_) if srat.iter().all(|b| *b == 0) => None,
+            Err(e) => {
+                log::warn!("Ignoring invalid SRAT: {:?}", e);
+                None
+            }
+        }
+    }
+
     pub fn should_launch_fw(&self) -> bool {
         self.igvm_param_block.firmware.size != 0
     }
@@ -389,4 +414,92 @@ impl IgvmParams<'_> {
     pub fn use_alternate_injection(&self) -> bool {
         self.igvm_param_block.use_alternate_injection != 0
     }
+
+    /// Minimum interval between heartbeat updates in TSC cycles, if the
+    /// heartbeat is enabled
+    pub fn heartbeat_interval(&self) -> Option<u64> {
+        let interval = self.igvm_param_block.heartbeat_interval;
+        (interval != 0).then_some(u64::from(interval) << 20)
+    }
+
+    /// Maximum number of pages in the page table memory pool, if limited
+    pub fn page_table_pool_limit(&self) -> Option<usize> {
+        let limit = self.igvm_param_block.page_table_pool_limit;
+        (limit != 0).then_some(limit as usize)
+    }
+
+    /// Caps on the resources the guest VMPL may use
+    pub fn guest_vmpl_limits(&self) -> VmplLimits {
+        let mut limits = VmplLimits::unlimited();
+        let vmsas = self.igvm_param_block.guest_vmsa_limit;
+        if vmsas != 0 {
+            limits.vmsas = u64::from(vmsas);
+        }
+        let calls = self.igvm_param_block.guest_call_rate;
+        if calls != 0 {
+            limits.calls_per_window = u64::from(calls);
+            limits.rate_window = 1 << 20;
+        }
+        let memory = self.igvm_param_block.guest_memory_limit;
+        if memory != 0 {
+            limits.memory_pages = u64::from(memory);
+        }
+        let shared = self.igvm_param_block.guest_shared_limit;
+        if shared != 0 {
+            limits.shared_pages = u64::from(shared);
+        }
+        limits
+    }
+
+    pub fn layout_randomization(&self) -> bool {
+        self.igvm_param_block.disable_layout_randomization == 0
+    }
+
+    /// Start of deferred guest memory, if any
+    pub fn deferred_memory_base(&self) -> Option<PhysAddr> {
+        let base = self.igvm_param_block.deferred_memory_base;
+        (base != 0).then(|| PhysAddr::from(base))
+    }
+
+    pub fn memory_state_queries(&self) -> bool {
+        self.igvm_param_block.memory_state_queries != 0
+    }
+
+    /// Real-time task budget of the scheduler, if configured
+    pub fn rt_task_budget(&self) -> Option<u32> {
+        let budget = self.igvm_param_block.rt_task_budget;
+        (budget != 0).then_some(u32::from(budget))
+    }
+
+    /// Number of pages in the shared memory pool, if configured
+    pub fn shared_pool_pages(&self) -> Option<usize> {
+        let pages = self.igvm_param_block.shared_pool_pages;
+        (pages != 0).then_some(usize::from(pages))
+    }
+
+    /// Background prevalidation of guest memory, if configured
+    pub fn prevalidation(&self) -> Option<PrevalidationParams> {
+        let block = &self.igvm_param_block;
+        if block.prevalidation_workers == 0 {
+            return None;
+        }
+        Some(PrevalidationParams {
+            baseline: usize::try_from(block.prevalidation_baseline).unwrap_or(usize::MAX),
+            workers: usize::from(block.prevalidation_workers),
+        })
+    }
+
+    pub fn event_channel(&self) -> Option<EventChannelParams> {
+        let block = &self.igvm_param_block;
+        if block.event_channel_port == 0 {
+            return None;
+        }
+        Some(EventChannelParams {
+            port: block.event_channel_port,
+            vector: block.event_channel_vector,
+            pages: usize::from(block.event_channel_pages)
+                .max(1)
+                .next_power_of_two(),
+        })
+    }
 }
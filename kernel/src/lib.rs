@@ -12,16 +12,19 @@
 
 pub mod acpi;
 pub mod address;
+pub mod boot_time;
 pub mod config;
 pub mod console;
 pub mod cpu;
 pub mod crypto;
 pub mod debug;
 pub mod error;
+pub mod event_channel;
 pub mod fs;
 pub mod fw_cfg;
 pub mod fw_meta;
 pub mod greq;
+pub mod heartbeat;
 pub mod igvm_params;
 pub mod insn_decode;
 pub mod io;
@@ -29,8 +32,11 @@ pub mod kernel_region;
 pub mod locking;
 pub mod mm;
 pub mod platform;
+pub mod prevalidate;
 pub mod protocols;
+pub mod provenance;
 pub mod requests;
+pub mod rng;
 pub mod serial;
 pub mod sev;
 pub mod string;
@@ -38,6 +44,8 @@ pub mod svsm_console;
 pub mod svsm_paging;
 pub mod syscall;
 pub mod task;
+pub mod teardown;
+pub mod time;
 pub mod types;
 pub mod utils;
 #[cfg(all(feature = "mstpm", not(test)))]
@@ -222,6 +222,38 @@ impl<T> RWLock<T> {
         }
     }
 
+    /// Tries to acquire a read lock without waiting. This only fails if a
+    /// writer holds the lock or waits for it, so it can be used from
+    /// contexts which might have interrupted the writer.
+    ///
+    /// # Returns
+    ///
+    /// A [`ReadLockGuard`] that provides read access to the protected data,
+    /// or `None` if the lock is held for writing.
+    pub fn try_lock_read(&self) -> Option<ReadLockGuard<'_, T>> {
+        let mut val = self.rwlock.load(Ordering::Relaxed);
+        loop {
+            let (readers, writers) = split_val(val);
+            if writers != 0 {
+                return None;
+            }
+            let new_val = compose_val(readers + 1, 0);
+            match self
+                .rwlock
+                .compare_exchange(val, new_val, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(cur) => val = cur,
+            }
+            core::hint::spin_loop();
+        }
+
+        Some(ReadLockGuard {
+            rwlock: &self.rwlock,
+            data: unsafe { &*self.data.get() },
+        })
+    }
+
     /// This function ensures exclusive access for a single writer and waits
     /// for all readers to finish before granting access to the writer.
     ///
@@ -329,4 +361,21 @@ mod tests {
         drop(read_guard1);
         drop(read_guard2);
     }
+
+    #[test]
+    fn test_try_lock_read() {
+        use crate::locking::*;
+        let rwlock = RWLock::new(7);
+
+        let read_guard = rwlock.lock_read();
+        let try_guard = rwlock.try_lock_read().unwrap();
+        assert_eq!(*try_guard, 7);
+        drop(read_guard);
+        drop(try_guard);
+
+        let write_guard = rwlock.lock_write();
+        assert!(rwlock.try_lock_read().is_none());
+        drop(write_guard);
+        assert!(rwlock.try_lock_read().is_some());
+    }
 }
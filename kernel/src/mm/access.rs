@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Typed access to temporarily mapped physical memory.
+//!
+//! [`PerCPUPageMappingGuard::virt_addr()`] hands out a raw [`VirtAddr`],
+//! which can be kept and used after the guard was dropped and the mapping
+//! removed. A [`TypedMapping`] instead mutably borrows the guard for as long
+//! as it is alive, so the borrow checker rejects any use of it past the
+//! unmap:
+//!
+//! ```compile_fail
+//! # use svsm::address::PhysAddr;
+//! # use svsm::mm::{access::TypedMapping, PerCPUPageMappingGuard};
+//! let mut guard = PerCPUPageMappingGuard::create_4k(PhysAddr::from(0x1000u64)).unwrap();
+//! let value = TypedMapping::<u64>::new(&mut guard, 0).unwrap();
+//! drop(guard);
+//! let _ = value.read();
+//! ```
+//!
+//! Accesses go through [`GuestPtr`], so they fail gracefully if the memory
+//! cannot be accessed.
+
+use crate::address::{Address, VirtAddr};
+use crate::error::SvsmError;
+use crate::mm::{GuestPtr, PerCPUPageMappingGuard};
+use crate::utils::MemoryRegion;
+
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+
+/// Number of `T` elements that fit into `region` from `offset` on, or
+/// `None` if there is no room for at least one or the element would be
+/// misaligned.
+fn mapped_count<T>(region: MemoryRegion<VirtAddr>, offset: usize) -> Option<usize> {
+    let len = region.len().checked_sub(offset)?;
+    let vaddr = region.start().checked_add(offset)?;
+    if !vaddr.is_aligned(align_of::<T>()) {
+        return None;
+    }
+    len.checked_div(size_of::<T>()).filter(|count| *count > 0)
+}
+
+/// One or more `T` in memory mapped by a [`PerCPUPageMappingGuard`], which
+/// stays borrowed until this is dropped.
+#[derive(Debug)]
+pub struct TypedMapping<'a, T: Copy> {
+    ptr: GuestPtr<T>,
+    /// Number of elements mapped from `ptr` on
+    count: usize,
+    _guard: PhantomData<&'a mut PerCPUPageMappingGuard>,
+}
+
+impl<'a, T: Copy> TypedMapping<'a, T> {
+    /// Access the `T` at `offset` bytes into the memory mapped by `guard`.
+    ///
+    /// # Returns
+    ///
+    /// The mapping on success, `Err(SvsmError::InvalidAddress)` if `offset`
+    /// is misaligned for `T` or there is no room for a `T` at `offset`.
+    pub fn new(guard: &'a mut PerCPUPageMappingGuard, offset: usize) -> Result<Self, SvsmError> {
+        let region = guard.region();
+        let count = mapped_count::<T>(region, offset).ok_or(SvsmError::InvalidAddress)?;
+        Ok(Self {
+            ptr: GuestPtr::new(region.start() + offset),
+            count,
+            _guard: PhantomData,
+        })
+    }
+
+    /// Number of `T` elements which can be accessed with
+    /// [`read_slice()`](Self::read_slice) and
+    /// [`write_slice()`](Self::write_slice)
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn read(&self) -> Result<T, SvsmError> {
+        self.ptr.read()
+    }
+
+    pub fn write(&mut self, val: T) -> Result<(), SvsmError> {
+        self.ptr.write(val)
+    }
+
+    pub fn read_slice(&self, buf: &mut [T]) -> Result<(), SvsmError> {
+        if buf.len() > self.count {
+            return Err(SvsmError::InvalidAddress);
+        }
+        self.ptr.read_slice(buf)
+    }
+
+    pub fn write_slice(&mut self, buf: &[T]) -> Result<(), SvsmError> {
+        if buf.len() > self.count {
+            return Err(SvsmError::InvalidAddress);
+        }
+        self.ptr.write_slice(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PAGE_SIZE;
+
+    #[test]
+    fn typed_mapping_bounds() {
+        let region = MemoryRegion::new(VirtAddr::from(0xffff_8000_0000_0000u64), PAGE_SIZE);
+        assert_eq!(mapped_count::<u64>(region, 0), Some(PAGE_SIZE / 8));
+        assert_eq!(mapped_count::<u64>(region, PAGE_SIZE - 8), Some(1));
+        assert_eq!(mapped_count::<u64>(region, PAGE_SIZE - 4), None);
+        assert_eq!(mapped_count::<u64>(region, PAGE_SIZE), None);
+        assert_eq!(mapped_count::<u64>(region, PAGE_SIZE + 8), None);
+        assert_eq!(mapped_count::<u64>(region, 4), None);
+        assert_eq!(mapped_count::<u8>(region, 3), Some(PAGE_SIZE - 3));
+        assert_eq!(mapped_count::<()>(region, 0), None);
+    }
+}
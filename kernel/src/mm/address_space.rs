@@ -4,10 +4,14 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::address::{PhysAddr, VirtAddr};
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use crate::rng::RandomSource;
 use crate::utils::immut_after_init::ImmutAfterInitCell;
+use crate::utils::MemoryRegion;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
 struct KernelMapping {
     virt_start: VirtAddr,
@@ -15,6 +19,23 @@ struct KernelMapping {
     phys_start: PhysAddr,
 }
 
+#[allow(dead_code)]
+impl KernelMapping {
+    fn phys_end(&self) -> PhysAddr {
+        self.phys_start + (self.virt_end - self.virt_start)
+    }
+
+    fn virt_to_phys(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        (vaddr >= self.virt_start && vaddr < self.virt_end)
+            .then(|| self.phys_start + (vaddr - self.virt_start))
+    }
+
+    fn phys_to_virt(&self, paddr: PhysAddr) -> Option<VirtAddr> {
+        (paddr >= self.phys_start && paddr < self.phys_end())
+            .then(|| self.virt_start + (paddr - self.phys_start))
+    }
+}
+
 static KERNEL_MAPPING: ImmutAfterInitCell<KernelMapping> = ImmutAfterInitCell::uninit();
 
 pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: PhysAddr) {
@@ -28,41 +49,150 @@ pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: PhysAd
         .expect("Already initialized kernel mapping info");
 }
 
-#[cfg(target_os = "none")]
-pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
-    if vaddr < KERNEL_MAPPING.virt_start || vaddr >= KERNEL_MAPPING.virt_end {
-        panic!("Invalid physical address {:#018x}", vaddr);
+/// Maximum number of SVSM memory ranges mapped in addition to the kernel
+/// region
+pub const MAX_EXTRA_KERNEL_MAPPINGS: usize = 8;
+
+/// Direct mappings of SVSM memory ranges outside of the kernel region. They
+/// are placed in the [`SVSM_EXTRA_MEM_BASE`] window in the order they are
+/// added, each keeping the offset of its physical address within a 2M page
+/// so that it can be mapped with huge pages.
+#[derive(Clone, Copy, Debug)]
+struct ExtraMappings {
+    mappings: [Option<KernelMapping>; MAX_EXTRA_KERNEL_MAPPINGS],
+    next_virt: VirtAddr,
+}
+
+impl ExtraMappings {
+    const fn new() -> Self {
+        Self {
+            mappings: [None; MAX_EXTRA_KERNEL_MAPPINGS],
+            next_virt: SVSM_EXTRA_MEM_BASE,
+        }
     }
 
-    let offset: usize = vaddr - KERNEL_MAPPING.virt_start;
+    fn iter(&self) -> impl Iterator<Item = &KernelMapping> {
+        self.mappings.iter().flatten()
+    }
+
+    /// Assigns a virtual range to `pregion`, which must not overlap with
+    /// `primary` or any other mapping.
+    fn add(
+        &mut self,
+        pregion: MemoryRegion<PhysAddr>,
+        primary: Option<KernelMapping>,
+    ) -> Result<MemoryRegion<VirtAddr>, SvsmError> {
+        if pregion.is_empty()
+            || !pregion.start().is_page_aligned()
+            || !pregion.end().is_page_aligned()
+        {
+            return Err(SvsmError::InvalidAddress);
+        }
+        if primary
+            .iter()
+            .chain(self.iter())
+            .any(|km| pregion.overlap(&MemoryRegion::from_addresses(km.phys_start, km.phys_end())))
+        {
+            return Err(SvsmError::InvalidAddress);
+        }
+        let slot = self
+            .mappings
+            .iter_mut()
+            .find(|km| km.is_none())
+            .ok_or(SvsmError::NotSupported)?;
+
+        let virt_start =
+            self.next_virt.align_up(PAGE_SIZE_2M) + pregion.start().bits() % PAGE_SIZE_2M;
+        let vregion = MemoryRegion::checked_new(virt_start, pregion.len()).ok_or(SvsmError::Mem)?;
+        if vregion.end() > SVSM_EXTRA_MEM_END {
+            return Err(SvsmError::Mem);
+        }
 
-    KERNEL_MAPPING.phys_start + offset
+        *slot = Some(KernelMapping {
+            virt_start: vregion.start(),
+            virt_end: vregion.end(),
+            phys_start: pregion.start(),
+        });
+        self.next_virt = vregion.end();
+        Ok(vregion)
+    }
 }
 
+static EXTRA_KERNEL_MAPPINGS: RWLock<ExtraMappings> = RWLock::new(ExtraMappings::new());
+
 #[cfg(target_os = "none")]
-pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
-    let size: usize = KERNEL_MAPPING.virt_end - KERNEL_MAPPING.virt_start;
-    if paddr < KERNEL_MAPPING.phys_start || paddr >= KERNEL_MAPPING.phys_start + size {
-        panic!("Invalid physical address {:#018x}", paddr);
-    }
+fn primary_kernel_mapping() -> Option<KernelMapping> {
+    Some(*KERNEL_MAPPING)
+}
 
-    let offset: usize = paddr - KERNEL_MAPPING.phys_start;
+#[cfg(not(target_os = "none"))]
+fn primary_kernel_mapping() -> Option<KernelMapping> {
+    None
+}
 
-    KERNEL_MAPPING.virt_start + offset
+/// Adds the SVSM memory range `pregion`, which lies outside of the kernel
+/// region, to the direct map and returns the virtual range assigned to it.
+/// The caller is responsible for installing the page table mappings.
+pub fn add_kernel_mapping(
+    pregion: MemoryRegion<PhysAddr>,
+) -> Result<MemoryRegion<VirtAddr>, SvsmError> {
+    EXTRA_KERNEL_MAPPINGS
+        .lock_write()
+        .add(pregion, primary_kernel_mapping())
+}
+
+#[cfg(target_os = "none")]
+pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
+    KERNEL_MAPPING
+        .virt_to_phys(vaddr)
+        .or_else(|| {
+            EXTRA_KERNEL_MAPPINGS
+                .lock_read()
+                .iter()
+                .find_map(|km| km.virt_to_phys(vaddr))
+        })
+        .unwrap_or_else(|| panic!("Invalid physical address {:#018x}", vaddr))
+}
+
+#[cfg(target_os = "none")]
+pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+    KERNEL_MAPPING
+        .phys_to_virt(paddr)
+        .or_else(|| {
+            EXTRA_KERNEL_MAPPINGS
+                .lock_read()
+                .iter()
+                .find_map(|km| km.phys_to_virt(paddr))
+        })
+        .unwrap_or_else(|| panic!("Invalid physical address {:#018x}", paddr))
+}
+
+/// Returns whether `vaddr` is within the direct mapping of SVSM memory,
+/// i.e. memory owned by the SVSM itself.
+#[cfg(target_os = "none")]
+pub fn virt_in_kernel_mapping(vaddr: VirtAddr) -> bool {
+    KERNEL_MAPPING.virt_to_phys(vaddr).is_some()
+        || EXTRA_KERNEL_MAPPINGS
+            .lock_read()
+            .iter()
+            .any(|km| km.virt_to_phys(vaddr).is_some())
 }
 
 #[cfg(not(target_os = "none"))]
 pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
-    use crate::address::Address;
     PhysAddr::from(vaddr.bits())
 }
 
 #[cfg(not(target_os = "none"))]
 pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
-    use crate::address::Address;
     VirtAddr::from(paddr.bits())
 }
 
+#[cfg(not(target_os = "none"))]
+pub fn virt_in_kernel_mapping(_vaddr: VirtAddr) -> bool {
+    false
+}
+
 // Address space definitions for SVSM virtual memory layout
 
 /// Size helpers
@@ -98,6 +228,11 @@ pub const PGTABLE_LVL3_IDX_SHARED: usize = 511;
 /// Base Address of shared memory region
 pub const SVSM_SHARED_BASE: VirtAddr = virt_from_idx(PGTABLE_LVL3_IDX_SHARED);
 
+/// Window for the direct mappings of SVSM memory outside of the kernel
+/// region, see [`add_kernel_mapping()`]
+pub const SVSM_EXTRA_MEM_BASE: VirtAddr = SVSM_SHARED_BASE.const_add(128 * SIZE_1G);
+pub const SVSM_EXTRA_MEM_END: VirtAddr = SVSM_SHARED_STACK_BASE;
+
 /// Mapping range for shared stacks
 pub const SVSM_SHARED_STACK_BASE: VirtAddr = SVSM_SHARED_BASE.const_add(256 * SIZE_1G);
 pub const SVSM_SHARED_STACK_END: VirtAddr = SVSM_SHARED_STACK_BASE.const_add(SIZE_1G);
@@ -154,6 +289,124 @@ pub const SVSM_PERTASK_END: VirtAddr = SVSM_PERTASK_BASE.const_add(SIZE_LEVEL3);
 /// Kernel stack for a task
 pub const SVSM_PERTASK_STACK_BASE: VirtAddr = SVSM_PERTASK_BASE;
 
+/// End of the range the kernel stack of a task can be placed in. The upper
+/// half of the task memory region is left for other task mappings.
+pub const SVSM_PERTASK_STACK_END: VirtAddr = SVSM_PERTASK_STACK_BASE.const_add(SIZE_LEVEL3 / 2);
+
+//
+// Layout randomization
+//
+
+/// Offsets of the per-CPU area and the SVSM stacks from the base addresses
+/// above. Stage2 picks them at random unless layout randomization is
+/// disabled, and passes them to the kernel in the launch info. The offset
+/// of the heap is only known to stage2, as the kernel finds the heap through
+/// the launch info anyway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LayoutSlides {
+    /// Offset of the fixed per-CPU mappings, from the per-CPU data page up to
+    /// the end of the temporary mappings, within the per-CPU region
+    pub percpu_base: usize,
+    /// Offset of the per-CPU init and IST stacks, on top of `percpu_base`
+    pub percpu_stacks: usize,
+    /// Offset of the kernel stack of every task
+    pub pertask_stack: usize,
+}
+
+impl LayoutSlides {
+    /// Largest offset of the per-CPU area, which must keep the temporary
+    /// mappings below [`SVSM_PERCPU_END`]. The offset is a multiple of
+    /// [`SIZE_LEVEL2`] so that each window of temporary mappings keeps its
+    /// own page table.
+    const PERCPU_BASE_MAX: usize = SVSM_PERCPU_END - SVSM_PERCPU_TEMP_END_2M;
+    /// Largest offset of the per-CPU stacks, which must stay below
+    /// [`SVSM_PERCPU_TEMP_BASE`]
+    const PERCPU_STACKS_MAX: usize = SIZE_LEVEL2 - SIZE_LEVEL1 - 2 * STACK_TOTAL_SIZE;
+    /// Largest offset of the task stack, which must stay below
+    /// [`SVSM_PERTASK_STACK_END`]
+    const PERTASK_STACK_MAX: usize =
+        SVSM_PERTASK_STACK_END - SVSM_PERTASK_STACK_BASE - STACK_TOTAL_SIZE;
+
+    /// Offsets for the fixed layout
+    pub const fn none() -> Self {
+        Self {
+            percpu_base: 0,
+            percpu_stacks: 0,
+            pertask_stack: 0,
+        }
+    }
+
+    /// Random offsets from `rng`. Stacks are placed at page granularity.
+    pub fn random(rng: &dyn RandomSource) -> Result<Self, SvsmError> {
+        Ok(Self {
+            percpu_base: random_slide(rng, Self::PERCPU_BASE_MAX, SIZE_LEVEL2)?,
+            percpu_stacks: random_slide(rng, Self::PERCPU_STACKS_MAX, PAGE_SIZE)?,
+            pertask_stack: random_slide(rng, Self::PERTASK_STACK_MAX, PAGE_SIZE)?,
+        })
+    }
+}
+
+/// Returns a random multiple of `align` which is not larger than `max`.
+pub fn random_slide(rng: &dyn RandomSource, max: usize, align: usize) -> Result<usize, SvsmError> {
+    let slots = (max / align) as u64 + 1;
+    Ok((rng.next_u64()? % slots) as usize * align)
+}
+
+static LAYOUT_SLIDES: ImmutAfterInitCell<LayoutSlides> =
+    ImmutAfterInitCell::new(LayoutSlides::none());
+
+pub fn init_layout_slides(slides: &LayoutSlides) {
+    LAYOUT_SLIDES
+        .reinit(slides)
+        .expect("Failed to set layout randomization offsets");
+}
+
+/// Address of the per-CPU data of the current CPU
+pub fn percpu_base() -> VirtAddr {
+    SVSM_PERCPU_BASE + LAYOUT_SLIDES.percpu_base
+}
+
+/// Address of the CAA mapping of the current CPU
+pub fn percpu_caa_base() -> VirtAddr {
+    SVSM_PERCPU_CAA_BASE + LAYOUT_SLIDES.percpu_base
+}
+
+/// Address of the guest VMSA mapping of the current CPU
+pub fn percpu_vmsa_base() -> VirtAddr {
+    SVSM_PERCPU_VMSA_BASE + LAYOUT_SLIDES.percpu_base
+}
+
+/// Window for PAGE_SIZEed temporary mappings
+pub fn percpu_temp_region_4k() -> MemoryRegion<VirtAddr> {
+    MemoryRegion::new(
+        SVSM_PERCPU_TEMP_BASE_4K + LAYOUT_SLIDES.percpu_base,
+        SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K,
+    )
+}
+
+/// Window for PAGE_SIZE_2Med temporary mappings
+pub fn percpu_temp_region_2m() -> MemoryRegion<VirtAddr> {
+    MemoryRegion::new(
+        SVSM_PERCPU_TEMP_BASE_2M + LAYOUT_SLIDES.percpu_base,
+        SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M,
+    )
+}
+
+/// Stack address of the per-cpu init task
+pub fn percpu_init_stack_base() -> VirtAddr {
+    SVSM_STACKS_INIT_TASK + LAYOUT_SLIDES.percpu_base + LAYOUT_SLIDES.percpu_stacks
+}
+
+/// DoubleFault IST stack base address
+pub fn percpu_ist_df_stack_base() -> VirtAddr {
+    SVSM_STACK_IST_DF_BASE + LAYOUT_SLIDES.percpu_base + LAYOUT_SLIDES.percpu_stacks
+}
+
+/// Kernel stack address of a task
+pub fn pertask_stack_base() -> VirtAddr {
+    SVSM_PERTASK_STACK_BASE + LAYOUT_SLIDES.pertask_stack
+}
+
 //
 // User-space mapping constants
 //
@@ -168,6 +421,7 @@ pub const USER_MEM_END: VirtAddr = USER_MEM_START.const_add(256 * SIZE_LEVEL3);
 mod tests {
     use super::*;
     use crate::locking::SpinLock;
+    use crate::rng::DeterministicRng;
 
     static KERNEL_MAPPING_TEST: ImmutAfterInitCell<KernelMapping> = ImmutAfterInitCell::uninit();
     static INITIALIZED: SpinLock<bool> = SpinLock::new(false);
@@ -201,6 +455,75 @@ mod tests {
         assert_eq!(km.phys_start, PhysAddr::new(0x3000));
     }
 
+    #[test]
+    fn test_layout_slides() {
+        let rng = DeterministicRng::new(42);
+        for _ in 0..64 {
+            let slide = random_slide(&rng, 5 * PAGE_SIZE_2M, PAGE_SIZE_2M).unwrap();
+            assert!(slide <= 5 * PAGE_SIZE_2M);
+            assert_eq!(slide % PAGE_SIZE_2M, 0);
+
+            let slides = LayoutSlides::random(&rng).unwrap();
+            assert_eq!(slides.percpu_base % SIZE_LEVEL2, 0);
+            assert!(SVSM_PERCPU_TEMP_END_2M + slides.percpu_base <= SVSM_PERCPU_END);
+            assert_eq!(slides.percpu_stacks % PAGE_SIZE, 0);
+            let df_stack_end = SVSM_STACK_IST_DF_BASE + slides.percpu_stacks + STACK_TOTAL_SIZE;
+            assert!(df_stack_end <= SVSM_PERCPU_TEMP_BASE);
+            assert_eq!(slides.pertask_stack % PAGE_SIZE, 0);
+            let task_stack_end = SVSM_PERTASK_STACK_BASE + slides.pertask_stack + STACK_TOTAL_SIZE;
+            assert!(task_stack_end <= SVSM_PERTASK_STACK_END);
+        }
+        assert_eq!(random_slide(&rng, PAGE_SIZE - 1, PAGE_SIZE).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_extra_mappings() {
+        let primary = KernelMapping {
+            virt_start: VirtAddr::new(0xffff_ff80_0020_0000),
+            virt_end: VirtAddr::new(0xffff_ff80_0040_0000),
+            phys_start: PhysAddr::new(0x8000_0000),
+        };
+        let mut extra = ExtraMappings::new();
+
+        // Overlaps with the kernel region
+        let pregion = MemoryRegion::new(PhysAddr::new(0x8010_0000), 0x20_0000);
+        assert!(matches!(
+            extra.add(pregion, Some(primary)),
+            Err(SvsmError::InvalidAddress)
+        ));
+        // Not page aligned
+        let pregion = MemoryRegion::new(PhysAddr::new(0x1000_0800), PAGE_SIZE);
+        assert!(matches!(
+            extra.add(pregion, Some(primary)),
+            Err(SvsmError::InvalidAddress)
+        ));
+
+        let first = MemoryRegion::new(PhysAddr::new(0x1000_0000), 0x10_1000);
+        let vfirst = extra.add(first, Some(primary)).unwrap();
+        assert_eq!(vfirst.start(), SVSM_EXTRA_MEM_BASE);
+        assert_eq!(vfirst.len(), first.len());
+
+        // Keeps the offset within a 2M page
+        let second = MemoryRegion::new(PhysAddr::new(0x2010_0000), 0x10_0000);
+        let vsecond = extra.add(second, Some(primary)).unwrap();
+        assert_eq!(
+            vsecond.start(),
+            SVSM_EXTRA_MEM_BASE + PAGE_SIZE_2M + 0x10_0000
+        );
+        assert!(matches!(
+            extra.add(second, Some(primary)),
+            Err(SvsmError::InvalidAddress)
+        ));
+
+        let km = extra.iter().nth(1).unwrap();
+        assert_eq!(
+            km.virt_to_phys(vsecond.start() + 0x1234),
+            Some(second.start() + 0x1234)
+        );
+        assert_eq!(km.phys_to_virt(second.end()), None);
+        assert_eq!(km.phys_to_virt(second.start()), Some(vsecond.start()));
+    }
+
     #[test]
     #[cfg(target_os = "none")]
     #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
@@ -5,16 +5,25 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::cpu::percpu::{in_nmi, this_cpu_page_cache};
 use crate::error::SvsmError;
 use crate::locking::SpinLock;
+use crate::mm::memory::find_numa_range;
+#[cfg(feature = "page-poison")]
+use crate::mm::poison::{self, PageEventKind};
+#[cfg(feature = "heap-redzones")]
+use crate::mm::redzone::{self, Quarantine};
 use crate::mm::virt_to_phys;
-use crate::types::{PAGE_SHIFT, PAGE_SIZE};
-use crate::utils::{align_down, align_up, zero_mem_region};
+use crate::time;
+use crate::types::PAGE_SIZE;
+use crate::utils::{align_down, align_up, zero_mem_region, ByteSize, PageOrder};
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem::size_of;
+#[cfg(feature = "page-poison")]
+use core::panic::Location;
 use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
-#[cfg(any(test, fuzzing))]
 use crate::locking::LockGuard;
 
 /// Represents possible errors that can occur during memory allocation.
@@ -32,6 +41,10 @@ pub enum AllocError {
     InvalidFilePage(VirtAddr),
     /// The page frame number (PFN) is invalid.
     InvalidPfn(usize),
+    /// The allocation would exceed the limit of a memory pool.
+    QuotaExceeded,
+    /// Memory cannot be allocated in NMI context.
+    NmiContext,
 }
 
 impl From<AllocError> for SvsmError {
@@ -40,8 +53,8 @@ impl From<AllocError> for SvsmError {
     }
 }
 
-/// Maximum order of page allocations (up to 128kb)
-pub const MAX_ORDER: usize = 6;
+/// Maximum order of page allocations (up to 2MiB)
+pub const MAX_ORDER: usize = 10;
 
 /// Calculates the order of a given size for page allocation.
 ///
@@ -51,12 +64,29 @@ pub const MAX_ORDER: usize = 6;
 ///
 /// # Returns
 ///
-/// The calculated order.
-pub fn get_order(size: usize) -> usize {
-    (size
-        .checked_next_power_of_two()
-        .map_or(usize::BITS, usize::ilog2) as usize)
-        .saturating_sub(PAGE_SHIFT)
+/// The calculated order, or `None` if no allocation order can hold `size`
+/// bytes.
+pub fn get_order(size: ByteSize) -> Option<PageOrder> {
+    size.order()
+}
+
+/// Constraint on the physical address of a page allocation, see
+/// [`allocate_pages_aligned()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageAlignment {
+    /// Aligned to the given power of two in bytes
+    Aligned(usize),
+    /// Not aligned to the given power of two in bytes
+    Misaligned(usize),
+}
+
+impl PageAlignment {
+    fn matches(self, paddr: PhysAddr) -> bool {
+        match self {
+            Self::Aligned(align) => paddr.is_aligned(align),
+            Self::Misaligned(align) => !paddr.is_aligned(align),
+        }
+    }
 }
 
 /// Enum representing the type of a memory page.
@@ -69,6 +99,8 @@ enum PageType {
     Compound = 3,
     // File pages used for file and task data
     File = 4,
+    // Allocated pages held by a per-CPU page cache
+    Cached = 5,
     Reserved = (1u64 << PageStorageType::TYPE_SHIFT) - 1,
 }
 
@@ -81,6 +113,7 @@ impl TryFrom<u64> for PageType {
             v if v == Self::SlabPage as u64 => Ok(Self::SlabPage),
             v if v == Self::Compound as u64 => Ok(Self::Compound),
             v if v == Self::File as u64 => Ok(Self::File),
+            v if v == Self::Cached as u64 => Ok(Self::Cached),
             v if v == Self::Reserved as u64 => Ok(Self::Reserved),
             _ => Err(AllocError::InvalidPageType),
         }
@@ -275,6 +308,25 @@ impl CompoundInfo {
     }
 }
 
+/// Struct representing information about a page held by a [`PageCache`].
+#[derive(Clone, Copy, Debug)]
+struct CachedInfo {
+    order: usize,
+}
+
+impl CachedInfo {
+    /// Encodes the [`CachedInfo`] into a [`PageStorageType`].
+    fn encode(&self) -> PageStorageType {
+        PageStorageType::new(PageType::Cached).encode_order(self.order)
+    }
+
+    /// Decodes a [`PageStorageType`] into a [`CachedInfo`].
+    fn decode(mem: PageStorageType) -> Self {
+        let order = mem.decode_order();
+        Self { order }
+    }
+}
+
 /// Struct representing information about a reserved memory page.
 #[derive(Clone, Copy, Debug)]
 struct ReservedInfo;
@@ -324,6 +376,7 @@ enum PageInfo {
     Slab(SlabPageInfo),
     Compound(CompoundInfo),
     File(FileInfo),
+    Cached(CachedInfo),
     Reserved(ReservedInfo),
 }
 
@@ -336,6 +389,7 @@ impl PageInfo {
             Self::Slab(si) => si.encode(),
             Self::Compound(ci) => ci.encode(),
             Self::File(fi) => fi.encode(),
+            Self::Cached(ci) => ci.encode(),
             Self::Reserved(ri) => ri.encode(),
         }
     }
@@ -352,6 +406,7 @@ impl PageInfo {
             PageType::SlabPage => Self::Slab(SlabPageInfo::decode(mem)),
             PageType::Compound => Self::Compound(CompoundInfo::decode(mem)),
             PageType::File => Self::File(FileInfo::decode(mem)),
+            PageType::Cached => Self::Cached(CachedInfo::decode(mem)),
             PageType::Reserved => Self::Reserved(ReservedInfo::decode(mem)),
         }
     }
@@ -364,6 +419,77 @@ pub struct MemInfo {
     free_pages: [usize; MAX_ORDER],
 }
 
+impl MemInfo {
+    /// Adds the information of another memory region.
+    fn merge(&mut self, other: &Self) {
+        for order in 0..MAX_ORDER {
+            self.total_pages[order] += other.total_pages[order];
+            self.free_pages[order] += other.free_pages[order];
+        }
+    }
+}
+
+/// Statistics of the page allocator, see [`stats()`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER],
+    /// Number of 4K pages managed by the allocator
+    pub total_pages: usize,
+    /// Number of 4K pages currently allocated
+    pub allocated_pages: usize,
+    /// Highest number of 4K pages allocated at any time
+    pub peak_allocated_pages: usize,
+    /// Number of allocations which failed for lack of free memory
+    pub failed_allocations: usize,
+}
+
+/// Level of memory pressure, see [`AllocStats::pressure()`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum MemoryPressure {
+    /// More than a quarter of the memory is free
+    Normal,
+    /// Less than a quarter of the memory is free
+    Low,
+    /// Less than 1/16th of the memory is free
+    Critical,
+}
+
+impl AllocStats {
+    /// Adds the statistics of another memory region.
+    fn merge(&mut self, other: &Self) {
+        for (blocks, other_blocks) in self.free_blocks.iter_mut().zip(other.free_blocks) {
+            *blocks += other_blocks;
+        }
+        self.total_pages += other.total_pages;
+        self.allocated_pages += other.allocated_pages;
+        self.peak_allocated_pages += other.peak_allocated_pages;
+        self.failed_allocations += other.failed_allocations;
+    }
+
+    /// Number of free 4K pages
+    pub fn free_pages(&self) -> usize {
+        self.total_pages - self.allocated_pages
+    }
+
+    /// Largest order with a free block, if any memory is free
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..MAX_ORDER).rev().find(|o| self.free_blocks[*o] != 0)
+    }
+
+    pub fn pressure(&self) -> MemoryPressure {
+        let free = self.free_pages();
+        if free < self.total_pages / 16 {
+            MemoryPressure::Critical
+        } else if free < self.total_pages / 4 {
+            MemoryPressure::Low
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+}
+
 /// Memory region with its physical/virtual addresses, page count, as well
 /// as other details.
 #[derive(Debug, Default)]
@@ -374,6 +500,8 @@ struct MemoryRegion {
     nr_pages: [usize; MAX_ORDER],
     next_page: [usize; MAX_ORDER],
     free_pages: [usize; MAX_ORDER],
+    peak_allocated_pages: usize,
+    failed_allocations: usize,
 }
 
 impl MemoryRegion {
@@ -386,6 +514,8 @@ impl MemoryRegion {
             nr_pages: [0; MAX_ORDER],
             next_page: [0; MAX_ORDER],
             free_pages: [0; MAX_ORDER],
+            peak_allocated_pages: 0,
+            failed_allocations: 0,
         }
     }
 
@@ -564,10 +694,39 @@ impl MemoryRegion {
         self.split_page(pfn, order + 1)
     }
 
+    /// Number of 4K pages currently allocated
+    fn allocated_pages(&self) -> usize {
+        let total: usize = (0..MAX_ORDER).map(|o| self.nr_pages[o] << o).sum();
+        let free: usize = (0..MAX_ORDER).map(|o| self.free_pages[o] << o).sum();
+        total - free
+    }
+
+    /// Updates the allocation statistics with the result of an allocation.
+    fn account<T>(&mut self, result: Result<T, AllocError>) -> Result<T, AllocError> {
+        match result {
+            Ok(_) => {
+                self.peak_allocated_pages = self.peak_allocated_pages.max(self.allocated_pages())
+            }
+            Err(AllocError::OutOfMemory) => self.failed_allocations += 1,
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Takes a free block of the given order off its free list, splitting
+    /// larger blocks if needed.
+    fn take_free_block(&mut self, order: usize) -> Result<usize, AllocError> {
+        let result = self
+            .refill_page_list(order)
+            .and_then(|_| self.get_next_page(order));
+        self.account(result)
+    }
+
     /// Allocates pages with a specific order and page information.
     fn allocate_pages_info(&mut self, order: usize, pg: PageInfo) -> Result<VirtAddr, AllocError> {
-        self.refill_page_list(order)?;
-        let pfn = self.get_next_page(order)?;
+        let pfn = self.take_free_block(order)?;
+        #[cfg(feature = "page-poison")]
+        self.check_poison(pfn, order);
         self.write_page_info(pfn, pg);
         Ok(self.start_virt + (pfn * PAGE_SIZE))
     }
@@ -578,25 +737,119 @@ impl MemoryRegion {
         self.allocate_pages_info(order, pg)
     }
 
-    /// Allocates a single page.
-    fn allocate_page(&mut self) -> Result<VirtAddr, AllocError> {
-        self.allocate_pages(0)
+    /// Returns the first block of `2^order` pages within the free block of
+    /// order `block_order` at `block_pfn` whose physical address satisfies
+    /// `fits`.
+    fn find_block(
+        &self,
+        block_pfn: usize,
+        block_order: usize,
+        order: usize,
+        fits: &impl Fn(PhysAddr) -> bool,
+    ) -> Option<usize> {
+        (0..1usize << (block_order - order))
+            .map(|i| block_pfn + (i << order))
+            .find(|pfn| fits(self.start_phys + pfn * PAGE_SIZE))
+    }
+
+    /// Takes the free block of order `block_order` at `block_pfn` off its
+    /// free list and splits it until the block of order `order` at `pfn`
+    /// is allocated. The remaining parts are returned to the free lists.
+    fn allocate_from_block(
+        &mut self,
+        block_pfn: usize,
+        block_order: usize,
+        pfn: usize,
+        order: usize,
+    ) -> Result<(), AllocError> {
+        self.allocate_pfn(block_pfn, block_order)?;
+        let mut block_pfn = block_pfn;
+        for split_order in (order + 1..=block_order).rev() {
+            self.split_page(block_pfn, split_order)?;
+            let half = 1usize << (split_order - 1);
+            if pfn >= block_pfn + half {
+                block_pfn += half;
+            }
+            self.allocate_pfn(block_pfn, split_order - 1)?;
+        }
+        Ok(())
     }
 
-    /// Allocates a zeroed page.
-    fn allocate_zeroed_page(&mut self) -> Result<VirtAddr, AllocError> {
-        let vaddr = self.allocate_page()?;
+    /// Allocates pages with a specific order whose physical address
+    /// satisfies `align`. Free blocks of the requested order are preferred
+    /// over splitting larger ones.
+    fn allocate_pages_aligned(
+        &mut self,
+        order: usize,
+        align: PageAlignment,
+    ) -> Result<VirtAddr, AllocError> {
+        let result = self.allocate_pages_where(order, &|paddr| align.matches(paddr));
+        self.account(result)
+    }
+
+    /// Allocates pages with a specific order within the physical range
+    /// `start..end`, optionally satisfying `align`. As the range is only a
+    /// preference of the caller, failures are not counted in the
+    /// statistics.
+    fn allocate_pages_in_range(
+        &mut self,
+        order: usize,
+        align: Option<PageAlignment>,
+        start: PhysAddr,
+        end: PhysAddr,
+    ) -> Result<VirtAddr, AllocError> {
+        let size = PAGE_SIZE << order;
+        let region_end = self.start_phys + self.page_count * PAGE_SIZE;
+        if end <= self.start_phys || start >= region_end {
+            return Err(AllocError::OutOfMemory);
+        }
+        self.allocate_pages_where(order, &|paddr| {
+            paddr >= start
+                && paddr.checked_add(size).is_some_and(|e| e <= end)
+                && align.map_or(true, |align| align.matches(paddr))
+        })
+    }
 
-        zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+    /// Allocates pages with a specific order whose physical address
+    /// satisfies `fits`. Free blocks of the requested order are preferred
+    /// over splitting larger ones. Failures are left to the caller to
+    /// account.
+    fn allocate_pages_where(
+        &mut self,
+        order: usize,
+        fits: &impl Fn(PhysAddr) -> bool,
+    ) -> Result<VirtAddr, AllocError> {
+        if order >= MAX_ORDER {
+            return Err(AllocError::InvalidPageOrder(order));
+        }
 
-        Ok(vaddr)
+        for block_order in order..MAX_ORDER {
+            let mut block_pfn = self.next_page[block_order];
+            while block_pfn != 0 {
+                if let Some(pfn) = self.find_block(block_pfn, block_order, order, fits) {
+                    self.allocate_from_block(block_pfn, block_order, pfn, order)?;
+                    #[cfg(feature = "page-poison")]
+                    self.check_poison(pfn, order);
+                    self.write_page_info(pfn, PageInfo::Allocated(AllocatedInfo { order }));
+                    return self.account(Ok(self.start_virt + (pfn * PAGE_SIZE)));
+                }
+                block_pfn = self.next_free_pfn(block_pfn, block_order);
+            }
+        }
+
+        Err(AllocError::OutOfMemory)
+    }
+
+    /// Allocates a single page.
+    fn allocate_page(&mut self) -> Result<VirtAddr, AllocError> {
+        self.allocate_pages(0)
     }
 
     /// Allocates a slab page.
     fn allocate_slab_page(&mut self, item_size: u16) -> Result<VirtAddr, AllocError> {
-        self.refill_page_list(0)?;
-
-        let pfn = self.get_next_page(0)?;
+        let pfn = self.take_free_block(0)?;
+        #[cfg(feature = "page-poison")]
+        self.check_poison(pfn, 0);
         let pg = PageInfo::Slab(SlabPageInfo {
             item_size: u64::from(item_size),
         });
@@ -812,24 +1065,50 @@ impl MemoryRegion {
 
         let res = self.read_page_info(pfn);
 
-        match res {
-            PageInfo::Allocated(ai) => {
-                self.free_page_order(pfn, ai.order);
-            }
-            PageInfo::Slab(_si) => {
-                self.free_page_order(pfn, 0);
-            }
+        let (start_pfn, order) = match res {
+            PageInfo::Allocated(ai) => (pfn, ai.order),
+            PageInfo::Slab(_si) => (pfn, 0),
             PageInfo::Compound(ci) => {
                 let mask = (1usize << ci.order) - 1;
-                let start_pfn = pfn & !mask;
-                self.free_page_order(start_pfn, ci.order);
+                (pfn & !mask, ci.order)
             }
-            PageInfo::File(_) => {
-                self.free_page_order(pfn, 0);
+            PageInfo::File(_) => (pfn, 0),
+            PageInfo::Cached(_) => {
+                panic!("Double free of page {:#018x}", vaddr);
             }
             _ => {
                 panic!("Unexpected page type in MemoryRegion::free_page()");
             }
+        };
+
+        #[cfg(feature = "page-poison")]
+        self.poison_pages(start_pfn, 1 << order);
+        self.free_page_order(start_pfn, order);
+    }
+
+    /// Fills `count` pages starting at `pfn`, which are being freed, with
+    /// the poison pattern.
+    #[cfg(feature = "page-poison")]
+    fn poison_pages(&self, pfn: usize, count: usize) {
+        // SAFETY: the pages are being freed, so nothing may use them anymore.
+        unsafe { poison::poison_range(self.start_virt + pfn * PAGE_SIZE, count * PAGE_SIZE) };
+    }
+
+    /// Checks that the free block of `order` at `pfn`, which is about to be
+    /// allocated, was not written to while it was free.
+    #[cfg(feature = "page-poison")]
+    fn check_poison(&self, pfn: usize, order: usize) {
+        // SAFETY: the block is owned by the allocator until it is returned.
+        unsafe { poison::check_poison(self.start_virt + pfn * PAGE_SIZE, PAGE_SIZE << order) };
+    }
+
+    fn stats(&self) -> AllocStats {
+        AllocStats {
+            free_blocks: self.free_pages,
+            total_pages: (0..MAX_ORDER).map(|o| self.nr_pages[o] << o).sum(),
+            allocated_pages: self.allocated_pages(),
+            peak_allocated_pages: self.peak_allocated_pages,
+            failed_allocations: self.failed_allocations,
         }
     }
 
@@ -861,6 +1140,9 @@ impl MemoryRegion {
             self.write_page_info(i, pg);
         }
 
+        #[cfg(feature = "page-poison")]
+        self.poison_pages(meta_pages, self.page_count - meta_pages);
+
         /* Now free all pages.  Any runs of pages aligned to the maximum order
          * will be freed directly into the maximum order bucket, and all other
          * pages will be freed individually so the correct orders can be
@@ -1012,14 +1294,287 @@ pub fn print_memory_info(info: &MemInfo) {
 /// root memory region.
 static ROOT_MEM: SpinLock<MemoryRegion> = SpinLock::new(MemoryRegion::new());
 
+/// Maximum number of memory zones in addition to the root memory region
+pub const MAX_MEMORY_ZONES: usize = 8;
+
+/// Memory regions managing SVSM memory ranges outside of the kernel region,
+/// see [`add_memory_zone()`]. Only the first [`NR_MEMORY_ZONES`] entries are
+/// in use. Zones are only added, never removed.
+static MEMORY_ZONES: [SpinLock<MemoryRegion>; MAX_MEMORY_ZONES] =
+    [const { SpinLock::new(MemoryRegion::new()) }; MAX_MEMORY_ZONES];
+static NR_MEMORY_ZONES: AtomicUsize = AtomicUsize::new(0);
+/// Serializes the addition of memory zones
+static MEMORY_ZONES_LOCK: SpinLock<()> = SpinLock::new(());
+
+/// Returns the memory zones in use.
+fn memory_zones() -> &'static [SpinLock<MemoryRegion>] {
+    &MEMORY_ZONES[..NR_MEMORY_ZONES.load(Ordering::Acquire)]
+}
+
+/// Returns the memory region managing `vaddr`, which is the root memory
+/// region unless `vaddr` belongs to a memory zone.
+fn region_of(vaddr: VirtAddr) -> &'static SpinLock<MemoryRegion> {
+    let start = ROOT_MEM_START.load(Ordering::Relaxed);
+    let in_root = vaddr
+        .bits()
+        .checked_sub(start)
+        .is_some_and(|off| off / PAGE_SIZE < ROOT_MEM_PAGES.load(Ordering::Relaxed));
+    if in_root {
+        return &ROOT_MEM;
+    }
+    memory_zones()
+        .iter()
+        .find(|zone| zone.lock().get_virt_offset(vaddr).is_some())
+        .unwrap_or(&ROOT_MEM)
+}
+
+/// Runs the allocation `f` on the root memory region and, if it is out of
+/// memory, on each memory zone until one of them succeeds.
+fn allocate_in_any_region(
+    f: impl Fn(&mut MemoryRegion) -> Result<VirtAddr, AllocError>,
+) -> Result<VirtAddr, AllocError> {
+    let result = f(&mut root_mem_for_alloc()?);
+    match result {
+        Err(AllocError::OutOfMemory) => memory_zones()
+            .iter()
+            .find_map(|zone| f(&mut zone.lock()).ok())
+            .ok_or(AllocError::OutOfMemory),
+        result => result,
+    }
+}
+
+/// Start address and page count of [`ROOT_MEM`], to look up the page info
+/// of allocated pages without taking its lock.
+static ROOT_MEM_START: AtomicUsize = AtomicUsize::new(0);
+static ROOT_MEM_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of pages a per-CPU magazine holds
+const PAGE_CACHE_SIZE: usize = 32;
+/// Number of pages moved between a magazine and [`ROOT_MEM`] at once
+const PAGE_CACHE_BATCH: usize = PAGE_CACHE_SIZE / 2;
+/// Highest order of allocations kept in the per-CPU page caches
+const PAGE_CACHE_MAX_ORDER: usize = 1;
+
+/// A stack of free pages of a single order
+#[derive(Clone, Copy, Debug)]
+struct PageMagazine {
+    pages: [VirtAddr; PAGE_CACHE_SIZE],
+    count: usize,
+}
+
+impl PageMagazine {
+    const fn new() -> Self {
+        Self {
+            pages: [VirtAddr::null(); PAGE_CACHE_SIZE],
+            count: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<VirtAddr> {
+        self.count = self.count.checked_sub(1)?;
+        Some(self.pages[self.count])
+    }
+
+    fn push(&mut self, vaddr: VirtAddr) {
+        self.pages[self.count] = vaddr;
+        self.count += 1;
+    }
+
+    /// Allocates up to [`PAGE_CACHE_BATCH`] blocks of `order` from `mem`.
+    /// Only fails if not a single block could be allocated.
+    fn refill(&mut self, mem: &mut MemoryRegion, order: usize) -> Result<(), AllocError> {
+        while self.count < PAGE_CACHE_BATCH {
+            match mem.allocate_pages(order) {
+                Ok(vaddr) => {
+                    set_page_cached(vaddr, order, true);
+                    self.push(vaddr);
+                }
+                Err(e) if self.count == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns up to `count` blocks of `order` to `mem`.
+    fn flush(&mut self, mem: &mut MemoryRegion, order: usize, count: usize) {
+        for _ in 0..count {
+            let Some(vaddr) = self.pop() else {
+                break;
+            };
+            set_page_cached(vaddr, order, false);
+            mem.free_page(vaddr);
+        }
+    }
+}
+
+/// Per-CPU cache of order-0 and order-1 page allocations, so that most page
+/// allocations and frees do not take the lock of the root memory region.
+/// Pages are moved to and from the root memory region in batches. Cached
+/// pages count as allocated in the statistics of the root memory region.
+#[derive(Debug)]
+pub struct PageCache {
+    magazines: [PageMagazine; PAGE_CACHE_MAX_ORDER + 1],
+}
+
+impl PageCache {
+    pub const fn new() -> Self {
+        Self {
+            magazines: [PageMagazine::new(); PAGE_CACHE_MAX_ORDER + 1],
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        root: &SpinLock<MemoryRegion>,
+        order: usize,
+    ) -> Result<VirtAddr, AllocError> {
+        let magazine = &mut self.magazines[order];
+        let vaddr = match magazine.pop() {
+            Some(vaddr) => vaddr,
+            None => {
+                magazine.refill(&mut root.lock(), order)?;
+                magazine.pop().ok_or(AllocError::OutOfMemory)?
+            }
+        };
+        set_page_cached(vaddr, order, false);
+        #[cfg(feature = "page-poison")]
+        {
+            // SAFETY: cached pages are owned by the cache until they are
+            // handed out.
+            unsafe { poison::check_poison(vaddr, PAGE_SIZE << order) };
+        }
+        Ok(vaddr)
+    }
+
+    fn free(&mut self, root: &SpinLock<MemoryRegion>, vaddr: VirtAddr, order: usize) {
+        let magazine = &mut self.magazines[order];
+        if magazine.count == PAGE_CACHE_SIZE {
+            magazine.flush(&mut root.lock(), order, PAGE_CACHE_BATCH);
+        }
+        #[cfg(feature = "page-poison")]
+        {
+            // SAFETY: the pages are being freed, so nothing may use them
+            // anymore.
+            unsafe { poison::poison_range(vaddr, PAGE_SIZE << order) };
+        }
+        set_page_cached(vaddr, order, true);
+        magazine.push(vaddr);
+    }
+
+    fn drain(&mut self, root: &SpinLock<MemoryRegion>) {
+        let mut mem = root.lock();
+        for (order, magazine) in self.magazines.iter_mut().enumerate() {
+            magazine.flush(&mut mem, order, PAGE_CACHE_SIZE);
+        }
+    }
+
+    /// Returns the number of pages held by the cache.
+    pub fn cached_pages(&self) -> usize {
+        self.magazines
+            .iter()
+            .enumerate()
+            .map(|(order, magazine)| magazine.count << order)
+            .sum()
+    }
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f` on the page cache of the current CPU. Returns `None` if the
+/// per-CPU data is not set up yet or the cache is in use by code this
+/// function interrupted.
+fn with_page_cache<R>(f: impl FnOnce(&mut PageCache) -> R) -> Option<R> {
+    let mut cache = this_cpu_page_cache()?.try_borrow_mut().ok()?;
+    Some(f(&mut cache))
+}
+
+/// Returns a pointer to the page info of the page at `vaddr` if it belongs
+/// to the root memory region.
+fn root_page_info(vaddr: VirtAddr) -> Option<*mut PageStorageType> {
+    let start = VirtAddr::from(ROOT_MEM_START.load(Ordering::Relaxed));
+    let pfn = vaddr.bits().checked_sub(start.bits())? / PAGE_SIZE;
+    if pfn >= ROOT_MEM_PAGES.load(Ordering::Relaxed) || !vaddr.is_page_aligned() {
+        return None;
+    }
+    // SAFETY: the pfn is within the page info array of the root memory
+    // region.
+    Some(unsafe { start.as_mut_ptr::<PageStorageType>().add(pfn) })
+}
+
+/// Returns the order of the page allocation starting at `vaddr` if it can
+/// be kept in a per-CPU page cache. The caller must own the allocation.
+///
+/// # Panics
+///
+/// Panics if the allocation is already held by a page cache, i.e. on a
+/// double free.
+fn cacheable_order(vaddr: VirtAddr) -> Option<usize> {
+    let info = root_page_info(vaddr)?;
+    // SAFETY: the info of an allocated page only changes when its owner
+    // frees it, so it can be read without holding the lock.
+    match PageInfo::from_mem(unsafe { info.read_volatile() }) {
+        PageInfo::Allocated(ai) if ai.order <= PAGE_CACHE_MAX_ORDER => Some(ai.order),
+        PageInfo::Cached(_) => panic!("Double free of page {:#018x}", vaddr),
+        _ => None,
+    }
+}
+
+/// Marks the block of `order` at `vaddr` in the root memory region as held
+/// by a page cache, or as allocated again. The caller must own the block.
+fn set_page_cached(vaddr: VirtAddr, order: usize, cached: bool) {
+    let info = if cached {
+        PageInfo::Cached(CachedInfo { order })
+    } else {
+        PageInfo::Allocated(AllocatedInfo { order })
+    };
+    let ptr = root_page_info(vaddr).expect("Cached page outside of the root memory region");
+    // SAFETY: the caller owns the block, and nothing else changes the info
+    // of an allocated or cached page, so it can be written without holding
+    // the lock.
+    unsafe { ptr.write_volatile(info.to_mem()) };
+}
+
+/// Returns the pages cached by the current CPU to the root memory region.
+/// Called when a CPU goes offline and when an allocation fails, so that
+/// cached pages do not cause allocation failures of this CPU.
+pub fn drain_page_cache() {
+    with_page_cache(|cache| cache.drain(&ROOT_MEM));
+}
+
+/// Locks the root memory region for an allocation. Allocations are refused
+/// in NMI context, as the interrupted code may hold the lock.
+fn root_mem_for_alloc() -> Result<LockGuard<'static, MemoryRegion>, AllocError> {
+    if in_nmi() {
+        return Err(AllocError::NmiContext);
+    }
+    Ok(ROOT_MEM.lock())
+}
+
+/// Records a successful page allocation by the caller in the history of
+/// page allocations and frees.
+#[cfg_attr(feature = "page-poison", track_caller)]
+fn track_alloc(result: Result<VirtAddr, SvsmError>) -> Result<VirtAddr, SvsmError> {
+    #[cfg(feature = "page-poison")]
+    if let Ok(vaddr) = result {
+        poison::record_page_event(PageEventKind::Alloc, vaddr, Location::caller());
+    }
+    result
+}
+
 /// Allocates a single memory page from the root memory region.
 ///
 /// # Returns
 ///
 /// Result containing the virtual address of the allocated page or an
 /// `SvsmError` if allocation fails.
+#[cfg_attr(feature = "page-poison", track_caller)]
 pub fn allocate_page() -> Result<VirtAddr, SvsmError> {
-    Ok(ROOT_MEM.lock().allocate_page()?)
+    allocate_pages(PageOrder::new(0))
 }
 
 /// Allocates multiple memory pages with a specified order from the root
@@ -1033,8 +1588,117 @@ pub fn allocate_page() -> Result<VirtAddr, SvsmError> {
 ///
 /// Result containing the virtual address of the allocated pages or an
 /// `SvsmError` if allocation fails.
-pub fn allocate_pages(order: usize) -> Result<VirtAddr, SvsmError> {
-    Ok(ROOT_MEM.lock().allocate_pages(order)?)
+#[cfg_attr(feature = "page-poison", track_caller)]
+pub fn allocate_pages(order: PageOrder) -> Result<VirtAddr, SvsmError> {
+    let order = order.get();
+    if order <= PAGE_CACHE_MAX_ORDER && !in_nmi() {
+        match with_page_cache(|cache| cache.allocate(&ROOT_MEM, order)) {
+            Some(Ok(vaddr)) => return track_alloc(Ok(vaddr)),
+            // Pages cached for the other order may be needed to satisfy
+            // this allocation.
+            Some(Err(_)) => drain_page_cache(),
+            None => {}
+        }
+    }
+    track_alloc(allocate_in_any_region(|mem| mem.allocate_pages(order)).map_err(SvsmError::from))
+}
+
+/// Allocates memory pages with a specified order from the root memory
+/// region whose physical address satisfies an alignment constraint.
+///
+/// # Arguments
+///
+/// * `order` - Order of the allocation, determining the number of pages (2^order).
+/// * `align` - Constraint on the physical address of the allocation.
+///
+/// # Returns
+///
+/// Result containing the virtual address of the allocated pages or an
+/// `SvsmError` if no free memory satisfies the constraint.
+#[cfg_attr(feature = "page-poison", track_caller)]
+pub fn allocate_pages_aligned(
+    order: PageOrder,
+    align: PageAlignment,
+) -> Result<VirtAddr, SvsmError> {
+    track_alloc(
+        allocate_in_any_region(|mem| mem.allocate_pages_aligned(order.get(), align))
+            .map_err(SvsmError::from),
+    )
+}
+
+/// Allocates memory pages with a specified order, preferring memory local
+/// to NUMA node `node`. Falls back to memory of any node if `node` is
+/// `None`, its topology is unknown, or it has no suitable free memory.
+///
+/// # Arguments
+///
+/// * `order` - Order of the allocation, determining the number of pages (2^order).
+/// * `align` - Optional constraint on the physical address of the allocation.
+/// * `node` - NUMA node to allocate from, see
+///   [`cpu_numa_node()`](crate::mm::memory::cpu_numa_node).
+///
+/// # Returns
+///
+/// Result containing the virtual address of the allocated pages or an
+/// `SvsmError` if allocation fails.
+#[cfg_attr(feature = "page-poison", track_caller)]
+pub fn allocate_pages_on_node(
+    order: PageOrder,
+    align: Option<PageAlignment>,
+    node: Option<u32>,
+) -> Result<VirtAddr, SvsmError> {
+    if let Some(node) = node {
+        let local = find_numa_range(node, |range| {
+            allocate_in_any_region(|mem| {
+                mem.allocate_pages_in_range(order.get(), align, range.start(), range.end())
+            })
+            .ok()
+        });
+        if let Some(vaddr) = local {
+            return track_alloc(Ok(vaddr));
+        }
+    }
+    match align {
+        Some(align) => allocate_pages_aligned(order, align),
+        None => allocate_pages(order),
+    }
+}
+
+/// Allocates a zeroed page, preferring memory local to NUMA node `node`.
+/// See [`allocate_pages_on_node()`].
+#[cfg_attr(feature = "page-poison", track_caller)]
+pub fn allocate_zeroed_page_on_node(node: Option<u32>) -> Result<VirtAddr, SvsmError> {
+    let vaddr = allocate_pages_on_node(PageOrder::new(0), None, node)?;
+    zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+    Ok(vaddr)
+}
+
+/// Allocates at least `bytes` bytes of physically contiguous memory whose
+/// physical address is aligned to `align`, a power of two. The memory
+/// consists of whole pages, so it can be converted to shared with
+/// [`make_region_shared()`](crate::mm::page_visibility::make_region_shared),
+/// e.g. for buffers handed to devices or the host. It is freed with
+/// [`free_page()`] on the returned virtual address.
+///
+/// # Returns
+///
+/// Result containing the virtual and physical address of the allocation,
+/// or `SvsmError::InvalidBytes` if `bytes` is zero or `align` is not a
+/// power of two.
+#[cfg_attr(feature = "page-poison", track_caller)]
+pub fn allocate_contiguous(bytes: usize, align: usize) -> Result<(VirtAddr, PhysAddr), SvsmError> {
+    if bytes == 0 || !align.is_power_of_two() {
+        return Err(SvsmError::InvalidBytes);
+    }
+    let order = get_order(ByteSize::new(bytes)).ok_or(AllocError::OutOfMemory)?;
+    // Allocations are only naturally aligned to their size within a
+    // memory region, so larger alignments must be requested explicitly.
+    let vaddr = if align <= PAGE_SIZE {
+        allocate_pages(order)?
+    } else {
+        allocate_pages_aligned(order, PageAlignment::Aligned(align))?
+    };
+    Ok((vaddr, virt_to_phys(vaddr)))
 }
 
 /// Allocate a slab page.
@@ -1048,7 +1712,9 @@ pub fn allocate_pages(order: usize) -> Result<VirtAddr, SvsmError> {
 /// Result containing the virtual address of the allocated slab page or an
 /// `SvsmError` if allocation fails.
 pub fn allocate_slab_page(item_size: u16) -> Result<VirtAddr, SvsmError> {
-    Ok(ROOT_MEM.lock().allocate_slab_page(item_size)?)
+    Ok(allocate_in_any_region(|mem| {
+        mem.allocate_slab_page(item_size)
+    })?)
 }
 
 /// Allocate a zeroed page.
@@ -1057,8 +1723,11 @@ pub fn allocate_slab_page(item_size: u16) -> Result<VirtAddr, SvsmError> {
 ///
 /// Result containing the virtual address of the allocated zeroed page or an
 /// `SvsmError` if allocation fails.
+#[cfg_attr(feature = "page-poison", track_caller)]
 pub fn allocate_zeroed_page() -> Result<VirtAddr, SvsmError> {
-    Ok(ROOT_MEM.lock().allocate_zeroed_page()?)
+    let vaddr = allocate_page()?;
+    zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+    Ok(vaddr)
 }
 
 /// Allocate a file page.
@@ -1068,7 +1737,7 @@ pub fn allocate_zeroed_page() -> Result<VirtAddr, SvsmError> {
 /// Result containing the virtual address of the allocated file page or an
 /// `SvsmError` if allocation fails.
 pub fn allocate_file_page() -> Result<VirtAddr, SvsmError> {
-    let vaddr = ROOT_MEM.lock().allocate_file_page()?;
+    let vaddr = allocate_in_any_region(|mem| mem.allocate_file_page())?;
     zero_mem_region(vaddr, vaddr + PAGE_SIZE);
     Ok(vaddr)
 }
@@ -1087,21 +1756,97 @@ pub fn allocate_file_page_ref() -> Result<PageRef, SvsmError> {
 }
 
 fn get_file_page(vaddr: VirtAddr) -> Result<(), SvsmError> {
-    Ok(ROOT_MEM.lock().get_file_page(vaddr)?)
+    Ok(region_of(vaddr).lock().get_file_page(vaddr)?)
 }
 
 fn put_file_page(vaddr: VirtAddr) -> Result<(), SvsmError> {
-    Ok(ROOT_MEM.lock().put_file_page(vaddr)?)
+    Ok(region_of(vaddr).lock().put_file_page(vaddr)?)
 }
 
 /// Free the page at the given virtual address.
+#[cfg_attr(feature = "page-poison", track_caller)]
 pub fn free_page(vaddr: VirtAddr) {
-    ROOT_MEM.lock().free_page(vaddr)
+    #[cfg(feature = "page-poison")]
+    poison::record_page_event(PageEventKind::Free, vaddr, Location::caller());
+    if let Some(order) = cacheable_order(vaddr) {
+        if with_page_cache(|cache| cache.free(&ROOT_MEM, vaddr, order)).is_some() {
+            return;
+        }
+    }
+    region_of(vaddr).lock().free_page(vaddr)
 }
 
-/// Retrieve information about the root memory
+/// Retrieve information about the root memory and all memory zones
 pub fn memory_info() -> MemInfo {
-    ROOT_MEM.lock().memory_info()
+    let mut info = ROOT_MEM.lock().memory_info();
+    for zone in memory_zones() {
+        info.merge(&zone.lock().memory_info());
+    }
+    info
+}
+
+/// Retrieve the statistics of the page allocator, summed over the root
+/// memory region and all memory zones. An allocation the root memory region
+/// cannot satisfy counts as failed in its statistics even if a memory zone
+/// satisfies it.
+pub fn stats() -> AllocStats {
+    let mut stats = ROOT_MEM.lock().stats();
+    for zone in memory_zones() {
+        stats.merge(&zone.lock().stats());
+    }
+    stats
+}
+
+static LAST_PRESSURE: AtomicU8 = AtomicU8::new(MemoryPressure::Normal as u8);
+static LAST_PRESSURE_CHECK: AtomicU64 = AtomicU64::new(0);
+
+/// Minimum number of TSC cycles between two memory pressure checks
+const PRESSURE_CHECK_INTERVAL: u64 = 1 << 30;
+
+impl MemoryPressure {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => Self::Normal,
+            1 => Self::Low,
+            _ => Self::Critical,
+        }
+    }
+}
+
+/// Returns the memory pressure and logs the allocator statistics whenever
+/// the pressure changed since the last check. The statistics are read
+/// under the allocator locks, so this checks at most once every
+/// [`PRESSURE_CHECK_INTERVAL`] cycles, by one CPU at a time, and returns
+/// the result of the last check otherwise.
+pub fn check_memory_pressure() -> MemoryPressure {
+    let now = time::now();
+    let last = LAST_PRESSURE_CHECK.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) < PRESSURE_CHECK_INTERVAL
+        || LAST_PRESSURE_CHECK
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return MemoryPressure::from_u8(LAST_PRESSURE.load(Ordering::Relaxed));
+    }
+
+    let stats = stats();
+    let pressure = stats.pressure();
+    if LAST_PRESSURE.swap(pressure as u8, Ordering::Relaxed) != pressure as u8 {
+        log_stats(&stats);
+    }
+    pressure
+}
+
+/// Logs the statistics of the root memory allocator
+pub fn log_stats(stats: &AllocStats) {
+    log::info!(
+        "Memory: {} of {} pages allocated, peak {}, {} failed allocations, pressure {:?}",
+        stats.allocated_pages,
+        stats.total_pages,
+        stats.peak_allocated_pages,
+        stats.failed_allocations,
+        stats.pressure()
+    );
 }
 
 /// Represents a slab memory page, used for efficient allocation of
@@ -1497,6 +2242,8 @@ pub struct SvsmAllocator {
     slab512: SpinLock<Slab<512>>,
     slab1024: SpinLock<Slab<1024>>,
     slab2048: SpinLock<Slab<2048>>,
+    #[cfg(feature = "heap-redzones")]
+    pub(super) quarantine: SpinLock<Quarantine>,
 }
 
 impl SvsmAllocator {
@@ -1511,6 +2258,8 @@ impl SvsmAllocator {
             slab512: SpinLock::new(Slab::new()),
             slab1024: SpinLock::new(Slab::new()),
             slab2048: SpinLock::new(Slab::new()),
+            #[cfg(feature = "heap-redzones")]
+            quarantine: SpinLock::new(Quarantine::new()),
         }
     }
 
@@ -1555,18 +2304,26 @@ impl SvsmAllocator {
         *self.slab512.lock() = Slab::new();
         *self.slab1024.lock() = Slab::new();
         *self.slab2048.lock() = Slab::new();
+        #[cfg(feature = "heap-redzones")]
+        {
+            *self.quarantine.lock() = Quarantine::new();
+        }
     }
-}
 
-unsafe impl GlobalAlloc for SvsmAllocator {
-    /// Allocates memory based on the specified layout.
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    /// Allocates memory for `layout` without redzones.
+    pub(super) fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        // The slab locks may be held by the interrupted code.
+        if in_nmi() {
+            return ptr::null_mut();
+        }
         let size = layout.size();
         let ret = match self.allocate(size) {
             Some(v) => v.map_err(Into::into),
             None => {
-                let order = get_order(size);
-                if order >= MAX_ORDER {
+                let Some(order) = get_order(ByteSize::new(size)) else {
+                    return ptr::null_mut();
+                };
+                if order.get() >= MAX_ORDER {
                     return ptr::null_mut();
                 }
                 allocate_pages(order)
@@ -1575,13 +2332,14 @@ unsafe impl GlobalAlloc for SvsmAllocator {
         ret.map_or_else(|_| ptr::null_mut(), |addr| addr.as_mut_ptr::<u8>())
     }
 
-    /// Deallocates memory based on the specified pointer and layout.
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Frees memory returned by [`Self::alloc_raw()`] for the same
+    /// `layout`.
+    pub(super) fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
         let virt_addr = VirtAddr::from(ptr);
         let size = layout.size();
 
         let info = {
-            let mem = ROOT_MEM.lock();
+            let mem = region_of(virt_addr).lock();
             let pfn = mem.get_pfn(virt_addr).expect("Freeing unknown memory");
             mem.read_page_info(pfn)
         };
@@ -1600,6 +2358,34 @@ unsafe impl GlobalAlloc for SvsmAllocator {
     }
 }
 
+unsafe impl GlobalAlloc for SvsmAllocator {
+    /// Allocates memory based on the specified layout.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-redzones")]
+        {
+            redzone::alloc(self, layout)
+        }
+        #[cfg(not(feature = "heap-redzones"))]
+        {
+            self.alloc_raw(layout)
+        }
+    }
+
+    /// Deallocates memory based on the specified pointer and layout.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-redzones")]
+        {
+            // The caller guarantees that `ptr` was returned by `alloc()`
+            // for `layout`.
+            redzone::dealloc(self, ptr, layout)
+        }
+        #[cfg(not(feature = "heap-redzones"))]
+        {
+            self.dealloc_raw(ptr, layout)
+        }
+    }
+}
+
 #[cfg_attr(any(target_os = "none"), global_allocator)]
 #[cfg_attr(not(target_os = "none"), allow(dead_code))]
 static ALLOCATOR: SvsmAllocator = SvsmAllocator::new();
@@ -1613,6 +2399,8 @@ pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
         region.start_virt = vstart;
         region.page_count = page_count;
         region.init_memory();
+        ROOT_MEM_START.store(vstart.bits(), Ordering::Relaxed);
+        ROOT_MEM_PAGES.store(page_count, Ordering::Relaxed);
         // drop lock here so slab initialization does not deadlock
     }
 
@@ -1622,6 +2410,34 @@ pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
         .expect("Failed to initialize SLAB_PAGE_SLAB");
 }
 
+/// Adds a memory zone managing `page_count` pages of SVSM memory at
+/// physical address `pstart`, mapped at `vstart`. The memory must be
+/// mapped, validated and not used for anything else. Pages of the zone are
+/// handed out once the root memory region runs out of memory.
+pub fn add_memory_zone(
+    pstart: PhysAddr,
+    vstart: VirtAddr,
+    page_count: usize,
+) -> Result<(), AllocError> {
+    let meta_pages = align_up(page_count * size_of::<PageStorageType>(), PAGE_SIZE) / PAGE_SIZE;
+    if page_count <= meta_pages {
+        return Err(AllocError::OutOfMemory);
+    }
+
+    let _guard = MEMORY_ZONES_LOCK.lock();
+    let index = NR_MEMORY_ZONES.load(Ordering::Relaxed);
+    let zone = MEMORY_ZONES.get(index).ok_or(AllocError::OutOfMemory)?;
+    {
+        let mut region = zone.lock();
+        region.start_phys = pstart;
+        region.start_virt = vstart;
+        region.page_count = page_count;
+        region.init_memory();
+    }
+    NR_MEMORY_ZONES.store(index + 1, Ordering::Release);
+    Ok(())
+}
+
 #[cfg(any(test, fuzzing))]
 /// A global lock on global memory. Should only be acquired via
 /// [`TestRootMem::setup()`].
@@ -1640,12 +2456,31 @@ pub fn layout_from_size(size: usize) -> Layout {
     Layout::from_size_align(size, align).unwrap()
 }
 
-pub fn layout_from_ptr(ptr: *mut u8) -> Option<Layout> {
+/// Returns the layout to free the heap allocation at `ptr` with.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation made by the global allocator.
+pub unsafe fn layout_from_ptr(ptr: *mut u8) -> Option<Layout> {
+    // With redzones, `ptr` is not at the start of the backing memory, which
+    // is larger than the layout redzone::dealloc() expects.
+    #[cfg(feature = "heap-redzones")]
+    {
+        // SAFETY: the caller guarantees that `ptr` was returned by the
+        // global allocator, which is redzone::alloc() with redzones.
+        unsafe { redzone::layout_of(ptr) }
+    }
+    #[cfg(not(feature = "heap-redzones"))]
+    backing_layout(ptr)
+}
+
+#[cfg(not(feature = "heap-redzones"))]
+fn backing_layout(ptr: *mut u8) -> Option<Layout> {
     let va = VirtAddr::from(ptr);
 
-    let root = ROOT_MEM.lock();
-    let pfn = root.get_pfn(va).ok()?;
-    let info = root.read_page_info(pfn);
+    let mem = region_of(va).lock();
+    let pfn = mem.get_pfn(va).ok()?;
+    let info = mem.read_page_info(pfn);
 
     match info {
         PageInfo::Allocated(ai) => {
@@ -1730,6 +2565,13 @@ impl Drop for TestRootMem<'_> {
         let layout = Layout::from_size_align(root_mem.page_count * PAGE_SIZE, PAGE_SIZE).unwrap();
         unsafe { dealloc(root_mem.start_virt.as_mut_ptr::<u8>(), layout) };
         *root_mem = MemoryRegion::new();
+        ROOT_MEM_PAGES.store(0, Ordering::Relaxed);
+
+        // Memory of zones belongs to the tests which added them
+        for zone in memory_zones() {
+            *zone.lock() = MemoryRegion::new();
+        }
+        NR_MEMORY_ZONES.store(0, Ordering::Release);
 
         // Reset the Slabs
         *SLAB_PAGE_SLAB.lock() = SlabPageSlab::new();
@@ -1894,6 +2736,204 @@ fn test_page_file() {
     assert!(matches!(info, PageInfo::Free { .. }));
 }
 
+#[test]
+/// Allocate pages with alignment constraints and verify that freeing them
+/// restores the free lists.
+fn test_page_alloc_aligned() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let mut root_mem = ROOT_MEM.lock();
+    let info_before = root_mem.memory_info();
+
+    let mut allocs = Vec::new();
+    for order in 0..4 {
+        let align = PageAlignment::Aligned(PAGE_SIZE << (order + 2));
+        let vaddr = root_mem.allocate_pages_aligned(order, align).unwrap();
+        assert!(align.matches(root_mem.virt_to_phys(vaddr).unwrap()));
+        allocs.push(vaddr);
+    }
+    for _ in 0..64 {
+        let align = PageAlignment::Misaligned(PAGE_SIZE * 2);
+        let vaddr = root_mem.allocate_pages_aligned(0, align).unwrap();
+        assert!(align.matches(root_mem.virt_to_phys(vaddr).unwrap()));
+        allocs.push(vaddr);
+    }
+
+    for vaddr in allocs {
+        root_mem.free_page(vaddr);
+    }
+    assert_eq!(info_before.free_pages, root_mem.memory_info().free_pages);
+}
+
+#[test]
+/// Allocate physically contiguous memory with alignment constraints.
+fn test_allocate_contiguous() {
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+    let (vaddr, paddr) = allocate_contiguous(3 * PAGE_SIZE + 1, 8).unwrap();
+    assert_eq!(paddr, virt_to_phys(vaddr));
+    assert!(paddr.is_page_aligned());
+    free_page(vaddr);
+
+    let align = PAGE_SIZE * 16;
+    let (vaddr, paddr) = allocate_contiguous(PAGE_SIZE, align).unwrap();
+    assert!(paddr.is_aligned(align));
+    free_page(vaddr);
+
+    assert!(allocate_contiguous(0, PAGE_SIZE).is_err());
+    assert!(allocate_contiguous(PAGE_SIZE, 3).is_err());
+    assert!(allocate_contiguous(PAGE_SIZE << MAX_ORDER, PAGE_SIZE).is_err());
+}
+
+#[test]
+/// Check that the statistics track allocations, the high-water mark and
+/// allocation failures.
+fn test_alloc_stats() {
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let mut root_mem = ROOT_MEM.lock();
+    let before = root_mem.stats();
+    assert_eq!(before.pressure(), MemoryPressure::Normal);
+
+    let a = root_mem.allocate_pages(2).unwrap();
+    let b = root_mem.allocate_page().unwrap();
+    let stats = root_mem.stats();
+    assert_eq!(stats.allocated_pages, before.allocated_pages + 5);
+    assert_eq!(stats.free_pages(), before.free_pages() - 5);
+
+    root_mem.free_page(a);
+    root_mem.free_page(b);
+    let stats = root_mem.stats();
+    assert_eq!(stats.allocated_pages, before.allocated_pages);
+    assert!(stats.peak_allocated_pages >= before.allocated_pages + 5);
+
+    assert!(root_mem
+        .allocate_pages_aligned(0, PageAlignment::Aligned(1 << 60))
+        .is_err());
+    assert_eq!(
+        root_mem.stats().failed_allocations,
+        before.failed_allocations + 1
+    );
+}
+
+#[test]
+fn test_page_alloc_in_range() {
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let mut root_mem = ROOT_MEM.lock();
+    let before = root_mem.stats();
+    let start = root_mem.start_phys + 0x40000;
+    let end = start + 0x10000;
+
+    let vaddr = root_mem
+        .allocate_pages_in_range(2, None, start, end)
+        .unwrap();
+    let paddr = root_mem.virt_to_phys(vaddr).unwrap();
+    assert!(paddr >= start && paddr + 4 * PAGE_SIZE <= end);
+    root_mem.free_page(vaddr);
+
+    // Ranges outside of the region are not counted as failures
+    let outside = root_mem.start_phys + root_mem.page_count * PAGE_SIZE;
+    assert!(root_mem
+        .allocate_pages_in_range(0, None, outside, outside + PAGE_SIZE)
+        .is_err());
+    assert_eq!(
+        root_mem.stats().failed_allocations,
+        before.failed_allocations
+    );
+}
+
+#[test]
+fn test_page_cache() {
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let before = stats();
+    let mut cache = PageCache::new();
+
+    // The first allocation refills the magazine with a whole batch
+    let a = cache.allocate(&ROOT_MEM, 0).unwrap();
+    assert_eq!(
+        stats().allocated_pages,
+        before.allocated_pages + PAGE_CACHE_BATCH
+    );
+    assert_eq!(cache.cached_pages(), PAGE_CACHE_BATCH - 1);
+    let b = cache.allocate(&ROOT_MEM, 1).unwrap();
+    assert_eq!(cacheable_order(a), Some(0));
+    assert_eq!(cacheable_order(b), Some(1));
+    assert_eq!(cacheable_order(b + PAGE_SIZE), None);
+
+    // Frees go to the cache until a magazine is full, which flushes a batch
+    cache.free(&ROOT_MEM, a, 0);
+    cache.free(&ROOT_MEM, b, 1);
+    let pages: [VirtAddr; PAGE_CACHE_SIZE] =
+        core::array::from_fn(|_| ROOT_MEM.lock().allocate_page().unwrap());
+    for page in pages {
+        cache.free(&ROOT_MEM, page, 0);
+    }
+    assert!(cache.magazines[0].count <= PAGE_CACHE_SIZE);
+    assert_eq!(
+        stats().allocated_pages,
+        before.allocated_pages + cache.cached_pages()
+    );
+
+    cache.drain(&ROOT_MEM);
+    assert_eq!(cache.cached_pages(), 0);
+    assert_eq!(stats().allocated_pages, before.allocated_pages);
+}
+
+#[test]
+#[should_panic]
+fn test_page_cache_double_free() {
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let mut cache = PageCache::new();
+    let page = cache.allocate(&ROOT_MEM, 0).unwrap();
+    cache.free(&ROOT_MEM, page, 0);
+    // The page is cached, so freeing it again must not cache it twice
+    cacheable_order(page);
+}
+
+#[test]
+#[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+fn test_memory_zones() {
+    extern crate alloc;
+    use alloc::alloc::{alloc, dealloc};
+    use alloc::vec::Vec;
+
+    let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+    let before = stats();
+
+    let zone_pages = 64;
+    let layout = Layout::from_size_align(zone_pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+    // SAFETY: the layout has a non-zero size.
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    let vstart = VirtAddr::from(ptr);
+    add_memory_zone(PhysAddr::from(vstart.bits()), vstart, zone_pages).unwrap();
+    assert_eq!(stats().total_pages, before.total_pages + zone_pages - 1);
+
+    // Pages of the zone are handed out once the root region is exhausted
+    let vend = vstart + zone_pages * PAGE_SIZE;
+    let mut pages = Vec::new();
+    loop {
+        let vaddr = allocate_page().unwrap();
+        pages.push(vaddr);
+        if vaddr >= vstart && vaddr < vend {
+            break;
+        }
+    }
+    assert!(core::ptr::eq(
+        region_of(*pages.last().unwrap()),
+        &MEMORY_ZONES[0]
+    ));
+    assert!(core::ptr::eq(region_of(pages[0]), &ROOT_MEM));
+
+    for vaddr in pages {
+        free_page(vaddr);
+    }
+    assert_eq!(stats().allocated_pages, before.allocated_pages);
+    // SAFETY: the zone is no longer used.
+    unsafe { dealloc(ptr, layout) };
+}
+
 #[cfg(test)]
 const TEST_SLAB_SIZES: [usize; 7] = [32, 64, 128, 256, 512, 1024, 2048];
 
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Memory encryption bits in physical addresses.
+//!
+//! Whether a mapping targets private or shared memory is encoded in a
+//! physical address bit of its page table entry. On SEV-SNP the C-bit is set
+//! for private memory, while with vTOM on SEV-SNP and on TDX a shared bit is
+//! set for shared memory instead. [`EncryptionMask`] hides this difference,
+//! so that page table code does not need to know which platform it runs on.
+
+use crate::address::{Address, PhysAddr};
+
+/// The address bits marking a page as private or shared. At most one of the
+/// two masks is non-zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncryptionMask {
+    private: usize,
+    shared: usize,
+}
+
+impl EncryptionMask {
+    /// No encryption bits, used when running without memory encryption.
+    pub const fn none() -> Self {
+        Self {
+            private: 0,
+            shared: 0,
+        }
+    }
+
+    /// Private memory is marked by setting the C-bit at position `c_bit`.
+    pub const fn c_bit(c_bit: u32) -> Self {
+        Self {
+            private: 1 << c_bit,
+            shared: 0,
+        }
+    }
+
+    /// Shared memory is marked by setting the bits in `shared`, e.g. vTOM
+    /// or the TDX shared bit.
+    pub const fn shared_bit(shared: usize) -> Self {
+        Self { private: 0, shared }
+    }
+
+    /// Bits set in the address of private mappings
+    pub const fn private_mask(&self) -> usize {
+        self.private
+    }
+
+    /// Bits set in the address of shared mappings
+    pub const fn shared_mask(&self) -> usize {
+        self.shared
+    }
+
+    /// All encryption bits, regardless of their polarity
+    pub const fn all(&self) -> usize {
+        self.private | self.shared
+    }
+
+    /// Returns the address used to map `paddr` as shared memory.
+    /// `paddr` must not carry any encryption bits.
+    pub fn make_shared(&self, paddr: PhysAddr) -> PhysAddr {
+        debug_assert_eq!(
+            self.strip(paddr),
+            paddr,
+            "encryption bits already set in {:#018x}",
+            paddr
+        );
+        let addr = PhysAddr::from(self.strip(paddr).bits() | self.shared);
+        debug_assert!(self.all() == 0 || self.is_shared(addr));
+        addr
+    }
+
+    /// Returns the address used to map `paddr` as private memory.
+    /// `paddr` must not carry any encryption bits.
+    pub fn make_private(&self, paddr: PhysAddr) -> PhysAddr {
+        debug_assert_eq!(
+            self.strip(paddr),
+            paddr,
+            "encryption bits already set in {:#018x}",
+            paddr
+        );
+        let addr = PhysAddr::from(self.strip(paddr).bits() | self.private);
+        debug_assert!(!self.is_shared(addr));
+        addr
+    }
+
+    /// Returns `paddr` with all encryption bits cleared.
+    pub fn strip(&self, paddr: PhysAddr) -> PhysAddr {
+        PhysAddr::from(paddr.bits() & !self.all())
+    }
+
+    /// Returns whether `paddr` encodes a shared mapping. Without memory
+    /// encryption, all memory is considered private.
+    pub fn is_shared(&self, paddr: PhysAddr) -> bool {
+        let bits = paddr.bits();
+        if self.shared != 0 {
+            bits & self.shared == self.shared
+        } else {
+            self.private != 0 && bits & self.private == 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: PhysAddr = PhysAddr::new(0x1234_5000);
+
+    #[test]
+    fn c_bit_polarity() {
+        let mask = EncryptionMask::c_bit(51);
+        let private = mask.make_private(ADDR);
+        let shared = mask.make_shared(ADDR);
+        assert_eq!(private.bits(), ADDR.bits() | 1 << 51);
+        assert_eq!(shared, ADDR);
+        assert!(!mask.is_shared(private));
+        assert!(mask.is_shared(shared));
+        assert_eq!(mask.strip(private), ADDR);
+    }
+
+    #[test]
+    fn shared_bit_polarity() {
+        let mask = EncryptionMask::shared_bit(1 << 47);
+        let private = mask.make_private(ADDR);
+        let shared = mask.make_shared(ADDR);
+        assert_eq!(private, ADDR);
+        assert_eq!(shared.bits(), ADDR.bits() | 1 << 47);
+        assert!(!mask.is_shared(private));
+        assert!(mask.is_shared(shared));
+        assert_eq!(mask.strip(shared), ADDR);
+    }
+
+    #[test]
+    fn no_encryption() {
+        let mask = EncryptionMask::none();
+        assert_eq!(mask.make_private(ADDR), ADDR);
+        assert_eq!(mask.make_shared(ADDR), ADDR);
+        assert!(!mask.is_shared(ADDR));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn reencode_private_as_shared() {
+        let mask = EncryptionMask::c_bit(51);
+        let _ = mask.make_shared(mask.make_private(ADDR));
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Reference counting of guest pages held by the SVSM.
+//!
+//! Some guest pages stay mapped in the SVSM beyond a single request, like
+//! the calling area of a vCPU, and several subsystems may hold the same
+//! page. Each holder keeps a [`GuestPageRef`], and requests which would
+//! change the validation or RMP state of a guest page are refused as long
+//! as any reference to it exists, so the state only changes once the last
+//! reference is dropped.
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::alloc::AllocError;
+use crate::utils::MemoryRegion;
+use alloc::vec::Vec;
+
+/// Reference counts of guest pages, sorted by address
+#[derive(Debug, Default)]
+struct RefTable {
+    pages: Vec<(PhysAddr, usize)>,
+}
+
+impl RefTable {
+    const fn new() -> Self {
+        Self { pages: Vec::new() }
+    }
+
+    fn get(&mut self, paddr: PhysAddr) -> Result<(), SvsmError> {
+        match self.pages.binary_search_by_key(&paddr, |(p, _)| *p) {
+            Ok(i) => self.pages[i].1 += 1,
+            Err(i) => {
+                self.pages
+                    .try_reserve(1)
+                    .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+                self.pages.insert(i, (paddr, 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the last reference to the page was dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page is not referenced.
+    fn put(&mut self, paddr: PhysAddr) -> bool {
+        let i = self
+            .pages
+            .binary_search_by_key(&paddr, |(p, _)| *p)
+            .expect("Dropping unreferenced guest page");
+        self.pages[i].1 -= 1;
+        if self.pages[i].1 > 0 {
+            return false;
+        }
+        self.pages.remove(i);
+        true
+    }
+
+    fn count(&self, paddr: PhysAddr) -> usize {
+        self.pages
+            .binary_search_by_key(&paddr, |(p, _)| *p)
+            .map_or(0, |i| self.pages[i].1)
+    }
+
+    fn any_in(&self, region: MemoryRegion<PhysAddr>) -> bool {
+        let i = self.pages.partition_point(|(p, _)| *p < region.start());
+        self.pages.get(i).is_some_and(|(p, _)| region.contains(*p))
+    }
+}
+
+static GUEST_PAGE_REFS: SpinLock<RefTable> = SpinLock::new(RefTable::new());
+
+/// A counted reference to a guest page. While it exists, the validation
+/// and RMP state of the page are not changed on behalf of the guest.
+#[derive(Debug)]
+pub struct GuestPageRef {
+    paddr: PhysAddr,
+}
+
+impl GuestPageRef {
+    /// Takes a reference to the guest page containing `paddr`.
+    pub fn new(paddr: PhysAddr) -> Result<Self, SvsmError> {
+        let paddr = paddr.page_align();
+        GUEST_PAGE_REFS.lock().get(paddr)?;
+        Ok(Self { paddr })
+    }
+
+    /// Takes another reference to the same page.
+    pub fn try_clone(&self) -> Result<Self, SvsmError> {
+        Self::new(self.paddr)
+    }
+
+    /// Returns the page-aligned physical address of the page.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.paddr
+    }
+}
+
+impl Drop for GuestPageRef {
+    fn drop(&mut self) {
+        GUEST_PAGE_REFS.lock().put(self.paddr);
+    }
+}
+
+/// Returns the number of references to the guest page containing `paddr`.
+pub fn guest_page_refs(paddr: PhysAddr) -> usize {
+    GUEST_PAGE_REFS.lock().count(paddr.page_align())
+}
+
+/// Returns `true` if any guest page in `region` is referenced.
+pub fn guest_pages_referenced(region: MemoryRegion<PhysAddr>) -> bool {
+    GUEST_PAGE_REFS.lock().any_in(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
+
+    #[test]
+    fn ref_table() {
+        let mut table = RefTable::new();
+        let page = PhysAddr::new(0x20_0000);
+        table.get(page).unwrap();
+        table.get(page).unwrap();
+        table.get(PhysAddr::new(0x1000)).unwrap();
+        assert_eq!(table.count(page), 2);
+
+        assert!(table.any_in(MemoryRegion::new(PhysAddr::new(0), PAGE_SIZE_2M)));
+        assert!(table.any_in(MemoryRegion::new(page, PAGE_SIZE)));
+        assert!(!table.any_in(MemoryRegion::new(page + PAGE_SIZE, PAGE_SIZE_2M)));
+
+        assert!(!table.put(page));
+        assert!(table.put(page));
+        assert_eq!(table.count(page), 0);
+        assert!(!table.any_in(MemoryRegion::new(page, PAGE_SIZE)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn put_unreferenced() {
+        RefTable::new().put(PhysAddr::new(0x1000));
+    }
+}
@@ -4,11 +4,14 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::address::{Address, VirtAddr};
+use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::error::SvsmError;
+use crate::mm::{
+    valid_phys_address, PerCPUPageMappingGuard, PAGE_SIZE, USER_MEM_END, USER_MEM_START,
+};
 
 use core::arch::asm;
-use core::mem::{size_of, MaybeUninit};
+use core::mem::{size_of, size_of_val, MaybeUninit};
 
 #[allow(dead_code)]
 #[inline]
@@ -148,7 +151,13 @@ unsafe fn read_u64(v: VirtAddr) -> Result<u64, SvsmError> {
 
 #[inline]
 unsafe fn do_movsb<T>(src: *const T, dst: *mut T) -> Result<(), SvsmError> {
-    let size: usize = size_of::<T>();
+    do_movsb_bytes(src.cast(), dst.cast(), size_of::<T>())
+}
+
+/// Copies `size` bytes with a single `rep movsb`, recovering from faults
+/// on either side through the exception table.
+#[inline]
+unsafe fn do_movsb_bytes(src: *const u8, dst: *mut u8, size: usize) -> Result<(), SvsmError> {
     let mut rcx: u64;
 
     asm!("1:cld
@@ -171,6 +180,32 @@ unsafe fn do_movsb<T>(src: *const T, dst: *mut T) -> Result<(), SvsmError> {
     }
 }
 
+/// Fills `size` bytes at `dst` with `val` using a single `rep stosb`,
+/// recovering from faults through the exception table.
+#[inline]
+unsafe fn do_stosb_bytes(dst: *mut u8, val: u8, size: usize) -> Result<(), SvsmError> {
+    let mut rcx: u64;
+
+    asm!("1:cld
+            rep stosb
+          2:
+         .pushsection \"__exception_table\",\"a\"
+         .balign 16
+         .quad (1b)
+         .quad (2b)
+         .popsection",
+            inout("rdi") dst => _,
+            in("al") val,
+            inout("rcx") size => rcx,
+            options(att_syntax, nostack));
+
+    if rcx == 0 {
+        Ok(())
+    } else {
+        Err(SvsmError::InvalidAddress)
+    }
+}
+
 #[derive(Debug)]
 pub struct GuestPtr<T: Copy> {
     ptr: *mut T,
@@ -209,6 +244,33 @@ impl<T: Copy> GuestPtr<T> {
         unsafe { do_movsb(buf, self.ptr) }
     }
 
+    /// Reads `buf.len()` consecutive elements starting at this pointer
+    /// with a single copy.
+    #[inline]
+    pub fn read_slice(&self, buf: &mut [T]) -> Result<(), SvsmError> {
+        unsafe {
+            do_movsb_bytes(
+                self.ptr.cast_const().cast(),
+                buf.as_mut_ptr().cast(),
+                size_of_val(buf),
+            )
+        }
+    }
+
+    /// Writes the elements of `buf` to consecutive locations starting at
+    /// this pointer with a single copy.
+    #[inline]
+    pub fn write_slice(&self, buf: &[T]) -> Result<(), SvsmError> {
+        unsafe { do_movsb_bytes(buf.as_ptr().cast(), self.ptr.cast(), size_of_val(buf)) }
+    }
+
+    /// Sets `count` bytes starting at this pointer to `val`. The count is in
+    /// bytes, not in elements of `T`.
+    #[inline]
+    pub fn write_bytes(&self, val: u8, count: usize) -> Result<(), SvsmError> {
+        unsafe { do_stosb_bytes(self.ptr.cast(), val, count) }
+    }
+
     #[inline]
     pub const fn cast<N: Copy>(&self) -> GuestPtr<N> {
         GuestPtr::from_ptr(self.ptr.cast())
@@ -220,6 +282,188 @@ impl<T: Copy> GuestPtr<T> {
     }
 }
 
+/// A pointer into the memory of the current user-mode task. System call
+/// handlers use it to copy arguments from and results to the task. Unlike a
+/// [`GuestPtr`], every access first checks that the accessed range lies
+/// entirely within user memory, so a task cannot make the kernel access its
+/// own memory. Pages which are not mapped yet are faulted in, and accesses to
+/// unmapped memory fail with [`SvsmError::InvalidAddress`] through the
+/// exception table instead of crashing the SVSM.
+#[derive(Debug)]
+pub struct UserPtr<T: Copy> {
+    guest: GuestPtr<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    #[inline]
+    pub fn new(v: VirtAddr) -> Self {
+        Self {
+            guest: GuestPtr::new(v),
+        }
+    }
+
+    /// Checks that `count` elements starting at this pointer are user memory
+    fn check(&self, count: usize) -> Result<(), SvsmError> {
+        let start = VirtAddr::from(self.guest.ptr);
+        let size = size_of::<T>()
+            .checked_mul(count)
+            .ok_or(SvsmError::InvalidAddress)?;
+        let end = start.checked_add(size).ok_or(SvsmError::InvalidAddress)?;
+        if start < USER_MEM_START || end > USER_MEM_END {
+            return Err(SvsmError::InvalidAddress);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn read(&self) -> Result<T, SvsmError> {
+        self.check(1)?;
+        self.guest.read()
+    }
+
+    #[inline]
+    pub fn write(&self, buf: T) -> Result<(), SvsmError> {
+        self.check(1)?;
+        self.guest.write(buf)
+    }
+
+    #[inline]
+    pub fn write_ref(&self, buf: &T) -> Result<(), SvsmError> {
+        self.check(1)?;
+        self.guest.write_ref(buf)
+    }
+
+    #[inline]
+    pub fn read_slice(&self, buf: &mut [T]) -> Result<(), SvsmError> {
+        self.check(buf.len())?;
+        self.guest.read_slice(buf)
+    }
+
+    #[inline]
+    pub fn write_slice(&self, buf: &[T]) -> Result<(), SvsmError> {
+        self.check(buf.len())?;
+        self.guest.write_slice(buf)
+    }
+
+    /// Sets `count` bytes starting at this pointer to `val`
+    #[inline]
+    pub fn write_bytes(&self, val: u8, count: usize) -> Result<(), SvsmError> {
+        self.cast::<u8>().check(count)?;
+        self.guest.write_bytes(val, count)
+    }
+
+    #[inline]
+    pub fn cast<N: Copy>(&self) -> UserPtr<N> {
+        UserPtr {
+            guest: self.guest.cast(),
+        }
+    }
+
+    #[inline]
+    pub fn offset(&self, count: isize) -> Self {
+        Self {
+            guest: self.guest.offset(count),
+        }
+    }
+}
+
+/// Writes `bytes` to guest physical memory at `gpa` through a single
+/// temporary mapping. Fails with [`SvsmError::InvalidAddress`] if any page
+/// of the range is not guest memory the SVSM may write to.
+fn write_guest_phys(gpa: PhysAddr, bytes: &[u8]) -> Result<(), SvsmError> {
+    let end = gpa + bytes.len();
+    if !(gpa.page_align().bits()..end.page_align_up().bits())
+        .step_by(PAGE_SIZE)
+        .all(|page| valid_phys_address(PhysAddr::from(page)))
+    {
+        return Err(SvsmError::InvalidAddress);
+    }
+    let guard = PerCPUPageMappingGuard::create(gpa.page_align(), end.page_align_up(), 0)?;
+    GuestPtr::<u8>::new(guard.virt_addr() + gpa.page_offset()).write_slice(bytes)
+}
+
+/// Size of the buffer of a [`GuestWriter`]
+pub const GUEST_WRITER_BATCH: usize = 256;
+
+/// Batches small writes to guest physical memory. Writes to adjacent
+/// addresses are collected and copied to the guest with a single mapping
+/// and copy when the next write is not adjacent, the buffer is full, or on
+/// [`GuestWriter::flush()`]. This avoids a mapping and TLB flush per field
+/// when updating many fields of a structure shared with the guest.
+///
+/// Until the writer is flushed, the guest may see none, some or all of the
+/// pending writes. Dropping the writer flushes it, but errors are only
+/// logged, so callers which need to handle them must flush explicitly.
+#[derive(Debug)]
+pub struct GuestWriter {
+    buf: [u8; GUEST_WRITER_BATCH],
+    start: PhysAddr,
+    len: usize,
+    flush_fn: fn(PhysAddr, &[u8]) -> Result<(), SvsmError>,
+}
+
+impl Default for GuestWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestWriter {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; GUEST_WRITER_BATCH],
+            start: PhysAddr::null(),
+            len: 0,
+            flush_fn: write_guest_phys,
+        }
+    }
+
+    /// Queues a write of `bytes` to `gpa`. Pending writes are flushed first
+    /// if `gpa` does not directly follow them or the buffer is full.
+    pub fn write_bytes(&mut self, gpa: PhysAddr, bytes: &[u8]) -> Result<(), SvsmError> {
+        let adjacent = self.len > 0 && self.start + self.len == gpa;
+        if !adjacent || self.len + bytes.len() > GUEST_WRITER_BATCH {
+            self.flush()?;
+        }
+        if bytes.len() > GUEST_WRITER_BATCH {
+            return (self.flush_fn)(gpa, bytes);
+        }
+        if self.len == 0 {
+            self.start = gpa;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    pub fn write_u32(&mut self, gpa: PhysAddr, val: u32) -> Result<(), SvsmError> {
+        self.write_bytes(gpa, &val.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, gpa: PhysAddr, val: u64) -> Result<(), SvsmError> {
+        self.write_bytes(gpa, &val.to_le_bytes())
+    }
+
+    /// Copies all pending writes to the guest.
+    pub fn flush(&mut self) -> Result<(), SvsmError> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let len = self.len;
+        // Drop the pending data even on failure, retrying would not help.
+        self.len = 0;
+        (self.flush_fn)(self.start, &self.buf[..len])
+    }
+}
+
+impl Drop for GuestWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("Failed to flush guest writes at {:#x}: {:?}", self.start, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -260,6 +504,26 @@ mod tests {
         assert_eq!(result, test_buffer);
     }
 
+    #[test]
+    #[cfg_attr(miri, ignore = "inline assembly")]
+    fn test_slice_copy() {
+        let src: [u32; 1000] = core::array::from_fn(|i| i as u32);
+        let ptr: GuestPtr<u32> = GuestPtr::new(VirtAddr::from(src.as_ptr()));
+        let mut dst = [0u32; 1000];
+        ptr.read_slice(&mut dst).unwrap();
+        assert_eq!(src, dst);
+
+        let mut target = [0u32; 1002];
+        let ptr: GuestPtr<u32> = GuestPtr::new(VirtAddr::from(target.as_mut_ptr()));
+        ptr.offset(1).write_slice(&src).unwrap();
+        assert_eq!(target[0], 0);
+        assert_eq!(target[1..1001], src);
+        assert_eq!(target[1001], 0);
+
+        // Empty copies do not touch memory
+        ptr.write_slice(&[]).unwrap();
+    }
+
     #[test]
     #[cfg_attr(miri, ignore = "inline assembly")]
     #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
@@ -269,4 +533,99 @@ mod tests {
         let err = ptr.read();
         assert!(err.is_err());
     }
+
+    extern crate alloc;
+    use crate::locking::SpinLock;
+    use alloc::vec::Vec;
+
+    static FLUSHES: SpinLock<Vec<(PhysAddr, Vec<u8>)>> = SpinLock::new(Vec::new());
+
+    fn record_flush(gpa: PhysAddr, bytes: &[u8]) -> Result<(), SvsmError> {
+        FLUSHES.lock().push((gpa, bytes.to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_bytes() {
+        let mut buf = [0xffu8; 64];
+        // Unaligned start and odd lengths, including zero
+        for (offset, len) in [(1, 0), (1, 1), (3, 7), (5, 13), (7, 57)] {
+            buf.fill(0xff);
+            let ptr = GuestPtr::<u8>::new(VirtAddr::from(buf.as_mut_ptr())).offset(offset);
+            ptr.write_bytes(0, len).unwrap();
+
+            let start = offset as usize;
+            assert!(buf[..start].iter().all(|b| *b == 0xff));
+            assert!(buf[start..start + len].iter().all(|b| *b == 0));
+            assert!(buf[start + len..].iter().all(|b| *b == 0xff));
+        }
+    }
+
+    #[test]
+    fn user_ptr_bounds() {
+        // Kernel addresses are rejected before they are accessed
+        let kernel = UserPtr::<u64>::new(USER_MEM_END);
+        assert!(matches!(kernel.read(), Err(SvsmError::InvalidAddress)));
+        assert!(matches!(kernel.write(0), Err(SvsmError::InvalidAddress)));
+
+        // As are ranges which end beyond user memory
+        let last = UserPtr::<u8>::new(USER_MEM_END - 4);
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            last.read_slice(&mut buf),
+            Err(SvsmError::InvalidAddress)
+        ));
+        assert!(matches!(
+            last.write_slice(&buf),
+            Err(SvsmError::InvalidAddress)
+        ));
+        assert!(matches!(
+            last.write_bytes(0, 8),
+            Err(SvsmError::InvalidAddress)
+        ));
+    }
+
+    #[test]
+    fn guest_writer_invalid_address() {
+        // Far outside of any guest memory, so this fails before mapping
+        let mut writer = GuestWriter::new();
+        writer
+            .write_u64(PhysAddr::from(1u64 << 50), 0x1234)
+            .unwrap();
+        assert!(matches!(writer.flush(), Err(SvsmError::InvalidAddress)));
+    }
+
+    #[test]
+    fn guest_writer_batching() {
+        let mut writer = GuestWriter {
+            flush_fn: record_flush,
+            ..GuestWriter::new()
+        };
+        writer.write_u32(PhysAddr::from(0x1000u64), 1).unwrap();
+        writer.write_u32(PhysAddr::from(0x1004u64), 2).unwrap();
+        writer.write_u64(PhysAddr::from(0x1008u64), 3).unwrap();
+        assert!(FLUSHES.lock().is_empty());
+
+        // A write which is not adjacent flushes the pending ones
+        writer.write_u32(PhysAddr::from(0x2000u64), 4).unwrap();
+        // Large writes are passed through
+        writer
+            .write_bytes(PhysAddr::from(0x2004u64), &[5; 300])
+            .unwrap();
+        drop(writer);
+
+        let flushes = core::mem::take(&mut *FLUSHES.lock());
+        assert_eq!(flushes.len(), 3);
+        assert_eq!(flushes[0].0, PhysAddr::from(0x1000u64));
+        assert_eq!(
+            flushes[0].1,
+            [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            flushes[1],
+            (PhysAddr::from(0x2000u64), [4, 0, 0, 0].to_vec())
+        );
+        assert_eq!(flushes[2].0, PhysAddr::from(0x2004u64));
+        assert_eq!(flushes[2].1.len(), 300);
+    }
 }
@@ -6,20 +6,55 @@
 
 extern crate alloc;
 
+use crate::acpi::tables::ACPINumaInfo;
 use crate::address::{Address, PhysAddr};
 use crate::config::SvsmConfig;
 use crate::cpu::percpu::PERCPU_VMSAS;
 use crate::error::SvsmError;
 use crate::locking::RWLock;
-use crate::utils::MemoryRegion;
+use crate::mm::alloc::AllocError;
+use crate::mm::PageBox;
+use crate::sev::vmsa::VMPL_MAX;
+use crate::types::{PageSize, PAGE_SIZE};
+use crate::utils::{align_down, MemoryRegion, TryVec};
 use alloc::vec::Vec;
 use bootlib::kernel_launch::KernelLaunchInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use super::pagetable::LAUNCH_VMSA_ADDR;
+use super::SIZE_1G;
 
 /// Global memory map containing various memory regions.
 static MEMORY_MAP: RWLock<Vec<MemoryRegion<PhysAddr>>> = RWLock::new(Vec::new());
 
+/// Guest memory which is not online yet. It is reported to the guest like
+/// all other guest memory, but the SVSM only accepts it once the guest
+/// first validates it, see [`online_deferred_at()`].
+static DEFERRED_MEMORY: RWLock<Vec<MemoryRegion<PhysAddr>>> = RWLock::new(Vec::new());
+
+/// Guest memory which was validated through the SVSM and not invalidated
+/// since. Only tracked if enabled with [`enable_validation_tracking()`].
+static GUEST_VALIDATED: RWLock<ValidationBitmap> = RWLock::new(ValidationBitmap::new());
+static VALIDATION_TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// Guest memory assigned to a single lower VMPL, sorted by address. Guest
+/// memory which is not assigned is available to all lower VMPLs.
+static VMPL_REGIONS: RWLock<Vec<VmplRegion>> = RWLock::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct VmplRegion {
+    region: MemoryRegion<PhysAddr>,
+    vmpl: usize,
+}
+
+/// Deferred memory is brought online on first use in naturally aligned
+/// chunks of this size.
+const DEFERRED_CHUNK_SIZE: usize = SIZE_1G;
+
+/// NUMA nodes of physical memory ranges and of CPUs. Empty if the NUMA
+/// topology is unknown, in which case all memory is treated as local.
+static NUMA_MAP: RWLock<ACPINumaInfo> = RWLock::new(ACPINumaInfo::new());
+
 /// Initializes the global memory map based on the provided configuration
 /// and kernel launch information.
 ///
@@ -44,11 +79,45 @@ pub fn init_memory_map(
     let kernel_region = MemoryRegion::from_addresses(kernel_start, kernel_end);
 
     // Remove SVSM memory from guest memory map
+    exclude_region(&mut regions, kernel_region);
+    for region in config.get_extra_svsm_regions()? {
+        exclude_region(&mut regions, region);
+    }
+
+    let deferred = match config.deferred_memory_base() {
+        Some(base) => split_deferred(&mut regions, base.page_align_up()),
+        None => Vec::new(),
+    };
+
+    log::info!("Guest Memory Regions:");
+    for r in regions.iter() {
+        log::info!("  {:018x}-{:018x}", r.start(), r.end());
+    }
+    if !deferred.is_empty() {
+        log::info!("Deferred Guest Memory Regions:");
+        for r in deferred.iter() {
+            log::info!("  {:018x}-{:018x}", r.start(), r.end());
+        }
+    }
+
+    *DEFERRED_MEMORY.lock_write() = deferred;
+    let mut map = MEMORY_MAP.lock_write();
+    *map = regions;
+
+    if let Some(numa) = config.load_numa_info() {
+        init_numa_map(numa);
+    }
+
+    Ok(())
+}
+
+/// Removes the memory in `excluded` from the memory ranges in `regions`.
+fn exclude_region(regions: &mut Vec<MemoryRegion<PhysAddr>>, excluded: MemoryRegion<PhysAddr>) {
     let mut i = 0;
     while i < regions.len() {
-        // Check if the region overlaps with SVSM memory.
+        // Check if the region overlaps with excluded memory.
         let region = regions[i];
-        if !region.overlap(&kernel_region) {
+        if !region.overlap(&excluded) {
             // Check the next region.
             i += 1;
             continue;
@@ -57,9 +126,9 @@ pub fn init_memory_map(
         // 1. Remove the region.
         regions.remove(i);
 
-        // 2. Insert a region up until the start of SVSM memory (if non-empty).
+        // 2. Insert a region up until the start of excluded memory (if non-empty).
         let region_before_start = region.start();
-        let region_before_end = kernel_region.start();
+        let region_before_end = excluded.start();
         if region_before_start < region_before_end {
             regions.insert(
                 i,
@@ -68,8 +137,8 @@ pub fn init_memory_map(
             i += 1;
         }
 
-        // 3. Insert a region up after the end of SVSM memory (if non-empty).
-        let region_after_start = kernel_region.end();
+        // 3. Insert a region up after the end of excluded memory (if non-empty).
+        let region_after_start = excluded.end();
         let region_after_end = region.end();
         if region_after_start < region_after_end {
             regions.insert(
@@ -79,25 +148,317 @@ pub fn init_memory_map(
             i += 1;
         }
     }
+}
 
-    log::info!("Guest Memory Regions:");
-    for r in regions.iter() {
-        log::info!("  {:018x}-{:018x}", r.start(), r.end());
+/// Removes the memory at and above `base` from `regions` and returns it.
+fn split_deferred(
+    regions: &mut Vec<MemoryRegion<PhysAddr>>,
+    base: PhysAddr,
+) -> Vec<MemoryRegion<PhysAddr>> {
+    let deferred = MemoryRegion::from_addresses(base, PhysAddr::from(u64::MAX).page_align());
+    take_region(regions, deferred)
+}
+
+/// Removes the memory in `taken` from `regions` and returns the parts of
+/// `regions` which overlapped with it.
+fn take_region(
+    regions: &mut Vec<MemoryRegion<PhysAddr>>,
+    taken: MemoryRegion<PhysAddr>,
+) -> Vec<MemoryRegion<PhysAddr>> {
+    let parts = regions
+        .iter()
+        .filter(|region| region.overlap(&taken))
+        .map(|region| {
+            MemoryRegion::from_addresses(
+                region.start().max(taken.start()),
+                region.end().min(taken.end()),
+            )
+        })
+        .collect();
+    exclude_region(regions, taken);
+    parts
+}
+
+/// Brings the deferred guest memory within `region` online, so that it is
+/// accepted as guest memory from then on. If memory runs out, the memory
+/// stays deferred.
+fn online_memory(region: MemoryRegion<PhysAddr>) -> Result<(), SvsmError> {
+    let mut deferred = DEFERRED_MEMORY.lock_write();
+    let parts = deferred
+        .iter()
+        .filter(|deferred| deferred.overlap(&region))
+        .count();
+    if parts == 0 {
+        return Ok(());
     }
 
+    // Make room in both lists before changing either of them. Taking the
+    // region out of the middle of a deferred region splits it in two.
     let mut map = MEMORY_MAP.lock_write();
-    *map = regions;
+    let oom = |_| SvsmError::Alloc(AllocError::OutOfMemory);
+    map.try_reserve(parts).map_err(oom)?;
+    deferred.try_reserve(1).map_err(oom)?;
 
+    for part in deferred.iter().filter(|deferred| deferred.overlap(&region)) {
+        let part = MemoryRegion::from_addresses(
+            part.start().max(region.start()),
+            part.end().min(region.end()),
+        );
+        log::info!(
+            "Guest memory {:018x}-{:018x} online",
+            part.start(),
+            part.end()
+        );
+        map.push(part);
+    }
+    exclude_region(&mut deferred, region);
+    map.sort_unstable_by_key(|region| region.start());
     Ok(())
 }
 
+/// Brings the chunk of deferred memory containing `paddr` online, if there
+/// is one. Returns whether `paddr` was deferred. Requests through which the
+/// guest starts using memory call this before checking the address with
+/// [`valid_phys_address()`], which never brings memory online itself.
+pub fn online_deferred_at(paddr: PhysAddr) -> Result<bool, SvsmError> {
+    if !DEFERRED_MEMORY
+        .lock_read()
+        .iter()
+        .any(|region| region.contains(paddr))
+    {
+        return Ok(false);
+    }
+    let chunk = MemoryRegion::new(
+        PhysAddr::from(align_down(paddr.bits(), DEFERRED_CHUNK_SIZE)),
+        DEFERRED_CHUNK_SIZE,
+    );
+    online_memory(chunk)?;
+    Ok(true)
+}
+
+/// Number of pages covered by a chunk of a [`ValidationBitmap`]
+const VALIDATION_CHUNK_PAGES: usize = SIZE_1G / PAGE_SIZE;
+
+/// One bit per 4K guest page, set if the page was validated through the
+/// SVSM. The bitmap is kept in chunks covering 1G of guest memory each,
+/// which are allocated when the first page in them is validated, so its
+/// size is bounded by the amount of guest memory.
+#[derive(Debug)]
+struct ValidationBitmap {
+    chunks: TryVec<Option<PageBox<[u64]>>>,
+}
+
+impl ValidationBitmap {
+    const fn new() -> Self {
+        Self {
+            chunks: TryVec::new(),
+        }
+    }
+
+    /// Chunk index, word index and bit mask of the page at `paddr`
+    fn position(paddr: PhysAddr) -> (usize, usize, u64) {
+        let pfn = paddr.pfn();
+        let bit = pfn % VALIDATION_CHUNK_PAGES;
+        (pfn / VALIDATION_CHUNK_PAGES, bit / 64, 1 << (bit % 64))
+    }
+
+    fn get(&self, paddr: PhysAddr) -> bool {
+        let (chunk, word, mask) = Self::position(paddr);
+        self.chunks
+            .get(chunk)
+            .and_then(Option::as_ref)
+            .is_some_and(|bits| bits[word] & mask != 0)
+    }
+
+    /// Sets the bits of the pages in `region` to `valid`. The bitmap is left
+    /// unchanged if a chunk cannot be allocated.
+    fn set(&mut self, region: MemoryRegion<PhysAddr>, valid: bool) -> Result<(), SvsmError> {
+        if region.is_empty() {
+            return Ok(());
+        }
+        if valid {
+            let first = Self::position(region.start()).0;
+            let last = Self::position(region.end() - 1).0;
+            while self.chunks.len() <= last {
+                self.chunks.try_push(None)?;
+            }
+            for chunk in self.chunks[first..=last].iter_mut() {
+                if chunk.is_none() {
+                    // SAFETY: all bit patterns are valid u64 values.
+                    *chunk = Some(unsafe {
+                        PageBox::try_new_zeroed_slice(VALIDATION_CHUNK_PAGES / 64)?
+                    });
+                }
+            }
+        }
+        for paddr in region.iter_pages(PageSize::Regular) {
+            let (chunk, word, mask) = Self::position(paddr);
+            // Chunks were allocated above if pages are marked valid, and
+            // missing chunks have no valid pages to clear.
+            if let Some(bits) = self.chunks.get_mut(chunk).and_then(Option::as_mut) {
+                if valid {
+                    bits[word] |= mask;
+                } else {
+                    bits[word] &= !mask;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts tracking which guest memory is validated through the SVSM, for
+/// [`guest_memory_state()`]. This is only needed to answer memory state
+/// queries, and must be enabled before guest memory is validated.
+pub fn enable_validation_tracking() {
+    VALIDATION_TRACKING.store(true, Ordering::Relaxed);
+}
+
+/// Records that the guest memory in `region` was validated (`valid`) or
+/// invalidated through the SVSM, see [`guest_memory_state()`]. Does nothing
+/// unless tracking was enabled with [`enable_validation_tracking()`].
+pub fn record_guest_validation(
+    region: MemoryRegion<PhysAddr>,
+    valid: bool,
+) -> Result<(), SvsmError> {
+    if !VALIDATION_TRACKING.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    GUEST_VALIDATED.lock_write().set(region, valid)
+}
+
+/// State of a guest page as seen by the SVSM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GuestMemoryState {
+    /// Not guest memory, e.g. memory of the SVSM itself
+    Invalid = 0,
+    /// Guest memory which was not validated through the SVSM. It is either
+    /// shared with the host or not accepted by the guest yet. Without
+    /// validation tracking, all guest memory is reported like this.
+    Unvalidated = 1,
+    /// Private guest memory validated through the SVSM
+    Validated = 2,
+    /// A VMSA of a guest CPU
+    Vmsa = 3,
+    /// Deferred guest memory which is not online yet
+    Deferred = 4,
+}
+
+/// Returns the state of the guest page at `paddr` as far as the SVSM knows.
+/// Deferred memory is reported as such and not brought online. The actual state can differ if the host changed it behind the
+/// back of the guest.
+pub fn guest_memory_state(paddr: PhysAddr) -> GuestMemoryState {
+    let page_addr = paddr.page_align();
+    let contains = |regions: &[MemoryRegion<PhysAddr>]| regions.iter().any(|r| r.contains(paddr));
+
+    if PERCPU_VMSAS.exists(page_addr) || page_addr == LAUNCH_VMSA_ADDR {
+        GuestMemoryState::Vmsa
+    } else if GUEST_VALIDATED.lock_read().get(page_addr) {
+        GuestMemoryState::Validated
+    } else if contains(&MEMORY_MAP.lock_read()) {
+        GuestMemoryState::Unvalidated
+    } else if contains(&DEFERRED_MEMORY.lock_read()) {
+        GuestMemoryState::Deferred
+    } else {
+        GuestMemoryState::Invalid
+    }
+}
+
+fn init_numa_map(numa: ACPINumaInfo) {
+    log::info!("NUMA Memory Ranges:");
+    for r in numa.memory.iter() {
+        log::info!(
+            "  {:018x}-{:018x} node {}",
+            r.region.start(),
+            r.region.end(),
+            r.node
+        );
+    }
+    *NUMA_MAP.lock_write() = numa;
+}
+
+/// Returns the NUMA node of the CPU with the given APIC ID, if known.
+pub fn cpu_numa_node(apic_id: u32) -> Option<u32> {
+    NUMA_MAP
+        .lock_read()
+        .cpus
+        .iter()
+        .find(|cpu| cpu.apic_id == apic_id)
+        .map(|cpu| cpu.node)
+}
+
+/// Returns the NUMA node of the physical address `paddr`, if known.
+pub fn phys_numa_node(paddr: PhysAddr) -> Option<u32> {
+    NUMA_MAP
+        .lock_read()
+        .memory
+        .iter()
+        .find(|r| r.region.contains(paddr))
+        .map(|r| r.node)
+}
+
+/// Calls `f` for each physical memory range of NUMA node `node` until it
+/// returns `Some`, and returns that value.
+pub fn find_numa_range<T>(
+    node: u32,
+    mut f: impl FnMut(MemoryRegion<PhysAddr>) -> Option<T>,
+) -> Option<T> {
+    NUMA_MAP
+        .lock_read()
+        .memory
+        .iter()
+        .filter(|r| r.node == node)
+        .find_map(|r| f(r.region))
+}
+
+/// Merges `online` and `deferred` into a single sorted list of regions,
+/// coalescing regions which touch.
+fn merge_regions(
+    online: &[MemoryRegion<PhysAddr>],
+    deferred: &[MemoryRegion<PhysAddr>],
+) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+    let mut all = Vec::new();
+    all.try_reserve(online.len() + deferred.len())
+        .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+    all.extend_from_slice(online);
+    all.extend_from_slice(deferred);
+    all.sort_unstable_by_key(|region| region.start());
+
+    let mut merged: Vec<MemoryRegion<PhysAddr>> = Vec::new();
+    merged
+        .try_reserve(all.len())
+        .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+    for region in all {
+        match merged.last_mut() {
+            Some(last) if last.contiguous(&region) => *last = last.merge(&region),
+            _ => merged.push(region),
+        }
+    }
+    Ok(merged)
+}
+
 pub fn write_guest_memory_map(config: &SvsmConfig<'_>) -> Result<(), SvsmError> {
     // Supply the memory map to the guest if required by the configuration.
-    config.write_guest_memory_map(&MEMORY_MAP.lock_read())
+    // Deferred memory is part of it, the guest brings it online by using it.
+    let map = merge_regions(&MEMORY_MAP.lock_read(), &DEFERRED_MEMORY.lock_read())?;
+    config.write_guest_memory_map(&map)
+}
+
+/// Returns a copy of the online guest memory map, without deferred memory
+pub fn guest_memory_regions() -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+    let map = MEMORY_MAP.lock_read();
+    let mut regions = Vec::new();
+    regions
+        .try_reserve(map.len())
+        .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+    regions.extend_from_slice(&map);
+    Ok(regions)
 }
 
 /// Returns `true` if the provided physical address `paddr` is valid, i.e.
 /// it is within the configured memory regions, otherwise returns `false`.
+/// Deferred memory is not valid until it is brought online.
 pub fn valid_phys_address(paddr: PhysAddr) -> bool {
     let page_addr = paddr.page_align();
 
@@ -114,6 +475,104 @@ pub fn valid_phys_address(paddr: PhysAddr) -> bool {
         .any(|region| region.contains(paddr))
 }
 
+/// Returns `true` if `paddr` is valid, see [`valid_phys_address()`], and
+/// not assigned to a VMPL other than `vmpl`. Protocol requests must check
+/// the addresses they access on behalf of a VMPL with this function.
+pub fn valid_phys_address_for(paddr: PhysAddr, vmpl: usize) -> bool {
+    valid_phys_address(paddr) && phys_address_owner(paddr).is_none_or(|owner| owner == vmpl)
+}
+
+/// Returns the VMPL the guest memory at `paddr` is assigned to, or `None`
+/// if it is available to all lower VMPLs.
+pub fn phys_address_owner(paddr: PhysAddr) -> Option<usize> {
+    VMPL_REGIONS
+        .lock_read()
+        .iter()
+        .find(|r| r.region.contains(paddr))
+        .map(|r| r.vmpl)
+}
+
+/// Returns `true` if no memory in `region` is assigned to a VMPL other
+/// than `vmpl`.
+pub fn phys_region_accessible_by(region: MemoryRegion<PhysAddr>, vmpl: usize) -> bool {
+    !VMPL_REGIONS
+        .lock_read()
+        .iter()
+        .any(|r| r.vmpl != vmpl && r.region.overlap(&region))
+}
+
+/// Replaces the assignments of the memory in `region` with `vmpl`, or
+/// removes them if `vmpl` is `None`.
+fn assign_region(
+    regions: &mut Vec<VmplRegion>,
+    region: MemoryRegion<PhysAddr>,
+    vmpl: Option<usize>,
+) {
+    let mut i = 0;
+    while i < regions.len() {
+        let r = regions[i];
+        if !r.region.overlap(&region) {
+            i += 1;
+            continue;
+        }
+        regions.remove(i);
+        let parts = [
+            (r.region.start(), region.start()),
+            (region.end(), r.region.end()),
+        ];
+        for (start, end) in parts {
+            if start < end {
+                let region = MemoryRegion::from_addresses(start, end);
+                regions.insert(
+                    i,
+                    VmplRegion {
+                        region,
+                        vmpl: r.vmpl,
+                    },
+                );
+                i += 1;
+            }
+        }
+    }
+    if let Some(vmpl) = vmpl {
+        let i = regions.partition_point(|r| r.region.start() < region.start());
+        regions.insert(i, VmplRegion { region, vmpl });
+    }
+}
+
+/// Assigns the guest memory in `region` to the lower VMPL `vmpl`, so that
+/// protocol requests of other VMPLs cannot reference it, or makes it
+/// available to all lower VMPLs again if `vmpl` is `None`.
+///
+/// # Returns
+///
+/// `Err(SvsmError::InvalidAddress)` if `region` is not entirely guest
+/// memory, `Err(SvsmError::NotSupported)` if `vmpl` is not a lower VMPL.
+pub fn assign_phys_region(
+    region: MemoryRegion<PhysAddr>,
+    vmpl: Option<usize>,
+) -> Result<(), SvsmError> {
+    if vmpl.is_some_and(|vmpl| vmpl == 0 || vmpl >= VMPL_MAX) {
+        return Err(SvsmError::NotSupported);
+    }
+    if !MEMORY_MAP
+        .lock_read()
+        .iter()
+        .any(|r| r.contains_region(&region))
+    {
+        return Err(SvsmError::InvalidAddress);
+    }
+
+    let mut regions = VMPL_REGIONS.lock_write();
+    // Splitting an assignment adds at most one entry, and the new
+    // assignment one more.
+    regions
+        .try_reserve(2)
+        .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+    assign_region(&mut regions, region, vmpl);
+    Ok(())
+}
+
 /// The starting address of the ISA range.
 const ISA_RANGE_START: PhysAddr = PhysAddr::new(0xa0000);
 
@@ -135,6 +594,195 @@ pub fn writable_phys_addr(paddr: PhysAddr) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+    fn test_numa_map() {
+        use crate::acpi::tables::{ACPICPUAffinity, ACPIMemoryAffinity};
+
+        let region = MemoryRegion::new(PhysAddr::new(0x4000_0000), 0x4000_0000);
+        init_numa_map(ACPINumaInfo {
+            memory: alloc::vec![ACPIMemoryAffinity { node: 1, region }],
+            cpus: alloc::vec![ACPICPUAffinity {
+                apic_id: 2,
+                node: 1
+            }],
+        });
+
+        assert_eq!(cpu_numa_node(2), Some(1));
+        assert_eq!(cpu_numa_node(0), None);
+        assert_eq!(phys_numa_node(PhysAddr::new(0x5000_0000)), Some(1));
+        assert_eq!(phys_numa_node(PhysAddr::new(0x1000)), None);
+        assert_eq!(find_numa_range(1, Some), Some(region));
+        assert_eq!(find_numa_range(0, Some), None);
+    }
+
+    #[test]
+    fn test_exclude_region() {
+        let mut regions = alloc::vec![
+            MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+            MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x8000_0000)),
+        ];
+        let svsm =
+            MemoryRegion::from_addresses(PhysAddr::new(0x2000_0000), PhysAddr::new(0x3000_0000));
+        exclude_region(&mut regions, svsm);
+        let extra =
+            MemoryRegion::from_addresses(PhysAddr::new(0x7000_0000), PhysAddr::new(0x8000_0000));
+        exclude_region(&mut regions, extra);
+
+        assert_eq!(
+            regions,
+            alloc::vec![
+                MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+                MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x2000_0000)),
+                MemoryRegion::from_addresses(
+                    PhysAddr::new(0x3000_0000),
+                    PhysAddr::new(0x7000_0000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_deferred() {
+        let mut regions = alloc::vec![
+            MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+            MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x8000_0000)),
+            MemoryRegion::from_addresses(
+                PhysAddr::new(0x1_0000_0000),
+                PhysAddr::new(0x2_0000_0000)
+            ),
+        ];
+        let deferred = split_deferred(&mut regions, PhysAddr::new(0x4000_0000));
+        assert_eq!(
+            regions,
+            alloc::vec![
+                MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+                MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x4000_0000)),
+            ]
+        );
+        assert_eq!(
+            deferred,
+            alloc::vec![
+                MemoryRegion::from_addresses(
+                    PhysAddr::new(0x4000_0000),
+                    PhysAddr::new(0x8000_0000)
+                ),
+                MemoryRegion::from_addresses(
+                    PhysAddr::new(0x1_0000_0000),
+                    PhysAddr::new(0x2_0000_0000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let online = [
+            MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+            MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x4000_0000)),
+        ];
+        let deferred = [
+            MemoryRegion::from_addresses(PhysAddr::new(0x4000_0000), PhysAddr::new(0x8000_0000)),
+            MemoryRegion::from_addresses(
+                PhysAddr::new(0x1_0000_0000),
+                PhysAddr::new(0x2_0000_0000),
+            ),
+        ];
+        assert_eq!(
+            merge_regions(&online, &deferred).unwrap(),
+            alloc::vec![
+                MemoryRegion::from_addresses(PhysAddr::new(0), PhysAddr::new(0xa_0000)),
+                MemoryRegion::from_addresses(PhysAddr::new(0x10_0000), PhysAddr::new(0x8000_0000)),
+                MemoryRegion::from_addresses(
+                    PhysAddr::new(0x1_0000_0000),
+                    PhysAddr::new(0x2_0000_0000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_take_region() {
+        let mut regions = alloc::vec![MemoryRegion::from_addresses(
+            PhysAddr::new(0x1_0000_0000),
+            PhysAddr::new(0x2_0000_0000)
+        )];
+        let chunk = MemoryRegion::new(PhysAddr::new(0x1_4000_0000), 0x4000_0000);
+        assert_eq!(take_region(&mut regions, chunk), alloc::vec![chunk]);
+        assert_eq!(regions.len(), 2);
+        // Memory is only taken once
+        assert!(take_region(&mut regions, chunk).is_empty());
+        assert_eq!(
+            regions.iter().map(MemoryRegion::len).sum::<usize>(),
+            0xc000_0000
+        );
+    }
+
+    #[test]
+    #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+    fn test_validation_bitmap() {
+        use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let page = |n: usize| PhysAddr::new(n * PAGE_SIZE);
+        let mut bitmap = ValidationBitmap::new();
+        assert!(!bitmap.get(page(0)));
+
+        // Only the chunks with validated pages are allocated
+        let region = MemoryRegion::new(page(VALIDATION_CHUNK_PAGES - 1), 2 * PAGE_SIZE);
+        bitmap.set(region, true).unwrap();
+        assert_eq!(bitmap.chunks.len(), 2);
+        assert!(bitmap.chunks.iter().all(Option::is_some));
+        assert!(!bitmap.get(page(VALIDATION_CHUNK_PAGES - 2)));
+        assert!(bitmap.get(page(VALIDATION_CHUNK_PAGES - 1)));
+        assert!(bitmap.get(page(VALIDATION_CHUNK_PAGES)));
+        assert!(!bitmap.get(page(VALIDATION_CHUNK_PAGES + 1)));
+
+        let second = MemoryRegion::new(page(VALIDATION_CHUNK_PAGES), PAGE_SIZE);
+        bitmap.set(second, false).unwrap();
+        assert!(bitmap.get(page(VALIDATION_CHUNK_PAGES - 1)));
+        assert!(!bitmap.get(page(VALIDATION_CHUNK_PAGES)));
+
+        // Invalidating untracked memory allocates nothing
+        let far = MemoryRegion::new(page(4 * VALIDATION_CHUNK_PAGES), PAGE_SIZE);
+        bitmap.set(far, false).unwrap();
+        assert_eq!(bitmap.chunks.len(), 2);
+    }
+
+    #[test]
+    fn test_assign_region() {
+        let range = |start: usize, end: usize| {
+            MemoryRegion::from_addresses(PhysAddr::new(start), PhysAddr::new(end))
+        };
+        let mut regions = alloc::vec::Vec::new();
+        assign_region(&mut regions, range(0x1000, 0x9000), Some(1));
+        assign_region(&mut regions, range(0x3000, 0x4000), Some(2));
+        assign_region(&mut regions, range(0x6000, 0x7000), None);
+        assert_eq!(
+            regions,
+            alloc::vec![
+                VmplRegion {
+                    region: range(0x1000, 0x3000),
+                    vmpl: 1
+                },
+                VmplRegion {
+                    region: range(0x3000, 0x4000),
+                    vmpl: 2
+                },
+                VmplRegion {
+                    region: range(0x4000, 0x6000),
+                    vmpl: 1
+                },
+                VmplRegion {
+                    region: range(0x7000, 0x9000),
+                    vmpl: 1
+                },
+            ]
+        );
+        assign_region(&mut regions, range(0, 0x10000), None);
+        assert!(regions.is_empty());
+    }
+
     #[test]
     #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
     fn test_valid_phys_address() {
@@ -149,4 +797,22 @@ mod tests {
         // Outside the region
         assert!(!valid_phys_address(PhysAddr::new(0x3000)));
     }
+
+    #[test]
+    #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+    fn test_online_deferred() {
+        let base = PhysAddr::from(0x100_0000_0000u64);
+        let deferred = MemoryRegion::new(base, 2 * DEFERRED_CHUNK_SIZE);
+        DEFERRED_MEMORY.lock_write().push(deferred);
+
+        let paddr = base + DEFERRED_CHUNK_SIZE + 0x1000;
+        assert!(!valid_phys_address(paddr));
+        assert_eq!(guest_memory_state(paddr), GuestMemoryState::Deferred);
+
+        // Only the chunk containing the address is brought online
+        assert!(online_deferred_at(paddr).unwrap());
+        assert!(valid_phys_address(paddr));
+        assert!(!valid_phys_address(base));
+        assert!(!online_deferred_at(paddr).unwrap());
+    }
 }
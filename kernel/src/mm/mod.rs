@@ -4,26 +4,41 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod access;
 pub mod address_space;
 pub mod alloc;
+pub mod encryption;
+pub mod guest_ref;
 pub mod guestmem;
 pub mod mappings;
 pub mod memory;
+pub mod object_cache;
 pub mod page_visibility;
+pub mod pagebox;
 pub mod pagetable;
+#[cfg(feature = "page-poison")]
+pub mod poison;
+pub mod pool;
 pub mod ptguards;
+#[cfg(feature = "heap-redzones")]
+pub mod redzone;
+pub mod shared_pool;
 pub mod stack;
 pub mod validate;
 pub mod virtualrange;
 pub mod vm;
 
 pub use address_space::*;
-pub use guestmem::GuestPtr;
-pub use memory::{valid_phys_address, writable_phys_addr};
+pub use guestmem::{GuestPtr, GuestWriter, UserPtr};
+pub use memory::{
+    online_deferred_at, phys_region_accessible_by, valid_phys_address, valid_phys_address_for,
+    writable_phys_addr,
+};
 pub use ptguards::*;
 
 pub use pagetable::PageTablePart;
 
 pub use alloc::{allocate_file_page, allocate_file_page_ref, PageRef};
+pub use pagebox::{PageBox, PageSlice};
 
 pub use mappings::{mmap_kernel, mmap_user, munmap_kernel, munmap_user, VMMappingGuard};
@@ -0,0 +1,473 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Caches for small fixed-size kernel objects.
+//!
+//! An [`ObjectCache`] hands out objects of a single type from pages of its
+//! own instead of the general heap, so that frequently allocated objects do
+//! not compete with other allocations for slab space and do not fragment
+//! it. Each page starts with a small header, followed by as many object
+//! slots as fit.
+//!
+//! New objects are built with the constructor of the cache. When a
+//! [`CachedObject`] is dropped, the object is not dropped but kept
+//! constructed, up to [`MAX_CACHED_OBJECTS`] of them, and the next
+//! [`ObjectCache::alloc()`] hands it out again without running the
+//! constructor. Users must therefore return objects in a state that is
+//! valid for reuse, or allocate with [`ObjectCache::alloc_with()`], which
+//! always sets a fresh value.
+//!
+//! Constructors and destructors run without the lock of the cache held, so
+//! they may allocate from the same cache or take locks of their own.
+
+use crate::address::{Address, VirtAddr};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::alloc::{allocate_page, free_page};
+use crate::types::PAGE_SIZE;
+
+use core::fmt;
+use core::mem::{self, align_of, size_of, ManuallyDrop};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// Number of freed objects a cache keeps constructed for reuse
+pub const MAX_CACHED_OBJECTS: usize = 16;
+
+/// Header at the start of every page of a cache
+#[derive(Debug)]
+struct PageHeader {
+    /// Slots holding an allocated or a cached object
+    live: usize,
+    /// Empty slots removed from the free list while shrinking the cache
+    unlinked: usize,
+}
+
+/// An object slot, linking to the next empty slot while it is empty
+union Slot<T> {
+    next: Option<NonNull<Slot<T>>>,
+    obj: ManuallyDrop<T>,
+}
+
+/// Allocation statistics of an [`ObjectCache`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of allocations
+    pub allocs: u64,
+    /// Number of allocations which reused a cached object
+    pub reused: u64,
+    /// Number of objects currently allocated
+    pub in_use: usize,
+    /// Number of freed objects kept constructed
+    pub cached: usize,
+    /// Number of pages owned by the cache
+    pub pages: usize,
+}
+
+#[derive(Debug)]
+struct CacheInner<T> {
+    /// Singly-linked list of empty slots
+    empty: Option<NonNull<Slot<T>>>,
+    /// Freed objects which are still constructed
+    cached: [Option<NonNull<Slot<T>>>; MAX_CACHED_OBJECTS],
+    stats: CacheStats,
+}
+
+// SAFETY: the inner state only refers to slots owned by the cache, and the
+// objects in them may be moved between threads if `T` can.
+unsafe impl<T: Send> Send for CacheInner<T> {}
+
+/// A cache of objects of type `T`, see the [module documentation](self)
+pub struct ObjectCache<T> {
+    name: &'static str,
+    ctor: fn() -> T,
+    inner: SpinLock<CacheInner<T>>,
+}
+
+impl<T> ObjectCache<T> {
+    /// Offset of the first slot in a page
+    const SLOTS_OFFSET: usize = size_of::<PageHeader>().next_multiple_of(align_of::<Slot<T>>());
+
+    /// Number of slots in a page
+    const SLOTS_PER_PAGE: usize =
+        PAGE_SIZE.saturating_sub(Self::SLOTS_OFFSET) / size_of::<Slot<T>>();
+
+    /// Creates an empty cache. `ctor` builds new objects for
+    /// [`alloc()`](Self::alloc).
+    pub const fn new(name: &'static str, ctor: fn() -> T) -> Self {
+        Self {
+            name,
+            ctor,
+            inner: SpinLock::new(CacheInner {
+                empty: None,
+                cached: [None; MAX_CACHED_OBJECTS],
+                stats: CacheStats {
+                    allocs: 0,
+                    reused: 0,
+                    in_use: 0,
+                    cached: 0,
+                    pages: 0,
+                },
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats
+    }
+
+    fn page_header(slot: NonNull<Slot<T>>) -> NonNull<PageHeader> {
+        let page = VirtAddr::from(slot.as_ptr()).page_align();
+        NonNull::new(page.as_mut_ptr::<PageHeader>()).unwrap()
+    }
+
+    /// Adds a new page to the cache and links its slots into the empty
+    /// list.
+    fn grow(&self, inner: &mut CacheInner<T>) -> Result<(), SvsmError> {
+        if Self::SLOTS_PER_PAGE == 0 || align_of::<Slot<T>>() > PAGE_SIZE {
+            return Err(SvsmError::NotSupported);
+        }
+        let page = allocate_page()?;
+        // SAFETY: the page was just allocated and starts with room for the
+        // header, which is suitably aligned as the page is.
+        unsafe {
+            page.as_mut_ptr::<PageHeader>().write(PageHeader {
+                live: 0,
+                unlinked: 0,
+            })
+        };
+        let first = page + Self::SLOTS_OFFSET;
+        for i in (0..Self::SLOTS_PER_PAGE).rev() {
+            let slot = (first + i * size_of::<Slot<T>>()).as_mut_ptr::<Slot<T>>();
+            // SAFETY: the slot is within the new page and aligned.
+            unsafe { slot.write(Slot { next: inner.empty }) };
+            inner.empty = NonNull::new(slot);
+        }
+        inner.stats.pages += 1;
+        Ok(())
+    }
+
+    /// Takes an empty slot, growing the cache if there is none.
+    fn take_empty(&self, inner: &mut CacheInner<T>) -> Result<NonNull<Slot<T>>, SvsmError> {
+        if inner.empty.is_none() {
+            self.grow(inner)?;
+        }
+        let slot = inner.empty.unwrap();
+        // SAFETY: slots in the empty list hold a link.
+        inner.empty = unsafe { slot.as_ref().next };
+        // SAFETY: every slot is in a page with a header.
+        unsafe { Self::page_header(slot).as_mut().live += 1 };
+        Ok(slot)
+    }
+
+    fn take_cached(inner: &mut CacheInner<T>) -> Option<NonNull<Slot<T>>> {
+        let slot = inner.cached.iter_mut().find_map(Option::take)?;
+        inner.stats.cached -= 1;
+        Some(slot)
+    }
+
+    /// Takes a cached object or an empty slot. Returns whether the slot
+    /// holds a cached object. The slot is counted as live either way, so
+    /// its page stays while the lock is dropped.
+    fn take_slot(&self) -> Result<(NonNull<Slot<T>>, bool), SvsmError> {
+        let mut inner = self.inner.lock();
+        let cached = Self::take_cached(&mut inner);
+        let slot = match cached {
+            Some(slot) => slot,
+            None => self.take_empty(&mut inner)?,
+        };
+        inner.stats.allocs += 1;
+        inner.stats.reused += u64::from(cached.is_some());
+        inner.stats.in_use += 1;
+        Ok((slot, cached.is_some()))
+    }
+
+    /// Allocates an object, reusing a cached one if possible and building
+    /// a new one with the constructor of the cache otherwise.
+    pub fn alloc(&self) -> Result<CachedObject<'_, T>, SvsmError> {
+        let (slot, cached) = self.take_slot()?;
+        if !cached {
+            // SAFETY: the slot is empty and exclusively ours.
+            unsafe {
+                slot.as_ptr().write(Slot {
+                    obj: ManuallyDrop::new((self.ctor)()),
+                })
+            };
+        }
+        Ok(CachedObject { slot, cache: self })
+    }
+
+    /// Allocates an object holding `value`. A cached object is reused for
+    /// its memory only, and is dropped.
+    pub fn alloc_with(&self, value: T) -> Result<CachedObject<'_, T>, SvsmError> {
+        let (slot, cached) = self.take_slot()?;
+        if cached {
+            // SAFETY: cached slots hold a constructed object, and this one
+            // is exclusively ours now.
+            unsafe { *(*slot.as_ptr()).obj = value };
+        } else {
+            // SAFETY: the slot is empty and exclusively ours.
+            unsafe {
+                slot.as_ptr().write(Slot {
+                    obj: ManuallyDrop::new(value),
+                })
+            };
+        }
+        Ok(CachedObject { slot, cache: self })
+    }
+
+    /// Puts the empty `slot` back on the empty list.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must be a live slot of this cache whose object was dropped.
+    unsafe fn link_empty(inner: &mut CacheInner<T>, slot: NonNull<Slot<T>>) {
+        // SAFETY: guaranteed by the caller.
+        unsafe {
+            slot.as_ptr().write(Slot { next: inner.empty });
+            Self::page_header(slot).as_mut().live -= 1;
+        }
+        inner.empty = Some(slot);
+    }
+
+    /// Drops the object in `slot` and puts the slot on the empty list. The
+    /// object is dropped without the lock held.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must hold a constructed object which is not used anymore.
+    unsafe fn release(&self, slot: NonNull<Slot<T>>) {
+        // SAFETY: guaranteed by the caller. The slot is still live, so its
+        // page is not freed while the object is dropped.
+        unsafe {
+            ManuallyDrop::drop(&mut (*slot.as_ptr()).obj);
+            Self::link_empty(&mut self.inner.lock(), slot);
+        }
+    }
+
+    fn free(&self, slot: NonNull<Slot<T>>) {
+        {
+            let mut guard = self.inner.lock();
+            let inner = &mut *guard;
+            inner.stats.in_use -= 1;
+            if let Some(entry) = inner.cached.iter_mut().find(|entry| entry.is_none()) {
+                *entry = Some(slot);
+                inner.stats.cached += 1;
+                return;
+            }
+        }
+        // SAFETY: the object was just freed by its owner.
+        unsafe { self.release(slot) };
+    }
+
+    /// Drops all cached objects and returns pages without any objects to
+    /// the page allocator.
+    pub fn shrink(&self) {
+        let cached = {
+            let mut inner = self.inner.lock();
+            inner.stats.cached = 0;
+            mem::replace(&mut inner.cached, [None; MAX_CACHED_OBJECTS])
+        };
+        for slot in cached.into_iter().flatten() {
+            // SAFETY: cached slots hold a constructed object nobody uses.
+            unsafe { self.release(slot) };
+        }
+
+        let mut inner = self.inner.lock();
+
+        // Unlink all empty slots of unused pages, freeing each page when
+        // the last of its slots was unlinked.
+        let mut link = &mut inner.empty;
+        let mut freed = 0;
+        while let Some(slot) = *link {
+            // SAFETY: every slot is in a page with a header, and slots in
+            // the empty list hold a link.
+            unsafe {
+                let mut header = Self::page_header(slot);
+                let header = header.as_mut();
+                if header.live != 0 {
+                    link = &mut (*slot.as_ptr()).next;
+                    continue;
+                }
+                *link = slot.as_ref().next;
+                header.unlinked += 1;
+                if header.unlinked == Self::SLOTS_PER_PAGE {
+                    free_page(VirtAddr::from(slot.as_ptr()).page_align());
+                    freed += 1;
+                }
+            }
+        }
+        inner.stats.pages -= freed;
+    }
+}
+
+impl<T> fmt::Debug for ObjectCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjectCache")
+            .field("name", &self.name)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// An object allocated from an [`ObjectCache`], which goes back to the cache
+/// when dropped
+pub struct CachedObject<'a, T> {
+    slot: NonNull<Slot<T>>,
+    cache: &'a ObjectCache<T>,
+}
+
+impl<T> Deref for CachedObject<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the slot holds a constructed object owned by this handle.
+        unsafe { &(*self.slot.as_ptr()).obj }
+    }
+}
+
+impl<T> DerefMut for CachedObject<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: as above, and the handle is borrowed mutably.
+        unsafe { &mut (*self.slot.as_ptr()).obj }
+    }
+}
+
+impl<T> Drop for CachedObject<'_, T> {
+    fn drop(&mut self) {
+        self.cache.free(self.slot);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachedObject<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// SAFETY: a CachedObject owns its object exclusively, like a Box.
+unsafe impl<T: Send> Send for CachedObject<'_, T> {}
+// SAFETY: a CachedObject only hands out shared references through &self.
+unsafe impl<T: Sync> Sync for CachedObject<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct Obj {
+        data: [u64; 6],
+    }
+
+    fn new_obj() -> Obj {
+        Obj { data: [7; 6] }
+    }
+
+    #[test]
+    fn reuse() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let cache = ObjectCache::new("test", new_obj);
+
+        let mut a = cache.alloc().unwrap();
+        assert_eq!(a.data, [7; 6]);
+        a.data[0] = 1;
+        let addr = VirtAddr::from(&*a as *const Obj);
+        drop(a);
+
+        // The freed object is handed out again as it was
+        let b = cache.alloc().unwrap();
+        assert_eq!(VirtAddr::from(&*b as *const Obj), addr);
+        assert_eq!(b.data[0], 1);
+
+        let c = cache.alloc_with(Obj { data: [3; 6] }).unwrap();
+        assert_eq!(c.data, [3; 6]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.allocs, 3);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.in_use, 2);
+        assert_eq!(stats.pages, 1);
+        drop((b, c));
+        cache.shrink();
+        assert_eq!(cache.stats().pages, 0);
+    }
+
+    #[test]
+    fn grow_and_shrink() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted(#[allow(dead_code)] [u8; 200]);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let cache = ObjectCache::new("counted", || Counted([0; 200]));
+        let per_page = ObjectCache::<Counted>::SLOTS_PER_PAGE;
+        assert!(per_page > 1);
+
+        let mut objs = [(); 64].map(|_| Some(cache.alloc().unwrap()));
+        let pages = cache.stats().pages;
+        assert_eq!(pages, objs.len().div_ceil(per_page));
+
+        // Keep one object alive, so its page stays
+        for obj in objs.iter_mut().skip(1) {
+            *obj = None;
+        }
+        assert_eq!(cache.stats().cached, MAX_CACHED_OBJECTS);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 63 - MAX_CACHED_OBJECTS);
+
+        cache.shrink();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 63);
+        let stats = cache.stats();
+        assert_eq!(stats.pages, 1);
+        assert_eq!(stats.cached, 0);
+        assert_eq!(stats.in_use, 1);
+
+        objs[0] = None;
+        cache.shrink();
+        assert_eq!(cache.stats().pages, 0);
+    }
+
+    #[test]
+    fn ctor_and_drop_unlocked() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        // Constructor and destructor take the lock of their own cache
+        struct Reentrant(u64);
+        impl Drop for Reentrant {
+            fn drop(&mut self) {
+                assert!(CACHE.stats().allocs > 0);
+            }
+        }
+        fn new_reentrant() -> Reentrant {
+            Reentrant(CACHE.stats().allocs)
+        }
+        static CACHE: ObjectCache<Reentrant> = ObjectCache::new("reentrant", new_reentrant);
+
+        let objs = [(); MAX_CACHED_OBJECTS + 1].map(|_| CACHE.alloc().unwrap());
+        assert_eq!(objs[1].0, 2);
+        drop(objs);
+        let obj = CACHE.alloc_with(Reentrant(0)).unwrap();
+        assert_eq!(CACHE.stats().reused, 1);
+        drop(obj);
+        CACHE.shrink();
+        assert_eq!(
+            CACHE.stats(),
+            CacheStats {
+                allocs: MAX_CACHED_OBJECTS as u64 + 2,
+                reused: 1,
+                ..CacheStats::default()
+            }
+        );
+    }
+}
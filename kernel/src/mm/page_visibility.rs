@@ -4,7 +4,7 @@
 //
 // Author: Jon Lange (jlange@microsoft.com)
 
-use crate::address::VirtAddr;
+use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::cpu::flush_tlb_global_sync;
 use crate::cpu::percpu::this_cpu;
 use crate::error::SvsmError;
@@ -16,53 +16,104 @@ use crate::platform::{PageStateChangeOp, SVSM_PLATFORM};
 use crate::types::{PageSize, PAGE_SIZE};
 use crate::utils::MemoryRegion;
 
-pub fn make_page_shared(vaddr: VirtAddr) -> Result<(), SvsmError> {
+/// Returns the physical range backing `region`, which must consist of whole
+/// pages that are contiguous in physical memory.
+fn phys_region(region: MemoryRegion<VirtAddr>) -> Result<MemoryRegion<PhysAddr>, SvsmError> {
+    if region.is_empty() || !region.start().is_page_aligned() || !region.end().is_page_aligned() {
+        return Err(SvsmError::InvalidAddress);
+    }
+    let paddr = virt_to_phys(region.start());
+    let contiguous = region
+        .iter_pages(PageSize::Regular)
+        .enumerate()
+        .all(|(i, vaddr)| virt_to_phys(vaddr) == paddr + i * PAGE_SIZE);
+    if !contiguous {
+        return Err(SvsmError::InvalidAddress);
+    }
+    Ok(MemoryRegion::new(paddr, region.len()))
+}
+
+fn set_valid_bits(pregion: MemoryRegion<PhysAddr>, valid: bool) {
+    for paddr in pregion.iter_pages(PageSize::Regular) {
+        if !valid_bitmap_valid_addr(paddr) {
+            continue;
+        }
+        if valid {
+            valid_bitmap_set_valid_4k(paddr);
+        } else {
+            valid_bitmap_clear_valid_4k(paddr);
+        }
+    }
+}
+
+/// Makes the pages in `region` shared with the host, using a single page
+/// state change request for all of them. The pages must be contiguous in
+/// physical memory. If the host refuses the request, the pages are
+/// validated again and stay private.
+pub fn make_region_shared(region: MemoryRegion<VirtAddr>) -> Result<(), SvsmError> {
+    let pregion = phys_region(region)?;
     let platform = SVSM_PLATFORM.as_dyn_ref();
 
     // Revoke page validation before changing page state.
-    platform.invalidate_page_range(MemoryRegion::new(vaddr, PAGE_SIZE))?;
-    let paddr = virt_to_phys(vaddr);
-    if valid_bitmap_valid_addr(paddr) {
-        valid_bitmap_clear_valid_4k(paddr);
+    platform.invalidate_page_range(region)?;
+    set_valid_bits(pregion, false);
+
+    // Ask the hypervisor to make the pages shared.
+    if let Err(e) =
+        platform.page_state_change(pregion, PageSize::Regular, PageStateChangeOp::Shared)
+    {
+        platform
+            .validate_page_range(region)
+            .expect("Failed to restore page validation");
+        set_valid_bits(pregion, true);
+        return Err(e);
     }
 
-    // Ask the hypervisor to make the page shared.
-    platform.page_state_change(
-        MemoryRegion::new(paddr, PAGE_SIZE),
-        PageSize::Regular,
-        PageStateChangeOp::Shared,
-    )?;
-
-    // Update the page tables to map the page as shared.
-    this_cpu()
-        .get_pgtable()
-        .set_shared_4k(vaddr)
-        .expect("Failed to remap shared page in page tables");
+    // Update the page tables to map the pages as shared.
+    {
+        let mut pgtable = this_cpu().get_pgtable();
+        for vaddr in region.iter_pages(PageSize::Regular) {
+            pgtable
+                .set_shared_4k(vaddr)
+                .expect("Failed to remap shared page in page tables");
+        }
+    }
     flush_tlb_global_sync();
 
     Ok(())
 }
 
-pub fn make_page_private(vaddr: VirtAddr) -> Result<(), SvsmError> {
-    // Update the page tables to map the page as private.
-    this_cpu().get_pgtable().set_encrypted_4k(vaddr)?;
+/// Makes the pages in `region`, which were shared with
+/// [`make_region_shared()`], private again using a single page state change
+/// request.
+pub fn make_region_private(region: MemoryRegion<VirtAddr>) -> Result<(), SvsmError> {
+    let pregion = phys_region(region)?;
+
+    // Update the page tables to map the pages as private.
+    {
+        let mut pgtable = this_cpu().get_pgtable();
+        for vaddr in region.iter_pages(PageSize::Regular) {
+            pgtable.set_encrypted_4k(vaddr)?;
+        }
+    }
     flush_tlb_global_sync();
 
     let platform = SVSM_PLATFORM.as_dyn_ref();
 
-    // Ask the hypervisor to make the page private.
-    let paddr = virt_to_phys(vaddr);
-    platform.page_state_change(
-        MemoryRegion::new(paddr, PAGE_SIZE),
-        PageSize::Regular,
-        PageStateChangeOp::Private,
-    )?;
+    // Ask the hypervisor to make the pages private.
+    platform.page_state_change(pregion, PageSize::Regular, PageStateChangeOp::Private)?;
 
-    // Revoke page validation before changing page state.
-    platform.validate_page_range(MemoryRegion::new(vaddr, PAGE_SIZE))?;
-    if valid_bitmap_valid_addr(paddr) {
-        valid_bitmap_set_valid_4k(paddr);
-    }
+    // Validate the pages after changing page state.
+    platform.validate_page_range(region)?;
+    set_valid_bits(pregion, true);
 
     Ok(())
 }
+
+pub fn make_page_shared(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    make_region_shared(MemoryRegion::new(vaddr, PAGE_SIZE))
+}
+
+pub fn make_page_private(vaddr: VirtAddr) -> Result<(), SvsmError> {
+    make_region_private(MemoryRegion::new(vaddr, PAGE_SIZE))
+}
@@ -0,0 +1,618 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Owned values backed directly by the page allocator.
+//!
+//! A [`PageBox`] is like a `Box`, but its memory always comes from the page
+//! allocator instead of the global heap, and it is always page aligned. This
+//! is needed for values that are shared with the hardware or the host by
+//! their physical address, and allows placement constraints such as 2M
+//! alignment. [`PageBox::try_new_guarded()`] surrounds the value with
+//! non-present guard pages, so that overruns fault instead of corrupting
+//! neighboring memory.
+//!
+//! A byte slice box can be split into several owned [`PageSlice`]s with
+//! [`PageBox::split_at()`], for instance to place a header and a payload
+//! with different alignments in the same page. The pages are freed once
+//! the last part is dropped.
+//!
+//! Boxes holding secrets such as key material or vTPM state should be
+//! created with [`PageBox::try_new_sensitive()`] or marked with
+//! [`PageBox::set_sensitive()`]. Their pages are scrubbed with volatile
+//! writes before they go back to the page allocator.
+
+extern crate alloc;
+
+use crate::address::VirtAddr;
+#[cfg(target_os = "none")]
+use crate::cpu::flush_tlb_global_sync;
+use crate::error::SvsmError;
+use crate::mm::alloc::{
+    allocate_pages, allocate_pages_aligned, free_page, get_order, AllocError, PageAlignment,
+};
+#[cfg(target_os = "none")]
+use crate::mm::pagetable::get_init_pgtable_locked;
+use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::{align_up, zero_mem_region, ByteSize, PageOrder};
+use alloc::sync::Arc;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, ManuallyDrop};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// An owned value of type `T` stored in pages of its own. `T` can also be a
+/// slice, see [`PageBox::try_new_slice()`].
+pub struct PageBox<T: ?Sized> {
+    ptr: NonNull<T>,
+    order: PageOrder,
+    guarded: bool,
+    sensitive: bool,
+    _phantom: PhantomData<T>,
+}
+
+/// Makes the guard pages directly before and after the pages at `vaddr`
+/// present or not present in the direct map.
+#[cfg(target_os = "none")]
+fn set_guards_present(vaddr: VirtAddr, order: PageOrder, present: bool) -> Result<(), SvsmError> {
+    let size = PAGE_SIZE << usize::from(order);
+    let mut pgtable = get_init_pgtable_locked();
+    for guard in [vaddr - PAGE_SIZE, vaddr + size] {
+        pgtable.set_present_4k(guard, present)?;
+    }
+    flush_tlb_global_sync();
+    Ok(())
+}
+
+/// Test builds cannot modify the page tables, so guard pages stay present.
+#[cfg(not(target_os = "none"))]
+fn set_guards_present(
+    _vaddr: VirtAddr,
+    _order: PageOrder,
+    _present: bool,
+) -> Result<(), SvsmError> {
+    Ok(())
+}
+
+/// Allocates pages of the given order with a non-present guard page on
+/// each side.
+fn allocate_guarded(order: PageOrder) -> Result<VirtAddr, SvsmError> {
+    let pages = (1usize << usize::from(order)) + 2;
+    let block_order = get_order(ByteSize::new(pages * PAGE_SIZE))
+        .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))?;
+    let block = allocate_pages(block_order)?;
+    let vaddr = block + PAGE_SIZE;
+    if let Err(e) = set_guards_present(vaddr, order, false) {
+        let _ = set_guards_present(vaddr, order, true);
+        free_page(block);
+        return Err(e);
+    }
+    Ok(vaddr)
+}
+
+fn free_guarded(vaddr: VirtAddr, order: PageOrder) {
+    // The mappings of the guard pages were already split when they were
+    // made non-present, so this cannot fail.
+    set_guards_present(vaddr, order, true).expect("Failed to restore PageBox guard pages");
+    free_page(vaddr - PAGE_SIZE);
+}
+
+/// Overwrites the pages at `vaddr` with zeros. The writes are volatile, so
+/// that they are not optimized away even though the memory is freed right
+/// after.
+fn scrub_pages(vaddr: VirtAddr, order: PageOrder) {
+    let base = vaddr.as_mut_ptr::<u64>();
+    let words = (PAGE_SIZE << usize::from(order)) / size_of::<u64>();
+    for i in 0..words {
+        // SAFETY: the pages are owned by the caller and about to be freed.
+        unsafe { ptr::write_volatile(base.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+fn free_pages(vaddr: VirtAddr, order: PageOrder, guarded: bool, sensitive: bool) {
+    if sensitive {
+        scrub_pages(vaddr, order);
+    }
+    if guarded {
+        free_guarded(vaddr, order);
+    } else {
+        free_page(vaddr);
+    }
+}
+
+impl<T> PageBox<T> {
+    /// Smallest allocation order holding a `T`
+    fn order() -> Result<PageOrder, SvsmError> {
+        if align_of::<T>() > PAGE_SIZE {
+            return Err(SvsmError::NotSupported);
+        }
+        get_order(ByteSize::new(size_of::<T>())).ok_or(SvsmError::Alloc(AllocError::OutOfMemory))
+    }
+
+    /// # Safety
+    ///
+    /// `vaddr` must point to a page allocation of order `order` which is
+    /// large enough for a `T`.
+    unsafe fn write_new(vaddr: VirtAddr, order: PageOrder, x: T) -> Self {
+        let ptr = vaddr.as_mut_ptr::<T>();
+        // SAFETY: the allocation is large enough and suitably aligned, as
+        // it is page aligned and `T` does not need more than that.
+        unsafe { ptr.write(x) };
+        Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            order,
+            guarded: false,
+            sensitive: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Moves `x` into newly allocated pages.
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub fn try_new(x: T) -> Result<Self, SvsmError> {
+        let order = Self::order()?;
+        let vaddr = allocate_pages(order)?;
+        // SAFETY: `vaddr` was just allocated with the order for a `T`.
+        Ok(unsafe { Self::write_new(vaddr, order, x) })
+    }
+
+    /// Moves `x` into newly allocated pages whose physical address
+    /// satisfies `align`.
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub fn try_new_aligned(x: T, align: PageAlignment) -> Result<Self, SvsmError> {
+        let order = Self::order()?;
+        let vaddr = allocate_pages_aligned(order, align)?;
+        // SAFETY: `vaddr` was just allocated with the order for a `T`.
+        Ok(unsafe { Self::write_new(vaddr, order, x) })
+    }
+
+    /// Moves `x` into newly allocated pages with a guard page on each side,
+    /// which is not present in the direct map. Accesses beyond the pages
+    /// holding the value fault immediately. The guard pages are only
+    /// adjacent to the end of the value if its size is a power-of-two
+    /// multiple of [`PAGE_SIZE`].
+    pub fn try_new_guarded(x: T) -> Result<Self, SvsmError> {
+        let order = Self::order()?;
+        let vaddr = allocate_guarded(order)?;
+        // SAFETY: `vaddr` was just allocated with the order for a `T`.
+        let mut b = unsafe { Self::write_new(vaddr, order, x) };
+        b.guarded = true;
+        Ok(b)
+    }
+
+    /// Moves `x` into newly allocated pages which are scrubbed when the box
+    /// is dropped, see [`PageBox::set_sensitive()`].
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub fn try_new_sensitive(x: T) -> Result<Self, SvsmError> {
+        let mut b = Self::try_new(x)?;
+        b.sensitive = true;
+        Ok(b)
+    }
+
+    /// Moves `x` into a newly allocated 2M page, which is 2M aligned in
+    /// physical memory and can be mapped as a huge page. Fails with
+    /// [`SvsmError::NotSupported`] if a `T` does not fit in 2M.
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub fn try_new_2m(x: T) -> Result<Self, SvsmError> {
+        if size_of::<T>() > PAGE_SIZE_2M || align_of::<T>() > PAGE_SIZE_2M {
+            return Err(SvsmError::NotSupported);
+        }
+        let order = get_order(ByteSize::new(PAGE_SIZE_2M)).unwrap();
+        let vaddr = allocate_pages_aligned(order, PageAlignment::Aligned(PAGE_SIZE_2M))?;
+        // SAFETY: `vaddr` was just allocated and is large enough for a `T`.
+        Ok(unsafe { Self::write_new(vaddr, order, x) })
+    }
+}
+
+impl<T> PageBox<[T]> {
+    /// Smallest allocation order holding `len` values of type `T`
+    fn slice_order(len: usize) -> Result<PageOrder, SvsmError> {
+        if align_of::<T>() > PAGE_SIZE {
+            return Err(SvsmError::NotSupported);
+        }
+        size_of::<T>()
+            .checked_mul(len)
+            .and_then(|size| get_order(ByteSize::new(size)))
+            .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))
+    }
+
+    fn from_raw_slice(vaddr: VirtAddr, len: usize, order: PageOrder) -> Self {
+        let ptr = NonNull::new(vaddr.as_mut_ptr::<T>()).unwrap();
+        Self {
+            ptr: NonNull::slice_from_raw_parts(ptr, len),
+            order,
+            guarded: false,
+            sensitive: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Allocates a slice of `len` default values.
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub fn try_new_slice(len: usize) -> Result<Self, SvsmError>
+    where
+        T: Default,
+    {
+        let order = Self::slice_order(len)?;
+        let vaddr = allocate_pages(order)?;
+        let ptr = vaddr.as_mut_ptr::<T>();
+        for i in 0..len {
+            // SAFETY: the allocation holds `len` suitably aligned values.
+            unsafe { ptr.add(i).write(T::default()) };
+        }
+        Ok(Self::from_raw_slice(vaddr, len, order))
+    }
+
+    /// Allocates a slice of `len` values with all bytes set to zero.
+    ///
+    /// # Safety
+    ///
+    /// The all-zero bit pattern must be a valid value of type `T`.
+    #[cfg_attr(feature = "page-poison", track_caller)]
+    pub unsafe fn try_new_zeroed_slice(len: usize) -> Result<Self, SvsmError> {
+        let order = Self::slice_order(len)?;
+        let vaddr = allocate_pages(order)?;
+        zero_mem_region(vaddr, vaddr + (PAGE_SIZE << usize::from(order)));
+        Ok(Self::from_raw_slice(vaddr, len, order))
+    }
+
+    /// Clones the values into newly allocated pages, see
+    /// [`PageBox::try_clone()`].
+    pub fn try_clone(&self) -> Result<Self, SvsmError>
+    where
+        T: Clone,
+    {
+        let vaddr = self.allocate_like()?;
+        let ptr = vaddr.as_mut_ptr::<T>();
+        for (i, x) in self.iter().enumerate() {
+            // SAFETY: the allocation has the same size as this one.
+            unsafe { ptr.add(i).write(x.clone()) };
+        }
+        let mut b = Self::from_raw_slice(vaddr, self.len(), self.order);
+        b.guarded = self.guarded;
+        b.sensitive = self.sensitive;
+        Ok(b)
+    }
+}
+
+impl PageBox<[u8]> {
+    /// Splits the box into `N` owned parts. Each entry of `layout` gives the
+    /// size and alignment of a part, which is placed at the next offset with
+    /// that alignment after the previous one. Alignments must be powers of
+    /// two, and are relative to the page-aligned start of the box.
+    ///
+    /// # Returns
+    ///
+    /// The parts on success, `Err(SvsmError::InvalidAddress)` if an
+    /// alignment is invalid or the parts do not fit into the box, in which
+    /// case the box is dropped.
+    pub fn split_at<const N: usize>(
+        self,
+        layout: [(usize, usize); N],
+    ) -> Result<[PageSlice; N], SvsmError> {
+        let mut offsets = [0; N];
+        let mut end = 0;
+        for ((size, align), offset) in layout.iter().zip(offsets.iter_mut()) {
+            if !align.is_power_of_two() {
+                return Err(SvsmError::InvalidAddress);
+            }
+            *offset = align_up(end, *align);
+            end = offset
+                .checked_add(*size)
+                .filter(|end| *end <= self.len())
+                .ok_or(SvsmError::InvalidAddress)?;
+        }
+
+        let b = ManuallyDrop::new(self);
+        let pages = Arc::new(SplitPages {
+            vaddr: b.vaddr(),
+            order: b.order,
+            guarded: b.guarded,
+            sensitive: b.sensitive,
+        });
+        let base = b.ptr.cast::<u8>();
+        Ok(core::array::from_fn(|i| {
+            // SAFETY: the part is within the box, as checked above, so the
+            // pointer is in bounds and not null.
+            let ptr = unsafe { NonNull::new_unchecked(base.as_ptr().add(offsets[i])) };
+            PageSlice {
+                ptr: NonNull::slice_from_raw_parts(ptr, layout[i].0),
+                _pages: pages.clone(),
+            }
+        }))
+    }
+}
+
+/// Pages of a [`PageBox`] which was split, freed with the last part
+#[derive(Debug)]
+struct SplitPages {
+    vaddr: VirtAddr,
+    order: PageOrder,
+    guarded: bool,
+    sensitive: bool,
+}
+
+impl Drop for SplitPages {
+    fn drop(&mut self) {
+        free_pages(self.vaddr, self.order, self.guarded, self.sensitive);
+    }
+}
+
+/// An owned part of a byte slice [`PageBox`], created by
+/// [`PageBox::split_at()`]. The parts of a box do not overlap.
+pub struct PageSlice {
+    ptr: NonNull<[u8]>,
+    _pages: Arc<SplitPages>,
+}
+
+impl PageSlice {
+    /// Virtual address of the part
+    pub fn vaddr(&self) -> VirtAddr {
+        VirtAddr::from(self.ptr.as_ptr().cast::<u8>())
+    }
+}
+
+impl Deref for PageSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the part is valid for as long as the pages are, and no
+        // other part overlaps with it.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl DerefMut for PageSlice {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and the part is borrowed mutably.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl fmt::Debug for PageSlice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageSlice")
+            .field("vaddr", &self.vaddr())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+// SAFETY: a PageSlice owns its bytes exclusively.
+unsafe impl Send for PageSlice {}
+// SAFETY: a PageSlice only hands out shared references through &self.
+unsafe impl Sync for PageSlice {}
+
+impl<T: Clone> PageBox<T> {
+    /// Clones the value into newly allocated pages of the same order, with
+    /// guard pages if this box has them and scrubbed on drop if it is
+    /// sensitive. Constraints on the physical
+    /// address this box was allocated with are not preserved.
+    pub fn try_clone(&self) -> Result<Self, SvsmError> {
+        let vaddr = self.allocate_like()?;
+        // SAFETY: `vaddr` was just allocated with the order of this box,
+        // which holds a `T`.
+        let mut b = unsafe { Self::write_new(vaddr, self.order, (**self).clone()) };
+        b.guarded = self.guarded;
+        b.sensitive = self.sensitive;
+        Ok(b)
+    }
+}
+
+impl<T: ?Sized> PageBox<T> {
+    /// Allocates pages with the same order and guard pages as this box
+    fn allocate_like(&self) -> Result<VirtAddr, SvsmError> {
+        if self.guarded {
+            allocate_guarded(self.order)
+        } else {
+            allocate_pages(self.order)
+        }
+    }
+
+    /// Virtual address of the boxed value
+    pub fn vaddr(&self) -> VirtAddr {
+        VirtAddr::from(self.ptr.as_ptr().cast::<u8>())
+    }
+
+    /// Order of the page allocation holding the value
+    pub fn page_order(&self) -> PageOrder {
+        self.order
+    }
+
+    /// Returns whether the value is surrounded by guard pages
+    pub fn is_guarded(&self) -> bool {
+        self.guarded
+    }
+
+    /// Marks the box as holding secrets, so that its pages are overwritten
+    /// with zeros before they are freed. This also applies to clones of the
+    /// box and to the parts it is split into.
+    pub fn set_sensitive(&mut self) {
+        self.sensitive = true;
+    }
+
+    /// Returns whether the pages are scrubbed when the box is dropped
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+    }
+
+    /// Consumes the box without freeing its pages, returning a reference
+    /// to the value which is valid for the rest of the SVSM's lifetime.
+    pub fn leak(b: Self) -> &'static mut T {
+        let b = ManuallyDrop::new(b);
+        // SAFETY: the pages are never freed, so the value lives forever.
+        unsafe { &mut *b.ptr.as_ptr() }
+    }
+}
+
+impl<T: ?Sized> Deref for PageBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the pointer is valid and initialized for as long as the
+        // box exists.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the pointer is valid and initialized for as long as the
+        // box exists, and the box is borrowed mutably.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the value is initialized and dropped only once.
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        free_pages(self.vaddr(), self.order, self.guarded, self.sensitive);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for PageBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// SAFETY: a PageBox owns its value exclusively, like a Box.
+unsafe impl<T: ?Sized + Send> Send for PageBox<T> {}
+// SAFETY: a PageBox only hands out shared references through &self.
+unsafe impl<T: ?Sized + Sync> Sync for PageBox<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+    #[test]
+    fn page_box() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let mut b = PageBox::try_new([7u64; 1024]).unwrap();
+        assert!(b.vaddr().is_page_aligned());
+        assert_eq!(usize::from(b.page_order()), 1);
+        b[1000] = 8;
+        assert_eq!(b[..2], [7, 7]);
+        assert_eq!(b[1000], 8);
+    }
+
+    #[test]
+    fn page_box_slice() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let mut b = PageBox::<[u32]>::try_new_slice(3000).unwrap();
+        assert_eq!(b.len(), 3000);
+        assert_eq!(usize::from(b.page_order()), 2);
+        assert!(b.iter().all(|v| *v == 0));
+        b[2999] = 5;
+        assert_eq!(b[2999], 5);
+
+        // SAFETY: zero is a valid u64.
+        let z = unsafe { PageBox::<[u64]>::try_new_zeroed_slice(10).unwrap() };
+        assert_eq!(*z, [0; 10]);
+    }
+
+    #[test]
+    fn page_box_guarded() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let b = PageBox::try_new_guarded([3u8; PAGE_SIZE]).unwrap();
+        assert!(b.is_guarded());
+        assert!(b.vaddr().is_page_aligned());
+        assert_eq!(usize::from(b.page_order()), 0);
+        assert!(b.iter().all(|v| *v == 3));
+
+        let v = PageBox::try_new_guarded(7u64).unwrap();
+        assert_eq!(*v, 7);
+        assert!(!PageBox::try_new(7u64).unwrap().is_guarded());
+    }
+
+    #[test]
+    fn page_box_clone() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let mut a = PageBox::try_new([1u32; 100]).unwrap();
+        let b = a.try_clone().unwrap();
+        a[0] = 2;
+        assert_ne!(a.vaddr(), b.vaddr());
+        assert_eq!(b[..2], [1, 1]);
+        assert_eq!(b.page_order(), a.page_order());
+
+        let g = PageBox::try_new_guarded(5u64).unwrap().try_clone().unwrap();
+        assert!(g.is_guarded());
+        assert_eq!(*g, 5);
+
+        let mut s = PageBox::<[u16]>::try_new_slice(10).unwrap();
+        s[9] = 9;
+        let t = s.try_clone().unwrap();
+        assert_eq!(t.len(), 10);
+        assert_eq!(t[9], 9);
+    }
+
+    #[test]
+    fn page_box_sensitive() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let b = PageBox::try_new_sensitive([0xa5u8; 100]).unwrap();
+        assert!(b.is_sensitive());
+        assert!(b.try_clone().unwrap().is_sensitive());
+        assert!(!PageBox::try_new(0u8).unwrap().is_sensitive());
+
+        let mut s = PageBox::<[u8]>::try_new_slice(PAGE_SIZE * 2).unwrap();
+        s.set_sensitive();
+        s.fill(0xff);
+        scrub_pages(s.vaddr(), s.page_order());
+        assert!(s.iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn page_box_split() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let b = PageBox::<[u8]>::try_new_slice(PAGE_SIZE).unwrap();
+        let base = b.vaddr();
+        let [mut header, mut payload] = b.split_at([(24, 8), (1024, 64)]).unwrap();
+        assert_eq!(header.vaddr(), base);
+        assert_eq!(header.len(), 24);
+        assert_eq!(payload.vaddr(), base + 64);
+        assert_eq!(payload.len(), 1024);
+
+        header.fill(1);
+        payload.fill(2);
+        drop(header);
+        assert!(payload.iter().all(|v| *v == 2));
+
+        let b = PageBox::<[u8]>::try_new_slice(100).unwrap();
+        assert!(matches!(
+            b.split_at([(50, 1), (50, 16)]),
+            Err(SvsmError::InvalidAddress)
+        ));
+        let b = PageBox::<[u8]>::try_new_slice(100).unwrap();
+        assert!(matches!(
+            b.split_at([(8, 3)]),
+            Err(SvsmError::InvalidAddress)
+        ));
+        let b = PageBox::<[u8]>::try_new_slice(100).unwrap();
+        let [a, c] = b.split_at([(50, 1), (50, 1)]).unwrap();
+        assert_eq!(c.vaddr(), a.vaddr() + 50);
+    }
+
+    #[test]
+    fn page_box_misaligned() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let align = PageAlignment::Misaligned(PAGE_SIZE * 2);
+        let boxes: [_; 16] = core::array::from_fn(|i| PageBox::try_new_aligned(i, align).unwrap());
+        for (i, b) in boxes.iter().enumerate() {
+            assert_eq!(**b, i);
+            assert!(!b.vaddr().is_aligned(PAGE_SIZE * 2));
+        }
+    }
+}
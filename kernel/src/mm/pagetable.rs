@@ -5,27 +5,30 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
-use crate::cpu::control_regs::write_cr3;
+use crate::cpu::control_regs::{read_cr3, write_cr3};
 use crate::cpu::features::{cpu_has_nx, cpu_has_pge};
 use crate::cpu::flush_tlb_global_sync;
 use crate::error::SvsmError;
 use crate::locking::{LockGuard, SpinLock};
-use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::mm::encryption::EncryptionMask;
+use crate::mm::pool::{pool, PoolId};
 use crate::mm::{phys_to_virt, virt_to_phys, PGTABLE_LVL3_IDX_SHARED};
 use crate::platform::SvsmPlatform;
 use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
 use crate::utils::immut_after_init::{ImmutAfterInitCell, ImmutAfterInitResult};
 use crate::utils::MemoryRegion;
 use bitflags::bitflags;
+use core::mem::{align_of, size_of};
 use core::ops::{Deref, DerefMut, Index, IndexMut};
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::{cmp, ptr};
 
 extern crate alloc;
 use alloc::boxed::Box;
 
 const ENTRY_COUNT: usize = 512;
-static PRIVATE_PTE_MASK: ImmutAfterInitCell<usize> = ImmutAfterInitCell::new(0);
-static SHARED_PTE_MASK: ImmutAfterInitCell<usize> = ImmutAfterInitCell::new(0);
+static ENCRYPT_MASK: ImmutAfterInitCell<EncryptionMask> =
+    ImmutAfterInitCell::new(EncryptionMask::none());
 static MAX_PHYS_ADDR: ImmutAfterInitCell<u64> = ImmutAfterInitCell::uninit();
 pub const LAUNCH_VMSA_ADDR: PhysAddr = PhysAddr::new(0xFFFFFFFFF000);
 static FEATURE_MASK: ImmutAfterInitCell<PTEntryFlags> =
@@ -56,8 +59,7 @@ pub fn paging_init(platform: &dyn SvsmPlatform, vtom: u64) -> ImmutAfterInitResu
 fn init_encrypt_mask(platform: &dyn SvsmPlatform, vtom: usize) -> ImmutAfterInitResult<()> {
     let masks = platform.get_page_encryption_masks(vtom);
 
-    PRIVATE_PTE_MASK.reinit(&masks.private_pte_mask)?;
-    SHARED_PTE_MASK.reinit(&masks.shared_pte_mask)?;
+    ENCRYPT_MASK.reinit(&masks.encrypt_mask)?;
 
     let guest_phys_addr_size = (masks.phys_addr_sizes >> 16) & 0xff;
     let host_phys_addr_size = masks.phys_addr_sizes & 0xff;
@@ -79,12 +81,9 @@ fn init_encrypt_mask(platform: &dyn SvsmPlatform, vtom: usize) -> ImmutAfterInit
     MAX_PHYS_ADDR.reinit(&max_addr)
 }
 
-fn private_pte_mask() -> usize {
-    *PRIVATE_PTE_MASK
-}
-
-fn shared_pte_mask() -> usize {
-    *SHARED_PTE_MASK
+/// Returns the encryption bits used in page table entries.
+pub fn encrypt_mask() -> EncryptionMask {
+    *ENCRYPT_MASK
 }
 
 /// Returns the exclusive end of the physical address space.
@@ -97,15 +96,15 @@ fn supported_flags(flags: PTEntryFlags) -> PTEntryFlags {
 }
 
 fn make_shared_address(paddr: PhysAddr) -> PhysAddr {
-    PhysAddr::from(paddr.bits() & !private_pte_mask() | shared_pte_mask())
+    encrypt_mask().make_shared(paddr)
 }
 
 fn make_private_address(paddr: PhysAddr) -> PhysAddr {
-    PhysAddr::from(paddr.bits() & !shared_pte_mask() | private_pte_mask())
+    encrypt_mask().make_private(paddr)
 }
 
 fn strip_confidentiality_bits(paddr: PhysAddr) -> PhysAddr {
-    PhysAddr::from(paddr.bits() & !(shared_pte_mask() | private_pte_mask()))
+    encrypt_mask().strip(paddr)
 }
 
 bitflags! {
@@ -173,10 +172,28 @@ impl PTEntry {
         PTEntryFlags::from_bits_truncate(self.0.bits() as u64)
     }
 
-    pub fn set(&mut self, addr: PhysAddr, flags: PTEntryFlags) {
+    pub fn new(addr: PhysAddr, flags: PTEntryFlags) -> Self {
         let addr = addr.bits() as u64;
         assert_eq!(addr & !0x000f_ffff_ffff_f000, 0);
-        self.0 = PhysAddr::from(addr | supported_flags(flags).bits());
+        Self(PhysAddr::from(addr | supported_flags(flags).bits()))
+    }
+
+    const fn from_raw(raw: u64) -> Self {
+        Self(PhysAddr::new(raw as usize))
+    }
+
+    pub fn set(&mut self, addr: PhysAddr, flags: PTEntryFlags) {
+        *self = Self::new(addr, flags);
+    }
+
+    /// Returns whether the hardware set the accessed bit
+    pub fn accessed(&self) -> bool {
+        self.flags().contains(PTEntryFlags::ACCESSED)
+    }
+
+    /// Returns whether the hardware set the dirty bit
+    pub fn dirty(&self) -> bool {
+        self.flags().contains(PTEntryFlags::DIRTY)
     }
 
     pub fn address(&self) -> PhysAddr {
@@ -185,6 +202,67 @@ impl PTEntry {
     }
 }
 
+const _: () = assert!(size_of::<PTEntry>() == size_of::<AtomicU64>());
+const _: () = assert!(align_of::<PTEntry>() == align_of::<AtomicU64>());
+
+/// Atomic accessor for a live page table entry.
+///
+/// Entries of a page table in use can be read concurrently by other CPUs and
+/// by the hardware page walker, which also sets the accessed and dirty bits
+/// behind the back of the SVSM. Modifications of such entries go through
+/// this type: new entries are published with release ordering, so that a
+/// lower-level page table is completely initialized before it becomes
+/// reachable, and read-modify-write updates use a compare-exchange loop, so
+/// that accessed and dirty bits set in the meantime are not lost.
+#[derive(Debug)]
+pub struct PteRef<'a>(&'a AtomicU64);
+
+impl<'a> PteRef<'a> {
+    pub fn new(entry: &'a mut PTEntry) -> Self {
+        let ptr = (entry as *mut PTEntry).cast::<u64>();
+        // SAFETY: PTEntry has the size and alignment of AtomicU64, and the
+        // exclusive borrow guarantees that the entry is not accessed
+        // non-atomically by Rust code while the PteRef exists.
+        Self(unsafe { AtomicU64::from_ptr(ptr) })
+    }
+
+    pub fn load(&self) -> PTEntry {
+        PTEntry::from_raw(self.0.load(Ordering::Acquire))
+    }
+
+    /// Publish a new entry, replacing the current one.
+    pub fn store(&self, entry: PTEntry) {
+        self.0.store(entry.raw(), Ordering::Release);
+    }
+
+    /// Clear the entry, returning its last value including the accessed and
+    /// dirty bits.
+    pub fn clear(&self) -> PTEntry {
+        PTEntry::from_raw(self.0.swap(0, Ordering::AcqRel))
+    }
+
+    /// Atomically replace the entry with the result of `f`, retrying if the
+    /// entry changed in the meantime. `f` may be called multiple times and
+    /// always gets the current value, including accessed and dirty bits.
+    /// Returns the previous value.
+    pub fn update<F>(&self, mut f: F) -> PTEntry
+    where
+        F: FnMut(PTEntry) -> PTEntry,
+    {
+        let mut current = self.0.load(Ordering::Acquire);
+        loop {
+            let new = f(PTEntry::from_raw(current)).raw();
+            match self
+                .0
+                .compare_exchange_weak(current, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(old) => return PTEntry::from_raw(old),
+                Err(old) => current = old,
+            }
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct PTPage {
@@ -247,7 +325,7 @@ impl PageTable {
     }
 
     fn allocate_page_table() -> Result<*mut PTPage, SvsmError> {
-        let ptr = allocate_zeroed_page()?;
+        let ptr = pool(PoolId::PageTables).allocate_zeroed_page()?;
         Ok(ptr.as_mut_ptr::<PTPage>())
     }
 
@@ -326,7 +404,7 @@ impl PageTable {
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
             | PTEntryFlags::ACCESSED;
-        entry.set(paddr, flags);
+        PteRef::new(entry).store(PTEntry::new(paddr, flags));
 
         let idx = PageTable::index::<2>(vaddr);
 
@@ -350,7 +428,7 @@ impl PageTable {
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
             | PTEntryFlags::ACCESSED;
-        entry.set(paddr, flags);
+        PteRef::new(entry).store(PTEntry::new(paddr, flags));
 
         let idx = PageTable::index::<1>(vaddr);
 
@@ -374,7 +452,7 @@ impl PageTable {
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
             | PTEntryFlags::ACCESSED;
-        entry.set(paddr, flags);
+        PteRef::new(entry).store(PTEntry::new(paddr, flags));
 
         let idx = PageTable::index::<0>(vaddr);
 
@@ -422,10 +500,10 @@ impl PageTable {
             }
         }
 
-        entry.set(
+        PteRef::new(entry).store(PTEntry::new(
             make_private_address(virt_to_phys(VirtAddr::from(page))),
             flags,
-        );
+        ));
 
         flush_tlb_global_sync();
 
@@ -442,19 +520,13 @@ impl PageTable {
     }
 
     fn make_pte_shared(entry: &mut PTEntry) {
-        let flags = entry.flags();
-        let addr = entry.address();
-
-        // entry.address() returned with c-bit clear already
-        entry.set(make_shared_address(addr), flags);
+        // e.address() returns the address with the c-bit clear already
+        PteRef::new(entry).update(|e| PTEntry::new(make_shared_address(e.address()), e.flags()));
     }
 
     fn make_pte_private(entry: &mut PTEntry) {
-        let flags = entry.flags();
-        let addr = entry.address();
-
-        // entry.address() returned with c-bit clear already
-        entry.set(make_private_address(addr), flags);
+        // e.address() returns the address with the c-bit clear already
+        PteRef::new(entry).update(|e| PTEntry::new(make_private_address(e.address()), e.flags()));
     }
 
     pub fn set_shared_4k(&mut self, vaddr: VirtAddr) -> Result<(), SvsmError> {
@@ -481,6 +553,28 @@ impl PageTable {
         }
     }
 
+    /// Marks the 4K page at `vaddr` present or not present, splitting a 2M
+    /// mapping if necessary. The rest of the entry is preserved, so that a
+    /// page can be made present again. The caller must flush the TLB.
+    pub fn set_present_4k(&mut self, vaddr: VirtAddr, present: bool) -> Result<(), SvsmError> {
+        let mapping = self.walk_addr(vaddr);
+        PageTable::split_4k(mapping)?;
+
+        if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
+            let present_bit = PTEntryFlags::PRESENT.bits();
+            PteRef::new(entry).update(|e| {
+                if present {
+                    PTEntry::from_raw(e.raw() | present_bit)
+                } else {
+                    PTEntry::from_raw(e.raw() & !present_bit)
+                }
+            });
+            Ok(())
+        } else {
+            Err(SvsmError::Mem)
+        }
+    }
+
     pub fn check_mapping(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
         match self.walk_addr(vaddr) {
             Mapping::Level0(entry) => Some(entry.address()),
@@ -501,7 +595,10 @@ impl PageTable {
         let mapping = self.alloc_pte_2m(vaddr);
 
         if let Mapping::Level1(entry) = mapping {
-            entry.set(make_private_address(paddr), flags | PTEntryFlags::HUGE);
+            PteRef::new(entry).store(PTEntry::new(
+                make_private_address(paddr),
+                flags | PTEntryFlags::HUGE,
+            ));
             Ok(())
         } else {
             Err(SvsmError::Mem)
@@ -515,7 +612,9 @@ impl PageTable {
 
         match mapping {
             Mapping::Level0(_) => unreachable!(),
-            Mapping::Level1(entry) => entry.clear(),
+            Mapping::Level1(entry) => {
+                PteRef::new(entry).clear();
+            }
             Mapping::Level2(entry) => assert!(!entry.present()),
             Mapping::Level3(entry) => assert!(!entry.present()),
         }
@@ -530,7 +629,7 @@ impl PageTable {
         let mapping = self.alloc_pte_4k(vaddr);
 
         if let Mapping::Level0(entry) = mapping {
-            entry.set(make_private_address(paddr), flags);
+            PteRef::new(entry).store(PTEntry::new(make_private_address(paddr), flags));
             Ok(())
         } else {
             Err(SvsmError::Mem)
@@ -541,7 +640,9 @@ impl PageTable {
         let mapping = self.walk_addr(vaddr);
 
         match mapping {
-            Mapping::Level0(entry) => entry.clear(),
+            Mapping::Level0(entry) => {
+                PteRef::new(entry).clear();
+            }
             Mapping::Level1(entry) => assert!(!entry.present()),
             Mapping::Level2(entry) => assert!(!entry.present()),
             Mapping::Level3(entry) => assert!(!entry.present()),
@@ -650,11 +751,11 @@ impl PageTable {
 
             match mapping {
                 Mapping::Level0(entry) => {
-                    entry.clear();
+                    PteRef::new(entry).clear();
                     vaddr = vaddr + PAGE_SIZE;
                 }
                 Mapping::Level1(entry) => {
-                    entry.clear();
+                    PteRef::new(entry).clear();
                     vaddr = vaddr + PAGE_SIZE_2M;
                 }
                 _ => {
@@ -674,11 +775,196 @@ impl PageTable {
             let entry = &mut self.root[idx];
             // The C bit is not required here because all page table fetches are
             // made as C=1.
-            entry.set(paddr, flags);
+            PteRef::new(entry).store(PTEntry::new(paddr, flags));
         }
     }
 }
 
+/// Address bits of a page table entry, including the encryption bits
+const PTE_ADDR_MASK: usize = 0x000f_ffff_ffff_f000;
+
+/// A present leaf entry of a page table, as reported by [`PageTable::walk()`]
+#[derive(Clone, Copy, Debug)]
+pub struct LeafMapping {
+    /// First virtual address of the mapping
+    pub vaddr: VirtAddr,
+    /// Physical address of the mapping, without encryption bits
+    pub paddr: PhysAddr,
+    /// Size of the mapping in bytes
+    pub size: usize,
+    pub flags: PTEntryFlags,
+    /// Whether the mapping targets memory shared with the host
+    pub shared: bool,
+}
+
+impl LeafMapping {
+    fn new(vaddr: VirtAddr, size: usize, entry: PTEntry) -> Self {
+        let raw = PhysAddr::from(entry.raw() as usize & PTE_ADDR_MASK);
+        Self {
+            vaddr,
+            paddr: entry.address(),
+            size,
+            flags: entry.flags(),
+            shared: encrypt_mask().is_shared(raw),
+        }
+    }
+
+    fn end(&self) -> VirtAddr {
+        VirtAddr::from(self.vaddr.bits().wrapping_add(self.size))
+    }
+
+    /// Flags relevant for dumping and merging, without the bits the
+    /// hardware updates
+    fn attrs(&self) -> PTEntryFlags {
+        self.flags
+            & (PTEntryFlags::WRITABLE
+                | PTEntryFlags::USER
+                | PTEntryFlags::GLOBAL
+                | PTEntryFlags::NX)
+    }
+
+    /// Returns whether `next` continues this mapping with the same
+    /// attributes
+    fn continued_by(&self, next: &Self) -> bool {
+        self.end() == next.vaddr
+            && self.paddr + self.size == next.paddr
+            && self.size == next.size
+            && self.attrs().bits() == next.attrs().bits()
+            && self.shared == next.shared
+    }
+
+    /// Checks the invariants the SVSM maintains for its mappings. `nx`
+    /// tells whether the NX bit is supported, as all mappings are
+    /// executable without it.
+    fn violation(&self, nx: bool) -> Option<&'static str> {
+        let exec = nx && !self.flags.contains(PTEntryFlags::NX);
+        if exec && self.flags.contains(PTEntryFlags::WRITABLE) {
+            Some("writable and executable")
+        } else if exec && self.shared {
+            Some("executable shared memory")
+        } else {
+            None
+        }
+    }
+}
+
+impl core::fmt::Display for LeafMapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>4} r{}{}{}{}{}",
+            self.vaddr,
+            self.end().bits().wrapping_sub(1),
+            self.paddr,
+            match self.size {
+                PAGE_SIZE => "4K",
+                PAGE_SIZE_2M => "2M",
+                _ => "1G",
+            },
+            flag(self.flags.contains(PTEntryFlags::WRITABLE), 'w'),
+            flag(!self.flags.contains(PTEntryFlags::NX), 'x'),
+            flag(self.flags.contains(PTEntryFlags::USER), 'u'),
+            flag(self.flags.contains(PTEntryFlags::GLOBAL), 'g'),
+            flag(self.shared, 's'),
+        )
+    }
+}
+
+impl PageTable {
+    fn walk_level(
+        page: &PTPage,
+        level: usize,
+        base: usize,
+        range: &MemoryRegion<VirtAddr>,
+        f: &mut dyn FnMut(&LeafMapping),
+    ) {
+        let size = PAGE_SIZE << (9 * level);
+        for (idx, entry) in page.entries.iter().enumerate() {
+            let start = base + idx * size;
+            let vaddr = VirtAddr::from(start);
+            let last = VirtAddr::from(start + (size - 1));
+            if !entry.present() || last < range.start() || vaddr >= range.end() {
+                continue;
+            }
+            let leaf = level == 0 || entry.flags().contains(PTEntryFlags::HUGE);
+            if leaf {
+                f(&LeafMapping::new(vaddr, size, *entry));
+            } else if let Some(next) = PageTable::entry_to_pagetable(*entry) {
+                PageTable::walk_level(next, level - 1, start, range, f);
+            }
+        }
+    }
+
+    /// Calls `f` for every present leaf mapping which overlaps `range`, in
+    /// ascending order of virtual addresses.
+    pub fn walk(&self, range: MemoryRegion<VirtAddr>, f: &mut dyn FnMut(&LeafMapping)) {
+        PageTable::walk_level(&self.root, 3, 0, &range, f);
+    }
+
+    /// Logs the mappings overlapping `range`. Contiguous mappings with the
+    /// same attributes are merged into a single line.
+    pub fn dump(&self, range: MemoryRegion<VirtAddr>) {
+        let mut run: Option<LeafMapping> = None;
+        self.walk(range, &mut |m| {
+            if let Some(r) = run.as_mut() {
+                if r.continued_by(m) {
+                    r.size += m.size;
+                    return;
+                }
+                log::info!("  {}", r);
+            }
+            run = Some(*m);
+        });
+        if let Some(r) = run {
+            log::info!("  {}", r);
+        }
+    }
+
+    /// Checks that no mapping is both writable and executable and that no
+    /// shared memory is executable. Logs every offending mapping and returns
+    /// their number.
+    pub fn verify(&self) -> usize {
+        let nx = supported_flags(PTEntryFlags::NX).contains(PTEntryFlags::NX);
+        let mut violations = 0;
+        self.walk(all_virt_addresses(), &mut |m| {
+            if let Some(what) = m.violation(nx) {
+                log::error!("Page table check: {}: {}", what, m);
+                violations += 1;
+            }
+        });
+        violations
+    }
+}
+
+fn all_virt_addresses() -> MemoryRegion<VirtAddr> {
+    MemoryRegion::from_addresses(VirtAddr::null(), VirtAddr::from(usize::MAX))
+}
+
+/// Returns the page table currently loaded on this CPU. Does not take any
+/// locks, so it can be used while handling a panic.
+fn current_pgtable() -> &'static PageTable {
+    // SAFETY: CR3 always points to a valid page table, which is mapped in
+    // the direct map like all page table pages.
+    unsafe { &*phys_to_virt(read_cr3()).as_ptr::<PageTable>() }
+}
+
+/// Logs the mappings of the current page table which overlap `range`.
+pub fn dump(range: MemoryRegion<VirtAddr>) {
+    log::info!(
+        "Page table mappings in {:#018x}-{:#018x}:",
+        range.start(),
+        range.end()
+    );
+    current_pgtable().dump(range);
+}
+
+/// Checks the invariants of the current page table, see
+/// [`PageTable::verify()`]. Returns the number of violations found.
+pub fn verify() -> usize {
+    current_pgtable().verify()
+}
+
 static INIT_PGTABLE: SpinLock<PageTableRef> = SpinLock::new(PageTableRef::unset());
 
 pub fn set_init_pgtable(pgtable: PageTableRef) {
@@ -704,7 +990,9 @@ impl PageTableRef {
     }
 
     pub fn alloc() -> Result<Self, SvsmError> {
-        let ptr = allocate_zeroed_page()?.as_mut_ptr();
+        let ptr = pool(PoolId::PageTables)
+            .allocate_zeroed_page()?
+            .as_mut_ptr();
         Ok(Self { ptr, owned: true })
     }
 
@@ -738,7 +1026,7 @@ impl DerefMut for PageTableRef {
 impl Drop for PageTableRef {
     fn drop(&mut self) {
         if self.owned {
-            free_page(VirtAddr::from(self.ptr))
+            pool(PoolId::PageTables).free_page(VirtAddr::from(self.ptr))
         }
     }
 }
@@ -770,7 +1058,7 @@ impl RawPageTablePart {
             let entry = page[idx];
 
             if RawPageTablePart::entry_to_page(entry).is_some() {
-                free_page(phys_to_virt(entry.address()));
+                pool(PoolId::PageTables).free_page(phys_to_virt(entry.address()));
             }
         }
     }
@@ -781,7 +1069,7 @@ impl RawPageTablePart {
 
             if let Some(l1_page) = RawPageTablePart::entry_to_page(entry) {
                 RawPageTablePart::free_lvl1(l1_page);
-                free_page(phys_to_virt(entry.address()));
+                pool(PoolId::PageTables).free_page(phys_to_virt(entry.address()));
             }
         }
     }
@@ -836,7 +1124,7 @@ impl RawPageTablePart {
         };
 
         if let Mapping::Level0(entry) = mapping {
-            entry.set(addr, flags);
+            PteRef::new(entry).store(PTEntry::new(addr, flags));
             Ok(())
         } else {
             Err(SvsmError::Mem)
@@ -847,11 +1135,7 @@ impl RawPageTablePart {
         let mapping = self.walk_addr(vaddr);
 
         match mapping {
-            Mapping::Level0(entry) => {
-                let e = *entry;
-                entry.clear();
-                Some(e)
-            }
+            Mapping::Level0(entry) => Some(PteRef::new(entry).clear()),
             Mapping::Level1(entry) => {
                 assert!(!entry.present());
                 None
@@ -885,7 +1169,7 @@ impl RawPageTablePart {
         };
 
         if let Mapping::Level1(entry) = mapping {
-            entry.set(addr, flags | PTEntryFlags::HUGE);
+            PteRef::new(entry).store(PTEntry::new(addr, flags | PTEntryFlags::HUGE));
             Ok(())
         } else {
             Err(SvsmError::Mem)
@@ -899,10 +1183,7 @@ impl RawPageTablePart {
 
         match mapping {
             Mapping::Level0(_) => None,
-            Mapping::Level1(entry) => {
-                entry.clear();
-                Some(*entry)
-            }
+            Mapping::Level1(entry) => Some(PteRef::new(entry).clear()),
             Mapping::Level2(entry) => {
                 assert!(!entry.present());
                 None
@@ -1081,3 +1362,108 @@ impl PageTablePart {
         self.get_mut().and_then(|r| r.unmap_2m(vaddr))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{allocate_zeroed_page, TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn pte_ref_update_keeps_hardware_bits() {
+        let present = PTEntryFlags::PRESENT | PTEntryFlags::WRITABLE;
+        let hw_bits = PTEntryFlags::ACCESSED | PTEntryFlags::DIRTY;
+        let mut entry = PTEntry::from_raw(0x1000 | present.bits());
+        let pte = PteRef::new(&mut entry);
+
+        let mut calls = 0;
+        let old = pte.update(|e| {
+            calls += 1;
+            if calls == 1 {
+                // The hardware sets the A/D bits after the entry was read
+                pte.0.fetch_or(hw_bits.bits(), Ordering::Relaxed);
+            }
+            PTEntry::from_raw(e.raw() & !PTEntryFlags::WRITABLE.bits())
+        });
+
+        assert!(calls >= 2);
+        assert!(old.accessed() && old.dirty());
+        let new = pte.load();
+        assert!(new.accessed() && new.dirty());
+        assert!(!new.flags().contains(PTEntryFlags::WRITABLE));
+        assert_eq!(pte.clear().raw(), new.raw());
+        assert!(pte.load().is_clear());
+    }
+
+    #[test]
+    fn walk_and_verify() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let entry = |addr: u64, flags: PTEntryFlags| PTEntry::from_raw(addr | flags.bits());
+        let table = || {
+            let vaddr = allocate_zeroed_page().unwrap();
+            // SAFETY: the page is zeroed and owned by the test.
+            unsafe { &mut *vaddr.as_mut_ptr::<PTPage>() }
+        };
+        let data = PTEntryFlags::PRESENT | PTEntryFlags::WRITABLE | PTEntryFlags::NX;
+        let huge = data | PTEntryFlags::HUGE;
+
+        let lvl1 = table();
+        lvl1[0] = entry(0x20_0000, huge);
+        lvl1[1] = entry(0x40_0000, huge);
+        lvl1[3] = entry(0x80_0000, huge);
+        let lvl2 = table();
+        lvl2[1] = entry(
+            virt_to_phys(VirtAddr::from(ptr::from_ref(lvl1))).bits() as u64,
+            data,
+        );
+        let mut pgtable = Box::new(PageTable::default());
+        pgtable.root[0] = entry(
+            virt_to_phys(VirtAddr::from(ptr::from_ref(lvl2))).bits() as u64,
+            data,
+        );
+
+        let mut found = Vec::new();
+        pgtable.walk(all_virt_addresses(), &mut |m| {
+            found.push((m.vaddr, m.paddr, m.size))
+        });
+        let base = 1usize << 30;
+        assert_eq!(
+            found,
+            [
+                (
+                    VirtAddr::from(base),
+                    PhysAddr::from(0x20_0000u64),
+                    PAGE_SIZE_2M
+                ),
+                (
+                    VirtAddr::from(base + PAGE_SIZE_2M),
+                    PhysAddr::from(0x40_0000u64),
+                    PAGE_SIZE_2M
+                ),
+                (
+                    VirtAddr::from(base + 3 * PAGE_SIZE_2M),
+                    PhysAddr::from(0x80_0000u64),
+                    PAGE_SIZE_2M
+                ),
+            ]
+        );
+
+        // Ranges are honored
+        found.clear();
+        let range = MemoryRegion::new(VirtAddr::from(base + PAGE_SIZE_2M), PAGE_SIZE_2M);
+        pgtable.walk(range, &mut |m| found.push((m.vaddr, m.paddr, m.size)));
+        assert_eq!(found.len(), 1);
+
+        // Only the first two mappings are contiguous
+        let mut maps = Vec::new();
+        pgtable.walk(all_virt_addresses(), &mut |m| maps.push(*m));
+        assert!(maps[0].continued_by(&maps[1]));
+        assert!(!maps[1].continued_by(&maps[2]));
+
+        let mut wx = maps[0];
+        assert_eq!(wx.violation(true), None);
+        wx.flags.remove(PTEntryFlags::NX);
+        assert!(wx.violation(true).is_some());
+        assert_eq!(wx.violation(false), None);
+    }
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Page poisoning and allocation tracking for testing.
+//!
+//! With the `page-poison` feature, the page allocator fills every page with
+//! [`PAGE_POISON`] when it is freed and checks that the poison is intact
+//! when the page is allocated again, which catches writes through dangling
+//! pointers to freed pages such as those of a dropped
+//! [`PageBox`](super::PageBox). The call sites of page allocations and frees
+//! are recorded in a ring buffer, and a poison violation panics with the
+//! recorded history of the affected pages.
+//!
+//! This costs time on every page allocation and free, so it is only meant
+//! for test builds.
+
+use crate::address::VirtAddr;
+use crate::locking::SpinLock;
+use core::fmt;
+use core::panic::Location;
+use core::slice;
+
+/// Fill pattern of free pages
+pub const PAGE_POISON: u8 = 0xaa;
+/// Number of allocations and frees kept in the history
+pub const HISTORY_SIZE: usize = 256;
+
+/// Fill `size` bytes at `vaddr` with the poison pattern.
+///
+/// # Safety
+///
+/// The memory must be free pages owned by the page allocator.
+pub unsafe fn poison_range(vaddr: VirtAddr, size: usize) {
+    // SAFETY: guaranteed by the caller.
+    unsafe { vaddr.as_mut_ptr::<u8>().write_bytes(PAGE_POISON, size) };
+}
+
+/// Returns the offset of the first byte in `size` bytes at `vaddr` which
+/// does not hold the poison pattern.
+///
+/// # Safety
+///
+/// The memory must be pages owned by the page allocator.
+pub unsafe fn find_poison_violation(vaddr: VirtAddr, size: usize) -> Option<usize> {
+    // SAFETY: guaranteed by the caller.
+    let bytes = unsafe { slice::from_raw_parts(vaddr.as_ptr::<u8>(), size) };
+    bytes.iter().position(|b| *b != PAGE_POISON)
+}
+
+/// Check that `size` bytes at `vaddr`, which are about to be allocated,
+/// still hold the poison pattern, and panic otherwise.
+///
+/// # Safety
+///
+/// The memory must be pages owned by the page allocator.
+pub unsafe fn check_poison(vaddr: VirtAddr, size: usize) {
+    // SAFETY: guaranteed by the caller.
+    if let Some(offset) = unsafe { find_poison_violation(vaddr, size) } {
+        panic!(
+            "Free page at {:#x} modified at offset {:#x}\n{}",
+            vaddr,
+            offset,
+            PageHistory { vaddr, size }
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageEventKind {
+    Alloc,
+    Free,
+}
+
+/// A recorded page allocation or free
+#[derive(Clone, Copy, Debug)]
+pub struct PageEvent {
+    pub kind: PageEventKind,
+    pub vaddr: VirtAddr,
+    pub location: &'static Location<'static>,
+}
+
+#[derive(Debug)]
+struct History {
+    events: [Option<PageEvent>; HISTORY_SIZE],
+    next: usize,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            events: [None; HISTORY_SIZE],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, event: PageEvent) {
+        self.events[self.next] = Some(event);
+        self.next = (self.next + 1) % HISTORY_SIZE;
+    }
+
+    /// Events from newest to oldest
+    fn iter(&self) -> impl Iterator<Item = &PageEvent> {
+        let (older, newer) = self.events.split_at(self.next);
+        newer.iter().chain(older.iter()).rev().flatten()
+    }
+}
+
+static HISTORY: SpinLock<History> = SpinLock::new(History::new());
+
+/// Record a page allocation or free made from `location`
+pub fn record_page_event(
+    kind: PageEventKind,
+    vaddr: VirtAddr,
+    location: &'static Location<'static>,
+) {
+    HISTORY.lock().push(PageEvent {
+        kind,
+        vaddr,
+        location,
+    });
+}
+
+/// Calls `f` for the recorded events of pages overlapping `size` bytes at
+/// `vaddr`, from newest to oldest.
+pub fn page_history(vaddr: VirtAddr, size: usize, mut f: impl FnMut(&PageEvent)) {
+    HISTORY
+        .lock()
+        .iter()
+        .filter(|event| event.vaddr >= vaddr && event.vaddr < vaddr + size)
+        .for_each(f);
+}
+
+/// Formats the recorded history of a range of pages
+#[derive(Clone, Copy, Debug)]
+struct PageHistory {
+    vaddr: VirtAddr,
+    size: usize,
+}
+
+impl fmt::Display for PageHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = Ok(());
+        page_history(self.vaddr, self.size, |event| {
+            result = result.and_then(|_| {
+                writeln!(
+                    f,
+                    "  {:?} {:#x} at {}",
+                    event.kind, event.vaddr, event.location
+                )
+            });
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PAGE_SIZE;
+
+    #[test]
+    fn poison() {
+        let mut page = [0u8; PAGE_SIZE];
+        let vaddr = VirtAddr::from(page.as_mut_ptr());
+        // SAFETY: the buffer stands in for a free page.
+        unsafe {
+            poison_range(vaddr, PAGE_SIZE);
+            assert_eq!(find_poison_violation(vaddr, PAGE_SIZE), None);
+        }
+        page[100] = 0;
+        // SAFETY: as above.
+        assert_eq!(
+            unsafe { find_poison_violation(VirtAddr::from(page.as_ptr()), PAGE_SIZE) },
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn history() {
+        // An address no real page uses, as the history is global
+        let vaddr = VirtAddr::from(0xffff_ff00_0000_0000u64);
+        record_page_event(PageEventKind::Alloc, vaddr, Location::caller());
+        record_page_event(PageEventKind::Free, vaddr, Location::caller());
+        record_page_event(PageEventKind::Alloc, vaddr + PAGE_SIZE, Location::caller());
+
+        let mut kinds = [None; 3];
+        let mut count = 0;
+        page_history(vaddr, PAGE_SIZE, |event| {
+            kinds[count] = Some(event.kind);
+            count += 1;
+        });
+        assert_eq!(count, 2);
+        assert_eq!(kinds[0], Some(PageEventKind::Free));
+        assert_eq!(kinds[1], Some(PageEventKind::Alloc));
+    }
+}
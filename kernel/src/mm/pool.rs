@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Per-subsystem memory pools.
+//!
+//! Pages allocated through a [`MemoryPool`] still come from the root page
+//! allocator, but are accounted to the pool of the subsystem that owns them.
+//! A pool can be given a limit, so that a leak in one subsystem exhausts
+//! only its own quota instead of all memory of the SVSM, and
+//! [`dump_pool_stats()`] reports which subsystem is holding on to memory.
+
+use crate::address::VirtAddr;
+use crate::error::SvsmError;
+use crate::mm::alloc::{allocate_pages, allocate_zeroed_page, free_page, AllocError};
+use crate::utils::PageOrder;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The subsystems with a memory pool of their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolId {
+    /// Buffers for SVSM protocol requests
+    Protocol,
+    /// vTPM state and command buffers
+    Vtpm,
+    /// Virtio queues and buffers
+    Virtio,
+    /// Page table pages
+    PageTables,
+}
+
+const NO_LIMIT: usize = usize::MAX;
+
+/// Usage statistics of a memory pool, in pages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Pages currently allocated from the pool
+    pub used: usize,
+    /// Highest number of pages allocated at any time
+    pub peak: usize,
+    /// Maximum number of pages the pool may hold, if limited
+    pub limit: Option<usize>,
+    /// Number of allocations refused because of the limit
+    pub failures: usize,
+}
+
+/// A named share of the page allocator
+#[derive(Debug)]
+pub struct MemoryPool {
+    name: &'static str,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    limit: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl MemoryPool {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(NO_LIMIT),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Limits the pool to `pages` pages, or removes the limit if `None`.
+    /// Lowering the limit below the current usage only affects further
+    /// allocations.
+    pub fn set_limit(&self, pages: Option<usize>) {
+        self.limit
+            .store(pages.unwrap_or(NO_LIMIT), Ordering::Relaxed);
+    }
+
+    fn charge(&self, pages: usize) -> Result<(), SvsmError> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let prev = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(pages).filter(|new| *new <= limit)
+            })
+            .map_err(|used| {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "Memory pool {}: allocation of {} pages exceeds limit ({}/{} pages used)",
+                    self.name,
+                    pages,
+                    used,
+                    limit
+                );
+                SvsmError::Alloc(AllocError::QuotaExceeded)
+            })?;
+        self.peak.fetch_max(prev + pages, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn uncharge(&self, pages: usize) {
+        let prev = self.used.fetch_sub(pages, Ordering::Relaxed);
+        debug_assert!(prev >= pages, "memory pool {} underflow", self.name);
+    }
+
+    /// Allocates 2^`order` pages and accounts them to the pool.
+    pub fn allocate_pages(&self, order: PageOrder) -> Result<VirtAddr, SvsmError> {
+        let pages = 1usize << usize::from(order);
+        self.charge(pages)?;
+        allocate_pages(order).inspect_err(|_| self.uncharge(pages))
+    }
+
+    /// Allocates a zeroed page and accounts it to the pool.
+    pub fn allocate_zeroed_page(&self) -> Result<VirtAddr, SvsmError> {
+        self.charge(1)?;
+        allocate_zeroed_page().inspect_err(|_| self.uncharge(1))
+    }
+
+    /// Frees pages allocated from this pool with the given `order`.
+    pub fn free_pages(&self, vaddr: VirtAddr, order: PageOrder) {
+        free_page(vaddr);
+        self.uncharge(1usize << usize::from(order));
+    }
+
+    /// Frees a single page allocated from this pool.
+    pub fn free_page(&self, vaddr: VirtAddr) {
+        self.free_pages(vaddr, PageOrder::new(0));
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let limit = self.limit.load(Ordering::Relaxed);
+        PoolStats {
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            limit: (limit != NO_LIMIT).then_some(limit),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static PROTOCOL_POOL: MemoryPool = MemoryPool::new("protocol");
+static VTPM_POOL: MemoryPool = MemoryPool::new("vtpm");
+static VIRTIO_POOL: MemoryPool = MemoryPool::new("virtio");
+static PAGE_TABLE_POOL: MemoryPool = MemoryPool::new("page-tables");
+
+/// Returns the memory pool of a subsystem
+pub fn pool(id: PoolId) -> &'static MemoryPool {
+    match id {
+        PoolId::Protocol => &PROTOCOL_POOL,
+        PoolId::Vtpm => &VTPM_POOL,
+        PoolId::Virtio => &VIRTIO_POOL,
+        PoolId::PageTables => &PAGE_TABLE_POOL,
+    }
+}
+
+/// Logs the usage of all memory pools. Does not take any locks, so it can
+/// be used while handling a panic.
+pub fn dump_pool_stats() {
+    log::info!("Memory pools (pages):");
+    for id in [
+        PoolId::Protocol,
+        PoolId::Vtpm,
+        PoolId::Virtio,
+        PoolId::PageTables,
+    ] {
+        let p = pool(id);
+        let stats = p.stats();
+        match stats.limit {
+            Some(limit) => log::info!(
+                "  {:<12} used {:>6} peak {:>6} limit {:>6} refused {}",
+                p.name(),
+                stats.used,
+                stats.peak,
+                limit,
+                stats.failures
+            ),
+            None => log::info!(
+                "  {:<12} used {:>6} peak {:>6} limit   none refused {}",
+                p.name(),
+                stats.used,
+                stats.peak,
+                stats.failures
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+    #[test]
+    fn pool_accounting() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let p = MemoryPool::new("test");
+        let a = p.allocate_pages(PageOrder::new(2)).unwrap();
+        let b = p.allocate_zeroed_page().unwrap();
+        assert_eq!(p.stats().used, 5);
+
+        p.free_pages(a, PageOrder::new(2));
+        p.free_page(b);
+        let stats = p.stats();
+        assert_eq!(stats.used, 0);
+        assert_eq!(stats.peak, 5);
+        assert_eq!(stats.limit, None);
+    }
+
+    #[test]
+    fn pool_limit() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        let p = MemoryPool::new("test");
+        p.set_limit(Some(2));
+        let a = p.allocate_zeroed_page().unwrap();
+        assert!(matches!(
+            p.allocate_pages(PageOrder::new(1)),
+            Err(SvsmError::Alloc(AllocError::QuotaExceeded))
+        ));
+        let b = p.allocate_zeroed_page().unwrap();
+        assert!(p.allocate_zeroed_page().is_err());
+        assert_eq!(p.stats().failures, 2);
+
+        p.free_page(a);
+        p.free_page(b);
+        assert_eq!(p.stats().used, 0);
+    }
+}
@@ -13,6 +13,8 @@ use crate::mm::virtualrange::{
     virt_alloc_range_2m, virt_alloc_range_4k, virt_free_range_2m, virt_free_range_4k,
 };
 use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
+use core::cmp;
+use core::slice;
 
 use crate::utils::MemoryRegion;
 
@@ -73,6 +75,114 @@ impl PerCPUPageMappingGuard {
     pub fn virt_addr(&self) -> VirtAddr {
         self.mapping.start()
     }
+
+    /// Returns the virtual memory region of the mapping. Use
+    /// [`TypedMapping`](super::access::TypedMapping) to access the mapped
+    /// memory without keeping addresses past the end of the mapping.
+    pub fn region(&self) -> MemoryRegion<VirtAddr> {
+        self.mapping
+    }
+
+    /// Returns an iterator that maps `region` one window of at most
+    /// `chunk_size` bytes at a time, so that large guest buffers can be
+    /// processed without mapping them into the SVSM address space at once.
+    /// Only the window of the chunk currently held is mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero or not a multiple of [`PAGE_SIZE`].
+    pub fn chunks(region: MemoryRegion<PhysAddr>, chunk_size: usize) -> MappingChunks {
+        assert!(chunk_size != 0 && chunk_size % PAGE_SIZE == 0);
+        MappingChunks {
+            region,
+            pos: region.start(),
+            chunk_size,
+        }
+    }
+}
+
+/// Iterator returned by [`PerCPUPageMappingGuard::chunks()`]
+#[derive(Debug)]
+pub struct MappingChunks {
+    region: MemoryRegion<PhysAddr>,
+    pos: PhysAddr,
+    chunk_size: usize,
+}
+
+impl MappingChunks {
+    /// Returns the physical range covered by the next chunk. Windows are
+    /// page-aligned except for the start of the first and the end of the
+    /// last one.
+    fn next_window(&self) -> Option<MemoryRegion<PhysAddr>> {
+        if self.pos >= self.region.end() {
+            return None;
+        }
+        let end = cmp::min(self.pos.page_align() + self.chunk_size, self.region.end());
+        Some(MemoryRegion::from_addresses(self.pos, end))
+    }
+}
+
+impl Iterator for MappingChunks {
+    type Item = Result<MappedChunk, SvsmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let window = self.next_window()?;
+        let map_start = window.start().page_align();
+        let result = PerCPUPageMappingGuard::create(map_start, window.end().page_align_up(), 0);
+
+        // Stop after the first failure
+        self.pos = match result {
+            Ok(_) => window.end(),
+            Err(_) => self.region.end(),
+        };
+
+        Some(result.map(|guard| MappedChunk {
+            guard,
+            offset: window.start() - map_start,
+            region: window,
+        }))
+    }
+}
+
+/// A window of a physical memory region, mapped for as long as the chunk
+/// is alive
+#[derive(Debug)]
+#[must_use = "if unused the mapping will immediately be unmapped"]
+pub struct MappedChunk {
+    guard: PerCPUPageMappingGuard,
+    offset: usize,
+    region: MemoryRegion<PhysAddr>,
+}
+
+impl MappedChunk {
+    /// Physical range covered by this chunk
+    pub fn phys_region(&self) -> MemoryRegion<PhysAddr> {
+        self.region
+    }
+
+    /// Virtual address of the first byte of the chunk
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.guard.virt_addr() + self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.region.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.region.is_empty()
+    }
+
+    /// Returns the contents of the chunk as a byte slice.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the underlying memory is not modified
+    /// while the returned slice is alive, e.g. because it is not accessible
+    /// to the guest or the guest is not running.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.virt_addr().as_ptr::<u8>(), self.len())
+    }
 }
 
 impl Drop for PerCPUPageMappingGuard {
@@ -87,3 +197,41 @@ impl Drop for PerCPUPageMappingGuard {
         flush_address_sync(self.mapping.start());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(
+        start: usize,
+        len: usize,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let mut chunks = PerCPUPageMappingGuard::chunks(
+            MemoryRegion::new(PhysAddr::from(start), len),
+            chunk_size,
+        );
+        core::iter::from_fn(move || {
+            let window = chunks.next_window()?;
+            chunks.pos = window.end();
+            Some((window.start().bits(), window.end().bits()))
+        })
+    }
+
+    #[test]
+    fn chunk_windows_aligned() {
+        let w: [(usize, usize); 3] = [(0x10000, 0x12000), (0x12000, 0x14000), (0x14000, 0x15000)];
+        assert!(windows(0x10000, 0x5000, 0x2000).eq(w));
+    }
+
+    #[test]
+    fn chunk_windows_unaligned() {
+        let w: [(usize, usize); 3] = [(0x10800, 0x12000), (0x12000, 0x14000), (0x14000, 0x14100)];
+        assert!(windows(0x10800, 0x3900, 0x2000).eq(w));
+    }
+
+    #[test]
+    fn chunk_windows_empty() {
+        assert_eq!(windows(0x10000, 0, PAGE_SIZE).count(), 0);
+    }
+}
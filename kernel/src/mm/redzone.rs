@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Hardened heap mode for testing.
+//!
+//! With the `heap-redzones` feature, every heap allocation is surrounded by
+//! redzones filled with a known pattern, which are checked when the
+//! allocation is freed to catch buffer overflows. Freed allocations are
+//! poisoned and kept in a quarantine instead of being reused right away.
+//! When an allocation leaves the quarantine its poison is checked, which
+//! catches writes after free, and freeing an allocation that is still in
+//! quarantine is reported as a double free. Violations cause a panic.
+//!
+//! The layout of an allocation is recorded in its front redzone, so that
+//! memory freed through the C allocator interface of the TPM libraries,
+//! which has no layout, can be returned with the layout it was allocated
+//! for (see [`layout_of()`]).
+//!
+//! This costs memory and time on every allocation, so it is only meant for
+//! test builds.
+
+use super::alloc::SvsmAllocator;
+use crate::address::VirtAddr;
+use core::alloc::Layout;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+
+/// Size of the redzone behind an allocation. The redzone in front of an
+/// allocation is as large as its alignment, but at least this size.
+pub const REDZONE_SIZE: usize = 32;
+/// Number of freed allocations held back from reuse
+pub const QUARANTINE_SLOTS: usize = 128;
+
+const FRONT_PATTERN: u8 = 0xfa;
+const BACK_PATTERN: u8 = 0xfb;
+/// Fill pattern of new allocations, to make reads of uninitialized memory
+/// stand out
+const ALLOC_PATTERN: u8 = 0xfc;
+const POISON_PATTERN: u8 = 0xfd;
+
+/// Offset of the layout header before the user memory. The bytes between
+/// the header and the user memory are part of the checked redzone, so
+/// small underflows are caught before they reach the header.
+const HEADER_OFFSET: usize = REDZONE_SIZE;
+/// The header holds the size and the alignment of the allocation
+const HEADER_SIZE: usize = 2 * size_of::<usize>();
+
+/// A detected heap corruption
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedzoneViolation {
+    /// The redzone in front of the allocation was modified at the given
+    /// offset before its start
+    Underflow(usize),
+    /// The redzone behind the allocation was modified at the given offset
+    /// after its end
+    Overflow(usize),
+    /// The freed allocation was modified at the given offset
+    UseAfterFree(usize),
+    /// The allocation was already freed
+    DoubleFree,
+    /// The allocation is freed with a different layout than the one it was
+    /// allocated for, or its header was overwritten
+    LayoutMismatch,
+}
+
+impl fmt::Display for RedzoneViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Underflow(off) => write!(f, "buffer underflow, {} bytes before start", off),
+            Self::Overflow(off) => write!(f, "buffer overflow, {} bytes after end", off),
+            Self::UseAfterFree(off) => write!(f, "write after free at offset {}", off),
+            Self::DoubleFree => write!(f, "double free"),
+            Self::LayoutMismatch => write!(f, "layout mismatch"),
+        }
+    }
+}
+
+/// Returns the layout of the allocation backing `layout` and the offset
+/// of the user memory within it.
+fn padded_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let front = layout.align().max(REDZONE_SIZE);
+    let size = front
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    let padded = Layout::from_size_align(size, layout.align()).ok()?;
+    Some((padded, front))
+}
+
+fn check_pattern(mem: &[u8], pattern: u8) -> Option<usize> {
+    mem.iter().position(|b| *b != pattern)
+}
+
+/// Checks the redzones of a live allocation, `front` bytes of which precede
+/// `size` bytes of user memory in `mem`. The layout header is skipped.
+fn check_redzones(mem: &[u8], front: usize, size: usize) -> Result<(), RedzoneViolation> {
+    let (head, rest) = mem.split_at(front);
+    let header = front - HEADER_OFFSET;
+    let (before, after) = head.split_at(header);
+    let after = &after[HEADER_SIZE..];
+    if let Some(pos) = after.iter().rposition(|b| *b != FRONT_PATTERN) {
+        return Err(RedzoneViolation::Underflow(after.len() - pos));
+    }
+    if let Some(pos) = before.iter().rposition(|b| *b != FRONT_PATTERN) {
+        return Err(RedzoneViolation::Underflow(front - pos));
+    }
+    match check_pattern(&rest[size..], BACK_PATTERN) {
+        Some(pos) => Err(RedzoneViolation::Overflow(pos)),
+        None => Ok(()),
+    }
+}
+
+/// Freed allocations which are not reused yet
+#[derive(Debug)]
+pub struct Quarantine {
+    slots: [Option<(VirtAddr, Layout)>; QUARANTINE_SLOTS],
+    next: usize,
+}
+
+impl Quarantine {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; QUARANTINE_SLOTS],
+            next: 0,
+        }
+    }
+
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.slots.iter().flatten().any(|(a, _)| *a == addr)
+    }
+
+    /// Adds a freed allocation and returns the oldest one if the
+    /// quarantine was full.
+    fn push(&mut self, entry: (VirtAddr, Layout)) -> Option<(VirtAddr, Layout)> {
+        let evicted = self.slots[self.next].replace(entry);
+        self.next = (self.next + 1) % QUARANTINE_SLOTS;
+        evicted
+    }
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must be a live allocation made by [`alloc()`] for `layout`.
+unsafe fn backing_memory<'a>(ptr: *mut u8, layout: Layout) -> (&'a mut [u8], Layout, usize) {
+    let (padded, front) = padded_layout(layout).expect("Invalid heap layout");
+    // SAFETY: alloc() returned `ptr` at offset `front` of an allocation of
+    // `padded`, which the caller still owns.
+    let mem = unsafe { slice::from_raw_parts_mut(ptr.sub(front), padded.size()) };
+    (mem, padded, front)
+}
+
+/// Allocates memory for `layout` surrounded by redzones.
+pub fn alloc(allocator: &SvsmAllocator, layout: Layout) -> *mut u8 {
+    let Some((padded, front)) = padded_layout(layout) else {
+        return ptr::null_mut();
+    };
+    let base = allocator.alloc_raw(padded);
+    if base.is_null() {
+        return base;
+    }
+    // SAFETY: `base` points to a fresh allocation of `padded.size()` bytes.
+    let mem = unsafe { slice::from_raw_parts_mut(base, padded.size()) };
+    let (head, rest) = mem.split_at_mut(front);
+    let (user, tail) = rest.split_at_mut(layout.size());
+    head.fill(FRONT_PATTERN);
+    write_header(&mut head[front - HEADER_OFFSET..], layout);
+    user.fill(ALLOC_PATTERN);
+    tail.fill(BACK_PATTERN);
+    user.as_mut_ptr()
+}
+
+fn write_header(mem: &mut [u8], layout: Layout) {
+    let (size, align) = mem[..HEADER_SIZE].split_at_mut(size_of::<usize>());
+    size.copy_from_slice(&layout.size().to_ne_bytes());
+    align.copy_from_slice(&layout.align().to_ne_bytes());
+}
+
+fn read_header(mem: &[u8]) -> Option<Layout> {
+    let (size, align) = mem[..HEADER_SIZE].split_at(size_of::<usize>());
+    let size = usize::from_ne_bytes(size.try_into().unwrap());
+    let align = usize::from_ne_bytes(align.try_into().unwrap());
+    Layout::from_size_align(size, align).ok()
+}
+
+/// Returns the layout recorded for an allocation by [`alloc()`], or `None`
+/// if the header was overwritten with an invalid layout.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation made by [`alloc()`].
+pub unsafe fn layout_of(ptr: *mut u8) -> Option<Layout> {
+    // SAFETY: the front redzone of the allocation is at least
+    // `HEADER_OFFSET` bytes large and holds the header at its end.
+    let header = unsafe { slice::from_raw_parts(ptr.sub(HEADER_OFFSET), HEADER_SIZE) };
+    read_header(header)
+}
+
+/// Checks the redzones of an allocation, poisons it and puts it into the
+/// quarantine. The allocation which falls out of the quarantine is checked
+/// for writes after free and returned to the allocator.
+///
+/// # Safety
+///
+/// `ptr` must have been returned by [`alloc()`] for the same `allocator` and
+/// `layout`.
+pub unsafe fn dealloc(allocator: &SvsmAllocator, ptr: *mut u8, layout: Layout) {
+    let addr = VirtAddr::from(ptr);
+    if allocator.quarantine.lock().contains(addr) {
+        report(addr, layout, RedzoneViolation::DoubleFree);
+    }
+
+    // SAFETY: the allocation is live, as it is not in the quarantine.
+    if unsafe { layout_of(ptr) } != Some(layout) {
+        report(addr, layout, RedzoneViolation::LayoutMismatch);
+    }
+    // SAFETY: the allocation is live and was made for `layout`.
+    let (mem, _, front) = unsafe { backing_memory(ptr, layout) };
+    if let Err(violation) = check_redzones(mem, front, layout.size()) {
+        report(addr, layout, violation);
+    }
+    mem[front..front + layout.size()].fill(POISON_PATTERN);
+
+    let evicted = allocator.quarantine.lock().push((addr, layout));
+    if let Some((addr, layout)) = evicted {
+        // SAFETY: quarantined allocations are still owned by the heap.
+        let (mem, padded, front) = unsafe { backing_memory(addr.as_mut_ptr(), layout) };
+        if let Some(pos) = check_pattern(&mem[front..front + layout.size()], POISON_PATTERN) {
+            report(addr, layout, RedzoneViolation::UseAfterFree(pos));
+        }
+        allocator.dealloc_raw(mem.as_mut_ptr(), padded);
+    }
+}
+
+fn report(addr: VirtAddr, layout: Layout, violation: RedzoneViolation) -> ! {
+    panic!(
+        "Heap corruption in allocation at {:#018x} ({} bytes): {}",
+        addr,
+        layout.size(),
+        violation
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+    #[test]
+    fn redzone_checks() {
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let (padded, front) = padded_layout(layout).unwrap();
+        assert_eq!(front, REDZONE_SIZE);
+        assert_eq!(padded.size(), 40 + 2 * REDZONE_SIZE);
+
+        let mut mem = [0u8; 40 + 2 * REDZONE_SIZE];
+        mem[..front].fill(FRONT_PATTERN);
+        write_header(&mut mem[front - HEADER_OFFSET..], layout);
+        mem[front + 40..].fill(BACK_PATTERN);
+        assert_eq!(check_redzones(&mem, front, 40), Ok(()));
+        assert_eq!(read_header(&mem[front - HEADER_OFFSET..]), Some(layout));
+
+        mem[front + 41] = 0;
+        assert_eq!(
+            check_redzones(&mem, front, 40),
+            Err(RedzoneViolation::Overflow(1))
+        );
+        mem[front + 41] = BACK_PATTERN;
+        mem[front - 2] = 0;
+        assert_eq!(
+            check_redzones(&mem, front, 40),
+            Err(RedzoneViolation::Underflow(2))
+        );
+
+        // Over-aligned allocations keep their alignment
+        let (_, front) = padded_layout(Layout::from_size_align(8, 256).unwrap()).unwrap();
+        assert_eq!(front, 256);
+    }
+
+    #[test]
+    fn quarantine() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let allocator = SvsmAllocator::new();
+        let layout = Layout::from_size_align(100, 16).unwrap();
+
+        let ptr = alloc(&allocator, layout);
+        assert!(!ptr.is_null());
+        // SAFETY: `ptr` is a live allocation for `layout`.
+        unsafe {
+            assert_eq!(layout_of(ptr), Some(layout));
+            ptr.write_bytes(0x42, layout.size());
+            dealloc(&allocator, ptr, layout);
+            let freed = slice::from_raw_parts(ptr, layout.size());
+            assert!(freed.iter().all(|b| *b == POISON_PATTERN));
+        }
+        assert!(allocator.quarantine.lock().contains(VirtAddr::from(ptr)));
+
+        // Filling the quarantine releases the first allocation
+        for _ in 0..QUARANTINE_SLOTS {
+            let p = alloc(&allocator, layout);
+            assert!(!p.is_null());
+            // SAFETY: `p` is a live allocation for `layout`.
+            unsafe { dealloc(&allocator, p, layout) };
+        }
+        assert!(!allocator.quarantine.lock().contains(VirtAddr::from(ptr)));
+    }
+}
@@ -0,0 +1,306 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Pool of pages shared with the host.
+//!
+//! Changing the visibility of a page takes a page state change request to
+//! the hypervisor and a global TLB flush, which is too expensive for buffers
+//! that are allocated on hot paths of host communication. The shared pool
+//! converts a range of pages to shared once at boot, and [`SharedBox`]
+//! allocations are carved out of it without further page state changes.
+//! Allocations are page granular, so no two of them share a page.
+//!
+//! If the pool is exhausted or not set up yet, [`SharedBox::try_new()`]
+//! falls back to converting freshly allocated pages, which are converted
+//! back when the box is dropped.
+//!
+//! The host can write to shared memory at any time, which plain loads and
+//! stores through Rust references do not allow for. Shared values are
+//! therefore limited to atomics and aggregates of atomics (see
+//! [`HostShared`]), and a [`SharedBox`] only hands out shared references.
+
+use crate::address::{PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::alloc::{allocate_pages, free_page, AllocError};
+use crate::mm::page_visibility::{make_region_private, make_region_shared};
+use crate::mm::virt_to_phys;
+use crate::types::PAGE_SIZE;
+use crate::utils::{zero_mem_region, Bitmap, ByteSize, MemoryRegion, PageCount};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, ManuallyDrop};
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize};
+
+/// Size of the pool if the configuration does not specify one
+pub const DEFAULT_SHARED_POOL_PAGES: usize = 16;
+/// Largest supported pool size
+pub const MAX_SHARED_POOL_PAGES: usize = 256;
+
+const BITMAP_WORDS: usize = MAX_SHARED_POOL_PAGES / 64;
+
+/// Types which can be placed in memory shared with the host.
+///
+/// # Safety
+///
+/// The host can write to shared memory at any time, so every bit pattern
+/// must be a valid value of the type, and the type must not contain pointers
+/// or references. An all-zero value must be valid as well. All of its
+/// memory must only be accessed with atomic operations, i.e. the type must
+/// consist of atomic integers only.
+pub unsafe trait HostShared {}
+
+macro_rules! impl_host_shared {
+    ($($ty:ty),*) => {
+        $(
+            // SAFETY: atomic integers are valid for any bit pattern and are
+            // only accessed with atomic operations.
+            unsafe impl HostShared for $ty {}
+        )*
+    };
+}
+
+impl_host_shared!(AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize);
+
+// SAFETY: an array is valid for any bit pattern if its elements are.
+unsafe impl<T: HostShared, const N: usize> HostShared for [T; N] {}
+
+/// Allocation state of the pages in the pool
+#[derive(Debug)]
+struct SharedPool {
+    base: VirtAddr,
+    pages: usize,
+    used: Bitmap<BITMAP_WORDS>,
+}
+
+impl SharedPool {
+    const fn empty() -> Self {
+        Self {
+            base: VirtAddr::null(),
+            pages: 0,
+            used: Bitmap::new(),
+        }
+    }
+
+    fn new(base: VirtAddr, pages: usize) -> Self {
+        assert!(pages <= MAX_SHARED_POOL_PAGES);
+        Self {
+            base,
+            pages,
+            used: Bitmap::new(),
+        }
+    }
+
+    /// Finds `count` free contiguous pages, first fit
+    fn allocate(&mut self, count: usize) -> Option<VirtAddr> {
+        let first = self.used.find_zero_range(count, self.pages)?;
+        self.used.set_range(first, count);
+        Some(self.base + first * PAGE_SIZE)
+    }
+
+    fn contains(&self, vaddr: VirtAddr) -> bool {
+        vaddr >= self.base && vaddr < self.base + self.pages * PAGE_SIZE
+    }
+
+    fn free(&mut self, vaddr: VirtAddr, count: usize) {
+        assert!(self.contains(vaddr));
+        let first = (vaddr - self.base) / PAGE_SIZE;
+        assert!(self.used.all_set(first, count));
+        self.used.clear_range(first, count);
+    }
+
+    fn free_pages(&self) -> usize {
+        self.pages - self.used.count_ones()
+    }
+}
+
+static SHARED_POOL: SpinLock<SharedPool> = SpinLock::new(SharedPool::empty());
+
+/// Converts the pages in `vaddr..vaddr + count * PAGE_SIZE` to shared.
+fn share_pages(vaddr: VirtAddr, count: usize) -> Result<(), SvsmError> {
+    make_region_shared(MemoryRegion::new(vaddr, count * PAGE_SIZE))
+}
+
+/// Set up the shared pool with at least `pages` pages, rounded up to a power
+/// of two. Must be called once, after page state changes are possible.
+pub fn shared_pool_init(pages: usize) -> Result<(), SvsmError> {
+    if pages == 0 || pages > MAX_SHARED_POOL_PAGES {
+        return Err(SvsmError::NotSupported);
+    }
+    if SHARED_POOL.lock().pages != 0 {
+        return Err(SvsmError::NotSupported);
+    }
+    let order = PageCount::new(pages)
+        .order()
+        .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))?;
+    let pages = 1usize << order.get();
+    let vaddr = allocate_pages(order)?;
+    if let Err(e) = share_pages(vaddr, pages) {
+        free_page(vaddr);
+        return Err(e);
+    }
+    // Shared pages have undefined contents after the page state change.
+    zero_mem_region(vaddr, vaddr + pages * PAGE_SIZE);
+
+    *SHARED_POOL.lock() = SharedPool::new(vaddr, pages);
+    log::info!("Shared memory pool of {} pages at {:#x}", pages, vaddr);
+    Ok(())
+}
+
+/// Number of pages currently free in the shared pool
+pub fn shared_pool_free_pages() -> usize {
+    SHARED_POOL.lock().free_pages()
+}
+
+/// An owned value of type `T` in memory shared with the host, see the
+/// [module documentation](self).
+pub struct SharedBox<T: HostShared> {
+    ptr: NonNull<T>,
+    pages: usize,
+    pooled: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: HostShared> SharedBox<T> {
+    /// Number of pages holding a `T`
+    fn pages() -> Result<usize, SvsmError> {
+        if align_of::<T>() > PAGE_SIZE {
+            return Err(SvsmError::NotSupported);
+        }
+        Ok(ByteSize::new(size_of::<T>()).pages_up().get().max(1))
+    }
+
+    /// Allocates pages outside of the pool and converts them to shared
+    fn allocate_unpooled(pages: usize) -> Result<VirtAddr, SvsmError> {
+        let order = PageCount::new(pages)
+            .order()
+            .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))?;
+        let vaddr = allocate_pages(order)?;
+        if let Err(e) = share_pages(vaddr, pages) {
+            free_page(vaddr);
+            return Err(e);
+        }
+        Ok(vaddr)
+    }
+
+    /// Moves `x` into shared pages, taken from the pool if possible.
+    pub fn try_new(x: T) -> Result<Self, SvsmError> {
+        let pages = Self::pages()?;
+        let pooled = SHARED_POOL.lock().allocate(pages);
+        let vaddr = match pooled {
+            Some(vaddr) => vaddr,
+            None => Self::allocate_unpooled(pages)?,
+        };
+        // Pooled pages contain data of their previous user and unpooled
+        // ones are undefined after the page state change.
+        zero_mem_region(vaddr, vaddr + pages * PAGE_SIZE);
+
+        let ptr = vaddr.as_mut_ptr::<T>();
+        // SAFETY: the pages are large enough for a `T` and suitably aligned,
+        // as they are page aligned and `T` does not need more than that.
+        unsafe { ptr.write(x) };
+        Ok(Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            pages,
+            pooled: pooled.is_some(),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Virtual address of the value
+    pub fn vaddr(&self) -> VirtAddr {
+        VirtAddr::from(self.ptr.as_ptr().cast::<u8>())
+    }
+
+    /// Guest physical address of the value, to be passed to the host
+    pub fn paddr(&self) -> PhysAddr {
+        virt_to_phys(self.vaddr())
+    }
+
+    /// Returns whether the value lives in the shared pool
+    pub fn is_pooled(&self) -> bool {
+        self.pooled
+    }
+
+    /// Consumes the box without releasing its pages, returning a reference
+    /// to the value which is valid for the rest of the SVSM's lifetime.
+    pub fn leak(b: Self) -> &'static T {
+        let b = ManuallyDrop::new(b);
+        // SAFETY: the pages are never released, so the value lives forever.
+        unsafe { b.ptr.as_ref() }
+    }
+}
+
+impl<T: HostShared> Deref for SharedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the pointer is valid for as long as the box exists, any
+        // value the host may have written is a valid `T`, and `T` is only
+        // accessed atomically, so concurrent host writes are allowed.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: HostShared> Drop for SharedBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the value is initialized and dropped only once.
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        let vaddr = self.vaddr();
+        if self.pooled {
+            SHARED_POOL.lock().free(vaddr, self.pages);
+            return;
+        }
+        make_region_private(MemoryRegion::new(vaddr, self.pages * PAGE_SIZE))
+            .expect("Failed to restore page visibility");
+        free_page(vaddr);
+    }
+}
+
+impl<T: HostShared + fmt::Debug> fmt::Debug for SharedBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// SAFETY: a SharedBox owns its value exclusively, like a Box.
+unsafe impl<T: HostShared + Send> Send for SharedBox<T> {}
+// SAFETY: a SharedBox only hands out shared references through &self.
+unsafe impl<T: HostShared + Sync> Sync for SharedBox<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_allocation() {
+        // The pool only tracks addresses, so no memory is needed.
+        let base = VirtAddr::from(0x1000_0000u64);
+        let mut pool = SharedPool::new(base, 8);
+        let a = pool.allocate(2).unwrap();
+        let b = pool.allocate(3).unwrap();
+        assert_eq!(a, base);
+        assert_eq!(b, base + 2 * PAGE_SIZE);
+        assert!(pool.allocate(4).is_none());
+        assert_eq!(pool.free_pages(), 3);
+
+        // Freed pages are reused, first fit
+        pool.free(a, 2);
+        assert_eq!(pool.allocate(1), Some(base));
+        assert_eq!(pool.allocate(2), Some(base + 5 * PAGE_SIZE));
+        assert_eq!(pool.allocate(1), Some(base + PAGE_SIZE));
+        assert_eq!(pool.free_pages(), 0);
+        assert!(pool.contains(base + 7 * PAGE_SIZE));
+        assert!(!pool.contains(base + 8 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn empty_pool() {
+        let mut pool = SharedPool::empty();
+        assert!(pool.allocate(1).is_none());
+        assert_eq!(pool.free_pages(), 0);
+    }
+}
@@ -15,7 +15,7 @@ use crate::mm::{
     STACK_PAGES, STACK_SIZE, STACK_TOTAL_SIZE, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END,
 };
 use crate::types::PAGE_SIZE;
-use crate::utils::MemoryRegion;
+use crate::utils::{IdAlloc, MemoryRegion};
 
 // Limit maximum number of stacks for now, address range support 2**16 8k stacks
 const MAX_STACKS: usize = 1024;
@@ -24,7 +24,7 @@ const BMP_QWORDS: usize = MAX_STACKS / 64;
 #[derive(Debug)]
 struct StackRange {
     region: MemoryRegion<VirtAddr>,
-    alloc_bitmap: [u64; BMP_QWORDS],
+    alloc_bitmap: IdAlloc<BMP_QWORDS>,
 }
 
 impl StackRange {
@@ -32,27 +32,13 @@ impl StackRange {
         let region = MemoryRegion::from_addresses(start, end);
         StackRange {
             region,
-            alloc_bitmap: [0; BMP_QWORDS],
+            alloc_bitmap: IdAlloc::new(),
         }
     }
 
     pub fn alloc(&mut self) -> Result<VirtAddr, SvsmError> {
-        for i in 0..BMP_QWORDS {
-            let val = !self.alloc_bitmap[i];
-            let idx = val.trailing_zeros() as usize;
-
-            if idx >= 64 {
-                continue;
-            }
-
-            let mask = 1u64 << idx;
-
-            self.alloc_bitmap[i] |= mask;
-
-            return Ok(self.region.start() + ((i * 64 + idx) * STACK_TOTAL_SIZE));
-        }
-
-        Err(SvsmError::Mem)
+        let idx = self.alloc_bitmap.alloc().ok_or(SvsmError::Mem)?;
+        Ok(self.region.start() + (idx * STACK_TOTAL_SIZE))
     }
 
     pub fn dealloc(&mut self, stack: VirtAddr) {
@@ -64,13 +50,7 @@ impl StackRange {
         assert!((offset % (STACK_TOTAL_SIZE)) <= STACK_SIZE);
         assert!(idx < MAX_STACKS);
 
-        let i = idx / 64;
-        let bit = idx % 64;
-        let mask = 1u64 << bit;
-
-        assert_eq!((self.alloc_bitmap[i] & mask), mask);
-
-        self.alloc_bitmap[i] &= !mask;
+        self.alloc_bitmap.free(idx);
     }
 }
 
@@ -10,15 +10,15 @@ use crate::locking::SpinLock;
 use crate::mm::alloc::{allocate_pages, get_order};
 use crate::mm::virt_to_phys;
 use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::MemoryRegion;
+use crate::utils::{ByteSize, MemoryRegion, PageOrder};
 use core::ptr;
 
 static VALID_BITMAP: SpinLock<ValidBitmap> = SpinLock::new(ValidBitmap::new());
 
 #[inline(always)]
-fn bitmap_alloc_order(region: MemoryRegion<PhysAddr>) -> usize {
-    let mem_size = region.len() / (PAGE_SIZE * 8);
-    get_order(mem_size)
+fn bitmap_alloc_order(region: MemoryRegion<PhysAddr>) -> PageOrder {
+    let mem_size = ByteSize::new(region.len() / (PAGE_SIZE * 8));
+    get_order(mem_size).expect("Valid bitmap size out of range")
 }
 
 pub fn init_valid_bitmap_ptr(region: MemoryRegion<PhysAddr>, bitmap: *mut u64) {
@@ -28,7 +28,7 @@ pub fn init_valid_bitmap_ptr(region: MemoryRegion<PhysAddr>, bitmap: *mut u64) {
 }
 
 pub fn init_valid_bitmap_alloc(region: MemoryRegion<PhysAddr>) -> Result<(), SvsmError> {
-    let order: usize = bitmap_alloc_order(region);
+    let order = bitmap_alloc_order(region);
     let bitmap_addr = allocate_pages(order)?;
 
     let mut vb_ref = VALID_BITMAP.lock();
@@ -40,7 +40,7 @@ pub fn init_valid_bitmap_alloc(region: MemoryRegion<PhysAddr>) -> Result<(), Svs
 }
 
 pub fn migrate_valid_bitmap() -> Result<(), SvsmError> {
-    let order: usize = VALID_BITMAP.lock().alloc_order();
+    let order = VALID_BITMAP.lock().alloc_order();
     let bitmap_addr = allocate_pages(order)?;
 
     // lock again here because allocator path also takes VALID_BITMAP.lock()
@@ -141,7 +141,7 @@ impl ValidBitmap {
         }
     }
 
-    fn alloc_order(&self) -> usize {
+    fn alloc_order(&self) -> PageOrder {
         bitmap_alloc_order(self.region)
     }
 
@@ -8,35 +8,67 @@ use crate::address::VirtAddr;
 use crate::cpu::percpu::this_cpu;
 use crate::error::SvsmError;
 use crate::types::{PAGE_SHIFT, PAGE_SHIFT_2M, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::bitmap_allocator::{BitmapAllocator, BitmapAllocator1024};
-use crate::utils::MemoryRegion;
+use crate::utils::{align_up, MemoryRegion};
 use core::fmt::Debug;
 
-use super::{
-    SVSM_PERCPU_TEMP_BASE_2M, SVSM_PERCPU_TEMP_BASE_4K, SVSM_PERCPU_TEMP_END_2M,
-    SVSM_PERCPU_TEMP_END_4K,
-};
-
 pub const VIRT_ALIGN_4K: usize = PAGE_SHIFT - 12;
 pub const VIRT_ALIGN_2M: usize = PAGE_SHIFT_2M - 12;
 
-#[derive(Debug, Default)]
+/// Maximum number of free extents in a [`VirtualRange`]
+const MAX_FREE_EXTENTS: usize = 64;
+
+/// A run of free pages
+#[derive(Clone, Copy, Debug)]
+struct FreeExtent {
+    start: usize,
+    len: usize,
+}
+
+impl FreeExtent {
+    const fn empty() -> Self {
+        Self { start: 0, len: 0 }
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+/// Allocator for a range of virtual pages. Free space is tracked as
+/// extents in a fixed-size array sorted by start, so the allocator never
+/// needs to allocate memory itself.
+///
+/// Free extents are separated by allocated pages, so there is at most one
+/// more free extent than there are live allocations. Limiting the number
+/// of live allocations to `MAX_FREE_EXTENTS - 1` hence guarantees that
+/// freeing never runs out of extents.
+#[derive(Debug)]
 pub struct VirtualRange {
     start_virt: VirtAddr,
     page_count: usize,
     page_shift: usize,
-    bits: BitmapAllocator1024,
+    used: usize,
+    allocations: usize,
+    nr_extents: usize,
+    extents: [FreeExtent; MAX_FREE_EXTENTS],
 }
 
-impl VirtualRange {
-    pub const CAPACITY: usize = BitmapAllocator1024::CAPACITY;
+impl Default for VirtualRange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl VirtualRange {
     pub const fn new() -> VirtualRange {
         VirtualRange {
             start_virt: VirtAddr::null(),
             page_count: 0,
             page_shift: PAGE_SHIFT,
-            bits: BitmapAllocator1024::new(),
+            used: 0,
+            allocations: 0,
+            nr_extents: 0,
+            extents: [FreeExtent::empty(); MAX_FREE_EXTENTS],
         }
     }
 
@@ -44,39 +76,134 @@ impl VirtualRange {
         self.start_virt = start_virt;
         self.page_count = page_count;
         self.page_shift = page_shift;
-        self.bits.set(0, page_count, false);
+        self.used = 0;
+        self.allocations = 0;
+        self.nr_extents = 0;
+        self.insert_extent(0, 0, page_count);
+    }
+
+    fn free_extents(&self) -> &[FreeExtent] {
+        &self.extents[..self.nr_extents]
+    }
+
+    /// Inserts a free extent of `len` pages at `start` as the extent with
+    /// index `index`.
+    fn insert_extent(&mut self, index: usize, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        assert!(self.nr_extents < MAX_FREE_EXTENTS);
+        self.extents.copy_within(index..self.nr_extents, index + 1);
+        self.extents[index] = FreeExtent { start, len };
+        self.nr_extents += 1;
+    }
+
+    fn remove_extent(&mut self, index: usize) {
+        self.extents.copy_within(index + 1..self.nr_extents, index);
+        self.nr_extents -= 1;
+    }
+
+    /// Marks `count` pages at page offset `start` as used, splitting the
+    /// free extent with index `index` which contains them.
+    fn carve(&mut self, index: usize, start: usize, count: usize) {
+        let extent = self.extents[index];
+        self.remove_extent(index);
+        self.insert_extent(index, start + count, extent.end() - (start + count));
+        self.insert_extent(index, extent.start, start - extent.start);
+        self.used += count;
+        self.allocations += 1;
     }
 
+    /// Allocates `page_count` pages whose page offset in the range is
+    /// aligned to `1 << alignment` pages.
     pub fn alloc(&mut self, page_count: usize, alignment: usize) -> Result<VirtAddr, SvsmError> {
+        if alignment >= usize::BITS as usize || self.allocations >= MAX_FREE_EXTENTS - 1 {
+            return Err(SvsmError::Mem);
+        }
+        let align = 1usize << alignment;
         // Always reserve an extra page to leave a guard between virtual memory allocations
-        match self.bits.alloc(page_count + 1, alignment) {
-            Some(offset) => Ok(self.start_virt + (offset << self.page_shift)),
-            None => Err(SvsmError::Mem),
+        let count = page_count + 1;
+
+        // Best fit: the smallest extent which can hold the allocation once
+        // aligned, the lowest one if several are equally small.
+        let (index, start) = self
+            .free_extents()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, extent)| {
+                let start = align_up(extent.start, align);
+                (start + count <= extent.end()).then_some((i, start))
+            })
+            .min_by_key(|&(i, _)| self.extents[i].len)
+            .ok_or(SvsmError::Mem)?;
+
+        self.carve(index, start, count);
+        Ok(self.start_virt + (start << self.page_shift))
+    }
+
+    /// Reserves `page_count` pages at `vaddr`, plus the guard page after
+    /// them, like [`VirtualRange::alloc()`]. Fails if any of the pages is
+    /// already in use.
+    pub fn reserve(&mut self, vaddr: VirtAddr, page_count: usize) -> Result<(), SvsmError> {
+        if vaddr < self.start_virt || self.allocations >= MAX_FREE_EXTENTS - 1 {
+            return Err(SvsmError::Mem);
         }
+        let start = (vaddr - self.start_virt) >> self.page_shift;
+        let count = page_count + 1;
+        let index = self
+            .free_extents()
+            .partition_point(|extent| extent.start <= start)
+            .checked_sub(1)
+            .filter(|&i| self.extents[i].end() >= start + count)
+            .ok_or(SvsmError::Mem)?;
+        self.carve(index, start, count);
+        Ok(())
     }
 
     pub fn free(&mut self, vaddr: VirtAddr, page_count: usize) {
         let offset = (vaddr - self.start_virt) >> self.page_shift;
         // Add 1 to the page count for the VM guard
-        self.bits.free(offset, page_count + 1);
+        let count = page_count + 1;
+        assert!(offset + count <= self.page_count);
+
+        let mut start = offset;
+        let mut end = offset + count;
+        // Index of the first free extent after the freed pages
+        let mut index = self
+            .free_extents()
+            .partition_point(|extent| extent.start < offset);
+        if let Some(next) = self.free_extents().get(index).copied() {
+            assert!(next.start >= end, "Freeing free virtual range");
+            if next.start == end {
+                self.remove_extent(index);
+                end = next.end();
+            }
+        }
+        if let Some(prev) = index.checked_sub(1).map(|i| self.extents[i]) {
+            assert!(prev.end() <= offset, "Freeing free virtual range");
+            if prev.end() == offset {
+                index -= 1;
+                self.remove_extent(index);
+                start = prev.start;
+            }
+        }
+
+        self.insert_extent(index, start, end - start);
+        self.used -= count;
+        self.allocations -= 1;
     }
 
     pub fn used_pages(&self) -> usize {
-        self.bits.used()
+        self.used
     }
 }
 
 pub fn virt_log_usage() {
-    let page_count4k = (SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE;
-    let page_count2m = (SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M) / PAGE_SIZE_2M;
-    let unused_cap_4k = BitmapAllocator1024::CAPACITY - page_count4k;
-    let unused_cap_2m = BitmapAllocator1024::CAPACITY - page_count2m;
-
     log::info!(
         "[CPU {}] Virtual memory pages used: {} * 4K, {} * 2M",
         this_cpu().get_apic_id(),
-        this_cpu().vrange_4k.borrow().used_pages() - unused_cap_4k,
-        this_cpu().vrange_2m.borrow().used_pages() - unused_cap_2m
+        this_cpu().vrange_4k.borrow().used_pages(),
+        this_cpu().vrange_2m.borrow().used_pages()
     );
 }
 
@@ -84,7 +211,6 @@ pub fn virt_alloc_range_4k(
     size_bytes: usize,
     alignment: usize,
 ) -> Result<MemoryRegion<VirtAddr>, SvsmError> {
-    // Each bit in our bitmap represents a 4K page
     if (size_bytes & (PAGE_SIZE - 1)) != 0 {
         return Err(SvsmError::Mem);
     }
@@ -107,7 +233,6 @@ pub fn virt_alloc_range_2m(
     size_bytes: usize,
     alignment: usize,
 ) -> Result<MemoryRegion<VirtAddr>, SvsmError> {
-    // Each bit in our bitmap represents a 2M page
     if (size_bytes & (PAGE_SIZE_2M - 1)) != 0 {
         return Err(SvsmError::Mem);
     }
@@ -128,7 +253,7 @@ pub fn virt_free_range_2m(vregion: MemoryRegion<VirtAddr>) {
 
 #[cfg(test)]
 mod tests {
-    use super::VirtualRange;
+    use super::{VirtualRange, MAX_FREE_EXTENTS};
     use crate::address::VirtAddr;
     use crate::types::{PAGE_SHIFT, PAGE_SHIFT_2M, PAGE_SIZE, PAGE_SIZE_2M};
 
@@ -206,4 +331,70 @@ mod tests {
         );
         assert_eq!(range.used_pages(), 0);
     }
+
+    #[test]
+    fn test_alloc_aligned() {
+        let mut range = VirtualRange::new();
+        range.init(VirtAddr::new(0x1000000), 1024, PAGE_SHIFT);
+
+        let v1 = range.alloc(1, 0).unwrap();
+        assert_eq!(v1, VirtAddr::new(0x1000000));
+        // The next 16-page aligned offset is 16
+        let v2 = range.alloc(1, 4).unwrap();
+        assert_eq!(v2, VirtAddr::new(0x1000000 + 16 * PAGE_SIZE));
+        // The gap between both allocations is used by a fitting request
+        let v3 = range.alloc(4, 0).unwrap();
+        assert_eq!(v3, VirtAddr::new(0x1000000 + 2 * PAGE_SIZE));
+        assert_eq!(range.used_pages(), 9);
+
+        let v4 = range.alloc(1, 9).unwrap();
+        assert_eq!(v4, VirtAddr::new(0x1000000 + 512 * PAGE_SIZE));
+        assert!(range.alloc(1, 9).is_err());
+        assert!(range.alloc(1, 64).is_err());
+    }
+
+    #[test]
+    fn test_alloc_limit() {
+        let mut range = VirtualRange::new();
+        range.init(VirtAddr::new(0x1000000), 1024, PAGE_SHIFT);
+
+        // Every other allocation is freed, leaving a free extent each
+        let addrs: [VirtAddr; MAX_FREE_EXTENTS - 1] =
+            core::array::from_fn(|_| range.alloc(1, 0).unwrap());
+        assert!(range.alloc(1, 0).is_err());
+        for addr in addrs.iter().step_by(2) {
+            range.free(*addr, 1);
+        }
+        for addr in addrs.iter().skip(1).step_by(2) {
+            range.free(*addr, 1);
+        }
+        assert_eq!(range.used_pages(), 0);
+        assert_eq!(range.free_extents().len(), 1);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut range = VirtualRange::new();
+        range.init(VirtAddr::new(0x1000000), 64, PAGE_SHIFT);
+
+        let fixed = VirtAddr::new(0x1000000 + 8 * PAGE_SIZE);
+        range.reserve(fixed, 4).unwrap();
+        assert!(range.reserve(fixed, 1).is_err());
+        assert!(range
+            .reserve(VirtAddr::new(0x1000000 + 60 * PAGE_SIZE), 4)
+            .is_err());
+
+        // Allocations do not overlap the reserved range or its guard page
+        let v1 = range.alloc(8, 0).unwrap();
+        assert_eq!(v1, VirtAddr::new(0x1000000 + 13 * PAGE_SIZE));
+        let v2 = range.alloc(4, 0).unwrap();
+        assert_eq!(v2, VirtAddr::new(0x1000000));
+
+        // Freeing everything merges the free space into a single extent
+        range.free(fixed, 4);
+        range.free(v1, 8);
+        range.free(v2, 4);
+        assert_eq!(range.used_pages(), 0);
+        assert!(range.alloc(63, 0).is_ok());
+    }
 }
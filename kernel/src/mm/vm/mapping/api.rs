@@ -11,6 +11,8 @@ use crate::mm::pagetable::PTEntryFlags;
 use crate::mm::vm::VMR;
 use crate::types::{PageSize, PAGE_SHIFT};
 
+use super::VMCow;
+
 use intrusive_collections::rbtree::Link;
 use intrusive_collections::{intrusive_adapter, KeyAdapter};
 
@@ -112,8 +114,22 @@ pub trait VirtualMapping: core::fmt::Debug {
         false
     }
 
+    /// Request whether the mapping maps pages on demand, from
+    /// [`VirtualMapping::handle_page_fault()`]. Page faults on other mappings
+    /// are not resolved, which lets [`VMR::handle_page_fault()`] reject them
+    /// without taking the write lock of the mapping.
+    ///
+    /// # Returns
+    ///
+    /// * `True` - When page faults on the mapping may be resolved
+    /// * `False` - When page faults on the mapping are always errors
+    fn maps_on_demand(&self) -> bool {
+        false
+    }
+
     /// Handle a page fault that occurred on a virtual memory address within
-    /// this mapping.
+    /// this mapping. Only called if [`VirtualMapping::maps_on_demand()`]
+    /// returns `true`.
     ///
     /// # Arguments
     ///
@@ -134,6 +150,18 @@ pub trait VirtualMapping: core::fmt::Debug {
     ) -> Result<VMPageFaultResolution, SvsmError> {
         Err(SvsmError::Mem)
     }
+
+    /// Create a copy-on-write clone of this mapping. The backing pages are
+    /// shared with the returned [`VMCow`] and must no longer be written
+    /// through this mapping; [`Mapping::clone_cow()`] takes care of that.
+    ///
+    /// # Returns
+    ///
+    /// The clone on success, `Err(SvsmError::NotSupported)` if the mapping
+    /// cannot be cloned.
+    fn clone_cow(&mut self) -> Result<VMCow, SvsmError> {
+        Err(SvsmError::NotSupported)
+    }
 }
 
 #[derive(Debug)]
@@ -161,6 +189,40 @@ impl Mapping {
     pub fn get_mut(&self) -> WriteLockGuard<'_, Box<dyn VirtualMapping>> {
         self.mapping.lock_write()
     }
+
+    /// Create a copy-on-write snapshot of this mapping. Afterwards both
+    /// this mapping and the returned one map the same pages read-only, and
+    /// a write fault in either gives it a private copy of the page.
+    ///
+    /// This does not update page-table entries which already map this
+    /// mapping. Mappings inserted into a [`VMR`] are snapshotted with
+    /// [`VMR::snapshot()`], which does.
+    ///
+    /// # Returns
+    ///
+    /// The snapshot on success, `Err(SvsmError::NotSupported)` if the
+    /// mapping does not support copy-on-write, or an allocation error.
+    pub(crate) fn clone_cow(&self) -> Result<Mapping, SvsmError> {
+        let mut mapping = self.get_mut();
+        let snapshot = mapping.clone_cow()?;
+        let source = mapping.clone_cow()?;
+        *mapping = Box::new(source);
+        Ok(Mapping::new(snapshot))
+    }
+
+    /// Replace the contents of this mapping with a copy-on-write clone of
+    /// `snapshot`, which stays usable for further restores. Like
+    /// [`Mapping::clone_cow()`], this does not update page-table entries;
+    /// use [`VMR::rollback()`] for mappings inserted into a [`VMR`].
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an allocation error.
+    pub(crate) fn restore(&self, snapshot: &Mapping) -> Result<(), SvsmError> {
+        let copy = snapshot.get_mut().clone_cow()?;
+        *self.get_mut() = Box::new(copy);
+        Ok(())
+    }
 }
 
 /// A single mapping of virtual memory in a virtual memory range
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+use crate::address::PhysAddr;
+use crate::error::SvsmError;
+use crate::mm::alloc::PageRef;
+use crate::mm::pagetable::PTEntryFlags;
+use crate::mm::vm::VMR;
+use crate::types::{PAGE_SHIFT, PAGE_SIZE};
+use crate::utils::TryVec;
+
+use super::{VMPageFaultResolution, VirtualMapping};
+
+/// A page of a copy-on-write mapping
+#[derive(Debug)]
+struct CowPage {
+    page: PageRef,
+    /// Whether the page may be shared with another mapping and must be
+    /// copied before it is written to
+    cow: bool,
+}
+
+/// Copy-on-write mapping created by [`Mapping::clone_cow()`](super::Mapping::clone_cow).
+///
+/// The backing pages are shared with the mapping the clone was made from
+/// and are mapped read-only. A write fault on such a page replaces it with
+/// a private copy, which is then mapped with the original flags.
+#[derive(Debug)]
+pub struct VMCow {
+    pages: TryVec<CowPage>,
+    /// Page-table flags for pages which are not shared
+    flags: PTEntryFlags,
+}
+
+impl VMCow {
+    /// Creates a copy-on-write mapping of `pages`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pages` - References to the backing pages, one per page of the mapping
+    /// * `flags` - Page-table flags to use once a page has been copied
+    pub fn new(pages: TryVec<PageRef>, flags: PTEntryFlags) -> Result<Self, SvsmError> {
+        let mut entries = TryVec::with_capacity(pages.len())?;
+        for page in pages.into_inner() {
+            entries.try_push(CowPage { page, cow: true })?;
+        }
+        Ok(Self {
+            pages: entries,
+            flags,
+        })
+    }
+
+    /// Returns references to all backing pages and marks them as shared, so
+    /// that the next write to any of them copies the page.
+    pub fn share_pages(&mut self) -> Result<TryVec<PageRef>, SvsmError> {
+        let mut refs = TryVec::with_capacity(self.pages.len())?;
+        for entry in self.pages.iter_mut() {
+            refs.try_push(entry.page.clone())?;
+            entry.cow = true;
+        }
+        Ok(refs)
+    }
+}
+
+impl VirtualMapping for VMCow {
+    fn mapping_size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    fn map(&self, offset: usize) -> Option<PhysAddr> {
+        self.pages
+            .get(offset >> PAGE_SHIFT)
+            .map(|entry| entry.page.phys_addr())
+    }
+
+    fn pt_flags(&self, offset: usize) -> PTEntryFlags {
+        match self.pages.get(offset >> PAGE_SHIFT) {
+            Some(entry) if entry.cow => self.flags - PTEntryFlags::WRITABLE,
+            _ => self.flags,
+        }
+    }
+
+    fn maps_on_demand(&self) -> bool {
+        // Write faults on shared pages are resolved by copying them
+        true
+    }
+
+    fn handle_page_fault(
+        &mut self,
+        _vmr: &VMR,
+        offset: usize,
+        write: bool,
+    ) -> Result<VMPageFaultResolution, SvsmError> {
+        let entry = self
+            .pages
+            .get_mut(offset >> PAGE_SHIFT)
+            .ok_or(SvsmError::Mem)?;
+
+        if !write || !entry.cow || !self.flags.contains(PTEntryFlags::WRITABLE) {
+            return Err(SvsmError::Mem);
+        }
+
+        entry.page = entry.page.try_copy_page()?;
+        entry.cow = false;
+
+        Ok(VMPageFaultResolution {
+            paddr: entry.page.phys_addr(),
+            flags: self.flags,
+        })
+    }
+
+    fn clone_cow(&mut self) -> Result<VMCow, SvsmError> {
+        VMCow::new(self.share_pages()?, self.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use crate::cpu::percpu::this_cpu;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+    use crate::mm::vm::{Mapping, VMFileMappingFlags, VMReserved, VMalloc};
+    use crate::mm::{SVSM_PERCPU_BASE, SVSM_PERCPU_END};
+    use alloc::sync::Arc;
+
+    #[test]
+    #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+    fn cow_write_fault_copies() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let vmr = VMR::new(SVSM_PERCPU_BASE, SVSM_PERCPU_END, PTEntryFlags::empty());
+
+        let mapping = VMalloc::new_mapping(2 * PAGE_SIZE, VMFileMappingFlags::Write).unwrap();
+        let paddr = mapping.get().map(0).unwrap();
+        let snapshot = mapping.clone_cow().unwrap();
+
+        // Both mappings share the pages read-only
+        for m in [&mapping, &snapshot] {
+            let m = m.get();
+            assert_eq!(m.map(0), Some(paddr));
+            assert!(!m.pt_flags(0).contains(PTEntryFlags::WRITABLE));
+        }
+
+        // Reads are not resolved by copying
+        assert!(snapshot
+            .get_mut()
+            .handle_page_fault(&vmr, 0, false)
+            .is_err());
+
+        // A write gives the snapshot its own writable copy
+        let res = snapshot.get_mut().handle_page_fault(&vmr, 0, true).unwrap();
+        assert_ne!(res.paddr, paddr);
+        assert!(res.flags.contains(PTEntryFlags::WRITABLE));
+        assert_eq!(snapshot.get().map(0), Some(res.paddr));
+        assert!(snapshot.get().pt_flags(0).contains(PTEntryFlags::WRITABLE));
+
+        // The other page and the source mapping are unchanged
+        assert!(!snapshot
+            .get()
+            .pt_flags(PAGE_SIZE)
+            .contains(PTEntryFlags::WRITABLE));
+        assert_eq!(mapping.get().map(0), Some(paddr));
+
+        // A second write fault on the copied page is not a COW fault
+        assert!(snapshot.get_mut().handle_page_fault(&vmr, 0, true).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(test_in_svsm, ignore = "Offline testing")]
+    fn cow_read_only_source() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let vmr = VMR::new(SVSM_PERCPU_BASE, SVSM_PERCPU_END, PTEntryFlags::empty());
+
+        let mapping = VMalloc::new_mapping(PAGE_SIZE, VMFileMappingFlags::Read).unwrap();
+        let snapshot = mapping.clone_cow().unwrap();
+
+        // Writes to a read-only mapping are never resolved
+        assert!(snapshot.get_mut().handle_page_fault(&vmr, 0, true).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn cow_snapshot_rollback() {
+        let mapping = VMalloc::new_mapping(PAGE_SIZE, VMFileMappingFlags::Write).unwrap();
+        let guard = this_cpu().new_mapping(Arc::new(mapping)).unwrap();
+        let ptr = guard.virt_addr().as_mut_ptr::<u64>();
+
+        // SAFETY: `ptr` points to the first page of the mapping, which
+        // stays mapped as long as `guard` exists. Writes to shared pages
+        // are resolved by the copy-on-write fault handler.
+        unsafe {
+            ptr.write_volatile(0x5a5a);
+            let snapshot = guard.snapshot().unwrap();
+            ptr.write_volatile(0xa5a5);
+            assert_eq!(ptr.read_volatile(), 0xa5a5);
+
+            guard.rollback(&snapshot).unwrap();
+            assert_eq!(ptr.read_volatile(), 0x5a5a);
+
+            // The snapshot survives a rollback and can be restored again
+            ptr.write_volatile(0xa5a5);
+            guard.rollback(&snapshot).unwrap();
+            assert_eq!(ptr.read_volatile(), 0x5a5a);
+        }
+    }
+
+    #[test]
+    fn cow_not_supported() {
+        let mapping = VMReserved::new_mapping(PAGE_SIZE);
+        assert!(matches!(mapping.clone_cow(), Err(SvsmError::NotSupported)));
+    }
+}
@@ -71,12 +71,9 @@ impl VMKernelStack {
     pub fn new_size(size: usize) -> Result<Self, SvsmError> {
         // Make sure size is page-aligned
         let size = page_align_up(size);
-        // At least two guard-pages needed
-        let total_size = (size + 2 * PAGE_SIZE).next_power_of_two();
-        let guard_pages = ((total_size - size) >> PAGE_SHIFT) / 2;
         let mut stack = VMKernelStack {
             alloc: RawAllocMapping::new(size),
-            guard_pages,
+            guard_pages: Self::guard_pages(size),
         };
         stack.alloc_pages()?;
 
@@ -107,6 +104,26 @@ impl VMKernelStack {
     fn alloc_pages(&mut self) -> Result<(), SvsmError> {
         self.alloc.alloc_pages()
     }
+
+    /// Number of guard pages on each side of a stack of `size` bytes, which
+    /// must be page-aligned
+    const fn guard_pages(size: usize) -> usize {
+        // At least two guard-pages needed
+        let total_size = (size + 2 * PAGE_SIZE).next_power_of_two();
+        ((total_size - size) >> PAGE_SHIFT) / 2
+    }
+}
+
+/// Returns whether `vaddr` lies in the guard pages below a kernel stack,
+/// i.e. whether an access to it is a stack overflow.
+///
+/// # Arguments
+///
+/// * `bounds` - Bounds of the stack as returned by [`VMKernelStack::bounds()`]
+/// * `vaddr` - Faulting address
+pub fn in_stack_guard(bounds: MemoryRegion<VirtAddr>, vaddr: VirtAddr) -> bool {
+    let guard_size = VMKernelStack::guard_pages(bounds.len()) << PAGE_SHIFT;
+    vaddr < bounds.start() && bounds.start() - vaddr <= guard_size
 }
 
 impl VirtualMapping for VMKernelStack {
@@ -137,3 +154,21 @@ impl VirtualMapping for VMKernelStack {
         PTEntryFlags::WRITABLE | PTEntryFlags::NX | PTEntryFlags::ACCESSED | PTEntryFlags::DIRTY
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_guard() {
+        let base = VirtAddr::from(0xffff_ff00_0000_0000u64);
+        let bounds = MemoryRegion::new(base + 4 * PAGE_SIZE, STACK_SIZE);
+        assert_eq!(VMKernelStack::guard_pages(STACK_SIZE), 4);
+
+        assert!(in_stack_guard(bounds, bounds.start() - 8));
+        assert!(in_stack_guard(bounds, base));
+        assert!(!in_stack_guard(bounds, base - 8));
+        assert!(!in_stack_guard(bounds, bounds.start()));
+        assert!(!in_stack_guard(bounds, bounds.end()));
+    }
+}
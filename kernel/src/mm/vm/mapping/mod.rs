@@ -5,6 +5,7 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 pub mod api;
+pub mod cow;
 pub mod file_mapping;
 pub mod kernel_stack;
 pub mod phys_mem;
@@ -13,8 +14,9 @@ pub mod reserved;
 pub mod vmalloc;
 
 pub use api::{Mapping, VMMAdapter, VMPageFaultResolution, VirtualMapping, VMM};
+pub use cow::VMCow;
 pub use file_mapping::{VMFileMapping, VMFileMappingFlags};
-pub use kernel_stack::VMKernelStack;
+pub use kernel_stack::{in_stack_guard, VMKernelStack};
 pub use phys_mem::VMPhysMem;
 pub use rawalloc::RawAllocMapping;
 pub use reserved::VMReserved;
@@ -5,9 +5,11 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
 use crate::mm::pagetable::PTEntryFlags;
+use crate::mm::vm::VMR;
 
-use super::{Mapping, VirtualMapping};
+use super::{Mapping, VMPageFaultResolution, VirtualMapping};
 
 /// Map physically contiguous memory
 #[derive(Default, Debug, Clone, Copy)]
@@ -18,6 +20,8 @@ pub struct VMPhysMem {
     size: usize,
     /// Whether mapping is writable
     writable: bool,
+    /// Whether pages are only mapped when they are first accessed
+    lazy: bool,
 }
 
 impl VMPhysMem {
@@ -37,6 +41,31 @@ impl VMPhysMem {
             base,
             size,
             writable,
+            lazy: false,
+        }
+    }
+
+    /// Initialize new instance of [`VMPhysMem`] which maps pages on demand.
+    /// Only the pages which are accessed get mapped, from the page fault
+    /// handler. This is useful for large guest buffers of which only small
+    /// parts, like headers, are accessed. Use [`VMRMapping::prefault()`] to
+    /// map parts of the region up front.
+    ///
+    /// [`VMRMapping::prefault()`]: crate::mm::vm::VMRMapping::prefault
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Physical base address to map
+    /// * `size` - Number of bytes to map
+    /// * `writable` - Whether mapping is writable
+    ///
+    /// # Returns
+    ///
+    /// New instance of [`VMPhysMem`]
+    pub fn new_lazy(base: PhysAddr, size: usize, writable: bool) -> Self {
+        VMPhysMem {
+            lazy: true,
+            ..Self::new(base, size, writable)
         }
     }
 
@@ -54,6 +83,30 @@ impl VMPhysMem {
     pub fn new_mapping(base: PhysAddr, size: usize, writable: bool) -> Mapping {
         Mapping::new(Self::new(base, size, writable))
     }
+
+    /// Initialize new [`Mapping`] with a lazy [`VMPhysMem`], see
+    /// [`VMPhysMem::new_lazy()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Physical base address to map
+    /// * `size` - Number of bytes to map
+    /// * `writable` - Whether mapping is writable
+    ///
+    /// # Returns
+    ///
+    /// New [`Mapping`] containing [`VMPhysMem`]
+    pub fn new_lazy_mapping(base: PhysAddr, size: usize, writable: bool) -> Mapping {
+        Mapping::new(Self::new_lazy(base, size, writable))
+    }
+
+    fn phys_addr(&self, offset: usize) -> Option<PhysAddr> {
+        if offset < self.size {
+            Some((self.base + offset).page_align())
+        } else {
+            None
+        }
+    }
 }
 
 impl VirtualMapping for VMPhysMem {
@@ -62,10 +115,10 @@ impl VirtualMapping for VMPhysMem {
     }
 
     fn map(&self, offset: usize) -> Option<PhysAddr> {
-        if offset < self.size {
-            Some((self.base + offset).page_align())
-        } else {
+        if self.lazy {
             None
+        } else {
+            self.phys_addr(offset)
         }
     }
 
@@ -78,4 +131,53 @@ impl VirtualMapping for VMPhysMem {
                 PTEntryFlags::empty()
             }
     }
+
+    fn maps_on_demand(&self) -> bool {
+        self.lazy
+    }
+
+    fn handle_page_fault(
+        &mut self,
+        _vmr: &VMR,
+        offset: usize,
+        write: bool,
+    ) -> Result<VMPageFaultResolution, SvsmError> {
+        if !self.lazy || (write && !self.writable) {
+            return Err(SvsmError::Mem);
+        }
+        let paddr = self.phys_addr(offset).ok_or(SvsmError::Mem)?;
+        Ok(VMPageFaultResolution {
+            paddr,
+            flags: self.pt_flags(offset),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::{SVSM_PERCPU_BASE, SVSM_PERCPU_END};
+    use crate::types::PAGE_SIZE;
+
+    #[test]
+    fn lazy_mapping_faults() {
+        let vmr = VMR::new(SVSM_PERCPU_BASE, SVSM_PERCPU_END, PTEntryFlags::empty());
+        let base = PhysAddr::from(0x10_0000u64);
+        let mut eager = VMPhysMem::new(base, 4 * PAGE_SIZE, false);
+        let mut lazy = VMPhysMem::new_lazy(base, 4 * PAGE_SIZE, false);
+        assert!(lazy.maps_on_demand());
+        assert!(!VMPhysMem::new(base, 4 * PAGE_SIZE, false).maps_on_demand());
+
+        assert_eq!(eager.map(PAGE_SIZE), Some(base + PAGE_SIZE));
+        assert!(eager.handle_page_fault(&vmr, PAGE_SIZE, false).is_err());
+
+        // Lazy mappings only provide pages from the fault handler
+        assert_eq!(lazy.map(PAGE_SIZE), None);
+        let res = lazy.handle_page_fault(&vmr, 2 * PAGE_SIZE, false).unwrap();
+        assert_eq!(res.paddr, base + 2 * PAGE_SIZE);
+        assert!(!res.flags.contains(PTEntryFlags::WRITABLE));
+        assert!(lazy.handle_page_fault(&vmr, 4 * PAGE_SIZE, false).is_err());
+        // Writes to read-only mappings are not resolved
+        assert!(lazy.handle_page_fault(&vmr, 0, true).is_err());
+    }
 }
@@ -10,7 +10,7 @@ use crate::address::PhysAddr;
 use crate::error::SvsmError;
 use crate::mm::alloc::{allocate_file_page_ref, PageRef};
 use crate::types::{PAGE_SHIFT, PAGE_SIZE};
-use crate::utils::align_up;
+use crate::utils::{align_up, TryVec};
 
 extern crate alloc;
 use alloc::vec::Vec;
@@ -121,4 +121,18 @@ impl RawAllocMapping {
         let pfn = offset >> PAGE_SHIFT;
         self.pages.get(pfn).and_then(|r| r.as_ref()).is_some()
     }
+
+    /// Returns references to all backing pages
+    ///
+    /// # Returns
+    ///
+    /// The pages on success, `Err(SvsmError::Mem)` if not all pages have
+    /// been allocated.
+    pub fn page_refs(&self) -> Result<TryVec<PageRef>, SvsmError> {
+        let mut refs = TryVec::with_capacity(self.pages.len())?;
+        for page in self.pages.iter() {
+            refs.try_push(page.clone().ok_or(SvsmError::Mem)?)?;
+        }
+        Ok(refs)
+    }
 }
@@ -9,7 +9,7 @@ use crate::error::SvsmError;
 use crate::mm::pagetable::PTEntryFlags;
 
 use super::rawalloc::RawAllocMapping;
-use super::{Mapping, VMFileMappingFlags, VirtualMapping};
+use super::{Mapping, VMCow, VMFileMappingFlags, VirtualMapping};
 
 /// Virtual mapping backed by allocated pages. This can be used for memory
 /// allocation if there is no need for the memory to be physically contiguous.
@@ -85,4 +85,8 @@ impl VirtualMapping for VMalloc {
     fn pt_flags(&self, _offset: usize) -> PTEntryFlags {
         self.flags
     }
+
+    fn clone_cow(&mut self) -> Result<VMCow, SvsmError> {
+        VMCow::new(self.alloc.page_refs()?, self.flags)
+    }
 }
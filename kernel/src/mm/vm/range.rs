@@ -430,6 +430,59 @@ impl VMR {
         cursor.remove().ok_or(SvsmError::Mem)
     }
 
+    /// Replace the backing of a [`VMM`] through `f` and rebuild its
+    /// page-table entries. The old entries are removed and flushed before
+    /// `f` runs, so that no stale translation to a page `f` releases or
+    /// shares survives.
+    fn update_vmm<T, F>(&self, base: VirtAddr, f: F) -> Result<T, SvsmError>
+    where
+        F: FnOnce(&Mapping) -> Result<T, SvsmError>,
+    {
+        let tree = self.tree.lock_read();
+        let node = tree.find(&base.pfn()).get().ok_or(SvsmError::Mem)?;
+        self.unmap_vmm(node);
+        flush_tlb_global_sync();
+        let result = f(&node.get_mapping_clone());
+        self.map_vmm(node)?;
+        result
+    }
+
+    /// Take a copy-on-write snapshot of the mapping at `base`. Afterwards
+    /// the mapping and the snapshot share their pages, and the first write
+    /// to a page through the mapping gives it a private copy. The mapping
+    /// must not be accessed by other CPUs while the snapshot is taken.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Virtual base address of the mapping
+    ///
+    /// # Returns
+    ///
+    /// The snapshot on success, `Err(SvsmError::NotSupported)` if the
+    /// mapping does not support copy-on-write, or `Err(SvsmError::Mem)` if
+    /// there is no mapping at `base`.
+    pub fn snapshot(&self, base: VirtAddr) -> Result<Arc<Mapping>, SvsmError> {
+        self.update_vmm(base, Mapping::clone_cow).map(Arc::new)
+    }
+
+    /// Roll the mapping at `base` back to the contents of `snapshot`, which
+    /// was taken with [`VMR::snapshot()`]. The snapshot is left unchanged
+    /// and can be rolled back to again. The mapping must not be accessed by
+    /// other CPUs during the rollback.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Virtual base address of the mapping
+    /// * `snapshot` - Snapshot to restore
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(SvsmError::Mem)` if there is no mapping at
+    /// `base`, or an allocation error.
+    pub fn rollback(&self, base: VirtAddr, snapshot: &Mapping) -> Result<(), SvsmError> {
+        self.update_vmm(base, |mapping| mapping.restore(snapshot))
+    }
+
     /// Dump all [`VMM`] mappings in the RBTree. This function is included for
     /// debugging purposes. And should not be called in production code.
     pub fn dump_ranges(&self) {
@@ -461,7 +514,7 @@ impl VMR {
     /// '()' if the page fault was successfully handled.
     ///
     /// 'SvsmError::Mem' if the page fault should propogate to the next handler.
-    pub fn handle_page_fault(&self, vaddr: VirtAddr, _write: bool) -> Result<(), SvsmError> {
+    pub fn handle_page_fault(&self, vaddr: VirtAddr, write: bool) -> Result<(), SvsmError> {
         // Get the mapping that contains the faulting address and check if the
         // fault happened on a mapped part of the range.
 
@@ -474,7 +527,32 @@ impl VMR {
             return Err(SvsmError::Mem);
         }
 
-        Ok(())
+        // Only mappings which map pages on demand resolve faults. Check that
+        // under the read lock, so that faults on other mappings do not
+        // contend with their users.
+        if !node.get_mapping().maps_on_demand() {
+            return Err(SvsmError::Mem);
+        }
+
+        // Let the mapping decide what to map at the faulting address.
+        let mut mapping = node.get_mapping_mut();
+        let page_size = mapping.page_size();
+        let page_addr = VirtAddr::from(align_down(vaddr.bits(), usize::from(page_size)));
+        let resolution = mapping.handle_page_fault(self, page_addr - start, write)?;
+
+        let (rstart, _) = self.virt_range();
+        let idx = PageTable::index::<3>(VirtAddr::from(page_addr - rstart));
+        let pt_flags = self.pt_flags | resolution.flags | PTEntryFlags::PRESENT;
+        let shared = mapping.shared();
+        let mut pgtbl_parts = self.pgtbl_parts.lock_write();
+        match page_size {
+            PageSize::Regular => {
+                pgtbl_parts[idx].map_4k(page_addr, resolution.paddr, pt_flags, shared)
+            }
+            PageSize::Huge => {
+                pgtbl_parts[idx].map_2m(page_addr, resolution.paddr, pt_flags, shared)
+            }
+        }
     }
 }
 
@@ -482,17 +560,53 @@ impl VMR {
 pub struct VMRMapping<'a> {
     vmr: &'a VMR,
     va: VirtAddr,
+    mapping: Arc<Mapping>,
 }
 
 impl<'a> VMRMapping<'a> {
     pub fn new(vmr: &'a VMR, mapping: Arc<Mapping>) -> Result<Self, SvsmError> {
-        let va = vmr.insert(mapping)?;
-        Ok(Self { vmr, va })
+        let va = vmr.insert(mapping.clone())?;
+        Ok(Self { vmr, va, mapping })
     }
 
     pub fn virt_addr(&self) -> VirtAddr {
         self.va
     }
+
+    /// Take a copy-on-write snapshot of the mapping, see [`VMR::snapshot()`].
+    pub fn snapshot(&self) -> Result<Arc<Mapping>, SvsmError> {
+        self.vmr.snapshot(self.va)
+    }
+
+    /// Roll the mapping back to `snapshot`, see [`VMR::rollback()`].
+    pub fn rollback(&self, snapshot: &Mapping) -> Result<(), SvsmError> {
+        self.vmr.rollback(self.va, snapshot)
+    }
+
+    /// Maps all pages covering `len` bytes at `offset` into the mapping, so
+    /// that accessing them does not fault. Does nothing for mappings which
+    /// do not map pages on demand, as those are fully mapped already.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset into the mapping
+    /// * `len` - Number of bytes to map
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(SvsmError::Mem)` if a page could not be
+    /// mapped
+    pub fn prefault(&self, offset: usize, len: usize) -> Result<(), SvsmError> {
+        if !self.mapping.get().maps_on_demand() {
+            return Ok(());
+        }
+        let start = (self.va + offset).page_align();
+        let end = (self.va + offset + len).page_align_up();
+        for vaddr in (start.bits()..end.bits()).step_by(PAGE_SIZE) {
+            self.vmr.handle_page_fault(VirtAddr::from(vaddr), false)?;
+        }
+        Ok(())
+    }
 }
 
 impl Drop for VMRMapping<'_> {
@@ -8,6 +8,7 @@ use crate::address::{PhysAddr, VirtAddr};
 use crate::cpu::percpu::PerCpu;
 use crate::error::SvsmError;
 use crate::io::IOPort;
+use crate::mm::encryption::EncryptionMask;
 use crate::platform::native::NativePlatform;
 use crate::platform::snp::SnpPlatform;
 use crate::platform::tdp::TdpPlatform;
@@ -26,8 +27,7 @@ pub static SVSM_PLATFORM: ImmutAfterInitCell<SvsmPlatformCell> = ImmutAfterInitC
 
 #[derive(Clone, Copy, Debug)]
 pub struct PageEncryptionMasks {
-    pub private_pte_mask: usize,
-    pub shared_pte_mask: usize,
+    pub encrypt_mask: EncryptionMask,
     pub addr_mask_width: u32,
     pub phys_addr_sizes: u32,
 }
@@ -9,6 +9,7 @@ use crate::cpu::cpuid::CpuidResult;
 use crate::cpu::msr::write_msr;
 use crate::cpu::percpu::PerCpu;
 use crate::error::SvsmError;
+use crate::mm::encryption::EncryptionMask;
 use crate::platform::{IOPort, PageEncryptionMasks, PageStateChangeOp, SvsmPlatform};
 use crate::svsm_console::NativeIOPort;
 use crate::types::PageSize;
@@ -49,8 +50,7 @@ impl SvsmPlatform for NativePlatform {
         // Find physical address size.
         let res = CpuidResult::get(0x80000008, 0);
         PageEncryptionMasks {
-            private_pte_mask: 0,
-            shared_pte_mask: 0,
+            encrypt_mask: EncryptionMask::none(),
             addr_mask_width: 64,
             phys_addr_sizes: res.eax,
         }
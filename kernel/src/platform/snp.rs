@@ -6,16 +6,14 @@
 
 use crate::address::{PhysAddr, VirtAddr};
 use crate::cpu::cpuid::cpuid_table;
-use crate::cpu::percpu::{current_ghcb, PerCpu};
+use crate::cpu::percpu::{current_ghcb, request_hv_doorbell_checks, this_cpu, PerCpu};
 use crate::error::SvsmError;
 use crate::io::IOPort;
+use crate::mm::encryption::EncryptionMask;
 use crate::platform::{PageEncryptionMasks, PageStateChangeOp, SvsmPlatform};
-use crate::sev::hv_doorbell::current_hv_doorbell;
-use crate::sev::msr_protocol::{hypervisor_ghcb_features, verify_ghcb_version, GHCBHvFeatures};
+use crate::sev::msr_protocol::{log_sev_caps, sev_caps};
 use crate::sev::status::vtom_enabled;
-use crate::sev::{
-    init_hypervisor_ghcb_features, pvalidate_range, sev_status_init, sev_status_verify, PvalidateOp,
-};
+use crate::sev::{init_sev_caps, pvalidate_range, sev_status_init, sev_status_verify, PvalidateOp};
 use crate::svsm_console::SVSMIOPort;
 use crate::types::PageSize;
 use crate::utils::MemoryRegion;
@@ -51,11 +49,12 @@ impl Default for SnpPlatform {
 impl SvsmPlatform for SnpPlatform {
     fn env_setup(&mut self) {
         sev_status_init();
+        init_sev_caps().expect("Failed to negotiate SEV capabilities with the hypervisor");
     }
 
     fn env_setup_late(&mut self) {
         sev_status_verify();
-        init_hypervisor_ghcb_features().expect("Failed to obtain hypervisor GHCB features");
+        log_sev_caps();
     }
 
     fn setup_percpu(&self, cpu: &PerCpu) -> Result<(), SvsmError> {
@@ -74,8 +73,7 @@ impl SvsmPlatform for SnpPlatform {
             cpuid_table(0x80000008).expect("Can not get physical address size from CPUID table");
         if vtom_enabled() {
             PageEncryptionMasks {
-                private_pte_mask: 0,
-                shared_pte_mask: vtom,
+                encrypt_mask: EncryptionMask::shared_bit(vtom),
                 addr_mask_width: vtom.leading_zeros(),
                 phys_addr_sizes: processor_capacity.eax,
             }
@@ -85,8 +83,7 @@ impl SvsmPlatform for SnpPlatform {
                 cpuid_table(0x8000001f).expect("Can not get C-Bit position from CPUID table");
             let c_bit = sev_capabilities.ebx & 0x3f;
             PageEncryptionMasks {
-                private_pte_mask: 1 << c_bit,
-                shared_pte_mask: 0,
+                encrypt_mask: EncryptionMask::c_bit(c_bit),
                 addr_mask_width: c_bit,
                 phys_addr_sizes: processor_capacity.eax,
             }
@@ -94,10 +91,6 @@ impl SvsmPlatform for SnpPlatform {
     }
 
     fn setup_guest_host_comm(&mut self, cpu: &PerCpu, is_bsp: bool) {
-        if is_bsp {
-            verify_ghcb_version();
-        }
-
         cpu.setup_ghcb().unwrap_or_else(|_| {
             if is_bsp {
                 panic!("Failed to setup BSP GHCB");
@@ -133,10 +126,9 @@ impl SvsmPlatform for SnpPlatform {
 
     fn configure_alternate_injection(&mut self, alt_inj_requested: bool) -> Result<(), SvsmError> {
         // If alternate injection was requested, then it must be supported by
-        // the hypervisor.
-        if alt_inj_requested
-            && !hypervisor_ghcb_features().contains(GHCBHvFeatures::SEV_SNP_EXT_INTERRUPTS)
-        {
+        // the hypervisor. Interrupts for the guest are delivered through the
+        // #HV doorbell page, so restricted injection is required as well.
+        if alt_inj_requested && !(sev_caps().alternate_injection() && sev_caps().hv_doorbell()) {
             return Err(SvsmError::NotSupported);
         }
 
@@ -197,13 +189,26 @@ impl SvsmPlatform for SnpPlatform {
     }
 
     fn post_irq(&self, icr: u64) -> Result<(), SvsmError> {
-        current_ghcb().hv_ipi(icr)?;
-        Ok(())
+        let Err(e) = current_ghcb().hv_ipi(icr) else {
+            return Ok(());
+        };
+        // The hypervisor may have dropped the #HV doorbell registration, e.g.
+        // across a save/restore. If so, re-register, have the other CPUs check
+        // theirs as well and retry once.
+        if this_cpu().revalidate_hv_doorbell()? {
+            request_hv_doorbell_checks();
+            current_ghcb().hv_ipi(icr)
+        } else {
+            Err(e)
+        }
     }
 
     fn eoi(&self) {
         // Issue an explicit EOI unless no explicit EOI is required.
-        if !current_hv_doorbell().no_eoi_required() {
+        let no_eoi_required = this_cpu()
+            .hv_doorbell()
+            .is_some_and(|doorbell| doorbell.no_eoi_required());
+        if !no_eoi_required {
             // 0x80B is the X2APIC EOI MSR.
             // Errors here cannot be handled but should not be grounds for
             // panic.
@@ -9,6 +9,7 @@ use crate::cpu::cpuid::CpuidResult;
 use crate::cpu::percpu::PerCpu;
 use crate::error::SvsmError;
 use crate::io::IOPort;
+use crate::mm::encryption::EncryptionMask;
 use crate::platform::{PageEncryptionMasks, PageStateChangeOp, SvsmPlatform};
 use crate::svsm_console::SVSMIOPort;
 use crate::types::PageSize;
@@ -48,8 +49,7 @@ impl SvsmPlatform for TdpPlatform {
         // Find physical address size.
         let res = CpuidResult::get(0x80000008, 0);
         PageEncryptionMasks {
-            private_pte_mask: 0,
-            shared_pte_mask: vtom,
+            encrypt_mask: EncryptionMask::shared_bit(vtom),
             addr_mask_width: vtom.trailing_zeros(),
             phys_addr_sizes: res.eax & 0xff,
         }
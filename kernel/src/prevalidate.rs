@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Background prevalidation of guest memory.
+//!
+//! A guest which does not use lazy validation validates all of its memory
+//! before it uses it, which makes booting guests with large amounts of
+//! memory slow. With prevalidation, the SVSM validates a baseline amount of
+//! guest memory before it launches the guest, and worker tasks validate the
+//! rest in the background while the guest boots. The guest must tolerate
+//! finding pages validated already, and can follow the progress with the
+//! [memory state protocol](crate::protocols::memstate).
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::memory::guest_memory_regions;
+use crate::protocols::core::prevalidate_guest_page;
+use crate::task::{create_kernel_task, schedule};
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::{align_up, MemoryRegion};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of worker tasks
+pub const MAX_PREVALIDATION_WORKERS: usize = 8;
+
+/// Guest memory is handed out to workers in chunks of up to this size
+const CHUNK_SIZE: usize = PAGE_SIZE_2M;
+
+/// Prevalidation configuration provided by the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrevalidationParams {
+    /// Bytes of guest memory validated before the guest is launched
+    pub baseline: usize,
+    /// Number of worker tasks validating the rest
+    pub workers: usize,
+}
+
+/// Progress of the prevalidation, in bytes of guest memory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrevalidationStats {
+    /// Guest memory to go through
+    pub total: usize,
+    /// Guest memory gone through so far, including memory which did not
+    /// need to be validated
+    pub processed: usize,
+    /// Guest memory validated by the SVSM
+    pub validated: usize,
+    /// Guest memory which could not be validated
+    pub failed: usize,
+}
+
+#[derive(Debug)]
+struct Progress {
+    total: AtomicUsize,
+    processed: AtomicUsize,
+    validated: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+static PROGRESS: Progress = Progress {
+    total: AtomicUsize::new(0),
+    processed: AtomicUsize::new(0),
+    validated: AtomicUsize::new(0),
+    failed: AtomicUsize::new(0),
+};
+
+/// Guest memory still to be validated, in descending order
+static WORK: SpinLock<Vec<MemoryRegion<PhysAddr>>> = SpinLock::new(Vec::new());
+
+/// Takes the next chunk from the lowest region in `work`, which is sorted
+/// in descending order. Chunks end at the next `CHUNK_SIZE` boundary, so
+/// that aligned chunks can be validated as a single large page.
+fn next_chunk(work: &mut Vec<MemoryRegion<PhysAddr>>) -> Option<MemoryRegion<PhysAddr>> {
+    let region = work.last_mut()?;
+    let start = region.start();
+    let boundary = PhysAddr::from(align_up(start.bits() + 1, CHUNK_SIZE));
+    if boundary >= region.end() {
+        return work.pop();
+    }
+    *region = MemoryRegion::from_addresses(boundary, region.end());
+    Some(MemoryRegion::from_addresses(start, boundary))
+}
+
+/// Validates the guest memory in `chunk`. Returns the number of bytes
+/// validated and the number of bytes which failed.
+fn validate_chunk(chunk: MemoryRegion<PhysAddr>) -> (usize, usize) {
+    if chunk.start().is_aligned(PAGE_SIZE_2M) && chunk.len() == PAGE_SIZE_2M {
+        // Fall back to 4K pages if the guest validated parts of the chunk
+        if let Ok(true) = prevalidate_guest_page(chunk.start(), PageSize::Huge) {
+            return (PAGE_SIZE_2M, 0);
+        }
+    }
+
+    let mut validated = 0;
+    let mut failed = 0;
+    for paddr in chunk.iter_pages(PageSize::Regular) {
+        match prevalidate_guest_page(paddr, PageSize::Regular) {
+            Ok(true) => validated += PAGE_SIZE,
+            Ok(false) => {}
+            Err(e) => {
+                log::debug!("Failed to prevalidate {:#x}: {:?}", paddr, e);
+                failed += PAGE_SIZE;
+            }
+        }
+    }
+    (validated, failed)
+}
+
+/// Validates the next chunk of guest memory. Returns `false` once all
+/// guest memory has been handed out.
+fn process_next_chunk() -> bool {
+    let Some(chunk) = next_chunk(&mut WORK.lock()) else {
+        return false;
+    };
+    let (validated, failed) = validate_chunk(chunk);
+    PROGRESS.validated.fetch_add(validated, Ordering::Relaxed);
+    PROGRESS.failed.fetch_add(failed, Ordering::Relaxed);
+    let processed = PROGRESS.processed.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+    if processed == PROGRESS.total.load(Ordering::Relaxed) {
+        let stats = prevalidation_stats();
+        log::info!(
+            "Prevalidation done: {} MiB validated, {} MiB failed",
+            stats.validated >> 20,
+            stats.failed >> 20
+        );
+    }
+    true
+}
+
+extern "C" fn prevalidation_worker() {
+    while process_next_chunk() {
+        // Let the request loop and other tasks run between chunks
+        schedule();
+    }
+}
+
+/// Validates `params.baseline` bytes of guest memory and starts worker
+/// tasks which validate the rest in the background. Must be called after
+/// the memory map is set up and before the guest is launched.
+pub fn prevalidate_guest_memory(params: PrevalidationParams) -> Result<(), SvsmError> {
+    let mut regions = guest_memory_regions()?;
+    let total = regions.iter().map(|r| r.len()).sum();
+    regions.reverse();
+    PROGRESS.total.store(total, Ordering::Relaxed);
+    *WORK.lock() = regions;
+
+    while PROGRESS.processed.load(Ordering::Relaxed) < params.baseline {
+        if !process_next_chunk() {
+            break;
+        }
+    }
+
+    let stats = prevalidation_stats();
+    log::info!(
+        "Prevalidated {} MiB of guest memory, {} MiB left to {} workers",
+        stats.validated >> 20,
+        (stats.total - stats.processed) >> 20,
+        params.workers
+    );
+
+    if stats.processed < stats.total {
+        for _ in 0..params.workers.clamp(1, MAX_PREVALIDATION_WORKERS) {
+            create_kernel_task(prevalidation_worker)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn prevalidation_stats() -> PrevalidationStats {
+    PrevalidationStats {
+        total: PROGRESS.total.load(Ordering::Relaxed),
+        processed: PROGRESS.processed.load(Ordering::Relaxed),
+        validated: PROGRESS.validated.load(Ordering::Relaxed),
+        failed: PROGRESS.failed.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> MemoryRegion<PhysAddr> {
+        MemoryRegion::from_addresses(PhysAddr::new(start), PhysAddr::new(end))
+    }
+
+    #[test]
+    fn chunks() {
+        let mut work = alloc::vec![range(0x60_0000, 0x60_1000), range(0x1000, 0x40_0000)];
+        assert_eq!(next_chunk(&mut work), Some(range(0x1000, 0x20_0000)));
+        assert_eq!(next_chunk(&mut work), Some(range(0x20_0000, 0x40_0000)));
+        assert_eq!(next_chunk(&mut work), Some(range(0x60_0000, 0x60_1000)));
+        assert_eq!(next_chunk(&mut work), None);
+    }
+}
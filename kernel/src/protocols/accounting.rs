@@ -0,0 +1,443 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Per-VMPL resource accounting.
+//!
+//! Every lower VMPL served by the SVSM is charged for the resources it
+//! consumes through protocol requests. Each resource has a configurable cap,
+//! so that a single VMPL cannot exhaust resources that are also needed to
+//! serve the others. The caps of the guest VMPL come from the IGVM
+//! parameters.
+//!
+//! Memory allocated on behalf of a VMPL goes through [`VmplPages`] and
+//! [`VmplSharedBox`], which charge it to both the memory pool of the
+//! subsystem and the VMPL.
+
+use crate::address::VirtAddr;
+use crate::locking::SpinLock;
+use crate::mm::pool::{pool, PoolId};
+use crate::mm::shared_pool::{HostShared, SharedBox};
+use crate::protocols::errors::SvsmReqError;
+use crate::sev::vmsa::VMPL_MAX;
+use crate::time::now;
+use crate::types::PAGE_SIZE;
+use crate::utils::{zero_mem_region, PageOrder};
+use core::mem::size_of;
+use core::ops::Deref;
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A resource that is accounted per VMPL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmplResource {
+    /// SVSM memory allocated on behalf of the VMPL, in pages
+    Memory,
+    /// Guest VMSAs registered by the VMPL
+    Vmsa,
+    /// Memory shared with the host on behalf of the VMPL, in pages
+    SharedBuffer,
+}
+
+const RESOURCE_COUNT: usize = 3;
+
+impl VmplResource {
+    const fn index(self) -> usize {
+        match self {
+            Self::Memory => 0,
+            Self::Vmsa => 1,
+            Self::SharedBuffer => 2,
+        }
+    }
+}
+
+/// Error returned when a VMPL goes over one of its limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountingError {
+    /// The VMPL index is not a valid lower VMPL
+    InvalidVmpl,
+    /// Charging the resource would exceed the cap for the VMPL
+    QuotaExceeded(VmplResource),
+    /// The VMPL issued too many protocol calls in the current window
+    RateLimited,
+}
+
+impl From<AccountingError> for SvsmReqError {
+    fn from(err: AccountingError) -> Self {
+        match err {
+            AccountingError::InvalidVmpl => Self::invalid_parameter(),
+            // A quota is not going to free up by retrying, deny the request.
+            AccountingError::QuotaExceeded(_) => Self::invalid_request(),
+            // Rate limiting is transient, the guest is expected to retry.
+            AccountingError::RateLimited => Self::busy(),
+        }
+    }
+}
+
+/// Caps applied to a single VMPL. A value of `u64::MAX` means unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmplLimits {
+    pub memory_pages: u64,
+    pub vmsas: u64,
+    pub shared_pages: u64,
+    /// Maximum number of protocol calls per `rate_window` TSC ticks
+    pub calls_per_window: u64,
+    pub rate_window: u64,
+}
+
+impl VmplLimits {
+    pub const fn unlimited() -> Self {
+        Self {
+            memory_pages: u64::MAX,
+            vmsas: u64::MAX,
+            shared_pages: u64::MAX,
+            calls_per_window: u64::MAX,
+            rate_window: u64::MAX,
+        }
+    }
+
+    const fn cap(&self, resource: VmplResource) -> u64 {
+        match resource {
+            VmplResource::Memory => self.memory_pages,
+            VmplResource::Vmsa => self.vmsas,
+            VmplResource::SharedBuffer => self.shared_pages,
+        }
+    }
+}
+
+impl Default for VmplLimits {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+/// Snapshot of the resource usage of a single VMPL
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VmplUsage {
+    pub memory_pages: u64,
+    pub vmsas: u64,
+    pub shared_pages: u64,
+    pub calls: u64,
+    pub denied: u64,
+}
+
+#[derive(Debug)]
+struct CallWindow {
+    start: u64,
+    calls: u64,
+}
+
+#[derive(Debug)]
+struct VmplAccount {
+    limits: SpinLock<VmplLimits>,
+    used: [AtomicU64; RESOURCE_COUNT],
+    window: SpinLock<CallWindow>,
+    calls: AtomicU64,
+    denied: AtomicU64,
+}
+
+impl VmplAccount {
+    const fn new() -> Self {
+        Self {
+            limits: SpinLock::new(VmplLimits::unlimited()),
+            used: [const { AtomicU64::new(0) }; RESOURCE_COUNT],
+            window: SpinLock::new(CallWindow { start: 0, calls: 0 }),
+            calls: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    fn deny(&self, err: AccountingError) -> AccountingError {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+        err
+    }
+
+    fn charge(&self, resource: VmplResource, amount: u64) -> Result<(), AccountingError> {
+        let cap = self.limits.lock().cap(resource);
+        self.used[resource.index()]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(amount).filter(|new| *new <= cap)
+            })
+            .map(|_| ())
+            .map_err(|_| self.deny(AccountingError::QuotaExceeded(resource)))
+    }
+
+    fn uncharge(&self, resource: VmplResource, amount: u64) {
+        // Resources that were not charged through this interface (e.g. the
+        // boot VMSA) may be released, so never wrap below zero.
+        let _ =
+            self.used[resource.index()].fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                Some(used.saturating_sub(amount))
+            });
+    }
+
+    fn charge_call_at(&self, now: u64) -> Result<(), AccountingError> {
+        let limits = *self.limits.lock();
+        let mut window = self.window.lock();
+
+        if now.wrapping_sub(window.start) >= limits.rate_window {
+            window.start = now;
+            window.calls = 0;
+        }
+        if window.calls >= limits.calls_per_window {
+            return Err(self.deny(AccountingError::RateLimited));
+        }
+        window.calls += 1;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset_counters(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.denied.store(0, Ordering::Relaxed);
+    }
+
+    fn usage(&self) -> VmplUsage {
+        let used = |r: VmplResource| self.used[r.index()].load(Ordering::Relaxed);
+        VmplUsage {
+            memory_pages: used(VmplResource::Memory),
+            vmsas: used(VmplResource::Vmsa),
+            shared_pages: used(VmplResource::SharedBuffer),
+            calls: self.calls.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static VMPL_ACCOUNTS: [VmplAccount; VMPL_MAX] = [const { VmplAccount::new() }; VMPL_MAX];
+
+fn account(vmpl: usize) -> Result<&'static VmplAccount, AccountingError> {
+    // VMPL0 is the SVSM itself and is never accounted
+    match vmpl {
+        0 => Err(AccountingError::InvalidVmpl),
+        _ => VMPL_ACCOUNTS.get(vmpl).ok_or(AccountingError::InvalidVmpl),
+    }
+}
+
+/// Set the resource caps for `vmpl`. Caps only apply to new charges, usage
+/// already above a lowered cap is kept until it is released.
+pub fn set_vmpl_limits(vmpl: usize, limits: VmplLimits) -> Result<(), AccountingError> {
+    *account(vmpl)?.limits.lock() = limits;
+    Ok(())
+}
+
+/// Charge `amount` units of `resource` to `vmpl`
+pub fn vmpl_charge(
+    vmpl: usize,
+    resource: VmplResource,
+    amount: u64,
+) -> Result<(), AccountingError> {
+    account(vmpl)?.charge(resource, amount)
+}
+
+/// Release `amount` units of `resource` previously charged to `vmpl`
+pub fn vmpl_uncharge(vmpl: usize, resource: VmplResource, amount: u64) {
+    if let Ok(account) = account(vmpl) {
+        account.uncharge(resource, amount);
+    }
+}
+
+/// Account a protocol call issued by `vmpl`, failing if it goes over the
+/// configured call rate.
+pub fn vmpl_charge_call(vmpl: usize) -> Result<(), AccountingError> {
+    account(vmpl)?.charge_call_at(now())
+}
+
+/// Reset the call and denial counters of `vmpl`. Resource usage is not
+/// affected, as it tracks resources that are still held.
+pub fn vmpl_reset_counters(vmpl: usize) {
+    if let Ok(account) = account(vmpl) {
+        account.reset_counters();
+    }
+}
+
+/// Return the current resource usage of `vmpl`
+pub fn vmpl_usage(vmpl: usize) -> Option<VmplUsage> {
+    account(vmpl).ok().map(VmplAccount::usage)
+}
+
+/// Zeroed pages allocated from the memory pool of a subsystem on behalf of
+/// a VMPL. They are charged to the pool and to the
+/// [`VmplResource::Memory`] of the VMPL until dropped.
+#[derive(Debug)]
+pub struct VmplPages {
+    vmpl: usize,
+    pool: PoolId,
+    vaddr: VirtAddr,
+    order: PageOrder,
+}
+
+impl VmplPages {
+    /// Allocate `2^order` zeroed pages from `pool_id` on behalf of `vmpl`.
+    /// Fails if either the pool or the memory cap of `vmpl` is exhausted.
+    pub fn allocate(vmpl: usize, pool_id: PoolId, order: PageOrder) -> Result<Self, SvsmReqError> {
+        let count = order.pages().get() as u64;
+        vmpl_charge(vmpl, VmplResource::Memory, count)?;
+        let vaddr = pool(pool_id)
+            .allocate_pages(order)
+            .inspect_err(|_| vmpl_uncharge(vmpl, VmplResource::Memory, count))?;
+        zero_mem_region(vaddr, vaddr + order.bytes().get());
+        Ok(Self {
+            vmpl,
+            pool: pool_id,
+            vaddr,
+            order,
+        })
+    }
+
+    /// Size of the allocation in bytes
+    pub fn len(&self) -> usize {
+        self.order.bytes().get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: the pages are owned by `self` and mapped for its lifetime.
+        unsafe { slice::from_raw_parts(self.vaddr.as_ptr::<u8>(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the pages are owned by `self` and mapped for its lifetime.
+        unsafe { slice::from_raw_parts_mut(self.vaddr.as_mut_ptr::<u8>(), self.len()) }
+    }
+}
+
+impl Drop for VmplPages {
+    fn drop(&mut self) {
+        pool(self.pool).free_pages(self.vaddr, self.order);
+        vmpl_uncharge(
+            self.vmpl,
+            VmplResource::Memory,
+            self.order.pages().get() as u64,
+        );
+    }
+}
+
+/// A [`SharedBox`] allocated on behalf of a VMPL. Its pages are charged to
+/// the [`VmplResource::SharedBuffer`] of the VMPL until dropped.
+#[derive(Debug)]
+pub struct VmplSharedBox<T: HostShared> {
+    vmpl: usize,
+    inner: SharedBox<T>,
+}
+
+impl<T: HostShared> VmplSharedBox<T> {
+    /// Shared allocations are page granular
+    const PAGES: u64 = size_of::<T>().div_ceil(PAGE_SIZE) as u64;
+
+    pub fn try_new(vmpl: usize, x: T) -> Result<Self, SvsmReqError> {
+        vmpl_charge(vmpl, VmplResource::SharedBuffer, Self::PAGES)?;
+        let inner = SharedBox::try_new(x)
+            .inspect_err(|_| vmpl_uncharge(vmpl, VmplResource::SharedBuffer, Self::PAGES))?;
+        Ok(Self { vmpl, inner })
+    }
+}
+
+impl<T: HostShared> Deref for VmplSharedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: HostShared> Drop for VmplSharedBox<T> {
+    fn drop(&mut self) {
+        vmpl_uncharge(self.vmpl, VmplResource::SharedBuffer, Self::PAGES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+
+    fn limited() -> VmplLimits {
+        VmplLimits {
+            memory_pages: 4,
+            vmsas: 2,
+            shared_pages: 0,
+            calls_per_window: 2,
+            rate_window: 100,
+        }
+    }
+
+    #[test]
+    fn quota_enforced() {
+        let account = VmplAccount::new();
+        *account.limits.lock() = limited();
+
+        account.charge(VmplResource::Vmsa, 1).unwrap();
+        assert_eq!(
+            account.charge(VmplResource::Vmsa, 2),
+            Err(AccountingError::QuotaExceeded(VmplResource::Vmsa))
+        );
+        account.charge(VmplResource::Vmsa, 1).unwrap();
+        assert!(account.charge(VmplResource::Vmsa, 1).is_err());
+
+        account.charge(VmplResource::Memory, 4).unwrap();
+        assert!(account.charge(VmplResource::SharedBuffer, 1).is_err());
+
+        let usage = account.usage();
+        assert_eq!(usage.memory_pages, 4);
+        assert_eq!(usage.vmsas, 2);
+        assert_eq!(usage.shared_pages, 0);
+        assert_eq!(usage.denied, 3);
+    }
+
+    #[test]
+    fn pages_charged_to_vmpl() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let vmpl = VMPL_MAX - 1;
+        set_vmpl_limits(vmpl, limited()).unwrap();
+
+        let mut pages = VmplPages::allocate(vmpl, PoolId::Protocol, PageOrder::new(1)).unwrap();
+        assert_eq!(pages.len(), 2 * PAGE_SIZE);
+        assert!(pages.as_mut_slice().iter().all(|b| *b == 0));
+        assert_eq!(vmpl_usage(vmpl).unwrap().memory_pages, 2);
+        assert!(VmplPages::allocate(vmpl, PoolId::Vtpm, PageOrder::new(2)).is_err());
+
+        drop(pages);
+        assert_eq!(vmpl_usage(vmpl).unwrap().memory_pages, 0);
+        set_vmpl_limits(vmpl, VmplLimits::unlimited()).unwrap();
+    }
+
+    #[test]
+    fn uncharge_saturates() {
+        let account = VmplAccount::new();
+        account.charge(VmplResource::Vmsa, 1).unwrap();
+        account.uncharge(VmplResource::Vmsa, 2);
+        assert_eq!(account.usage().vmsas, 0);
+    }
+
+    #[test]
+    fn call_rate_window() {
+        let account = VmplAccount::new();
+        *account.limits.lock() = limited();
+
+        account.charge_call_at(1000).unwrap();
+        account.charge_call_at(1050).unwrap();
+        assert_eq!(
+            account.charge_call_at(1099),
+            Err(AccountingError::RateLimited)
+        );
+        // A new window starts once `rate_window` ticks have elapsed
+        account.charge_call_at(1100).unwrap();
+        assert_eq!(account.usage().calls, 3);
+    }
+
+    #[test]
+    fn vmpl0_not_accounted() {
+        assert_eq!(
+            vmpl_charge(0, VmplResource::Vmsa, 1),
+            Err(AccountingError::InvalidVmpl)
+        );
+        assert_eq!(
+            vmpl_charge(VMPL_MAX, VmplResource::Vmsa, 1),
+            Err(AccountingError::InvalidVmpl)
+        );
+    }
+}
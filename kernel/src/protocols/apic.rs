@@ -7,6 +7,8 @@
 use crate::cpu::percpu::this_cpu;
 use crate::platform::SVSM_PLATFORM;
 use crate::protocols::errors::SvsmReqError;
+use crate::protocols::manifest::ServiceInfo;
+use crate::protocols::registry::ProtocolInfo;
 use crate::protocols::RequestParams;
 
 const SVSM_REQ_APIC_QUERY_FEATURES: u32 = 0;
@@ -23,6 +25,25 @@ pub const APIC_PROTOCOL: u32 = 3;
 pub const APIC_PROTOCOL_VERSION_MIN: u32 = 1;
 pub const APIC_PROTOCOL_VERSION_MAX: u32 = 1;
 
+/// The APIC protocol is only available if the calling CPU supports
+/// alternate injection.
+pub const APIC_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: APIC_PROTOCOL,
+    version_min: APIC_PROTOCOL_VERSION_MIN,
+    version_max: APIC_PROTOCOL_VERSION_MAX,
+    handler: apic_protocol_request,
+    available: || this_cpu().use_apic_emulation(),
+};
+
+/// APIC emulation service, GUID c409a8a8-313d-40df-b8e4-792354c4f7e5
+pub const APIC_SERVICE: ServiceInfo = ServiceInfo::new(
+    [
+        0xa8, 0xa8, 0x09, 0xc4, 0x3d, 0x31, 0xdf, 0x40, 0xb8, 0xe4, 0x79, 0x23, 0x54, 0xc4, 0xf7,
+        0xe5,
+    ],
+    APIC_PROTOCOL,
+);
+
 const SVSM_ERR_APIC_CANNOT_DISABLE: u64 = 0;
 const SVSM_ERR_APIC_CANNOT_LOCK: u64 = 1;
 
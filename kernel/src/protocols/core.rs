@@ -10,11 +10,18 @@ use crate::cpu::percpu::{this_cpu, this_cpu_shared, PERCPU_AREAS, PERCPU_VMSAS};
 use crate::cpu::vmsa::{vmsa_mut_ref_from_vaddr, vmsa_ref_from_vaddr};
 use crate::error::SvsmError;
 use crate::locking::RWLock;
+use crate::mm::guest_ref::guest_pages_referenced;
+use crate::mm::memory::{guest_memory_state, record_guest_validation, GuestMemoryState};
 use crate::mm::virtualrange::{VIRT_ALIGN_2M, VIRT_ALIGN_4K};
 use crate::mm::PerCPUPageMappingGuard;
-use crate::mm::{valid_phys_address, writable_phys_addr, GuestPtr};
-use crate::protocols::apic::{APIC_PROTOCOL, APIC_PROTOCOL_VERSION_MAX, APIC_PROTOCOL_VERSION_MIN};
+use crate::mm::{
+    online_deferred_at, phys_region_accessible_by, valid_phys_address, valid_phys_address_for,
+    writable_phys_addr, GuestPtr,
+};
+use crate::protocols::accounting::{vmpl_charge, vmpl_uncharge, VmplResource};
 use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::query_protocol;
+use crate::protocols::wire::{Reserved, Wire};
 use crate::protocols::RequestParams;
 use crate::requests::SvsmCaa;
 use crate::sev::utils::{
@@ -22,8 +29,9 @@ use crate::sev::utils::{
     rmp_set_guest_vmsa, PvalidateOp, RMPFlags, SevSnpError,
 };
 use crate::sev::vmsa::VMSAControl;
-use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::zero_mem_region;
+use crate::types::{PageSize, GUEST_VMPL, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::{zero_mem_region, MemoryRegion};
+use crate::wire_struct;
 use cpuarch::vmsa::VMSA;
 
 const SVSM_REQ_CORE_REMAP_CA: u32 = 0;
@@ -48,12 +56,13 @@ const CORE_PROTOCOL_VERSION_MAX: u32 = 1;
 // the lock for write.
 static PVALIDATE_LOCK: RWLock<()> = RWLock::new(());
 
-#[repr(C, packed)]
-#[derive(Copy, Clone)]
-struct PValidateRequest {
-    entries: u16,
-    next: u16,
-    resv: u32,
+wire_struct! {
+    /// PVALIDATE request header (SVSM spec, table 9)
+    struct PValidateRequest: 8 {
+        entries: u16,
+        next: u16,
+        resv: Reserved<4>,
+    }
 }
 
 fn core_create_vcpu_error_restore(paddr: Option<PhysAddr>, vaddr: Option<VirtAddr>) {
@@ -81,19 +90,25 @@ fn check_vmsa(new: &VMSA, sev_features: u64, svme_mask: u64) -> bool {
         && new.sev_features == sev_features
 }
 
-/// per-cpu request mapping area size (1GB)
+/// Handles SVSM_REQ_CORE_CREATE_VCPU, charging the new VMSA to the guest
+/// VMPL.
 fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
+    vmpl_charge(GUEST_VMPL, VmplResource::Vmsa, 1)?;
+    core_do_create_vcpu(params).inspect_err(|_| vmpl_uncharge(GUEST_VMPL, VmplResource::Vmsa, 1))
+}
+
+fn core_do_create_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
     let paddr = PhysAddr::from(params.rcx);
     let pcaa = PhysAddr::from(params.rdx);
     let apic_id: u32 = (params.r8 & 0xffff_ffff) as u32;
 
     // Check VMSA address
-    if !valid_phys_address(paddr) || !paddr.is_page_aligned() {
+    if !valid_phys_address_for(paddr, params.vmpl) || !paddr.is_page_aligned() {
         return Err(SvsmReqError::invalid_address());
     }
 
     // Check CAA address
-    if !valid_phys_address(pcaa) || !pcaa.is_page_aligned() {
+    if !valid_phys_address_for(pcaa, params.vmpl) || !pcaa.is_page_aligned() {
         return Err(SvsmReqError::invalid_address());
     }
 
@@ -178,6 +193,11 @@ fn core_delete_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
     // Tell everyone the news and flush temporary mapping
     flush_tlb_global_sync();
 
+    // A VMSA which could not be cleared is still in use by the guest
+    if res.is_ok() {
+        vmpl_uncharge(GUEST_VMPL, VmplResource::Vmsa, 1);
+    }
+
     res
 }
 
@@ -213,20 +233,8 @@ fn core_query_protocol(params: &mut RequestParams) -> Result<(), SvsmReqError> {
             CORE_PROTOCOL_VERSION_MIN,
             CORE_PROTOCOL_VERSION_MAX,
         ),
-        APIC_PROTOCOL => {
-            // The APIC protocol is only supported if the calling CPU supports
-            // alternate injection.
-            if this_cpu().use_apic_emulation() {
-                protocol_supported(
-                    version,
-                    APIC_PROTOCOL_VERSION_MIN,
-                    APIC_PROTOCOL_VERSION_MAX,
-                )
-            } else {
-                0
-            }
-        }
-        _ => 0,
+        _ => query_protocol(protocol, version)
+            .map_or(0, |(min, max)| protocol_supported(version, min, max)),
     };
 
     params.rcx = ret_val;
@@ -246,7 +254,42 @@ fn core_configure_vtom(params: &mut RequestParams) -> Result<(), SvsmReqError> {
     }
 }
 
-fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError> {
+/// Clears a guest page which was just validated and grants the guest access
+/// to it.
+fn grant_validated_page(paddr: PhysAddr, vaddr: VirtAddr, huge: PageSize) -> Result<(), SvsmError> {
+    // Zero out a page when it is validated and before giving other VMPLs
+    // access to it. This is necessary to prevent a possible HV attack:
+    //
+    // Attack scenario:
+    //   1) SVSM stores secrets in VMPL0 memory at GPA A
+    //   2) HV invalidates GPA A and maps the SPA to GPA B, which is in the
+    //      OS range of GPAs
+    //   3) Guest OS asks SVSM to validate GPA B
+    //   4) SVSM validates page and gives OS access
+    //   5) OS can now read SVSM secrets from GPA B
+    //
+    // The SVSM will not notice the attack until it tries to access GPA A
+    // again. Prevent it by clearing every page before giving access to
+    // other VMPLs.
+    //
+    // Be careful to not clear GPAs which the HV might have mapped
+    // read-only, as the write operation might cause infinite #NPF loops.
+    //
+    // Special thanks to Tom Lendacky for reporting the issue and tracking
+    // down the #NPF loops.
+    //
+    if writable_phys_addr(paddr) {
+        // FIXME: This check leaves a window open for the attack described
+        // above. Remove the check once OVMF and Linux have been fixed and
+        // no longer try to pvalidate MMIO memory.
+        zero_mem_region(vaddr, vaddr + usize::from(huge));
+    } else {
+        log::warn!("Not clearing possible read-only page at PA {:#x}", paddr);
+    }
+    rmp_grant_guest_access(vaddr, huge)
+}
+
+fn core_pvalidate_one(entry: u64, vmpl: usize, flush: &mut bool) -> Result<(), SvsmReqError> {
     let (page_size_bytes, valign, huge) = match entry & 3 {
         0 => (PAGE_SIZE, VIRT_ALIGN_4K, PageSize::Regular),
         1 => (PAGE_SIZE_2M, VIRT_ALIGN_2M, PageSize::Huge),
@@ -265,11 +308,22 @@ fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError>
         return Err(SvsmReqError::invalid_parameter());
     }
 
-    if !valid_phys_address(paddr) {
+    // The guest starts using deferred memory by validating it
+    online_deferred_at(paddr)?;
+
+    let region = MemoryRegion::new(paddr, page_size_bytes);
+    if !valid_phys_address_for(paddr, vmpl) || !phys_region_accessible_by(region, vmpl) {
         log::debug!("Invalid phys address: {:#x}", paddr);
         return Err(SvsmReqError::invalid_address());
     }
 
+    // Pages held by the SVSM, like a calling area, keep their state until
+    // the last reference to them is dropped.
+    if guest_pages_referenced(region) {
+        log::debug!("Guest page in use by the SVSM: {:#x}", paddr);
+        return Err(SvsmReqError::invalid_address());
+    }
+
     let guard = PerCPUPageMappingGuard::create(paddr, paddr + page_size_bytes, valign)?;
     let vaddr = guard.virt_addr();
 
@@ -288,46 +342,65 @@ fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError>
 
     drop(lock);
 
+    if let Err(e) = record_guest_validation(region, valid == PvalidateOp::Valid) {
+        log::warn!("Failed to record validation state of {:#x}: {:?}", paddr, e);
+    }
+
     if valid == PvalidateOp::Valid {
-        // Zero out a page when it is validated and before giving other VMPLs
-        // access to it. This is necessary to prevent a possible HV attack:
-        //
-        // Attack scenario:
-        //   1) SVSM stores secrets in VMPL0 memory at GPA A
-        //   2) HV invalidates GPA A and maps the SPA to GPA B, which is in the
-        //      OS range of GPAs
-        //   3) Guest OS asks SVSM to validate GPA B
-        //   4) SVSM validates page and gives OS access
-        //   5) OS can now read SVSM secrets from GPA B
-        //
-        // The SVSM will not notice the attack until it tries to access GPA A
-        // again. Prevent it by clearing every page before giving access to
-        // other VMPLs.
-        //
-        // Be careful to not clear GPAs which the HV might have mapped
-        // read-only, as the write operation might cause infinite #NPF loops.
-        //
-        // Special thanks to Tom Lendacky for reporting the issue and tracking
-        // down the #NPF loops.
-        //
-        if writable_phys_addr(paddr) {
-            // FIXME: This check leaves a window open for the attack described
-            // above. Remove the check once OVMF and Linux have been fixed and
-            // no longer try to pvalidate MMIO memory.
-            zero_mem_region(vaddr, vaddr + page_size_bytes);
-        } else {
-            log::warn!("Not clearing possible read-only page at PA {:#x}", paddr);
-        }
-        rmp_grant_guest_access(vaddr, huge)?;
+        grant_validated_page(paddr, vaddr, huge)?;
     }
 
     Ok(())
 }
 
+/// Validates the guest page of size `huge` at `paddr` on behalf of the
+/// guest, so that the guest does not have to validate it itself. Pages which
+/// are not guest memory, are held by the SVSM or might be read-only are left
+/// alone, as are pages the guest validated already.
+///
+/// Returns whether the page was validated. Validating a 2M page fails if
+/// part of it was validated already, in which case the caller may retry
+/// with 4K pages.
+pub fn prevalidate_guest_page(paddr: PhysAddr, huge: PageSize) -> Result<bool, SvsmError> {
+    let (page_size_bytes, valign) = match huge {
+        PageSize::Regular => (PAGE_SIZE, VIRT_ALIGN_4K),
+        PageSize::Huge => (PAGE_SIZE_2M, VIRT_ALIGN_2M),
+    };
+    let region = MemoryRegion::new(paddr, page_size_bytes);
+    if !valid_phys_address(paddr)
+        || !writable_phys_addr(paddr)
+        || guest_pages_referenced(region)
+        || guest_memory_state(paddr) != GuestMemoryState::Unvalidated
+    {
+        return Ok(false);
+    }
+
+    let guard = PerCPUPageMappingGuard::create(paddr, paddr + page_size_bytes, valign)?;
+    let vaddr = guard.virt_addr();
+
+    // Keep PVALIDATE requests of the guest out until the page is cleared and
+    // accessible, so that the guest cannot store data in it which is then
+    // cleared.
+    let _lock = PVALIDATE_LOCK.lock_write();
+
+    match pvalidate(vaddr, huge, PvalidateOp::Valid) {
+        Ok(()) => {}
+        // The guest was faster
+        Err(SvsmError::SevSnp(SevSnpError::FAIL_UNCHANGED(_))) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    if let Err(e) = record_guest_validation(region, true) {
+        log::warn!("Failed to record validation state of {:#x}: {:?}", paddr, e);
+    }
+    grant_validated_page(paddr, vaddr, huge)?;
+
+    Ok(true)
+}
+
 fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
     let gpa = PhysAddr::from(params.rcx);
 
-    if !gpa.is_aligned(8) || !valid_phys_address(gpa) {
+    if !gpa.is_aligned(8) || !valid_phys_address_for(gpa, params.vmpl) {
         return Err(SvsmReqError::invalid_parameter());
     }
 
@@ -337,14 +410,16 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
     let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
     let start = guard.virt_addr();
 
-    let guest_page = GuestPtr::<PValidateRequest>::new(start + offset);
-    let mut request = guest_page.read()?;
+    let guest_page = GuestPtr::<[u8; PValidateRequest::SIZE]>::new(start + offset);
+    let mut request = PValidateRequest::from_bytes(&guest_page.read()?)?;
 
     let entries = request.entries;
     let next = request.next;
 
-    // Each entry is 8 bytes in size, 8 bytes for the request header
-    let max_entries: u16 = ((PAGE_SIZE - offset - 8) / 8).try_into().unwrap();
+    // Each entry is 8 bytes in size
+    let max_entries: u16 = ((PAGE_SIZE - offset - PValidateRequest::SIZE) / 8)
+        .try_into()
+        .unwrap();
 
     if entries == 0 || entries > max_entries || entries <= next {
         return Err(SvsmReqError::invalid_parameter());
@@ -364,7 +439,7 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
             }
         };
 
-        loop_result = core_pvalidate_one(entry, &mut flush);
+        loop_result = core_pvalidate_one(entry, params.vmpl, &mut flush);
         match loop_result {
             Ok(()) => request.next += 1,
             Err(SvsmReqError::RequestError(..)) => break,
@@ -372,7 +447,10 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
         }
     }
 
-    if let Err(e) = guest_page.write_ref(&request) {
+    let mut header = [0u8; PValidateRequest::SIZE];
+    // Cannot fail, the buffer is exactly the size of the header
+    request.to_bytes(&mut header).unwrap();
+    if let Err(e) = guest_page.write(header) {
         loop_result = Err(e.into());
     }
 
@@ -386,7 +464,7 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
 fn core_remap_ca(params: &RequestParams) -> Result<(), SvsmReqError> {
     let gpa = PhysAddr::from(params.rcx);
 
-    if !gpa.is_aligned(8) || !valid_phys_address(gpa) || gpa.crosses_page(8) {
+    if !gpa.is_aligned(8) || !valid_phys_address_for(gpa, params.vmpl) || gpa.crosses_page(8) {
         return Err(SvsmReqError::invalid_parameter());
     }
 
@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Debug protocol.
+//!
+//! Runtime toggles to investigate a running SVSM without rebooting the guest
+//! with a different build. This protocol is specific to COCONUT-SVSM and not
+//! part of the SVSM specification. It is only available if the guest was
+//! launched with a policy that allows debugging, as it lets the guest change
+//! how much the SVSM reveals about itself.
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::console::{set_log_level, set_module_log_level};
+use crate::cpu::irq_latency::{irq_latency_reset, log_irq_latency};
+use crate::greq::driver::{
+    guest_request_driver_ready, guest_request_stats, guest_request_stats_reset,
+    GuestRequestPriority,
+};
+use crate::greq::pld_report::{AttestationReport, SnpReportResponse};
+use crate::greq::services::{alloc_report_buffer, get_regular_report};
+use crate::mm::access::TypedMapping;
+use crate::mm::{pagetable, valid_phys_address_for, PerCPUPageMappingGuard};
+use crate::protocols::accounting::vmpl_reset_counters;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::ProtocolInfo;
+use crate::protocols::{RequestParams, SVSM_DEBUG_PROTOCOL};
+use crate::sev::rmp_fault::{rmp_fault_stats, rmp_fault_stats_reset};
+use crate::sev::vmsa::VMPL_MAX;
+use crate::utils::TryVec;
+use core::str;
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::LevelFilter;
+
+const SVSM_REQ_DEBUG_QUERY: u32 = 0;
+const SVSM_REQ_DEBUG_SET_LOG_LEVEL: u32 = 1;
+const SVSM_REQ_DEBUG_RESET_METRICS: u32 = 2;
+const SVSM_REQ_DEBUG_CHECK_PAGETABLES: u32 = 3;
+const SVSM_REQ_DEBUG_LOG_METRICS: u32 = 4;
+
+pub const DEBUG_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const DEBUG_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub const DEBUG_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: SVSM_DEBUG_PROTOCOL,
+    version_min: DEBUG_PROTOCOL_VERSION_MIN,
+    version_max: DEBUG_PROTOCOL_VERSION_MAX,
+    handler: debug_protocol_request,
+    available: debug_policy_allowed,
+};
+
+/// Maximum length of a module path passed to SVSM_REQ_DEBUG_SET_LOG_LEVEL
+const MAX_MODULE_PATH_LEN: usize = 64;
+
+const POLICY_UNKNOWN: u8 = 0;
+const POLICY_ALLOWED: u8 = 1;
+const POLICY_DENIED: u8 = 2;
+
+static DEBUG_POLICY: AtomicU8 = AtomicU8::new(POLICY_UNKNOWN);
+
+fn query_debug_policy() -> Result<bool, SvsmReqError> {
+    // An all-zero MSG_REPORT_REQ asks for a VMPL0 report with no user data
+    let mut buffer = alloc_report_buffer()?;
+    get_regular_report(GuestRequestPriority::Svsm, &mut buffer[..])?;
+    let response = SnpReportResponse::try_from_as_ref(&buffer[..])?;
+    Ok(response.report().policy() & AttestationReport::POLICY_DEBUG != 0)
+}
+
+/// Drop the cached guest policy so that it is read again from a fresh
+/// attestation report.
+pub fn invalidate_debug_policy() {
+    DEBUG_POLICY.store(POLICY_UNKNOWN, Ordering::Relaxed);
+}
+
+/// Returns whether the guest policy allows debugging. The policy is read
+/// from an attestation report the first time and cached afterwards. If the
+/// report cannot be obtained, debugging is denied until the cached policy
+/// is invalidated.
+pub fn debug_policy_allowed() -> bool {
+    match DEBUG_POLICY.load(Ordering::Relaxed) {
+        POLICY_ALLOWED => true,
+        POLICY_DENIED => false,
+        // Without a guest request channel there is nothing to cache yet
+        _ if !guest_request_driver_ready() => false,
+        _ => {
+            let allowed = query_debug_policy().unwrap_or_else(|e| {
+                log::warn!("Failed to read guest policy: {:?}", e);
+                false
+            });
+            let state = if allowed {
+                POLICY_ALLOWED
+            } else {
+                POLICY_DENIED
+            };
+            DEBUG_POLICY.store(state, Ordering::Relaxed);
+            allowed
+        }
+    }
+}
+
+fn level_filter(level: u64) -> Option<LevelFilter> {
+    Some(match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => return None,
+    })
+}
+
+fn read_module_path(gpa: PhysAddr, len: usize, vmpl: usize) -> Result<TryVec<u8>, SvsmReqError> {
+    if len == 0
+        || len > MAX_MODULE_PATH_LEN
+        || !valid_phys_address_for(gpa, vmpl)
+        || gpa.crosses_page(len)
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let mut guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    let start = TypedMapping::<u8>::new(&mut guard, gpa.page_offset())?;
+    let mut path = TryVec::from_elem(0u8, len)?;
+    start.read_slice(&mut path)?;
+    Ok(path)
+}
+
+fn debug_query(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    // Bitmap of the supported calls
+    params.rcx = (1 << SVSM_REQ_DEBUG_QUERY)
+        | (1 << SVSM_REQ_DEBUG_SET_LOG_LEVEL)
+        | (1 << SVSM_REQ_DEBUG_RESET_METRICS)
+        | (1 << SVSM_REQ_DEBUG_CHECK_PAGETABLES)
+        | (1 << SVSM_REQ_DEBUG_LOG_METRICS);
+    Ok(())
+}
+
+fn debug_set_log_level(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let level = level_filter(params.rcx).ok_or_else(SvsmReqError::invalid_parameter)?;
+
+    if params.rdx == 0 {
+        log::info!("Debug protocol: setting log level to {}", level);
+        set_log_level(level);
+        return Ok(());
+    }
+
+    let path = read_module_path(PhysAddr::from(params.rdx), params.r8 as usize, params.vmpl)?;
+    let module = str::from_utf8(&path).map_err(|_| SvsmReqError::invalid_parameter())?;
+    log::info!(
+        "Debug protocol: setting log level of {} to {}",
+        module,
+        level
+    );
+    if set_module_log_level(module, Some(level)) {
+        Ok(())
+    } else {
+        Err(SvsmReqError::invalid_request())
+    }
+}
+
+fn debug_reset_metrics() -> Result<(), SvsmReqError> {
+    guest_request_stats_reset();
+    irq_latency_reset();
+    rmp_fault_stats_reset();
+    (1..VMPL_MAX).for_each(vmpl_reset_counters);
+    Ok(())
+}
+
+/// Writes the metrics reset by SVSM_REQ_DEBUG_RESET_METRICS to the log.
+fn debug_log_metrics() -> Result<(), SvsmReqError> {
+    let greq = guest_request_stats();
+    log::info!(
+        "SNP_GUEST_REQUEST: {} requests, {} contended, {} deferred to the SVSM, {} busy retries, {} failures",
+        greq.requests,
+        greq.contended,
+        greq.deferred,
+        greq.busy_retries,
+        greq.failures
+    );
+    log_irq_latency();
+    let rmp = rmp_fault_stats();
+    log::info!(
+        "RMP violations: {} guest memory, {} revoked by host, {} SVSM bugs",
+        rmp.guest_memory,
+        rmp.host_revoked,
+        rmp.svsm_bug
+    );
+    Ok(())
+}
+
+/// Checks the page table invariants of the calling CPU and returns the number
+/// of violations in `rcx`. The offending mappings are logged.
+fn debug_check_pagetables(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    params.rcx = pagetable::verify() as u64;
+    Ok(())
+}
+
+pub fn debug_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    if !debug_policy_allowed() {
+        return Err(SvsmReqError::unsupported_protocol());
+    }
+
+    match request {
+        SVSM_REQ_DEBUG_QUERY => debug_query(params),
+        SVSM_REQ_DEBUG_SET_LOG_LEVEL => debug_set_log_level(params),
+        SVSM_REQ_DEBUG_RESET_METRICS => debug_reset_metrics(),
+        SVSM_REQ_DEBUG_CHECK_PAGETABLES => debug_check_pagetables(params),
+        SVSM_REQ_DEBUG_LOG_METRICS => debug_log_metrics(),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_level_mapping() {
+        assert_eq!(level_filter(0), Some(LevelFilter::Off));
+        assert_eq!(level_filter(3), Some(LevelFilter::Info));
+        assert_eq!(level_filter(5), Some(LevelFilter::Trace));
+        assert_eq!(level_filter(6), None);
+    }
+}
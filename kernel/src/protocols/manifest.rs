@@ -0,0 +1,381 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Services manifest protocol.
+//!
+//! Guest drivers bind to SVSM services by GUID instead of probing protocol
+//! numbers. Subsystems register a [`ServiceInfo`] for each service they
+//! provide, and `SVSM_REQ_SERVICES_GET_MANIFEST` writes a manifest of all
+//! services whose protocol is currently available into a guest page. The
+//! manifest starts with a [`ManifestHeader`], followed by one
+//! [`ManifestEntry`] per service. The supported version range of each entry
+//! is taken from the protocol registry, so the manifest always agrees with
+//! `SVSM_CORE_QUERY_PROTOCOL`. This protocol is specific to COCONUT-SVSM.
+//!
+//! `SVSM_REQ_SERVICES_UPDATE_NOTIFY` lets a guest vCPU subscribe to
+//! notifications about TCB and certificate updates, after which attestation
+//! reports and certificates obtained earlier are stale.
+//!
+//! `SVSM_REQ_SERVICES_ATTEST_MANIFEST` returns the manifest together with a
+//! VMPL0 attestation report whose `REPORT_DATA` binds it, so that a remote
+//! verifier can trust the list of services offered by the SVSM. The first
+//! 48 bytes of `REPORT_DATA` are the SHA-384 digest of the manifest, followed
+//! by the 8-byte guest nonce and zeroes.
+
+use crate::address::{Address, PhysAddr};
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::greq::driver::GuestRequestPriority;
+use crate::greq::pld_report::{AttestationReport, SnpReportResponse, USER_DATA_SIZE};
+use crate::greq::services::{alloc_report_buffer, get_regular_report};
+use crate::greq::update::{attestation_generation, subscribe_attestation_updates};
+use crate::locking::RWLock;
+use crate::mm::access::TypedMapping;
+use crate::mm::pool::PoolId;
+use crate::mm::{valid_phys_address_for, PerCPUPageMappingGuard, PAGE_SIZE};
+use crate::protocols::accounting::VmplPages;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::{find_protocol, ProtocolInfo};
+use crate::protocols::wire::{Reserved, Wire, WireWriter};
+use crate::protocols::{RequestParams, SVSM_SERVICES_PROTOCOL};
+use crate::utils::PageOrder;
+use crate::wire_struct;
+use core::mem::size_of;
+use sha2::{Digest, Sha384};
+
+const SVSM_REQ_SERVICES_QUERY: u32 = 0;
+const SVSM_REQ_SERVICES_GET_MANIFEST: u32 = 1;
+const SVSM_REQ_SERVICES_UPDATE_NOTIFY: u32 = 2;
+const SVSM_REQ_SERVICES_ATTEST_MANIFEST: u32 = 3;
+
+pub const SERVICES_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const SERVICES_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub const SERVICES_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: SVSM_SERVICES_PROTOCOL,
+    version_min: SERVICES_PROTOCOL_VERSION_MIN,
+    version_max: SERVICES_PROTOCOL_VERSION_MAX,
+    handler: services_protocol_request,
+    available: ProtocolInfo::always_available,
+};
+
+/// "SVSM" in little-endian byte order
+pub const MANIFEST_MAGIC: u32 = 0x4d53_5653;
+pub const MANIFEST_VERSION: u32 = 1;
+
+wire_struct! {
+    /// Header of the services manifest
+    pub struct ManifestHeader: 16 {
+        pub magic: u32,
+        pub version: u32,
+        /// Size of the manifest in bytes, including this header
+        pub size: u32,
+        /// Number of entries following the header
+        pub count: u32,
+    }
+}
+
+wire_struct! {
+    /// Manifest entry describing one service
+    pub struct ManifestEntry: 56 {
+        /// Service GUID, in the byte order of EFI GUIDs
+        pub guid: [u8; 16],
+        /// Protocol number to use for calls to the service
+        pub protocol: u32,
+        pub version_min: u32,
+        pub version_max: u32,
+        resv: Reserved<4>,
+        /// Service specific doorbell, or zero if the service has none
+        pub doorbell: u64,
+        /// Guest physical address of the service queue, or zero
+        pub queue_gpa: u64,
+        /// Size of the service queue in pages
+        pub queue_pages: u32,
+        resv2: Reserved<4>,
+    }
+}
+
+/// A service the SVSM offers to the guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// GUID identifying the service, in the byte order of EFI GUIDs
+    pub guid: [u8; 16],
+    /// Protocol implementing the service
+    pub protocol: u32,
+    /// Doorbell the guest uses to notify the service, if any
+    pub doorbell: Option<u64>,
+    /// Guest physical address and size in pages of the service queue, if
+    /// the service uses one
+    pub queue: Option<(PhysAddr, u32)>,
+}
+
+impl ServiceInfo {
+    /// A service which is only reached through calls to its protocol
+    pub const fn new(guid: [u8; 16], protocol: u32) -> Self {
+        Self {
+            guid,
+            protocol,
+            doorbell: None,
+            queue: None,
+        }
+    }
+}
+
+const MAX_SERVICES: usize = 8;
+
+static SERVICES: RWLock<[Option<ServiceInfo>; MAX_SERVICES]> = RWLock::new([None; MAX_SERVICES]);
+
+/// Add a service to the manifest. Fails with [`SvsmError::NotSupported`] if
+/// a service with the same GUID is already registered or the manifest is
+/// full.
+pub fn register_service(info: ServiceInfo) -> Result<(), SvsmError> {
+    let mut services = SERVICES.lock_write();
+    if services.iter().flatten().any(|s| s.guid == info.guid) {
+        return Err(SvsmError::NotSupported);
+    }
+    let slot = services
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or(SvsmError::NotSupported)?;
+    *slot = Some(info);
+    Ok(())
+}
+
+fn manifest_entry(service: &ServiceInfo) -> Option<ManifestEntry> {
+    let protocol = find_protocol(service.protocol)?;
+    let (queue_gpa, queue_pages) = service
+        .queue
+        .map_or((0, 0), |(gpa, pages)| (u64::from(gpa), pages));
+    Some(ManifestEntry {
+        guid: service.guid,
+        protocol: service.protocol,
+        version_min: protocol.version_min,
+        version_max: protocol.version_max,
+        resv: Reserved,
+        doorbell: service.doorbell.unwrap_or(0),
+        queue_gpa,
+        queue_pages,
+        resv2: Reserved,
+    })
+}
+
+/// Encode the manifest of the currently available services into `buf`.
+/// Returns the size of the manifest, which is larger than `buf` if the
+/// manifest did not fit, in which case the contents of `buf` are undefined.
+pub fn encode_manifest(buf: &mut [u8]) -> usize {
+    let services = *SERVICES.lock_read();
+    let mut entries = services.iter().flatten().filter_map(manifest_entry);
+    let count = entries.clone().count();
+    let size = ManifestHeader::SIZE + count * ManifestEntry::SIZE;
+
+    let header = ManifestHeader {
+        magic: MANIFEST_MAGIC,
+        version: MANIFEST_VERSION,
+        size: size as u32,
+        count: count as u32,
+    };
+    let mut writer = WireWriter::new(buf);
+    // Encoding only fails if `buf` is too small, which the caller detects
+    // from the returned size.
+    let _ = writer
+        .write(&header)
+        .and_then(|_| entries.try_for_each(|entry| writer.write(&entry)));
+    size
+}
+
+fn services_query(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    // Bitmap of the supported calls
+    params.rcx = (1 << SVSM_REQ_SERVICES_QUERY)
+        | (1 << SVSM_REQ_SERVICES_GET_MANIFEST)
+        | (1 << SVSM_REQ_SERVICES_UPDATE_NOTIFY)
+        | (1 << SVSM_REQ_SERVICES_ATTEST_MANIFEST);
+    Ok(())
+}
+
+/// Checks the guest buffer at GPA `rcx` of `rdx` bytes, which must not
+/// cross a page boundary, and returns its address and length.
+fn guest_buffer(params: &RequestParams) -> Result<(PhysAddr, usize), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    if len == 0
+        || len > PAGE_SIZE
+        || !valid_phys_address_for(gpa, params.vmpl)
+        || gpa.crosses_page(len)
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    Ok((gpa, len))
+}
+
+/// Write the manifest to the guest buffer at GPA `rcx` of `rdx` bytes, which
+/// must not cross a page boundary. The size of the manifest is returned in
+/// `rcx`. If the buffer is too small, nothing is written and the call fails
+/// with an invalid parameter error, with the required size in `rcx`.
+fn services_get_manifest(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let (gpa, len) = guest_buffer(params)?;
+
+    let mut pages = VmplPages::allocate(params.vmpl, PoolId::Protocol, PageOrder::new(0))?;
+    let manifest = &mut pages.as_mut_slice()[..len];
+    let size = encode_manifest(manifest);
+    params.rcx = size as u64;
+    if size > len {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let mut guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    TypedMapping::<u8>::new(&mut guard, gpa.page_offset())?.write_slice(&manifest[..size])?;
+    Ok(())
+}
+
+/// `REPORT_DATA` binding an attestation report to `manifest` and the guest
+/// provided `nonce`
+fn manifest_binding(manifest: &[u8], nonce: u64) -> [u8; USER_DATA_SIZE] {
+    let mut data = [0u8; USER_DATA_SIZE];
+    data[..48].copy_from_slice(&Sha384::digest(manifest));
+    data[48..56].copy_from_slice(&nonce.to_le_bytes());
+    data
+}
+
+/// Write a VMPL0 attestation report binding the manifest and the nonce in
+/// `r8`, followed by the manifest itself, to the guest buffer at GPA `rcx`
+/// of `rdx` bytes. The buffer must not cross a page boundary. The combined
+/// size is returned in `rcx`. If the buffer is too small, nothing is written
+/// and the call fails with an invalid parameter error, with the required
+/// size in `rcx`.
+fn services_attest_manifest(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let (gpa, len) = guest_buffer(params)?;
+
+    // Encode into a full page so that the report is taken over the same
+    // manifest which is returned, even if the guest buffer is too small.
+    let mut pages = VmplPages::allocate(params.vmpl, PoolId::Protocol, PageOrder::new(0))?;
+    let manifest_size = encode_manifest(pages.as_mut_slice());
+    let report_size = size_of::<AttestationReport>();
+    let size = report_size + manifest_size;
+    params.rcx = size as u64;
+    if size > len {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let manifest = &pages.as_slice()[..manifest_size];
+
+    // MSG_REPORT_REQ for a VMPL0 report, followed by zeroes
+    let mut buffer = alloc_report_buffer()?;
+    buffer[..USER_DATA_SIZE].copy_from_slice(&manifest_binding(manifest, params.r8));
+    get_regular_report(GuestRequestPriority::Guest, &mut buffer[..])?;
+    let response = SnpReportResponse::try_from_as_ref(&buffer[..])?;
+
+    let mut guard = PerCPUPageMappingGuard::create_4k(gpa.page_align())?;
+    TypedMapping::<u8>::new(&mut guard, gpa.page_offset())?
+        .write_slice(response.report().as_bytes())?;
+    TypedMapping::<u8>::new(&mut guard, gpa.page_offset() + report_size)?.write_slice(manifest)?;
+    Ok(())
+}
+
+/// Raise the interrupt vector in `rcx` on the calling vCPU after every TCB
+/// or certificate update, or stop doing so if `rcx` is zero. Requires APIC
+/// emulation to deliver the interrupt. The number of updates processed so
+/// far is returned in `rcx`.
+fn services_update_notify(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let vector = u8::try_from(params.rcx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let cpu = this_cpu();
+    if vector != 0 && (vector < 0x20 || !cpu.use_apic_emulation()) {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    subscribe_attestation_updates(cpu.get_apic_id(), vector)?;
+    params.rcx = attestation_generation();
+    Ok(())
+}
+
+pub fn services_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_REQ_SERVICES_QUERY => services_query(params),
+        SVSM_REQ_SERVICES_GET_MANIFEST => services_get_manifest(params),
+        SVSM_REQ_SERVICES_UPDATE_NOTIFY => services_update_notify(params),
+        SVSM_REQ_SERVICES_ATTEST_MANIFEST => services_attest_manifest(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use crate::protocols::registry::register_protocol;
+    use crate::protocols::wire::WireReader;
+    use alloc::vec::Vec;
+
+    // Protocol number from the vendor-specific range which is not used by
+    // any real protocol, as the registries are shared by all tests.
+    const TEST_PROTOCOL: u32 = 0xffff_ffe0;
+    const TEST_GUID: [u8; 16] = [0xa5; 16];
+    const TEST_GUID_UNREGISTERED: [u8; 16] = [0x5a; 16];
+
+    fn test_handler(_request: u32, _params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        Ok(())
+    }
+
+    #[test]
+    fn manifest() {
+        register_protocol(ProtocolInfo {
+            id: TEST_PROTOCOL,
+            version_min: 2,
+            version_max: 3,
+            handler: test_handler,
+            available: ProtocolInfo::always_available,
+        })
+        .unwrap();
+        let service = ServiceInfo {
+            doorbell: Some(0x1234),
+            queue: Some((PhysAddr::from(0x10_0000u64), 2)),
+            ..ServiceInfo::new(TEST_GUID, TEST_PROTOCOL)
+        };
+        register_service(service).unwrap();
+        assert!(register_service(service).is_err());
+        // Services of unregistered protocols are not listed
+        register_service(ServiceInfo::new(TEST_GUID_UNREGISTERED, 0xffff_ffe1)).unwrap();
+
+        let mut buf = [0u8; PAGE_SIZE];
+        let size = encode_manifest(&mut buf);
+        let mut reader = WireReader::new(&buf[..size]);
+        let header: ManifestHeader = reader.read().unwrap();
+        assert_eq!(header.magic, MANIFEST_MAGIC);
+        assert_eq!(header.size as usize, size);
+
+        let entries = (0..header.count)
+            .map(|_| reader.read::<ManifestEntry>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(reader.remaining().is_empty());
+        assert!(!entries.iter().any(|e| e.guid == TEST_GUID_UNREGISTERED));
+        let entry = entries.iter().find(|e| e.guid == TEST_GUID).unwrap();
+        assert_eq!(entry.protocol, TEST_PROTOCOL);
+        assert_eq!((entry.version_min, entry.version_max), (2, 3));
+        assert_eq!(entry.doorbell, 0x1234);
+        assert_eq!((entry.queue_gpa, entry.queue_pages), (0x10_0000, 2));
+
+        // A buffer that is too small reports the required size
+        assert_eq!(encode_manifest(&mut [0u8; 8]), size);
+    }
+
+    #[test]
+    fn manifest_binding_data() {
+        let mut buf = [0u8; PAGE_SIZE];
+        let size = encode_manifest(&mut buf);
+        let manifest = &buf[..size];
+
+        let data = manifest_binding(manifest, 0x0123_4567_89ab_cdef);
+        assert_eq!(&data[..48], Sha384::digest(manifest).as_slice());
+        assert_eq!(&data[48..56], &0x0123_4567_89ab_cdefu64.to_le_bytes());
+        assert!(data[56..].iter().all(|&b| b == 0));
+        // Any change to the manifest or the nonce changes the binding
+        assert_ne!(
+            manifest_binding(&manifest[1..], 0x0123_4567_89ab_cdef),
+            data
+        );
+        assert_ne!(manifest_binding(manifest, 0), data);
+    }
+}
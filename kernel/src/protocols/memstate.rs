@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Memory state protocol.
+//!
+//! Lets an enlightened guest query the state of its memory as seen by the
+//! SVSM: whether a page is validated, a VMSA, or not guest memory at all.
+//! A guest which suspects that the host changed the state of its memory
+//! can compare this with its own view and resynchronize. This protocol is
+//! specific to COCONUT-SVSM and not part of the SVSM specification. It is
+//! only available if enabled in the IGVM parameters, and the number of
+//! pages queried is rate limited, as each query walks the memory maps of
+//! the SVSM.
+//!
+//! If the SVSM [prevalidates](crate::prevalidate) guest memory, the guest
+//! can query the progress to learn when it no longer needs to validate its
+//! memory itself.
+
+use crate::address::{Address, PhysAddr};
+use crate::locking::SpinLock;
+use crate::mm::access::TypedMapping;
+use crate::mm::memory::guest_memory_state;
+use crate::mm::{valid_phys_address_for, PerCPUPageMappingGuard};
+use crate::prevalidate::prevalidation_stats;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::ProtocolInfo;
+use crate::protocols::{RequestParams, SVSM_MEMSTATE_PROTOCOL};
+use crate::time::now;
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SVSM_REQ_MEMSTATE_QUERY: u32 = 0;
+const SVSM_REQ_MEMSTATE_GET_STATE: u32 = 1;
+const SVSM_REQ_MEMSTATE_PREVALIDATION: u32 = 2;
+
+pub const MEMSTATE_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const MEMSTATE_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub const MEMSTATE_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: SVSM_MEMSTATE_PROTOCOL,
+    version_min: MEMSTATE_PROTOCOL_VERSION_MIN,
+    version_max: MEMSTATE_PROTOCOL_VERSION_MAX,
+    handler: memstate_protocol_request,
+    available: memstate_queries_enabled,
+};
+
+/// Maximum number of pages per SVSM_REQ_MEMSTATE_GET_STATE call, one state
+/// byte each
+const MAX_PAGES_PER_CALL: usize = PAGE_SIZE;
+
+/// Maximum number of pages queried per rate window
+const MAX_PAGES_PER_WINDOW: u64 = 1 << 20;
+
+/// Length of a rate window in TSC cycles
+const RATE_WINDOW: u64 = 1 << 32;
+
+static MEMSTATE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allows or forbids lower VMPLs to use the memory state protocol
+pub fn set_memstate_queries_enabled(enabled: bool) {
+    MEMSTATE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn memstate_queries_enabled() -> bool {
+    MEMSTATE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of pages queried in the current rate window
+#[derive(Debug)]
+struct QueryWindow {
+    start: u64,
+    pages: u64,
+}
+
+impl QueryWindow {
+    const fn new() -> Self {
+        Self { start: 0, pages: 0 }
+    }
+
+    /// Accounts a query of `pages` pages at time `now`, failing if it goes
+    /// over the limit of the current window.
+    fn charge_at(&mut self, now: u64, pages: u64) -> Result<(), SvsmReqError> {
+        if now.wrapping_sub(self.start) >= RATE_WINDOW {
+            self.start = now;
+            self.pages = 0;
+        }
+        let pages = self
+            .pages
+            .checked_add(pages)
+            .filter(|pages| *pages <= MAX_PAGES_PER_WINDOW)
+            .ok_or_else(SvsmReqError::busy)?;
+        self.pages = pages;
+        Ok(())
+    }
+}
+
+static QUERY_WINDOW: SpinLock<QueryWindow> = SpinLock::new(QueryWindow::new());
+
+fn memstate_query(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    // Bitmap of the supported calls
+    params.rcx = (1 << SVSM_REQ_MEMSTATE_QUERY)
+        | (1 << SVSM_REQ_MEMSTATE_GET_STATE)
+        | (1 << SVSM_REQ_MEMSTATE_PREVALIDATION);
+    Ok(())
+}
+
+/// Writes the [`GuestMemoryState`](crate::mm::memory::GuestMemoryState) of
+/// `rdx` pages starting at the page-aligned GPA in `rcx` to the buffer at
+/// the GPA in `r8`, one byte per page. The buffer must not cross a page
+/// boundary. Returns the number of pages in `rcx`.
+fn memstate_get_state(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let start = PhysAddr::from(params.rcx);
+    let count = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let buffer = PhysAddr::from(params.r8);
+
+    if !start.is_page_aligned()
+        || count == 0
+        || count > MAX_PAGES_PER_CALL
+        || !valid_phys_address_for(buffer, params.vmpl)
+        || buffer.crosses_page(count)
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let end = (count * PAGE_SIZE)
+        .checked_add(start.bits())
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+
+    QUERY_WINDOW.lock().charge_at(now(), count as u64)?;
+
+    let mut states = [0u8; MAX_PAGES_PER_CALL];
+    for (state, paddr) in states
+        .iter_mut()
+        .zip((start.bits()..end).step_by(PAGE_SIZE))
+    {
+        *state = guest_memory_state(PhysAddr::from(paddr)) as u8;
+    }
+
+    let mut guard = PerCPUPageMappingGuard::create_4k(buffer.page_align())?;
+    let mut out = TypedMapping::<u8>::new(&mut guard, buffer.page_offset())?;
+    out.write_slice(&states[..count])?;
+
+    params.rcx = count as u64;
+    Ok(())
+}
+
+/// Returns the progress of the prevalidation of guest memory: the number of
+/// bytes to go through in `rcx`, the number of bytes gone through in `rdx`
+/// and the number of bytes which could not be validated in `r8`. All are
+/// zero if guest memory is not prevalidated.
+fn memstate_prevalidation(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let stats = prevalidation_stats();
+    params.rcx = stats.total as u64;
+    params.rdx = stats.processed as u64;
+    params.r8 = stats.failed as u64;
+    Ok(())
+}
+
+pub fn memstate_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    if !memstate_queries_enabled() {
+        return Err(SvsmReqError::unsupported_protocol());
+    }
+
+    match request {
+        SVSM_REQ_MEMSTATE_QUERY => memstate_query(params),
+        SVSM_REQ_MEMSTATE_GET_STATE => memstate_get_state(params),
+        SVSM_REQ_MEMSTATE_PREVALIDATION => memstate_prevalidation(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_rate_limit() {
+        let mut window = QueryWindow::new();
+        window.charge_at(10, MAX_PAGES_PER_WINDOW - 1).unwrap();
+        window.charge_at(20, 1).unwrap();
+        assert!(window.charge_at(30, 1).is_err());
+        // A new window starts once RATE_WINDOW cycles have elapsed
+        window.charge_at(10 + RATE_WINDOW, 1).unwrap();
+        assert!(window
+            .charge_at(20 + RATE_WINDOW, MAX_PAGES_PER_WINDOW)
+            .is_err());
+    }
+}
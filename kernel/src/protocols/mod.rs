@@ -4,22 +4,43 @@
 //
 // Author: Dov Murik <dovmurik@linux.ibm.com>
 
+pub mod accounting;
 pub mod apic;
 pub mod core;
+pub mod debug;
 pub mod errors;
+pub mod manifest;
+pub mod memstate;
+pub mod registry;
 #[cfg(all(feature = "mstpm", not(test)))]
 pub mod vtpm;
+pub mod watchdog;
+pub mod wire;
 
+use crate::error::SvsmError;
+use crate::protocols::apic::{APIC_PROTOCOL_INFO, APIC_SERVICE};
+use crate::protocols::debug::DEBUG_PROTOCOL_INFO;
+use crate::protocols::manifest::{register_service, SERVICES_PROTOCOL_INFO};
+use crate::protocols::memstate::MEMSTATE_PROTOCOL_INFO;
+use crate::protocols::registry::register_protocol;
+use crate::protocols::watchdog::WATCHDOG_PROTOCOL_INFO;
 use cpuarch::vmsa::{GuestVMExit, VMSA};
 
 // SVSM protocols
 pub const SVSM_CORE_PROTOCOL: u32 = 0;
 pub const SVSM_VTPM_PROTOCOL: u32 = 2;
 pub const SVSM_APIC_PROTOCOL: u32 = 3;
+// COCONUT-SVSM specific protocols
+pub const SVSM_DEBUG_PROTOCOL: u32 = 0x8000_0000;
+pub const SVSM_SERVICES_PROTOCOL: u32 = 0x8000_0001;
+pub const SVSM_MEMSTATE_PROTOCOL: u32 = 0x8000_0002;
+pub const SVSM_WATCHDOG_PROTOCOL: u32 = 0x8000_0003;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct RequestParams {
     pub guest_exit_code: GuestVMExit,
+    /// VMPL the request was issued from
+    vmpl: usize,
     sev_features: u64,
     rcx: u64,
     rdx: u64,
@@ -30,6 +51,7 @@ impl RequestParams {
     pub fn from_vmsa(vmsa: &VMSA) -> Self {
         RequestParams {
             guest_exit_code: vmsa.guest_exit_code,
+            vmpl: usize::from(vmsa.vmpl),
             sev_features: vmsa.sev_features,
             rcx: vmsa.rcx,
             rdx: vmsa.rdx,
@@ -43,3 +65,15 @@ impl RequestParams {
         vmsa.r8 = self.r8;
     }
 }
+
+/// Register the optional protocols which do not depend on the
+/// initialization of another subsystem. Other protocols are registered by
+/// their subsystems.
+pub fn register_protocols() -> Result<(), SvsmError> {
+    register_protocol(APIC_PROTOCOL_INFO)?;
+    register_service(APIC_SERVICE)?;
+    register_protocol(DEBUG_PROTOCOL_INFO)?;
+    register_protocol(MEMSTATE_PROTOCOL_INFO)?;
+    register_protocol(WATCHDOG_PROTOCOL_INFO)?;
+    register_protocol(SERVICES_PROTOCOL_INFO)
+}
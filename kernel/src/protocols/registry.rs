@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Registry of optional SVSM protocols.
+//!
+//! The core protocol is always available and handled directly by the request
+//! loop. All other protocols are optional, depending on the features the
+//! SVSM was built with and on the platform it runs on. Their subsystems
+//! register them here during initialization, and both the request
+//! dispatcher and `SVSM_CORE_QUERY_PROTOCOL` consult the registry. This way
+//! a protocol that is not built in, failed to initialize or is not
+//! available to the calling CPU is reported the same way everywhere.
+
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+/// Handler for the calls of a protocol
+pub type ProtocolHandler = fn(u32, &mut RequestParams) -> Result<(), SvsmReqError>;
+
+/// Description of a registered protocol
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolInfo {
+    /// Protocol number
+    pub id: u32,
+    /// Lowest supported protocol version
+    pub version_min: u32,
+    /// Highest supported protocol version
+    pub version_max: u32,
+    /// Handler for the calls of the protocol
+    pub handler: ProtocolHandler,
+    /// Returns whether the protocol is available to the calling CPU. Checked
+    /// on every lookup.
+    pub available: fn() -> bool,
+}
+
+impl ProtocolInfo {
+    /// Availability check for protocols that are always available once
+    /// registered
+    pub fn always_available() -> bool {
+        true
+    }
+}
+
+const MAX_PROTOCOLS: usize = 8;
+
+static PROTOCOLS: RWLock<[Option<ProtocolInfo>; MAX_PROTOCOLS]> =
+    RWLock::new([None; MAX_PROTOCOLS]);
+
+/// Register an optional protocol. Fails with [`SvsmError::NotSupported`] if
+/// the protocol is already registered or the registry is full.
+pub fn register_protocol(info: ProtocolInfo) -> Result<(), SvsmError> {
+    let mut protocols = PROTOCOLS.lock_write();
+    if protocols.iter().flatten().any(|p| p.id == info.id) {
+        return Err(SvsmError::NotSupported);
+    }
+    let slot = protocols
+        .iter_mut()
+        .find(|p| p.is_none())
+        .ok_or(SvsmError::NotSupported)?;
+    *slot = Some(info);
+    Ok(())
+}
+
+/// Returns the protocol with number `id` if it is registered and available
+/// to the calling CPU.
+pub fn find_protocol(id: u32) -> Option<ProtocolInfo> {
+    let info = PROTOCOLS
+        .lock_read()
+        .iter()
+        .flatten()
+        .find(|p| p.id == id)
+        .copied()?;
+    (info.available)().then_some(info)
+}
+
+/// Dispatch a call to an optional protocol
+pub fn protocol_request(
+    protocol: u32,
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    let info = find_protocol(protocol).ok_or_else(SvsmReqError::unsupported_protocol)?;
+    (info.handler)(request, params)
+}
+
+/// Returns the supported `(min, max)` version range of an optional protocol
+/// if it is available and supports `version`.
+pub fn query_protocol(protocol: u32, version: u32) -> Option<(u32, u32)> {
+    let info = find_protocol(protocol)?;
+    (info.version_min..=info.version_max)
+        .contains(&version)
+        .then_some((info.version_min, info.version_max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Protocol numbers from the vendor-specific range which are not used by
+    // any real protocol, as the registry is shared by all tests.
+    const TEST_PROTOCOL: u32 = 0xffff_fff0;
+    const TEST_PROTOCOL_UNAVAILABLE: u32 = 0xffff_fff1;
+
+    fn test_handler(request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        params.rcx = request.into();
+        Ok(())
+    }
+
+    fn never_available() -> bool {
+        false
+    }
+
+    #[test]
+    fn registry() {
+        let info = ProtocolInfo {
+            id: TEST_PROTOCOL,
+            version_min: 1,
+            version_max: 2,
+            handler: test_handler,
+            available: ProtocolInfo::always_available,
+        };
+        register_protocol(info).unwrap();
+        assert!(register_protocol(info).is_err());
+        register_protocol(ProtocolInfo {
+            id: TEST_PROTOCOL_UNAVAILABLE,
+            available: never_available,
+            ..info
+        })
+        .unwrap();
+
+        assert_eq!(query_protocol(TEST_PROTOCOL, 2), Some((1, 2)));
+        assert_eq!(query_protocol(TEST_PROTOCOL, 3), None);
+        assert_eq!(query_protocol(TEST_PROTOCOL_UNAVAILABLE, 1), None);
+
+        let mut params = RequestParams::default();
+        protocol_request(TEST_PROTOCOL, 5, &mut params).unwrap();
+        assert_eq!(params.rcx, 5);
+        assert!(matches!(
+            protocol_request(TEST_PROTOCOL_UNAVAILABLE, 0, &mut params),
+            Err(SvsmReqError::RequestError(_))
+        ));
+    }
+}
@@ -8,16 +8,26 @@
 
 extern crate alloc;
 
-use core::{mem::size_of, slice::from_raw_parts_mut};
+use core::slice::from_raw_parts_mut;
 
-use alloc::vec::Vec;
+use alloc::sync::Arc;
 
 use crate::{
     address::{Address, PhysAddr},
-    mm::{valid_phys_address, GuestPtr, PerCPUPageMappingGuard},
-    protocols::{errors::SvsmReqError, RequestParams},
+    cpu::percpu::this_cpu,
+    mm::{pool::PoolId, valid_phys_address_for, vm::VMPhysMem, GuestPtr},
+    protocols::{
+        accounting::VmplPages,
+        errors::SvsmReqError,
+        manifest::ServiceInfo,
+        registry::ProtocolInfo,
+        wire::{Wire, WireReader, WireWriter},
+        RequestParams, SVSM_VTPM_PROTOCOL,
+    },
     types::PAGE_SIZE,
+    utils::PageOrder,
     vtpm::{vtpm_get_locked, MsTpmSimulatorInterface, VtpmProtocolInterface},
+    wire_struct,
 };
 
 /// vTPM platform commands (SVSM spec, section 8.1 - SVSM_VTPM_QUERY)
@@ -64,43 +74,48 @@ fn is_vtpm_platform_command_supported(cmd: TpmPlatformCommand) -> bool {
     vtpm.get_supported_commands().iter().any(|x| *x == cmd)
 }
 
-const SEND_COMMAND_REQ_INBUF_SIZE: usize = PAGE_SIZE - 9;
-
 // vTPM protocol services (SVSM spec, table 14)
 const SVSM_VTPM_QUERY: u32 = 0;
 const SVSM_VTPM_COMMAND: u32 = 1;
 
-/// TPM_SEND_COMMAND request structure (SVSM spec, table 16)
-#[derive(Clone, Copy, Debug)]
-#[repr(C, packed)]
-struct TpmSendCommandRequest {
-    /// MSSIM platform command ID
-    command: u32,
-    /// Locality usage for the vTPM is not defined yet (must be zero)
-    locality: u8,
-    /// Size of the input buffer
-    inbuf_size: u32,
-    /// Input buffer that contains the TPM command
-    inbuf: [u8; SEND_COMMAND_REQ_INBUF_SIZE],
-}
-
-impl TpmSendCommandRequest {
-    // Take as slice and return a reference for Self
-    pub fn try_from_as_ref(buffer: &[u8]) -> Result<&Self, SvsmReqError> {
-        let buffer = buffer
-            .get(..size_of::<Self>())
-            .ok_or_else(SvsmReqError::invalid_parameter)?;
+pub const VTPM_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const VTPM_PROTOCOL_VERSION_MAX: u32 = 1;
 
-        // SAFETY: TpmSendCommandRequest has no invalid representations, as it
-        // is comprised entirely of integer types. It is repr(packed), so its
-        // required alignment is simply 1. We have checked the size, so this
-        // is entirely safe.
-        let request = unsafe { &*buffer.as_ptr().cast::<Self>() };
+pub const VTPM_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: SVSM_VTPM_PROTOCOL,
+    version_min: VTPM_PROTOCOL_VERSION_MIN,
+    version_max: VTPM_PROTOCOL_VERSION_MAX,
+    handler: vtpm_protocol_request,
+    available: ProtocolInfo::always_available,
+};
 
-        Ok(request)
+/// vTPM service, GUID d0798bbc-58d1-40ca-a623-00dc0b1b0166
+pub const VTPM_SERVICE: ServiceInfo = ServiceInfo::new(
+    [
+        0xbc, 0x8b, 0x79, 0xd0, 0xd1, 0x58, 0xca, 0x40, 0xa6, 0x23, 0x00, 0xdc, 0x0b, 0x1b, 0x01,
+        0x66,
+    ],
+    SVSM_VTPM_PROTOCOL,
+);
+
+wire_struct! {
+    /// TPM_SEND_COMMAND request header (SVSM spec, table 16), followed by
+    /// the input buffer that contains the TPM command
+    struct TpmSendCommandRequest: 9 {
+        /// MSSIM platform command ID
+        command: u32,
+        /// Locality usage for the vTPM is not defined yet (must be zero)
+        locality: u8,
+        /// Size of the input buffer
+        inbuf_size: u32,
     }
+}
 
-    pub fn send(&self) -> Result<Vec<u8>, SvsmReqError> {
+impl TpmSendCommandRequest {
+    /// Send the TPM command in `inbuf` on behalf of `vmpl`. Returns the
+    /// buffer holding the response, charged to `vmpl`, and the response
+    /// length.
+    pub fn send(&self, vmpl: usize, inbuf: &[u8]) -> Result<(VmplPages, usize), SvsmReqError> {
         // TODO: Before implementing locality, we need to agree what it means
         // to the platform
         if self.locality != 0 {
@@ -109,69 +124,39 @@ impl TpmSendCommandRequest {
 
         let mut length = self.inbuf_size as usize;
 
-        let tpm_cmd = self
-            .inbuf
+        let tpm_cmd = inbuf
             .get(..length)
             .ok_or_else(SvsmReqError::invalid_parameter)?;
-        let mut buffer: Vec<u8> = Vec::with_capacity(SEND_COMMAND_RESP_OUTBUF_SIZE);
-        buffer.extend_from_slice(tpm_cmd);
-
         // The buffer slice must be large enough to hold the TPM command response
-        buffer.resize(SEND_COMMAND_RESP_OUTBUF_SIZE, 0);
+        let mut pages = VmplPages::allocate(vmpl, PoolId::Vtpm, PageOrder::new(0))?;
+        let buffer = pages
+            .as_mut_slice()
+            .get_mut(..SEND_COMMAND_RESP_OUTBUF_SIZE)
+            .ok_or_else(SvsmReqError::invalid_request)?;
+        buffer
+            .get_mut(..length)
+            .ok_or_else(SvsmReqError::invalid_parameter)?
+            .copy_from_slice(tpm_cmd);
 
         let vtpm = vtpm_get_locked();
-        vtpm.send_tpm_command(buffer.as_mut_slice(), &mut length, self.locality)?;
+        vtpm.send_tpm_command(buffer, &mut length, self.locality)?;
 
         if length > buffer.len() {
             return Err(SvsmReqError::invalid_request());
         }
-        buffer.truncate(length);
 
-        Ok(buffer)
+        Ok((pages, length))
     }
 }
 
-const SEND_COMMAND_RESP_OUTBUF_SIZE: usize = PAGE_SIZE - 4;
+const SEND_COMMAND_RESP_OUTBUF_SIZE: usize = PAGE_SIZE - TpmSendCommandResponse::SIZE;
 
-/// TPM_SEND_COMMAND response structure (SVSM spec, table 17)
-#[derive(Clone, Copy, Debug)]
-#[repr(C, packed)]
-struct TpmSendCommandResponse {
-    /// Size of the output buffer
-    outbuf_size: u32,
-    /// Output buffer that will hold the command response
-    outbuf: [u8; SEND_COMMAND_RESP_OUTBUF_SIZE],
-}
-
-impl TpmSendCommandResponse {
-    // Take as slice and return a &mut Self
-    pub fn try_from_as_mut_ref(buffer: &mut [u8]) -> Result<&mut Self, SvsmReqError> {
-        let buffer = buffer
-            .get_mut(..size_of::<Self>())
-            .ok_or_else(SvsmReqError::invalid_parameter)?;
-
-        // SAFETY: TpmSendCommandResponse has no invalid representations, as it
-        // is comprised entirely of integer types. It is repr(packed), so its
-        // required alignment is simply 1. We have checked the size, so this
-        // is entirely safe.
-        let response = unsafe { &mut *buffer.as_mut_ptr().cast::<Self>() };
-
-        Ok(response)
-    }
-
-    /// Write the response to the outbuf
-    ///
-    /// # Arguments
-    ///
-    /// * `response`: TPM_SEND_COMMAND response slice
-    pub fn set_outbuf(&mut self, response: &[u8]) -> Result<(), SvsmReqError> {
-        self.outbuf
-            .get_mut(..response.len())
-            .ok_or_else(SvsmReqError::invalid_request)?
-            .copy_from_slice(response);
-        self.outbuf_size = response.len() as u32;
-
-        Ok(())
+wire_struct! {
+    /// TPM_SEND_COMMAND response header (SVSM spec, table 17), followed by
+    /// the output buffer that holds the command response
+    struct TpmSendCommandResponse: 4 {
+        /// Size of the output buffer
+        outbuf_size: u32,
     }
 }
 
@@ -190,20 +175,26 @@ fn vtpm_query_request(params: &mut RequestParams) -> Result<(), SvsmReqError> {
 ///
 /// * `buffer`: Contains the TpmSendCommandRequest. It will also be
 ///             used to store the TpmSendCommandResponse as a byte slice
+/// * `vmpl`: VMPL the intermediate response buffer is charged to
 ///
 /// # Returns
 ///
 /// * `u32`: Number of bytes written back to `buffer` as part of
 ///          the TpmSendCommandResponse
-fn tpm_send_command_request(buffer: &mut [u8]) -> Result<u32, SvsmReqError> {
-    let outbuf: Vec<u8> = {
-        let request = TpmSendCommandRequest::try_from_as_ref(buffer)?;
-        request.send()?
+fn tpm_send_command_request(buffer: &mut [u8], vmpl: usize) -> Result<u32, SvsmReqError> {
+    let (outbuf, length) = {
+        let mut reader = WireReader::new(buffer);
+        let request: TpmSendCommandRequest = reader.read()?;
+        request.send(vmpl, reader.remaining())?
+    };
+    let response = TpmSendCommandResponse {
+        outbuf_size: length as u32,
     };
-    let response = TpmSendCommandResponse::try_from_as_mut_ref(buffer)?;
-    let _ = response.set_outbuf(outbuf.as_slice());
+    let mut writer = WireWriter::new(buffer);
+    writer.write(&response)?;
+    writer.bytes(&outbuf.as_slice()[..length])?;
 
-    Ok(outbuf.len() as u32)
+    Ok(length as u32)
 }
 
 fn vtpm_command_request(params: &RequestParams) -> Result<(), SvsmReqError> {
@@ -212,16 +203,19 @@ fn vtpm_command_request(params: &RequestParams) -> Result<(), SvsmReqError> {
     if paddr.is_null() {
         return Err(SvsmReqError::invalid_parameter());
     }
-    if !valid_phys_address(paddr) {
+    if !valid_phys_address_for(paddr, params.vmpl) {
         return Err(SvsmReqError::invalid_address());
     }
 
     // The vTPM buffer size is one page, but it not required to be page aligned.
+    // Map it on demand, as most commands and responses are small enough that
+    // the second page of an unaligned buffer is never touched.
     let start = paddr.page_align();
     let offset = paddr.page_offset();
     let end = (paddr + PAGE_SIZE).page_align_up();
 
-    let guard = PerCPUPageMappingGuard::create(start, end, 0)?;
+    let mapping = Arc::new(VMPhysMem::new_lazy_mapping(start, end - start, true));
+    let guard = this_cpu().new_mapping(mapping)?;
     let vaddr = guard.virt_addr() + offset;
 
     // vTPM common request/response structure (SVSM spec, table 15)
@@ -240,7 +234,7 @@ fn vtpm_command_request(params: &RequestParams) -> Result<(), SvsmReqError> {
     let buffer = unsafe { from_raw_parts_mut(vaddr.as_mut_ptr::<u8>(), PAGE_SIZE) };
 
     let response_size = match cmd {
-        TpmPlatformCommand::SendCommand => tpm_send_command_request(buffer)?,
+        TpmPlatformCommand::SendCommand => tpm_send_command_request(buffer, params.vmpl)?,
     };
 
     GuestPtr::<u32>::new(vaddr).write(response_size)?;
@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Watchdog protocol.
+//!
+//! A watchdog which the guest arms with a timeout and then pets
+//! periodically. When a deadline passes without the guest petting the
+//! watchdog, the SVSM sets [`HealthFlags::WATCHDOG_EXPIRED`] in the
+//! heartbeat page and counts the expiration, so that an orchestration layer
+//! learns that the guest OS is stuck without running an agent in the
+//! guest. This protocol is specific to COCONUT-SVSM and not part of the
+//! SVSM specification.
+//!
+//! Deadlines are checked in the request loop, so an expiration is only
+//! noticed the next time the SVSM runs.
+//!
+//! [`HealthFlags::WATCHDOG_EXPIRED`]: crate::heartbeat::HealthFlags::WATCHDOG_EXPIRED
+
+use crate::heartbeat::{heartbeat_report_watchdog, heartbeat_set_flags, HealthFlags};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::ProtocolInfo;
+use crate::protocols::{RequestParams, SVSM_WATCHDOG_PROTOCOL};
+use crate::time::now;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const SVSM_REQ_WATCHDOG_QUERY: u32 = 0;
+const SVSM_REQ_WATCHDOG_START: u32 = 1;
+const SVSM_REQ_WATCHDOG_PET: u32 = 2;
+const SVSM_REQ_WATCHDOG_STOP: u32 = 3;
+
+pub const WATCHDOG_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const WATCHDOG_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub const WATCHDOG_PROTOCOL_INFO: ProtocolInfo = ProtocolInfo {
+    id: SVSM_WATCHDOG_PROTOCOL,
+    version_min: WATCHDOG_PROTOCOL_VERSION_MIN,
+    version_max: WATCHDOG_PROTOCOL_VERSION_MAX,
+    handler: watchdog_protocol_request,
+    available: ProtocolInfo::always_available,
+};
+
+/// Timeouts are passed in units of 2^20 TSC cycles, like the heartbeat
+/// interval.
+const TIMEOUT_SHIFT: u32 = 20;
+
+/// Largest timeout accepted, in units of 2^20 TSC cycles
+const MAX_TIMEOUT: u64 = u32::MAX as u64;
+
+#[derive(Debug)]
+struct Watchdog {
+    /// TSC value at which the watchdog expires, zero if it is stopped
+    deadline: AtomicU64,
+    /// Time between a pet and the deadline, in TSC cycles
+    timeout: AtomicU64,
+    /// Set once the current deadline was missed
+    expired: AtomicBool,
+}
+
+impl Watchdog {
+    const fn new() -> Self {
+        Self {
+            deadline: AtomicU64::new(0),
+            timeout: AtomicU64::new(0),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    fn start(&self, now: u64, timeout: u64) {
+        self.timeout.store(timeout, Ordering::Relaxed);
+        self.pet(now);
+    }
+
+    /// Moves the deadline `timeout` cycles past `now`. Returns whether the
+    /// watchdog had expired.
+    fn pet(&self, now: u64) -> bool {
+        let deadline = now.saturating_add(self.timeout.load(Ordering::Relaxed));
+        self.deadline.store(deadline.max(1), Ordering::Relaxed);
+        self.expired.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns whether the watchdog had expired.
+    fn stop(&self) -> bool {
+        self.deadline.store(0, Ordering::Relaxed);
+        self.expired.swap(false, Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        self.deadline.load(Ordering::Relaxed) != 0
+    }
+
+    /// Checks the deadline. Returns `true` exactly once per missed deadline.
+    fn check(&self, now: u64) -> bool {
+        let deadline = self.deadline.load(Ordering::Relaxed);
+        deadline != 0 && now >= deadline && !self.expired.swap(true, Ordering::Relaxed)
+    }
+}
+
+static WATCHDOG: Watchdog = Watchdog::new();
+
+/// Reports an expiration of the guest watchdog to the host if its deadline
+/// passed. Called from the request loop.
+pub fn watchdog_poll() {
+    if WATCHDOG.check(now()) {
+        log::warn!("Guest watchdog expired");
+        heartbeat_report_watchdog();
+    }
+}
+
+fn watchdog_recovered() {
+    log::info!("Guest watchdog recovered");
+    heartbeat_set_flags(HealthFlags::WATCHDOG_EXPIRED, false);
+}
+
+fn watchdog_query(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    // Bitmap of the supported calls
+    params.rcx = (1 << SVSM_REQ_WATCHDOG_QUERY)
+        | (1 << SVSM_REQ_WATCHDOG_START)
+        | (1 << SVSM_REQ_WATCHDOG_PET)
+        | (1 << SVSM_REQ_WATCHDOG_STOP);
+    Ok(())
+}
+
+/// Arms the watchdog with the timeout in `rcx`, in units of 2^20 TSC
+/// cycles. A running watchdog is re-armed with the new timeout.
+fn watchdog_start(params: &RequestParams) -> Result<(), SvsmReqError> {
+    if params.rcx == 0 || params.rcx > MAX_TIMEOUT {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    WATCHDOG.start(now(), params.rcx << TIMEOUT_SHIFT);
+    Ok(())
+}
+
+fn watchdog_pet() -> Result<(), SvsmReqError> {
+    if !WATCHDOG.is_running() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    if WATCHDOG.pet(now()) {
+        watchdog_recovered();
+    }
+    Ok(())
+}
+
+fn watchdog_stop() -> Result<(), SvsmReqError> {
+    if WATCHDOG.stop() {
+        watchdog_recovered();
+    }
+    Ok(())
+}
+
+pub fn watchdog_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_REQ_WATCHDOG_QUERY => watchdog_query(params),
+        SVSM_REQ_WATCHDOG_START => watchdog_start(params),
+        SVSM_REQ_WATCHDOG_PET => watchdog_pet(),
+        SVSM_REQ_WATCHDOG_STOP => watchdog_stop(),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let watchdog = Watchdog::new();
+        assert!(!watchdog.check(u64::MAX));
+
+        watchdog.start(1000, 100);
+        assert!(watchdog.is_running());
+        assert!(!watchdog.check(1099));
+        assert!(watchdog.check(1100));
+        // Each missed deadline is only reported once
+        assert!(!watchdog.check(1200));
+
+        assert!(watchdog.pet(1200));
+        assert!(!watchdog.check(1250));
+        assert!(!watchdog.pet(1250));
+        assert!(watchdog.check(1350));
+
+        assert!(watchdog.stop());
+        assert!(!watchdog.is_running());
+        assert!(!watchdog.check(u64::MAX));
+    }
+}
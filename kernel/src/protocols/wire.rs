@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Serialization of protocol request and response structures.
+//!
+//! Structures exchanged with the guest through memory are decoded from and
+//! encoded to byte buffers field by field, in the little-endian layout
+//! defined by the SVSM specification. Structures are declared with
+//! [`wire_struct!`](crate::wire_struct), which checks their encoded size
+//! against the specification at compile time. Reserved fields are declared
+//! with [`Reserved`] and must be zero, and version fields with [`Version`]
+//! are checked against the range supported by the SVSM.
+
+use crate::protocols::errors::SvsmReqError;
+
+/// A type with a fixed-size, little-endian encoding in guest memory
+pub trait Wire: Sized {
+    /// Size in bytes of the encoded value
+    const SIZE: usize;
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, SvsmReqError>;
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), SvsmReqError>;
+
+    /// Decode a value from the start of `buf`. Trailing bytes are ignored.
+    fn from_bytes(buf: &[u8]) -> Result<Self, SvsmReqError> {
+        WireReader::new(buf).read()
+    }
+
+    /// Encode the value to the start of `buf`, returning the number of bytes
+    /// written.
+    fn to_bytes(&self, buf: &mut [u8]) -> Result<usize, SvsmReqError> {
+        let mut writer = WireWriter::new(buf);
+        writer.write(self)?;
+        Ok(writer.position())
+    }
+}
+
+/// Cursor decoding [`Wire`] values from a byte buffer
+#[derive(Debug)]
+pub struct WireReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn read<T: Wire>(&mut self) -> Result<T, SvsmReqError> {
+        T::decode(self)
+    }
+
+    /// Consume the next `len` bytes
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], SvsmReqError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        let bytes = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SvsmReqError> {
+        let mut arr = [0u8; N];
+        arr.copy_from_slice(self.bytes(N)?);
+        Ok(arr)
+    }
+
+    /// The bytes that have not been consumed yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+/// Cursor encoding [`Wire`] values into a byte buffer
+#[derive(Debug)]
+pub struct WireWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> WireWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn write<T: Wire>(&mut self, value: &T) -> Result<(), SvsmReqError> {
+        value.encode(self)
+    }
+
+    /// Append `bytes` to the buffer
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), SvsmReqError> {
+        let end = self
+            .pos
+            .checked_add(bytes.len())
+            .ok_or_else(SvsmReqError::invalid_request)?;
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or_else(SvsmReqError::invalid_request)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Number of bytes written so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+macro_rules! impl_wire_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Wire for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                fn decode(reader: &mut WireReader<'_>) -> Result<Self, SvsmReqError> {
+                    reader.array().map(<$ty>::from_le_bytes)
+                }
+
+                fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), SvsmReqError> {
+                    writer.bytes(&self.to_le_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_int!(u8, u16, u32, u64);
+
+impl<const N: usize> Wire for [u8; N] {
+    const SIZE: usize = N;
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, SvsmReqError> {
+        reader.array()
+    }
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), SvsmReqError> {
+        writer.bytes(self)
+    }
+}
+
+/// `N` reserved bytes. Decoding fails unless all of them are zero, and they
+/// are always encoded as zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reserved<const N: usize>;
+
+impl<const N: usize> Wire for Reserved<N> {
+    const SIZE: usize = N;
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, SvsmReqError> {
+        if reader.bytes(N)?.iter().all(|b| *b == 0) {
+            Ok(Self)
+        } else {
+            Err(SvsmReqError::invalid_parameter())
+        }
+    }
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), SvsmReqError> {
+        writer.bytes(&[0u8; N])
+    }
+}
+
+/// A 32-bit structure version. Decoding fails if the version is outside of
+/// `MIN..=MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Version<const MIN: u32, const MAX: u32>(u32);
+
+impl<const MIN: u32, const MAX: u32> Version<MIN, MAX> {
+    pub fn new(version: u32) -> Option<Self> {
+        (MIN..=MAX).contains(&version).then_some(Self(version))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl<const MIN: u32, const MAX: u32> Wire for Version<MIN, MAX> {
+    const SIZE: usize = 4;
+
+    fn decode(reader: &mut WireReader<'_>) -> Result<Self, SvsmReqError> {
+        Self::new(reader.read()?).ok_or_else(SvsmReqError::invalid_parameter)
+    }
+
+    fn encode(&self, writer: &mut WireWriter<'_>) -> Result<(), SvsmReqError> {
+        writer.write(&self.0)
+    }
+}
+
+/// Declare a structure with a [`Wire`] encoding consisting of its fields in
+/// declaration order, without padding. The size given after the structure
+/// name is the size from the specification and is checked at compile time.
+///
+/// ```ignore
+/// wire_struct! {
+///     /// PVALIDATE request header (SVSM spec, table 9)
+///     struct PValidateRequest: 8 {
+///         entries: u16,
+///         next: u16,
+///         resv: Reserved<4>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! wire_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident: $size:literal {
+            $(
+                $(#[$fmeta:meta])*
+                $fvis:vis $field:ident: $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        $vis struct $name {
+            $(
+                $(#[$fmeta])*
+                $fvis $field: $ty,
+            )*
+        }
+
+        impl $crate::protocols::wire::Wire for $name {
+            const SIZE: usize = 0 $(+ <$ty as $crate::protocols::wire::Wire>::SIZE)*;
+
+            fn decode(
+                reader: &mut $crate::protocols::wire::WireReader<'_>,
+            ) -> Result<Self, $crate::protocols::errors::SvsmReqError> {
+                Ok(Self {
+                    $($field: reader.read()?,)*
+                })
+            }
+
+            fn encode(
+                &self,
+                writer: &mut $crate::protocols::wire::WireWriter<'_>,
+            ) -> Result<(), $crate::protocols::errors::SvsmReqError> {
+                $(writer.write(&self.$field)?;)*
+                Ok(())
+            }
+        }
+
+        const _: () = assert!(
+            <$name as $crate::protocols::wire::Wire>::SIZE == $size,
+            concat!("size of ", stringify!($name), " does not match the specification")
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    wire_struct! {
+        struct TestRequest: 16 {
+            version: Version<1, 2>,
+            flags: u8,
+            resv: Reserved<3>,
+            addr: u64,
+        }
+    }
+
+    const ENCODED: [u8; 16] = [
+        2, 0, 0, 0, 0x80, 0, 0, 0, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+    ];
+
+    #[test]
+    fn decode_little_endian() {
+        let req = TestRequest::from_bytes(&ENCODED).unwrap();
+        assert_eq!(req.version.get(), 2);
+        assert_eq!(req.flags, 0x80);
+        assert_eq!(req.addr, 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let req = TestRequest::from_bytes(&ENCODED).unwrap();
+        let mut buf = [0xffu8; 20];
+        assert_eq!(req.to_bytes(&mut buf).unwrap(), TestRequest::SIZE);
+        assert_eq!(buf[..16], ENCODED);
+        assert_eq!(buf[16..], [0xff; 4]);
+    }
+
+    #[test]
+    fn reserved_must_be_zero() {
+        let mut bytes = ENCODED;
+        bytes[6] = 1;
+        assert!(TestRequest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn version_out_of_range() {
+        let mut bytes = ENCODED;
+        bytes[0] = 3;
+        assert!(TestRequest::from_bytes(&bytes).is_err());
+        bytes[0] = 0;
+        assert!(TestRequest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn short_buffers() {
+        assert!(TestRequest::from_bytes(&ENCODED[..15]).is_err());
+        let req = TestRequest::from_bytes(&ENCODED).unwrap();
+        assert!(req.to_bytes(&mut [0u8; 15]).is_err());
+    }
+
+    #[test]
+    fn reader_remaining() {
+        let mut reader = WireReader::new(&ENCODED);
+        let _: u32 = reader.read().unwrap();
+        assert_eq!(reader.remaining().len(), 12);
+        assert_eq!(reader.bytes(12).unwrap(), &ENCODED[4..]);
+        assert!(reader.bytes(1).is_err());
+    }
+}
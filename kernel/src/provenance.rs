@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Build provenance information.
+//!
+//! The kernel reserves a zero-filled `.provenance` section, which the build
+//! replaces after linking with a blob generated by
+//! `scripts/gen-provenance.sh`. The blob consists of `key=value` lines,
+//! padded with zero bytes to the size of the section. Builds that skip this
+//! step (e.g. the test kernel) leave the section empty.
+
+use core::ptr;
+use core::str;
+
+/// Size of the `.provenance` section. Must match the size used by
+/// `scripts/gen-provenance.sh`.
+pub const PROVENANCE_SIZE: usize = 256;
+
+#[used]
+#[link_section = ".provenance"]
+static PROVENANCE: [u8; PROVENANCE_SIZE] = [0; PROVENANCE_SIZE];
+
+/// Parsed build provenance blob
+#[derive(Clone, Copy, Debug)]
+pub struct Provenance {
+    buf: [u8; PROVENANCE_SIZE],
+    len: usize,
+}
+
+impl Provenance {
+    /// Parse a provenance blob. Returns `None` if the blob is empty or not
+    /// valid UTF-8.
+    pub fn parse(blob: &[u8]) -> Option<Self> {
+        let len = blob.iter().position(|b| *b == 0).unwrap_or(blob.len());
+        if len == 0 || len > PROVENANCE_SIZE {
+            return None;
+        }
+        str::from_utf8(&blob[..len]).ok()?;
+
+        let mut buf = [0u8; PROVENANCE_SIZE];
+        buf[..len].copy_from_slice(&blob[..len]);
+        Some(Self { buf, len })
+    }
+
+    fn as_str(&self) -> &str {
+        // Checked in parse()
+        str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+
+    /// Iterate over the `(key, value)` pairs in the blob. Malformed lines
+    /// are skipped.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.as_str()
+            .lines()
+            .filter_map(|line| line.split_once('='))
+    }
+
+    /// Returns the value for `key`, if present
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+}
+
+/// Returns the provenance information embedded into this binary, if any.
+pub fn build_provenance() -> Option<Provenance> {
+    let mut blob = [0u8; PROVENANCE_SIZE];
+    for (i, b) in blob.iter_mut().enumerate() {
+        // The section contents are replaced after linking, so the compiler
+        // must not assume the initial all-zero contents.
+        // SAFETY: `i` is within the bounds of the static.
+        *b = unsafe { ptr::read_volatile(PROVENANCE.as_ptr().add(i)) };
+    }
+    Provenance::parse(&blob)
+}
+
+/// Log the build provenance information embedded into this binary
+pub fn log_provenance() {
+    match build_provenance() {
+        Some(provenance) => {
+            for (key, value) in provenance.entries() {
+                log::info!("Build {:<10}: {}", key, value);
+            }
+        }
+        None => log::info!("No build provenance information available"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_blob() {
+        let mut blob = [0u8; PROVENANCE_SIZE];
+        let data = b"commit=0123456789ab\nfeatures=mstpm\nbogus\ntimestamp=1700000000\n";
+        blob[..data.len()].copy_from_slice(data);
+
+        let provenance = Provenance::parse(&blob).unwrap();
+        assert_eq!(provenance.get("commit"), Some("0123456789ab"));
+        assert_eq!(provenance.get("features"), Some("mstpm"));
+        assert_eq!(provenance.get("timestamp"), Some("1700000000"));
+        assert_eq!(provenance.get("bogus"), None);
+        assert_eq!(provenance.entries().count(), 3);
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(Provenance::parse(&[0u8; PROVENANCE_SIZE]).is_none());
+        assert!(Provenance::parse(&[0xffu8, 0xfe]).is_none());
+        assert!(Provenance::parse(&[b'a'; PROVENANCE_SIZE + 1]).is_none());
+    }
+}
@@ -5,18 +5,24 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::cpu::flush_tlb_global_sync;
-use crate::cpu::percpu::{process_requests, this_cpu, wait_for_requests};
+use crate::cpu::panic::park_if_panicking;
+use crate::cpu::percpu::{process_requests, this_cpu, this_cpu_shared, wait_for_requests};
+use crate::cpu::vcpu_state::VcpuState;
 use crate::error::SvsmError;
+use crate::event_channel::event_channel_poll;
+use crate::greq::update::attestation_update_poll;
+use crate::heartbeat::{heartbeat_report_error, heartbeat_tick, HeartbeatError};
+use crate::mm::alloc::check_memory_pressure;
 use crate::mm::GuestPtr;
-use crate::protocols::apic::apic_protocol_request;
+use crate::protocols::accounting::vmpl_charge_call;
 use crate::protocols::core::core_protocol_request;
 use crate::protocols::errors::{SvsmReqError, SvsmResultCode};
+use crate::protocols::registry::protocol_request;
+use crate::protocols::watchdog::watchdog_poll;
+use crate::protocols::{RequestParams, SVSM_CORE_PROTOCOL};
 use crate::sev::ghcb::switch_to_vmpl;
-
-#[cfg(all(feature = "mstpm", not(test)))]
-use crate::protocols::{vtpm::vtpm_protocol_request, SVSM_VTPM_PROTOCOL};
-use crate::protocols::{RequestParams, SVSM_APIC_PROTOCOL, SVSM_CORE_PROTOCOL};
-use crate::sev::vmsa::VMSAControl;
+use crate::sev::msr_emul::{complete_msr_exit, decode_msr_exit, emulate_msr};
+use crate::sev::vmsa::{VMSAControl, VmsaDiff, VmsaRegs};
 use crate::types::GUEST_VMPL;
 use crate::utils::halt;
 use cpuarch::vmsa::GuestVMExit;
@@ -81,6 +87,7 @@ pub fn update_mappings() -> Result<(), SvsmError> {
         Some(paddr) => cpu.map_guest_vmsa(paddr)?,
         None => ret = Err(SvsmError::MissingVMSA),
     }
+    cpu.set_guest_vcpu(locked.vmsa_phys());
 
     if let Some(paddr) = locked.caa_phys() {
         cpu.map_guest_caa(paddr)?
@@ -106,16 +113,45 @@ fn request_loop_once(
         return Ok(false);
     }
 
+    vmpl_charge_call(GUEST_VMPL)?;
+
     match protocol {
-        SVSM_CORE_PROTOCOL => core_protocol_request(request, params).map(|_| true),
-        #[cfg(all(feature = "mstpm", not(test)))]
-        SVSM_VTPM_PROTOCOL => vtpm_protocol_request(request, params).map(|_| true),
-        SVSM_APIC_PROTOCOL => apic_protocol_request(request, params).map(|_| true),
-        _ => Err(SvsmReqError::unsupported_protocol()),
+        SVSM_CORE_PROTOCOL => core_protocol_request(request, params),
+        _ => protocol_request(protocol, request, params),
     }
+    .map(|_| true)
+}
+
+/// Emulate the MSR access which caused the guest VMPL to exit.
+fn handle_msr_exit() {
+    let (msr, access) = {
+        let cpu = this_cpu();
+        let mut vmsa_ref = cpu.guest_vmsa_ref();
+        decode_msr_exit(vmsa_ref.vmsa())
+    };
+
+    // The VMSA reference must be dropped here, as emulated MSRs may need to
+    // access the VMSA themselves.
+    let result = emulate_msr(msr, access);
+
+    let cpu = this_cpu();
+    let mut vmsa_ref = cpu.guest_vmsa_ref();
+    complete_msr_exit(vmsa_ref.vmsa(), access, result);
+}
+
+/// Update the run state of the vCPU of this CPU when it enters or leaves
+/// the guest VMPL. Fails if the vCPU must not enter the guest, for example
+/// because it is parked for migration, or was halted.
+fn set_vcpu_running(running: bool) -> Result<(), SvsmError> {
+    let state = if running {
+        VcpuState::Running
+    } else {
+        VcpuState::Runnable
+    };
+    this_cpu().set_guest_vcpu_state(state).map(|_| ())
 }
 
-fn check_requests() -> Result<bool, SvsmReqError> {
+pub fn check_requests() -> Result<bool, SvsmReqError> {
     let cpu = this_cpu();
     let vmsa_ref = cpu.guest_vmsa_ref();
     if let Some(caa_addr) = vmsa_ref.caa_addr() {
@@ -128,12 +164,27 @@ fn check_requests() -> Result<bool, SvsmReqError> {
     }
 }
 
+/// Revalidates the #HV doorbell page of this CPU if that was requested, e.g.
+/// because the host reported that it restored the guest, or because
+/// another CPU found its registration dropped.
+fn check_hv_doorbell() {
+    if this_cpu_shared().take_hv_doorbell_check() {
+        if let Err(e) = this_cpu().revalidate_hv_doorbell() {
+            log::error!("Failed to revalidate #HV doorbell page: {:?}", e);
+        }
+    }
+}
+
 pub fn request_loop() {
     loop {
+        // Never return to the guest while another CPU is handling a panic.
+        park_if_panicking();
+        check_hv_doorbell();
+
         // Determine whether the guest is runnable.  If not, halt and wait for
         // the guest to execute.  When halting, assume that the hypervisor
         // will schedule the guest VMPL on its own.
-        if update_mappings().is_ok() {
+        if update_mappings().is_ok() && set_vcpu_running(true).is_ok() {
             // Make VMSA runnable again by setting EFER.SVME.  This requires a
             // separate scope so the CPU reference does not outlive the use of
             // the VMSA reference.
@@ -155,10 +206,17 @@ pub fn request_loop() {
             switch_to_vmpl(GUEST_VMPL as u32);
         } else {
             loop {
-                log::debug!("No VMSA or CAA! Halting");
+                log::debug!("No runnable VMSA or CAA! Halting");
                 halt();
+                park_if_panicking();
 
-                if update_mappings().is_ok() {
+                // Keep reporting to the host while the guest is not running
+                // on this CPU.
+                heartbeat_tick();
+
+                check_hv_doorbell();
+
+                if update_mappings().is_ok() && set_vcpu_running(true).is_ok() {
                     break;
                 }
             }
@@ -174,11 +232,30 @@ pub fn request_loop() {
             // Clear EFER.SVME in guest VMSA
             vmsa.disable();
 
+            let exit_code = vmsa.guest_exit_code;
+            match exit_code {
+                GuestVMExit::VMGEXIT | GuestVMExit::MSR => exit_trace.reset(),
+                code => exit_trace.record(code as u64, VmsaRegs::capture(vmsa)),
+            }
+
             let rax = vmsa.rax;
 
             ((rax >> 32) as u32, (rax & 0xffff_ffff) as u32)
         };
 
+        // Fails if the vCPU was halted while it was running, e.g. because
+        // it was deleted. Its last exit must not be handled then.
+        if let Err(e) = set_vcpu_running(false) {
+            log::debug!("vCPU stopped while running: {:?}", e);
+            continue;
+        }
+
+        event_channel_poll();
+        attestation_update_poll();
+        watchdog_poll();
+        heartbeat_tick();
+        check_memory_pressure();
+
         match check_requests() {
             Ok(pending) => {
                 if pending {
@@ -199,18 +276,53 @@ pub fn request_loop() {
                     request,
                     err
                 );
+                heartbeat_report_error(HeartbeatError::FatalRequest);
                 break;
             }
         }
     }
 }
 
+/// Traces guest exits which are not SVSM requests. When the guest exits
+/// repeatedly with the same exit code, which usually means it is stuck on a
+/// fault, the registers changed since the previous exit are logged.
+#[derive(Debug, Default)]
+struct ExitTrace {
+    last: Option<(u64, VmsaRegs)>,
+    repeats: u32,
+}
+
+impl ExitTrace {
+    fn record(&mut self, exit_code: u64, regs: VmsaRegs) {
+        match &self.last {
+            Some((code, last)) if *code == exit_code => {
+                self.repeats += 1;
+                log::debug!(
+                    "Guest exit {:#x} repeated {} times, changed registers:\n{}",
+                    exit_code,
+                    self.repeats,
+                    VmsaDiff::new(last, &regs)
+                );
+            }
+            _ => self.repeats = 0,
+        }
+        self.last = Some((exit_code, regs));
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+        self.repeats = 0;
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn request_processing_main() {
     let apic_id = this_cpu().get_apic_id();
 
     log::info!("Launching request-processing task on CPU {}", apic_id);
 
+    let mut exit_trace = ExitTrace::default();
+
     loop {
         wait_for_requests();
 
@@ -225,6 +337,12 @@ pub extern "C" fn request_processing_main() {
             // Clear EFER.SVME in guest VMSA
             vmsa.disable();
 
+            let exit_code = vmsa.guest_exit_code;
+            match exit_code {
+                GuestVMExit::VMGEXIT | GuestVMExit::MSR => exit_trace.reset(),
+                code => exit_trace.record(code as u64, VmsaRegs::capture(vmsa)),
+            }
+
             rax = vmsa.rax;
             RequestInfo {
                 protocol: (rax >> 32) as u32,
@@ -233,6 +351,11 @@ pub extern "C" fn request_processing_main() {
             }
         };
 
+        if matches!(request_info.params.guest_exit_code, GuestVMExit::MSR) {
+            handle_msr_exit();
+            continue;
+        }
+
         rax = match request_loop_once(
             &mut request_info.params,
             request_info.protocol,
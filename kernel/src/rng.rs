@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Sources of random numbers.
+//!
+//! Code which needs randomness, such as the generation of nonces, either
+//! takes a [`RandomSource`] so that callers can inject one, or uses the
+//! global functions of this module, which are backed by the `RDRAND`
+//! instruction. In test builds they are backed by a [`DeterministicRng`]
+//! with a fixed seed instead, so that test runs are reproducible.
+
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use core::arch::asm;
+
+/// A source of random numbers
+pub trait RandomSource: Sync {
+    /// Returns a random 64-bit number, or [`SvsmError::NotSupported`] if
+    /// the source cannot provide one.
+    fn next_u64(&self) -> Result<u64, SvsmError>;
+
+    /// Fill `buf` with random bytes
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<(), SvsmError> {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64()?.to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// Random numbers from the `RDRAND` instruction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RdRand;
+
+impl RdRand {
+    /// Number of attempts recommended by Intel and AMD before giving up on
+    /// a transiently exhausted `RDRAND`
+    const RETRIES: usize = 10;
+}
+
+impl RandomSource for RdRand {
+    fn next_u64(&self) -> Result<u64, SvsmError> {
+        for _ in 0..Self::RETRIES {
+            let value: u64;
+            let ok: u8;
+            // SAFETY: RDRAND has no side effects beyond its outputs.
+            unsafe {
+                asm!("rdrand {0}",
+                     "setc {1}",
+                     out(reg) value,
+                     out(reg_byte) ok,
+                     options(nomem, nostack));
+            }
+            if ok != 0 {
+                return Ok(value);
+            }
+        }
+        Err(SvsmError::NotSupported)
+    }
+}
+
+/// Reproducible pseudo-random numbers for tests. Not suitable for anything
+/// security related.
+#[derive(Debug)]
+pub struct DeterministicRng {
+    state: SpinLock<u64>,
+}
+
+impl DeterministicRng {
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            state: SpinLock::new(seed),
+        }
+    }
+}
+
+impl RandomSource for DeterministicRng {
+    // SplitMix64
+    fn next_u64(&self) -> Result<u64, SvsmError> {
+        let mut state = self.state.lock();
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Ok(z ^ (z >> 31))
+    }
+}
+
+#[cfg(not(test))]
+static SYSTEM_RNG: RdRand = RdRand;
+#[cfg(test)]
+static SYSTEM_RNG: DeterministicRng = DeterministicRng::new(0);
+
+/// The source used by [`random_u64()`] and [`random_bytes()`]
+pub fn system_rng() -> &'static dyn RandomSource {
+    &SYSTEM_RNG
+}
+
+/// Returns a random 64-bit number from the system source
+pub fn random_u64() -> Result<u64, SvsmError> {
+    SYSTEM_RNG.next_u64()
+}
+
+/// Fill `buf` with random bytes from the system source
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), SvsmError> {
+    SYSTEM_RNG.fill_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let a = DeterministicRng::new(1234);
+        let b = DeterministicRng::new(1234);
+        for _ in 0..16 {
+            assert_eq!(a.next_u64().unwrap(), b.next_u64().unwrap());
+        }
+        assert_ne!(
+            DeterministicRng::new(0).next_u64().unwrap(),
+            DeterministicRng::new(1).next_u64().unwrap()
+        );
+    }
+
+    #[test]
+    fn splitmix_reference() {
+        // First output of SplitMix64 seeded with 0
+        let rng = DeterministicRng::new(0);
+        assert_eq!(rng.next_u64().unwrap(), 0xe220_a839_7b1d_cdaf);
+    }
+
+    #[test]
+    fn fill_partial_chunk() {
+        let rng = DeterministicRng::new(7);
+        let mut buf = [0u8; 11];
+        rng.fill_bytes(&mut buf).unwrap();
+
+        let rng = DeterministicRng::new(7);
+        let first = rng.next_u64().unwrap().to_le_bytes();
+        let second = rng.next_u64().unwrap().to_le_bytes();
+        assert_eq!(buf[..8], first);
+        assert_eq!(buf[8..], second[..3]);
+    }
+}
@@ -18,15 +18,19 @@ use crate::platform::PageStateChangeOp;
 use crate::sev::hv_doorbell::HVDoorbell;
 use crate::sev::sev_snp_enabled;
 use crate::sev::utils::raw_vmgexit;
-use crate::types::{Bytes, PageSize, GUEST_VMPL, PAGE_SIZE_2M};
+use crate::types::{Bytes, PageSize, GUEST_VMPL, PAGE_SIZE, PAGE_SIZE_2M};
 use crate::utils::MemoryRegion;
 
 use core::arch::global_asm;
 use core::cell::Cell;
+use core::fmt;
 use core::mem::{self, offset_of};
+use core::ops::Range;
 use core::ptr;
 
-use super::msr_protocol::{invalidate_page_msr, register_ghcb_gpa_msr, validate_page_msr};
+use super::msr_protocol::{
+    invalidate_page_msr, register_ghcb_gpa_msr, sev_caps, validate_page_msr, GHCBHvFeatures,
+};
 use super::{pvalidate, PvalidateOp};
 
 #[repr(C, packed)]
@@ -50,6 +54,19 @@ const PSC_FLAG_HUGE: u64 = 1 << PSC_FLAG_HUGE_SHIFT;
 
 const GHCB_BUFFER_SIZE: usize = 0x7f0;
 
+/// The shared buffer is reserved in slots of this many bytes
+const SCRATCH_SLOT_SIZE: usize = 16;
+const SCRATCH_SLOTS: usize = GHCB_BUFFER_SIZE / SCRATCH_SLOT_SIZE;
+
+/// Released parts of the shared buffer are filled with this byte
+const SCRATCH_POISON: u8 = 0xa5;
+
+/// Largest part of the shared buffer a single page state change batch may
+/// reserve, leaving the rest to requests issued from nested contexts
+const PSC_SCRATCH_MAX: usize = SCRATCH_SLOTS / 2 * SCRATCH_SLOT_SIZE;
+/// Page state change header plus a single entry
+const PSC_SCRATCH_MIN: usize = 16;
+
 macro_rules! ghcb_getter {
     ($name:ident, $field:ident,$t:ty) => {
         #[allow(unused)]
@@ -79,6 +96,10 @@ pub enum GhcbError {
     VmgexitInvalid,
     // A response from the hypervisor included an error code
     VmgexitError(u64, u64),
+    // The GHCB is not in the state required for a lifecycle transition
+    InvalidState(GhcbState),
+    // Not enough of the shared buffer is free for a request
+    ScratchExhausted,
 }
 
 impl From<GhcbError> for SvsmError {
@@ -87,6 +108,44 @@ impl From<GhcbError> for SvsmError {
     }
 }
 
+/// Lifecycle state of a per-CPU GHCB page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GhcbState {
+    /// The page is not registered with the hypervisor
+    Unregistered = 0,
+    /// The page is registered and idle
+    Registered = 1,
+    /// A VMGEXIT using the page is in progress
+    InUse = 2,
+    /// The page was unregistered temporarily, for instance while the CPU is
+    /// in a deep idle state, and must be registered again before use
+    Parked = 3,
+}
+
+impl GhcbState {
+    pub const fn from_u8(val: u8) -> Self {
+        match val {
+            1 => Self::Registered,
+            2 => Self::InUse,
+            3 => Self::Parked,
+            _ => Self::Unregistered,
+        }
+    }
+}
+
+impl fmt::Display for GhcbState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Unregistered => "unregistered",
+            Self::Registered => "registered",
+            Self::InUse => "in use",
+            Self::Parked => "parked",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u64)]
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
@@ -104,6 +163,7 @@ enum GHCBExitCode {
     CONFIGURE_INT_INJ = 0x8000_0019,
     DISABLE_ALT_INJ = 0x8000_001A,
     SPECIFIC_EOI = 0x8000_001B,
+    HV_FEATURES = 0x8000_FFFD,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -358,8 +418,15 @@ impl GHCB {
 
         let ghcb_address = VirtAddr::from(self as *const GHCB);
         let ghcb_pa = u64::from(virt_to_phys(ghcb_address));
+        // Record the exit so that a hang in the hypervisor can be attributed
+        // to this CPU. Restoring the previous state keeps nested exits from
+        // exception handlers consistent.
+        let shared = this_cpu().shared();
+        let state = shared.ghcb_state();
+        shared.set_ghcb_state(GhcbState::InUse);
         write_msr(SEV_GHCB, ghcb_pa);
         raw_vmgexit();
+        shared.set_ghcb_state(state);
 
         let sw_exit_info_1 = self.get_exit_info_1_valid()?;
         if sw_exit_info_1 != 0 {
@@ -408,6 +475,51 @@ impl GHCB {
         Ok(())
     }
 
+    /// Reserves `len` bytes of the shared buffer for a single request. The
+    /// bytes are poisoned when the returned [`ScratchRegion`] is dropped, so
+    /// requests issued from nested contexts, like exception handlers, never
+    /// share bytes of the buffer or see each other's data.
+    pub fn reserve_scratch(&self, len: usize) -> Result<ScratchRegion<'_>, GhcbError> {
+        let scratch = this_cpu().ghcb_scratch();
+        let slots = scratch.reserve(len)?;
+        Ok(ScratchRegion {
+            ghcb: self,
+            scratch,
+            offset: slots.start * SCRATCH_SLOT_SIZE,
+            len,
+        })
+    }
+
+    /// Like [`GHCB::reserve_scratch()`], but reserves as much of the shared
+    /// buffer as is free, between `min` and `max` bytes. Meant for requests
+    /// which can be split into batches.
+    pub fn reserve_scratch_up_to(
+        &self,
+        min: usize,
+        max: usize,
+    ) -> Result<ScratchRegion<'_>, GhcbError> {
+        let scratch = this_cpu().ghcb_scratch();
+        let slots = scratch.reserve_up_to(min, max)?;
+        Ok(ScratchRegion {
+            ghcb: self,
+            scratch,
+            offset: slots.start * SCRATCH_SLOT_SIZE,
+            len: slots.len() * SCRATCH_SLOT_SIZE,
+        })
+    }
+
+    fn poison_buffer(&self, offset: usize, len: usize) {
+        assert!(offset + len <= GHCB_BUFFER_SIZE);
+        // SAFETY: the range was checked to be within the buffer.
+        unsafe {
+            self.buffer
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .write_bytes(SCRATCH_POISON, len)
+        }
+    }
+
     fn write_buffer<T>(&self, data: &T, offset: usize) -> Result<(), GhcbError>
     where
         T: Copy,
@@ -447,14 +559,43 @@ impl GHCB {
         entry
     }
 
+    /// Fallback for hypervisors without support for the page state change
+    /// NAE event, which changes the state of one 4K page at a time through
+    /// the GHCB MSR protocol. Huge page operations are not available.
+    fn page_state_change_msr(
+        region: MemoryRegion<PhysAddr>,
+        op: PageStateChangeOp,
+    ) -> Result<(), SvsmError> {
+        let change = match op {
+            PageStateChangeOp::Private => validate_page_msr,
+            PageStateChangeOp::Shared => invalidate_page_msr,
+            PageStateChangeOp::Psmash | PageStateChangeOp::Unsmash => {
+                return Err(SvsmError::NotSupported)
+            }
+        };
+
+        for paddr in region.iter_pages(PageSize::Regular) {
+            change(paddr)?;
+        }
+        Ok(())
+    }
+
     pub fn page_state_change(
         &self,
         region: MemoryRegion<PhysAddr>,
         size: PageSize,
         op: PageStateChangeOp,
     ) -> Result<(), SvsmError> {
-        // Maximum entries (8 bytes each_ minus 8 bytes for header
-        let max_entries: u16 = ((GHCB_BUFFER_SIZE - 8) / 8).try_into().unwrap();
+        if !sev_caps().page_state_change() {
+            return Self::page_state_change_msr(region, op);
+        }
+
+        // Entries are 8 bytes each after an 8 byte header. Batches never
+        // take more than PSC_SCRATCH_MAX bytes of the shared buffer, so
+        // that a page state change from a nested context still finds room.
+        let needed = 8 + region.len().div_ceil(PAGE_SIZE) * 8;
+        let scratch = self.reserve_scratch_up_to(PSC_SCRATCH_MIN, needed.min(PSC_SCRATCH_MAX))?;
+        let max_entries: u16 = ((scratch.len() - 8) / 8).try_into().unwrap();
         let mut entries: u16 = 0;
         let mut paddr = region.start();
         let end = region.end();
@@ -479,7 +620,7 @@ impl GHCB {
             let pgsize = usize::from(size);
             let entry = self.psc_entry(paddr, op_mask, 0, size);
             let offset = usize::from(entries) * 8 + 8;
-            self.write_buffer(&entry, offset)?;
+            scratch.write(&entry, offset)?;
             entries += 1;
             paddr = paddr + pgsize;
 
@@ -489,11 +630,8 @@ impl GHCB {
                     end_entry: entries - 1,
                     reserved: 0,
                 };
-                self.write_buffer(&header, 0)?;
-
-                let buffer_va = VirtAddr::from(self.buffer.as_ptr());
-                let buffer_pa = u64::from(virt_to_phys(buffer_va));
-                self.set_sw_scratch_valid(buffer_pa);
+                scratch.write(&header, 0)?;
+                self.set_sw_scratch_valid(u64::from(scratch.phys_addr()));
 
                 if let Err(mut e) = self.vmgexit(GHCBExitCode::SNP_PSC, 0, 0) {
                     if let Err(err) = self.get_exit_info_2_valid() {
@@ -555,6 +693,25 @@ impl GHCB {
         Ok(())
     }
 
+    /// Returns the #HV doorbell page currently registered with the
+    /// hypervisor for this CPU, or `None` if there is none.
+    pub fn query_hv_doorbell(&self) -> Result<Option<PhysAddr>, SvsmError> {
+        self.clear();
+        self.vmgexit(GHCBExitCode::HV_DOORBELL, 2, 0)?;
+        let paddr = self.get_exit_info_2_valid()?;
+        Ok((paddr != 0).then_some(PhysAddr::from(paddr)))
+    }
+
+    /// Queries the hypervisor features through the GHCB. Unlike the MSR
+    /// protocol request performed at boot, this can be issued at any time to
+    /// detect changes in what the hypervisor supports.
+    pub fn hypervisor_features(&self) -> Result<GHCBHvFeatures, SvsmError> {
+        self.clear();
+        self.vmgexit(GHCBExitCode::HV_FEATURES, 0, 0)?;
+        let features = self.get_exit_info_2_valid()?;
+        Ok(GHCBHvFeatures::from_bits_truncate(features))
+    }
+
     pub fn guest_request(&self, req_page: VirtAddr, resp_page: VirtAddr) -> Result<(), SvsmError> {
         self.clear();
 
@@ -683,6 +840,99 @@ impl GHCB {
     }
 }
 
+/// Tracks which parts of the shared buffer of a GHCB are reserved. Each
+/// CPU has one for its GHCB.
+#[derive(Debug, Default)]
+pub struct GhcbScratch {
+    /// Bitmap of reserved slots
+    used: Cell<u128>,
+}
+
+impl GhcbScratch {
+    pub const fn new() -> Self {
+        Self { used: Cell::new(0) }
+    }
+
+    /// Reserves the first free range of slots which fits `len` bytes.
+    fn reserve(&self, len: usize) -> Result<Range<usize>, GhcbError> {
+        let count = len.div_ceil(SCRATCH_SLOT_SIZE).max(1);
+        if count > SCRATCH_SLOTS {
+            return Err(GhcbError::ScratchExhausted);
+        }
+        let mask = (1u128 << count) - 1;
+        let used = self.used.get();
+        let start = (0..=SCRATCH_SLOTS - count)
+            .find(|start| used & (mask << start) == 0)
+            .ok_or(GhcbError::ScratchExhausted)?;
+        self.used.set(used | (mask << start));
+        Ok(start..start + count)
+    }
+
+    /// Reserves the largest free range of slots which fits at least `min`
+    /// and at most `max` bytes.
+    fn reserve_up_to(&self, min: usize, max: usize) -> Result<Range<usize>, GhcbError> {
+        let min_count = min.div_ceil(SCRATCH_SLOT_SIZE).max(1);
+        let max_count = max.div_ceil(SCRATCH_SLOT_SIZE).min(SCRATCH_SLOTS);
+        (min_count..=max_count)
+            .rev()
+            .find_map(|count| self.reserve(count * SCRATCH_SLOT_SIZE).ok())
+            .ok_or(GhcbError::ScratchExhausted)
+    }
+
+    fn release(&self, slots: Range<usize>) {
+        let mask = ((1u128 << slots.len()) - 1) << slots.start;
+        let used = self.used.get();
+        assert_eq!(used & mask, mask, "Releasing unreserved GHCB scratch");
+        self.used.set(used & !mask);
+    }
+}
+
+/// Part of the shared buffer of a GHCB reserved for a single request
+#[derive(Debug)]
+pub struct ScratchRegion<'a> {
+    ghcb: &'a GHCB,
+    scratch: &'a GhcbScratch,
+    /// Offset into the shared buffer
+    offset: usize,
+    len: usize,
+}
+
+impl ScratchRegion<'_> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the guest physical address of the region, to be passed to
+    /// the hypervisor in `SW_SCRATCH`.
+    pub fn phys_addr(&self) -> PhysAddr {
+        let buffer = VirtAddr::from(self.ghcb.buffer.as_ptr());
+        virt_to_phys(buffer) + self.offset
+    }
+
+    /// Writes `data` at `offset` into the region.
+    pub fn write<T: Copy>(&self, data: &T, offset: usize) -> Result<(), GhcbError> {
+        offset
+            .checked_add(mem::size_of::<T>())
+            .filter(|end| *end <= self.len)
+            .ok_or(GhcbError::InvalidOffset)?;
+        self.ghcb.write_buffer(data, self.offset + offset)
+    }
+}
+
+impl Drop for ScratchRegion<'_> {
+    fn drop(&mut self) {
+        let start = self.offset / SCRATCH_SLOT_SIZE;
+        let count = self.len.div_ceil(SCRATCH_SLOT_SIZE).max(1);
+        self.ghcb
+            .poison_buffer(self.offset, count * SCRATCH_SLOT_SIZE);
+        self.scratch.release(start..start + count);
+    }
+}
+
 extern "C" {
     pub fn switch_to_vmpl_unsafe(hv_doorbell: *const HVDoorbell, vmpl: u32) -> bool;
 }
@@ -697,7 +947,7 @@ pub fn switch_to_vmpl(vmpl: u32) {
             // Process any pending #HV events before leaving the SVSM.  No event
             // can cancel the request to enter the guest VMPL, so proceed with
             // guest entry once events have been handled.
-            doorbell.process_pending_events();
+            doorbell.process_pending_events(None);
             ptr::from_ref(doorbell)
         }
         None => ptr::null(),
@@ -758,6 +1008,8 @@ global_asm!(
         jz no_pending_events
         testw $0x8000, (%rdi)
         jz no_pending_events
+        /* There is no interrupted context to pass. */
+        xorl %esi, %esi
         call process_hv_events
     no_pending_events:
         movl $1, %eax
@@ -791,4 +1043,61 @@ mod tests {
         assert_eq!(offset_of!(GHCB, usage), 0xffc);
         assert_eq!(mem::size_of::<GHCB>(), 0x1000);
     }
+
+    #[test]
+    fn test_ghcb_state() {
+        for state in [
+            GhcbState::Unregistered,
+            GhcbState::Registered,
+            GhcbState::InUse,
+            GhcbState::Parked,
+        ] {
+            assert_eq!(GhcbState::from_u8(state as u8), state);
+        }
+    }
+
+    #[test]
+    fn test_ghcb_scratch() {
+        let scratch = GhcbScratch::new();
+        let a = scratch.reserve(8).unwrap();
+        let b = scratch.reserve(SCRATCH_SLOT_SIZE + 1).unwrap();
+        assert_eq!(a, 0..1);
+        assert_eq!(b, 1..3);
+        assert!(scratch.reserve(GHCB_BUFFER_SIZE).is_err());
+
+        // Released slots are reused by the next fitting request
+        scratch.release(a);
+        assert_eq!(scratch.reserve(SCRATCH_SLOT_SIZE).unwrap(), 0..1);
+        assert_eq!(
+            scratch
+                .reserve(GHCB_BUFFER_SIZE - 3 * SCRATCH_SLOT_SIZE)
+                .unwrap(),
+            3..SCRATCH_SLOTS
+        );
+        assert!(scratch.reserve(1).is_err());
+    }
+
+    #[test]
+    fn test_ghcb_scratch_up_to() {
+        let scratch = GhcbScratch::new();
+        let psc = scratch
+            .reserve_up_to(PSC_SCRATCH_MIN, PSC_SCRATCH_MAX)
+            .unwrap();
+        assert_eq!(psc, 0..PSC_SCRATCH_MAX / SCRATCH_SLOT_SIZE);
+
+        // A nested page state change still gets a full batch
+        let nested = scratch
+            .reserve_up_to(PSC_SCRATCH_MIN, PSC_SCRATCH_MAX)
+            .unwrap();
+        assert_eq!(nested.len(), psc.len());
+
+        // Smaller batches take whatever is left
+        let rest = scratch
+            .reserve_up_to(PSC_SCRATCH_MIN, PSC_SCRATCH_MAX)
+            .unwrap();
+        assert_eq!(rest, nested.end..SCRATCH_SLOTS);
+        assert!(scratch
+            .reserve_up_to(PSC_SCRATCH_MIN, PSC_SCRATCH_MAX)
+            .is_err());
+    }
 }
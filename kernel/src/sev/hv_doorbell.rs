@@ -3,14 +3,17 @@
 
 use crate::address::VirtAddr;
 use crate::cpu::idt::svsm::common_isr_handler;
-use crate::cpu::percpu::this_cpu;
+use crate::cpu::irq_latency::IrqLatencyTimer;
+use crate::cpu::percpu::{NmiGuard, PerCpuInfo, PERCPU_AREAS};
+use crate::cpu::X86ExceptionContext;
 use crate::error::SvsmError;
 use crate::mm::page_visibility::{make_page_private, make_page_shared};
 use crate::mm::virt_to_phys;
 use crate::sev::ghcb::GHCB;
+use crate::teardown::{register_teardown_hook, TeardownCtx, TeardownReason};
 
 use bitfield_struct::bitfield;
-use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 
 #[bitfield(u8)]
 pub struct HVDoorbellFlags {
@@ -73,7 +76,17 @@ impl HVDoorbell {
         Ok(())
     }
 
-    pub fn process_pending_events(&self) {
+    /// Returns a doorbell page that is no longer registered with the
+    /// hypervisor to a private state, so that it can be freed.
+    pub fn fini(vaddr: VirtAddr) -> Result<(), SvsmError> {
+        make_page_private(vaddr)
+    }
+
+    /// Handles the pending events. `ctx` is the context interrupted by the
+    /// #HV, if the events are processed from the #HV handler.
+    pub fn process_pending_events(&self, ctx: Option<&X86ExceptionContext>) {
+        let timer = IrqLatencyTimer::start();
+
         // Clear the NoFurtherSignal bit before processing.  If any additional
         // signal comes in after processing has commenced, it may be missed by
         // this loop, but it will be detected when interrupts are processed
@@ -100,13 +113,40 @@ impl HVDoorbell {
             if vector == 0 {
                 break;
             }
-            common_isr_handler(vector as usize);
+            common_isr_handler(vector as usize, ctx);
+            timer.record(vector);
         }
 
         // Ignore per-VMPL events; these will be consumed when APIC emulation
         // is performed.
     }
 
+    /// Moves the pending per-VMPL event state from a doorbell page that is
+    /// no longer registered with the hypervisor into this one, so that
+    /// events signalled on the old page are not lost when it is replaced.
+    /// Any vector pending for the SVSM itself must be consumed from the old
+    /// page with [`HVDoorbell::process_pending_events()`] instead.
+    pub fn take_pending_from(&self, old: &Self) {
+        let events = old.per_vmpl_events.swap(0, Ordering::Relaxed);
+        self.per_vmpl_events.fetch_or(events, Ordering::Relaxed);
+
+        for (new, old) in self.per_vmpl.iter().zip(old.per_vmpl.iter()) {
+            for (new_irr, old_irr) in new.irr.iter().zip(old.irr.iter()) {
+                new_irr.fetch_or(old_irr.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+            }
+            for (new_isr, old_isr) in new.isr.iter().zip(old.isr.iter()) {
+                new_isr.fetch_or(old_isr.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+            }
+            // Only carry over the old status if nothing has been signalled
+            // on the new page yet, since the status describes a single
+            // pending vector.
+            let status = old.status.swap(0, Ordering::Relaxed);
+            let _ = new
+                .status
+                .compare_exchange(0, status, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
     pub fn no_eoi_required(&self) -> bool {
         // Check to see if the "no EOI required" flag is set to determine
         // whether an explicit EOI can be avoided.
@@ -134,24 +174,61 @@ impl HVDoorbell {
     }
 }
 
-/// Gets the HV doorbell page configured for this CPU.
-///
-/// # Panics
-///
-/// Panics if te HV doorbell page has not been set up beforehand.
-pub fn current_hv_doorbell() -> &'static HVDoorbell {
-    this_cpu()
-        .hv_doorbell()
-        .expect("HV doorbell page dereferenced before allocating")
-}
-
 /// # Safety
 /// This function takes a raw pointer to the #HV doorbell page because it is
 /// called directly from assembly, and should not be invoked directly from
-/// Rust code.
+/// Rust code. `ctx` is the interrupted context, or `None` if there is none.
 #[no_mangle]
-pub unsafe extern "C" fn process_hv_events(hv_doorbell: *const HVDoorbell) {
+pub unsafe extern "C" fn process_hv_events(
+    hv_doorbell: *const HVDoorbell,
+    ctx: Option<&X86ExceptionContext>,
+) {
+    // #HV is delivered regardless of the locks held by the interrupted code.
+    let _guard = NmiGuard::enter();
     unsafe {
-        (*hv_doorbell).process_pending_events();
+        (*hv_doorbell).process_pending_events(ctx);
+    }
+}
+
+static TEARDOWN_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Registers the teardown of the #HV doorbell pages of all CPUs. Called
+/// by every CPU setting up a doorbell page, only the first call registers
+/// the hook.
+pub fn register_hv_doorbell_teardown() {
+    if TEARDOWN_REGISTERED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Err(e) = register_teardown_hook("#HV doorbell", hv_doorbell_teardown) {
+        log::warn!("Failed to register #HV doorbell teardown: {:?}", e);
+    }
+}
+
+/// Makes the #HV doorbell pages of all CPUs private again. The pages are
+/// leaked rather than freed, as the hypervisor keeps them registered. On
+/// panic, nothing is done: changing the page state takes page table and
+/// GHCB locks, which a parked CPU may hold.
+fn hv_doorbell_teardown(ctx: &TeardownCtx<'_>) -> Result<(), SvsmError> {
+    if ctx.reason() == TeardownReason::Panic {
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    for cpu in PERCPU_AREAS.iter().map(PerCpuInfo::unwrap) {
+        if ctx.expired() {
+            return Err(SvsmError::NotSupported);
+        }
+        let Some(vaddr) = cpu.take_hv_doorbell_page() else {
+            continue;
+        };
+        if let Err(e) = HVDoorbell::fini(vaddr) {
+            log::error!(
+                "Failed to reclaim #HV doorbell page of CPU {}: {:?}",
+                cpu.apic_id(),
+                e
+            );
+            result = Err(e);
+        }
     }
+    result
 }
@@ -0,0 +1,716 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Transport security for the live-migration agent.
+//!
+//! The state exported by the migration agent is confidential guest state,
+//! so it must not reach the host in the clear. Before any state is
+//! transferred, the source and the destination SVSM run a [`Handshake`]
+//! over the channel the host provides. Each side generates an ephemeral
+//! P-384 key and sends a hello message carrying the public key, an
+//! attestation report whose `REPORT_DATA` is the SHA-384 digest of that key
+//! and the certificate table of its platform. A side only proceeds if the
+//! report of its peer binds the received key, is signed by a VCEK which
+//! chains up to a trusted ARK, as checked by a [`ReportVerifier`], and is
+//! accepted by a [`PeerPolicy`]. The ECDH shared secret is then
+//! expanded with HKDF-SHA384, salted with the digest of both hello messages,
+//! into one AES-256-GCM key per direction.
+//!
+//! Exported state is sent as a stream of records, sealed by a [`Sealer`] and
+//! opened by an [`Opener`]. Each record header carries a sequence number,
+//! which is also the nonce, and is authenticated along with the payload, so
+//! the host can neither modify, reorder nor replay records. A stream ends
+//! with a [`RecordType::Final`] record, which lets the receiver tell a
+//! complete stream from a truncated one.
+
+use crate::crypto::aead::{Aes256Gcm, Aes256GcmTrait, AUTHTAG_SIZE, IV_SIZE, KEY_SIZE};
+use crate::error::SvsmError;
+use crate::greq::driver::GuestRequestPriority;
+use crate::greq::msg::SNP_GUEST_REQ_MAX_DATA_SIZE;
+use crate::greq::pld_report::{AttestationReport, SnpReportResponse, USER_DATA_SIZE};
+use crate::greq::services::{alloc_report_buffer, get_regular_report};
+use crate::greq::update::copy_certificates;
+use crate::greq::verify::ReportVerifier;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::wire::{Wire, WireReader, WireWriter};
+use crate::rng::RandomSource;
+use crate::utils::TryVec;
+use crate::wire_struct;
+use core::fmt;
+use core::mem::size_of;
+use p384::ecdh::diffie_hellman;
+use p384::elliptic_curve::sec1::ToEncodedPoint;
+use p384::elliptic_curve::zeroize::Zeroize;
+use p384::{PublicKey, SecretKey};
+use sha2::{Digest, Sha384};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationError {
+    /// A hello message or record is malformed
+    InvalidMessage,
+    /// The peer uses a different version of the transport
+    Version,
+    /// No attestation report could be obtained for the local key
+    Report,
+    /// The peer report does not bind the public key of the peer
+    UnboundKey,
+    /// The peer report or its certificate chain failed verification
+    Unverified,
+    /// The peer policy rejected the peer
+    PeerRejected,
+    /// A record arrived out of order or was replayed
+    Sequence,
+    /// A record failed authentication
+    Authentication,
+    /// The stream was finished or failed before
+    StreamClosed,
+}
+
+impl From<MigrationError> for SvsmError {
+    fn from(err: MigrationError) -> Self {
+        Self::Migration(err)
+    }
+}
+
+/// "SVMH" in little-endian byte order
+pub const HELLO_MAGIC: u32 = 0x484d_5653;
+pub const MIGRATION_VERSION: u32 = 1;
+
+/// Maximum size of the certificate table in a hello message
+const MAX_CERTS_SIZE: usize = SNP_GUEST_REQ_MAX_DATA_SIZE;
+
+/// Size of an uncompressed SEC1-encoded P-384 public key
+const PUBLIC_KEY_SIZE: usize = 97;
+const SECRET_KEY_SIZE: usize = 48;
+
+/// Maximum payload of a single record. Larger buffers are split.
+pub const MAX_RECORD_DATA: usize = 0x10000;
+
+const SOURCE_KEY_INFO: &[u8] = b"svsm migration source to destination";
+const DESTINATION_KEY_INFO: &[u8] = b"svsm migration destination to source";
+
+wire_struct! {
+    /// Header of a hello message, followed by the public key, the
+    /// attestation report and the certificate table of the sender
+    struct HelloHeader: 16 {
+        magic: u32,
+        version: u32,
+        role: u32,
+        certs_len: u32,
+    }
+}
+
+/// Size of a hello message without the certificate table
+pub const HELLO_SIZE: usize = HelloHeader::SIZE + PUBLIC_KEY_SIZE + size_of::<AttestationReport>();
+
+wire_struct! {
+    /// Header of a record, followed by the encrypted payload and the
+    /// authentication tag
+    struct RecordHeader: 16 {
+        seq: u64,
+        record_type: u32,
+        len: u32,
+    }
+}
+
+/// Side of a migration an SVSM is on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Role {
+    Source = 0,
+    Destination = 1,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Self::Source => Self::Destination,
+            Self::Destination => Self::Source,
+        }
+    }
+
+    /// HKDF info for the key protecting records sent by this side
+    fn key_info(self) -> &'static [u8] {
+        match self {
+            Self::Source => SOURCE_KEY_INFO,
+            Self::Destination => DESTINATION_KEY_INFO,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RecordType {
+    /// A chunk of exported state
+    Data = 1,
+    /// End of the stream, without payload
+    Final = 2,
+}
+
+impl TryFrom<u32> for RecordType {
+    type Error = MigrationError;
+
+    fn try_from(val: u32) -> Result<Self, MigrationError> {
+        match val {
+            1 => Ok(Self::Data),
+            2 => Ok(Self::Final),
+            _ => Err(MigrationError::InvalidMessage),
+        }
+    }
+}
+
+/// Decides whether the SVSM on the other side of a migration is trusted
+pub trait PeerPolicy {
+    /// Returns whether to migrate to or from the SVSM which produced `peer`.
+    /// `local` is the report of this SVSM. The signature of `peer` has been
+    /// verified at this point.
+    fn accept(&self, local: &AttestationReport, peer: &AttestationReport) -> bool;
+}
+
+/// Returns whether both reports describe guests with the same launch
+/// measurement and policy, which is what most policies require.
+pub fn same_launch(local: &AttestationReport, peer: &AttestationReport) -> bool {
+    local.measurement() == peer.measurement() && local.policy() == peer.policy()
+}
+
+/// `REPORT_DATA` binding an attestation report to `public_key`
+fn key_binding(public_key: &[u8]) -> [u8; USER_DATA_SIZE] {
+    let mut data = [0u8; USER_DATA_SIZE];
+    data[..48].copy_from_slice(&Sha384::digest(public_key));
+    data
+}
+
+/// Attestation report of this SVSM and the certificate table of its
+/// platform, which the peer needs to verify the report
+#[derive(Debug)]
+pub struct LocalReport {
+    pub report: AttestationReport,
+    pub certs: TryVec<u8>,
+}
+
+/// Requests an attestation report for `user_data` from the PSP and gets the
+/// certificate table, to be passed to [`Handshake::new()`].
+pub fn request_report(user_data: &[u8; USER_DATA_SIZE]) -> Result<LocalReport, SvsmError> {
+    let map_err = |err| match err {
+        SvsmReqError::FatalError(e) => e,
+        SvsmReqError::RequestError(_) => SvsmError::Migration(MigrationError::Report),
+    };
+    // MSG_REPORT_REQ for a VMPL0 report, followed by zeroes
+    let mut buffer = alloc_report_buffer()?;
+    buffer[..USER_DATA_SIZE].copy_from_slice(user_data);
+    get_regular_report(GuestRequestPriority::Svsm, &mut buffer[..]).map_err(map_err)?;
+    let response = SnpReportResponse::try_from_as_ref(&buffer[..]).map_err(map_err)?;
+    response.validate().map_err(map_err)?;
+    let report = *response.report();
+
+    let mut certs = TryVec::from_elem(0u8, MAX_CERTS_SIZE)?;
+    let len = copy_certificates(&mut certs).map_err(map_err)?;
+    certs.truncate(len);
+    Ok(LocalReport { report, certs })
+}
+
+/// Key agreement with the SVSM on the other side of a migration
+#[derive(Debug)]
+pub struct Handshake {
+    role: Role,
+    secret: SecretKey,
+    report: AttestationReport,
+    hello: TryVec<u8>,
+}
+
+impl Handshake {
+    /// Generates an ephemeral key with randomness from `rng` and builds the
+    /// hello message, getting an attestation report for the key from
+    /// `reporter`, usually [`request_report()`].
+    pub fn new<F>(role: Role, rng: &dyn RandomSource, reporter: F) -> Result<Self, SvsmError>
+    where
+        F: FnOnce(&[u8; USER_DATA_SIZE]) -> Result<LocalReport, SvsmError>,
+    {
+        // Candidates outside of the scalar field are rare, just retry.
+        let secret = loop {
+            let mut bytes = [0u8; SECRET_KEY_SIZE];
+            rng.fill_bytes(&mut bytes)?;
+            if let Ok(secret) = SecretKey::from_slice(&bytes) {
+                break secret;
+            }
+        };
+        let public_key = secret.public_key().to_encoded_point(false);
+        let LocalReport { report, certs } = reporter(&key_binding(public_key.as_bytes()))?;
+        if certs.len() > MAX_CERTS_SIZE {
+            return Err(MigrationError::Report.into());
+        }
+
+        let header = HelloHeader {
+            magic: HELLO_MAGIC,
+            version: MIGRATION_VERSION,
+            role: role as u32,
+            certs_len: certs.len() as u32,
+        };
+        let mut hello = TryVec::from_elem(0u8, HELLO_SIZE + certs.len())?;
+        let mut writer = WireWriter::new(&mut hello);
+        writer
+            .write(&header)
+            .and_then(|_| writer.bytes(public_key.as_bytes()))
+            .and_then(|_| writer.bytes(report.as_bytes()))
+            .and_then(|_| writer.bytes(&certs))
+            .map_err(|_| MigrationError::InvalidMessage)?;
+
+        Ok(Self {
+            role,
+            secret,
+            report,
+            hello,
+        })
+    }
+
+    /// The hello message to send to the peer
+    pub fn hello(&self) -> &[u8] {
+        &self.hello
+    }
+
+    /// Checks the hello message of the peer with `verifier` and against
+    /// `policy` and derives the record keys. Returns the [`Sealer`] for
+    /// records sent to the peer and the [`Opener`] for records received from
+    /// it.
+    pub fn finish(
+        self,
+        peer_hello: &[u8],
+        verifier: &dyn ReportVerifier,
+        policy: &dyn PeerPolicy,
+    ) -> Result<(Sealer, Opener), SvsmError> {
+        let mut reader = WireReader::new(peer_hello);
+        let header: HelloHeader = reader.read().map_err(|_| MigrationError::InvalidMessage)?;
+        if header.magic != HELLO_MAGIC {
+            return Err(MigrationError::InvalidMessage.into());
+        }
+        if header.version != MIGRATION_VERSION {
+            return Err(MigrationError::Version.into());
+        }
+        let certs_len = header.certs_len as usize;
+        if header.role != self.role.peer() as u32
+            || certs_len > MAX_CERTS_SIZE
+            || peer_hello.len() != HELLO_SIZE + certs_len
+        {
+            return Err(MigrationError::InvalidMessage.into());
+        }
+        let public_key = reader
+            .bytes(PUBLIC_KEY_SIZE)
+            .map_err(|_| MigrationError::InvalidMessage)?;
+        let report = reader
+            .bytes(size_of::<AttestationReport>())
+            .and_then(AttestationReport::try_from_as_ref)
+            .map_err(|_| MigrationError::InvalidMessage)?;
+
+        if report.report_data() != key_binding(public_key) {
+            return Err(MigrationError::UnboundKey.into());
+        }
+        verifier
+            .verify(report, reader.remaining())
+            .map_err(|_| MigrationError::Unverified)?;
+        if !policy.accept(&self.report, report) {
+            return Err(MigrationError::PeerRejected.into());
+        }
+        let peer_key =
+            PublicKey::from_sec1_bytes(public_key).map_err(|_| MigrationError::InvalidMessage)?;
+
+        // Both sides hash the hello messages in the same order
+        let mut transcript = Sha384::new();
+        match self.role {
+            Role::Source => {
+                transcript.update(&self.hello[..]);
+                transcript.update(peer_hello);
+            }
+            Role::Destination => {
+                transcript.update(peer_hello);
+                transcript.update(&self.hello[..]);
+            }
+        }
+        let salt = transcript.finalize();
+
+        let shared = diffie_hellman(self.secret.to_nonzero_scalar(), peer_key.as_affine());
+        let hkdf = shared.extract::<Sha384>(Some(salt.as_slice()));
+        let mut send_key = [0u8; KEY_SIZE];
+        let mut recv_key = [0u8; KEY_SIZE];
+        hkdf.expand(self.role.key_info(), &mut send_key)
+            .and_then(|_| hkdf.expand(self.role.peer().key_info(), &mut recv_key))
+            .map_err(|_| MigrationError::InvalidMessage)?;
+
+        let keys = (Sealer::new(send_key), Opener::new(recv_key));
+        send_key.zeroize();
+        recv_key.zeroize();
+        Ok(keys)
+    }
+}
+
+fn aead_error(err: SvsmReqError) -> SvsmError {
+    match err {
+        SvsmReqError::FatalError(e) => e,
+        SvsmReqError::RequestError(_) => MigrationError::Authentication.into(),
+    }
+}
+
+fn record_nonce(seq: u64) -> [u8; IV_SIZE] {
+    let mut iv = [0u8; IV_SIZE];
+    iv[IV_SIZE - 8..].copy_from_slice(&seq.to_be_bytes());
+    iv
+}
+
+/// Encrypts the records of an outgoing stream
+pub struct Sealer {
+    key: [u8; KEY_SIZE],
+    seq: u64,
+    closed: bool,
+}
+
+impl Sealer {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            key,
+            seq: 0,
+            closed: false,
+        }
+    }
+
+    fn seal_record(
+        &mut self,
+        record_type: RecordType,
+        data: &[u8],
+        out: &mut TryVec<u8>,
+    ) -> Result<(), SvsmError> {
+        if self.closed {
+            return Err(MigrationError::StreamClosed.into());
+        }
+        let header = RecordHeader {
+            seq: self.seq,
+            record_type: record_type as u32,
+            len: data.len() as u32,
+        };
+        let mut aad = [0u8; RecordHeader::SIZE];
+        header
+            .to_bytes(&mut aad)
+            .map_err(|_| MigrationError::InvalidMessage)?;
+
+        let start = out.len();
+        out.try_resize(start + aad.len() + data.len() + AUTHTAG_SIZE, 0)?;
+        out[start..start + aad.len()].copy_from_slice(&aad);
+        if let Err(e) = Aes256Gcm::encrypt(
+            &record_nonce(self.seq),
+            &self.key,
+            &aad,
+            data,
+            &mut out[start + aad.len()..],
+        ) {
+            out.truncate(start);
+            self.closed = true;
+            return Err(aead_error(e));
+        }
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// Appends records carrying `data` to `out`
+    pub fn seal(&mut self, data: &[u8], out: &mut TryVec<u8>) -> Result<(), SvsmError> {
+        data.chunks(MAX_RECORD_DATA)
+            .try_for_each(|chunk| self.seal_record(RecordType::Data, chunk, out))
+    }
+
+    /// Appends the final record to `out`, after which nothing can be sealed
+    pub fn finish(&mut self, out: &mut TryVec<u8>) -> Result<(), SvsmError> {
+        self.seal_record(RecordType::Final, &[], out)?;
+        self.closed = true;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sealer")
+            .field("seq", &self.seq)
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Sealer {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Decrypts the records of an incoming stream
+pub struct Opener {
+    key: [u8; KEY_SIZE],
+    seq: u64,
+    finished: bool,
+    closed: bool,
+}
+
+impl Opener {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            key,
+            seq: 0,
+            finished: false,
+            closed: false,
+        }
+    }
+
+    /// Returns whether the final record was received. A stream which ends
+    /// before that was truncated.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn open_record(&mut self, buf: &[u8], out: &mut TryVec<u8>) -> Result<usize, SvsmError> {
+        let header = RecordHeader::from_bytes(buf).map_err(|_| MigrationError::InvalidMessage)?;
+        let len = header.len as usize;
+        let record_type = RecordType::try_from(header.record_type)?;
+        if len > MAX_RECORD_DATA || (record_type == RecordType::Final && len != 0) {
+            return Err(MigrationError::InvalidMessage.into());
+        }
+        let end = RecordHeader::SIZE + len + AUTHTAG_SIZE;
+        let ciphertext = buf
+            .get(RecordHeader::SIZE..end)
+            .ok_or(MigrationError::InvalidMessage)?;
+        if header.seq != self.seq {
+            return Err(MigrationError::Sequence.into());
+        }
+
+        let start = out.len();
+        out.try_resize(start + len, 0)?;
+        if let Err(e) = Aes256Gcm::decrypt(
+            &record_nonce(self.seq),
+            &self.key,
+            &buf[..RecordHeader::SIZE],
+            ciphertext,
+            &mut out[start..],
+        ) {
+            out.truncate(start);
+            return Err(aead_error(e));
+        }
+        self.seq += 1;
+        self.finished = record_type == RecordType::Final;
+        Ok(end)
+    }
+
+    /// Decrypts the record at the start of `buf`, appending its payload to
+    /// `out`, and returns the size of the record. The stream cannot be used
+    /// anymore after an error, as the host tampered with it.
+    pub fn open(&mut self, buf: &[u8], out: &mut TryVec<u8>) -> Result<usize, SvsmError> {
+        if self.closed || self.finished {
+            return Err(MigrationError::StreamClosed.into());
+        }
+        self.open_record(buf, out).map_err(|e| {
+            self.closed = true;
+            e
+        })
+    }
+}
+
+impl fmt::Debug for Opener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Opener")
+            .field("seq", &self.seq)
+            .field("finished", &self.finished)
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Opener {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::greq::verify::VerifyError;
+    use crate::mm::alloc::{TestRootMem, DEFAULT_TEST_MEMORY_SIZE};
+    use crate::rng::DeterministicRng;
+
+    const MEASUREMENT_OFFSET: usize = 0x90;
+    const REPORT_DATA_OFFSET: usize = 0x50;
+
+    /// Stand-in for the certificate table, checked by [`FakeVerifier`]
+    const FAKE_CERTS: &[u8] = b"certificates";
+
+    /// Builds an unsigned report with the given measurement
+    fn fake_report(
+        measurement: u8,
+        user_data: &[u8; USER_DATA_SIZE],
+    ) -> Result<LocalReport, SvsmError> {
+        let mut bytes = [0u8; size_of::<AttestationReport>()];
+        bytes[REPORT_DATA_OFFSET..REPORT_DATA_OFFSET + USER_DATA_SIZE].copy_from_slice(user_data);
+        bytes[MEASUREMENT_OFFSET..MEASUREMENT_OFFSET + 48].fill(measurement);
+        let mut certs = TryVec::new();
+        certs.try_extend_from_slice(FAKE_CERTS)?;
+        Ok(LocalReport {
+            report: *AttestationReport::try_from_as_ref(&bytes).unwrap(),
+            certs,
+        })
+    }
+
+    /// Accepts reports sent along with [`FAKE_CERTS`]
+    #[derive(Debug)]
+    struct FakeVerifier;
+
+    impl ReportVerifier for FakeVerifier {
+        fn verify(&self, _: &AttestationReport, certs: &[u8]) -> Result<(), VerifyError> {
+            if certs == FAKE_CERTS {
+                Ok(())
+            } else {
+                Err(VerifyError::Certificate)
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct SameLaunch;
+
+    impl PeerPolicy for SameLaunch {
+        fn accept(&self, local: &AttestationReport, peer: &AttestationReport) -> bool {
+            same_launch(local, peer)
+        }
+    }
+
+    fn handshake(role: Role, seed: u64, measurement: u8) -> Handshake {
+        let rng = DeterministicRng::new(seed);
+        Handshake::new(role, &rng, |data| fake_report(measurement, data)).unwrap()
+    }
+
+    fn connect() -> ((Sealer, Opener), (Sealer, Opener)) {
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xaa);
+        let mut src_hello = [0u8; HELLO_SIZE + FAKE_CERTS.len()];
+        let mut dst_hello = [0u8; HELLO_SIZE + FAKE_CERTS.len()];
+        src_hello.copy_from_slice(src.hello());
+        dst_hello.copy_from_slice(dst.hello());
+        (
+            src.finish(&dst_hello, &FakeVerifier, &SameLaunch).unwrap(),
+            dst.finish(&src_hello, &FakeVerifier, &SameLaunch).unwrap(),
+        )
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let ((mut sealer, _), (_, mut opener)) = connect();
+
+        let mut wire = TryVec::new();
+        sealer.seal(b"vmsa state", &mut wire).unwrap();
+        sealer.seal(b"more state", &mut wire).unwrap();
+        sealer.finish(&mut wire).unwrap();
+        assert!(sealer.seal(b"late", &mut wire).is_err());
+        assert!(!wire.windows(10).any(|w| w == b"vmsa state"));
+
+        let mut data = TryVec::new();
+        let mut pos = 0;
+        while !opener.is_finished() {
+            pos += opener.open(&wire[pos..], &mut data).unwrap();
+        }
+        assert_eq!(pos, wire.len());
+        assert_eq!(&data[..], b"vmsa statemore state");
+    }
+
+    #[test]
+    fn tampering() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let ((mut sealer, _), (_, mut opener)) = connect();
+        let mut wire = TryVec::new();
+        sealer.seal(b"first", &mut wire).unwrap();
+        let first = wire.len();
+        sealer.seal(b"second", &mut wire).unwrap();
+
+        // A modified payload fails authentication and closes the stream
+        let mut modified = wire.try_clone().unwrap();
+        modified[RecordHeader::SIZE] ^= 1;
+        let mut data = TryVec::new();
+        assert!(matches!(
+            opener.open(&modified, &mut data),
+            Err(SvsmError::Migration(MigrationError::Authentication))
+        ));
+        assert!(data.is_empty());
+        assert!(matches!(
+            opener.open(&wire, &mut data),
+            Err(SvsmError::Migration(MigrationError::StreamClosed))
+        ));
+
+        // Records cannot be skipped or replayed
+        let ((mut sealer, _), (_, mut opener)) = connect();
+        let mut wire = TryVec::new();
+        sealer.seal(b"first", &mut wire).unwrap();
+        sealer.seal(b"second", &mut wire).unwrap();
+        assert!(matches!(
+            opener.open(&wire[first..], &mut data),
+            Err(SvsmError::Migration(MigrationError::Sequence))
+        ));
+    }
+
+    #[test]
+    fn peer_checks() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+
+        // Different launch measurement
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xbb);
+        assert!(matches!(
+            src.finish(dst.hello(), &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::PeerRejected))
+        ));
+
+        // Report without a valid certificate chain
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xaa);
+        let mut hello = [0u8; HELLO_SIZE + FAKE_CERTS.len()];
+        hello.copy_from_slice(dst.hello());
+        hello[HELLO_SIZE] ^= 1;
+        assert!(matches!(
+            src.finish(&hello, &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::Unverified))
+        ));
+
+        // Certificate table truncated by the host
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xaa);
+        assert!(matches!(
+            src.finish(&dst.hello()[..HELLO_SIZE], &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::InvalidMessage))
+        ));
+
+        // Different transport version
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xaa);
+        let mut hello = [0u8; HELLO_SIZE + FAKE_CERTS.len()];
+        hello.copy_from_slice(dst.hello());
+        hello[4..8].copy_from_slice(&(MIGRATION_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            src.finish(&hello, &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::Version))
+        ));
+
+        // Public key replaced by the host
+        let src = handshake(Role::Source, 1, 0xaa);
+        let dst = handshake(Role::Destination, 2, 0xaa);
+        let other = handshake(Role::Destination, 3, 0xaa);
+        let mut hello = [0u8; HELLO_SIZE + FAKE_CERTS.len()];
+        hello.copy_from_slice(dst.hello());
+        hello[HelloHeader::SIZE..HelloHeader::SIZE + PUBLIC_KEY_SIZE].copy_from_slice(
+            &other.hello()[HelloHeader::SIZE..HelloHeader::SIZE + PUBLIC_KEY_SIZE],
+        );
+        assert!(matches!(
+            src.finish(&hello, &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::UnboundKey))
+        ));
+
+        // Both sides claiming the same role
+        let src = handshake(Role::Source, 1, 0xaa);
+        let other = handshake(Role::Source, 2, 0xaa);
+        assert!(matches!(
+            src.finish(other.hello(), &FakeVerifier, &SameLaunch),
+            Err(SvsmError::Migration(MigrationError::InvalidMessage))
+        ));
+    }
+}
@@ -6,14 +6,17 @@
 
 pub mod ghcb;
 pub mod hv_doorbell;
+pub mod migration;
+pub mod msr_emul;
 pub mod msr_protocol;
+pub mod rmp_fault;
 pub mod secrets_page;
 pub mod status;
 pub mod vmsa;
 
 pub mod utils;
 
-pub use msr_protocol::init_hypervisor_ghcb_features;
+pub use msr_protocol::init_sev_caps;
 pub use secrets_page::{secrets_page, secrets_page_mut, SecretsPage, VMPCK_SIZE};
 pub use status::sev_status_init;
 pub use status::sev_status_verify;
@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! MSR emulation for guest VMPLs.
+//!
+//! MSR accesses of a guest VMPL which exit to the SVSM are looked up in
+//! [`MSR_TABLE`]. Each entry selects whether the access is emulated by the
+//! SVSM, forwarded to the hypervisor through the GHCB, or denied with a #GP
+//! injected into the guest. MSRs not listed in the table are forwarded.
+
+use crate::cpu::idt::common::GP_VECTOR;
+use crate::cpu::msr::SEV_GHCB;
+use crate::cpu::percpu::{current_ghcb, this_cpu};
+use crate::cpu::X86GeneralRegs;
+use crate::error::SvsmError;
+
+use cpuarch::vmsa::{VmsaEventInject, VmsaEventType, VMSA};
+
+/// First and last MSR of the x2APIC register range
+const MSR_X2APIC_FIRST: u32 = 0x800;
+const MSR_X2APIC_LAST: u32 = 0x8ff;
+
+const MSR_TSC: u32 = 0x10;
+const MSR_TSC_ADJUST: u32 = 0x3b;
+/// SEV-SNP guest TSC frequency, only meaningful with Secure TSC
+const MSR_GUEST_TSC_FREQ: u32 = 0xc001_0134;
+
+/// An MSR access by a guest VMPL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsrAccess {
+    Read,
+    Write(u64),
+}
+
+/// Handler for an emulated MSR. Returns the value read for read accesses
+/// and is ignored for writes. An error results in a #GP in the guest.
+pub type MsrHandler = fn(u32, MsrAccess) -> Result<u64, SvsmError>;
+
+/// What to do with an access to an MSR
+#[derive(Clone, Copy, Debug)]
+pub enum MsrPolicy {
+    /// Handle the access in the SVSM
+    Emulate(MsrHandler),
+    /// Forward the access to the hypervisor
+    PassThrough,
+    /// Forward reads to the hypervisor and deny writes
+    ReadOnly,
+    /// Inject a #GP into the guest
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MsrEntry {
+    first: u32,
+    last: u32,
+    policy: MsrPolicy,
+}
+
+impl MsrEntry {
+    const fn single(msr: u32, policy: MsrPolicy) -> Self {
+        Self {
+            first: msr,
+            last: msr,
+            policy,
+        }
+    }
+
+    const fn range(first: u32, last: u32, policy: MsrPolicy) -> Self {
+        Self {
+            first,
+            last,
+            policy,
+        }
+    }
+}
+
+static MSR_TABLE: &[MsrEntry] = &[
+    // The GHCB MSR of a guest VMPL is managed by the guest and the
+    // hypervisor and must never be serviced on the SVSM's behalf.
+    MsrEntry::single(SEV_GHCB, MsrPolicy::Deny),
+    // x2APIC registers are emulated by the SVSM if the calling CPU uses
+    // APIC emulation.
+    MsrEntry::range(
+        MSR_X2APIC_FIRST,
+        MSR_X2APIC_LAST,
+        MsrPolicy::Emulate(x2apic_msr),
+    ),
+    // The TSC must not be changed behind the back of the other VMPLs.
+    MsrEntry::single(MSR_TSC, MsrPolicy::ReadOnly),
+    MsrEntry::single(MSR_TSC_ADJUST, MsrPolicy::ReadOnly),
+    // Without Secure TSC support in the SVSM, the hypervisor-provided TSC
+    // frequency cannot be trusted and is not exposed.
+    MsrEntry::single(MSR_GUEST_TSC_FREQ, MsrPolicy::Deny),
+];
+
+/// Returns the policy for accesses to `msr`
+pub fn msr_policy(msr: u32) -> MsrPolicy {
+    MSR_TABLE
+        .iter()
+        .find(|e| (e.first..=e.last).contains(&msr))
+        .map_or(MsrPolicy::PassThrough, |e| e.policy)
+}
+
+fn x2apic_msr(msr: u32, access: MsrAccess) -> Result<u64, SvsmError> {
+    let cpu = this_cpu();
+    if !cpu.use_apic_emulation() {
+        return passthrough(msr, access);
+    }
+    match access {
+        MsrAccess::Read => cpu.read_apic_register(msr.into()),
+        MsrAccess::Write(value) => cpu.write_apic_register(msr.into(), value).map(|_| 0),
+    }
+    .map_err(|_| SvsmError::Apic)
+}
+
+fn passthrough(msr: u32, access: MsrAccess) -> Result<u64, SvsmError> {
+    let ghcb = current_ghcb();
+    match access {
+        MsrAccess::Read => {
+            let mut regs = X86GeneralRegs {
+                rcx: msr as usize,
+                ..Default::default()
+            };
+            ghcb.rdmsr_regs(&mut regs)?;
+            Ok(((regs.rdx as u64) << 32) | (regs.rax as u64 & 0xffff_ffff))
+        }
+        MsrAccess::Write(value) => ghcb.wrmsr(msr, value).map(|_| 0),
+    }
+}
+
+/// Perform an MSR access of a guest VMPL according to its policy. Returns
+/// the value read for read accesses, or `None` if the guest must receive a
+/// #GP.
+pub fn emulate_msr(msr: u32, access: MsrAccess) -> Option<u64> {
+    let result = match (msr_policy(msr), access) {
+        (MsrPolicy::Emulate(handler), _) => handler(msr, access),
+        (MsrPolicy::PassThrough, _) | (MsrPolicy::ReadOnly, MsrAccess::Read) => {
+            passthrough(msr, access)
+        }
+        (MsrPolicy::ReadOnly, MsrAccess::Write(_)) | (MsrPolicy::Deny, _) => {
+            Err(SvsmError::NotSupported)
+        }
+    };
+
+    result
+        .inspect_err(|e| log::debug!("Guest MSR {:#x} {:?} failed: {:?}", msr, access, e))
+        .ok()
+}
+
+/// Decode the MSR access of a guest VMPL which exited with an MSR intercept.
+///
+/// The result of [`emulate_msr()`] is applied with [`complete_msr_exit()`].
+/// The guest VMSA must not be borrowed while the access is emulated, since
+/// emulated registers like the x2APIC ones need to access it themselves.
+pub fn decode_msr_exit(vmsa: &VMSA) -> (u32, MsrAccess) {
+    let msr = vmsa.rcx as u32;
+    let access = if vmsa.guest_exitinfo1 & 1 != 0 {
+        MsrAccess::Write(((vmsa.rdx & 0xffff_ffff) << 32) | (vmsa.rax & 0xffff_ffff))
+    } else {
+        MsrAccess::Read
+    };
+    (msr, access)
+}
+
+/// Write the result of an emulated MSR access back to the guest VMSA
+pub fn complete_msr_exit(vmsa: &mut VMSA, access: MsrAccess, result: Option<u64>) {
+    let Some(value) = result else {
+        vmsa.event_inj = VmsaEventInject::new()
+            .with_vector(GP_VECTOR as u8)
+            .with_event_type(VmsaEventType::Exception)
+            .with_error_code_valid(true)
+            .with_error_code(0)
+            .with_valid(true);
+        return;
+    };
+
+    if access == MsrAccess::Read {
+        vmsa.rax = value & 0xffff_ffff;
+        vmsa.rdx = value >> 32;
+    }
+    // RDMSR and WRMSR are both 2 bytes long
+    vmsa.rip += 2;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_lookup() {
+        assert!(matches!(msr_policy(SEV_GHCB), MsrPolicy::Deny));
+        assert!(matches!(msr_policy(0x808), MsrPolicy::Emulate(_)));
+        assert!(matches!(msr_policy(MSR_TSC), MsrPolicy::ReadOnly));
+        assert!(matches!(msr_policy(0x1b), MsrPolicy::PassThrough));
+    }
+
+    #[test]
+    fn denied_access() {
+        assert_eq!(emulate_msr(SEV_GHCB, MsrAccess::Read), None);
+        assert_eq!(emulate_msr(MSR_TSC, MsrAccess::Write(0)), None);
+    }
+}
@@ -23,6 +23,8 @@ pub enum GhcbMsrError {
     // The data section of the response did not match our request,
     // or it was malformed altogether.
     DataMismatch,
+    // The hypervisor supports no GHCB protocol version we implement
+    UnsupportedVersion { min: u16, max: u16 },
 }
 
 impl From<GhcbMsrError> for SvsmError {
@@ -67,67 +69,171 @@ impl Display for GHCBHvFeatures {
     }
 }
 
-static GHCB_HV_FEATURES: ImmutAfterInitCell<GHCBHvFeatures> = ImmutAfterInitCell::uninit();
+/// Lowest GHCB protocol version implemented by the SVSM
+pub const GHCB_VERSION_MIN: u16 = 2;
+/// Highest GHCB protocol version implemented by the SVSM
+pub const GHCB_VERSION_MAX: u16 = 2;
+/// First GHCB protocol version with the SEV-SNP extensions, which include
+/// the hypervisor feature query and all features it reports
+const GHCB_VERSION_SNP: u16 = 2;
 
-/// Check that we support the hypervisor's advertised GHCB versions.
-pub fn verify_ghcb_version() {
+/// SEV capabilities negotiated with the hypervisor at boot. Optional
+/// features are only used if the hypervisor advertises them.
+#[derive(Clone, Copy, Debug)]
+pub struct SevCaps {
+    ghcb_version: u16,
+    hv_features: GHCBHvFeatures,
+}
+
+impl SevCaps {
+    /// Returns the negotiated GHCB protocol version.
+    pub fn ghcb_version(&self) -> u16 {
+        self.ghcb_version
+    }
+
+    /// Returns the features advertised by the hypervisor.
+    pub fn hv_features(&self) -> GHCBHvFeatures {
+        self.hv_features
+    }
+
+    /// Whether the hypervisor supports `feature`. Always `false` if the
+    /// negotiated GHCB protocol version predates the SEV-SNP extensions.
+    pub fn has(&self, feature: GHCBHvFeatures) -> bool {
+        self.ghcb_version >= GHCB_VERSION_SNP && self.hv_features.contains(feature)
+    }
+
+    /// Whether an #HV doorbell page can be used, which requires restricted
+    /// injection.
+    pub fn hv_doorbell(&self) -> bool {
+        self.has(GHCBHvFeatures::SEV_SNP_RESTR_INJ)
+    }
+
+    /// Whether alternate injection can be used.
+    pub fn alternate_injection(&self) -> bool {
+        self.has(GHCBHvFeatures::SEV_SNP_EXT_INTERRUPTS)
+    }
+
+    /// Whether the page state change NAE event can be used.
+    pub fn page_state_change(&self) -> bool {
+        self.has(GHCBHvFeatures::SEV_PAGE_STATE_CHANGE)
+    }
+}
+
+static SEV_CAPS: ImmutAfterInitCell<SevCaps> = ImmutAfterInitCell::uninit();
+
+/// Hypervisor features the SVSM can boot without, along with what is lost
+/// when the hypervisor does not advertise them.
+const OPTIONAL_FEATURES: &[(GHCBHvFeatures, &str)] = &[
+    (
+        GHCBHvFeatures::SEV_SNP_RESTR_INJ,
+        "restricted injection not supported, #HV doorbell and alternate injection disabled",
+    ),
+    (
+        GHCBHvFeatures::SEV_SNP_EXT_INTERRUPTS,
+        "alternate injection not supported, APIC emulation disabled",
+    ),
+    (
+        GHCBHvFeatures::SEV_PAGE_STATE_CHANGE,
+        "page state change NAE event not supported, falling back to the MSR protocol",
+    ),
+];
+
+/// Log which subsystems are disabled or degraded because the hypervisor does
+/// not support the optional features they depend on.
+fn log_missing_optional_features(features: GHCBHvFeatures) {
+    for (feature, consequence) in OPTIONAL_FEATURES {
+        if !features.contains(*feature) {
+            log::warn!("Hypervisor GHCB features: {}", consequence);
+        }
+    }
+}
+
+/// Returns the highest GHCB protocol version supported by both the
+/// hypervisor, which supports `hv_min..=hv_max`, and the SVSM.
+fn negotiate_version(hv_min: u16, hv_max: u16) -> Option<u16> {
+    let version = hv_max.min(GHCB_VERSION_MAX);
+    (version >= hv_min && version >= GHCB_VERSION_MIN).then_some(version)
+}
+
+/// Queries the GHCB protocol versions supported by the hypervisor and
+/// returns the highest one the SVSM implements.
+fn negotiate_ghcb_version() -> Result<u16, GhcbMsrError> {
     // Request SEV information.
     write_msr(SEV_GHCB, GHCBMsr::SEV_INFO_REQ);
     raw_vmgexit();
     let sev_info = read_msr(SEV_GHCB);
 
-    // Parse the results.
-
-    let response_ty = sev_info & 0xfff;
-    assert_eq!(
-        response_ty,
-        GHCBMsr::SEV_INFO_RESP,
-        "unexpected response type: {response_ty:#05x}"
-    );
+    if (sev_info & 0xfff) != GHCBMsr::SEV_INFO_RESP {
+        return Err(GhcbMsrError::InfoMismatch);
+    }
 
-    // Compare announced supported GHCB MSR protocol version range
-    // for compatibility.
-    let min_version = (sev_info >> 32) & 0xffff;
-    let max_version = (sev_info >> 48) & 0xffff;
-    assert!(
-        (min_version..=max_version).contains(&2),
-        "the hypervisor doesn't support GHCB version 2 (min: {min_version}, max: {max_version})"
-    );
+    let min = ((sev_info >> 32) & 0xffff) as u16;
+    let max = ((sev_info >> 48) & 0xffff) as u16;
+    negotiate_version(min, max).ok_or(GhcbMsrError::UnsupportedVersion { min, max })
 }
 
-pub fn hypervisor_ghcb_features() -> GHCBHvFeatures {
-    *GHCB_HV_FEATURES
+/// Returns the SEV capabilities negotiated at boot.
+pub fn sev_caps() -> SevCaps {
+    *SEV_CAPS
 }
 
-pub fn init_hypervisor_ghcb_features() -> Result<(), GhcbMsrError> {
+fn query_hypervisor_features() -> Result<GHCBHvFeatures, GhcbMsrError> {
     write_msr(SEV_GHCB, GHCBMsr::SNP_HV_FEATURES_REQ);
     raw_vmgexit();
     let result = read_msr(SEV_GHCB);
-    if (result & 0xFFF) == GHCBMsr::SNP_HV_FEATURES_RESP {
-        let features = GHCBHvFeatures::from_bits_truncate(result >> 12);
-
-        // Verify that the required features are supported.
-        let required = GHCBHvFeatures::SEV_SNP
-            | GHCBHvFeatures::SEV_SNP_AP_CREATION
-            | GHCBHvFeatures::SEV_SNP_MULTI_VMPL;
-        let missing = !features & required;
-        if !missing.is_empty() {
-            log::error!(
-                "Required hypervisor GHCB features not available: present={:#x}, required={:#x}, missing={:#x}",
-                features, required, missing
-            );
-            // FIXME - enforce this panic once KVM advertises the required
-            // features.
-            // panic!("Required hypervisor GHCB features not available");
-        }
+    if (result & 0xFFF) != GHCBMsr::SNP_HV_FEATURES_RESP {
+        return Err(GhcbMsrError::InfoMismatch);
+    }
+    Ok(GHCBHvFeatures::from_bits_truncate(result >> 12))
+}
 
-        GHCB_HV_FEATURES
-            .init(&features)
-            .expect("Already initialized GHCB HV features");
-        Ok(())
+/// Negotiates the GHCB protocol version and queries the hypervisor
+/// features, and records both in the [`SevCaps`]. Must be called once,
+/// before the GHCB is used. Nothing is logged, as this runs before the
+/// console is set up, see [`log_sev_caps()`].
+pub fn init_sev_caps() -> Result<(), GhcbMsrError> {
+    let ghcb_version = negotiate_ghcb_version()?;
+    // The feature query is part of the SEV-SNP extensions
+    let hv_features = if ghcb_version >= GHCB_VERSION_SNP {
+        query_hypervisor_features()?
     } else {
-        Err(GhcbMsrError::InfoMismatch)
+        GHCBHvFeatures::empty()
+    };
+
+    SEV_CAPS
+        .init(&SevCaps {
+            ghcb_version,
+            hv_features,
+        })
+        .expect("Already initialized SEV capabilities");
+    Ok(())
+}
+
+/// Logs the capabilities recorded by [`init_sev_caps()`], and which
+/// subsystems are affected by missing hypervisor features.
+pub fn log_sev_caps() {
+    let caps = sev_caps();
+    log::info!(
+        "GHCB protocol version {}, hypervisor features {}",
+        caps.ghcb_version(),
+        caps.hv_features()
+    );
+
+    // Verify that the required features are supported.
+    let required = GHCBHvFeatures::SEV_SNP
+        | GHCBHvFeatures::SEV_SNP_AP_CREATION
+        | GHCBHvFeatures::SEV_SNP_MULTI_VMPL;
+    let missing = !caps.hv_features() & required;
+    if !missing.is_empty() {
+        log::error!(
+            "Required hypervisor GHCB features not available: present={:#x}, required={:#x}, missing={:#x}",
+            caps.hv_features(), required, missing
+        );
+        // FIXME - enforce this panic once KVM advertises the required
+        // features.
+        // panic!("Required hypervisor GHCB features not available");
     }
+    log_missing_optional_features(caps.hv_features());
 }
 
 pub fn register_ghcb_gpa_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
@@ -182,8 +288,27 @@ pub fn invalidate_page_msr(addr: PhysAddr) -> Result<(), GhcbMsrError> {
     set_page_valid_status_msr(addr, false)
 }
 
+/// Termination reason code set defined by the GHCB specification
+pub const TERM_REASON_SET_GHCB: u8 = 0;
+/// Termination reason code set for SVSM-specific reasons
+pub const TERM_REASON_SET_SVSM: u8 = 1;
+/// SVSM memory was made inaccessible by a change of its RMP state
+pub const TERM_REASON_SVSM_RMP_VIOLATION: u8 = 0x10;
+/// The initial CPU state supplied by the host failed validation
+pub const TERM_REASON_SVSM_INITIAL_STATE: u8 = 0x11;
+/// Unhandled exception in stage 2. The exception vector is added to this
+/// code, so the range up to 0x3f is reserved.
+pub const TERM_REASON_SVSM_STAGE2_EXCEPTION: u8 = 0x20;
+
 pub fn request_termination_msr() -> ! {
-    let info: u64 = GHCBMsr::TERM_REQ;
+    request_termination_msr_reason(TERM_REASON_SET_GHCB, 0)
+}
+
+/// Request termination of the guest, reporting `reason_code` from the code
+/// set `reason_set` to the hypervisor.
+pub fn request_termination_msr_reason(reason_set: u8, reason_code: u8) -> ! {
+    let info: u64 =
+        GHCBMsr::TERM_REQ | (u64::from(reason_set & 0xf) << 12) | (u64::from(reason_code) << 16);
 
     write_msr(SEV_GHCB, info);
     raw_vmgexit();
@@ -191,3 +316,33 @@ pub fn request_termination_msr() -> ! {
         halt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(1, 2), Some(2));
+        assert_eq!(negotiate_version(2, 5), Some(GHCB_VERSION_MAX));
+        assert_eq!(negotiate_version(1, 1), None);
+        assert_eq!(negotiate_version(GHCB_VERSION_MAX + 1, 5), None);
+    }
+
+    #[test]
+    fn features_need_snp_version() {
+        let features = GHCBHvFeatures::SEV_SNP | GHCBHvFeatures::SEV_SNP_RESTR_INJ;
+        let caps = SevCaps {
+            ghcb_version: GHCB_VERSION_SNP,
+            hv_features: features,
+        };
+        assert!(caps.hv_doorbell());
+        assert!(!caps.page_state_change());
+
+        let caps = SevCaps {
+            ghcb_version: GHCB_VERSION_SNP - 1,
+            hv_features: features,
+        };
+        assert!(!caps.hv_doorbell());
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Handling of RMP violations.
+//!
+//! An access that fails the RMP check raises a #PF with the RMP bit set in
+//! the error code. Depending on which memory was accessed, the violation is
+//! either caused by the guest, by the hypervisor or by a bug in the SVSM,
+//! and each of these is handled differently.
+
+use crate::address::VirtAddr;
+use crate::cpu::extable::{handle_exception_table, in_exception_table};
+use crate::cpu::X86ExceptionContext;
+use crate::mm::virt_in_kernel_mapping;
+use crate::sev::msr_protocol::{
+    request_termination_msr_reason, TERM_REASON_SET_SVSM, TERM_REASON_SVSM_RMP_VIOLATION,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Cause of an RMP violation in SVSM context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RmpFaultCause {
+    /// Access to guest memory through an accessor covered by the exception
+    /// table. The guest changed the RMP state of its own page, so the access
+    /// is failed back to the caller.
+    GuestMemory,
+    /// Access to memory owned by the SVSM. The SVSM never changes the RMP
+    /// state of these pages behind the back of their users, so the
+    /// hypervisor must have done so. The contents of the page can no longer
+    /// be trusted, so revalidating it is not an option and the guest is
+    /// terminated.
+    HostRevoked,
+    /// Any other access. This is a bug in the SVSM, e.g. a guest page mapped
+    /// with the wrong attributes, and results in a panic.
+    SvsmBug,
+}
+
+impl RmpFaultCause {
+    const fn index(self) -> usize {
+        match self {
+            Self::GuestMemory => 0,
+            Self::HostRevoked => 1,
+            Self::SvsmBug => 2,
+        }
+    }
+
+    /// Cause of a violation, given whether the faulting instruction is
+    /// covered by the exception table and whether the accessed address is
+    /// part of the SVSM kernel mappings.
+    const fn from_fault(in_extable: bool, kernel_mapping: bool) -> Self {
+        if in_extable {
+            Self::GuestMemory
+        } else if kernel_mapping {
+            Self::HostRevoked
+        } else {
+            Self::SvsmBug
+        }
+    }
+
+    fn record(self) {
+        RMP_FAULT_COUNTS[self.index()].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static RMP_FAULT_COUNTS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+
+/// Number of RMP violations seen per cause
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RmpFaultStats {
+    pub guest_memory: u64,
+    pub host_revoked: u64,
+    pub svsm_bug: u64,
+}
+
+/// Return the number of RMP violations seen so far, per cause
+pub fn rmp_fault_stats() -> RmpFaultStats {
+    let count = |cause: RmpFaultCause| RMP_FAULT_COUNTS[cause.index()].load(Ordering::Relaxed);
+    RmpFaultStats {
+        guest_memory: count(RmpFaultCause::GuestMemory),
+        host_revoked: count(RmpFaultCause::HostRevoked),
+        svsm_bug: count(RmpFaultCause::SvsmBug),
+    }
+}
+
+/// Reset the RMP violation counters
+pub fn rmp_fault_stats_reset() {
+    for count in RMP_FAULT_COUNTS.iter() {
+        count.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Determine the cause of an RMP violation at `rip` accessing `vaddr`
+pub fn classify_rmp_fault(rip: VirtAddr, vaddr: VirtAddr) -> RmpFaultCause {
+    RmpFaultCause::from_fault(in_exception_table(rip), virt_in_kernel_mapping(vaddr))
+}
+
+/// Handle an RMP violation raised by SVSM code while accessing `vaddr`.
+/// Returns `true` if execution can continue, and `false` if the violation
+/// is an SVSM bug and must be treated as fatal by the caller.
+pub fn handle_rmp_fault(ctxt: &mut X86ExceptionContext, vaddr: VirtAddr) -> bool {
+    let rip = VirtAddr::from(ctxt.frame.rip);
+    let cause = classify_rmp_fault(rip, vaddr);
+    cause.record();
+
+    match cause {
+        RmpFaultCause::GuestMemory => {
+            log::warn!(
+                "RMP violation accessing guest memory at {:#018x} (RIP {:#018x})",
+                vaddr,
+                rip
+            );
+            handle_exception_table(ctxt)
+        }
+        RmpFaultCause::HostRevoked => {
+            log::error!(
+                "RMP violation on SVSM memory at {:#018x} (RIP {:#018x}), page revoked by the host - terminating",
+                vaddr,
+                rip
+            );
+            request_termination_msr_reason(TERM_REASON_SET_SVSM, TERM_REASON_SVSM_RMP_VIOLATION)
+        }
+        RmpFaultCause::SvsmBug => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fault_classification() {
+        // The exception table takes precedence, guest memory may be mapped
+        // anywhere.
+        assert_eq!(
+            RmpFaultCause::from_fault(true, true),
+            RmpFaultCause::GuestMemory
+        );
+        assert_eq!(
+            RmpFaultCause::from_fault(true, false),
+            RmpFaultCause::GuestMemory
+        );
+        assert_eq!(
+            RmpFaultCause::from_fault(false, true),
+            RmpFaultCause::HostRevoked
+        );
+        assert_eq!(
+            RmpFaultCause::from_fault(false, false),
+            RmpFaultCause::SvsmBug
+        );
+    }
+
+    #[test]
+    fn fault_stats() {
+        rmp_fault_stats_reset();
+        RmpFaultCause::HostRevoked.record();
+        RmpFaultCause::SvsmBug.record();
+        RmpFaultCause::SvsmBug.record();
+        assert_eq!(
+            rmp_fault_stats(),
+            RmpFaultStats {
+                guest_memory: 0,
+                host_revoked: 1,
+                svsm_bug: 2,
+            }
+        );
+        rmp_fault_stats_reset();
+        assert_eq!(rmp_fault_stats(), RmpFaultStats::default());
+    }
+}
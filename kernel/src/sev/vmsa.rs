@@ -5,31 +5,31 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use super::utils::{rmp_adjust, RMPFlags};
-use crate::address::{Address, VirtAddr};
+use crate::address::VirtAddr;
 use crate::error::SvsmError;
-use crate::mm::alloc::{allocate_pages, free_page};
+use crate::mm::alloc::{allocate_pages_on_node, free_page, PageAlignment};
 use crate::platform::guest_cpu::GuestCpuState;
 use crate::sev::status::SEVStatusFlags;
 use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::zero_mem_region;
+use crate::utils::{zero_mem_region, PageOrder};
 
+use core::fmt;
 use cpuarch::vmsa::{VmsaEventInject, VmsaEventType, VMSA};
 
 pub const VMPL_MAX: usize = 4;
 
-pub fn allocate_new_vmsa(vmpl: RMPFlags) -> Result<VirtAddr, SvsmError> {
+/// Allocates a VMSA page for `vmpl`, preferring memory local to NUMA node
+/// `node`.
+pub fn allocate_new_vmsa(vmpl: RMPFlags, node: Option<u32>) -> Result<VirtAddr, SvsmError> {
     assert!(vmpl.bits() < (VMPL_MAX as u64));
 
     // Make sure the VMSA page is not 2M aligned. Some hardware generations
     // can't handle this properly.
-    let mut vmsa_page = allocate_pages(0)?;
-    if vmsa_page.is_aligned(PAGE_SIZE_2M) {
-        free_page(vmsa_page);
-        vmsa_page = allocate_pages(1)?;
-        if vmsa_page.is_aligned(PAGE_SIZE_2M) {
-            vmsa_page = vmsa_page + PAGE_SIZE;
-        }
-    }
+    let vmsa_page = allocate_pages_on_node(
+        PageOrder::new(0),
+        Some(PageAlignment::Misaligned(PAGE_SIZE_2M)),
+        node,
+    )?;
 
     zero_mem_region(vmsa_page, vmsa_page + PAGE_SIZE);
 
@@ -158,3 +158,151 @@ impl GuestCpuState for VMSA {
         self.sev_features = sev_status.as_sev_features();
     }
 }
+
+/// Reads the value of one named VMSA field
+type VmsaFieldReader = fn(&VMSA) -> u64;
+
+macro_rules! vmsa_fields {
+    (
+        segments: [$($seg:ident),* $(,)?],
+        registers: [$($reg:ident),* $(,)?],
+        other: [$($name:ident => $read:expr),* $(,)?] $(,)?
+    ) => {
+        const VMSA_FIELDS: &[(&str, VmsaFieldReader)] = &[
+            $(
+                (concat!(stringify!($seg), ".selector"), |v: &VMSA| u64::from(v.$seg.selector)),
+                (concat!(stringify!($seg), ".flags"), |v: &VMSA| u64::from(v.$seg.flags)),
+                (concat!(stringify!($seg), ".limit"), |v: &VMSA| u64::from(v.$seg.limit)),
+                (concat!(stringify!($seg), ".base"), |v: &VMSA| v.$seg.base),
+            )*
+            $((stringify!($reg), |v: &VMSA| u64::from(v.$reg)),)*
+            $((stringify!($name), $read),)*
+        ];
+    };
+}
+
+vmsa_fields! {
+    segments: [es, cs, ss, ds, fs, gs, gdt, ldt, idt, tr],
+    registers: [
+        pl0_ssp, pl1_ssp, pl2_ssp, pl3_ssp, u_cet, vmpl, cpl, efer, xss, cr4, cr3, cr0,
+        dr7, dr6, rflags, rip, dr0, dr1, dr2, dr3, dr0_mask, dr1_mask, dr2_mask, dr3_mask,
+        rsp, s_cet, ssp, isst_addr, rax, star, lstar, cstar, sfmask, kernel_gs_base,
+        sysenter_cs, sysenter_esp, sysenter_eip, cr2, g_pat, dbgctl, br_from, br_to,
+        last_excp_from, last_excp_to, pkru, guest_tsc_scale, guest_tsc_offset,
+        reg_prot_nonce, rcx, rdx, rbx, rbp, rsi, rdi, r8, r9, r10, r11, r12, r13, r14, r15,
+        guest_exitinfo1, guest_exitinfo2, guest_nrip, sev_features, vtom, tlb_id, pcpu_id,
+        xcr0, x87_dp, mxcsr, x87_ftw, x87_fsw, x87_fcw, x87_fop, x87_ds, x87_cs, x87_rip,
+    ],
+    other: [
+        guest_exitintinfo => |v: &VMSA| u64::from(v.guest_exitintinfo),
+        vintr_ctrl => |v: &VMSA| u64::from(v.vintr_ctrl),
+        guest_exit_code => |v: &VMSA| v.guest_exit_code as u64,
+        event_inj => |v: &VMSA| u64::from(v.event_inj),
+    ],
+}
+
+/// Snapshot of the named register fields of a VMSA. The FPU register
+/// contents and reserved fields are not included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmsaRegs {
+    values: [u64; VMSA_FIELDS.len()],
+}
+
+impl VmsaRegs {
+    pub fn capture(vmsa: &VMSA) -> Self {
+        let mut values = [0u64; VMSA_FIELDS.len()];
+        for (value, (_, read)) in values.iter_mut().zip(VMSA_FIELDS) {
+            *value = read(vmsa);
+        }
+        Self { values }
+    }
+
+    /// Returns the value of the field called `name`, using the names
+    /// printed by [`VmsaDiff`].
+    pub fn get(&self, name: &str) -> Option<u64> {
+        VMSA_FIELDS
+            .iter()
+            .position(|(n, _)| *n == name)
+            .map(|i| self.values[i])
+    }
+}
+
+/// The fields which changed between two VMSA snapshots. Formatting a
+/// `VmsaDiff` prints one line per changed field, with the old and the new
+/// value.
+#[derive(Clone, Copy, Debug)]
+pub struct VmsaDiff<'a> {
+    old: &'a VmsaRegs,
+    new: &'a VmsaRegs,
+}
+
+impl<'a> VmsaDiff<'a> {
+    pub fn new(old: &'a VmsaRegs, new: &'a VmsaRegs) -> Self {
+        Self { old, new }
+    }
+
+    /// Iterates over the changed fields as `(name, old, new)`
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64, u64)> + 'a {
+        let (old, new) = (self.old, self.new);
+        VMSA_FIELDS
+            .iter()
+            .zip(old.values.iter().zip(new.values.iter()))
+            .filter(|(_, (a, b))| a != b)
+            .map(|((name, _), (a, b))| (*name, *a, *b))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+impl fmt::Display for VmsaDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "  (no changes)");
+        }
+        for (i, (name, old, new)) in self.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  {:<16} {:#018x} -> {:#018x}", name, old, new)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::format;
+    use cpuarch::vmsa::GuestVMExit;
+
+    #[test]
+    fn vmsa_diff() {
+        let mut vmsa = Box::<VMSA>::default();
+        vmsa.rip = 0x1000;
+        vmsa.cs.selector = 0x8;
+        let old = VmsaRegs::capture(&vmsa);
+        assert_eq!(old.get("rip"), Some(0x1000));
+        assert_eq!(old.get("cs.selector"), Some(0x8));
+        assert_eq!(old.get("fpreg_x87"), None);
+        assert!(VmsaDiff::new(&old, &old).is_empty());
+
+        vmsa.rip = 0x1004;
+        vmsa.cr2 = 0xdead_b000;
+        vmsa.guest_exit_code = GuestVMExit::NPF;
+        let new = VmsaRegs::capture(&vmsa);
+        let diff = VmsaDiff::new(&old, &new);
+        let changed: [(&str, u64, u64); 3] = [
+            ("rip", 0x1000, 0x1004),
+            ("cr2", 0, 0xdead_b000),
+            ("guest_exit_code", GuestVMExit::INVALID as u64, 0x400),
+        ];
+        assert!(diff.iter().eq(changed));
+        assert!(format!("{}", diff)
+            .starts_with("  rip              0x0000000000001000 -> 0x0000000000001004\n"));
+    }
+}
@@ -23,12 +23,13 @@ use svsm::console::{init_console, install_console_logger};
 use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table};
 use svsm::cpu::gdt;
 use svsm::cpu::idt::stage2::{early_idt_init, early_idt_init_no_ghcb};
+use svsm::cpu::initial_state::{DescriptorTableReg, DescriptorTables, InitialCpuState};
+use svsm::cpu::msr::rdtsc;
 use svsm::cpu::percpu::{this_cpu, PerCpu};
 use svsm::error::SvsmError;
 use svsm::fw_cfg::FwCfg;
 use svsm::igvm_params::IgvmParams;
 use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
-use svsm::mm::init_kernel_mapping_info;
 use svsm::mm::pagetable::{
     get_init_pgtable_locked, paging_init_early, set_init_pgtable, PTEntryFlags, PageTable,
     PageTableRef,
@@ -36,8 +37,13 @@ use svsm::mm::pagetable::{
 use svsm::mm::validate::{
     init_valid_bitmap_alloc, valid_bitmap_addr, valid_bitmap_set_valid_range,
 };
+use svsm::mm::{init_kernel_mapping_info, random_slide, LayoutSlides, SVSM_EXTRA_MEM_BASE};
 use svsm::platform::{PageStateChangeOp, SvsmPlatform, SvsmPlatformCell};
+use svsm::rng::{RandomSource, RdRand};
 use svsm::serial::SerialPort;
+use svsm::sev::msr_protocol::{
+    request_termination_msr_reason, TERM_REASON_SET_SVSM, TERM_REASON_SVSM_INITIAL_STATE,
+};
 use svsm::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
 use svsm::utils::immut_after_init::ImmutAfterInitCell;
 use svsm::utils::{halt, is_aligned, MemoryRegion};
@@ -47,6 +53,32 @@ extern "C" {
     pub static heap_end: u8;
     pub static mut pgtable: PageTable;
     pub static CPUID_PAGE: SnpCpuidTable;
+    static gdt64_desc: DescriptorTableReg;
+}
+
+fn verify_initial_state(state: &InitialCpuState, platform_type: SvsmPlatformType) {
+    // Only the SEV-SNP launch VMSA has known descriptor tables: an empty
+    // IDT, so that any exception before the SVSM installs its own handlers
+    // shuts the guest down, and the GDT the boot code loads over it. The
+    // TDX stage 1 path starts with the architectural reset IDTR.
+    let tables = matches!(platform_type, SvsmPlatformType::Snp).then(|| DescriptorTables {
+        // SAFETY: gdt64_desc is initialized by the boot code and never
+        // changes.
+        gdtr: unsafe { gdt64_desc },
+        idtr: DescriptorTableReg::default(),
+    });
+    let Err(e) = state.verify(tables) else {
+        return;
+    };
+
+    log::error!(
+        "Refusing to run with host-supplied initial CPU state: {}",
+        e
+    );
+    if matches!(platform_type, SvsmPlatformType::Snp) {
+        request_termination_msr_reason(TERM_REASON_SET_SVSM, TERM_REASON_SVSM_INITIAL_STATE);
+    }
+    panic!("Invalid initial CPU state: {}", e);
 }
 
 fn setup_stage2_allocator() {
@@ -79,10 +111,11 @@ static CONSOLE_SERIAL: ImmutAfterInitCell<SerialPort<'_>> = ImmutAfterInitCell::
 fn setup_env(
     config: &SvsmConfig<'_>,
     platform: &mut dyn SvsmPlatform,
+    platform_type: SvsmPlatformType,
     launch_info: &Stage2LaunchInfo,
 ) {
     gdt().load();
-    early_idt_init_no_ghcb();
+    early_idt_init_no_ghcb(platform_type);
     platform.env_setup();
 
     install_console_logger("Stage2").expect("Console logger already initialized");
@@ -291,9 +324,28 @@ fn load_igvm_params(
     Ok((igvm_vregion, igvm_pregion))
 }
 
+/// Calls `f` with the source of randomness for the SVSM memory layout and
+/// returns its result, or `default` if layout randomization is disabled or
+/// fails.
+fn randomize_layout<T>(
+    config: &SvsmConfig<'_>,
+    default: T,
+    f: impl FnOnce(&dyn RandomSource) -> Result<T, SvsmError>,
+) -> T {
+    if !config.layout_randomization() {
+        return default;
+    }
+    f(&RdRand).unwrap_or_else(|e| {
+        log::warn!("Failed to randomize SVSM memory layout: {:?}", e);
+        default
+    })
+}
+
 /// Maps any remaining memory between the end of the kernel image and the end
 /// of the allocated kernel memory region as heap space. Exclude any memory
-/// reserved by the configuration.
+/// reserved by the configuration. Unless layout randomization is disabled,
+/// the heap is mapped at a random distance from the kernel image, below the
+/// window for additional SVSM memory.
 ///
 /// # Panics
 ///
@@ -309,7 +361,6 @@ fn prepare_heap(
 ) -> Result<(MemoryRegion<VirtAddr>, MemoryRegion<PhysAddr>), SvsmError> {
     // Heap starts after kernel
     let heap_pstart = loaded_kernel_pregion.end();
-    let heap_vstart = loaded_kernel_vregion.end();
 
     // Compute size, excluding any memory reserved by the configuration.
     let heap_size = kernel_region
@@ -318,6 +369,12 @@ fn prepare_heap(
         .and_then(|r| r.checked_sub(config.reserved_kernel_area_size()))
         .expect("Insufficient physical space for kernel image")
         .into();
+
+    // The offset between virtual and physical heap addresses must stay
+    // 2M-aligned, so slide the heap in 2M steps.
+    let max_slide = (SVSM_EXTRA_MEM_BASE - loaded_kernel_vregion.end()).saturating_sub(heap_size);
+    let slide = randomize_layout(config, 0, |rng| random_slide(rng, max_slide, PAGE_SIZE_2M));
+    let heap_vstart = loaded_kernel_vregion.end() + slide;
     let heap_pregion = MemoryRegion::new(heap_pstart, heap_size);
     let heap_vregion = MemoryRegion::new(heap_vstart, heap_size);
 
@@ -328,12 +385,18 @@ fn prepare_heap(
 
 #[no_mangle]
 pub extern "C" fn stage2_main(launch_info: &Stage2LaunchInfo) {
+    // Capture the state left by the host and the boot code before it is
+    // changed, but only check it once errors can be reported.
+    let initial_state = InitialCpuState::capture();
+    let entry_tsc = rdtsc();
+
     let platform_type = SvsmPlatformType::from(launch_info.platform_type);
     let mut platform_cell = SvsmPlatformCell::new(platform_type);
     let platform = platform_cell.as_mut_dyn_ref();
 
     let config = get_svsm_config(launch_info, platform).expect("Failed to get SVSM configuration");
-    setup_env(&config, platform, launch_info);
+    setup_env(&config, platform, platform_type, launch_info);
+    verify_initial_state(&initial_state, platform_type);
 
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM) Stage 2 Loader");
 
@@ -385,6 +448,8 @@ pub extern "C" fn stage2_main(launch_info: &Stage2LaunchInfo) {
     )
     .expect("Failed to map and validate heap");
 
+    let slides = randomize_layout(&config, LayoutSlides::none(), LayoutSlides::random);
+
     // Build the handover information describing the memory layout and hand
     // control to the SVSM kernel.
     let launch_info = KernelLaunchInfo {
@@ -392,6 +457,9 @@ pub extern "C" fn stage2_main(launch_info: &Stage2LaunchInfo) {
         kernel_region_phys_end: u64::from(kernel_region.end()),
         heap_area_phys_start: u64::from(heap_pregion.start()),
         heap_area_virt_start: u64::from(heap_vregion.start()),
+        percpu_base_slide: slides.percpu_base as u64,
+        percpu_stacks_slide: slides.percpu_stacks as u64,
+        pertask_stack_slide: slides.pertask_stack as u64,
         heap_area_size: heap_vregion.len() as u64,
         kernel_region_virt_start: u64::from(loaded_kernel_vregion.start()),
         kernel_elf_stage2_virt_start: u64::from(launch_info.kernel_elf_start),
@@ -405,6 +473,7 @@ pub extern "C" fn stage2_main(launch_info: &Stage2LaunchInfo) {
         igvm_params_phys_addr: u64::from(igvm_pregion.start()),
         igvm_params_virt_addr: u64::from(igvm_vregion.start()),
         vtom: launch_info.vtom,
+        stage2_entry_tsc: entry_tsc,
         debug_serial_port: config.debug_serial_port(),
         use_alternate_injection: config.use_alternate_injection(),
         platform_type,
@@ -424,6 +493,7 @@ pub extern "C" fn stage2_main(launch_info: &Stage2LaunchInfo) {
         "  kernel_virtual_base   = {:#018x}",
         loaded_kernel_vregion.start()
     );
+    log::info!("  heap_virtual_base     = {:#018x}", heap_vregion.start());
 
     let valid_bitmap = valid_bitmap_addr();
 
@@ -17,6 +17,7 @@ use core::ptr;
 use core::slice;
 use cpuarch::snp_cpuid::SnpCpuidTable;
 use svsm::address::{PhysAddr, VirtAddr};
+use svsm::boot_time::{boot_phase_reached, boot_phase_reached_at, log_boot_phases, BootPhase};
 use svsm::config::SvsmConfig;
 use svsm::console::{init_console, install_console_logger};
 use svsm::cpu::control_regs::{cr0_init, cr4_init};
@@ -24,32 +25,48 @@ use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table};
 use svsm::cpu::efer::efer_init;
 use svsm::cpu::gdt;
 use svsm::cpu::idt::svsm::{early_idt_init, idt_init};
+use svsm::cpu::panic::{dump_parked_cpus, panic_begin, park_this_cpu};
 use svsm::cpu::percpu::current_ghcb;
 use svsm::cpu::percpu::PerCpu;
-use svsm::cpu::percpu::{this_cpu, this_cpu_shared};
+use svsm::cpu::percpu::{hv_doorbell_events_init, this_cpu, this_cpu_shared};
 use svsm::cpu::smp::start_secondary_cpus;
 use svsm::debug::gdbstub::svsm_gdbstub::{debug_break, gdbstub_start};
 use svsm::debug::stacktrace::print_stack;
 use svsm::error::SvsmError;
+use svsm::event_channel::event_channel_init;
 use svsm::fs::{initialize_fs, populate_ram_fs};
 use svsm::fw_cfg::FwCfg;
 use svsm::greq::driver::guest_request_driver_init;
+use svsm::greq::update::attestation_update_init;
+use svsm::heartbeat::{heartbeat_init, heartbeat_set_flags, HealthFlags};
 use svsm::igvm_params::IgvmParams;
 use svsm::kernel_region::new_kernel_region;
 use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
-use svsm::mm::memory::{init_memory_map, write_guest_memory_map};
-use svsm::mm::pagetable::paging_init;
+use svsm::mm::memory::{
+    enable_validation_tracking, init_memory_map, record_guest_validation, write_guest_memory_map,
+};
+use svsm::mm::pagetable::{self, paging_init};
+use svsm::mm::pool::{dump_pool_stats, pool, PoolId};
+use svsm::mm::shared_pool::shared_pool_init;
 use svsm::mm::virtualrange::virt_log_usage;
-use svsm::mm::{init_kernel_mapping_info, PerCPUPageMappingGuard};
+use svsm::mm::{
+    init_kernel_mapping_info, init_layout_slides, LayoutSlides, PerCPUPageMappingGuard,
+};
 use svsm::platform::{SvsmPlatformCell, SVSM_PLATFORM};
+use svsm::prevalidate::prevalidate_guest_memory;
+use svsm::protocols::accounting::set_vmpl_limits;
+use svsm::protocols::memstate::set_memstate_queries_enabled;
+use svsm::protocols::register_protocols;
+use svsm::provenance::log_provenance;
 use svsm::requests::{request_loop, request_processing_main, update_mappings};
 use svsm::serial::SerialPort;
 use svsm::sev::utils::{rmp_adjust, RMPFlags};
 use svsm::sev::{secrets_page, secrets_page_mut};
 use svsm::svsm_console::SVSMIOPort;
-use svsm::svsm_paging::{init_page_table, invalidate_early_boot_memory};
+use svsm::svsm_paging::{init_extra_svsm_memory, init_page_table, invalidate_early_boot_memory};
 use svsm::task::exec_user;
-use svsm::task::{create_kernel_task, schedule_init};
+use svsm::task::{create_kernel_task, schedule_init, set_rt_budget};
+use svsm::teardown::{run_teardown, TeardownReason};
 use svsm::types::{PageSize, GUEST_VMPL, PAGE_SIZE};
 use svsm::utils::{halt, immut_after_init::ImmutAfterInitCell, zero_mem_region};
 #[cfg(all(feature = "mstpm", not(test)))]
@@ -220,6 +237,7 @@ fn validate_fw(config: &SvsmConfig<'_>, launch_info: &KernelLaunchInfo) -> Resul
                 return Err(e);
             }
         }
+        record_guest_validation(region, true)?;
     }
 
     Ok(())
@@ -250,6 +268,11 @@ fn mapping_info_init(launch_info: &KernelLaunchInfo) {
         VirtAddr::from(launch_info.heap_area_virt_end()),
         PhysAddr::from(launch_info.heap_area_phys_start),
     );
+    init_layout_slides(&LayoutSlides {
+        percpu_base: launch_info.percpu_base_slide as usize,
+        percpu_stacks: launch_info.percpu_stacks_slide as usize,
+        pertask_stack: launch_info.pertask_stack_slide as usize,
+    });
 }
 
 /// # Panics
@@ -279,6 +302,8 @@ fn init_cpuid_table(addr: VirtAddr) {
 #[no_mangle]
 pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: usize) {
     let launch_info: KernelLaunchInfo = *li;
+    boot_phase_reached_at(BootPhase::Stage2Entry, launch_info.stage2_entry_tsc);
+    boot_phase_reached(BootPhase::KernelEntry);
     let vb_ptr = VirtAddr::new(vb_addr).as_mut_ptr::<u64>();
 
     mapping_info_init(&launch_info);
@@ -328,6 +353,7 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: usize) {
 
     paging_init(platform, li.vtom).expect("Failed to initialize paging");
     init_page_table(&launch_info, &kernel_elf).expect("Could not initialize the page table");
+    boot_phase_reached(BootPhase::PagingUp);
 
     // SAFETY: this PerCpu has just been allocated and no other CPUs have been
     // brought up, thus it cannot be aliased and we can get a mutable
@@ -359,6 +385,7 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: usize) {
     install_console_logger("SVSM").expect("Console logger already initialized");
 
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM)");
+    log_provenance();
 
     dump_cpuid_table();
     platform.env_setup_late();
@@ -371,9 +398,17 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: usize) {
     let bp = this_cpu().get_top_of_stack();
     log::info!("BSP Runtime stack starts @ {:#018x}", bp);
 
-    platform
-        .configure_alternate_injection(launch_info.use_alternate_injection)
-        .expect("Alternate injection required but not available");
+    if let Err(e) = platform.configure_alternate_injection(launch_info.use_alternate_injection) {
+        // Without alternate injection the guest can still run, just without
+        // APIC emulation by the SVSM.
+        log::warn!(
+            "Alternate injection requested but not available ({:?}), APIC emulation disabled",
+            e
+        );
+        platform
+            .configure_alternate_injection(false)
+            .expect("Failed to disable alternate injection");
+    }
 
     SVSM_PLATFORM
         .init(&platform_cell)
@@ -411,7 +446,13 @@ pub extern "C" fn svsm_main() {
         SvsmConfig::FirmwareConfig(FwCfg::new(&CONSOLE_IO))
     };
 
+    set_rt_budget(config.rt_task_budget());
+
+    init_extra_svsm_memory(platform, &config).expect("Failed to add SVSM memory");
     init_memory_map(&config, &LAUNCH_INFO).expect("Failed to init guest memory map");
+    if config.memory_state_queries() {
+        enable_validation_tracking();
+    }
 
     initialize_fs();
 
@@ -433,6 +474,7 @@ pub extern "C" fn svsm_main() {
     log::info!("{} CPU(s) present", nr_cpus);
 
     start_secondary_cpus(platform, &cpus, launch_info.vtom);
+    boot_phase_reached(BootPhase::SmpDone);
 
     let fw_metadata = config.get_fw_metadata();
     if let Some(ref fw_meta) = fw_metadata {
@@ -449,15 +491,53 @@ pub extern "C" fn svsm_main() {
         prepare_fw_launch(fw_meta).expect("Failed to setup guest VMSA/CAA");
     }
 
+    set_memstate_queries_enabled(config.memory_state_queries());
+    register_protocols().expect("Failed to register SVSM protocols");
+    set_vmpl_limits(GUEST_VMPL, config.guest_vmpl_limits())
+        .expect("Failed to set guest resource limits");
+    pool(PoolId::PageTables).set_limit(config.page_table_pool_limit());
+
+    if let Err(e) = shared_pool_init(config.shared_pool_pages()) {
+        log::error!("Failed to set up shared memory pool: {:?}", e);
+    }
+
+    if let Some(params) = config.event_channel() {
+        if let Err(e) = event_channel_init(params) {
+            log::error!("Failed to set up host event channel: {:?}", e);
+        } else {
+            if let Err(e) = attestation_update_init() {
+                log::error!("Failed to register attestation update handler: {:?}", e);
+            }
+            if let Err(e) = hv_doorbell_events_init() {
+                log::error!("Failed to register #HV doorbell event handler: {:?}", e);
+            }
+        }
+    }
+
+    if let Some(interval) = config.heartbeat_interval() {
+        if let Err(e) = heartbeat_init(interval) {
+            log::error!("Failed to set up heartbeat page: {:?}", e);
+        }
+    }
+
     #[cfg(all(feature = "mstpm", not(test)))]
     vtpm_init().expect("vTPM failed to initialize");
 
+    if let Some(params) = config.prevalidation() {
+        if let Err(e) = prevalidate_guest_memory(params) {
+            log::error!("Failed to prevalidate guest memory: {:?}", e);
+        }
+    }
+
+    boot_phase_reached(BootPhase::ServicesUp);
+
     virt_log_usage();
 
     if config.should_launch_fw() {
         if let Err(e) = launch_fw(&config) {
             panic!("Failed to launch FW: {:#?}", e);
         }
+        boot_phase_reached(BootPhase::GuestLaunched);
     }
 
     create_kernel_task(request_processing_main).expect("Failed to launch request processing task");
@@ -469,6 +549,8 @@ pub extern "C" fn svsm_main() {
         log::info!("Failed to launch /init");
     }
 
+    heartbeat_set_flags(HealthFlags::RUNNING, true);
+    log_boot_phases();
     request_loop();
 
     panic!("Road ends here!");
@@ -481,9 +563,19 @@ fn panic(info: &PanicInfo<'_>) -> ! {
     secrets_page_mut().clear_vmpck(2);
     secrets_page_mut().clear_vmpck(3);
 
+    // Only the first CPU to panic reports it, all others stop silently.
+    if !panic_begin() {
+        park_this_cpu();
+    }
+
     log::error!("Panic: CPU[{}] {}", this_cpu().get_apic_id(), info);
 
+    run_teardown(TeardownReason::Panic);
+
     print_stack(3);
+    dump_parked_cpus();
+    dump_pool_stats();
+    pagetable::verify();
 
     loop {
         debug_break();
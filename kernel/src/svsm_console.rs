@@ -50,6 +50,21 @@ impl IOPort for SVSMIOPort {
             Err(_e) => request_termination_msr(),
         }
     }
+
+    fn outl(&self, port: u16, value: u32) {
+        let ret = current_ghcb().ioio_out(port, GHCBIOSize::Size32, value as u64);
+        if ret.is_err() {
+            request_termination_msr();
+        }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        let ret = current_ghcb().ioio_in(port, GHCBIOSize::Size32);
+        match ret {
+            Ok(v) => (v & 0xffff_ffff) as u32,
+            Err(_e) => request_termination_msr(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -101,4 +116,24 @@ impl IOPort for NativeIOPort {
         }
         ret
     }
+
+    fn outl(&self, port: u16, value: u32) {
+        unsafe {
+            asm!("out %eax, %dx",
+                 in("dx") port,
+                 in("eax") value,
+                 options(att_syntax));
+        }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        let mut ret: u32;
+        unsafe {
+            asm!("in %dx, %eax",
+                 in("dx") port,
+                 out("eax") ret,
+                 options(att_syntax));
+        }
+        ret
+    }
 }
@@ -8,8 +8,9 @@ use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::config::SvsmConfig;
 use crate::error::SvsmError;
 use crate::igvm_params::IgvmParams;
-use crate::mm::pagetable::{set_init_pgtable, PTEntryFlags, PageTableRef};
-use crate::mm::PerCPUPageMappingGuard;
+use crate::mm::alloc::add_memory_zone;
+use crate::mm::pagetable::{get_init_pgtable_locked, set_init_pgtable, PTEntryFlags, PageTableRef};
+use crate::mm::{add_kernel_mapping, PerCPUPageMappingGuard};
 use crate::platform::PageStateChangeOp;
 use crate::platform::SvsmPlatform;
 use crate::types::{PageSize, PAGE_SIZE};
@@ -163,3 +164,43 @@ pub fn invalidate_early_boot_memory(
 
     Ok(())
 }
+
+/// Maps and validates an SVSM memory range outside of the kernel region and
+/// hands it to the page allocator as a separate memory zone.
+fn add_svsm_memory_range(
+    platform: &dyn SvsmPlatform,
+    config: &SvsmConfig<'_>,
+    region: MemoryRegion<PhysAddr>,
+) -> Result<(), SvsmError> {
+    let pregion =
+        MemoryRegion::from_addresses(region.start().page_align_up(), region.end().page_align());
+    let vregion = add_kernel_mapping(pregion)?;
+    get_init_pgtable_locked().map_region(vregion, pregion.start(), PTEntryFlags::data())?;
+
+    if config.page_state_change_required() {
+        platform.page_state_change(pregion, PageSize::Huge, PageStateChangeOp::Private)?;
+    }
+    platform.validate_page_range(vregion)?;
+
+    add_memory_zone(pregion.start(), vregion.start(), pregion.len() / PAGE_SIZE)?;
+    Ok(())
+}
+
+/// Adds the memory ranges the configuration assigns to the SVSM in addition
+/// to the kernel region to the direct map and the page allocator. The
+/// valid bitmap only covers the kernel region, so pages of these ranges are
+/// not tracked in it.
+pub fn init_extra_svsm_memory(
+    platform: &dyn SvsmPlatform,
+    config: &SvsmConfig<'_>,
+) -> Result<(), SvsmError> {
+    for region in config.get_extra_svsm_regions()? {
+        log::info!(
+            "Adding SVSM memory region {:018x}-{:018x}",
+            region.start(),
+            region.end()
+        );
+        add_svsm_memory_range(platform, config, region)?;
+    }
+    Ok(())
+}
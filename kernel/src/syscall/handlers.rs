@@ -4,13 +4,23 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::task::{current_task_terminated, schedule};
+use crate::address::VirtAddr;
+use crate::cpu::X86ExceptionContext;
+use crate::mm::alloc::stats;
+use crate::task::{
+    current_task, current_task_terminated, return_from_exception, schedule, terminate,
+};
 
 pub fn sys_hello() -> usize {
     log::info!("Hello, world! System call invoked from user-space.");
     0
 }
 
+/// Returns the number of free 4K pages
+pub fn sys_meminfo() -> usize {
+    stats().free_pages()
+}
+
 pub fn sys_exit() -> ! {
     log::info!("Terminating current task");
     unsafe {
@@ -19,3 +29,26 @@ pub fn sys_exit() -> ! {
     schedule();
     panic!("schedule() returned in sys_exit()");
 }
+
+/// Registers the exception handler of the current task, or removes it if
+/// `handler` is zero
+pub fn sys_exc_handler(handler: usize) -> usize {
+    match current_task().set_exception_handler(VirtAddr::from(handler)) {
+        Ok(()) => 0,
+        Err(_) => !0,
+    }
+}
+
+/// Resumes the current task from its exception handler with the frame at
+/// the address in `rdi`. The task is terminated if the frame is invalid.
+pub fn sys_exc_return(ctxt: &mut X86ExceptionContext) {
+    let frame_addr = VirtAddr::from(ctxt.regs.rdi);
+    if let Err(e) = return_from_exception(ctxt, frame_addr) {
+        log::error!(
+            "Invalid exception return to frame {:#018x}: {:?} - Terminating task",
+            frame_addr,
+            e
+        );
+        terminate();
+    }
+}
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Reflection of exceptions raised by user-mode tasks.
+//!
+//! A task can register an exception handler with `SYS_EXC_HANDLER`. When the
+//! task raises a fault the kernel does not resolve itself, the register state
+//! at the time of the fault is saved as a [`UserExceptionFrame`] on the user
+//! stack, below the red zone, and the task continues at the handler with the
+//! address of the frame in `rdi` and the vector in `rsi`. The handler may
+//! modify the frame and resumes the task with `SYS_EXC_RETURN`, passing the
+//! frame address in `rdi`. It must not return, and the stack slot above the
+//! frame holds a zero return address.
+//!
+//! Tasks without a handler, and tasks faulting again before they resume from
+//! their handler, are terminated with a [`UserFaultReport`] in the log. A
+//! faulting task never brings down the whole SVSM.
+
+use crate::address::{Address, VirtAddr};
+use crate::cpu::idt::common::{
+    AC_VECTOR, BR_VECTOR, DE_VECTOR, GP_VECTOR, MF_VECTOR, NM_VECTOR, OF_VECTOR, PF_VECTOR,
+    SS_VECTOR, UD_VECTOR, XF_VECTOR,
+};
+use crate::cpu::{X86ExceptionContext, X86GeneralRegs};
+use crate::error::SvsmError;
+use crate::mm::{UserPtr, USER_MEM_END, USER_MEM_START};
+use crate::utils::align_down;
+
+use super::schedule::{current_task, terminate};
+
+use core::fmt;
+use core::mem::size_of;
+
+/// Bytes below the stack pointer which user code may use without
+/// adjusting it, as in the System V ABI
+pub const USER_RED_ZONE: usize = 128;
+
+/// RFLAGS bits the exception handler may change in the saved frame: CF,
+/// PF, AF, ZF, SF, DF and OF
+const RFLAGS_USER_MASK: usize = 0xcd5;
+/// Trap flag, cleared on entry to the exception handler
+const RFLAGS_TF: usize = 1 << 8;
+/// Direction flag, cleared on entry to the exception handler
+const RFLAGS_DF: usize = 1 << 10;
+
+/// State of a task at the time of an exception, as passed to its handler
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UserExceptionFrame {
+    pub regs: X86GeneralRegs,
+    pub rip: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub vector: usize,
+    pub error_code: usize,
+    /// Faulting address of a page fault, zero otherwise
+    pub fault_address: usize,
+}
+
+/// Returns whether exceptions with `vector` are reflected to user mode
+pub fn is_user_exception(vector: usize) -> bool {
+    matches!(
+        vector,
+        DE_VECTOR
+            | OF_VECTOR
+            | BR_VECTOR
+            | UD_VECTOR
+            | NM_VECTOR
+            | SS_VECTOR
+            | GP_VECTOR
+            | PF_VECTOR
+            | MF_VECTOR
+            | AC_VECTOR
+            | XF_VECTOR
+    )
+}
+
+fn exception_name(vector: usize) -> &'static str {
+    match vector {
+        DE_VECTOR => "divide error",
+        OF_VECTOR => "overflow",
+        BR_VECTOR => "bound range exceeded",
+        UD_VECTOR => "invalid opcode",
+        NM_VECTOR => "device not available",
+        SS_VECTOR => "stack-segment fault",
+        GP_VECTOR => "general-protection fault",
+        PF_VECTOR => "page fault",
+        MF_VECTOR => "x87 floating-point error",
+        AC_VECTOR => "alignment check",
+        XF_VECTOR => "SIMD floating-point error",
+        _ => "exception",
+    }
+}
+
+/// Description of a fault that terminated a user-mode task
+#[derive(Clone, Copy, Debug)]
+pub struct UserFaultReport {
+    pub task_id: u32,
+    pub vector: usize,
+    pub error_code: usize,
+    pub rip: usize,
+    pub rsp: usize,
+    pub fault_address: Option<VirtAddr>,
+    /// Why the fault was not reflected to the task
+    pub reason: &'static str,
+}
+
+impl fmt::Display for UserFaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Task {}: unhandled {} (vector {}) at RIP {:#018x} RSP {:#018x} error code {:#x}",
+            self.task_id,
+            exception_name(self.vector),
+            self.vector,
+            self.rip,
+            self.rsp,
+            self.error_code
+        )?;
+        if let Some(addr) = self.fault_address {
+            write!(f, " address {:#018x}", addr)?;
+        }
+        write!(f, " ({}) - Terminating task", self.reason)
+    }
+}
+
+fn reflect_exception(
+    handler: VirtAddr,
+    ctxt: &mut X86ExceptionContext,
+    vector: usize,
+    fault_address: Option<VirtAddr>,
+) -> Result<(), SvsmError> {
+    let frame = UserExceptionFrame {
+        regs: ctxt.regs,
+        rip: ctxt.frame.rip,
+        rflags: ctxt.frame.flags,
+        rsp: ctxt.frame.rsp,
+        vector,
+        error_code: ctxt.error_code,
+        fault_address: fault_address.map_or(0, |addr| addr.bits()),
+    };
+
+    let frame_addr = ctxt
+        .frame
+        .rsp
+        .checked_sub(USER_RED_ZONE + size_of::<UserExceptionFrame>())
+        .map(|addr| VirtAddr::from(align_down(addr, 16)))
+        .ok_or(SvsmError::InvalidAddress)?;
+    let ret_addr = frame_addr
+        .checked_sub(size_of::<u64>())
+        .ok_or(SvsmError::InvalidAddress)?;
+    UserPtr::<UserExceptionFrame>::new(frame_addr).write(frame)?;
+    UserPtr::<u64>::new(ret_addr).write(0)?;
+
+    ctxt.regs.rdi = frame_addr.bits();
+    ctxt.regs.rsi = vector;
+    ctxt.frame.rip = handler.bits();
+    ctxt.frame.rsp = ret_addr.bits();
+    ctxt.frame.flags &= !(RFLAGS_TF | RFLAGS_DF);
+    Ok(())
+}
+
+/// Reflects an exception raised by the current user-mode task to its
+/// handler, or terminates the task if that is not possible.
+pub fn handle_user_exception(
+    ctxt: &mut X86ExceptionContext,
+    vector: usize,
+    fault_address: Option<VirtAddr>,
+) {
+    let task = current_task();
+    let reason = match task.exception_handler() {
+        None => "no exception handler",
+        Some(_) if !is_user_exception(vector) => "exception not reflected",
+        Some(_) if !task.enter_exception_handler() => "fault in exception handler",
+        Some(handler) => match reflect_exception(handler, ctxt, vector, fault_address) {
+            Ok(()) => return,
+            Err(_) => "user stack not writable",
+        },
+    };
+
+    let report = UserFaultReport {
+        task_id: task.get_task_id(),
+        vector,
+        error_code: ctxt.error_code,
+        rip: ctxt.frame.rip,
+        rsp: ctxt.frame.rsp,
+        fault_address,
+        reason,
+    };
+    log::error!("{}", report);
+    drop(task);
+    terminate();
+}
+
+/// Resumes the current task from its exception handler with the state in
+/// the [`UserExceptionFrame`] at `frame_addr`. Only the RFLAGS bits in
+/// [`RFLAGS_USER_MASK`] are taken from the frame.
+pub fn return_from_exception(
+    ctxt: &mut X86ExceptionContext,
+    frame_addr: VirtAddr,
+) -> Result<(), SvsmError> {
+    let task = current_task();
+    let frame = UserPtr::<UserExceptionFrame>::new(frame_addr).read()?;
+    let rip = VirtAddr::from(frame.rip);
+    if rip < USER_MEM_START || rip >= USER_MEM_END {
+        return Err(SvsmError::InvalidAddress);
+    }
+    if !task.leave_exception_handler() {
+        return Err(SvsmError::NotSupported);
+    }
+
+    ctxt.regs = frame.regs;
+    ctxt.frame.rip = frame.rip;
+    ctxt.frame.rsp = frame.rsp;
+    ctxt.frame.flags = (ctxt.frame.flags & !RFLAGS_USER_MASK) | (frame.rflags & RFLAGS_USER_MASK);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use crate::cpu::idt::common::{MCE_VECTOR, NMI_VECTOR};
+    use alloc::format;
+
+    #[test]
+    fn user_exceptions() {
+        assert!(is_user_exception(DE_VECTOR));
+        assert!(is_user_exception(PF_VECTOR));
+        assert!(!is_user_exception(NMI_VECTOR));
+        assert!(!is_user_exception(MCE_VECTOR));
+    }
+
+    #[test]
+    fn fault_report() {
+        let report = UserFaultReport {
+            task_id: 3,
+            vector: PF_VECTOR,
+            error_code: 6,
+            rip: 0x8000_1000,
+            rsp: 0x8000_f000,
+            fault_address: Some(VirtAddr::from(0x10u64)),
+            reason: "no exception handler",
+        };
+        let text = format!("{}", report);
+        assert!(text.starts_with("Task 3: unhandled page fault (vector 14)"));
+        assert!(text.contains("address 0x0000000000000010"));
+    }
+}
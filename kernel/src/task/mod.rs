@@ -4,20 +4,26 @@
 //
 // Author: Roy Hopkins <rhopkins@suse.de>
 
+mod exception;
 mod exec;
 mod schedule;
 mod tasks;
 mod waiting;
 
 pub use schedule::{
-    create_kernel_task, create_user_task, current_task, current_task_terminated, is_current_task,
-    schedule, schedule_init, schedule_task, terminate, RunQueue, TASKLIST,
+    create_kernel_task, create_rt_kernel_task, create_user_task, current_task,
+    current_task_terminated, is_current_task, schedule, schedule_init, schedule_task,
+    set_rt_budget, terminate, try_current_task, RunQueue, DEFAULT_RT_BUDGET, TASKLIST,
 };
 
 pub use tasks::{
-    is_task_fault, Task, TaskContext, TaskError, TaskListAdapter, TaskPointer, TaskRunListAdapter,
-    TaskState, INITIAL_TASK_ID, TASK_FLAG_SHARE_PT,
+    is_task_fault, SchedClass, Task, TaskContext, TaskError, TaskListAdapter, TaskPointer,
+    TaskRunListAdapter, TaskState, INITIAL_TASK_ID, TASK_FLAG_SHARE_PT,
 };
 
+pub use exception::{
+    handle_user_exception, is_user_exception, return_from_exception, UserExceptionFrame,
+    UserFaultReport, USER_RED_ZONE,
+};
 pub use exec::exec_user;
 pub use waiting::WaitQueue;
@@ -20,6 +20,11 @@
 //! The scheduler is cooperative. A task runs until it voluntarily calls the
 //! [`schedule()`] function.
 //!
+//! Tasks of the [`SchedClass::RealTime`] class are kept in a separate list
+//! and always run before normal tasks. To prevent them from starving normal
+//! tasks, only a limited number of real-time tasks is scheduled in a row
+//! while normal tasks are waiting, see [`set_rt_budget()`].
+//!
 //! Only when a task is in [`RUNNING`] or [`TERMINATED`] state it is assigned to a
 //! specific CPU. Tasks in the [`BLOCKED`] state have no CPU assigned and will run
 //! on the CPU where their event is triggered that makes them [`RUNNING`] again.
@@ -31,7 +36,7 @@
 extern crate alloc;
 
 use super::INITIAL_TASK_ID;
-use super::{Task, TaskListAdapter, TaskPointer, TaskRunListAdapter};
+use super::{SchedClass, Task, TaskListAdapter, TaskPointer, TaskRunListAdapter};
 use crate::address::Address;
 use crate::cpu::percpu::this_cpu;
 use crate::error::SvsmError;
@@ -40,8 +45,21 @@ use alloc::sync::Arc;
 use core::arch::{asm, global_asm};
 use core::cell::OnceCell;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU32, Ordering};
 use intrusive_collections::LinkedList;
 
+/// Default number of real-time tasks scheduled in a row while normal tasks
+/// are waiting
+pub const DEFAULT_RT_BUDGET: u32 = 8;
+
+static RT_BUDGET: AtomicU32 = AtomicU32::new(DEFAULT_RT_BUDGET);
+
+/// Sets the number of times real-time tasks can be scheduled in a row while
+/// normal tasks are waiting to run. A budget of zero is treated as one.
+pub fn set_rt_budget(budget: u32) {
+    RT_BUDGET.store(budget.max(1), Ordering::Relaxed);
+}
+
 /// A RunQueue implementation that uses an RBTree to efficiently sort the priority
 /// of tasks within the queue.
 #[derive(Debug, Default)]
@@ -49,6 +67,13 @@ pub struct RunQueue {
     /// Linked list with runable tasks
     run_list: LinkedList<TaskRunListAdapter>,
 
+    /// Linked list with runable real-time tasks
+    rt_list: LinkedList<TaskRunListAdapter>,
+
+    /// Number of real-time tasks which can still be scheduled before a
+    /// waiting normal task must run
+    rt_budget: u32,
+
     /// Pointer to currently running task
     current_task: Option<TaskPointer>,
 
@@ -66,6 +91,8 @@ impl RunQueue {
     pub fn new() -> Self {
         Self {
             run_list: LinkedList::new(TaskRunListAdapter::new()),
+            rt_list: LinkedList::new(TaskRunListAdapter::new()),
+            rt_budget: RT_BUDGET.load(Ordering::Relaxed),
             current_task: None,
             idle_task: OnceCell::new(),
             terminated_task: None,
@@ -73,7 +100,9 @@ impl RunQueue {
     }
 
     /// Find the next task to run, which is either the task at the front of the
-    /// run_list or the idle task, if the run_list is empty.
+    /// rt_list, the task at the front of the run_list or the idle task, if
+    /// both are empty. Real-time tasks are preferred unless their budget is
+    /// exhausted and a normal task is waiting.
     ///
     /// # Returns
     ///
@@ -84,18 +113,32 @@ impl RunQueue {
     /// Panics if there are no tasks to run and no idle task has been
     /// allocated via [`set_idle_task()`](Self::set_idle_task).
     fn get_next_task(&mut self) -> TaskPointer {
+        if !self.rt_list.is_empty() {
+            if self.run_list.is_empty() {
+                return self.rt_list.pop_front().unwrap();
+            }
+            if self.rt_budget > 0 {
+                self.rt_budget -= 1;
+                return self.rt_list.pop_front().unwrap();
+            }
+        }
+        self.rt_budget = RT_BUDGET.load(Ordering::Relaxed);
         self.run_list
             .pop_front()
             .unwrap_or_else(|| self.idle_task.get().unwrap().clone())
     }
 
     /// Update state before a task is scheduled out. Non-idle tasks in RUNNING
-    /// state will be put at the end of the run_list. Terminated tasks will be
+    /// state will be put at the end of the run_list, or the rt_list for
+    /// real-time tasks. Terminated tasks will be
     /// stored in the terminated_task field of the RunQueue and be destroyed
     /// after the task-switch.
     fn handle_task(&mut self, task: TaskPointer) {
         if task.is_running() && !task.is_idle_task() {
-            self.run_list.push_back(task);
+            match task.sched_class() {
+                SchedClass::Normal => self.run_list.push_back(task),
+                SchedClass::RealTime => self.rt_list.push_back(task),
+            }
         } else if task.is_terminated() {
             self.terminated_task = Some(task);
         }
@@ -226,8 +269,22 @@ impl TaskList {
 pub static TASKLIST: SpinLock<TaskList> = SpinLock::new(TaskList::new());
 
 pub fn create_kernel_task(entry: extern "C" fn()) -> Result<TaskPointer, SvsmError> {
+    create_kernel_task_class(entry, SchedClass::Normal)
+}
+
+/// Creates a kernel task of the real-time class, for latency-critical work
+/// such as completing requests signalled by interrupts.
+pub fn create_rt_kernel_task(entry: extern "C" fn()) -> Result<TaskPointer, SvsmError> {
+    create_kernel_task_class(entry, SchedClass::RealTime)
+}
+
+fn create_kernel_task_class(
+    entry: extern "C" fn(),
+    class: SchedClass,
+) -> Result<TaskPointer, SvsmError> {
     let cpu = this_cpu();
     let task = Task::create(cpu, entry)?;
+    task.set_sched_class(class);
     TASKLIST.lock().list().push_back(task.clone());
 
     // Put task on the runqueue of this CPU
@@ -253,6 +310,17 @@ pub fn current_task() -> TaskPointer {
     this_cpu().current_task()
 }
 
+/// Returns the task running on this CPU, or `None` if there is none or the
+/// run queue is currently being modified. Safe to use from exception
+/// handlers.
+pub fn try_current_task() -> Option<TaskPointer> {
+    this_cpu()
+        .runqueue()
+        .try_borrow()
+        .ok()
+        .and_then(|rq| rq.current_task.clone())
+}
+
 /// Check to see if the task scheduled on the current processor has the given id
 pub fn is_current_task(id: u32) -> bool {
     match &this_cpu().runqueue().borrow().current_task {
@@ -9,7 +9,7 @@ extern crate alloc;
 use alloc::sync::Arc;
 use core::fmt;
 use core::mem::size_of;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
 use crate::address::{Address, VirtAddr};
 use crate::cpu::idt::svsm::default_return;
@@ -23,8 +23,8 @@ use crate::locking::{RWLock, SpinLock};
 use crate::mm::pagetable::{PTEntryFlags, PageTableRef};
 use crate::mm::vm::{Mapping, VMFileMappingFlags, VMKernelStack, VMR};
 use crate::mm::{
-    mappings::create_anon_mapping, mappings::create_file_mapping, VMMappingGuard,
-    SVSM_PERTASK_BASE, SVSM_PERTASK_END, SVSM_PERTASK_STACK_BASE, USER_MEM_END, USER_MEM_START,
+    mappings::create_anon_mapping, mappings::create_file_mapping, pertask_stack_base,
+    VMMappingGuard, SVSM_PERTASK_BASE, SVSM_PERTASK_END, USER_MEM_END, USER_MEM_START,
 };
 use crate::types::{SVSM_USER_CS, SVSM_USER_DS};
 use crate::utils::MemoryRegion;
@@ -91,6 +91,17 @@ pub struct TaskContext {
     pub ret_addr: u64,
 }
 
+/// Scheduling class of a task
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum SchedClass {
+    /// Tasks scheduled round-robin with all other normal tasks
+    #[default]
+    Normal,
+    /// Latency-critical tasks, which run before any normal task as long as
+    /// the real-time budget of their CPU is not exhausted
+    RealTime,
+}
+
 #[repr(C)]
 struct TaskSchedState {
     /// Whether this is an idle task
@@ -99,6 +110,9 @@ struct TaskSchedState {
     /// Current state of the task
     state: TaskState,
 
+    /// Scheduling class of the task
+    class: SchedClass,
+
     /// CPU this task is currently assigned to
     cpu: u32,
 }
@@ -133,6 +147,13 @@ pub struct Task {
     /// ID of the task
     id: u32,
 
+    /// User-mode address of the exception handler, zero if none is
+    /// registered
+    exception_handler: AtomicUsize,
+
+    /// Set while an exception is reflected to the user-mode handler
+    in_exception_handler: AtomicBool,
+
     /// Link to global task list
     list_link: LinkedListAtomicLink,
 
@@ -177,13 +198,13 @@ impl Task {
         vm_kernel_range.initialize()?;
 
         let (stack, raw_bounds, rsp_offset) = Self::allocate_ktask_stack(cpu, entry)?;
-        vm_kernel_range.insert_at(SVSM_PERTASK_STACK_BASE, stack)?;
+        vm_kernel_range.insert_at(pertask_stack_base(), stack)?;
 
         vm_kernel_range.populate(&mut pgtable);
 
         // Remap at the per-task offset
         let bounds = MemoryRegion::new(
-            SVSM_PERTASK_STACK_BASE + raw_bounds.start().into(),
+            pertask_stack_base() + raw_bounds.start().into(),
             raw_bounds.len(),
         );
 
@@ -200,9 +221,12 @@ impl Task {
             sched_state: RWLock::new(TaskSchedState {
                 idle_task: false,
                 state: TaskState::RUNNING,
+                class: SchedClass::Normal,
                 cpu: cpu.get_apic_id(),
             }),
             id: TASK_ID_ALLOCATOR.next_id(),
+            exception_handler: AtomicUsize::new(0),
+            in_exception_handler: AtomicBool::new(false),
             list_link: LinkedListAtomicLink::default(),
             runlist_link: LinkedListAtomicLink::default(),
         }))
@@ -217,7 +241,7 @@ impl Task {
         vm_kernel_range.initialize()?;
 
         let (stack, raw_bounds, stack_offset) = Self::allocate_utask_stack(cpu, user_entry)?;
-        vm_kernel_range.insert_at(SVSM_PERTASK_STACK_BASE, stack)?;
+        vm_kernel_range.insert_at(pertask_stack_base(), stack)?;
 
         vm_kernel_range.populate(&mut pgtable);
 
@@ -226,7 +250,7 @@ impl Task {
 
         // Remap at the per-task offset
         let bounds = MemoryRegion::new(
-            SVSM_PERTASK_STACK_BASE + raw_bounds.start().into(),
+            pertask_stack_base() + raw_bounds.start().into(),
             raw_bounds.len(),
         );
 
@@ -243,9 +267,12 @@ impl Task {
             sched_state: RWLock::new(TaskSchedState {
                 idle_task: false,
                 state: TaskState::RUNNING,
+                class: SchedClass::Normal,
                 cpu: cpu.get_apic_id(),
             }),
             id: TASK_ID_ALLOCATOR.next_id(),
+            exception_handler: AtomicUsize::new(0),
+            in_exception_handler: AtomicBool::new(false),
             list_link: LinkedListAtomicLink::default(),
             runlist_link: LinkedListAtomicLink::default(),
         }))
@@ -293,6 +320,19 @@ impl Task {
         self.sched_state.lock_read().idle_task
     }
 
+    /// Changes the scheduling class of the task. Takes effect the next
+    /// time the task is put on a run queue.
+    pub fn set_sched_class(&self, class: SchedClass) {
+        self.sched_state
+            .lock_write()
+            .panic_on_idle("Trying to change class of idle task")
+            .class = class;
+    }
+
+    pub fn sched_class(&self) -> SchedClass {
+        self.sched_state.lock_read().class
+    }
+
     pub fn update_cpu(&self, new_cpu: u32) -> u32 {
         let mut state = self.sched_state.lock_write();
         let old_cpu = state.cpu;
@@ -300,6 +340,35 @@ impl Task {
         old_cpu
     }
 
+    /// Register the user-mode handler that exceptions of this task are
+    /// reflected to. A null address removes the handler, so that faults
+    /// terminate the task.
+    pub fn set_exception_handler(&self, handler: VirtAddr) -> Result<(), SvsmError> {
+        if !handler.is_null() && (handler < USER_MEM_START || handler >= USER_MEM_END) {
+            return Err(SvsmError::InvalidAddress);
+        }
+        self.exception_handler
+            .store(handler.bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn exception_handler(&self) -> Option<VirtAddr> {
+        let handler = VirtAddr::from(self.exception_handler.load(Ordering::Relaxed));
+        (!handler.is_null()).then_some(handler)
+    }
+
+    /// Marks the task as running its exception handler. Returns `false` if
+    /// it already was, in which case the exception cannot be reflected.
+    pub fn enter_exception_handler(&self) -> bool {
+        !self.in_exception_handler.swap(true, Ordering::Relaxed)
+    }
+
+    /// Marks the task as having left its exception handler. Returns
+    /// `false` if it was not running it.
+    pub fn leave_exception_handler(&self) -> bool {
+        self.in_exception_handler.swap(false, Ordering::Relaxed)
+    }
+
     pub fn handle_pf(&self, vaddr: VirtAddr, write: bool) -> Result<(), SvsmError> {
         self.vm_kernel_range.handle_page_fault(vaddr, write)
     }
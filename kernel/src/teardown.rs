@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Ordered teardown of subsystems.
+//!
+//! Subsystems which hand resources to the host, like shared pages or
+//! doorbells, register a teardown hook when they are initialized. When the
+//! SVSM stops, [`run_teardown()`] runs the hooks in the reverse order of
+//! their registration, so that a subsystem is torn down before the ones it
+//! was built on. Each hook gets a deadline, which it should honor by
+//! skipping work that can wait, as the SVSM may be stopping because
+//! something is already broken. Hooks which fail or overrun their deadline
+//! are reported, and the remaining hooks still run.
+//!
+//! The hooks are kept in a fixed-size table, so that running them does not
+//! allocate, which matters on the panic path.
+
+extern crate alloc;
+
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::time::{system_clock, Clock};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Maximum number of teardown hooks
+pub const MAX_TEARDOWN_HOOKS: usize = 16;
+
+/// Default time a hook may take, in TSC cycles
+pub const DEFAULT_TEARDOWN_TIMEOUT: u64 = 1 << 32;
+
+/// Why the SVSM is stopping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TeardownReason {
+    /// The guest is rebooted
+    Reboot,
+    /// The SVSM is replaced by a new version
+    Update,
+    /// The SVSM panicked. Hooks should only do what is needed to leave the
+    /// host with a consistent view, and must not take locks which might be
+    /// held by a parked CPU.
+    Panic,
+}
+
+/// State passed to a teardown hook
+#[derive(Clone, Copy)]
+pub struct TeardownCtx<'a> {
+    reason: TeardownReason,
+    deadline: u64,
+    clock: &'a dyn Clock,
+}
+
+impl core::fmt::Debug for TeardownCtx<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TeardownCtx")
+            .field("reason", &self.reason)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl TeardownCtx<'_> {
+    pub fn reason(&self) -> TeardownReason {
+        self.reason
+    }
+
+    /// Returns whether the deadline of the hook has passed
+    pub fn expired(&self) -> bool {
+        self.clock.now() >= self.deadline
+    }
+}
+
+pub type TeardownFn = fn(&TeardownCtx<'_>) -> Result<(), SvsmError>;
+
+#[derive(Clone, Copy, Debug)]
+struct TeardownHook {
+    name: &'static str,
+    func: TeardownFn,
+    /// Time the hook may take, in TSC cycles
+    timeout: u64,
+}
+
+/// Outcome of running a single hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HookResult {
+    Done,
+    Failed,
+    Overrun,
+}
+
+#[derive(Debug)]
+struct TeardownHooks {
+    hooks: [Option<TeardownHook>; MAX_TEARDOWN_HOOKS],
+    count: usize,
+}
+
+impl TeardownHooks {
+    const fn new() -> Self {
+        Self {
+            hooks: [None; MAX_TEARDOWN_HOOKS],
+            count: 0,
+        }
+    }
+
+    fn register(&mut self, hook: TeardownHook) -> Result<(), SvsmError> {
+        let slot = self.hooks.get_mut(self.count).ok_or(SvsmError::Mem)?;
+        *slot = Some(hook);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Runs all hooks, last registered first, and calls `report` with the
+    /// outcome of each.
+    fn run(
+        &self,
+        reason: TeardownReason,
+        clock: &dyn Clock,
+        mut report: impl FnMut(&'static str, HookResult),
+    ) {
+        for hook in self.hooks[..self.count].iter().rev().flatten() {
+            let ctx = TeardownCtx {
+                reason,
+                deadline: clock.now().saturating_add(hook.timeout),
+                clock,
+            };
+            let result = match (hook.func)(&ctx) {
+                Err(e) => {
+                    log::error!("Teardown of {} failed: {:?}", hook.name, e);
+                    HookResult::Failed
+                }
+                Ok(()) if ctx.expired() => HookResult::Overrun,
+                Ok(()) => HookResult::Done,
+            };
+            report(hook.name, result);
+        }
+    }
+}
+
+static TEARDOWN_HOOKS: SpinLock<TeardownHooks> = SpinLock::new(TeardownHooks::new());
+static TEARDOWN_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Registers `func` to tear down the subsystem `name` with the default
+/// timeout. Subsystems register their hook once they are initialized.
+pub fn register_teardown_hook(name: &'static str, func: TeardownFn) -> Result<(), SvsmError> {
+    register_teardown_hook_timeout(name, func, DEFAULT_TEARDOWN_TIMEOUT)
+}
+
+/// Like [`register_teardown_hook()`], with a `timeout` in TSC cycles.
+pub fn register_teardown_hook_timeout(
+    name: &'static str,
+    func: TeardownFn,
+    timeout: u64,
+) -> Result<(), SvsmError> {
+    TEARDOWN_HOOKS.lock().register(TeardownHook {
+        name,
+        func,
+        timeout,
+    })
+}
+
+/// Runs all teardown hooks in the reverse order of their registration.
+/// Only the first call runs the hooks, later calls return immediately.
+pub fn run_teardown(reason: TeardownReason) {
+    if TEARDOWN_STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    // A CPU parked while registering a hook would hold the lock forever.
+    let hooks = if reason == TeardownReason::Panic {
+        TEARDOWN_HOOKS.try_lock()
+    } else {
+        Some(TEARDOWN_HOOKS.lock())
+    };
+    let Some(hooks) = hooks else {
+        log::error!("Teardown hooks are locked, skipping teardown");
+        return;
+    };
+
+    log::info!("Tearing down subsystems ({:?})", reason);
+    hooks.run(reason, system_clock(), |name, result| {
+        if result == HookResult::Overrun {
+            log::warn!("Teardown of {} overran its deadline", name);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::MockClock;
+    use alloc::vec::Vec;
+
+    fn ok(_ctx: &TeardownCtx<'_>) -> Result<(), SvsmError> {
+        Ok(())
+    }
+
+    fn fail(_ctx: &TeardownCtx<'_>) -> Result<(), SvsmError> {
+        Err(SvsmError::NotSupported)
+    }
+
+    fn hook(name: &'static str, func: TeardownFn, timeout: u64) -> TeardownHook {
+        TeardownHook {
+            name,
+            func,
+            timeout,
+        }
+    }
+
+    #[test]
+    fn reverse_order() {
+        let mut hooks = TeardownHooks::new();
+        hooks.register(hook("first", ok, 10)).unwrap();
+        hooks.register(hook("second", fail, 10)).unwrap();
+        hooks.register(hook("third", ok, 0)).unwrap();
+
+        let clock = MockClock::new(100);
+        let mut results = Vec::new();
+        hooks.run(TeardownReason::Reboot, &clock, |name, result| {
+            results.push((name, result))
+        });
+        assert_eq!(
+            results,
+            [
+                ("third", HookResult::Overrun),
+                ("second", HookResult::Failed),
+                ("first", HookResult::Done),
+            ]
+        );
+    }
+
+    #[test]
+    fn table_full() {
+        let mut hooks = TeardownHooks::new();
+        for _ in 0..MAX_TEARDOWN_HOOKS {
+            hooks.register(hook("hook", ok, 10)).unwrap();
+        }
+        assert!(hooks.register(hook("hook", ok, 10)).is_err());
+    }
+}
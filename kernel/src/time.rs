@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Monotonic time sources.
+//!
+//! Code which needs timestamps either takes a [`Clock`] so that callers can
+//! inject one, or uses [`now()`], which reads the TSC. In test builds, both
+//! on the host and inside the SVSM, [`now()`] is backed by a [`MockClock`]
+//! instead, so that test results do not depend on the speed of the machine
+//! they run on.
+//!
+//! Rate limits and deadlines (the watchdog, call accounting, the heartbeat,
+//! memory state queries and memory pressure checks) should use [`now()`]
+//! rather than reading the TSC directly, so that they can be tested. Raw
+//! cycle counts for profiling, like the interrupt latency statistics, keep
+//! using the TSC.
+
+use crate::cpu::msr::rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A source of monotonic timestamps, in TSC cycles
+pub trait Clock: Sync {
+    fn now(&self) -> u64;
+}
+
+/// Clock reading the TSC of the current CPU
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TscClock;
+
+impl Clock for TscClock {
+    fn now(&self) -> u64 {
+        rdtsc()
+    }
+}
+
+/// Clock which only advances when told to, for tests
+#[derive(Debug, Default)]
+pub struct MockClock {
+    ticks: AtomicU64,
+}
+
+impl MockClock {
+    pub const fn new(start: u64) -> Self {
+        Self {
+            ticks: AtomicU64::new(start),
+        }
+    }
+
+    /// Move the clock forward by `ticks`
+    pub fn advance(&self, ticks: u64) {
+        self.ticks.fetch_add(ticks, Ordering::Relaxed);
+    }
+
+    /// Set the clock to `ticks`, which must not be in its past
+    pub fn set(&self, ticks: u64) {
+        let prev = self.ticks.swap(ticks, Ordering::Relaxed);
+        debug_assert!(prev <= ticks, "MockClock moved backwards");
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(test))]
+static SYSTEM_CLOCK: TscClock = TscClock;
+#[cfg(test)]
+static SYSTEM_CLOCK: MockClock = MockClock::new(0);
+
+/// The clock used by [`now()`]
+pub fn system_clock() -> &'static dyn Clock {
+    &SYSTEM_CLOCK
+}
+
+/// Advance the clock used by [`now()`] in test builds. As the clock is
+/// shared by all tests, tests which check exact timestamps should use their
+/// own [`MockClock`] instead.
+#[cfg(test)]
+pub fn advance_system_clock(ticks: u64) {
+    SYSTEM_CLOCK.advance(ticks);
+}
+
+/// Current timestamp of the system clock
+pub fn now() -> u64 {
+    SYSTEM_CLOCK.now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new(100);
+        assert_eq!(clock.now(), 100);
+        clock.advance(50);
+        assert_eq!(clock.now(), 150);
+        clock.set(1000);
+        assert_eq!(clock.now(), 1000);
+    }
+
+    #[test]
+    fn system_clock_is_mocked() {
+        let before = now();
+        advance_system_clock(10);
+        assert!(now() >= before + 10);
+    }
+}
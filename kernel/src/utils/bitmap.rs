@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Fixed-size bitmaps and ID allocators built on them.
+//!
+//! Unlike the allocators in [`bitmap_allocator`](super::bitmap_allocator),
+//! these are flat arrays of 64-bit words with a capacity chosen at compile
+//! time, and operate on whole words where possible.
+
+use core::iter;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Mask of the bits `start..end` within a word, with `start < end <= 64`
+const fn word_mask(start: usize, end: usize) -> u64 {
+    let high = if end == WORD_BITS {
+        u64::MAX
+    } else {
+        (1u64 << end) - 1
+    };
+    high & !((1u64 << start) - 1)
+}
+
+/// Word indices and masks covering the bits `start..start + count`
+fn range_masks(start: usize, count: usize) -> impl Iterator<Item = (usize, u64)> {
+    let end = start + count;
+    let mut bit = start;
+    iter::from_fn(move || {
+        if bit >= end {
+            return None;
+        }
+        let word = bit / WORD_BITS;
+        let word_end = end.min((word + 1) * WORD_BITS);
+        let mask = word_mask(bit % WORD_BITS, word_end - word * WORD_BITS);
+        bit = word_end;
+        Some((word, mask))
+    })
+}
+
+/// A bitmap of `N` 64-bit words, i.e. `N * 64` bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bitmap<const N: usize> {
+    words: [u64; N],
+}
+
+impl<const N: usize> Default for Bitmap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Bitmap<N> {
+    /// Creates a bitmap with all bits clear
+    pub const fn new() -> Self {
+        Self { words: [0; N] }
+    }
+
+    /// Number of bits in the bitmap
+    pub const fn capacity(&self) -> usize {
+        N * WORD_BITS
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `bit` is out of range.
+    pub fn get(&self, bit: usize) -> bool {
+        self.words[bit / WORD_BITS] & (1 << (bit % WORD_BITS)) != 0
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `bit` is out of range.
+    pub fn set(&mut self, bit: usize) {
+        self.words[bit / WORD_BITS] |= 1 << (bit % WORD_BITS);
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `bit` is out of range.
+    pub fn clear(&mut self, bit: usize) {
+        self.words[bit / WORD_BITS] &= !(1 << (bit % WORD_BITS));
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the bits `start..start + count` are out of bounds.
+    fn check_range(&self, start: usize, count: usize) {
+        let end = start.checked_add(count);
+        assert!(
+            end.is_some_and(|end| end <= self.capacity()),
+            "Bitmap range out of bounds"
+        );
+    }
+
+    /// Sets the bits `start..start + count`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn set_range(&mut self, start: usize, count: usize) {
+        self.check_range(start, count);
+        for (word, mask) in range_masks(start, count) {
+            self.words[word] |= mask;
+        }
+    }
+
+    /// Clears the bits `start..start + count`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn clear_range(&mut self, start: usize, count: usize) {
+        self.check_range(start, count);
+        for (word, mask) in range_masks(start, count) {
+            self.words[word] &= !mask;
+        }
+    }
+
+    /// Returns whether all bits `start..start + count` are set
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn all_set(&self, start: usize, count: usize) -> bool {
+        self.check_range(start, count);
+        range_masks(start, count).all(|(word, mask)| self.words[word] & mask == mask)
+    }
+
+    /// Returns the first clear bit at or after `start`
+    pub fn find_first_zero(&self, start: usize) -> Option<usize> {
+        if start >= self.capacity() {
+            return None;
+        }
+        let first = start / WORD_BITS;
+        // Treat the bits before `start` in its word as set
+        let skip = !word_mask(start % WORD_BITS, WORD_BITS);
+        self.words[first..]
+            .iter()
+            .enumerate()
+            .find_map(|(i, word)| {
+                let word = if i == 0 { word | skip } else { *word };
+                (word != u64::MAX).then(|| (first + i) * WORD_BITS + word.trailing_ones() as usize)
+            })
+    }
+
+    /// Returns the first set bit at or after `start`
+    pub fn find_first_one(&self, start: usize) -> Option<usize> {
+        if start >= self.capacity() {
+            return None;
+        }
+        let first = start / WORD_BITS;
+        let skip = word_mask(start % WORD_BITS, WORD_BITS);
+        self.words[first..]
+            .iter()
+            .enumerate()
+            .find_map(|(i, word)| {
+                let word = if i == 0 { word & skip } else { *word };
+                (word != 0).then(|| (first + i) * WORD_BITS + word.trailing_zeros() as usize)
+            })
+    }
+
+    /// Returns the start of the first run of `count` clear bits below
+    /// `limit`
+    pub fn find_zero_range(&self, count: usize, limit: usize) -> Option<usize> {
+        let limit = limit.min(self.capacity());
+        let mut start = 0;
+        loop {
+            start = self.find_first_zero(start)?;
+            if start + count > limit {
+                return None;
+            }
+            match self.find_first_one(start) {
+                Some(used) if used < start + count => start = used + 1,
+                _ => return Some(start),
+            }
+        }
+    }
+
+    /// Number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Allocator for IDs in the range `0..N * 64`, always handing out the
+/// lowest free ID
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdAlloc<const N: usize> {
+    used: Bitmap<N>,
+}
+
+impl<const N: usize> IdAlloc<N> {
+    pub const fn new() -> Self {
+        Self {
+            used: Bitmap::new(),
+        }
+    }
+
+    /// Allocates the lowest free ID, or returns `None` if all IDs are in
+    /// use
+    pub fn alloc(&mut self) -> Option<usize> {
+        let id = self.used.find_first_zero(0)?;
+        self.used.set(id);
+        Some(id)
+    }
+
+    /// Marks `id` as in use, e.g. because it is reserved. Returns `false`
+    /// if it already was.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is out of range.
+    pub fn reserve(&mut self, id: usize) -> bool {
+        let free = !self.used.get(id);
+        self.used.set(id);
+        free
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` is out of range or not allocated.
+    pub fn free(&mut self, id: usize) {
+        assert!(self.used.get(id), "Freeing unallocated ID {}", id);
+        self.used.clear(id);
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` is out of range.
+    pub fn is_allocated(&self, id: usize) -> bool {
+        self.used.get(id)
+    }
+
+    /// Number of allocated IDs
+    pub fn used(&self) -> usize {
+        self.used.count_ones()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert_eq!(word_mask(0, 64), u64::MAX);
+        assert_eq!(word_mask(0, 1), 1);
+        assert_eq!(word_mask(4, 8), 0xf0);
+        assert_eq!(word_mask(63, 64), 1 << 63);
+    }
+
+    #[test]
+    fn single_bits() {
+        let mut bmp = Bitmap::<2>::new();
+        assert_eq!(bmp.capacity(), 128);
+        bmp.set(0);
+        bmp.set(127);
+        assert!(bmp.get(0) && bmp.get(127) && !bmp.get(64));
+        bmp.clear(0);
+        assert!(!bmp.get(0));
+        assert_eq!(bmp.count_ones(), 1);
+    }
+
+    #[test]
+    fn ranges() {
+        let mut bmp = Bitmap::<4>::new();
+        bmp.set_range(60, 70);
+        assert_eq!(bmp.count_ones(), 70);
+        assert!(!bmp.get(59) && bmp.get(60) && bmp.get(129) && !bmp.get(130));
+        assert!(bmp.all_set(60, 70));
+        assert!(!bmp.all_set(59, 2));
+
+        bmp.clear_range(64, 64);
+        assert_eq!(bmp.count_ones(), 6);
+        assert!(bmp.get(63) && !bmp.get(64) && !bmp.get(127) && bmp.get(128));
+
+        bmp.set_range(0, 256);
+        assert_eq!(bmp.count_ones(), 256);
+        bmp.clear_range(0, 0);
+        assert_eq!(bmp.count_ones(), 256);
+    }
+
+    #[test]
+    #[should_panic]
+    fn range_out_of_bounds() {
+        Bitmap::<1>::new().set_range(60, 5);
+    }
+
+    #[test]
+    fn find() {
+        let mut bmp = Bitmap::<2>::new();
+        assert_eq!(bmp.find_first_zero(0), Some(0));
+        assert_eq!(bmp.find_first_one(0), None);
+
+        bmp.set_range(0, 70);
+        assert_eq!(bmp.find_first_zero(0), Some(70));
+        assert_eq!(bmp.find_first_zero(100), Some(100));
+        assert_eq!(bmp.find_first_one(5), Some(5));
+        assert_eq!(bmp.find_first_one(70), None);
+
+        bmp.set_range(70, 58);
+        assert_eq!(bmp.find_first_zero(0), None);
+        assert_eq!(bmp.find_first_zero(128), None);
+    }
+
+    #[test]
+    fn find_zero_range() {
+        let mut bmp = Bitmap::<2>::new();
+        bmp.set(3);
+        bmp.set(10);
+        assert_eq!(bmp.find_zero_range(3, 128), Some(0));
+        assert_eq!(bmp.find_zero_range(4, 128), Some(4));
+        assert_eq!(bmp.find_zero_range(6, 128), Some(4));
+        assert_eq!(bmp.find_zero_range(7, 128), Some(11));
+        assert_eq!(bmp.find_zero_range(117, 128), Some(11));
+        assert_eq!(bmp.find_zero_range(118, 128), None);
+        assert_eq!(bmp.find_zero_range(7, 17), None);
+    }
+
+    #[test]
+    fn id_alloc() {
+        let mut ids = IdAlloc::<1>::new();
+        assert!(ids.reserve(0));
+        assert!(!ids.reserve(0));
+        assert_eq!(ids.alloc(), Some(1));
+        assert_eq!(ids.alloc(), Some(2));
+        ids.free(1);
+        assert_eq!(ids.alloc(), Some(1));
+        while ids.alloc().is_some() {}
+        assert_eq!(ids.used(), 64);
+        ids.free(40);
+        assert!(!ids.is_allocated(40));
+        assert_eq!(ids.alloc(), Some(40));
+    }
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Collections whose allocations can fail.
+//!
+//! `Vec` and `Box` from the `alloc` crate abort when an allocation fails,
+//! which takes down the whole SVSM if a guest request merely asks for a
+//! large buffer while memory is tight. [`TryVec`] and [`try_box()`] report
+//! such failures as [`AllocError::OutOfMemory`] instead.
+
+extern crate alloc;
+
+use crate::error::SvsmError;
+use crate::mm::alloc::AllocError;
+use alloc::alloc::alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+
+fn oom<E>(_: E) -> SvsmError {
+    SvsmError::Alloc(AllocError::OutOfMemory)
+}
+
+/// Moves `val` into a new heap allocation, failing instead of aborting if
+/// memory is exhausted.
+pub fn try_box<T>(val: T) -> Result<Box<T>, SvsmError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(val));
+    }
+    // SAFETY: the layout has a non-zero size.
+    let ptr = unsafe { alloc(layout) }.cast::<T>();
+    if ptr.is_null() {
+        return Err(oom(()));
+    }
+    // SAFETY: `ptr` is a fresh allocation with the layout of `T`, which
+    // `Box` will free with the same layout.
+    unsafe {
+        ptr.write(val);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// A vector whose growth is always fallible.
+///
+/// Capacity grows to the next power of two in bytes, which matches the
+/// size classes of the slab allocator for small vectors and the page orders
+/// of the page allocator for large ones, so no allocated memory is wasted.
+/// It does not implement `Clone`, as cloning allocates; use
+/// [`TryVec::try_clone()`] instead.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TryVec<T> {
+    inner: Vec<T>,
+}
+
+impl<T> TryVec<T> {
+    pub const fn new() -> Self {
+        Self { inner: Vec::new() }
+    }
+
+    /// Creates an empty vector which can hold at least `capacity` elements
+    /// without reallocating.
+    pub fn with_capacity(capacity: usize) -> Result<Self, SvsmError> {
+        let mut vec = Self::new();
+        vec.inner.try_reserve_exact(capacity).map_err(oom)?;
+        Ok(vec)
+    }
+
+    /// Capacity to grow to when `additional` more elements are needed
+    fn grown_capacity(&self, additional: usize) -> Option<usize> {
+        let needed = self.inner.len().checked_add(additional)?;
+        let elem = size_of::<T>();
+        if elem == 0 || needed <= self.inner.capacity() {
+            return Some(needed);
+        }
+        let bytes = needed
+            .max(self.inner.capacity() * 2)
+            .checked_mul(elem)?
+            .checked_next_power_of_two()?;
+        Some(bytes / elem)
+    }
+
+    /// Makes room for at least `additional` more elements.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), SvsmError> {
+        let capacity = self
+            .grown_capacity(additional)
+            .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))?;
+        let len = self.inner.len();
+        self.inner.try_reserve_exact(capacity - len).map_err(oom)
+    }
+
+    pub fn try_push(&mut self, val: T) -> Result<(), SvsmError> {
+        self.try_reserve(1)?;
+        self.inner.push(val);
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.inner.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.inner.pop()
+    }
+
+    /// Returns the underlying `Vec`. Growing it further is not fallible
+    /// anymore.
+    pub fn into_inner(self) -> Vec<T> {
+        self.inner
+    }
+}
+
+impl<T: Clone> TryVec<T> {
+    /// Creates a vector of `len` copies of `val`, like `vec![val; len]`.
+    pub fn from_elem(val: T, len: usize) -> Result<Self, SvsmError> {
+        let mut vec = Self::with_capacity(len)?;
+        vec.inner.resize(len, val);
+        Ok(vec)
+    }
+
+    /// Returns a copy of the vector, failing instead of aborting if memory
+    /// is exhausted.
+    pub fn try_clone(&self) -> Result<Self, SvsmError> {
+        let mut vec = Self::with_capacity(self.inner.len())?;
+        vec.inner.extend_from_slice(&self.inner);
+        Ok(vec)
+    }
+
+    pub fn try_extend_from_slice(&mut self, other: &[T]) -> Result<(), SvsmError> {
+        self.try_reserve(other.len())?;
+        self.inner.extend_from_slice(other);
+        Ok(())
+    }
+
+    /// Resizes the vector to `len` elements, filling new elements with
+    /// `val`.
+    pub fn try_resize(&mut self, len: usize, val: T) -> Result<(), SvsmError> {
+        self.try_reserve(len.saturating_sub(self.inner.len()))?;
+        self.inner.resize(len, val);
+        Ok(())
+    }
+}
+
+impl<T> Deref for TryVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for TryVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.inner
+    }
+}
+
+impl<T> From<TryVec<T>> for Vec<T> {
+    fn from(vec: TryVec<T>) -> Self {
+        vec.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growth_policy() {
+        let mut vec = TryVec::<u64>::new();
+        vec.try_push(1).unwrap();
+        // 8 bytes are needed, grow to at least one element
+        assert!(vec.capacity() >= 1);
+        vec.try_extend_from_slice(&[2, 3, 4, 5]).unwrap();
+        // 40 bytes round up to 64 bytes
+        assert_eq!(vec.capacity(), 8);
+        vec.try_resize(9, 0).unwrap();
+        assert_eq!(vec.capacity(), 16);
+        assert_eq!(&vec[..5], &[1, 2, 3, 4, 5]);
+        assert_eq!(vec.len(), 9);
+
+        let copy = vec.try_clone().unwrap();
+        assert_eq!(copy, vec);
+        assert_eq!(copy.capacity(), 9);
+    }
+
+    #[test]
+    fn allocation_failure() {
+        assert!(matches!(
+            TryVec::<u64>::with_capacity(usize::MAX / 4),
+            Err(SvsmError::Alloc(AllocError::OutOfMemory))
+        ));
+        let mut vec = TryVec::from_elem(0u8, 16).unwrap();
+        assert!(vec.try_reserve(usize::MAX).is_err());
+        assert_eq!(vec.len(), 16);
+    }
+
+    #[test]
+    fn boxed() {
+        let b = try_box([7u32; 64]).unwrap();
+        assert!(b.iter().all(|v| *v == 7));
+        try_box(()).unwrap();
+    }
+}
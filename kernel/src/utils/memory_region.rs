@@ -10,7 +10,7 @@ use crate::types::PageSize;
 /// An abstraction over a memory region, expressed in terms of physical
 /// ([`PhysAddr`](crate::address::PhysAddr)) or virtual
 /// ([`VirtAddr`](crate::address::VirtAddr)) addresses.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MemoryRegion<A> {
     start: A,
     end: A,
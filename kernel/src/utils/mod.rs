@@ -4,12 +4,18 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod bitmap;
 pub mod bitmap_allocator;
+pub mod fallible;
 pub mod immut_after_init;
 pub mod memory_region;
+pub mod units;
 pub mod util;
 
+pub use bitmap::{Bitmap, IdAlloc};
+pub use fallible::{try_box, TryVec};
 pub use memory_region::MemoryRegion;
+pub use units::{ByteSize, PageCount, PageOrder};
 pub use util::{
     align_down, align_up, halt, is_aligned, overlap, page_align_up, page_offset, zero_mem_region,
 };
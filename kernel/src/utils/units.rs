@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 The COCONUT-SVSM Authors
+
+//! Unit types for memory sizes.
+//!
+//! Byte lengths, page counts and allocation orders are all plain integers
+//! underneath, which makes it easy to pass one where another is expected.
+//! These wrappers keep them apart and only allow checked conversions.
+
+use crate::types::{PAGE_SHIFT, PAGE_SIZE};
+use core::fmt;
+
+/// A size in bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(usize);
+
+/// A number of 4KiB pages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageCount(usize);
+
+/// The order of a page allocation, i.e. the allocation spans `2^order`
+/// pages. Always small enough for the allocation size to fit in a `usize`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageOrder(usize);
+
+impl ByteSize {
+    pub const fn new(bytes: usize) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// Number of pages needed to hold this many bytes
+    pub const fn pages_up(self) -> PageCount {
+        PageCount(self.0.div_ceil(PAGE_SIZE))
+    }
+
+    /// Returns the page count if this size is an exact multiple of the page
+    /// size.
+    pub const fn exact_pages(self) -> Option<PageCount> {
+        if self.0 % PAGE_SIZE == 0 {
+            Some(PageCount(self.0 / PAGE_SIZE))
+        } else {
+            None
+        }
+    }
+
+    /// Smallest allocation order that can hold this many bytes, or `None`
+    /// if no such order fits in a `usize`.
+    pub fn order(self) -> Option<PageOrder> {
+        let size = self.0.checked_next_power_of_two()?;
+        Some(PageOrder(
+            (size.ilog2() as usize).saturating_sub(PAGE_SHIFT),
+        ))
+    }
+
+    pub const fn checked_add(self, other: Self) -> Option<Self> {
+        match self.0.checked_add(other.0) {
+            Some(v) => Some(Self(v)),
+            None => None,
+        }
+    }
+
+    pub const fn checked_sub(self, other: Self) -> Option<Self> {
+        match self.0.checked_sub(other.0) {
+            Some(v) => Some(Self(v)),
+            None => None,
+        }
+    }
+}
+
+impl PageCount {
+    pub const fn new(pages: usize) -> Self {
+        Self(pages)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// Size in bytes of this many pages, or `None` on overflow
+    pub const fn bytes(self) -> Option<ByteSize> {
+        match self.0.checked_mul(PAGE_SIZE) {
+            Some(v) => Some(ByteSize(v)),
+            None => None,
+        }
+    }
+
+    /// Smallest allocation order spanning at least this many pages, or
+    /// `None` if no such order exists.
+    pub fn order(self) -> Option<PageOrder> {
+        let pages = self.0.checked_next_power_of_two()?;
+        PageOrder::checked_new(pages.ilog2() as usize)
+    }
+
+    pub const fn checked_add(self, other: Self) -> Option<Self> {
+        match self.0.checked_add(other.0) {
+            Some(v) => Some(Self(v)),
+            None => None,
+        }
+    }
+
+    pub const fn checked_sub(self, other: Self) -> Option<Self> {
+        match self.0.checked_sub(other.0) {
+            Some(v) => Some(Self(v)),
+            None => None,
+        }
+    }
+}
+
+impl PageOrder {
+    /// Largest order whose size in bytes still fits in a `usize`
+    pub const MAX: Self = Self(usize::BITS as usize - PAGE_SHIFT - 1);
+
+    /// # Panics
+    ///
+    /// Panics if an allocation of this order would not fit in a `usize`.
+    pub const fn new(order: usize) -> Self {
+        assert!(order <= Self::MAX.0);
+        Self(order)
+    }
+
+    /// Returns `None` if an allocation of this order would not fit in a
+    /// `usize`.
+    pub const fn checked_new(order: usize) -> Option<Self> {
+        if order <= Self::MAX.0 {
+            Some(Self(order))
+        } else {
+            None
+        }
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// Number of pages in an allocation of this order
+    pub const fn pages(self) -> PageCount {
+        PageCount(1 << self.0)
+    }
+
+    /// Size in bytes of an allocation of this order
+    pub const fn bytes(self) -> ByteSize {
+        ByteSize(PAGE_SIZE << self.0)
+    }
+}
+
+impl From<PageCount> for usize {
+    fn from(count: PageCount) -> Self {
+        count.0
+    }
+}
+
+impl From<ByteSize> for usize {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+impl From<PageOrder> for usize {
+    fn from(order: PageOrder) -> Self {
+        order.0
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} bytes", self.0)
+    }
+}
+
+impl fmt::Display for PageCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} pages", self.0)
+    }
+}
+
+impl fmt::Display for PageOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "order {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_size_to_pages() {
+        assert_eq!(ByteSize::new(0).pages_up(), PageCount::new(0));
+        assert_eq!(ByteSize::new(1).pages_up(), PageCount::new(1));
+        assert_eq!(ByteSize::new(PAGE_SIZE).pages_up(), PageCount::new(1));
+        assert_eq!(ByteSize::new(PAGE_SIZE + 1).pages_up(), PageCount::new(2));
+        assert_eq!(
+            ByteSize::new(3 * PAGE_SIZE).exact_pages(),
+            Some(PageCount::new(3))
+        );
+        assert_eq!(ByteSize::new(PAGE_SIZE - 1).exact_pages(), None);
+    }
+
+    #[test]
+    fn byte_size_order() {
+        assert_eq!(ByteSize::new(0).order(), Some(PageOrder(0)));
+        assert_eq!(ByteSize::new(PAGE_SIZE).order(), Some(PageOrder(0)));
+        assert_eq!(ByteSize::new(PAGE_SIZE + 1).order(), Some(PageOrder(1)));
+        assert_eq!(ByteSize::new(4 * PAGE_SIZE).order(), Some(PageOrder(2)));
+        assert_eq!(ByteSize::new(usize::MAX).order(), None);
+    }
+
+    #[test]
+    fn page_count_conversions() {
+        assert_eq!(
+            PageCount::new(2).bytes(),
+            Some(ByteSize::new(2 * PAGE_SIZE))
+        );
+        assert_eq!(PageCount::new(usize::MAX).bytes(), None);
+        assert_eq!(PageCount::new(1).order(), Some(PageOrder::new(0)));
+        assert_eq!(PageCount::new(3).order(), Some(PageOrder::new(2)));
+        assert_eq!(PageCount::new(usize::MAX).order(), None);
+    }
+
+    #[test]
+    fn page_order_bounds() {
+        assert_eq!(PageOrder::new(3).pages(), PageCount::new(8));
+        assert_eq!(PageOrder::new(3).bytes(), ByteSize::new(8 * PAGE_SIZE));
+        assert!(PageOrder::checked_new(PageOrder::MAX.get()).is_some());
+        assert!(PageOrder::checked_new(PageOrder::MAX.get() + 1).is_none());
+        // The largest order must still be representable in bytes
+        assert!(PageOrder::MAX.pages().bytes().is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_order_too_large() {
+        let _ = PageOrder::new(PageOrder::MAX.get() + 1);
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let a = ByteSize::new(usize::MAX);
+        assert_eq!(a.checked_add(ByteSize::new(1)), None);
+        assert_eq!(ByteSize::new(1).checked_sub(ByteSize::new(2)), None);
+        assert_eq!(
+            PageCount::new(2).checked_sub(PageCount::new(1)),
+            Some(PageCount::new(1))
+        );
+        assert_eq!(
+            PageCount::new(usize::MAX).checked_add(PageCount::new(1)),
+            None
+        );
+    }
+}
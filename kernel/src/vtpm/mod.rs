@@ -10,6 +10,9 @@
 /// TPM 2.0 Reference Implementation by Microsoft
 pub mod mstpm;
 
+use crate::protocols::manifest::register_service;
+use crate::protocols::registry::register_protocol;
+use crate::protocols::vtpm::{VTPM_PROTOCOL_INFO, VTPM_SERVICE};
 use crate::vtpm::mstpm::MsTpm as Vtpm;
 use crate::{locking::LockGuard, protocols::vtpm::TpmPlatformCommand};
 use crate::{locking::SpinLock, protocols::errors::SvsmReqError};
@@ -71,13 +74,15 @@ pub trait VtpmInterface: MsTpmSimulatorInterface {
 static VTPM: SpinLock<Vtpm> = SpinLock::new(Vtpm::new());
 
 /// Initialize the TPM by calling the init() implementation of the
-/// [`VtpmInterface`]
+/// [`VtpmInterface`] and make the vTPM protocol available to the guest
 pub fn vtpm_init() -> Result<(), SvsmReqError> {
     let mut vtpm = VTPM.lock();
     if vtpm.is_powered_on() {
         return Ok(());
     }
     vtpm.init()?;
+    register_protocol(VTPM_PROTOCOL_INFO)?;
+    register_service(VTPM_SERVICE)?;
     Ok(())
 }
 
@@ -42,9 +42,13 @@ pub extern "C" fn calloc(items: c_ulong, size: c_ulong) -> *mut c_void {
 
 #[no_mangle]
 pub unsafe extern "C" fn realloc(p: *mut c_void, size: c_ulong) -> *mut c_void {
+    if p.is_null() {
+        return malloc(size);
+    }
     let ptr = p as *mut u8;
     let new_size = size as usize;
-    if let Some(layout) = layout_from_ptr(ptr) {
+    // SAFETY: the TPM libraries only pass pointers returned by malloc().
+    if let Some(layout) = unsafe { layout_from_ptr(ptr) } {
         return unsafe { _realloc(ptr, layout, new_size).cast() };
     }
     ptr::null_mut()
@@ -56,7 +60,8 @@ pub unsafe extern "C" fn free(p: *mut c_void) {
         return;
     }
     let ptr = p as *mut u8;
-    if let Some(layout) = layout_from_ptr(ptr.cast()) {
+    // SAFETY: the TPM libraries only pass pointers returned by malloc().
+    if let Some(layout) = unsafe { layout_from_ptr(ptr.cast()) } {
         unsafe { dealloc(ptr, layout) }
     }
 }
@@ -0,0 +1,60 @@
+#!/bin/bash
+# SPDX-License-Identifier: MIT OR Apache-2.0
+#
+# Copyright (c) 2024 The COCONUT-SVSM Authors
+#
+# Check that external build inputs match the versions they are pinned to,
+# so that a build cannot silently pick up a different firmware image or a
+# locally modified dependency.
+#
+# Usage: check-pins.sh [--file <path> <sha256>]... [--submodules]
+#
+# --file fails if the SHA-256 digest of <path> differs from <sha256>.
+# --submodules fails if any submodule is not checked out at the commit
+# recorded in the superproject or has local modifications.
+
+set -e
+
+status=0
+
+while [ $# -gt 0 ]; do
+	case "$1" in
+	--file)
+		path=$2
+		expected=$(echo "$3" | tr 'A-F' 'a-f')
+		shift 3
+		actual=$(sha256sum "$path" | cut -d' ' -f1)
+		if [ "$actual" != "$expected" ]; then
+			echo "$path: SHA-256 is $actual, pinned to $expected" >&2
+			status=1
+		fi
+		;;
+	--submodules)
+		shift
+		# Unpinned submodules are prefixed with '+' (different commit),
+		# '-' (not initialized) or 'U' (merge conflicts).
+		unpinned=$(git submodule status --recursive | grep -v '^ ' || true)
+		if [ -n "$unpinned" ]; then
+			echo "Submodules not at their pinned commit:" >&2
+			echo "$unpinned" >&2
+			status=1
+		fi
+		dirty=$(git submodule foreach --quiet --recursive \
+			'git diff --quiet HEAD || echo "$displaypath"')
+		if [ -n "$dirty" ]; then
+			echo "Submodules with local modifications:" >&2
+			echo "$dirty" >&2
+			status=1
+		fi
+		;;
+	*)
+		echo "Usage: $0 [--file <path> <sha256>]... [--submodules]" >&2
+		exit 2
+		;;
+	esac
+done
+
+if [ $status -ne 0 ]; then
+	echo "Refusing to build with unpinned inputs" >&2
+fi
+exit $status
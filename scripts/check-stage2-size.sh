@@ -0,0 +1,64 @@
+#!/bin/bash
+# SPDX-License-Identifier: MIT OR Apache-2.0
+#
+# Copyright (c) 2024 The COCONUT-SVSM Authors
+#
+# Check that the memory footprint of the stage2 ELF fits into its budget in
+# low memory. Stage2 is loaded at 64k and its heap ends where the secrets and
+# CPUID pages start, so an oversized stage2 otherwise only shows up as a
+# broken boot.
+#
+# Usage: check-stage2-size.sh <stage2 ELF> <max size> [--report]
+#
+# The footprint is measured from the start of the lowest to the end of the
+# highest loadable segment, including .bss. With --report, the size of each
+# section is printed as well.
+
+set -e
+
+READELF=${READELF:-readelf}
+
+if [ $# -lt 2 ] || [ $# -gt 3 ] || { [ $# -eq 3 ] && [ "$3" != "--report" ]; }; then
+	echo "Usage: $0 <stage2 ELF> <max size> [--report]" >&2
+	exit 1
+fi
+
+ELF=$1
+MAX=$(($2))
+REPORT=$3
+
+START=
+END=0
+# Program header lines: Type Offset VirtAddr PhysAddr FileSiz MemSiz ...
+while read -r TYPE _ VADDR _ _ MEMSZ _; do
+	[ "$TYPE" = "LOAD" ] || continue
+	if [ -z "$START" ] || [ $((VADDR)) -lt "$START" ]; then
+		START=$((VADDR))
+	fi
+	if [ $((VADDR + MEMSZ)) -gt $END ]; then
+		END=$((VADDR + MEMSZ))
+	fi
+done < <("$READELF" -lW "$ELF")
+
+if [ -z "$START" ]; then
+	echo "$ELF has no loadable segments" >&2
+	exit 1
+fi
+SIZE=$((END - START))
+
+if [ -n "$REPORT" ]; then
+	echo "stage2 sections:"
+	# Section header lines: [Nr] Name Type Address Off Size ...
+	"$READELF" -SW "$ELF" | sed -n 's/^ *\[ *[0-9]*\] *//p' |
+		while read -r NAME _ ADDR _ SECSIZE _; do
+			[ $((0x$ADDR)) -ne 0 ] || continue
+			printf "  %-20s %8d\n" "$NAME" $((0x$SECSIZE))
+		done
+	printf "stage2 footprint: %d of %d bytes (%d%%)\n" $SIZE $MAX $((SIZE * 100 / MAX))
+fi
+
+if [ $SIZE -gt $MAX ]; then
+	printf "stage2 is too large: %d bytes (%#x) exceeds the maximum of %d bytes (%#x) by %d bytes\n" \
+		$SIZE $SIZE $MAX $MAX $((SIZE - MAX)) >&2
+	exit 1
+fi
@@ -0,0 +1,49 @@
+#!/bin/bash
+# SPDX-License-Identifier: MIT OR Apache-2.0
+#
+# Copyright (c) 2024 The COCONUT-SVSM Authors
+#
+# Generate the build provenance blob which is injected into the .provenance
+# section of the SVSM kernel ELF. See kernel/src/provenance.rs for the format.
+#
+# Usage: gen-provenance.sh <output file> <features> <profile>
+#
+# The build timestamp is taken from SOURCE_DATE_EPOCH if set, otherwise from
+# the commit time, so that rebuilding the same tree yields the same binary.
+
+set -e
+
+# Must match PROVENANCE_SIZE in kernel/src/provenance.rs
+SIZE=256
+
+if [ $# -ne 3 ]; then
+	echo "Usage: $0 <output file> <features> <profile>" >&2
+	exit 1
+fi
+
+OUTPUT=$1
+FEATURES=$2
+PROFILE=$3
+
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+TOP_DIR=$SCRIPT_DIR/..
+
+COMMIT=$(git -C "$TOP_DIR" describe --always --dirty --abbrev=12 2> /dev/null || echo unknown)
+TIMESTAMP=${SOURCE_DATE_EPOCH:-$(git -C "$TOP_DIR" log -1 --format=%ct 2> /dev/null || echo 0)}
+DEPS=$(sha256sum "$TOP_DIR/Cargo.lock" | cut -c1-16)
+
+{
+	printf 'commit=%s\n' "$COMMIT"
+	printf 'timestamp=%s\n' "$TIMESTAMP"
+	printf 'profile=%s\n' "$PROFILE"
+	printf 'features=%s\n' "$FEATURES"
+	printf 'deps=%s\n' "$DEPS"
+} > "$OUTPUT"
+
+if [ "$(stat -c %s "$OUTPUT")" -gt $SIZE ]; then
+	echo "Provenance information exceeds $SIZE bytes" >&2
+	rm -f "$OUTPUT"
+	exit 1
+fi
+
+truncate -s $SIZE "$OUTPUT"
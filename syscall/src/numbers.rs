@@ -8,3 +8,6 @@
 
 pub const SYS_HELLO: u64 = 0;
 pub const SYS_EXIT: u64 = 1;
+pub const SYS_MEMINFO: u64 = 2;
+pub const SYS_EXC_HANDLER: u64 = 3;
+pub const SYS_EXC_RETURN: u64 = 4;